@@ -0,0 +1,114 @@
+// Generate memory.x (the MEMORY block linker.ld includes) from the
+// selected board's consts, so the link addresses can't drift from what
+// the SPL code uses.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[allow(dead_code)]
+#[path = "src/board/qemu_virt.rs"]
+mod qemu_virt;
+#[allow(dead_code)]
+#[path = "src/board/sifive_u.rs"]
+mod sifive_u;
+
+struct Memory {
+    flash_base: usize,
+    flash_size: usize,
+    ram_base: usize,
+    ram_size: usize,
+    postcode: usize,
+    handoff: usize,
+    code_budget: usize,
+    bss_budget: usize,
+    stack_min: usize,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/board");
+    println!("cargo:rerun-if-changed=linker.ld");
+
+    // For the boot report (src/report.rs) and the self-descriptor
+    // (src/descriptor.rs). Not a git checkout, or no git: "unknown".
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let describe = git(&["describe", "--always", "--dirty", "--tags"]);
+    println!("cargo:rustc-env=SPL_GIT_DESCRIBE={}", describe);
+    println!("cargo:rustc-env=SPL_GIT_HASH={}", git(&["rev-parse", "HEAD"]));
+    // Reproducible builds set SOURCE_DATE_EPOCH.
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+        });
+    println!("cargo:rustc-env=SPL_BUILD_TIME={}", build_time);
+
+    let boards = [
+        (
+            "CARGO_FEATURE_BOARD_QEMU_VIRT",
+            Memory {
+                flash_base: qemu_virt::FLASH_BASE[0],
+                flash_size: qemu_virt::FLASH_SIZE[0],
+                ram_base: qemu_virt::RAM_BASE,
+                ram_size: qemu_virt::SPL_RAM_SIZE,
+                postcode: qemu_virt::POSTCODE_ADDR,
+                handoff: qemu_virt::HANDOFF_ADDR,
+                code_budget: qemu_virt::CODE_BUDGET,
+                bss_budget: qemu_virt::BSS_BUDGET,
+                stack_min: qemu_virt::STACK_MIN,
+            },
+        ),
+        (
+            "CARGO_FEATURE_BOARD_SIFIVE_U",
+            Memory {
+                flash_base: sifive_u::FLASH_BASE[0],
+                flash_size: sifive_u::FLASH_SIZE[0],
+                ram_base: sifive_u::RAM_BASE,
+                ram_size: sifive_u::SPL_RAM_SIZE,
+                postcode: sifive_u::POSTCODE_ADDR,
+                handoff: sifive_u::HANDOFF_ADDR,
+                code_budget: sifive_u::CODE_BUDGET,
+                bss_budget: sifive_u::BSS_BUDGET,
+                stack_min: sifive_u::STACK_MIN,
+            },
+        ),
+    ];
+    let mut selected = boards.iter().filter(|(feature, _)| env::var_os(feature).is_some());
+    // None or several: src/board.rs reports it with a proper error.
+    let (Some((_, mem)), None) = (selected.next(), selected.next()) else {
+        return;
+    };
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let mut memory_x = format!(
+        "MEMORY\n{{\n    FLASH (rx)  : ORIGIN = 0x{:x}, LENGTH = 0x{:x}\n    RAM   (rwx) : ORIGIN = 0x{:x}, LENGTH = 0x{:x}\n}}\n\n__postcode = 0x{:x};\n__spl_handoff = 0x{:x};\n",
+        mem.flash_base, mem.flash_size, mem.ram_base, mem.ram_size, mem.postcode, mem.handoff,
+    );
+    // Size budget, for linker.ld's checks; size_report.sh reads them back
+    // from the ELF.
+    memory_x += &format!(
+        "__code_budget = 0x{:x};\n__bss_budget = 0x{:x};\n__stack_min = 0x{:x};\n",
+        mem.code_budget, mem.bss_budget, mem.stack_min
+    );
+    fs::write(out.join("memory.x"), memory_x).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+}
+
+// Output of `git <args>`, trimmed, or "unknown".
+fn git(args: &[&str]) -> String {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -1,24 +1,54 @@
 #!/usr/bin/env bash
 set -euo pipefail
 
-# Build SPL1 (Rust) and prepare a 32 MiB NOR pflash image (pflash0.img)
-# for QEMU "virt" where:
-#   - SPL1 executes in place from 0x2000_0000 (pflash0)
-#   - Boot metadata (counters) live in the last 128 KiB block
+# Build SPL1 (Rust) and prepare the two 32 MiB NOR pflash images of QEMU
+# "virt" (pflash0.img, pflash1.img) where:
+#   - SPL1 is stored at 0x2000_0000 (pflash0, never written by SPL1); its
+#     _start copies the rest of it to RAM and runs from there
+#   - An optional SPL2 (src/spl2.rs) right behind it, at 1 MiB of pflash0
+#   - Bank A / bank B images (SplImageHeader + payload, see src/image.rs)
+#     live at 0 / 8 MiB of pflash1 (0x2200_0000), 8 MiB each
+#   - Boot metadata (counters) live in the last 128 KiB block of pflash1
+#   - The verification cache (src/vcache.rs) in the block before it
+#   - A layout descriptor (src/layout_desc.rs) at 2 MiB of pflash0, saying
+#     where this script put all of the above, for SPL1 to check
+#
+# Optional: BANK_A_IMG=... BANK_B_IMG=... SPL2_IMG=... ./prepare_flash.sh
 
 FLASH_SIZE_MB=32
 FLASH_SIZE=$((FLASH_SIZE_MB * 1024 * 1024))
 BLOCK_SIZE=$((128 * 1024)) # 128 KiB
 FLASH_IMG="pflash0.img"
+DATA_IMG="pflash1.img"
 TARGET_TRIPLE="riscv64imac-unknown-none-elf"
 PROFILE="release" # or "debug"
 
 ELF="target/${TARGET_TRIPLE}/${PROFILE}/spl1-riscv"
 BIN="spl1.bin"
 
-# Where boot metadata lives: last block of flash
+# Where boot metadata lives: last block of pflash1
 META_OFFSET=$((FLASH_SIZE - BLOCK_SIZE))
 
+# SPL room in pflash0, bank images in pflash1 (must match
+# src/board/qemu_virt.rs)
+SPL_FLASH_SIZE=$((0x00100000))
+SPL2_OFFSET=$((0x00100000))
+SPL2_SIZE=$((0x00100000))
+SPL2_IMG="${SPL2_IMG:-}"
+BANK_A_OFFSET=$((0x00000000))
+BANK_B_OFFSET=$((0x00800000))
+BANK_SIZE=$((0x00800000))
+BANK_A_IMG="${BANK_A_IMG:-}"
+BANK_B_IMG="${BANK_B_IMG:-}"
+
+# Layout descriptor (must match src/layout_desc.rs): bump the major
+# version for a layout older SPLs would misread, the minor one otherwise.
+LAYOUT_OFFSET=$((0x00200000))
+LAYOUT_MAJOR=1
+LAYOUT_MINOR=0
+IMAGE_VERSION=1 # SplImageHeader::VERSION of the bank images we write
+TOOL_VERSION="prepare_flash 1" # at most 16 bytes
+
 echo "=== Building SPL1 (${PROFILE}) for ${TARGET_TRIPLE} ==="
 cargo build --target "${TARGET_TRIPLE}" --${PROFILE}
 
@@ -27,32 +57,92 @@ if [[ ! -f "${ELF}" ]]; then
   exit 1
 fi
 
+echo "=== Size report ==="
+NM=riscv64-unknown-elf-nm ./size_report.sh "${ELF}"
+
 echo "=== Converting ELF to raw binary ==="
 riscv64-unknown-elf-objcopy -O binary "${ELF}" "${BIN}"
 
 BIN_SIZE=$(stat -c '%s' "${BIN}")
 echo "SPL1 binary size: ${BIN_SIZE} bytes"
 
-if (( BIN_SIZE > META_OFFSET )); then
-  echo "ERROR: SPL binary (${BIN_SIZE} bytes) overlaps metadata block (starts at ${META_OFFSET})." >&2
+if (( BIN_SIZE > SPL_FLASH_SIZE )); then
+  echo "ERROR: SPL binary (${BIN_SIZE} bytes) exceeds its ${SPL_FLASH_SIZE} bytes of flash." >&2
   exit 1
 fi
 
-echo "=== Creating ${FLASH_SIZE_MB} MiB flash image filled with 0xFF ==="
-dd if=/dev/zero bs=1M count="${FLASH_SIZE_MB}" status=none | \
-  tr '\000' '\377' > "${FLASH_IMG}"
+for img in "${FLASH_IMG}" "${DATA_IMG}"; do
+  echo "=== Creating ${FLASH_SIZE_MB} MiB flash image ${img} filled with 0xFF ==="
+  dd if=/dev/zero bs=1M count="${FLASH_SIZE_MB}" status=none | \
+    tr '\000' '\377' > "${img}"
+done
 
 echo "=== Writing SPL1 at flash offset 0x00000000 ==="
 dd if="${BIN}" of="${FLASH_IMG}" bs=1 conv=notrunc status=none
 
-echo "=== Ensuring metadata block (last 128 KiB) is erased (0xFF) ==="
+# `n` bytes of `value`, little-endian, to stdout.
+le() {
+  local value="$1" n="$2" i
+  for ((i = 0; i < n; i++)); do
+    printf "\\x$(printf '%02x' $(( (value >> (8 * i)) & 0xff )))"
+  done
+}
+
+write_layout() {
+  local desc="layout.bin"
+  {
+    printf 'SPL1LAYT'
+    le "${LAYOUT_MAJOR}" 2; le "${LAYOUT_MINOR}" 2; le 256 2; le "${IMAGE_VERSION}" 2
+    printf '%s' "${TOOL_VERSION}"; head -c $((16 - ${#TOOL_VERSION})) /dev/zero
+    le 1 4; le 2 4                                  # metadata on pflash1; 2 banks
+    le "${META_OFFSET}" 8; le "${BLOCK_SIZE}" 8; le 0 8
+    le 1 4; le "${BANK_SIZE}" 4; le "${BANK_A_OFFSET}" 8
+    le 1 4; le "${BANK_SIZE}" 4; le "${BANK_B_OFFSET}" 8
+    head -c $((6 * 16 + 60)) /dev/zero              # unused banks, reserved
+  } > "${desc}"
+  # CRC-32 (IEEE) of the above: gzip's trailer has it, little-endian
+  gzip -c < "${desc}" | tail -c 8 | head -c 4 >> "${desc}"
+  echo "=== Writing layout descriptor ${LAYOUT_MAJOR}.${LAYOUT_MINOR}" \
+    "at 0x$(printf '%x' "${LAYOUT_OFFSET}") of ${FLASH_IMG} ==="
+  dd if="${desc}" of="${FLASH_IMG}" bs=1 seek="${LAYOUT_OFFSET}" conv=notrunc status=none
+  rm -f "${desc}"
+}
+
+write_bank() {
+  local name="$1" img="$2" offset="$3"
+  [[ -z "${img}" ]] && return 0
+  local size
+  size=$(stat -c '%s' "${img}")
+  if (( size > BANK_SIZE )); then
+    echo "ERROR: bank ${name} image ${img} (${size} bytes) exceeds ${BANK_SIZE} bytes." >&2
+    exit 1
+  fi
+  echo "=== Writing bank ${name} image ${img} at 0x$(printf '%x' "${offset}") ==="
+  dd if="${img}" of="${DATA_IMG}" bs=1M oflag=seek_bytes seek="${offset}" conv=notrunc status=none
+}
+
+if [[ -n "${SPL2_IMG}" ]]; then
+  SPL2_IMG_SIZE=$(stat -c '%s' "${SPL2_IMG}")
+  if (( SPL2_IMG_SIZE > SPL2_SIZE )); then
+    echo "ERROR: SPL2 image ${SPL2_IMG} (${SPL2_IMG_SIZE} bytes) exceeds ${SPL2_SIZE} bytes." >&2
+    exit 1
+  fi
+  echo "=== Writing SPL2 image ${SPL2_IMG} at 0x$(printf '%x' "${SPL2_OFFSET}") of ${FLASH_IMG} ==="
+  dd if="${SPL2_IMG}" of="${FLASH_IMG}" bs=1M oflag=seek_bytes seek="${SPL2_OFFSET}" conv=notrunc status=none
+fi
+
+write_layout
+write_bank A "${BANK_A_IMG}" "${BANK_A_OFFSET}"
+write_bank B "${BANK_B_IMG}" "${BANK_B_OFFSET}"
+
+echo "=== Ensuring metadata block (last 128 KiB of ${DATA_IMG}) is erased (0xFF) ==="
 dd if=/dev/zero bs="${BLOCK_SIZE}" count=1 status=none | \
   tr '\000' '\377' | \
-  dd of="${FLASH_IMG}" bs=1 seek="${META_OFFSET}" conv=notrunc status=none
+  dd of="${DATA_IMG}" bs=1 seek="${META_OFFSET}" conv=notrunc status=none
 
 echo
-echo "Done. Generated flash image: ${FLASH_IMG}"
-echo "  - size        : ${FLASH_SIZE_MB} MiB"
+echo "Done. Generated flash images: ${FLASH_IMG} (SPL1), ${DATA_IMG} (banks, metadata)"
+echo "  - size        : ${FLASH_SIZE_MB} MiB each"
 echo "  - meta offset : ${META_OFFSET} (0x$(printf '%x' "${META_OFFSET}"))"
 echo
 echo "Run QEMU like this to boot SPL1 directly from pflash0:"
@@ -61,4 +151,5 @@ echo "    -M virt \\"
 echo "    -m 256M \\"
 echo "    -bios none \\"
 echo "    -drive if=pflash,format=raw,unit=0,file=${FLASH_IMG},readonly=off \\"
+echo "    -drive if=pflash,format=raw,unit=1,file=${DATA_IMG},readonly=off \\"
 echo "    -display none -serial stdio -monitor none"
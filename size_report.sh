@@ -0,0 +1,111 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Size report of an SPL1 ELF: .text + .rodata, .bss and the stack against
+# the board's budget (src/board/, put in the ELF by build.rs/memory.x),
+# then code and data by module with the change since size-baseline.txt.
+# linker.ld already refuses a build over budget; this says where it went.
+#
+#   ./size_report.sh [elf]            report, exit status 1 over budget
+#   ./size_report.sh --update [elf]   same, then rewrite size-baseline.txt
+#
+# Compare builds by feature (e.g. log-error against log-debug to see what
+# the logs cost) by running it on each.
+
+TARGET_TRIPLE="riscv64imac-unknown-none-elf"
+NM="${NM:-riscv64-unknown-elf-nm}"
+BASELINE="size-baseline.txt"
+
+UPDATE=0
+if [[ "${1:-}" == "--update" ]]; then
+  UPDATE=1
+  shift
+fi
+ELF="${1:-target/${TARGET_TRIPLE}/release/spl1-riscv}"
+
+if [[ ! -f "${ELF}" ]]; then
+  echo "ERROR: ELF not found at ${ELF}" >&2
+  exit 1
+fi
+
+# Value of a linker symbol
+sym() {
+  local v
+  v=$("${NM}" -t d "${ELF}" | awk -v s="$1" '$3 == s { print $1; exit }')
+  if [[ -z "${v}" ]]; then
+    echo "ERROR: no symbol $1 in ${ELF} (built before the size budget?)" >&2
+    exit 1
+  fi
+  echo $((10#${v}))
+}
+
+CODE=$(( $(sym __rodata_end) - $(sym __image_start) ))
+BSS=$(( $(sym __bss_end) - $(sym __bss_start) ))
+STACK=$(( $(sym _stack_top) - $(sym _stack_bottom) ))
+CODE_BUDGET=$(sym __code_budget)
+BSS_BUDGET=$(sym __bss_budget)
+STACK_MIN=$(sym __stack_min)
+
+OVER=0
+budget() {
+  local name="$1" size="$2" limit="$3" verdict="ok"
+  if { [[ "${name}" == stack ]] && (( size < limit )); } ||
+     { [[ "${name}" != stack ]] && (( size > limit )); }; then
+    verdict="OVER BUDGET"
+    OVER=1
+  fi
+  printf '%-16s %8d of %8d  %s\n' "${name}" "${size}" "${limit}" "${verdict}"
+}
+
+echo "=== Budget ($(basename "${ELF}")) ==="
+budget ".text+.rodata" "${CODE}" "${CODE_BUDGET}"
+budget ".bss" "${BSS}" "${BSS_BUDGET}"
+budget "stack" "${STACK}" "${STACK_MIN}"
+
+# Symbol sizes by module: the part after "spl1_riscv::" (a trait impl
+# going by its type's), or the crate for anything else; impls on
+# primitive types and the like are "(other)". Code is
+# t/T and r/R symbols, data d/D and b/B.
+MODULES=$("${NM}" -S -t d --size-sort -C "${ELF}" | awk '
+  NF >= 4 && $2 + 0 > 0 {
+    size = $2 + 0; type = tolower($3)
+    name = $4; for (i = 5; i <= NF; i++) name = name " " $i
+    sub(/^[<&]+/, "", name)
+    n = split(name, part, "::")
+    mod = (part[1] == "spl1_riscv" && n > 2) ? part[2] : part[1]
+    sub(/[ <>].*/, "", mod)
+    if (n == 1 || mod !~ /^[a-z_][a-z0-9_]*$/ || mod ~ /^([iu](8|16|32|64|128|size)|f32|f64|str|bool|char|mut|dyn)$/)
+      mod = "(other)"
+    if (type == "t" || type == "r") code[mod] += size
+    else if (type == "d" || type == "b") data[mod] += size
+    else next
+    seen[mod] = 1
+  }
+  END { for (m in seen) printf "%s %d %d\n", m, code[m], data[m] }' | sort -k2,2nr)
+
+echo
+echo "=== By module (change since ${BASELINE}) ==="
+printf '%-24s %8s %8s %9s\n' module code data change
+awk '
+  FNR == NR { if ($0 !~ /^#/ && NF == 3) old[$1] = $2 + $3; next }
+  {
+    printf "%-24s %8d %8d %+9d\n", $1, $2, $3, $2 + $3 - old[$1]
+    delete old[$1]
+  }
+  END { for (m in old) printf "%-24s %8d %8d %+9d\n", m, 0, 0, -old[m] }' <(cat "${BASELINE}" 2>/dev/null || true) - <<< "${MODULES}"
+
+if (( UPDATE )); then
+  {
+    echo "# SPL1 size by module: name, code bytes, data bytes. From"
+    echo "# ./size_report.sh --update, on a release build with default features."
+    echo "${MODULES}"
+  } > "${BASELINE}"
+  echo
+  echo "Wrote ${BASELINE}"
+fi
+
+if (( OVER )); then
+  echo >&2
+  echo "ERROR: SPL over its size budget (see src/board/)" >&2
+  exit 1
+fi
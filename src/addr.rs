@@ -0,0 +1,119 @@
+use core::fmt;
+use core::ops::{Add, Sub};
+
+// Two kinds of numbers that are both a usize underneath and must not be
+// mixed up: CPU physical addresses (a UART, a load address, the base of
+// a flash chip) and byte offsets within a flash device (a bank, the boot
+// log). An offset only becomes an address through FlashOffset::to_phys()
+// (IntelFlash::addr_of() for a flash at hand), so every place that does
+// it can be found with grep.
+//
+// Both are repr(transparent), so the assembly routines can take them as
+// is. The board constants stay plain usize (build.rs and the simulator
+// read them too); they get their type where they are used.
+
+macro_rules! address_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[repr(transparent)]
+        pub struct $name(usize);
+
+        // Both types get the whole set, whether each uses it yet or not.
+        #[allow(dead_code)]
+        impl $name {
+            pub const ZERO: Self = $name(0);
+
+            pub const fn new(value: usize) -> Self {
+                $name(value)
+            }
+
+            /// The number itself, for arithmetic and MMIO.
+            pub const fn get(self) -> usize {
+                self.0
+            }
+
+            /// `self + n`, None if it overflows.
+            pub const fn checked_add(self, n: usize) -> Option<Self> {
+                match self.0.checked_add(n) {
+                    Some(v) => Some($name(v)),
+                    None => None,
+                }
+            }
+
+            /// Rounded down to a multiple of `align` (a power of two).
+            pub const fn align_down(self, align: usize) -> Self {
+                $name(self.0 & !(align - 1))
+            }
+
+            /// Rounded up to a multiple of `align` (a power of two), None
+            /// if that overflows.
+            pub const fn align_up(self, align: usize) -> Option<Self> {
+                match self.0.checked_add(align - 1) {
+                    Some(v) => Some($name(v & !(align - 1))),
+                    None => None,
+                }
+            }
+
+            /// Whether it is a multiple of `align` (any non-zero value).
+            pub const fn is_aligned(self, align: usize) -> bool {
+                self.0.is_multiple_of(align)
+            }
+        }
+
+        /// Panics on overflow in debug builds, like usize.
+        impl Add<usize> for $name {
+            type Output = Self;
+
+            fn add(self, n: usize) -> Self {
+                $name(self.0 + n)
+            }
+        }
+
+        /// The distance from `other` up to `self`.
+        impl Sub for $name {
+            type Output = usize;
+
+            fn sub(self, other: Self) -> usize {
+                self.0 - other.0
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}(0x{:x})", stringify!($name), self.0)
+            }
+        }
+
+        /// For the `0x{:08x}` of log lines, flags and width honoured.
+        impl fmt::LowerHex for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::LowerHex::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+address_type! {
+    /// A CPU physical address.
+    PhysAddr
+}
+
+address_type! {
+    /// A byte offset within a flash device, from its start.
+    FlashOffset
+}
+
+impl PhysAddr {
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+}
+
+impl FlashOffset {
+    /// Where the byte at this offset of a flash mapped at `flash_base`
+    /// is for the CPU: the one conversion from an offset to an address.
+    pub const fn to_phys(self, flash_base: PhysAddr) -> PhysAddr {
+        PhysAddr(flash_base.0 + self.0)
+    }
+}
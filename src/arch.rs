@@ -1,16 +1,593 @@
 use core::arch::global_asm;
 
+pub mod barrier;
+pub mod csr;
+pub mod probe;
+
+pub use probe::{try_read_volatile, Fault};
+
+use csr::{csr_write, PrivMode};
+
+use crate::postcode::Postcode;
+use crate::{clint, pmp};
+
+// Values handed to us by the previous stage (QEMU's reset vector, or a
+// ROM) in a0/a1. They are stored here by _start before any Rust code
+// runs, so nothing can clobber them on the way to spl_main.
+//
+//  - a0: hart ID of the hart executing _start
+//  - a1: physical address of the flattened device tree (0 if none)
+//
+// Only the boot hart's values are meaningful; other harts must not rely
+// on these statics (they may carry another hart's a0/a1).
+//
+// The boot hart is whichever won the lottery below, not hart 0 or what
+// a0 says: _start records its mhartid in BOOT_MHARTID, and that is the
+// hart ID the SPL logs, puts in /chosen and hands off in a0. Only that
+// hart runs Rust code before the handoff (the others park in _start),
+// so only it ever touches the console or issues flash commands.
+#[unsafe(no_mangle)]
+static mut BOOT_HARTID: usize = 0;
+#[unsafe(no_mangle)]
+static mut BOOT_DTB_PA: usize = 0;
+#[unsafe(no_mangle)]
+static mut BOOT_MHARTID: usize = 0;
+
+/// Registers received at reset, as saved by _start, and the hart that
+/// got them.
+#[derive(Debug, Clone, Copy)]
+pub struct BootArgs {
+    /// The boot hart's mhartid.
+    pub hartid: usize,
+    /// a0 as received: the previous stage's idea of our hart ID.
+    pub a0: usize,
+    pub dtb_pa: usize,
+}
+
+/// Harts with an mhartid at or above this park forever.
+pub const MAX_HARTS: usize = 8;
+
+// Boot-hart election: every hart amoswaps LOTTERY_TAKEN in here, the
+// one that reads back anything else (zero, or power-on garbage) is the
+// boot hart. Reopened by reopen_election() before anything that can
+// reset the board with RAM intact. See the .lottery section in
+// linker.ld.
+const LOTTERY_TAKEN: u64 = 0x4c4f_5454_4552_5931; // "LOTTERY1"
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".lottery")]
+static mut BOOT_LOTTERY: u64 = 0;
+
+// Set to HART_CHECKED_IN by each hart as it parks, so the boot hart
+// knows which ones exist. Outside .bss for the same reason as the
+// lottery, and cleared along with it.
+const HART_CHECKED_IN: usize = 0x5041_524b; // "PARK"
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".lottery")]
+static mut HART_PARKED: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+// Parking mailbox, one slot per hart. A parked hart sleeps in wfi and
+// jumps to the address in its slot once it becomes non-zero, with
+// a0 = mhartid, a1 = its HART_ARG1 slot and sp at the top of its
+// HART_STACKS entry. Whoever releases a hart (us, or OpenSBI's HSM later
+// on) writes the slots and then raises an IPI through the CLINT msip
+// register to wake it up.
+#[unsafe(no_mangle)]
+static mut HART_RELEASE: [usize; MAX_HARTS] = [0; MAX_HARTS];
+#[unsafe(no_mangle)]
+static mut HART_ARG1: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+// A small stack per hart, enough for spl_secondary_entry() to get into
+// the payload.
+const HART_STACK_SHIFT: usize = 11;
+const HART_STACK_SIZE: usize = 1 << HART_STACK_SHIFT;
+
+#[repr(C, align(16))]
+struct HartStacks([[u8; HART_STACK_SIZE]; MAX_HARTS]);
+
+#[unsafe(no_mangle)]
+static mut HART_STACKS: HartStacks = HartStacks([[0; HART_STACK_SIZE]; MAX_HARTS]);
+
+// What released harts enter, hartid and dtb aside (see release_harts()).
+static mut SECONDARY_ARGS: HandoffArgs = HandoffArgs {
+    entry: 0,
+    hartid: 0,
+    dtb: 0,
+    arg2: 0,
+    mode: PrivMode::Machine,
+    interrupts: false,
+};
+static mut SECONDARY_PMP: pmp::Saved = pmp::Saved::OFF;
+
+// Startup self-check: one zero-initialized (.bss) and one initialized
+// (.data) static. If _start did its job they read back as 0 and
+// INIT_CHECK_PATTERN respectively.
+const INIT_CHECK_PATTERN: u32 = 0x5a5a_a5a5;
+static mut INIT_CHECK_BSS: u32 = 0;
+static mut INIT_CHECK_DATA: u32 = INIT_CHECK_PATTERN;
+
+/// Verify that .bss was cleared and .data was copied by _start.
+///
+/// Returns the (bss, data) values read back so the caller can log them.
+pub fn check_static_init() -> Result<(), (u32, u32)> {
+    let (bss, data) = unsafe {
+        (
+            core::ptr::read_volatile(&raw const INIT_CHECK_BSS),
+            core::ptr::read_volatile(&raw const INIT_CHECK_DATA),
+        )
+    };
+    if bss == 0 && data == INIT_CHECK_PATTERN {
+        Ok(())
+    } else {
+        Err((bss, data))
+    }
+}
+
+unsafe extern "C" {
+    static __spl_ram_start: u8;
+    static __spl_ram_end: u8;
+    static __image_start: u8;
+    static __image_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+    static _stack_bottom: u8;
+    static _stack_top: u8;
+}
+
+// _start fills the stack with STACK_CANARY before first use, except its
+// lowest word, which gets STACK_GUARD.
+const STACK_CANARY: u64 = 0x5354_4b21_5354_4b21;
+const STACK_GUARD: u64 = 0x4755_4152_4421_2121;
+
+/// Stack high-water mark, as found by stack_check().
+#[derive(Debug, Clone, Copy)]
+pub struct StackUsage {
+    /// Bytes below _stack_top ever written.
+    pub used: usize,
+    pub size: usize,
+}
+
+/// Whether the guard word at _stack_bottom is still intact (false once
+/// the stack has overflowed into it).
+pub fn stack_guard_intact() -> bool {
+    let guard = (&raw const _stack_bottom).cast::<u64>();
+    unsafe { core::ptr::read_volatile(guard) == STACK_GUARD }
+}
+
+/// Scan up from _stack_bottom for the lowest word that no longer holds
+/// the canary: everything from there up has been used.
+pub fn stack_check() -> StackUsage {
+    let bottom = &raw const _stack_bottom as usize;
+    let top = &raw const _stack_top as usize;
+    let mut p = bottom + 8;
+    while p < top && unsafe { core::ptr::read_volatile(p as *const u64) } == STACK_CANARY {
+        p += 8;
+    }
+    StackUsage {
+        used: top - p,
+        size: top - bottom,
+    }
+}
+
+/// RAM used by the SPL itself (data, bss, stack), as [start, end).
+pub fn spl_ram_region() -> (usize, usize) {
+    (
+        &raw const __spl_ram_start as usize,
+        &raw const __spl_ram_end as usize,
+    )
+}
+
+/// The SPL's code and data as copied to RAM by _start, as [start, end).
+pub fn image_region() -> (usize, usize) {
+    (&raw const __image_start as usize, &raw const __image_end as usize)
+}
+
+/// .rodata (string literals, tables) as copied to RAM, as [start, end).
+pub fn rodata_region() -> (usize, usize) {
+    (&raw const __rodata_start as usize, &raw const __rodata_end as usize)
+}
+
+unsafe extern "C" {
+    fn spl_current_pc() -> usize;
+}
+
+// Where code runs from: the address of the auipc in spl_current_pc,
+// which is in .text along with the rest of the SPL.
+global_asm!(
+    r#"
+    .section .text
+    .globl spl_current_pc
+    .align 2
+spl_current_pc:
+    auipc a0, 0
+    ret
+"#
+);
+
+/// An address in the code running now (.text, wherever _start put it).
+pub fn current_pc() -> usize {
+    unsafe { spl_current_pc() }
+}
+
+/// .bss, as [start, end).
+pub fn bss_region() -> (usize, usize) {
+    (&raw const __bss_start as usize, &raw const __bss_end as usize)
+}
+
+/// The stack, guard word included, as [start, end).
+pub fn stack_region() -> (usize, usize) {
+    (&raw const _stack_bottom as usize, &raw const _stack_top as usize)
+}
+
+/// What the next stage is entered with. a0/a1 follow the RISC-V boot
+/// convention; anything else we pass on gets a field here and a
+/// register in handoff(), not whatever the registers happened to hold.
+#[derive(Debug, Clone, Copy)]
+pub struct HandoffArgs {
+    pub entry: usize,
+    /// a0
+    pub hartid: usize,
+    /// a1: the DTB (our patched copy, normally)
+    pub dtb: usize,
+    /// a2: SPL2's handoff block (see spl2.rs), or the payload's (see
+    /// handoff.rs).
+    pub arg2: usize,
+    /// Privilege level to enter it in.
+    pub mode: PrivMode,
+    /// Leave mstatus.MIE set, for the boot watchdog (see watchdog.rs).
+    /// Otherwise the next stage starts with machine interrupts off.
+    /// M-mode entry only.
+    pub interrupts: bool,
+}
+
+// Traps a payload below M-mode handles itself, as OpenSBI delegates
+// them: misaligned fetch, breakpoint, ecall from U-mode, page faults;
+// and the supervisor software, timer and external interrupts.
+const MEDELEG: usize = (1 << 0) | (1 << 3) | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15);
+const MIDELEG: usize = (1 << 1) | (1 << 5) | (1 << 9);
+
+/// Enter the next stage: a0 = hartid, a1 = dtb, a2 = arg2, a3..a7 zero.
+///
+/// In M-mode it is a plain jump, with mstatus.MIE cleared unless
+/// `interrupts`. Below M-mode (no SBI: the payload runs on the bare
+/// machine) the usual traps are delegated to it, it starts with
+/// translation off, its interrupts off and stvec on a parking loop
+/// until it installs its own, and is entered through mret. PMP must
+/// already grant it memory (see pmp::setup()).
+///
+/// Goes through asm trampolines rather than a call through a
+/// transmuted fn pointer, so the compiler assumes nothing about the
+/// callee and sets up nothing we didn't ask for.
+pub fn handoff(args: &HandoffArgs) -> ! {
+    if args.mode == PrivMode::Machine {
+        if !args.interrupts {
+            csr::write_mstatus(csr::read_mstatus().with_mie(false));
+        }
+        unsafe { spl_handoff(args.entry, args.hartid, args.dtb, args.arg2) }
+    }
+    csr::write_mstatus(csr::read_mstatus().with_mie(false));
+    csr_write!(medeleg, MEDELEG);
+    csr_write!(mideleg, MIDELEG);
+    csr_write!(satp, 0);
+    csr_write!(sie, 0);
+    csr_write!(stvec, spl_stvec_park as *const () as usize);
+    csr::write_mstatus(csr::read_mstatus().with_mpp(args.mode).with_mpie(false));
+    unsafe { spl_handoff_mret(args.entry, args.hartid, args.dtb, args.arg2) }
+}
+
+unsafe extern "C" {
+    fn spl_handoff(entry: usize, hartid: usize, dtb: usize, arg2: usize) -> !;
+    fn spl_handoff_mret(entry: usize, hartid: usize, dtb: usize, arg2: usize) -> !;
+    fn spl_stvec_park();
+}
+
+// Handoff trampolines: a0 = entry, a1 = hartid, a2 = dtb, a3 = arg2.
+// They move the arguments into place for the next stage and jump (or
+// mret, to the mode already in mstatus.MPP), never to return.
+global_asm!(
+    r#"
+    .section .text
+    .globl spl_handoff
+    .align 2
+spl_handoff:
+    mv t0, a0
+    mv a0, a1
+    mv a1, a2
+    mv a2, a3
+    li a3, 0
+    li a4, 0
+    li a5, 0
+    li a6, 0
+    li a7, 0
+    jr t0
+
+    .globl spl_handoff_mret
+    .align 2
+spl_handoff_mret:
+    csrw mepc, a0
+    mv a0, a1
+    mv a1, a2
+    mv a2, a3
+    li a3, 0
+    li a4, 0
+    li a5, 0
+    li a6, 0
+    li a7, 0
+    mret
+
+    // stvec until the payload sets its own: a trap there stops it
+    // quietly instead of running off into random memory.
+    .globl spl_stvec_park
+    .align 2
+spl_stvec_park:
+    wfi
+    j spl_stvec_park
+"#
+);
+
+/// Release every parked hart into the payload `args` describes, each
+/// with its own hartid in a0 and `args.dtb` in a1, in `args.mode` with
+/// interrupts off (the watchdog is the boot hart's alone) and this
+/// hart's PMP entries. Call it last thing before our own handoff().
+/// Returns how many harts were woken.
+pub fn release_harts(args: &HandoffArgs) -> usize {
+    unsafe {
+        core::ptr::write_volatile(
+            &raw mut SECONDARY_ARGS,
+            HandoffArgs {
+                interrupts: false,
+                ..*args
+            },
+        );
+        core::ptr::write_volatile(&raw mut SECONDARY_PMP, pmp::save());
+    }
+    let mut released = 0;
+    for hart in (0..MAX_HARTS).filter(|&h| h != args.hartid) {
+        unsafe {
+            if core::ptr::read_volatile(&raw const HART_PARKED[hart]) != HART_CHECKED_IN {
+                continue;
+            }
+            core::ptr::write_volatile(&raw mut HART_ARG1[hart], args.dtb);
+            // The arguments must be visible before the go.
+            barrier::fence_rw_rw();
+            core::ptr::write_volatile(
+                &raw mut HART_RELEASE[hart],
+                spl_secondary_entry as *const () as usize,
+            );
+        }
+        barrier::fence_rw_rw();
+        clint::send_ipi(hart);
+        released += 1;
+    }
+    released
+}
+
+// Where a hart released by release_harts() lands, on its HART_STACKS
+// entry.
+#[unsafe(no_mangle)]
+extern "C" fn spl_secondary_entry(hartid: usize, arg1: usize) -> ! {
+    clint::clear_ipi(hartid);
+    csr::write_mie(csr::read_mie() & !csr::MIE_MSIE);
+    let (args, saved) = unsafe {
+        (
+            core::ptr::read_volatile(&raw const SECONDARY_ARGS),
+            core::ptr::read_volatile(&raw const SECONDARY_PMP),
+        )
+    };
+    if args.mode != PrivMode::Machine {
+        pmp::restore(&saved);
+    }
+    handoff(&HandoffArgs {
+        hartid,
+        dtb: arg1,
+        ..args
+    })
+}
+
+/// Open the boot-hart election again, with no hart checked in, for the
+/// next time the board comes out of reset: RAM (and so the lottery) may
+/// survive it. Called before resetting (see prepare_reset()), and at the
+/// handoff since the payload can reset without us; never while harts
+/// can still be on their way into _start's lottery.
+pub fn reopen_election() {
+    unsafe {
+        core::ptr::write_volatile(&raw mut BOOT_LOTTERY, 0);
+        core::ptr::write_volatile(&raw mut HART_PARKED, [0; MAX_HARTS]);
+    }
+    barrier::fence_rw_rw();
+}
+
+/// Everything a reset that keeps RAM must not find on the way back up:
+/// the election reopened and every mailbox slot empty (a hart waking
+/// early, before the new boot hart clears .bss, would otherwise jump
+/// into the payload we are leaving). For platform::reset() only.
+pub fn prepare_reset() {
+    reopen_election();
+    unsafe { core::ptr::write_volatile(&raw mut HART_RELEASE, [0; MAX_HARTS]) };
+    barrier::fence_rw_rw();
+}
+
+/// Return the a0/a1 values and the boot hart saved by _start.
+pub fn boot_args() -> BootArgs {
+    unsafe {
+        BootArgs {
+            hartid: boot_hart(),
+            a0: core::ptr::read_volatile(&raw const BOOT_HARTID),
+            dtb_pa: core::ptr::read_volatile(&raw const BOOT_DTB_PA),
+        }
+    }
+}
+
+/// mhartid of the hart that won the boot-hart lottery.
+pub fn boot_hart() -> usize {
+    unsafe { core::ptr::read_volatile(&raw const BOOT_MHARTID) }
+}
+
+/// Whether the calling hart is the boot hart.
+pub fn on_boot_hart() -> bool {
+    csr::read_mhartid() == boot_hart()
+}
+
 // Put _start in a dedicated .text.init section, which we KEEP first
-// in linker.ld
+// in linker.ld. It is the only code executed from flash (see the
+// relocation below); parked harts stay in it too, asleep in wfi and only
+// woken at handoff, after the SPL's last flash command.
 global_asm!(
     r#"
     .section .text.init
     .globl _start
 _start:
+    // Nothing below may touch a0/a1: they carry hartid/dtb until saved.
+
+    // Elect the boot hart: the first one to swap LOTTERY_TAKEN into the
+    // lottery wins, everybody else reads it back and parks without
+    // touching the stack or memory.
+    la t0, BOOT_LOTTERY
+    li t1, {lottery_taken}
+    .option push
+    .option arch, +a
+    amoswap.d.aq t2, t1, (t0)
+    .option pop
+    beq t2, t1, park_hart
+    li t0, {pc_entered}
+    ld t1, .Lpostcode
+    sb t0, 0(t1)
+
+    // Relocate: copy the image (code, rodata, .data) from where it was
+    // loaded to its RAM link address. The source is PC-relative and the
+    // destination absolute, so this works wherever the image sits; it is
+    // skipped when the image already runs at its link address.
+    la t0, __image_load_start
+    ld t2, .Limage_start
+    ld t1, .Limage_end
+    sub t1, t1, t2
+    add t1, t1, t0
+    beq t0, t2, 2f
+1:
+    bgeu t0, t1, 2f
+    ld t3, 0(t0)
+    sd t3, 0(t2)
+    addi t0, t0, 8
+    addi t2, t2, 8
+    j 1b
+2:
+
+    // Zero .bss (8-byte aligned bounds, see linker.ld).
+    ld t0, .Lbss_start
+    ld t1, .Lbss_end
+3:
+    bgeu t0, t1, 4f
+    sd zero, 0(t0)
+    addi t0, t0, 8
+    j 3b
+4:
+    li t0, {pc_bss_cleared}
+    ld t1, .Lpostcode
+    sb t0, 0(t1)
+
+    // Save a0 (hartid) and a1 (dtb) now that .bss is stable, and who
+    // we are: our mhartid, whatever a0 says. Only t0/t1 are used as
+    // scratch so both argument registers stay intact.
+    ld t0, .Lboot_hartid
+    sd a0, 0(t0)
+    ld t0, .Lboot_dtb_pa
+    sd a1, 0(t0)
+    csrr t1, mhartid
+    ld t0, .Lboot_mhartid
+    sd t1, 0(t0)
+
+    // Paint the stack: guard word at the bottom, canary above it.
+    ld t0, .Lstack_bottom
+    ld t1, .Lstack_top
+    li t2, {stack_guard}
+    sd t2, 0(t0)
+    addi t0, t0, 8
+    li t2, {stack_canary}
+7:
+    bgeu t0, t1, 8f
+    sd t2, 0(t0)
+    addi t0, t0, 8
+    j 7b
+8:
+
     // Set up stack pointer (symbol provided by linker.ld)
-    la sp, _stack_top
+    ld sp, .Lstack_top
+    li t0, {pc_stack_set}
+    ld t1, .Lpostcode
+    sb t0, 0(t1)
 
-    // For now we ignore a0/a1 contents and just jump to spl_main.
-    j spl_main
-"#
+    // Make the copied code fetchable, then continue in RAM. spl_main
+    // reads the saved values through arch::boot_args().
+    .option push
+    .option arch, +zifencei
+    fence.i
+    .option pop
+    ld t0, .Lspl_main
+    jr t0
+
+    // Secondary harts: pure asm, no stack until released. Check in,
+    // then sleep until our mailbox slot holds an entry point and jump
+    // there with a0 = mhartid, a1 = our HART_ARG1 slot and sp at the top
+    // of our HART_STACKS entry. MSIE is enabled (with mstatus.MIE still
+    // 0) so an IPI wakes us from wfi without taking a trap.
+park_hart:
+    li t1, 8
+    csrs mie, t1
+    csrr t0, mhartid
+    li t1, {max_harts}
+    bgeu t0, t1, 6f
+    slli t3, t0, 3
+    // Our mailbox slot is in .bss, which the boot hart may not have
+    // cleared yet: empty it ourselves before checking in, or a stale
+    // entry from before a warm reset would send us off at the first
+    // wakeup.
+    la t2, HART_RELEASE
+    add t2, t2, t3
+    sd zero, 0(t2)
+    fence rw, rw
+    la t4, HART_PARKED
+    add t4, t4, t3
+    li t1, {hart_checked_in}
+    sd t1, 0(t4)
+5:
+    wfi
+    ld t4, 0(t2)
+    beqz t4, 5b
+    la t5, HART_ARG1
+    add t5, t5, t3
+    ld a1, 0(t5)
+    la sp, HART_STACKS
+    addi t1, t0, 1
+    slli t1, t1, {hart_stack_shift}
+    add sp, sp, t1
+    mv a0, t0
+    jr t4
+6:
+    wfi
+    j 6b
+
+    // Link addresses of what _start touches in RAM.
+    .balign 8
+.Limage_start:  .dword __image_start
+.Limage_end:    .dword __image_end
+.Lbss_start:    .dword __bss_start
+.Lbss_end:      .dword __bss_end
+.Lboot_hartid:  .dword BOOT_HARTID
+.Lboot_dtb_pa:  .dword BOOT_DTB_PA
+.Lboot_mhartid: .dword BOOT_MHARTID
+.Lstack_bottom: .dword _stack_bottom
+.Lstack_top:    .dword _stack_top
+.Lspl_main:     .dword spl_main
+.Lpostcode:     .dword __postcode
+"#,
+    lottery_taken = const LOTTERY_TAKEN,
+    hart_checked_in = const HART_CHECKED_IN,
+    max_harts = const MAX_HARTS,
+    hart_stack_shift = const HART_STACK_SHIFT,
+    stack_guard = const STACK_GUARD,
+    stack_canary = const STACK_CANARY,
+    pc_entered = const Postcode::Entered as u8,
+    pc_bss_cleared = const Postcode::BssCleared as u8,
+    pc_stack_set = const Postcode::StackSet as u8,
 );
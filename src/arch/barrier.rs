@@ -0,0 +1,48 @@
+// Memory ordering and instruction-fetch barriers.
+//
+// RISC-V only promises that a hart sees its own stores in its data
+// accesses. Instruction fetch is a separate path (possibly a separate,
+// non-coherent I-cache), and other observers (another hart, a DMA
+// master, the next stage after we are gone) may see stores out of order.
+// QEMU is coherent and never needs any of this; real silicon does, so
+// these always execute.
+
+use core::arch::asm;
+
+/// `fence.i`: make all stores this hart has done so far visible to its
+/// own instruction fetches. Hart-local; it says nothing about other harts.
+#[inline(always)]
+pub fn fence_i() {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +zifencei",
+            "fence.i",
+            ".option pop",
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// `fence rw, rw`: order every earlier load/store before every later one,
+/// as seen by any other observer.
+#[inline(always)]
+pub fn fence_rw_rw() {
+    unsafe {
+        asm!("fence rw, rw", options(nostack, preserves_flags));
+    }
+}
+
+/// Make code just written at [addr, addr + len) safe to execute on this
+/// hart.
+///
+/// The data fence first drains the copy out of the store buffer, so it
+/// is globally visible before the next stage (or a released hart) reads
+/// it as data. `fence.i` then discards stale instruction-fetch state for
+/// this hart. The base ISA has no ranged I-cache flush, so the whole
+/// cache is synchronized; the range only documents what was written.
+#[inline(always)]
+pub fn sync_icache_for_region(_addr: usize, _len: usize) {
+    fence_rw_rw();
+    fence_i();
+}
@@ -0,0 +1,191 @@
+// Control and status register access.
+//
+// `csr_read!(name)` / `csr_write!(name, value)` expand to a single
+// csrr / csrw of the CSR spelled `name`, so each access site stays a
+// one-liner and the asm strings don't get copied around.
+
+/// Read a CSR by name: `let v = csr_read!(pmpaddr0);`
+macro_rules! csr_read {
+    ($csr:ident) => {{
+        let v: usize;
+        unsafe {
+            core::arch::asm!(
+                concat!("csrr {0}, ", stringify!($csr)),
+                out(reg) v,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        v
+    }};
+}
+
+/// Write a CSR by name: `csr_write!(pmpcfg0, cfg);`
+macro_rules! csr_write {
+    ($csr:ident, $val:expr) => {{
+        let v: usize = $val;
+        unsafe {
+            core::arch::asm!(
+                concat!("csrw ", stringify!($csr), ", {0}"),
+                in(reg) v,
+                options(nostack, preserves_flags)
+            );
+        }
+    }};
+}
+
+pub(crate) use csr_read;
+pub(crate) use csr_write;
+
+// pmpNcfg fields (one byte per entry in pmpcfg0/2/...).
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A_OFF: u8 = 0 << 3;
+pub const PMP_A_NAPOT: u8 = 3 << 3;
+pub const PMP_A_MASK: u8 = 3 << 3;
+/// Locked: enforced in M-mode too, and read-only until reset.
+pub const PMP_L: u8 = 1 << 7;
+
+/// mie.MSIE: machine software interrupt (IPI) enable.
+pub const MIE_MSIE: usize = 1 << 3;
+/// mie.MTIE: machine timer interrupt enable.
+pub const MIE_MTIE: usize = 1 << 7;
+/// mie.MEIE: machine external interrupt (PLIC) enable.
+#[cfg_attr(not(any(feature = "uart-irq", feature = "plic-selftest")), allow(dead_code))]
+pub const MIE_MEIE: usize = 1 << 11;
+
+// Typed accessors for the machine-mode CSRs the SPL uses. Each one is a
+// single inlined csrr/csrw.
+
+/// Hart ID of the executing hart.
+#[inline(always)]
+pub fn read_mhartid() -> usize {
+    csr_read!(mhartid)
+}
+
+#[inline(always)]
+pub fn read_mstatus() -> Mstatus {
+    Mstatus(csr_read!(mstatus))
+}
+
+#[inline(always)]
+pub fn write_mstatus(v: Mstatus) {
+    csr_write!(mstatus, v.0)
+}
+
+#[inline(always)]
+pub fn read_mie() -> usize {
+    csr_read!(mie)
+}
+
+#[inline(always)]
+pub fn write_mie(v: usize) {
+    csr_write!(mie, v)
+}
+
+#[inline(always)]
+pub fn write_mtvec(v: Mtvec) {
+    csr_write!(mtvec, v.0)
+}
+
+#[inline(always)]
+pub fn read_mepc() -> usize {
+    csr_read!(mepc)
+}
+
+#[inline(always)]
+pub fn write_mepc(v: usize) {
+    csr_write!(mepc, v)
+}
+
+#[inline(always)]
+pub fn read_mcause() -> usize {
+    csr_read!(mcause)
+}
+
+#[inline(always)]
+pub fn read_mtval() -> usize {
+    csr_read!(mtval)
+}
+
+/// Privilege level, as encoded in mstatus.MPP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivMode {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+/// mstatus value with helpers for the fields an `mret` depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mstatus(pub usize);
+
+impl Mstatus {
+    const MIE: usize = 1 << 3;
+    const MPIE: usize = 1 << 7;
+    const MPP_SHIFT: usize = 11;
+    const MPP_MASK: usize = 3 << Self::MPP_SHIFT;
+
+    /// Machine interrupt enable.
+    #[cfg_attr(not(feature = "uart-irq"), allow(dead_code))]
+    pub fn mie(self) -> bool {
+        self.0 & Self::MIE != 0
+    }
+
+    pub fn with_mie(self, on: bool) -> Self {
+        if on {
+            Mstatus(self.0 | Self::MIE)
+        } else {
+            Mstatus(self.0 & !Self::MIE)
+        }
+    }
+
+    /// Interrupt enable restored into MIE by `mret`.
+    pub fn mpie(self) -> bool {
+        self.0 & Self::MPIE != 0
+    }
+
+    pub fn with_mpie(self, on: bool) -> Self {
+        if on {
+            Mstatus(self.0 | Self::MPIE)
+        } else {
+            Mstatus(self.0 & !Self::MPIE)
+        }
+    }
+
+    /// Privilege level `mret` returns to (2 is reserved, read as None).
+    pub fn mpp(self) -> Option<PrivMode> {
+        match (self.0 & Self::MPP_MASK) >> Self::MPP_SHIFT {
+            0 => Some(PrivMode::User),
+            1 => Some(PrivMode::Supervisor),
+            3 => Some(PrivMode::Machine),
+            _ => None,
+        }
+    }
+
+    pub fn with_mpp(self, mode: PrivMode) -> Self {
+        Mstatus((self.0 & !Self::MPP_MASK) | ((mode as usize) << Self::MPP_SHIFT))
+    }
+}
+
+/// mtvec.MODE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    /// All traps go to BASE.
+    Direct = 0,
+    /// Interrupts go to BASE + 4 * cause.
+    #[allow(dead_code)]
+    Vectored = 1,
+}
+
+/// mtvec value: 4-byte aligned BASE plus MODE in the low two bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mtvec(pub usize);
+
+impl Mtvec {
+    const MODE_MASK: usize = 3;
+
+    pub fn new(base: usize, mode: TrapMode) -> Self {
+        Mtvec((base & !Self::MODE_MASK) | mode as usize)
+    }
+}
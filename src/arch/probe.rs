@@ -0,0 +1,212 @@
+// Loads and stores that may fault: "is anything there?" without a trap
+// dump. Each access is done by a tiny leaf routine in the asm window
+// [spl_probe_begin, spl_probe_end). When a load or store faults with
+// mepc inside it, the trap handler calls recover(), which resumes at
+// spl_probe_fault with the cause and the faulting address in a0/a1,
+// instead of dumping and parking. The routines touch no stack and keep
+// ra, so the recovery point is the same for every call and every hart:
+// nothing needs saving before an access.
+//
+// Constraints:
+//  - trap::init() must have run on the calling hart (mtvec on our
+//    handler), otherwise a fault goes wherever mtvec points.
+//  - One primitive access per call, nothing else: this is not a way to
+//    catch faults in arbitrary code.
+//  - A store to a device may have had its effect even if it faulted,
+//    and a read may have side effects; only probe what tolerates it.
+
+use core::arch::global_asm;
+
+use crate::trap::TrapFrame;
+
+/// A load or store that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// mcause: access fault, page fault or misaligned access.
+    pub cause: usize,
+    /// mtval: the address that faulted.
+    pub addr: usize,
+}
+
+// What the routines return in a0/a1: cause 0 when the access went
+// through.
+#[repr(C)]
+struct Status {
+    cause: usize,
+    addr: usize,
+}
+
+impl Status {
+    fn result(self) -> Result<(), Fault> {
+        match self.cause {
+            0 => Ok(()),
+            cause => Err(Fault {
+                cause,
+                addr: self.addr,
+            }),
+        }
+    }
+}
+
+unsafe extern "C" {
+    fn spl_probe_begin();
+    fn spl_probe_end();
+    fn spl_probe_fault();
+    fn spl_probe_read_u8(addr: usize, out: *mut u8) -> Status;
+    fn spl_probe_read_u16(addr: usize, out: *mut u16) -> Status;
+    fn spl_probe_read_u32(addr: usize, out: *mut u32) -> Status;
+    fn spl_probe_read_u64(addr: usize, out: *mut u64) -> Status;
+}
+
+// Stores only have the console's "mw" for a user.
+#[cfg_attr(not(feature = "console"), allow(dead_code))]
+unsafe extern "C" {
+    fn spl_probe_write_u8(addr: usize, v: u8) -> Status;
+    fn spl_probe_write_u16(addr: usize, v: u16) -> Status;
+    fn spl_probe_write_u32(addr: usize, v: u32) -> Status;
+    fn spl_probe_write_u64(addr: usize, v: u64) -> Status;
+}
+
+// Uncompressed, so every instruction in the window is 4 bytes and
+// nothing outside of it can be mistaken for a probe.
+global_asm!(
+    r#"
+    .section .text
+    .option push
+    .option norvc
+    .align 2
+    .globl spl_probe_begin
+spl_probe_begin:
+
+    .globl spl_probe_read_u8
+spl_probe_read_u8:
+    lbu t0, 0(a0)
+    sb t0, 0(a1)
+    li a0, 0
+    ret
+
+    .globl spl_probe_read_u16
+spl_probe_read_u16:
+    lhu t0, 0(a0)
+    sh t0, 0(a1)
+    li a0, 0
+    ret
+
+    .globl spl_probe_read_u32
+spl_probe_read_u32:
+    lwu t0, 0(a0)
+    sw t0, 0(a1)
+    li a0, 0
+    ret
+
+    .globl spl_probe_read_u64
+spl_probe_read_u64:
+    ld t0, 0(a0)
+    sd t0, 0(a1)
+    li a0, 0
+    ret
+
+    .globl spl_probe_write_u8
+spl_probe_write_u8:
+    sb a1, 0(a0)
+    li a0, 0
+    ret
+
+    .globl spl_probe_write_u16
+spl_probe_write_u16:
+    sh a1, 0(a0)
+    li a0, 0
+    ret
+
+    .globl spl_probe_write_u32
+spl_probe_write_u32:
+    sw a1, 0(a0)
+    li a0, 0
+    ret
+
+    .globl spl_probe_write_u64
+spl_probe_write_u64:
+    sd a1, 0(a0)
+    li a0, 0
+    ret
+
+    .globl spl_probe_end
+spl_probe_end:
+
+    // Resumed here by recover(), with a0/a1 already set.
+    .globl spl_probe_fault
+spl_probe_fault:
+    ret
+    .option pop
+"#
+);
+
+/// A type try_read_volatile() and try_write_volatile() can access in one
+/// instruction: u8, u16, u32, u64.
+pub trait Primitive: Copy + Default + sealed::Sealed {
+    #[doc(hidden)]
+    unsafe fn probe_read(addr: usize, out: *mut Self) -> Result<(), Fault>;
+    #[doc(hidden)]
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    unsafe fn probe_write(addr: usize, v: Self) -> Result<(), Fault>;
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! primitive {
+    ($t:ty, $read:ident, $write:ident) => {
+        impl sealed::Sealed for $t {}
+
+        impl Primitive for $t {
+            unsafe fn probe_read(addr: usize, out: *mut Self) -> Result<(), Fault> {
+                unsafe { $read(addr, out) }.result()
+            }
+
+            unsafe fn probe_write(addr: usize, v: Self) -> Result<(), Fault> {
+                unsafe { $write(addr, v) }.result()
+            }
+        }
+    };
+}
+
+primitive!(u8, spl_probe_read_u8, spl_probe_write_u8);
+primitive!(u16, spl_probe_read_u16, spl_probe_write_u16);
+primitive!(u32, spl_probe_read_u32, spl_probe_write_u32);
+primitive!(u64, spl_probe_read_u64, spl_probe_write_u64);
+
+/// Volatile read of the `T` at `addr`, or the fault if the load traps
+/// (nothing there, or not readable). See the constraints above.
+///
+/// # Safety
+/// A read at `addr` must have no side effects we mind.
+pub unsafe fn try_read_volatile<T: Primitive>(addr: usize) -> Result<T, Fault> {
+    let mut v = T::default();
+    unsafe { T::probe_read(addr, &mut v) }?;
+    Ok(v)
+}
+
+/// Volatile write of `v` at `addr`, or the fault if the store traps.
+///
+/// # Safety
+/// `addr` must not be memory anything else relies on, and a write
+/// there must have no side effects we mind.
+#[cfg_attr(not(feature = "console"), allow(dead_code))]
+pub unsafe fn try_write_volatile<T: Primitive>(addr: usize, v: T) -> Result<(), Fault> {
+    unsafe { T::probe_write(addr, v) }
+}
+
+/// Called by the trap handler for a load/store fault at `mepc`: if it
+/// hit a probe, point it at the recovery point with the fault in a0/a1
+/// and return true.
+pub fn recover(frame: &mut TrapFrame, mepc: usize, mcause: usize, mtval: usize) -> bool {
+    let window = spl_probe_begin as *const () as usize..spl_probe_end as *const () as usize;
+    if !window.contains(&mepc) {
+        return false;
+    }
+    frame.set_a(0, mcause);
+    frame.set_a(1, mtval);
+    super::csr::write_mepc(spl_probe_fault as *const () as usize);
+    true
+}
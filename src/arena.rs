@@ -0,0 +1,81 @@
+// Scratch RAM for sizable temporary buffers: a bump allocator over the
+// linker's .heap region (_heap_start.._heap_end). Nothing is ever freed
+// on its own; reset() starts over, between boot phases.
+//
+// Running out returns None: callers fall back to doing without (e.g. the
+// original DTB is passed on unpatched), the SPL never panics over it.
+
+unsafe extern "C" {
+    static _heap_start: u8;
+    static _heap_end: u8;
+}
+
+// Only the boot hart runs Rust code, so plain statics are enough.
+static mut USED: usize = 0;
+static mut PEAK: usize = 0;
+
+/// The arena, as [start, end).
+pub fn region() -> (usize, usize) {
+    (&raw const _heap_start as usize, &raw const _heap_end as usize)
+}
+
+/// Take `size` zeroed bytes aligned to `align` (a power of two), or None
+/// if the arena can't fit them.
+pub fn alloc(size: usize, align: usize) -> Option<&'static mut [u8]> {
+    if !align.is_power_of_two() {
+        return None;
+    }
+    let (start, end) = region();
+    unsafe {
+        let addr = (start + USED).checked_next_multiple_of(align)?;
+        let top = addr.checked_add(size)?;
+        if top > end {
+            return None;
+        }
+        USED = top - start;
+        PEAK = core::cmp::max(PEAK, USED);
+        let buf = core::slice::from_raw_parts_mut(addr as *mut u8, size);
+        buf.fill(0);
+        Some(buf)
+    }
+}
+
+/// Give the whole arena back.
+///
+/// # Safety
+/// No buffer returned by alloc() so far may be used afterwards.
+#[allow(dead_code)]
+pub unsafe fn reset() {
+    unsafe {
+        USED = 0;
+    }
+}
+
+/// Most bytes ever in use at once, and the arena size.
+pub fn peak() -> (usize, usize) {
+    let (start, end) = region();
+    (unsafe { PEAK }, end - start)
+}
+
+// With the "alloc" feature the arena also backs the global allocator,
+// for code that wants `alloc` types. dealloc() is a no-op.
+#[cfg(feature = "alloc")]
+mod global {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    struct ArenaAlloc;
+
+    unsafe impl GlobalAlloc for ArenaAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            match super::alloc(layout.size(), layout.align()) {
+                Some(buf) => buf.as_mut_ptr(),
+                None => core::ptr::null_mut(),
+            }
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    #[global_allocator]
+    static GLOBAL: ArenaAlloc = ArenaAlloc;
+}
@@ -0,0 +1,57 @@
+use crate::addr::FlashOffset;
+use crate::board;
+use crate::bootmeta::{BankDesc, BootMeta};
+
+// The boot scheme's banks and how many failures each one gets, shared
+// by spl_main and the host simulator (src/sim/).
+
+// The banks, by index (BootBank): log tag, priority and where each one
+// is in flash. B is tried first, as it always has been; older SPLs
+// logged A as 0x1111_1111 and B as 0x0000_0000. Up to MAX_BANKS.
+pub const BOOT_BANKS: [BankDesc; 2] = [
+    BankDesc {
+        name: "A",
+        tag: 0xAAAA_AAA8,
+        legacy: Some(0x1111_1111),
+        priority: 1,
+        unit: board::BANKS_UNIT,
+        offset: FlashOffset::new(board::BANK_A_OFFSET),
+        size: board::BANK_SIZE,
+    },
+    BankDesc {
+        name: "B",
+        tag: 0xBBBB_BBB8,
+        legacy: Some(0x0000_0000),
+        priority: 0,
+        unit: board::BANKS_UNIT,
+        offset: FlashOffset::new(board::BANK_B_OFFSET),
+        size: board::BANK_SIZE,
+    },
+];
+const _: () = assert!(<BootMeta>::valid_table(&BOOT_BANKS), "bad BOOT_BANKS table");
+
+// The bank table in use: BOOT_BANKS, with the banks the flash's layout
+// descriptor places elsewhere moved there (see layout_desc.rs).
+static mut TABLE: [BankDesc; BOOT_BANKS.len()] = BOOT_BANKS;
+
+/// The bank table in use (BOOT_BANKS, or where the flash says).
+pub fn table() -> &'static [BankDesc] {
+    let table = &raw const TABLE;
+    unsafe { &*table }
+}
+
+/// Move bank `index` to `size` bytes at `offset` of flash `unit`. Only
+/// before anything reads the table: first thing after the flash probe.
+pub fn relocate(index: usize, unit: usize, offset: FlashOffset, size: usize) {
+    let bank = BankDesc {
+        unit,
+        offset,
+        size,
+        ..BOOT_BANKS[index]
+    };
+    unsafe { TABLE[index] = bank };
+}
+
+/// Failed trials (handed off, never confirmed) before a bank is passed
+/// over for the next one by priority.
+pub const MAX_TRIALS: u32 = 4;
@@ -0,0 +1,411 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use crate::addr::{FlashOffset, PhysAddr};
+use crate::crc32::crc32;
+use crate::flash_intel::{FlashError, IntelFlash, RetryPolicy};
+use crate::{arch, board, clint};
+use crate::{slog_info, slog_warn};
+
+// Flash "black box": a crash (fatal trap or panic) in the SPL, or the
+// boot deadline passing with nothing loaded, leaves a record here, so
+// one that happened before the payload ran is still known after a power
+// cycle. The crash path only programs (1→0) the next free record, never
+// erases, and formats nothing: a full region just isn't written. The
+// next boot logs the new records (so they also reach the OS through the
+// log ring) and marks them seen; once every record is seen and the
+// region is full, it is erased. Little-endian:
+//
+//   0x00  u32   magic     "SPBX"
+//   0x04  u32   reason    REASON_*
+//   0x08  u32   attempts  unconfirmed attempts of the slot booting, 0
+//                         before one is picked
+//   0x0c  u32   line      panic line, 0 for a trap
+//   0x10  u64   mtime     at the crash
+//   0x18  u64   mcause    trap only
+//   0x20  u64   mepc      trap only
+//   0x28  u64   mtval     trap only
+//   0x30  u64   sp        trapped sp, or the panic handler's
+//   0x38  [u64; 4]        the words at sp, zero if sp is off the stack
+//   0x58  [32]  file      panic file, its last 32 bytes, or the phase
+//                         the deadline passed in, NUL-padded
+//   0x78  u32   crc       CRC-32 of bytes 0x00..0x78
+//   0x7c  u32   seen      all-ones until a boot has logged the record
+//
+// The magic is programmed first, so a record torn by a power cut still
+// takes its slot, and fails its CRC.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SPBX");
+const RECORD_SIZE: usize = 0x80;
+const CRC_AT: usize = 0x78;
+const SEEN_AT: usize = 0x7c;
+const FILE_LEN: usize = 32;
+const STACK_WORDS: usize = 4;
+const ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+pub const REASON_TRAP: u32 = 1;
+pub const REASON_PANIC: u32 = 2;
+pub const REASON_DEADLINE: u32 = 3;
+
+/// One crash, as recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub reason: u32,
+    pub attempts: u32,
+    pub line: u32,
+    pub mtime: u64,
+    pub mcause: u64,
+    pub mepc: u64,
+    pub mtval: u64,
+    pub sp: u64,
+    pub stack: [u64; STACK_WORDS],
+    pub file: [u8; FILE_LEN],
+}
+
+impl Record {
+    // No formatting and no allocation: this runs on the crash path.
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut b = [0xffu8; RECORD_SIZE];
+        b[0x00..0x04].copy_from_slice(&MAGIC.to_le_bytes());
+        b[0x04..0x08].copy_from_slice(&self.reason.to_le_bytes());
+        b[0x08..0x0c].copy_from_slice(&self.attempts.to_le_bytes());
+        b[0x0c..0x10].copy_from_slice(&self.line.to_le_bytes());
+        b[0x10..0x18].copy_from_slice(&self.mtime.to_le_bytes());
+        b[0x18..0x20].copy_from_slice(&self.mcause.to_le_bytes());
+        b[0x20..0x28].copy_from_slice(&self.mepc.to_le_bytes());
+        b[0x28..0x30].copy_from_slice(&self.mtval.to_le_bytes());
+        b[0x30..0x38].copy_from_slice(&self.sp.to_le_bytes());
+        for (i, w) in self.stack.iter().enumerate() {
+            b[0x38 + 8 * i..0x40 + 8 * i].copy_from_slice(&w.to_le_bytes());
+        }
+        b[0x58..CRC_AT].copy_from_slice(&self.file);
+        let crc = crc32(&b[..CRC_AT]);
+        b[CRC_AT..SEEN_AT].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    /// The record in `b` and whether it was seen, None unless its magic
+    /// and CRC are right.
+    pub fn decode(b: &[u8; RECORD_SIZE]) -> Option<(Self, bool)> {
+        let u32_at = |o: usize| u32::from_le_bytes(b[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(b[o..o + 8].try_into().unwrap());
+        if u32_at(0x00) != MAGIC || u32_at(CRC_AT) != crc32(&b[..CRC_AT]) {
+            return None;
+        }
+        let record = Record {
+            reason: u32_at(0x04),
+            attempts: u32_at(0x08),
+            line: u32_at(0x0c),
+            mtime: u64_at(0x10),
+            mcause: u64_at(0x18),
+            mepc: u64_at(0x20),
+            mtval: u64_at(0x28),
+            sp: u64_at(0x30),
+            stack: core::array::from_fn(|i| u64_at(0x38 + 8 * i)),
+            file: b[0x58..CRC_AT].try_into().unwrap(),
+        };
+        Some((record, u32_at(SEEN_AT) != ERASED_WORD))
+    }
+
+    fn file(&self) -> &str {
+        let len = self.file.iter().position(|&c| c == 0).unwrap_or(FILE_LEN);
+        core::str::from_utf8(&self.file[..len]).unwrap_or("?")
+    }
+}
+
+/// As the boot log prints it.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            REASON_TRAP => write!(
+                f,
+                "trap mcause=0x{:x} mepc=0x{:x} mtval=0x{:x}",
+                self.mcause, self.mepc, self.mtval
+            )?,
+            REASON_PANIC => write!(f, "panic at {}:{}", self.file(), self.line)?,
+            REASON_DEADLINE => write!(f, "boot deadline passed in {}", self.file())?,
+            r => write!(f, "reason {}", r)?,
+        }
+        write!(f, " sp=0x{:x} [", self.sp)?;
+        for (i, w) in self.stack.iter().enumerate() {
+            write!(f, "{}0x{:x}", if i == 0 { "" } else { " " }, w)?;
+        }
+        write!(f, "] attempts={} mtime={}", self.attempts, self.mtime)
+    }
+}
+
+pub struct BlackBox<'a> {
+    flash: &'a IntelFlash,
+    offset: FlashOffset,
+    size: usize,
+}
+
+impl<'a> BlackBox<'a> {
+    pub fn new(flash: &'a IntelFlash, offset: FlashOffset, size: usize) -> Self {
+        BlackBox { flash, offset, size }
+    }
+
+    fn capacity(&self) -> usize {
+        self.size / RECORD_SIZE
+    }
+
+    fn read(&self, idx: usize) -> [u8; RECORD_SIZE] {
+        let mut b = [0u8; RECORD_SIZE];
+        self.flash.read_slice(self.offset + idx * RECORD_SIZE, &mut b);
+        b
+    }
+
+    // The first slot whose magic is erased, None if the region is full.
+    fn free_slot(&self) -> Option<usize> {
+        (0..self.capacity()).find(|&idx| self.flash.read_u32_le(self.offset + idx * RECORD_SIZE) == ERASED_WORD)
+    }
+
+    // Crash path: program `record` in the first free slot, its seen word
+    // left erased. Nothing if the region is full.
+    fn write(&self, record: &Record) -> Result<(), FlashError> {
+        let Some(idx) = self.free_slot() else {
+            return Ok(());
+        };
+        self.flash.program(self.offset + idx * RECORD_SIZE, &record.encode()[..SEEN_AT])
+    }
+
+    /// Log the records no boot has logged yet, and return how many there
+    /// were. Torn records are counted, not shown.
+    pub fn report(&self) -> usize {
+        let mut new = 0;
+        for idx in 0..self.free_slot().unwrap_or(self.capacity()) {
+            match Record::decode(&self.read(idx)) {
+                Some((_, true)) => {}
+                Some((record, false)) => {
+                    slog_warn!("WARNING: black box #{}: {}", idx, record);
+                    new += 1;
+                }
+                None if self.flash.read_u32_le(self.offset + idx * RECORD_SIZE + SEEN_AT) == ERASED_WORD => {
+                    slog_warn!("WARNING: black box #{}: torn record", idx);
+                    new += 1;
+                }
+                None => {}
+            }
+        }
+        new
+    }
+
+    /// Mark every record seen, then erase the region if it is full, so
+    /// the next crash has room.
+    pub fn acknowledge(&self) -> Result<(), FlashError> {
+        let used = self.free_slot().unwrap_or(self.capacity());
+        for idx in 0..used {
+            let at = self.offset + idx * RECORD_SIZE + SEEN_AT;
+            if self.flash.read_u32_le(at) == ERASED_WORD {
+                self.flash.program_u32_le(at, 0)?;
+            }
+        }
+        if used == self.capacity() {
+            slog_info!("black box: full, erasing");
+            self.flash.erase_range(self.offset, self.size)?;
+        }
+        Ok(())
+    }
+}
+
+// Where the crash path writes: the black box unit's base, 0 until armed.
+static FLASH_BASE: AtomicUsize = AtomicUsize::new(0);
+static FLASH_SIZE: AtomicUsize = AtomicUsize::new(0);
+static ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+// One record per crash, even if recording it traps or panics.
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// Let the crash path write to `flash` (the board::BLACKBOX_UNIT one).
+/// Not armed: crashes aren't recorded (e.g. no NOR writes at all).
+pub fn arm(flash: &IntelFlash) {
+    FLASH_SIZE.store(flash.size, Ordering::Relaxed);
+    FLASH_BASE.store(flash.base.get(), Ordering::Relaxed);
+}
+
+/// The attempt count to put in crash records from now on.
+pub fn note_attempts(attempts: u32) {
+    ATTEMPTS.store(attempts, Ordering::Relaxed);
+}
+
+// The words at `sp`, if it points into the stack.
+fn stack_words(sp: usize) -> [u64; STACK_WORDS] {
+    let (start, end) = arch::stack_region();
+    if sp < start || sp.saturating_add(8 * STACK_WORDS) > end || !sp.is_multiple_of(8) {
+        return [0; STACK_WORDS];
+    }
+    core::array::from_fn(|i| unsafe { core::ptr::read_volatile((sp as *const u64).add(i)) })
+}
+
+fn record(mut record: Record) {
+    if CRASHING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let base = FLASH_BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return;
+    }
+    // No retries: bounded time, and nothing logged.
+    let mut flash = IntelFlash::unit(board::BLACKBOX_UNIT, PhysAddr::new(base));
+    flash.size = FLASH_SIZE.load(Ordering::Relaxed);
+    flash.retry = RetryPolicy { attempts: 1, backoff_us: 0 };
+    let bb = BlackBox::new(&flash, FlashOffset::new(board::BLACKBOX_OFFSET), board::BLACKBOX_SIZE);
+    record.attempts = ATTEMPTS.load(Ordering::Relaxed);
+    record.mtime = clint::mtime();
+    let _ = bb.write(&record);
+}
+
+/// Crash path: a fatal trap.
+pub fn record_trap(mcause: usize, mepc: usize, mtval: usize, sp: usize) {
+    record(Record {
+        reason: REASON_TRAP,
+        attempts: 0,
+        line: 0,
+        mtime: 0,
+        mcause: mcause as u64,
+        mepc: mepc as u64,
+        mtval: mtval as u64,
+        sp: sp as u64,
+        stack: stack_words(sp),
+        file: [0; FILE_LEN],
+    });
+}
+
+// The last FILE_LEN bytes of `s`, NUL-padded.
+fn tail(s: &str) -> [u8; FILE_LEN] {
+    let tail = &s.as_bytes()[s.len().saturating_sub(FILE_LEN)..];
+    let mut name = [0u8; FILE_LEN];
+    name[..tail.len()].copy_from_slice(tail);
+    name
+}
+
+/// Crash path: a panic at `file`:`line`.
+pub fn record_panic(file: &str, line: u32) {
+    let marker = 0u64;
+    let sp = &raw const marker as usize;
+    record(Record {
+        reason: REASON_PANIC,
+        attempts: 0,
+        line,
+        mtime: 0,
+        mcause: 0,
+        mepc: 0,
+        mtval: 0,
+        sp: sp as u64,
+        stack: stack_words(sp),
+        file: tail(file),
+    });
+}
+
+/// The boot deadline passed in `phase` (see budget.rs), nothing loaded.
+pub fn record_deadline(phase: &str) {
+    let marker = 0u64;
+    let sp = &raw const marker as usize;
+    record(Record {
+        reason: REASON_DEADLINE,
+        attempts: 0,
+        line: 0,
+        mtime: 0,
+        mcause: 0,
+        mepc: 0,
+        mtval: 0,
+        sp: sp as u64,
+        stack: stack_words(sp),
+        file: tail(phase),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 4 * RECORD_SIZE;
+
+    // A one-block region in the second block, the first one in use.
+    fn flash() -> IntelFlash {
+        let mut image = vec![0xa5; BLOCK];
+        image.resize(2 * BLOCK, 0xff);
+        IntelFlash::new(PhysAddr::new(0x2000_0000), 2 * BLOCK, BLOCK, image)
+    }
+
+    fn black_box(flash: &IntelFlash) -> BlackBox<'_> {
+        BlackBox::new(flash, FlashOffset::new(BLOCK), BLOCK)
+    }
+
+    fn panic(line: u32) -> Record {
+        Record {
+            reason: REASON_PANIC,
+            attempts: 2,
+            line,
+            mtime: 1234,
+            mcause: 0,
+            mepc: 0,
+            mtval: 0,
+            sp: 0x8004_1f00,
+            stack: [1, 2, 3, 4],
+            file: tail("a/path/longer/than/thirty-two/bytes/src/main.rs"),
+        }
+    }
+
+    // Records take the slots in order, a torn one too, and stay put
+    // once seen.
+    #[test]
+    fn slots() {
+        let flash = flash();
+        let bb = black_box(&flash);
+        assert_eq!(bb.free_slot(), Some(0));
+        bb.write(&panic(1)).unwrap();
+        assert_eq!(bb.free_slot(), Some(1));
+        flash.program_u32_le(FlashOffset::new(BLOCK + RECORD_SIZE), MAGIC).unwrap();
+        assert_eq!(bb.free_slot(), Some(2));
+        assert_eq!(bb.report(), 2);
+        bb.acknowledge().unwrap();
+        assert_eq!(bb.report(), 0);
+        assert_eq!(bb.free_slot(), Some(2));
+        bb.write(&panic(3)).unwrap();
+        assert_eq!(Record::decode(&bb.read(2)), Some((panic(3), false)));
+        assert_eq!(bb.report(), 1);
+    }
+
+    // A full region takes no more records, and is erased once they are
+    // all seen; nothing outside it is.
+    #[test]
+    fn full() {
+        let flash = flash();
+        let bb = black_box(&flash);
+        for line in 0..4 {
+            bb.write(&panic(line)).unwrap();
+        }
+        assert_eq!(bb.free_slot(), None);
+        flash.inject(Default::default());
+        bb.write(&panic(4)).unwrap();
+        assert_eq!(flash.ops().programs, 0);
+        assert_eq!(bb.report(), 4);
+        bb.acknowledge().unwrap();
+        assert_eq!(bb.free_slot(), Some(0));
+        assert_eq!(bb.report(), 0);
+        let image = flash.into_image();
+        assert!(image[..BLOCK].iter().all(|&b| b == 0xa5));
+        assert!(image[BLOCK..].iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn decode() {
+        let record = panic(42);
+        let mut b = record.encode();
+        assert_eq!(Record::decode(&b), Some((record, false)));
+        assert_eq!(
+            format!("{}", record),
+            "panic at han/thirty-two/bytes/src/main.rs:42 sp=0x80041f00 [0x1 0x2 0x3 0x4] attempts=2 mtime=1234"
+        );
+        b[SEEN_AT..].fill(0);
+        assert_eq!(Record::decode(&b), Some((record, true)));
+        // A flipped bit anywhere before the CRC, or the CRC's own.
+        for bit in [0, 8 * 0x0c, 8 * 0x60 + 3, 8 * CRC_AT] {
+            let mut torn = b;
+            torn[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(Record::decode(&torn), None);
+        }
+        b[..4].fill(0xff);
+        assert_eq!(Record::decode(&b), None);
+    }
+}
@@ -0,0 +1,35 @@
+// Board addresses and default layout, picked at build time by exactly one
+// board-* cargo feature. Every board module exports the same set of
+// consts; nothing else in the SPL hardcodes a board address (the DTB
+// still overrides the device bases at boot).
+//
+// The board files hold plain consts only: build.rs includes them too, to
+// generate the linker script's MEMORY block (memory.x). Every board is
+// compiled in, for the console probe's list of UARTS; only the selected
+// one's consts are re-exported.
+
+#[cfg_attr(not(feature = "board-qemu-virt"), allow(dead_code))]
+mod qemu_virt;
+#[cfg(feature = "board-qemu-virt")]
+pub use qemu_virt::*;
+
+#[cfg_attr(not(feature = "board-sifive-u"), allow(dead_code))]
+mod sifive_u;
+#[cfg(feature = "board-sifive-u")]
+pub use sifive_u::*;
+
+// Every board's console UART (compatible, base, input clock), for a
+// binary that runs on any of them (see logger/uart.rs), in the order to
+// probe them: SiFive first, since on sifive_u the PRCI sits at the
+// 16550's 0x1000_0000 and reads fine, while on virt nothing answers at
+// 0x1001_0000.
+pub const UARTS: [(&str, usize, u32); 2] = [
+    (sifive_u::UART_COMPATIBLE, sifive_u::UART_BASE, sifive_u::UART_CLOCK_HZ),
+    (qemu_virt::UART_COMPATIBLE, qemu_virt::UART_BASE, qemu_virt::UART_CLOCK_HZ),
+];
+
+#[cfg(not(any(feature = "board-qemu-virt", feature = "board-sifive-u")))]
+compile_error!("no board selected: enable one of the board-* features");
+
+#[cfg(all(feature = "board-qemu-virt", feature = "board-sifive-u"))]
+compile_error!("more than one board-* feature enabled");
@@ -0,0 +1,108 @@
+// QEMU virt machine, SPL in pflash0, banks in pflash1 (see
+// prepare_flash.sh).
+
+/// Board name, for logs.
+pub const NAME: &str = "qemu-virt";
+
+// Console: ns16550a, 3.6864 MHz clock
+pub const UART_COMPATIBLE: &str = "ns16550a";
+pub const UART_BASE: usize = 0x1000_0000;
+pub const UART_CLOCK_HZ: u32 = 3_686_400;
+pub const UART_BAUD: u32 = 115_200;
+
+// CLINT, and the mtime rate if the DTB has no timebase-frequency
+pub const CLINT_BASE: usize = 0x0200_0000;
+pub const TIMEBASE_HZ: u32 = 10_000_000;
+
+// sifive_test finisher (QEMU exit and reset)
+pub const TEST_FINISHER_BASE: usize = 0x0010_0000;
+
+// Board reset: this 32-bit value written to this register
+pub const RESET_REG: usize   = TEST_FINISHER_BASE;
+pub const RESET_VALUE: u32   = 0x7777;                          // FINISHER_RESET
+
+// PLIC, the console UART's source on it, and each hart's M-mode
+// context
+pub const PLIC_BASE: usize = 0x0c00_0000;
+pub const PLIC_SOURCES: u32 = 96; // 0 (none) included
+#[cfg_attr(not(any(feature = "uart-irq", feature = "plic-selftest")), allow(dead_code))]
+pub const UART_IRQ: u32 = 10;
+pub const PLIC_M_CONTEXT: &[usize] = &[0, 2, 4, 6, 8, 10, 12, 14];
+
+// Goldfish RTC (wall clock), if the DTB has none
+pub const RTC_BASE: Option<usize> = Some(0x0010_1000);
+
+// QEMU firmware configuration device (see fw_cfg.rs), if the DTB has
+// none
+pub const FWCFG_BASE: Option<usize> = Some(0x1010_0000);
+
+// CFI flash units, by index: pflash0 (the SPL, and a golden image if
+// any; never written by the SPL) and pflash1 (the banks and the boot
+// metadata). Size and block size are what we expect; CFI has the last
+// word at boot.
+pub const FLASH_UNITS: usize = 2;
+pub const FLASH_BASE: [usize; FLASH_UNITS]       = [0x2000_0000, 0x2200_0000];
+pub const FLASH_SIZE: [usize; FLASH_UNITS]       = [32 * 1024 * 1024; FLASH_UNITS];
+pub const FLASH_BUS_WIDTH: [usize; FLASH_UNITS]  = [1; FLASH_UNITS]; // bytes per program cycle
+pub const FLASH_BLOCK_SIZE: [usize; FLASH_UNITS] = [128 * 1024; FLASH_UNITS];
+pub const FLASH_WRITABLE: [bool; FLASH_UNITS]    = [false, true];
+
+// DRAM, if the DTB has no memory node (QEMU default: 128 MiB). The SPL
+// itself runs in its first SPL_RAM_SIZE bytes.
+pub const RAM_BASE: usize     = 0x8000_0000;
+pub const RAM_SIZE: usize     = 128 * 1024 * 1024;
+pub const SPL_RAM_SIZE: usize = 1024 * 1024;
+
+// Boot progress code (see postcode.rs): a byte in the last 16 bytes of
+// the SPL RAM window, kept off the stack by linker.ld. No LEDs or GPIO
+// to mirror it to.
+pub const POSTCODE_ADDR: usize = RAM_BASE + SPL_RAM_SIZE - 16;
+// Device register the code is also written to: none on this board.
+pub const POSTCODE_PORT: Option<usize> = None;
+
+// Payload handoff block (see handoff.rs): the 128 bytes under the
+// postcode, at an address C payloads can hard-code. The stack stops
+// short of it too.
+pub const HANDOFF_ADDR: usize = POSTCODE_ADDR - 128;
+
+// Size budget of the SPL in its RAM window, checked when linking (see
+// linker.ld, and size_report.sh for what uses it): .text + .rodata and
+// .bss at most this, and at least STACK_MIN bytes left for the stack.
+// The heap is a fixed 256 KiB on top.
+#[cfg_attr(not(feature = "oversize-test"), allow(dead_code))]
+pub const CODE_BUDGET: usize = 512 * 1024;
+#[allow(dead_code)] // build.rs
+pub const BSS_BUDGET: usize  = 64 * 1024;
+#[allow(dead_code)] // build.rs
+pub const STACK_MIN: usize   = 32 * 1024;
+
+// Default flash layout (must match prepare_flash.sh): the SPL in the
+// first 1 MiB of pflash0; two 8 MiB banks at the start of pflash1, boot
+// metadata in its last block.
+pub const SPL_FLASH_SIZE: usize = 0x0010_0000;                 // 1 MiB
+pub const BANKS_UNIT: usize    = 1;
+pub const BANK_A_OFFSET: usize = 0x0000_0000;
+pub const BANK_B_OFFSET: usize = 0x0080_0000;                  // 8 MiB
+pub const BANK_SIZE: usize     = 0x0080_0000;                  // 8 MiB each
+pub const META_UNIT: usize     = 1;
+pub const META_OFFSET: usize   = FLASH_SIZE[META_UNIT] - FLASH_BLOCK_SIZE[META_UNIT]; // last block
+pub const META_SIZE: usize     = FLASH_BLOCK_SIZE[META_UNIT];
+// SPL2 (see spl2.rs), chainloaded when its slot isn't erased: right
+// behind the SPL in pflash0
+pub const SPL2_UNIT: usize     = 0;
+pub const SPL2_OFFSET: usize   = SPL_FLASH_SIZE;               // 1 MiB
+pub const SPL2_SIZE: usize     = 0x0010_0000;                  // 1 MiB
+// Flash layout descriptor (see layout_desc.rs), written by the
+// provisioning tool: one block right behind SPL2 in pflash0
+pub const LAYOUT_UNIT: usize   = 0;
+pub const LAYOUT_OFFSET: usize = 0x0020_0000;                  // 2 MiB
+pub const LAYOUT_SIZE: usize   = FLASH_BLOCK_SIZE[LAYOUT_UNIT];
+// Verification cache (see vcache.rs): the block before the metadata
+pub const VCACHE_UNIT: usize   = META_UNIT;
+pub const VCACHE_OFFSET: usize = META_OFFSET - FLASH_BLOCK_SIZE[VCACHE_UNIT];
+pub const VCACHE_SIZE: usize   = FLASH_BLOCK_SIZE[VCACHE_UNIT];
+// Crash records (see blackbox.rs): the block before the verification
+// cache
+pub const BLACKBOX_UNIT: usize   = META_UNIT;
+pub const BLACKBOX_OFFSET: usize = VCACHE_OFFSET - FLASH_BLOCK_SIZE[BLACKBOX_UNIT];
+pub const BLACKBOX_SIZE: usize   = FLASH_BLOCK_SIZE[BLACKBOX_UNIT];
@@ -0,0 +1,108 @@
+// QEMU sifive_u / SiFive FU540 memory map.
+//
+// Not bootable as is: the flash behind the QSPI0 XIP window is SPI NOR,
+// not CFI. This is the address map the driver for it will use. The
+// console (a SiFive UART) works.
+
+/// Board name, for logs.
+pub const NAME: &str = "sifive-u";
+
+// Console: UART0
+pub const UART_COMPATIBLE: &str = "sifive,uart0";
+pub const UART_BASE: usize = 0x1001_0000;
+pub const UART_CLOCK_HZ: u32 = 500_000_000; // tlclk
+pub const UART_BAUD: u32 = 115_200;
+
+// CLINT, and the mtime rate if the DTB has no timebase-frequency
+// (FU540 RTCCLK)
+pub const CLINT_BASE: usize = 0x0200_0000;
+pub const TIMEBASE_HZ: u32 = 1_000_000;
+
+// sifive_test finisher (QEMU only)
+pub const TEST_FINISHER_BASE: usize = 0x0010_0000;
+
+// Board reset: this 32-bit value written to this register. The FU540
+// has no reset register of its own, so QEMU's finisher for now.
+pub const RESET_REG: usize   = TEST_FINISHER_BASE;
+pub const RESET_VALUE: u32   = 0x7777;                          // FINISHER_RESET
+
+// PLIC, UART0's source on it, and each hart's M-mode context: the E51
+// (hart 0) only has that one, the U54s an S-mode one after each
+pub const PLIC_BASE: usize = 0x0c00_0000;
+pub const PLIC_SOURCES: u32 = 54; // 0 (none) included
+#[cfg_attr(not(any(feature = "uart-irq", feature = "plic-selftest")), allow(dead_code))]
+pub const UART_IRQ: u32 = 4;
+pub const PLIC_M_CONTEXT: &[usize] = &[0, 1, 3, 5, 7];
+
+// Wall clock: the FU540 has no RTC
+pub const RTC_BASE: Option<usize> = None;
+
+// No QEMU firmware configuration device
+pub const FWCFG_BASE: Option<usize> = None;
+
+// QSPI0 flash, memory-mapped (XIP): a single unit
+pub const FLASH_UNITS: usize = 1;
+pub const FLASH_BASE: [usize; FLASH_UNITS]       = [0x2000_0000];
+pub const FLASH_SIZE: [usize; FLASH_UNITS]       = [32 * 1024 * 1024];
+pub const FLASH_BUS_WIDTH: [usize; FLASH_UNITS]  = [1]; // bytes per program cycle
+pub const FLASH_BLOCK_SIZE: [usize; FLASH_UNITS] = [64 * 1024];
+pub const FLASH_WRITABLE: [bool; FLASH_UNITS]    = [true];
+
+// DRAM, if the DTB has no memory node. The SPL itself runs in its first
+// SPL_RAM_SIZE bytes.
+pub const RAM_BASE: usize     = 0x8000_0000;
+pub const RAM_SIZE: usize     = 1024 * 1024 * 1024;
+pub const SPL_RAM_SIZE: usize = 1024 * 1024;
+
+// Boot progress code (see postcode.rs): a byte in the last 16 bytes of
+// the SPL RAM window, kept off the stack by linker.ld. No LEDs or GPIO
+// to mirror it to.
+pub const POSTCODE_ADDR: usize = RAM_BASE + SPL_RAM_SIZE - 16;
+// Device register the code is also written to: none on this board.
+pub const POSTCODE_PORT: Option<usize> = None;
+
+// Payload handoff block (see handoff.rs): the 128 bytes under the
+// postcode, at an address C payloads can hard-code. The stack stops
+// short of it too.
+pub const HANDOFF_ADDR: usize = POSTCODE_ADDR - 128;
+
+// Size budget of the SPL in its RAM window, checked when linking (see
+// linker.ld, and size_report.sh for what uses it): .text + .rodata and
+// .bss at most this, and at least STACK_MIN bytes left for the stack.
+// The heap is a fixed 256 KiB on top.
+#[cfg_attr(not(feature = "oversize-test"), allow(dead_code))]
+pub const CODE_BUDGET: usize = 512 * 1024;
+#[allow(dead_code)] // build.rs
+pub const BSS_BUDGET: usize  = 64 * 1024;
+#[allow(dead_code)] // build.rs
+pub const STACK_MIN: usize   = 32 * 1024;
+
+// Default flash layout: everything on the one unit, the SPL up to bank
+// A, metadata in the last block.
+pub const SPL_FLASH_SIZE: usize = 0x0010_0000;                 // 1 MiB
+pub const BANKS_UNIT: usize    = 0;
+pub const BANK_A_OFFSET: usize = 0x0010_0000;                  // 1 MiB
+pub const BANK_B_OFFSET: usize = 0x0090_0000;                  // 9 MiB
+pub const BANK_SIZE: usize     = 0x0080_0000;                  // 8 MiB each
+pub const META_UNIT: usize     = 0;
+pub const META_OFFSET: usize   = FLASH_SIZE[META_UNIT] - FLASH_BLOCK_SIZE[META_UNIT]; // last block
+pub const META_SIZE: usize     = FLASH_BLOCK_SIZE[META_UNIT];
+// SPL2 (see spl2.rs), chainloaded when its slot isn't erased: right
+// behind bank B
+pub const SPL2_UNIT: usize     = 0;
+pub const SPL2_OFFSET: usize   = 0x0110_0000;                  // 17 MiB
+pub const SPL2_SIZE: usize     = 0x0010_0000;                  // 1 MiB
+// Flash layout descriptor (see layout_desc.rs), written by the
+// provisioning tool: one block right behind SPL2
+pub const LAYOUT_UNIT: usize   = 0;
+pub const LAYOUT_OFFSET: usize = 0x0120_0000;                  // 18 MiB
+pub const LAYOUT_SIZE: usize   = FLASH_BLOCK_SIZE[LAYOUT_UNIT];
+// Verification cache (see vcache.rs): the block before the metadata
+pub const VCACHE_UNIT: usize   = META_UNIT;
+pub const VCACHE_OFFSET: usize = META_OFFSET - FLASH_BLOCK_SIZE[VCACHE_UNIT];
+pub const VCACHE_SIZE: usize   = FLASH_BLOCK_SIZE[VCACHE_UNIT];
+// Crash records (see blackbox.rs): the block before the verification
+// cache
+pub const BLACKBOX_UNIT: usize   = META_UNIT;
+pub const BLACKBOX_OFFSET: usize = VCACHE_OFFSET - FLASH_BLOCK_SIZE[BLACKBOX_UNIT];
+pub const BLACKBOX_SIZE: usize   = FLASH_BLOCK_SIZE[BLACKBOX_UNIT];
@@ -0,0 +1,245 @@
+use crate::bootmeta::{BootBank, BootMeta, EntryState, Trials};
+use crate::dtb::Fdt;
+use crate::flash_intel::FlashError;
+use crate::ramtrials::Cycle;
+use crate::reset_cause::ResetCause;
+use crate::storm::Storm;
+use crate::{slog_error, slog_info, slog_warn};
+
+/// Whether a boot gets an entry in the boot log. Every entry costs
+/// metadata space, and sooner or later a compaction (an erase), which
+/// adds up on boards rebooted all day long.
+#[derive(Debug, Clone, Copy)]
+pub struct BootPolicy {
+    /// NOR writes allowed at all (should_record_boot()).
+    pub writes: bool,
+    /// /chosen "spl,dev-no-record": never record (developer boards).
+    pub no_record: bool,
+    /// Skip the bank whose newest entry is a confirmed boot of it.
+    pub trust_success: bool,
+    /// Skip when the newest entry already is an unconfirmed attempt of
+    /// the same bank: consecutive failures count once.
+    pub coalesce: bool,
+    /// Never write the boot log (META_READ_ONLY, "spl,meta-read-only").
+    pub meta_read_only: bool,
+    /// What reset us: after a watchdog reset the attempt is always
+    /// recorded, after a deliberate one it's coalesced.
+    pub reset: ResetCause,
+    /// In a reboot storm: record one attempt in so many only.
+    pub storm: Option<Storm>,
+}
+
+impl BootPolicy {
+    /// `built_in`, with what the /chosen flags turn on.
+    pub fn from_chosen(fdt: Option<&Fdt>, built_in: BootPolicy) -> Self {
+        let flag = |name| fdt.is_some_and(|f| matches!(f.node_prop("chosen", name), Ok(Some(_))));
+        BootPolicy {
+            no_record: built_in.no_record || flag("spl,dev-no-record"),
+            trust_success: built_in.trust_success || flag("spl,trust-success"),
+            coalesce: built_in.coalesce || flag("spl,coalesce-attempts"),
+            meta_read_only: built_in.meta_read_only || flag("spl,meta-read-only"),
+            ..built_in
+        }
+    }
+
+    /// Ok to record an attempt of `bank`, or why not.
+    pub fn decide(&self, trials: &Trials, bank: BootBank) -> Result<(), &'static str> {
+        if !self.writes {
+            return Err("no NOR writes from SPL1 (QEMU)");
+        }
+        if self.no_record {
+            return Err("spl,dev-no-record set");
+        }
+        // Watchdog resets included: a hang loops as well as a reset.
+        if let Some(storm) = self.storm
+            && !storm.boots.is_multiple_of(storm.record_every)
+        {
+            return Err("reboot storm, only some attempts recorded");
+        }
+        // The previous attempt hung: that counts, coalescing or not.
+        if self.reset == ResetCause::Watchdog {
+            return Ok(());
+        }
+        match trials.last {
+            Some((b, EntryState::Confirmed)) if self.trust_success && b == bank => {
+                Err("newest entry is a confirmed boot of this bank")
+            }
+            Some((b, EntryState::HandedOff)) if self.reset == ResetCause::Software && b == bank => {
+                Err("deliberate reboot, not another attempt of this bank")
+            }
+            Some((b, EntryState::Started | EntryState::HandedOff)) if self.coalesce && b == bank => {
+                Err("coalesced with the newest entry, an unconfirmed attempt of this bank")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get `meta` ready for this boot, `trials` its scan: read-only if
+    /// the policy or an earlier boot of `cycle` says so, initialized if
+    /// blank and writes are allowed, and when read-only, the failures
+    /// `cycle` counted added to `trials`.
+    pub fn prepare(&self, meta: &BootMeta, cycle: &Cycle, trials: &mut Trials) {
+        if self.meta_read_only {
+            meta.set_read_only("policy");
+        } else if cycle.read_only {
+            meta.set_read_only("found write-protected earlier this power cycle");
+        }
+        if let Err(e) = meta.init(self.writes) {
+            slog_warn!("WARNING: boot log: initialization failed: {:?}", e);
+        }
+        if meta.read_only() {
+            for bank in meta.banks().filter(|&b| cycle.failed(b) > 0) {
+                slog_info!("bank {}: {} failed boots this power cycle (RAM)", bank, cycle.failed(bank));
+            }
+            cycle.add_to(trials);
+        }
+    }
+
+    /// Record an attempt of `bank` if decide() lets it be: the entry's
+    /// index, for record_handoff().
+    pub fn record(&self, meta: &BootMeta, trials: &Trials, bank: BootBank) -> Option<usize> {
+        if let Err(why) = self.decide(trials, bank) {
+            slog_info!("bank {}: boot not recorded: {}", bank, why);
+            return None;
+        }
+        match meta.record_boot(bank) {
+            Ok(idx) => {
+                slog_info!("recorded new boot trial for bank {}", bank);
+                return Some(idx);
+            }
+            Err(FlashError::MetaPoisoned) => slog_error!("ERROR: boot log poisoned, boot trial not recorded"),
+            Err(FlashError::MetaReadOnly) => slog_info!("bank {}: boot not recorded: boot log read-only", bank),
+            Err(e) => slog_warn!("WARNING: failed to record boot trial: {:?}", e),
+        }
+        None
+    }
+}
+
+/// The last of the boot log a boot writes, on its way to the payload:
+/// the compaction record_boot() left erasing finished, then the attempt
+/// `recorded` marked handed off. From there on a boot that doesn't get
+/// confirmed is the payload's fault.
+pub fn record_handoff(meta: &BootMeta, recorded: Option<usize>) {
+    if let Err(e) = meta.finish_compaction() {
+        slog_warn!("WARNING: boot log compaction failed: {:?}", e);
+    }
+    if let Some(idx) = recorded
+        && let Err(e) = meta.record_handoff(idx)
+    {
+        slog_warn!("WARNING: failed to record handoff: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtb::tests::{Item, Item::Begin, Item::End, blob};
+
+    const A: BootBank = BootBank(0);
+    const B: BootBank = BootBank(1);
+
+    // Records everything, as a production build does.
+    const ALL: BootPolicy = BootPolicy {
+        writes: true,
+        no_record: false,
+        trust_success: false,
+        coalesce: false,
+        meta_read_only: false,
+        reset: ResetCause::PowerOn,
+        storm: None,
+    };
+
+    fn last(entry: Option<(BootBank, EntryState)>) -> Trials {
+        Trials { last: entry, ..Trials::default() }
+    }
+
+    #[test]
+    fn no_writes() {
+        let policy = BootPolicy { writes: false, ..ALL };
+        assert_eq!(policy.decide(&last(None), A), Err("no NOR writes from SPL1 (QEMU)"));
+        let watchdog = BootPolicy { reset: ResetCause::Watchdog, ..policy };
+        assert!(watchdog.decide(&last(None), A).is_err());
+    }
+
+    #[test]
+    fn no_record() {
+        let policy = BootPolicy { no_record: true, reset: ResetCause::Watchdog, ..ALL };
+        assert_eq!(policy.decide(&last(None), A), Err("spl,dev-no-record set"));
+    }
+
+    // One attempt in `record_every`, watchdog resets included.
+    #[test]
+    fn storm() {
+        for reset in [ResetCause::PowerOn, ResetCause::Watchdog] {
+            let recorded: Vec<u32> = (9..=20)
+                .filter(|&boots| {
+                    let policy = BootPolicy { reset, storm: Some(Storm { boots, record_every: 4 }), ..ALL };
+                    policy.decide(&last(None), A).is_ok()
+                })
+                .collect();
+            assert_eq!(recorded, [12, 16, 20]);
+        }
+    }
+
+    // After a hang the attempt counts, whatever the log ends with.
+    #[test]
+    fn watchdog() {
+        let policy = BootPolicy { trust_success: true, coalesce: true, reset: ResetCause::Watchdog, ..ALL };
+        for state in [EntryState::Started, EntryState::HandedOff, EntryState::Confirmed] {
+            assert_eq!(policy.decide(&last(Some((A, state))), A), Ok(()));
+        }
+    }
+
+    #[test]
+    fn trust_success() {
+        let confirmed = last(Some((A, EntryState::Confirmed)));
+        let policy = BootPolicy { trust_success: true, ..ALL };
+        assert_eq!(policy.decide(&confirmed, A), Err("newest entry is a confirmed boot of this bank"));
+        assert_eq!(policy.decide(&confirmed, B), Ok(()));
+        assert_eq!(policy.decide(&last(Some((A, EntryState::HandedOff))), A), Ok(()));
+        assert_eq!(ALL.decide(&confirmed, A), Ok(()));
+    }
+
+    #[test]
+    fn software_reset() {
+        let handed_off = last(Some((A, EntryState::HandedOff)));
+        let policy = BootPolicy { reset: ResetCause::Software, ..ALL };
+        assert_eq!(policy.decide(&handed_off, A), Err("deliberate reboot, not another attempt of this bank"));
+        assert_eq!(policy.decide(&handed_off, B), Ok(()));
+        assert_eq!(policy.decide(&last(Some((A, EntryState::Started))), A), Ok(()));
+        assert_eq!(ALL.decide(&handed_off, A), Ok(()));
+    }
+
+    #[test]
+    fn coalesce() {
+        let policy = BootPolicy { coalesce: true, ..ALL };
+        let why = "coalesced with the newest entry, an unconfirmed attempt of this bank";
+        for state in [EntryState::Started, EntryState::HandedOff] {
+            assert_eq!(policy.decide(&last(Some((A, state))), A), Err(why));
+            assert_eq!(policy.decide(&last(Some((A, state))), B), Ok(()));
+            assert_eq!(ALL.decide(&last(Some((A, state))), A), Ok(()));
+        }
+        assert_eq!(policy.decide(&last(Some((A, EntryState::Confirmed))), A), Ok(()));
+        assert_eq!(policy.decide(&last(None), A), Ok(()));
+    }
+
+    // The /chosen flags only ever turn a setting on.
+    #[test]
+    fn from_chosen() {
+        let dtb = blob(&[
+            Begin(""),
+            Begin("chosen"),
+            Item::Prop("spl,coalesce-attempts", b""),
+            Item::Prop("spl,meta-read-only", b""),
+            End,
+            End,
+        ]);
+        let fdt = Fdt::new(&dtb).unwrap();
+        let built_in = BootPolicy { trust_success: true, ..ALL };
+        let policy = BootPolicy::from_chosen(Some(&fdt), built_in);
+        assert!(policy.writes && policy.trust_success && policy.coalesce && policy.meta_read_only);
+        assert!(!policy.no_record);
+        let policy = BootPolicy::from_chosen(None, built_in);
+        assert!(policy.trust_success && !policy.coalesce && !policy.meta_read_only);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::hash::{Sha256, SHA256_LEN};
+
+// Measured-boot event log: what the SPL measured and decided, for the
+// next stages to attest to. Its location is advertised in /chosen as
+// "spl,event-log" (u64 address, u64 size).
+//
+// Layout at `region().0`, little-endian:
+//
+//   0x00  magic     b"SEVT"
+//   0x04  count     records written
+//   0x08  capacity  records room
+//   0x0c  rec_size  bytes per record (56)
+//   0x10  digest    rolling SHA-256: zero, then for each record
+//                   digest = SHA-256(digest || record)
+//   0x30  records[capacity]
+//
+// A record:
+//
+//   0x00  event     u32, see Event
+//   0x04  slot      u32, bank index, SLOT_GOLDEN, SLOT_RAM, SLOT_SPL2 or SLOT_NONE
+//   0x08  result    u32, 0 for success
+//   0x0c  reserved  u32, zero
+//   0x10  value     u64, per event (entry point, log index...)
+//   0x18  digest    [32], SHA-256 measured, zero if none
+//
+// Unlike the console ring it starts over on every boot (each one is
+// measured from scratch), and records past the capacity are dropped
+// and left out of the rolling digest.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SEVT");
+const CAPACITY: usize = 32;
+const RECORD_SIZE: usize = 56;
+
+pub const SLOT_SPL2: u32 = 0xFFFF_FFFC;
+pub const SLOT_RAM: u32 = 0xFFFF_FFFD;
+pub const SLOT_GOLDEN: u32 = 0xFFFF_FFFE;
+pub const SLOT_NONE: u32 = 0xFFFF_FFFF;
+
+/// What a record is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Event {
+    /// SPL entered; value: boot hart id.
+    SplStart = 1,
+    /// Boot log scanned; slot: the bank chosen, result: its failed
+    /// trials, value: next free log index.
+    MetaScan = 2,
+    /// Payload measured; digest: its SHA-256, value: its size.
+    ImageDigest = 3,
+    /// Candidate checked; result: 0 accepted, 1 rejected.
+    Verdict = 4,
+    /// Jumping; value: the entry point.
+    Jump = 5,
+}
+
+/// One record, before it is laid out.
+pub struct Record {
+    pub event: Event,
+    pub slot: u32,
+    pub result: u32,
+    pub value: u64,
+    pub digest: [u8; SHA256_LEN],
+}
+
+impl Record {
+    pub fn new(event: Event, slot: u32) -> Self {
+        Record {
+            event,
+            slot,
+            result: 0,
+            value: 0,
+            digest: [0; SHA256_LEN],
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut b = [0u8; RECORD_SIZE];
+        b[0x00..0x04].copy_from_slice(&(self.event as u32).to_le_bytes());
+        b[0x04..0x08].copy_from_slice(&self.slot.to_le_bytes());
+        b[0x08..0x0c].copy_from_slice(&self.result.to_le_bytes());
+        b[0x10..0x18].copy_from_slice(&self.value.to_le_bytes());
+        b[0x18..].copy_from_slice(&self.digest);
+        b
+    }
+}
+
+#[repr(C, align(8))]
+struct Log {
+    magic: u32,
+    count: u32,
+    capacity: u32,
+    rec_size: u32,
+    digest: [u8; SHA256_LEN],
+    records: [[u8; RECORD_SIZE]; CAPACITY],
+}
+
+#[unsafe(link_section = ".spl_events")]
+static mut LOG: Log = Log {
+    magic: 0,
+    count: 0,
+    capacity: 0,
+    rec_size: 0,
+    digest: [0; SHA256_LEN],
+    records: [[0; RECORD_SIZE]; CAPACITY],
+};
+
+fn log() -> *mut Log {
+    &raw mut LOG
+}
+
+/// Start an empty log.
+pub fn init() {
+    let l = log();
+    unsafe {
+        (*l).count = 0;
+        (*l).capacity = CAPACITY as u32;
+        (*l).rec_size = RECORD_SIZE as u32;
+        (*l).digest = [0; SHA256_LEN];
+        (*l).magic = MAGIC;
+    }
+}
+
+/// Append `rec` and chain it into the rolling digest. Returns false if
+/// the log is full (or init() hasn't run) and it was dropped.
+pub fn record(rec: &Record) -> bool {
+    let l = log();
+    unsafe {
+        let n = (*l).count as usize;
+        if (*l).magic != MAGIC || n >= CAPACITY {
+            return false;
+        }
+        let bytes = rec.to_bytes();
+        let mut h = Sha256::new();
+        h.update(&(*l).digest);
+        h.update(&bytes);
+        (*l).records[n] = bytes;
+        (*l).digest = h.finish();
+        (*l).count = n as u32 + 1;
+    }
+    true
+}
+
+/// Address and size (header included) of the log.
+pub fn region() -> (usize, usize) {
+    (log() as usize, core::mem::size_of::<Log>())
+}
+
+/// Records written and the rolling digest so far.
+pub fn summary() -> (u32, [u8; SHA256_LEN]) {
+    let l = log();
+    unsafe { ((*l).count, (*l).digest) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // The log is a static: one test at a time.
+    static LOG_LOCK: Mutex<()> = Mutex::new(());
+
+    // What the next stage makes of the log at region(): the record count
+    // if its header is sound and the records chain up to its digest.
+    fn verify() -> Option<usize> {
+        let (base, size) = region();
+        let b = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+        let u32_at = |off: usize| u32::from_le_bytes(b[off..off + 4].try_into().unwrap()) as usize;
+        if u32_at(0) != MAGIC as usize || u32_at(0x0c) != RECORD_SIZE {
+            return None;
+        }
+        let count = u32_at(0x04);
+        let records = b[0x30..].chunks(RECORD_SIZE).take(count.min(u32_at(0x08)));
+        let digest = records.fold([0; SHA256_LEN], |digest, rec| {
+            let mut h = Sha256::new();
+            h.update(&digest);
+            h.update(rec);
+            h.finish()
+        });
+        (digest[..] == b[0x10..0x30]).then_some(count)
+    }
+
+    fn records() -> [Record; 3] {
+        let mut scan = Record::new(Event::MetaScan, 1);
+        scan.result = 2;
+        scan.value = 17;
+        let mut digest = Record::new(Event::ImageDigest, 1);
+        digest.digest = [0x5a; SHA256_LEN];
+        [Record::new(Event::SplStart, SLOT_NONE), scan, digest]
+    }
+
+    #[test]
+    fn chain() {
+        let _lock = LOG_LOCK.lock().unwrap();
+        init();
+        assert_eq!(verify(), Some(0));
+        assert_eq!(summary(), (0, [0; SHA256_LEN]));
+        let mut digests = Vec::new();
+        for rec in &records() {
+            assert!(record(rec));
+            digests.push(summary().1);
+        }
+        assert_eq!(verify(), Some(3));
+        assert_eq!(summary().0, 3);
+        // Each digest chains the one before.
+        let mut h = Sha256::new();
+        h.update(&digests[1]);
+        h.update(&records()[2].to_bytes());
+        assert_eq!(h.finish(), digests[2]);
+
+        // Starting over gives the same chain.
+        init();
+        for rec in &records() {
+            record(rec);
+        }
+        assert_eq!(summary(), (3, digests[2]));
+    }
+
+    // A record changed after the fact, or dropped, breaks the chain.
+    #[test]
+    fn broken_link() {
+        let _lock = LOG_LOCK.lock().unwrap();
+        for (n, byte) in [(0, 0), (1, 0x08), (2, 0x18 + 31)] {
+            init();
+            for rec in &records() {
+                record(rec);
+            }
+            unsafe { (*log()).records[n][byte] ^= 1 };
+            assert_eq!(verify(), None, "record {} byte {}", n, byte);
+        }
+        init();
+        for rec in &records() {
+            record(rec);
+        }
+        unsafe { (*log()).count = 2 };
+        assert_eq!(verify(), None);
+    }
+
+    // Past the capacity, records are dropped and the digest stays.
+    #[test]
+    fn full() {
+        let _lock = LOG_LOCK.lock().unwrap();
+        init();
+        for i in 0..CAPACITY {
+            assert!(record(&Record::new(Event::Verdict, i as u32)));
+        }
+        let full = summary();
+        assert!(!record(&Record::new(Event::Jump, 0)));
+        assert_eq!(summary(), full);
+        assert_eq!(verify(), Some(CAPACITY));
+
+        unsafe { (*log()).magic = 0 };
+        assert!(!record(&Record::new(Event::Jump, 0)));
+        assert_eq!(verify(), None);
+    }
+}
@@ -1,178 +1,2036 @@
+use core::cell::Cell;
+use core::marker::PhantomData;
 use core::result::Result;
+use crate::addr::FlashOffset;
+use crate::clint;
 use crate::flash_intel::{FlashError, IntelFlash};
-use crate::slog; // slog! macro
+use crate::{slog_debug, slog_error, slog_info, slog_warn};
 
-/// Which bank we booted from / are about to try.
+// The boot log: an append-only log of boot attempts in one NOR block,
+// for the bank choice. What it does when the block fills up, when a
+// compaction fails or is cut off, when the block is locked, and what
+// the boot override is for, is in README.md. The block holds:
+//
+//   word 0     header: 0xC0 | flags in the top byte, the compactions
+//              (erases of the block) so far in the rest. Flags: 0x01
+//              bit strikes, 0x02 / 0x03 the 16-bit / extended codecs,
+//              0x04 entries still being written back. Top byte 0x40
+//              (bit 31 cleared): poisoned, reads as empty. 0x80 (bit 30
+//              cleared): cut off mid-erase, reads as empty and full.
+//              No header: an older SPL's log, in 32-bit tokens.
+//   entries    one per attempt, as the codec writes it (EntryCodec),
+//              erased past the last one. A 32-bit token is the bank's
+//              tag | 0b11 (A: 0xAAAA_AAAB with the default table), bit
+//              0 cleared once handed off, bit 1 once confirmed. A
+//              bank's legacy token (0x1111_1111 / 0x0000_0000 for A /
+//              B) is an older SPL's attempt, handed off, unconfirmed.
+//   last word  boot override: 0xFFFF_FFFF none, 0x5AFE_00nn the bank of
+//              index nn, 0x5AFE_00FF the golden image, 0 consumed.
+//
+// Words are programmed with program_u32_le(): a write cut short leaves
+// the top byte erased, which no token has, so scan() ends the log
+// there. A bit-strike log ("bitstrike-log") splits the words between
+// the header and the override into equal areas, one per bank, then
+// handoff and confirm: attempt n clears bit n of its bank's area, then
+// bit n of the other two as it gets that far. Entry indices are attempt
+// numbers there, each read as the token it stands for.
+
+/// Most banks a bank table may have.
+pub const MAX_BANKS: usize = 8;
+
+/// One bank (firmware slot) of the boot scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankDesc {
+    /// Short name: logs, the console, /chosen "spl,boot-bank".
+    pub name: &'static str,
+    /// Log token of the bank with the two state bits clear (see
+    /// EntryState). Its top byte must not be 0xFF (see the layout above).
+    pub tag: u32,
+    /// Token older SPLs wrote for this bank, if any.
+    pub legacy: Option<u32>,
+    /// Lower is tried first while it has trials left.
+    pub priority: u8,
+    /// Where the image is: flash unit, offset and size.
+    pub unit: usize,
+    pub offset: FlashOffset,
+    pub size: usize,
+}
+
+/// Which bank we booted from / are about to try: an index into
+/// banks::table(), the table spl_main hands to BootMeta::new().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootBank(pub u8);
+
+impl BootBank {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn desc(self) -> &'static BankDesc {
+        &crate::banks::table()[self.index()]
+    }
+}
+
+impl core::fmt::Display for BootBank {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.desc().name)
+    }
+}
+
+/// How far a boot attempt got. Each step clears one more bit of its
+/// log entry, so it is a 1→0 write over the same word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryState {
+    /// Recorded by the SPL, never handed off: the SPL itself died.
+    Started,
+    /// The SPL jumped into the payload, which never confirmed.
+    HandedOff,
+    /// The payload (or whoever runs after it) confirmed the boot.
+    Confirmed,
+}
+
+impl EntryState {
+    const MASK: u32 = 0b11;
+    const BIT_NOT_HANDED_OFF: u32 = 1 << 0;
+    const BIT_NOT_CONFIRMED: u32 = 1 << 1;
+
+    // The two state bits of a token: set while the attempt hasn't got
+    // that far.
+    fn bits(self) -> u32 {
+        match self {
+            EntryState::Started => Self::BIT_NOT_HANDED_OFF | Self::BIT_NOT_CONFIRMED,
+            EntryState::HandedOff => Self::BIT_NOT_CONFIRMED,
+            EntryState::Confirmed => 0,
+        }
+    }
+
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits & Self::MASK {
+            0b11 => Some(EntryState::Started),
+            0b10 => Some(EntryState::HandedOff),
+            0b00 => Some(EntryState::Confirmed),
+            // Confirmed without a handoff: not something we write.
+            _ => None,
+        }
+    }
+}
+
+/// What a boot log entry records.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum BootBank {
-    A,
-    B,
-}
-
-/// Simple append-only log of boot attempts, stored in NOR flash.
-///
-/// Layout in the metadata region:
-///   - each entry is a 32-bit word
-///   - 0xFFFF_FFFF = erased/unused
-///   - 0x1111_1111 = "booted bank A"
-///   - 0x0000_0000 = "booted bank B"
-///
-/// The log grows by appending words; compaction is theoretically
-/// supported but depends on block_erase() being implemented.
-pub struct BootMeta<'a> {
+pub enum EntryKind {
+    /// Never written: the log ends there.
+    Erased,
+    /// An attempt of a bank, and how far it got.
+    Attempt(BootBank, EntryState),
+    /// Anything else, a torn write included: the log ends there too.
+    Unknown,
+}
+
+/// How a token log writes one boot attempt (see BootMeta): the entry's
+/// size and its bit patterns. BootMeta takes its codec as a type
+/// parameter, so the choice costs nothing at run time.
+pub trait EntryCodec {
+    /// Bytes per entry, 2 or 4: one value programmed at a multiple of
+    /// its size. Each state is reached from the one before by clearing
+    /// bits of it.
+    const SIZE: usize;
+    /// Its bit in the top byte of the log's header (none for the 32-bit
+    /// tokens older SPLs wrote without a header).
+    const HEADER_FLAG: u32;
+    /// Erased flash, as an entry.
+    const ERASED: u32 = u32::MAX >> (32 - 8 * Self::SIZE as u32);
+    /// Neither erased nor an attempt: what an entry two banks struck in
+    /// a bit-strike log reads as.
+    const CONFLICT: u32;
+    /// For the logs and the console.
+    const NAME: &'static str;
+
+    /// The entry of an attempt of `bank` (in table `banks`) that got as
+    /// far as `state`, in the low SIZE bytes.
+    fn encode(banks: &[BankDesc], bank: BootBank, state: EntryState) -> u32;
+
+    /// What `raw`, the SIZE bytes of an entry as read, records.
+    fn decode(banks: &[BankDesc], raw: &[u8]) -> EntryKind;
+}
+
+/// 32-bit entries: the bank's tag with the two state bits, as in the
+/// layout at the top of this file. Older SPLs' legacy tokens read as handed
+/// off.
+#[cfg_attr(any(feature = "meta-x16", feature = "meta-ext"), allow(dead_code))]
+pub struct Token32;
+
+impl EntryCodec for Token32 {
+    const SIZE: usize = 4;
+    const HEADER_FLAG: u32 = 0;
+    // No token has 0xFF in its top byte.
+    const CONFLICT: u32 = 0xFFFF_0000;
+    const NAME: &'static str = "token";
+
+    fn encode(banks: &[BankDesc], bank: BootBank, state: EntryState) -> u32 {
+        banks[bank.index()].tag | state.bits()
+    }
+
+    fn decode(banks: &[BankDesc], raw: &[u8]) -> EntryKind {
+        let Ok(raw) = raw.try_into() else {
+            return EntryKind::Unknown;
+        };
+        let word = u32::from_le_bytes(raw);
+        if word == Self::ERASED {
+            return EntryKind::Erased;
+        }
+        let find = |f: &dyn Fn(&BankDesc) -> bool| banks.iter().position(f).map(|i| BootBank(i as u8));
+        if let Some(bank) = find(&|b| b.legacy == Some(word)) {
+            return EntryKind::Attempt(bank, EntryState::HandedOff);
+        }
+        match (find(&|b| b.tag == word & !EntryState::MASK), EntryState::from_bits(word)) {
+            (Some(bank), Some(state)) => EntryKind::Attempt(bank, state),
+            _ => EntryKind::Unknown,
+        }
+    }
+}
+
+/// 16-bit entries, half the flash of Token32 per attempt: 0xA0 | the
+/// bank's index in the top byte, 0xFC | the two state bits in the low
+/// one (0xA0FF, 0xA0FE, 0xA0FC for the first bank). One program cycle on
+/// an x16 part; on an x8 one a write cut short leaves the top byte
+/// erased, which no entry has. The bank tags aren't used.
+#[cfg_attr(not(feature = "meta-x16"), allow(dead_code))]
+pub struct Token16;
+
+#[cfg_attr(not(feature = "meta-x16"), allow(dead_code))]
+impl Token16 {
+    const BANK_TAG: u16 = 0xA0;
+    const BANK_MASK: u16 = 0xF8;
+    // The low byte's bits above the state ones, left erased.
+    const FILL: u16 = 0xFC;
+}
+
+impl EntryCodec for Token16 {
+    const SIZE: usize = 2;
+    const HEADER_FLAG: u32 = 0x02;
+    const CONFLICT: u32 = 0;
+    const NAME: &'static str = "16-bit token";
+
+    fn encode(_banks: &[BankDesc], bank: BootBank, state: EntryState) -> u32 {
+        u32::from((Self::BANK_TAG | u16::from(bank.0)) << 8 | Self::FILL) | state.bits()
+    }
+
+    fn decode(banks: &[BankDesc], raw: &[u8]) -> EntryKind {
+        let Ok(raw) = raw.try_into() else {
+            return EntryKind::Unknown;
+        };
+        let entry = u16::from_le_bytes(raw);
+        if u32::from(entry) == Self::ERASED {
+            return EntryKind::Erased;
+        }
+        let top = entry >> 8;
+        let index = usize::from(top & !Self::BANK_MASK);
+        match EntryState::from_bits(entry.into()) {
+            Some(state) if top & Self::BANK_MASK == Self::BANK_TAG && entry & Self::FILL == Self::FILL
+                && index < banks.len() =>
+            {
+                EntryKind::Attempt(BootBank(index as u8), state)
+            }
+            _ => EntryKind::Unknown,
+        }
+    }
+}
+
+/// Extended 32-bit entries, from the top byte down: 0xE0 | the bank's
+/// index, that byte's complement as its check, a reason code, then 0xFC
+/// | the two state bits (0xE01FFFFF, 0xE01FFFFE, 0xE01FFFFC for the
+/// first bank). The SPL leaves the reason erased: it is for a payload or
+/// a later stage to record why an attempt failed, by clearing bits of
+/// it, and reads the same to the log. A bank byte and check that don't
+/// pair up (a torn write, a flipped bit) read as Unknown.
+#[cfg_attr(not(feature = "meta-ext"), allow(dead_code))]
+pub struct TokenExt;
+
+#[cfg_attr(not(feature = "meta-ext"), allow(dead_code))]
+impl TokenExt {
+    const BANK_TAG: u32 = 0xE0;
+    const BANK_MASK: u32 = 0xF8;
+    // The low byte's bits above the state ones, left erased.
+    const FILL: u32 = 0xFC;
+}
+
+impl EntryCodec for TokenExt {
+    const SIZE: usize = 4;
+    const HEADER_FLAG: u32 = 0x03;
+    const CONFLICT: u32 = 0;
+    const NAME: &'static str = "extended token";
+
+    fn encode(_banks: &[BankDesc], bank: BootBank, state: EntryState) -> u32 {
+        let top = Self::BANK_TAG | u32::from(bank.0);
+        top << 24 | (!top & 0xFF) << 16 | 0xFF << 8 | Self::FILL | state.bits()
+    }
+
+    fn decode(banks: &[BankDesc], raw: &[u8]) -> EntryKind {
+        let Ok(raw) = raw.try_into() else {
+            return EntryKind::Unknown;
+        };
+        let entry = u32::from_le_bytes(raw);
+        if entry == Self::ERASED {
+            return EntryKind::Erased;
+        }
+        let top = entry >> 24;
+        let index = (top & !Self::BANK_MASK) as usize;
+        let tagged = top & Self::BANK_MASK == Self::BANK_TAG && entry >> 16 & 0xFF == !top & 0xFF;
+        match EntryState::from_bits(entry) {
+            Some(state) if tagged && entry & Self::FILL == Self::FILL && index < banks.len() =>
+            {
+                EntryKind::Attempt(BootBank(index as u8), state)
+            }
+            _ => EntryKind::Unknown,
+        }
+    }
+}
+
+#[cfg(all(feature = "meta-x16", feature = "meta-ext"))]
+compile_error!("meta-x16 and meta-ext both pick the boot log's codec");
+
+/// The codec of this build's boot log: Token16 with the "meta-x16"
+/// feature, TokenExt with "meta-ext", Token32 otherwise.
+#[cfg(not(any(feature = "meta-x16", feature = "meta-ext")))]
+pub type LogCodec = Token32;
+#[cfg(feature = "meta-x16")]
+pub type LogCodec = Token16;
+#[cfg(all(feature = "meta-ext", not(feature = "meta-x16")))]
+pub type LogCodec = TokenExt;
+
+/// Attempts of one bank, by how far they got.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BankTrials {
+    pub no_handoff: u32,
+    pub unconfirmed: u32,
+    pub confirmed: u32,
+}
+
+impl BankTrials {
+    /// Attempts the bank itself is blamed for: handed off and never
+    /// confirmed. SPL-side failures don't count against it.
+    pub fn failed(&self) -> u32 {
+        self.unconfirmed
+    }
+
+    fn count(&mut self, state: EntryState) {
+        match state {
+            EntryState::Started => self.no_handoff += 1,
+            EntryState::HandedOff => self.unconfirmed += 1,
+            EntryState::Confirmed => self.confirmed += 1,
+        }
+    }
+}
+
+/// A bank choice forced for one boot, whatever the trial counts (see
+/// BootMeta::boot_override()).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForceBoot {
+    Bank(BootBank),
+    Golden,
+}
+
+impl core::fmt::Display for ForceBoot {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ForceBoot::Bank(bank) => write!(f, "bank {}", bank),
+            ForceBoot::Golden => f.write_str("golden"),
+        }
+    }
+}
+
+/// What init() found in the block, and made of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaInit {
+    /// A log, ours or an older SPL's: left as it is.
+    Valid,
+    /// Erased: it got its header.
+    Initialized,
+    /// A header cut short by a power cut: erased and initialized again.
+    Redone,
+    /// Erased or cut short, but no NOR writes allowed: left as it is.
+    Blank,
+    /// Neither a log nor erased: poisoned.
+    Corrupt,
+}
+
+/// What scan() found in the log.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Trials {
+    /// By bank index; unused past the table's length.
+    pub banks: [BankTrials; MAX_BANKS],
+    /// Index of the first free entry.
+    pub next_idx: usize,
+    /// The newest entry, if any.
+    pub last: Option<(BootBank, EntryState)>,
+}
+
+impl Trials {
+    pub fn bank(&self, bank: BootBank) -> &BankTrials {
+        &self.banks[bank.index()]
+    }
+
+    fn bank_mut(&mut self, bank: BootBank) -> &mut BankTrials {
+        &mut self.banks[bank.index()]
+    }
+}
+
+/// Part of a compaction's write-back: `count` values of `width` bytes
+/// (2 or 4), all `value`, one after the other from `offset` bytes into
+/// the block on. Laid out for SPL2 (see spl2.rs).
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RewriteRun {
+    pub offset: u32,
+    pub width: u32,
+    pub count: u32,
+    pub value: u32,
+}
+
+/// Runs of a write-back at most: the header marked rewriting, the
+/// override, two per bank, the header done and an entry appended.
+pub const REWRITE_RUNS: usize = 4 + 2 * MAX_BANKS;
+
+/// What a compaction writes once its erase is over, run by run in
+/// order (see BootMeta::hand_off_compaction()).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Rewrite {
+    runs: [RewriteRun; REWRITE_RUNS],
+    len: usize,
+}
+
+impl Rewrite {
+    pub fn runs(&self) -> &[RewriteRun] {
+        &self.runs[..self.len]
+    }
+
+    fn push(&mut self, offset: usize, width: usize, count: u32, value: u32) {
+        if count > 0 {
+            let (offset, width) = (offset as u32, width as u32);
+            self.runs[self.len] = RewriteRun { offset, width, count, value };
+            self.len += 1;
+        }
+    }
+
+    // The `width` bytes at `offset` once it is all written: erased but
+    // where a run programs them.
+    fn read(&self, offset: usize, width: usize) -> u32 {
+        let mut raw = [0xFFu8; 4];
+        for (at, byte) in (offset..).zip(&mut raw[..width]) {
+            for run in self.runs() {
+                let (start, width) = (run.offset as usize, run.width as usize);
+                if (start..start + run.count as usize * width).contains(&at) {
+                    *byte &= run.value.to_le_bytes()[(at - start) % width];
+                }
+            }
+        }
+        u32::from_le_bytes(raw)
+    }
+
+    // A write once the write-back is handed off: only over one value it
+    // programs by itself (the entry appended, say), which it then
+    // programs as written.
+    fn write(&mut self, offset: usize, width: usize, value: u32) -> Result<(), FlashError> {
+        let run = self.runs[..self.len]
+            .iter_mut()
+            .rev()
+            .find(|r| r.offset as usize == offset && r.width as usize == width && r.count == 1)
+            .ok_or(FlashError::ProgramError)?;
+        run.value &= value;
+        Ok(())
+    }
+}
+
+/// The boot log in one NOR block, laid out as described at the top of
+/// this file. scan() counts each bank's trials; record_boot(),
+/// record_handoff() and the payload's record_success() append an
+/// attempt and move it along. A full log is compacted: the block erased
+/// and the unconfirmed attempts written back, in the background if the
+/// boot can't wait. A compaction that fails halfway poisons the log,
+/// and a block that refuses writes makes it read-only: either way it
+/// stops writing (see README.md).
+pub struct BootMeta<'a, C: EntryCodec = LogCodec> {
     flash: &'a IntelFlash,
-    meta_offset: usize,
+    meta_offset: FlashOffset,
     meta_size: usize,
+    banks: &'a [BankDesc],
+    poisoned: Cell<bool>,
+    read_only: Cell<bool>,
+    compacting: Cell<Option<Compaction>>,
+    /// Its write-back once hand_off_compaction() gave it to the next
+    /// stage.
+    handed_off: Cell<Option<Rewrite>>,
+    codec: PhantomData<C>,
+}
+
+// How the entries of a block are written (see the layout above).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tokens,
+    Strikes,
+    /// Tokens of another codec than this build's (entry width or
+    /// layout).
+    OtherWidth,
 }
 
-impl<'a> BootMeta<'a> {
+impl Format {
+    // The one this build writes.
+    const WRITES: Format = if cfg!(feature = "bitstrike-log") { Format::Strikes } else { Format::Tokens };
+
+    fn name<C: EntryCodec>(self) -> &'static str {
+        match self {
+            Format::Tokens => C::NAME,
+            Format::Strikes => "bit-strike",
+            Format::OtherWidth => "other codec's token",
+        }
+    }
+}
+
+// A compaction whose erase is under way: what goes back in the block
+// once it is over.
+#[derive(Debug, Clone, Copy)]
+struct Compaction {
+    count: u32,
+    override_word: Option<u32>,
+    trials: Trials,
+    /// An entry to append after the rewritten ones: index and token.
+    append: Option<(usize, u32)>,
+}
+
+impl<'a, C: EntryCodec> BootMeta<'a, C> {
     const ERASED_WORD: u32 = 0xFFFF_FFFF;
-    const TOKEN_BANK_A: u32 = 0x1111_1111;
-    const TOKEN_BANK_B: u32 = 0x0000_0000;
+    const HEADER_TAG: u32 = 0xC0;
+    const POISON_TAG: u32 = 0x40;
+    const ERASING_TAG: u32 = 0x80;
+    // In the header's top byte: a bit-strike log.
+    const STRIKES_FLAG: u32 = 0x01;
+    // Every encoding flag of the header's top byte: the above, and the
+    // codecs'.
+    const HEADER_FLAGS: u32 = 0x03;
+    // In the header's top byte too: the compaction that wrote it is
+    // still writing the log back.
+    const REWRITING_FLAG: u32 = 0x04;
+    // Handoff and confirm areas, after the banks'.
+    const STRIKE_AREAS: usize = 2;
+    const HEADER_COUNT_MASK: u32 = 0x00FF_FFFF;
+    const OVERRIDE_NONE: u32 = 0xFFFF_FFFF;
+    const OVERRIDE_CONSUMED: u32 = 0;
+    const OVERRIDE_TAG: u32 = 0x5AFE_0000;
+    const OVERRIDE_GOLDEN: u32 = 0xFF;
+    // init(): words at the start of the block checked one by one, and
+    // how many more it samples across the rest.
+    const BLANK_HEAD_WORDS: usize = 16;
+    const BLANK_SAMPLES: usize = 64;
 
+    /// The header, the override word and the bit-strike words.
     pub const WORD_SIZE: usize = core::mem::size_of::<u32>();
+    /// Bytes per token entry.
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    pub const ENTRY_SIZE: usize = C::SIZE;
+    // Token entries the header takes up.
+    const HEADER_ENTRIES: usize = Self::WORD_SIZE / C::SIZE;
 
-    pub const fn new(
+    /// The boot log in the block at `meta_offset` of `flash`, which
+    /// must be exactly one whole block of the device: a compaction
+    /// erases it. OutOfRange (logged) if it isn't. `banks` is the bank
+    /// table, 1 to MAX_BANKS entries with distinct tags.
+    pub fn new(
         flash: &'a IntelFlash,
-        meta_offset: usize,
+        meta_offset: FlashOffset,
         meta_size: usize,
-    ) -> Self {
-        BootMeta {
+        banks: &'a [BankDesc],
+    ) -> Result<Self, FlashError> {
+        assert!(Self::valid_table(banks), "bad boot bank table");
+        let fits = meta_offset.get().checked_add(meta_size).is_some_and(|end| end <= flash.size);
+        if !fits || meta_size != flash.block_size || !meta_offset.is_aligned(flash.block_size) {
+            slog_error!(
+                "ERROR: boot log at 0x{:x}, {} bytes: not one block ({} KiB) of flash at 0x{:x} ({} KiB)",
+                meta_offset,
+                meta_size,
+                flash.block_size / 1024,
+                flash.base,
+                flash.size / 1024
+            );
+            return Err(FlashError::OutOfRange);
+        }
+        Ok(BootMeta {
             flash,
             meta_offset,
             meta_size,
+            banks,
+            poisoned: Cell::new(false),
+            read_only: Cell::new(false),
+            compacting: Cell::new(None),
+            handed_off: Cell::new(None),
+            codec: PhantomData,
+        })
+    }
+
+    /// Whether `banks` can be used: not too long, and no word of the log
+    /// could be read as two different things.
+    pub const fn valid_table(banks: &[BankDesc]) -> bool {
+        if banks.is_empty() || banks.len() > MAX_BANKS {
+            return false;
+        }
+        let mut i = 0;
+        while i < banks.len() {
+            let tag = banks[i].tag;
+            let top = tag >> 24;
+            if tag & EntryState::MASK != 0
+                || top == 0xFF
+                || Self::header_tag(tag) == Self::HEADER_TAG
+                || Self::header_tag(tag) == Self::POISON_TAG
+                || Self::header_tag(tag) == Self::ERASING_TAG
+            {
+                return false;
+            }
+            let mut j = i + 1;
+            while j < banks.len() {
+                if banks[j].tag == tag {
+                    return false;
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    /// Every bank of the table, in table order.
+    pub fn banks(&self) -> impl Iterator<Item = BootBank> + '_ {
+        (0..self.banks.len()).map(|i| BootBank(i as u8))
+    }
+
+    /// The bank called `name` (any case).
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    pub fn bank_by_name(&self, name: &str) -> Option<BootBank> {
+        self.banks().find(|b| self.banks[b.index()].name.eq_ignore_ascii_case(name))
+    }
+
+    // Bank indices sorted by priority, the table order breaking ties.
+    fn by_priority(&self) -> ([BootBank; MAX_BANKS], usize) {
+        let mut order = [BootBank(0); MAX_BANKS];
+        let n = self.banks.len();
+        for (i, b) in self.banks().enumerate() {
+            order[i] = b;
+            let mut j = i;
+            while j > 0 && self.banks[order[j - 1].index()].priority > self.banks[b.index()].priority {
+                order.swap(j - 1, j);
+                j -= 1;
+            }
         }
+        (order, n)
+    }
+
+    /// `first`, then every other bank by priority: the order to try them
+    /// in when `first` is the one chosen.
+    pub fn fallback_order(&self, first: BootBank) -> impl Iterator<Item = BootBank> {
+        let (order, n) = self.by_priority();
+        core::iter::once(first).chain((0..n).map(move |i| order[i]).filter(move |&b| b != first))
     }
 
+    // Words of the block but the override word.
     fn words_capacity(&self) -> usize {
-        self.meta_size / Self::WORD_SIZE
+        self.meta_size / Self::WORD_SIZE - 1
+    }
+
+    fn override_idx(&self) -> usize {
+        self.meta_size / Self::WORD_SIZE - 1
+    }
+
+    /// The pending boot override, if any: Ok if it names something we
+    /// can boot, Err with the word if it is neither that nor erased or
+    /// consumed.
+    pub fn boot_override(&self) -> Option<Result<ForceBoot, u32>> {
+        let w = self.read_word(self.override_idx());
+        if w == Self::OVERRIDE_NONE || w == Self::OVERRIDE_CONSUMED {
+            return None;
+        }
+        if w & !0xFF != Self::OVERRIDE_TAG {
+            return Some(Err(w));
+        }
+        Some(match w & 0xFF {
+            Self::OVERRIDE_GOLDEN => Ok(ForceBoot::Golden),
+            i if (i as usize) < self.banks.len() => Ok(ForceBoot::Bank(BootBank(i as u8))),
+            _ => Err(w),
+        })
     }
 
-    fn word_offset(&self, idx: usize) -> usize {
-        self.meta_offset + idx * Self::WORD_SIZE
+    /// Mark the boot override consumed, whatever it held.
+    pub fn consume_override(&self) -> Result<(), FlashError> {
+        self.write_word(self.override_idx(), Self::OVERRIDE_CONSUMED)
+    }
+
+    // The `width` bytes (2 or 4) at `offset` into the block. Both this
+    // and write_at() finish a compaction left in the background first (a
+    // failed one poisons the log, which is what the caller then sees).
+    // A compaction handed off reads as it will once written back.
+    fn read_at(&self, offset: usize, width: usize) -> u32 {
+        let _ = self.finish_compaction();
+        if let Some(rewrite) = self.handed_off.get() {
+            return rewrite.read(offset, width);
+        }
+        let mut raw = [0u8; 4];
+        self.flash.read_slice(self.meta_offset + offset, &mut raw[..width]);
+        u32::from_le_bytes(raw)
+    }
+
+    fn write_at(&self, offset: usize, width: usize, value: u32) -> Result<(), FlashError> {
+        let _ = self.finish_compaction();
+        self.writable()?;
+        if let Some(mut rewrite) = self.handed_off.get() {
+            rewrite.write(offset, width, value)?;
+            self.handed_off.set(Some(rewrite));
+            return Ok(());
+        }
+        let at = self.meta_offset + offset;
+        match width {
+            2 => self.flash.program_u16_le(at, value as u16),
+            _ => self.flash.program_u32_le(at, value),
+        }
+        .map_err(|e| self.locked(e))
     }
 
     fn read_word(&self, idx: usize) -> u32 {
-        self.flash.read_u32_le(self.word_offset(idx))
+        self.read_at(idx * Self::WORD_SIZE, Self::WORD_SIZE)
     }
 
     fn write_word(&self, idx: usize, value: u32) -> Result<(), FlashError> {
-        let bytes = value.to_le_bytes();
-        self.flash.program(self.word_offset(idx), &bytes)
+        self.write_at(idx * Self::WORD_SIZE, Self::WORD_SIZE, value)
     }
 
-    /// Scan the metadata area and count how many times each bank appears,
-    /// and where the next free entry is.
-    ///
-    /// Returns: (a_count, b_count, next_free_index)
-    pub fn scan(&self) -> (u32, u32, usize) {
-        let mut a_count = 0u32;
-        let mut b_count = 0u32;
-        let mut idx = 0usize;
-        let cap = self.words_capacity();
-
-        while idx < cap {
-            let w = self.read_word(idx);
-            if w == Self::ERASED_WORD {
-                break;
-            } else if w == Self::TOKEN_BANK_A {
-                a_count += 1;
-            } else if w == Self::TOKEN_BANK_B {
-                b_count += 1;
-            } else {
+    // Like read_word() and write_word(), for token entries: the header
+    // is the first entries' room.
+    fn read_entry(&self, idx: usize) -> u32 {
+        self.read_at(idx * C::SIZE, C::SIZE)
+    }
+
+    fn write_entry(&self, idx: usize, value: u32) -> Result<(), FlashError> {
+        self.write_at(idx * C::SIZE, C::SIZE, value)
+    }
+
+    // MetaReadOnly once the log is read-only: no more flash writes.
+    fn writable(&self) -> Result<(), FlashError> {
+        match self.read_only.get() {
+            true => Err(FlashError::MetaReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // A write or erase of the block failed with `e`: if the block is
+    // locked, the log is read-only from now on (see BootMeta).
+    fn locked(&self, e: FlashError) -> FlashError {
+        if e != FlashError::Protected {
+            return e;
+        }
+        self.set_read_only("its block is write-protected");
+        FlashError::MetaReadOnly
+    }
+
+    /// Make the log read-only for good (see BootMeta), `why` saying so
+    /// in the one notice this logs.
+    pub fn set_read_only(&self, why: &str) {
+        if !self.read_only.replace(true) {
+            slog_info!("boot log read-only ({}): nothing recorded, banks chosen from its counts", why);
+        }
+    }
+
+    /// Whether the log is read-only (see BootMeta).
+    pub fn read_only(&self) -> bool {
+        self.read_only.get()
+    }
+
+    fn token(&self, bank: BootBank, state: EntryState) -> u32 {
+        C::encode(self.banks, bank, state)
+    }
+
+    // The top byte of a header word, its flags left out.
+    const fn header_tag(word: u32) -> u32 {
+        word >> 24 & !(Self::HEADER_FLAGS | Self::REWRITING_FLAG)
+    }
+
+    // How the block's entries are written: as its header says, or as
+    // this build writes them in a blank block. A log without a header
+    // that isn't blank is an older SPL's, in 32-bit tokens (the codec
+    // without a flag).
+    fn format(&self) -> Format {
+        let w = self.read_word(0);
+        let flags = match self.compaction_count() {
+            _ if w == Self::ERASED_WORD => return Format::WRITES,
+            Some(_) => w >> 24 & Self::HEADER_FLAGS,
+            None => 0,
+        };
+        match flags {
+            Self::STRIKES_FLAG => Format::Strikes,
+            f if f == C::HEADER_FLAG => Format::Tokens,
+            _ => Format::OtherWidth,
+        }
+    }
+
+    // Where entries start in a block with a header.
+    fn entries_from(format: Format) -> usize {
+        match format {
+            Format::Tokens | Format::OtherWidth => Self::HEADER_ENTRIES,
+            Format::Strikes => 0,
+        }
+    }
+
+    // Index of the first entry, past the header if there is one.
+    fn first_entry(&self, format: Format) -> usize {
+        match format {
+            Format::Tokens | Format::OtherWidth if self.compaction_count().is_some() => Self::HEADER_ENTRIES,
+            _ => 0,
+        }
+    }
+
+    // Entries the block can hold (header included for tokens: entry
+    // indices count from its start).
+    fn capacity(&self, format: Format) -> usize {
+        match format {
+            Format::Tokens | Format::OtherWidth => (self.meta_size - Self::WORD_SIZE) / C::SIZE,
+            Format::Strikes => self.area_words() * 32,
+        }
+    }
+
+    // Words of each strike area: the banks', then handoff and confirm.
+    fn area_words(&self) -> usize {
+        (self.words_capacity() - 1) / (self.banks.len() + Self::STRIKE_AREAS)
+    }
+
+    fn handoff_area(&self) -> usize {
+        self.banks.len()
+    }
+
+    fn confirm_area(&self) -> usize {
+        self.banks.len() + 1
+    }
+
+    // The word of strike area `area` holding bit `idx`, and the bit.
+    fn strike_bit(&self, area: usize, idx: usize) -> (usize, u32) {
+        (1 + area * self.area_words() + idx / 32, 1 << (idx % 32))
+    }
+
+    fn struck(&self, area: usize, idx: usize) -> bool {
+        let (word, bit) = self.strike_bit(area, idx);
+        self.read_word(word) & bit == 0
+    }
+
+    fn strike(&self, area: usize, idx: usize) -> Result<(), FlashError> {
+        let (word, bit) = self.strike_bit(area, idx);
+        let w = self.read_word(word);
+        if w & bit == 0 {
+            return Ok(());
+        }
+        self.write_word(word, w & !bit)
+    }
+
+    // Entry `idx` as a token: the entry itself in a token log, the
+    // token its strikes stand for in a bit-strike one. Nothing of a log
+    // of another codec.
+    fn entry(&self, format: Format, idx: usize) -> u32 {
+        match format {
+            Format::Tokens => return self.read_entry(idx),
+            Format::OtherWidth => return C::ERASED,
+            Format::Strikes => {}
+        }
+        let mut banks = self.banks().filter(|b| self.struck(b.index(), idx));
+        match (banks.next(), banks.next()) {
+            (None, _) => C::ERASED,
+            (Some(bank), None) => {
+                let handed_off = self.struck(self.handoff_area(), idx);
+                let state = match (handed_off, self.struck(self.confirm_area(), idx)) {
+                    (false, _) => EntryState::Started,
+                    (true, false) => EntryState::HandedOff,
+                    (true, true) => EntryState::Confirmed,
+                };
+                self.token(bank, state)
+            }
+            _ => C::CONFLICT,
+        }
+    }
+
+    // Write entry `idx` as `token` (one of ours), or move it on to it.
+    // Strikes go bank, handoff, confirm: one cut short reads as the
+    // state before.
+    fn put_entry(&self, format: Format, idx: usize, token: u32) -> Result<(), FlashError> {
+        if format != Format::Strikes {
+            return self.write_entry(idx, token);
+        }
+        let (bank, state) = self.decode(token).ok_or(FlashError::ProgramError)?;
+        let areas = [bank.index(), self.handoff_area(), self.confirm_area()];
+        let n = match state {
+            EntryState::Started => 1,
+            EntryState::HandedOff => 2,
+            EntryState::Confirmed => 3,
+        };
+        areas[..n].iter().try_for_each(|&area| self.strike(area, idx))
+    }
+
+    // What a token records, by the codec.
+    fn kind(&self, token: u32) -> EntryKind {
+        C::decode(self.banks, &token.to_le_bytes()[..C::SIZE])
+    }
+
+    /// The bank and state a log entry records, None if it isn't a token.
+    pub fn decode(&self, token: u32) -> Option<(BootBank, EntryState)> {
+        match self.kind(token) {
+            EntryKind::Attempt(bank, state) => Some((bank, state)),
+            EntryKind::Erased | EntryKind::Unknown => None,
+        }
+    }
+
+    /// Number of times the block was compacted (erased), None if the log
+    /// has no header yet. A poisoned log keeps its count.
+    pub fn compaction_count(&self) -> Option<u32> {
+        let w = self.read_word(0);
+        matches!(Self::header_tag(w), Self::HEADER_TAG | Self::POISON_TAG | Self::ERASING_TAG)
+            .then_some(w & Self::HEADER_COUNT_MASK)
+    }
+
+    fn header(format: Format, count: u32) -> u32 {
+        let flag = match format {
+            Format::Tokens | Format::OtherWidth => C::HEADER_FLAG,
+            Format::Strikes => Self::STRIKES_FLAG,
+        };
+        (Self::HEADER_TAG | flag) << 24 | count.min(Self::HEADER_COUNT_MASK)
+    }
+
+    /// How the block's entries are written: "token", "16-bit token",
+    /// "extended token", "bit-strike" or "other codec's token".
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    pub fn encoding(&self) -> &'static str {
+        self.format().name::<C>()
+    }
+
+    /// Whether a failed compaction left the log unusable (see BootMeta).
+    pub fn poisoned(&self) -> bool {
+        self.poisoned.get() || Self::header_tag(self.read_word(0)) == Self::POISON_TAG
+    }
+
+    /// Whether a compaction was cut off mid-erase, or before it had
+    /// written the log back (see BootMeta).
+    pub fn erase_interrupted(&self) -> bool {
+        let w = self.read_word(0);
+        match Self::header_tag(w) {
+            Self::ERASING_TAG => true,
+            Self::HEADER_TAG => w >> 24 & Self::REWRITING_FLAG != 0,
+            _ => false,
+        }
+    }
+
+    /// First thing on every boot, before scan(): give an erased block
+    /// (fresh from the factory) its header, so the first record_boot()
+    /// writes into a log, not into blank flash. Erased is the first
+    /// BLANK_HEAD_WORDS words and BLANK_SAMPLES more across the block
+    /// (but the override word, which a tool may have set). The header,
+    /// with no entry after it, is what marks the block initialized: a
+    /// single word, so a power cut either leaves the block erased (done
+    /// again on the next boot) or tears the header, its top byte still
+    /// erased, and the next boot erases the block and starts over (a
+    /// compaction: the count is 1 then). A block that is neither a log
+    /// (a header, or an older SPL's first token) nor erased is not
+    /// guessed at: it is poisoned, like a failed compaction, but in RAM
+    /// only (the next boot finds it the same): a poisoned header
+    /// programmed over whatever is there could read as a token. So is a
+    /// blank block whose header can't be written, for this boot: that
+    /// leaves no log to count trials in. Nothing is written without
+    /// `writes`.
+    pub fn init(&self, writes: bool) -> Result<MetaInit, FlashError> {
+        let w = self.read_word(0);
+        if self.compaction_count().is_some() {
+            return Ok(MetaInit::Valid);
+        }
+        // A log without a header is an older SPL's, in 32-bit tokens.
+        let torn = w != Self::ERASED_WORD && w >> 24 == 0xFF;
+        let legacy = matches!(Token32::decode(self.banks, &w.to_le_bytes()), EntryKind::Attempt(..));
+        if legacy {
+            return Ok(MetaInit::Valid);
+        }
+        if (w != Self::ERASED_WORD && !torn) || !self.blank() {
+            slog_error!("ERROR: boot log block holds neither a log nor erased flash (first word 0x{:08x})", w);
+            slog_error!("ERROR: no boot is recorded until the boot log is erased");
+            self.poisoned.set(true);
+            return Ok(MetaInit::Corrupt);
+        }
+        if !writes {
+            slog_info!("boot log: blank block, not initialized (no NOR writes)");
+            return Ok(MetaInit::Blank);
+        }
+        if torn {
+            slog_warn!("WARNING: boot log: header cut short (0x{:08x}), initializing the block again", w);
+            self.erase()?;
+            return Ok(MetaInit::Redone);
+        }
+        let header = Self::header(Format::WRITES, 0);
+        if let Err(e) = self.rewrite(0, || self.write_word(0, header)) {
+            // Whatever erased the block, the counts went with it.
+            if e != FlashError::MetaReadOnly {
+                slog_error!("ERROR: boot log: blank block, header not written ({:?}): nothing recorded", e);
+                self.poisoned.set(true);
+            }
+            return Err(e);
+        }
+        slog_info!("boot log: blank block initialized ({} encoding)", Format::WRITES.name::<C>());
+        Ok(MetaInit::Initialized)
+    }
+
+    // Whether the block reads as erased, as init() samples it.
+    fn blank(&self) -> bool {
+        let words = self.words_capacity();
+        let step = (words / Self::BLANK_SAMPLES).max(1);
+        (1..Self::BLANK_HEAD_WORDS.min(words))
+            .chain((Self::BLANK_HEAD_WORDS..words).step_by(step))
+            .all(|idx| self.read_word(idx) == Self::ERASED_WORD)
+    }
+
+    /// Scan the metadata area: count each bank's attempts by state, and
+    /// find where the next free entry is.
+    pub fn scan(&self) -> Trials {
+        let format = self.format();
+        let cap = self.capacity(format);
+        // Nothing counts, and there is no room for more.
+        if self.poisoned() || self.erase_interrupted() || format == Format::OtherWidth {
+            return Trials {
+                next_idx: cap,
+                ..Trials::default()
+            };
+        }
+        if format == Format::Strikes {
+            return self.scan_strikes();
+        }
+        let mut trials = Trials {
+            next_idx: self.first_entry(format),
+            ..Trials::default()
+        };
+
+        while trials.next_idx < cap {
+            let (bank, state) = match self.kind(self.read_entry(trials.next_idx)) {
+                EntryKind::Attempt(bank, state) => (bank, state),
+                EntryKind::Erased => break,
                 // Unknown value, stop scanning to be conservative.
+                EntryKind::Unknown => break,
+            };
+            trials.bank_mut(bank).count(state);
+            trials.last = Some((bank, state));
+            trials.next_idx += 1;
+        }
+
+        trials
+    }
+
+    // scan() of a bit-strike log, 32 entries at a time. The log ends at
+    // the first entry no bank struck, or that two did, or confirmed but
+    // not handed off: what a token log would stop at.
+    fn scan_strikes(&self) -> Trials {
+        let mut trials = Trials::default();
+        let word = |area, i| !self.read_word(self.strike_bit(area, 32 * i).0);
+        for i in 0..self.area_words() {
+            let (handed_off, confirmed) = (word(self.handoff_area(), i), word(self.confirm_area(), i));
+            let mut struck = [0u32; MAX_BANKS];
+            let (mut used, mut clash) = (0u32, 0u32);
+            for bank in self.banks() {
+                let w = word(bank.index(), i);
+                clash |= used & w;
+                used |= w;
+                struck[bank.index()] = w;
+            }
+            let end = !used | clash | (confirmed & !handed_off);
+            let valid = 1u32.checked_shl(end.trailing_zeros()).map_or(u32::MAX, |b| b - 1);
+            for bank in self.banks() {
+                let w = struck[bank.index()] & valid;
+                let t = trials.bank_mut(bank);
+                t.no_handoff += (w & !handed_off).count_ones();
+                t.unconfirmed += (w & handed_off & !confirmed).count_ones();
+                t.confirmed += (w & handed_off & confirmed).count_ones();
+            }
+            trials.next_idx += valid.count_ones() as usize;
+            if valid != u32::MAX {
                 break;
             }
-            idx += 1;
         }
+        if trials.next_idx > 0 {
+            trials.last = self.decode(self.entry(Format::Strikes, trials.next_idx - 1));
+        }
+        trials
+    }
 
-        (a_count, b_count, idx)
+    /// Log entries, oldest first, up to the first erased one: tokens (in
+    /// the low ENTRY_SIZE bytes), as written or as a bit-strike log's
+    /// strikes stand for. An unknown one is yielded too, but ends the log
+    /// for scan().
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    pub fn entries(&self) -> impl Iterator<Item = u32> + '_ {
+        let format = self.format();
+        (self.first_entry(format)..self.capacity(format))
+            .map(move |idx| self.entry(format, idx))
+            .take_while(|&w| w != C::ERASED)
     }
 
-    /// Compact the log by erasing the whole block and rewriting only the
-    /// effective counts.
-    ///
-    /// For real NOR, let's use working block_erase(); in QEMU the
-    /// current flash_intel::block_erase() is a stub and this will error.
-    fn compact(
-        &self,
-        mut a_count: u32,
-        mut b_count: u32,
-    ) -> Result<(), FlashError> {
-        let block_index = self.meta_offset / self.flash.block_size;
+    /// The last `n` entries before `next_idx` (scan()'s), oldest first,
+    /// as entries() yields them.
+    pub fn entries_before(&self, next_idx: usize, n: usize) -> impl Iterator<Item = u32> + '_ {
+        let format = self.format();
+        let start = next_idx.saturating_sub(n).max(self.first_entry(format));
+        (start..next_idx).map(move |idx| self.entry(format, idx))
+    }
 
-        slog!("compact: erasing block index {}", block_index);
-        self.flash.block_erase(block_index)?;
+    /// Compact the log by erasing the whole block and rewriting only the
+    /// attempts that still count: confirmed ones are dropped, older
+    /// SPLs' tokens come back in the current encoding, and the log in
+    /// the encoding this build writes. If that fails
+    /// the log is poisoned and this returns MetaPoisoned.
+    fn compact(&self, trials: &Trials) -> Result<(), FlashError> {
+        self.compact_start(trials, None)?;
+        self.finish_compaction()
+    }
 
-        let mut idx = 0usize;
+    // Start a compaction: mark the header, start the erase and keep what
+    // to write back, `append` last. Returns the index `append` gets.
+    fn compact_start(&self, trials: &Trials, append: Option<u32>) -> Result<usize, FlashError> {
+        self.finish_compaction()?;
+        self.writable()?;
+        // The block is the next stage's.
+        if self.handed_off.get().is_some() {
+            return Err(FlashError::ProgramError);
+        }
+        let block_index = self.meta_offset.get() / self.flash.block_size;
+        let old = self.read_word(0);
+        let count = self.compaction_count().unwrap_or(0).saturating_add(1);
+        let override_word =
+            self.boot_override().and_then(Result::ok).map(|_| self.read_word(self.override_idx()));
+        let idx = Self::entries_from(Format::WRITES)
+            + self
+                .banks()
+                .map(|b| (trials.bank(b).no_handoff + trials.bank(b).unconfirmed) as usize)
+                .sum::<usize>();
 
-        while a_count > 0 {
-            self.write_word(idx, Self::TOKEN_BANK_A)?;
-            idx += 1;
-            a_count -= 1;
+        slog_info!("compact: erasing block index {} (compaction {})", block_index, count);
+        // Not over a poisoned header: bit 30 is what tells it apart.
+        if matches!(Self::header_tag(old), Self::HEADER_TAG | Self::ERASING_TAG) {
+            self.write_word(0, old & !(1 << 30)).map_err(|e| self.poison(count, e))?;
         }
+        self.flash.erase_start(block_index).map_err(|e| self.poison(count, self.locked(e)))?;
+        self.compacting.set(Some(Compaction {
+            count,
+            override_word,
+            trials: *trials,
+            append: append.filter(|_| idx < self.capacity(Format::WRITES)).map(|token| (idx, token)),
+        }));
+        Ok(idx)
+    }
 
-        while b_count > 0 {
-            self.write_word(idx, Self::TOKEN_BANK_B)?;
-            idx += 1;
-            b_count -= 1;
+    /// Wait for a compaction record_boot() left erasing in the background
+    /// and write the log back. Nothing to do without one.
+    pub fn finish_compaction(&self) -> Result<(), FlashError> {
+        let Some(c) = self.compacting.take() else {
+            return Ok(());
+        };
+        let block_index = self.meta_offset.get() / self.flash.block_size;
+        let count = c.count;
+        if let Err(e) = self.flash.erase_finish() {
+            if e == FlashError::Protected {
+                return Err(self.poison(count, self.locked(e)));
+            }
+            // The erase in one go, with the retry policy this time.
+            slog_warn!("WARNING: compact: erase failed ({:?}), erasing again", e);
+            self.flash.block_erase(block_index).map_err(|e| self.poison(count, e))?;
+        }
+        for run in self.write_back(&c).runs() {
+            self.write_run(run).map_err(|e| self.poison(count, e))?;
+        }
+        let format = Format::WRITES;
+        if format == Format::Strikes {
+            let mut idx = Self::entries_from(format);
+            for bank in self.banks() {
+                let t = c.trials.bank(bank);
+                for (state, n) in [
+                    (EntryState::Started, t.no_handoff),
+                    (EntryState::HandedOff, t.unconfirmed),
+                ] {
+                    for _ in 0..n {
+                        let token = self.token(bank, state);
+                        self.rewrite(idx, || self.put_entry(format, idx, token))
+                            .map_err(|e| self.poison(count, e))?;
+                        idx += 1;
+                    }
+                }
+            }
+        }
+        let header = Self::header(format, count);
+        self.rewrite(0, || self.write_word(0, header)).map_err(|e| self.poison(count, e))?;
+        if let Some((idx, token)) = c.append {
+            self.put_entry(format, idx, token)?;
         }
 
+        self.poisoned.set(false);
         Ok(())
     }
 
-    /// Record a boot attempt for the given bank.
+    // What a compaction writes first once its erase is over: the header
+    // marked rewriting, the override, then each bank's attempts that
+    // didn't get confirmed (but in a bit-strike log, whose strikes
+    // finish_compaction() writes itself). The header done goes last.
+    fn write_back(&self, c: &Compaction) -> Rewrite {
+        let format = Format::WRITES;
+        let mut rewrite = Rewrite::default();
+        let header = Self::header(format, c.count);
+        rewrite.push(0, Self::WORD_SIZE, 1, header | Self::REWRITING_FLAG << 24);
+        if let Some(word) = c.override_word {
+            rewrite.push(self.override_idx() * Self::WORD_SIZE, Self::WORD_SIZE, 1, word);
+        }
+        if format != Format::Strikes {
+            let mut idx = Self::entries_from(format);
+            for bank in self.banks() {
+                let t = c.trials.bank(bank);
+                for (state, n) in [
+                    (EntryState::Started, t.no_handoff),
+                    (EntryState::HandedOff, t.unconfirmed),
+                ] {
+                    rewrite.push(idx * C::SIZE, C::SIZE, n, self.token(bank, state));
+                    idx += n as usize;
+                }
+            }
+        }
+        rewrite
+    }
+
+    // A run of a write-back, each value written as rewrite() does.
+    fn write_run(&self, run: &RewriteRun) -> Result<(), FlashError> {
+        let width = run.width as usize;
+        (0..run.count as usize).try_for_each(|i| {
+            let offset = run.offset as usize + i * width;
+            self.rewrite(offset / width, || self.write_at(offset, width, run.value))
+        })
+    }
+
+    /// Give a compaction record_boot() left erasing to the next stage,
+    /// instead of waiting for the erase here (see BootMeta): the erase
+    /// goes on, and from then on the log reads as it will once written
+    /// back (handed_off()). A write only goes to a value the write-back
+    /// programs by itself, such as record_handoff() of the entry
+    /// appended. False if there is no such compaction, or for a
+    /// bit-strike log, which finish_compaction() writes back.
+    pub fn hand_off_compaction(&self) -> bool {
+        if Format::WRITES == Format::Strikes {
+            return false;
+        }
+        let Some(c) = self.compacting.take() else {
+            return false;
+        };
+        let mut rewrite = self.write_back(&c);
+        rewrite.push(0, Self::WORD_SIZE, 1, Self::header(Format::WRITES, c.count));
+        if let Some((idx, token)) = c.append {
+            rewrite.push(idx * C::SIZE, C::SIZE, 1, token);
+        }
+        slog_info!("compact: erase and write-back ({} runs) left to the next stage", rewrite.len);
+        self.handed_off.set(Some(rewrite));
+        self.flash.erase_resume();
+        true
+    }
+
+    /// The write-back hand_off_compaction() gave to the next stage.
+    pub fn handed_off(&self) -> Option<Rewrite> {
+        self.handed_off.get()
+    }
+
+    // A write of a compaction (word or entry `idx`): tried again as the
+    // flash's retry policy says before the log is given up on. A torn
+    // word takes the same value again (only 1→0 transitions).
+    fn rewrite(&self, idx: usize, write: impl Fn() -> Result<(), FlashError>) -> Result<(), FlashError> {
+        let retry = self.flash.retry;
+        // Nothing a retry can help.
+        let locked = |e| matches!(e, FlashError::Protected | FlashError::MetaReadOnly);
+        let mut attempt = 1;
+        loop {
+            match write() {
+                Err(e) if !locked(e) && attempt < retry.attempts => {
+                    slog_warn!("WARNING: compact: entry {} failed ({:?}), writing it again", idx, e);
+                    clint::delay_us(retry.backoff_us);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // A compaction failed with `cause`: mark the log unusable, in flash
+    // if the header can still be written, and in RAM for this boot. Not
+    // a read-only one: its block was never erased.
+    fn poison(&self, count: u32, cause: FlashError) -> FlashError {
+        if cause == FlashError::MetaReadOnly {
+            return cause;
+        }
+        slog_error!("ERROR: boot log compaction failed ({:?}), poisoning the log", cause);
+        slog_error!("ERROR: no boot is recorded until the boot log is erased");
+        self.poisoned.set(true);
+        let poison = Self::header(Format::WRITES, count) & !(1 << 31);
+        if let Err(e) = self.write_word(0, poison) {
+            slog_error!("ERROR: could not write the poisoned header ({:?}): for this boot only", e);
+        }
+        FlashError::MetaPoisoned
+    }
+
+    /// Record a boot attempt for the given bank. Returns its entry
+    /// index, for record_handoff().
     ///
     /// The runtime decision to *call* this (or not) is made in spl_main
-    /// via should_record_boot(), so this function always assumes "writes allowed".
-    pub fn record_boot(&self, bank: BootBank) -> Result<(), FlashError> {
-        let (a_count, b_count, mut next_idx) = self.scan();
-        let cap = self.words_capacity();
+    /// by its BootPolicy, so this function always assumes "writes allowed"
+    /// (but for a read-only log: MetaReadOnly).
+    pub fn record_boot(&self, bank: BootBank) -> Result<usize, FlashError> {
+        if self.poisoned() {
+            return Err(FlashError::MetaPoisoned);
+        }
+        self.writable()?;
+        let format = self.format();
+        let trials = self.scan();
+        let mut next_idx = trials.next_idx;
+        let cap = self.capacity(format);
 
-        slog!(
-            "record_boot: start (bank={:?}, a_count={}, b_count={}, next_idx={}, cap={})",
+        slog_debug!(
+            "record_boot: start (bank={}, trials={:?}, cap={})",
             bank,
-            a_count,
-            b_count,
-            next_idx,
+            trials,
             cap
         );
 
-        if next_idx >= cap {
-            slog!("record_boot: log full, compacting");
-            self.compact(a_count, b_count)?;
-            let (_a2, _b2, idx2) = self.scan();
-            next_idx = idx2;
-            slog!("record_boot: after compact scan: next_idx={}", next_idx);
+        if next_idx == 0 && self.compaction_count().is_none() {
+            // Blank block: header first.
+            self.write_word(0, Self::header(format, 0))?;
+            next_idx = Self::entries_from(format);
+        }
+        let token = self.token(bank, EntryState::Started);
+        if format != Format::WRITES {
+            slog_info!(
+                "record_boot: converting the log from the {} to the {} encoding",
+                format.name::<C>(),
+                Format::WRITES.name::<C>()
+            );
+        }
+        // A write cut short ends the log (see scan()) on an entry that
+        // may not take this token: then it goes after a compaction too.
+        let torn = format == Format::Tokens && next_idx < cap && self.read_entry(next_idx) & token != token;
+        if next_idx >= cap || torn || format != Format::WRITES {
+            if next_idx >= cap {
+                slog_info!("record_boot: log full, compacting in the background");
+            } else if torn {
+                slog_warn!("WARNING: record_boot: entry {} torn, compacting in the background", next_idx);
+            }
+            next_idx = self.compact_start(&trials, Some(token))?;
+            slog_debug!("record_boot: token 0x{:08x} goes at entry {} after the erase", token, next_idx);
+            if next_idx >= self.capacity(Format::WRITES) {
+                return Err(FlashError::ProgramError);
+            }
+            return Ok(next_idx);
         }
 
-        let token = match bank {
-            BootBank::A => Self::TOKEN_BANK_A,
-            BootBank::B => Self::TOKEN_BANK_B,
-        };
+        slog_debug!("record_boot: writing token 0x{:08x} as {} entry {}", token, format.name::<C>(), next_idx);
 
-        slog!(
-            "record_boot: writing token 0x{:08x} at word index {} (offset=0x{:x})",
-            token,
-            next_idx,
-            self.word_offset(next_idx),
-        );
+        self.put_entry(format, next_idx, token)?;
+        Ok(next_idx)
+    }
+
+    /// Mark the attempt recorded at `idx` as handed off to the payload,
+    /// right before the jump.
+    pub fn record_handoff(&self, idx: usize) -> Result<(), FlashError> {
+        if self.poisoned() {
+            return Err(FlashError::MetaPoisoned);
+        }
+        let format = self.format();
+        match self.decode(self.entry(format, idx)) {
+            Some((bank, EntryState::Started)) => {
+                self.put_entry(format, idx, self.token(bank, EntryState::HandedOff))
+            }
+            _ => Err(FlashError::ProgramError),
+        }
+    }
+
+    /// Confirm the last handed-off attempt of `bank`: it booted fine and
+    /// no longer counts against it. This is what a payload does (with
+    /// its own flash driver) once it is up.
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    pub fn record_success(&self, bank: BootBank) -> Result<(), FlashError> {
+        if self.poisoned() {
+            return Err(FlashError::MetaPoisoned);
+        }
+        // Only a current-encoding token: an older SPL's has no bit to clear.
+        let format = self.format();
+        let handed_off = self.token(bank, EntryState::HandedOff);
+        let next_idx = self.scan().next_idx;
+        match (0..next_idx).rev().find(|&idx| self.entry(format, idx) == handed_off) {
+            Some(idx) => self.put_entry(format, idx, self.token(bank, EntryState::Confirmed)),
+            None => Err(FlashError::ProgramError),
+        }
+    }
 
-        self.write_word(next_idx, token)
+    /// Forget the boot trials of `bank` (e.g. after writing a new image
+    /// to it), keeping the other bank's. Erases and rewrites the log.
+    pub fn reset_trials(&self, bank: BootBank) -> Result<(), FlashError> {
+        let mut trials = self.scan();
+        if *trials.bank(bank) == BankTrials::default() {
+            return Ok(());
+        }
+        *trials.bank_mut(bank) = BankTrials::default();
+        self.compact(&trials)
     }
 
-    /// Pick which bank to boot next (A/B) based on how many trials each
-    /// already has.
+    /// Forget every boot trial: erase the log block. Also the way out of
+    /// a poisoned log.
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    pub fn erase(&self) -> Result<(), FlashError> {
+        self.compact(&Trials::default())
+    }
+
+    /// Pick which bank to boot next: the first by priority with fewer
+    /// than `max_trials` failed trials (of a poisoned log: the first by
+    /// priority).
     pub fn choose_bank(&self, max_trials: u32) -> BootBank {
-        let (a_count, b_count, _idx) = self.scan();
+        self.choose_bank_from(&self.scan(), max_trials)
+    }
+
+    /// choose_bank() going by `trials`: scan()'s, with whatever else
+    /// counts added (see ramtrials.rs).
+    pub fn choose_bank_from(&self, trials: &Trials, max_trials: u32) -> BootBank {
+        let (order, n) = self.by_priority();
+        order[..n]
+            .iter()
+            .copied()
+            .find(|&b| trials.bank(b).failed() < max_trials)
+            // All of them reached max_trials: the first by priority, by
+            // convention.
+            .unwrap_or(order[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::PhysAddr;
+    use crate::banks::{BOOT_BANKS, MAX_TRIALS};
+    use crate::flash_intel::{BgErase, Faults};
+
+    // A small log block, for compactions every few dozen boots, behind
+    // one block of something else.
+    const BLOCK: usize = 512;
+    const A: BootBank = BootBank(0);
+    const B: BootBank = BootBank(1);
+
+    fn flash() -> IntelFlash {
+        IntelFlash::new(PhysAddr::new(0x2000_0000), 2 * BLOCK, BLOCK, Vec::new())
+    }
+
+    // The log as a boot sees it: nothing kept in RAM from the last one.
+    fn meta<C: EntryCodec>(flash: &IntelFlash) -> BootMeta<'_, C> {
+        BootMeta::new(flash, FlashOffset::new(BLOCK), BLOCK, &BOOT_BANKS).unwrap()
+    }
+
+    // One boot as spl_main and the sim run it. The payload of the first
+    // bank by priority never confirms, the others' always do: it runs
+    // out of trials, and the next one's confirmed attempts fill the log.
+    fn boot<C: EntryCodec>(flash: &IntelFlash) {
+        let meta = meta::<C>(flash);
+        let _ = meta.init(true);
+        let bank = meta.choose_bank(MAX_TRIALS);
+        let recorded = meta.record_boot(bank);
+        let _ = meta.finish_compaction();
+        if let Ok(idx) = recorded {
+            let _ = meta.record_handoff(idx);
+        }
+        if bank != meta.by_priority().0[0] {
+            let _ = meta.record_success(bank);
+        }
+    }
+
+    // Enough boots for a compaction and then some.
+    fn boots<C: EntryCodec>(flash: &IntelFlash) -> usize {
+        meta::<C>(flash).capacity(Format::WRITES) + 8
+    }
+
+    // Attempts of each bank the log holds against it, as the next boot
+    // sees them: they only ever go away when confirmed, and each boot
+    // makes one.
+    fn blamed<C: EntryCodec>(flash: &IntelFlash) -> Option<[u32; MAX_BANKS]> {
+        let meta = meta::<C>(flash);
+        let _ = meta.init(true);
+        if meta.poisoned() || meta.erase_interrupted() {
+            return None;
+        }
+        let trials = meta.scan();
+        Some(core::array::from_fn(|i| trials.banks[i].no_handoff + trials.banks[i].unconfirmed))
+    }
+
+    // Unless the log is poisoned (then it claims nothing), no bank has
+    // fewer attempts against it after a boot than before.
+    fn check_blame<C: EntryCodec>(before: Option<[u32; MAX_BANKS]>, flash: &IntelFlash, what: &str) {
+        if let (Some(before), Some(after)) = (before, blamed::<C>(flash)) {
+            for i in 0..BOOT_BANKS.len() {
+                assert!(after[i] >= before[i], "{}: bank {} down from {} to {}", what, i, before[i], after[i]);
+            }
+        }
+    }
+
+    // A boot, checked unless it compacted the log: a compaction that
+    // fails or is cut off after its erase loses the entries it was
+    // writing back (see BootMeta).
+    fn checked_boot<C: EntryCodec>(flash: &IntelFlash, what: &str) {
+        let (before, erases) = (blamed::<C>(flash), flash.ops().erases);
+        boot::<C>(flash);
+        if flash.ops().erases == erases {
+            check_blame::<C>(before, flash, what);
+        }
+    }
+
+    // With the flash behaving again the log records the next boots,
+    // whichever bank they are of: its free entry takes a token, or the
+    // log compacts. A poisoned log needs erase() first.
+    fn check_records<C: EntryCodec>(flash: &IntelFlash, what: &str) {
+        flash.inject(Faults::default());
+        let meta = meta::<C>(flash);
+        let _ = meta.init(true);
+        if meta.poisoned() {
+            meta.erase().unwrap_or_else(|e| panic!("{}: erase: {:?}", what, e));
+        }
+        let (order, n) = meta.by_priority();
+        for &bank in &order[..n] {
+            let before = meta.scan().bank(bank).no_handoff;
+            meta.record_boot(bank).unwrap_or_else(|e| panic!("{}: bank {}: record_boot: {:?}", what, bank, e));
+            meta.finish_compaction().unwrap_or_else(|e| panic!("{}: compaction: {:?}", what, e));
+            assert_eq!(meta.scan().bank(bank).no_handoff, before + 1, "{}: bank {} not counted", what, bank);
+        }
+    }
+
+    // Programs and erases of a fault-free run.
+    fn dry_run<C: EntryCodec>() -> (u32, u32) {
+        let flash = flash();
+        for _ in 0..boots::<C>(&flash) {
+            boot::<C>(&flash);
+        }
+        let ops = flash.ops();
+        (ops.programs, ops.erases)
+    }
+
+    // Each program of a run (sampled on long ones) failing in turn, once
+    // or for good.
+    fn failed_programs<C: EntryCodec>() {
+        let (programs, _) = dry_run::<C>();
+        for e in [FlashError::ProgramError, FlashError::Protected] {
+            for len in [1, u32::MAX] {
+                for n in (1..=programs).step_by((programs / 400).max(1) as usize) {
+                    let what = format!("programs {}+{} failing with {:?}", n, len, e);
+                    let flash = flash();
+                    flash.inject(Faults {
+                        program: Some((n, len, e)),
+                        ..Faults::default()
+                    });
+                    for _ in 0..boots::<C>(&flash) {
+                        checked_boot::<C>(&flash, &what);
+                    }
+                    check_records::<C>(&flash, &what);
+                }
+            }
+        }
+    }
+
+    fn failed_erases<C: EntryCodec>() {
+        let (_, erases) = dry_run::<C>();
+        assert!(erases > 0, "no compaction in the run");
+        for e in [FlashError::EraseError, FlashError::Protected] {
+            for n in 1..=erases {
+                let what = format!("erase {} failing with {:?}", n, e);
+                let flash = flash();
+                flash.inject(Faults {
+                    erase: Some((n, 1, e)),
+                    ..Faults::default()
+                });
+                for _ in 0..boots::<C>(&flash) {
+                    checked_boot::<C>(&flash, &what);
+                }
+                check_records::<C>(&flash, &what);
+            }
+        }
+    }
+
+    // The power cut at every byte of a run (sampled on long ones): a
+    // torn write never loses an entry written before it, and the next
+    // boots record again. A compaction cut short can lose the entries it
+    // was writing back.
+    fn power_cuts<C: EntryCodec>() {
+        let flash = flash();
+        let boots = boots::<C>(&flash);
+        for _ in 0..boots {
+            boot::<C>(&flash);
+        }
+        let total = flash.ops().bytes;
+        for cut in (0..total).step_by((total / 2000).max(1)) {
+            let what = format!("power cut after {} bytes", cut);
+            let flash = self::flash();
+            flash.inject(Faults {
+                power_cut: Some(cut),
+                ..Faults::default()
+            });
+            let mut before = None;
+            let mut erases = 0;
+            for _ in 0..boots {
+                (before, erases) = (blamed::<C>(&flash), flash.ops().erases);
+                boot::<C>(&flash);
+                if flash.ops().cut {
+                    break;
+                }
+            }
+            let erased = flash.ops().erases != erases;
+            // The boot after the cut.
+            flash.inject(Faults::default());
+            if !erased {
+                check_blame::<C>(before, &flash, &what);
+            }
+            check_records::<C>(&flash, &what);
+        }
+    }
+
+    // A byte of the log that never changes, anywhere in its first words:
+    // counts stay right or the log gives up (poisoned), and it never
+    // counts a write that didn't take.
+    fn stuck_bytes<C: EntryCodec>() {
+        for offset in BLOCK..BLOCK + 64 {
+            let what = format!("byte 0x{:x} stuck", offset);
+            let flash = flash();
+            flash.inject(Faults {
+                stuck: vec![offset],
+                ..Faults::default()
+            });
+            for _ in 0..boots::<C>(&flash) {
+                checked_boot::<C>(&flash, &what);
+            }
+        }
+    }
+
+    // The erase of a compaction went through, writing the log back did
+    // not: the log is poisoned, in flash too, records nothing and falls
+    // back on the bank order until erased.
+    fn rewrite_failure_poisons<C: EntryCodec>() {
+        let flash = flash();
+        let full = meta::<C>(&flash).capacity(Format::WRITES);
+        while meta::<C>(&flash).scan().next_idx < full {
+            boot::<C>(&flash);
+        }
+        // Program 1 marks the header erasing, then the erase, then the
+        // header goes back: fail it and its retries.
+        flash.inject(Faults {
+            program: Some((2, flash.retry.attempts, FlashError::ProgramError)),
+            ..Faults::default()
+        });
+        let meta = meta::<C>(&flash);
+        meta.record_boot(A).unwrap();
+        assert_eq!(meta.finish_compaction(), Err(FlashError::MetaPoisoned));
+        assert_eq!(flash.ops().erases, 1);
+
+        let meta = self::meta::<C>(&flash);
+        assert!(meta.poisoned());
+        assert_eq!(meta.record_boot(A), Err(FlashError::MetaPoisoned));
+        assert_eq!(meta.choose_bank(MAX_TRIALS), meta.by_priority().0[0]);
+        check_records::<C>(&flash, "rewrite failure");
+    }
+
+    // Each state of an attempt of each bank reads back as written and is
+    // reached from the one before by clearing bits. Erased flash reads
+    // as such. A write cut short (top byte erased) is no attempt, and
+    // CONFLICT and a short read are neither.
+    fn codec_round_trip<C: EntryCodec>() {
+        let raw = |entry: u32| entry.to_le_bytes();
+        for bank in (0..BOOT_BANKS.len()).map(|i| BootBank(i as u8)) {
+            let mut prev = C::ERASED;
+            for state in [EntryState::Started, EntryState::HandedOff, EntryState::Confirmed] {
+                let what = format!("{}: bank {} {:?}", C::NAME, bank, state);
+                let entry = C::encode(&BOOT_BANKS, bank, state);
+                assert_eq!(entry & !prev, 0, "{}: 0x{:x} after 0x{:x}", what, entry, prev);
+                let kind = C::decode(&BOOT_BANKS, &raw(entry)[..C::SIZE]);
+                assert_eq!(kind, EntryKind::Attempt(bank, state), "{}", what);
+                let torn = entry | 0xFF << (8 * C::SIZE - 8);
+                let kind = C::decode(&BOOT_BANKS, &raw(torn)[..C::SIZE]);
+                assert!(!matches!(kind, EntryKind::Attempt(..)), "{} torn: {:?}", what, kind);
+                prev = entry;
+            }
+        }
+        assert_eq!(C::decode(&BOOT_BANKS, &raw(C::ERASED)[..C::SIZE]), EntryKind::Erased);
+        assert_eq!(C::decode(&BOOT_BANKS, &raw(C::CONFLICT)[..C::SIZE]), EntryKind::Unknown);
+        assert_eq!(C::decode(&BOOT_BANKS, &[0; 1]), EntryKind::Unknown);
+    }
+
+    // A full log compacts in the background: the erase is started and
+    // left running, and the log written back once it is waited for.
+    fn background_compaction<C: EntryCodec>() {
+        let flash = full::<C>();
+        let meta = meta::<C>(&flash);
+        let before = meta.scan();
+        let idx = meta.record_boot(A).unwrap();
+        assert!(matches!(flash.erase_state(), BgErase::Running(..)));
+        meta.finish_compaction().unwrap();
+        assert_eq!(flash.erase_state(), BgErase::Idle);
+        let after = meta.scan();
+        assert_eq!(after.next_idx, idx + 1);
+        for bank in meta.banks() {
+            let (b, a) = (before.bank(bank), after.bank(bank));
+            let this = (bank == A) as u32;
+            assert_eq!((a.no_handoff, a.unconfirmed, a.confirmed), (b.no_handoff + this, b.unconfirmed, 0));
+        }
+    }
+
+    // A log full enough for the next record_boot() to compact it.
+    fn full<C: EntryCodec>() -> IntelFlash {
+        let flash = flash();
+        let full = meta::<C>(&flash).capacity(Format::WRITES);
+        while meta::<C>(&flash).scan().next_idx < full {
+            boot::<C>(&flash);
+        }
+        flash
+    }
+
+    // What SPL2 does with a compaction handed to it (see spl2.rs).
+    fn next_stage(flash: &IntelFlash, rewrite: &Rewrite) {
+        flash.erase_finish().unwrap();
+        for run in rewrite.runs() {
+            for i in 0..run.count as usize {
+                let at = FlashOffset::new(BLOCK + run.offset as usize + i * run.width as usize);
+                match run.width {
+                    2 => flash.program_u16_le(at, run.value as u16),
+                    _ => flash.program_u32_le(at, run.value),
+                }
+                .unwrap();
+            }
+        }
+    }
+
+    fn block(flash: &IntelFlash) -> Vec<u8> {
+        let mut b = vec![0; BLOCK];
+        flash.read_slice(FlashOffset::new(BLOCK), &mut b);
+        b
+    }
+
+    // A compaction handed to the next stage: the boot goes on without
+    // the erase, reading the log as it will be, and the next stage's
+    // writes leave the block as finishing it here would have.
+    fn handed_off_compaction<C: EntryCodec>() {
+        let (here, there) = (full::<C>(), full::<C>());
+        let meta = meta::<C>(&here);
+        let idx = meta.record_boot(A).unwrap();
+        meta.finish_compaction().unwrap();
+        let started = meta.scan();
+        meta.record_handoff(idx).unwrap();
+
+        let handed = self::meta::<C>(&there);
+        assert_eq!(handed.record_boot(A), Ok(idx));
+        if Format::WRITES == Format::Strikes {
+            assert!(!handed.hand_off_compaction());
+            return;
+        }
+        assert!(handed.hand_off_compaction());
+        assert_eq!(handed.scan(), started);
+        assert_eq!(handed.compaction_count(), meta.compaction_count());
+        assert!(!handed.erase_interrupted());
+        handed.record_handoff(idx).unwrap();
+        assert_eq!(handed.scan(), meta.scan());
+        assert_eq!(handed.record_boot(A), Err(FlashError::ProgramError));
+        assert!(matches!(there.erase_state(), BgErase::Running(..) | BgErase::Suspended(..)));
+
+        next_stage(&there, &handed.handed_off().unwrap());
+        assert_eq!(block(&there), block(&here));
+    }
+
+    // The next stage never finishes it (the board reset mid-erase): the
+    // next boot sees the marker and compacts again.
+    fn handed_off_compaction_cut_off<C: EntryCodec>() {
+        let flash = full::<C>();
+        let meta = meta::<C>(&flash);
+        meta.record_boot(A).unwrap();
+        if !meta.hand_off_compaction() {
+            return;
+        }
+        flash.reset();
+        let meta = self::meta::<C>(&flash);
+        assert_eq!(meta.init(true), Ok(MetaInit::Valid));
+        assert!(meta.erase_interrupted());
+        let idx = meta.record_boot(A).unwrap();
+        meta.finish_compaction().unwrap();
+        assert!(!meta.erase_interrupted());
+        assert_eq!(meta.scan().next_idx, idx + 1);
+        assert_eq!(meta.scan().bank(A).no_handoff, 1);
+    }
+
+    // A few boots, and then one not handed off yet: something for each
+    // kind of write to do. Returns the last one's entry.
+    fn started<C: EntryCodec>() -> (IntelFlash, usize) {
+        let flash = flash();
+        for _ in 0..3 {
+            boot::<C>(&flash);
+        }
+        let idx = meta::<C>(&flash).record_boot(A).unwrap();
+        (flash, idx)
+    }
+
+    // Every write of the log there is (record_success() of the bank that
+    // never confirms), each with something to write.
+    fn write_everything<C: EntryCodec>(meta: &BootMeta<'_, C>, idx: usize) -> [Result<(), FlashError>; 6] {
+        [
+            meta.record_boot(A).map(drop),
+            meta.record_handoff(idx),
+            meta.record_success(B),
+            meta.consume_override(),
+            meta.reset_trials(B),
+            meta.erase(),
+        ]
+    }
+
+    // Read-only by policy: nothing written, banks chosen from the counts
+    // as before.
+    fn read_only_by_policy<C: EntryCodec>() {
+        let (flash, idx) = started::<C>();
+        let meta = meta::<C>(&flash);
+        let (before, trials, bank) = (block(&flash), meta.scan(), meta.choose_bank(MAX_TRIALS));
+        flash.inject(Faults::default());
+        meta.set_read_only("policy");
+        assert!(meta.read_only());
+        assert_eq!(meta.record_boot(A), Err(FlashError::MetaReadOnly));
+        assert_eq!(meta.record_handoff(idx), Err(FlashError::MetaReadOnly));
+        assert_eq!((flash.ops().programs, flash.ops().erases), (0, 0));
+        assert_eq!(block(&flash), before);
+        assert!(!meta.poisoned());
+        assert_eq!(meta.scan(), trials);
+        assert_eq!(meta.choose_bank(MAX_TRIALS), bank);
+    }
+
+    // Read-only on a Protected error, whether from an entry's program or
+    // from a compaction's first write: not poisoned, the block as it was.
+    fn read_only_when_locked<C: EntryCodec>() {
+        for (flash, what) in [(started::<C>().0, "entry"), (full::<C>(), "compaction")] {
+            let meta = meta::<C>(&flash);
+            let (before, trials) = (block(&flash), meta.scan());
+            flash.lock(1);
+            flash.inject(Faults::default());
+            assert!(!meta.read_only());
+            assert_eq!(meta.record_boot(A), Err(FlashError::MetaReadOnly), "{}", what);
+            assert!(meta.read_only(), "{}", what);
+            assert!(!meta.poisoned(), "{}", what);
+            assert_eq!(meta.finish_compaction(), Ok(()), "{}", what);
+            assert_eq!(flash.ops().erases, 0, "{}", what);
+            assert_eq!(block(&flash), before, "{}", what);
+            assert_eq!(meta.scan(), trials, "{}", what);
+
+            let next = self::meta::<C>(&flash);
+            assert_eq!(next.init(true), Ok(MetaInit::Valid), "{}", what);
+            assert!(!next.erase_interrupted(), "{}", what);
+        }
+    }
+
+    // Once read-only, however it got there, every write is refused
+    // without a program or erase of the flash.
+    fn read_only_writes_nothing<C: EntryCodec>() {
+        for locked in [false, true] {
+            let (flash, idx) = started::<C>();
+            let meta = meta::<C>(&flash);
+            match locked {
+                true => {
+                    flash.lock(1);
+                    assert_eq!(meta.record_handoff(idx), Err(FlashError::MetaReadOnly));
+                }
+                false => meta.set_read_only("policy"),
+            }
+            let (before, ops) = (block(&flash), flash.ops());
+            for (i, result) in write_everything(&meta, idx).into_iter().enumerate() {
+                assert_eq!(result, Err(FlashError::MetaReadOnly), "locked {}: write {}", locked, i);
+            }
+            assert_eq!(meta.finish_compaction(), Ok(()));
+            assert_eq!((flash.ops().programs, flash.ops().erases), (ops.programs, ops.erases));
+            assert_eq!(block(&flash), before);
+        }
+    }
+
+    // No attempt counted against any bank.
+    fn no_trials<C: EntryCodec>(meta: &BootMeta<'_, C>) -> bool {
+        meta.scan().banks == [BankTrials::default(); MAX_BANKS]
+    }
+
+    // init() on each kind of block it can find: erased, a log (ours or
+    // an older SPL's), a header cut short, neither a log nor erased.
+    fn init_states<C: EntryCodec>() {
+        let flash = self::flash();
+        assert_eq!(self::meta::<C>(&flash).init(false), Ok(MetaInit::Blank));
+        assert_eq!(flash.ops().programs, 0);
+        let meta = self::meta::<C>(&flash);
+        assert_eq!(meta.init(true), Ok(MetaInit::Initialized));
+        assert_eq!(meta.compaction_count(), Some(0));
+        assert!(no_trials(&meta));
+
+        let (flash, _) = started::<C>();
+        let (before, trials) = (block(&flash), self::meta::<C>(&flash).scan());
+        flash.inject(Faults::default());
+        let meta = self::meta::<C>(&flash);
+        assert_eq!(meta.init(true), Ok(MetaInit::Valid));
+        assert_eq!((flash.ops().programs, flash.ops().erases), (0, 0));
+        assert_eq!((block(&flash), meta.scan()), (before, trials));
+
+        let flash = self::flash();
+        let legacy = Token32::encode(&BOOT_BANKS, A, EntryState::Started);
+        flash.program_u32_le(FlashOffset::new(BLOCK), legacy).unwrap();
+        assert_eq!(self::meta::<C>(&flash).init(true), Ok(MetaInit::Valid));
+
+        let flash = self::flash();
+        let header = BootMeta::<C>::header(Format::WRITES, 0);
+        flash.program_u16_le(FlashOffset::new(BLOCK), header as u16).unwrap();
+        assert_eq!(self::meta::<C>(&flash).init(false), Ok(MetaInit::Blank));
+        let meta = self::meta::<C>(&flash);
+        assert_eq!(meta.init(true), Ok(MetaInit::Redone));
+        assert!(meta.compaction_count().is_some() && no_trials(&meta));
+        assert_eq!(self::meta::<C>(&flash).init(true), Ok(MetaInit::Valid));
+
+        // Garbage in the first word, or further in where init() samples.
+        for at in [0, BLOCK / 2] {
+            let flash = self::flash();
+            flash.program_u32_le(FlashOffset::new(BLOCK + at), 0x1234_5678).unwrap();
+            flash.inject(Faults::default());
+            let meta = self::meta::<C>(&flash);
+            assert_eq!(meta.init(true), Ok(MetaInit::Corrupt), "garbage at {}", at);
+            assert!(meta.poisoned());
+            assert_eq!(meta.record_boot(A), Err(FlashError::MetaPoisoned));
+            assert_eq!((flash.ops().programs, flash.ops().erases), (0, 0));
+            assert_eq!(self::meta::<C>(&flash).init(true), Ok(MetaInit::Corrupt));
+            meta.erase().unwrap();
+            assert_eq!(self::meta::<C>(&flash).init(true), Ok(MetaInit::Valid));
+        }
+    }
+
+    // A power cut anywhere in initializing an erased block, and another
+    // in the boot after it initializing it again: never a corrupt log,
+    // and a boot with the power on leaves one that records.
+    fn init_power_cuts<C: EntryCodec>() {
+        let word = BootMeta::<C>::WORD_SIZE;
+        for first in 0..word {
+            for second in [0, 1, word, BLOCK / 2, BLOCK, BLOCK + word + 1, 2 * BLOCK] {
+                let what = format!("cut after {} then {} bytes", first, second);
+                let flash = flash();
+                for cut in [Some(first), Some(second), None] {
+                    flash.inject(Faults {
+                        power_cut: cut,
+                        ..Faults::default()
+                    });
+                    let result = meta::<C>(&flash).init(true);
+                    assert_ne!(result, Ok(MetaInit::Corrupt), "{}", what);
+                    flash.reset();
+                }
+                let meta = meta::<C>(&flash);
+                assert_eq!(meta.init(true), Ok(MetaInit::Valid), "{}", what);
+                assert!(no_trials(&meta), "{}", what);
+                check_records::<C>(&flash, &what);
+            }
+        }
+    }
+
+    // The suite, once per codec.
+    macro_rules! suite {
+        ($($module:ident: $codec:ty),*) => {$(
+            mod $module {
+                use super::*;
+
+                #[test]
+                fn codec_round_trip() {
+                    super::codec_round_trip::<$codec>();
+                }
+
+                #[test]
+                fn failed_programs() {
+                    super::failed_programs::<$codec>();
+                }
+
+                #[test]
+                fn failed_erases() {
+                    super::failed_erases::<$codec>();
+                }
+
+                #[test]
+                fn power_cuts() {
+                    super::power_cuts::<$codec>();
+                }
+
+                #[test]
+                fn stuck_bytes() {
+                    super::stuck_bytes::<$codec>();
+                }
+
+                #[test]
+                fn rewrite_failure_poisons() {
+                    super::rewrite_failure_poisons::<$codec>();
+                }
+
+                #[test]
+                fn background_compaction() {
+                    super::background_compaction::<$codec>();
+                }
+
+                #[test]
+                fn handed_off_compaction() {
+                    super::handed_off_compaction::<$codec>();
+                }
+
+                #[test]
+                fn handed_off_compaction_cut_off() {
+                    super::handed_off_compaction_cut_off::<$codec>();
+                }
+
+                #[test]
+                fn init_states() {
+                    super::init_states::<$codec>();
+                }
+
+                #[test]
+                fn init_power_cuts() {
+                    super::init_power_cuts::<$codec>();
+                }
+
+                #[test]
+                fn read_only_by_policy() {
+                    super::read_only_by_policy::<$codec>();
+                }
+
+                #[test]
+                fn read_only_when_locked() {
+                    super::read_only_when_locked::<$codec>();
+                }
+
+                #[test]
+                fn read_only_writes_nothing() {
+                    super::read_only_writes_nothing::<$codec>();
+                }
+            }
+        )*};
+    }
+
+    suite!(token32: Token32, token16: Token16, token_ext: TokenExt);
+
+    // One boot of a run: whether its payload confirms goes by its number
+    // alone. Returns the bank chosen and the attempts against each after.
+    fn run_boot<C: EntryCodec>(flash: &IntelFlash, n: usize) -> (BootBank, [(u32, u32); MAX_BANKS]) {
+        let meta = meta::<C>(flash);
+        meta.init(true).unwrap();
+        let bank = meta.choose_bank(MAX_TRIALS);
+        let idx = meta.record_boot(bank).unwrap();
+        meta.finish_compaction().unwrap();
+        meta.record_handoff(idx).unwrap();
+        if !n.is_multiple_of(13) && !n.is_multiple_of(17) {
+            meta.record_success(bank).unwrap();
+        }
+        let trials = meta.scan();
+        (bank, core::array::from_fn(|i| (trials.banks[i].no_handoff, trials.banks[i].unconfirmed)))
+    }
 
-        if b_count < max_trials {
-            BootBank::B
-        } else if a_count < max_trials {
-            BootBank::A
-        } else {
-            // Both reached max_trials, fall back to B by convention.
-            BootBank::B
+    // The same boots give the same bank choices and counts with every
+    // codec, through compactions of each.
+    #[test]
+    fn codecs_agree() {
+        let (token32, token16, token_ext) = (flash(), flash(), flash());
+        for n in 1..=3 * boots::<Token16>(&token16) {
+            let expected = run_boot::<Token32>(&token32, n);
+            assert_eq!(run_boot::<Token16>(&token16, n), expected, "16-bit tokens, boot {}", n);
+            assert_eq!(run_boot::<TokenExt>(&token_ext, n), expected, "extended tokens, boot {}", n);
         }
+        let compactions = |flash: &IntelFlash| meta::<Token32>(flash).compaction_count();
+        assert!(compactions(&token32) > Some(1) && compactions(&token16) > Some(0), "too few compactions");
     }
 }
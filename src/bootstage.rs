@@ -0,0 +1,101 @@
+use crate::arch;
+use crate::clint;
+use crate::postcode::{self, Postcode};
+use crate::slog_info;
+
+// Boot phase timestamps. mark() costs one mtime read and a store; the
+// table is only formatted once, right before the jump, with the payload
+// copy's throughput if there was one (see throughput()).
+
+const MAX_MARKS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Start,
+    FlashProbed,
+    MetaScanned,
+    ImageVerified,
+    ImageCopied,
+    Jumping,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Start => "start",
+            Stage::FlashProbed => "flash probed",
+            Stage::MetaScanned => "metadata scanned",
+            Stage::ImageVerified => "image verified",
+            Stage::ImageCopied => "image copied",
+            Stage::Jumping => "jumping",
+        }
+    }
+
+    // The postcode stamped along with it; Start has the ones of _start.
+    fn postcode(self) -> Option<Postcode> {
+        match self {
+            Stage::Start => None,
+            Stage::FlashProbed => Some(Postcode::FlashProbed),
+            Stage::MetaScanned => Some(Postcode::MetaScanned),
+            Stage::ImageVerified => Some(Postcode::ImageVerified),
+            Stage::ImageCopied => Some(Postcode::ImageLoaded),
+            Stage::Jumping => Some(Postcode::Jumping),
+        }
+    }
+}
+
+// Only the boot hart runs Rust code, so plain statics are enough.
+static mut MARKS: [(Stage, u64); MAX_MARKS] = [(Stage::Start, 0); MAX_MARKS];
+static mut COUNT: usize = 0;
+// Last payload copy: what it was, bytes, mtime ticks.
+static mut COPY: Option<(&str, usize, u64)> = None;
+
+/// Record a payload copy of `bytes` that took `ticks` of mtime, `what`
+/// naming it (e.g. "copy+hash"), for report(). The last one is kept.
+pub fn throughput(what: &'static str, bytes: usize, ticks: u64) {
+    unsafe { (&raw mut COPY).write(Some((what, bytes, ticks))) };
+}
+
+/// Record `stage` at the current mtime. Marks past MAX_MARKS are dropped.
+///
+/// Also checks the stack guard, so an overflow is caught at the latest
+/// by the end of the phase it happened in.
+pub fn mark(stage: Stage) {
+    if !arch::stack_guard_intact() {
+        panic!("stack overflow: guard word at _stack_bottom overwritten");
+    }
+    if let Some(code) = stage.postcode() {
+        postcode::set(code);
+    }
+    let now = clint::mtime();
+    unsafe {
+        let n = COUNT;
+        if n < MAX_MARKS {
+            (&raw mut MARKS).cast::<(Stage, u64)>().add(n).write((stage, now));
+            COUNT = n + 1;
+        }
+    }
+}
+
+/// Log every mark with its time since reset and since the previous one.
+pub fn report() {
+    let marks =
+        unsafe { core::slice::from_raw_parts((&raw const MARKS).cast::<(Stage, u64)>(), COUNT) };
+    slog_info!("boot stages:        time (us)   delta (us)");
+    let mut prev = None;
+    for &(stage, ticks) in marks {
+        let delta = prev.map_or(0, |p| ticks.saturating_sub(p));
+        slog_info!(
+            "  {:<16} {:>12} {:>12}",
+            stage.name(),
+            clint::ticks_to_us(ticks),
+            clint::ticks_to_us(delta)
+        );
+        prev = Some(ticks);
+    }
+    if let Some((what, bytes, ticks)) = unsafe { (&raw const COPY).read() } {
+        // Bytes per us are MB/s.
+        let per_100us = bytes as u64 * 100 / clint::ticks_to_us(ticks).max(1);
+        slog_info!("  {:<16} {:>12} bytes, {}.{:02} MB/s", what, bytes, per_100us / 100, per_100us % 100);
+    }
+}
@@ -0,0 +1,418 @@
+use core::fmt;
+
+use crate::clint;
+use crate::dtb::Fdt;
+use crate::logger;
+use crate::{slog_info, slog_warn};
+
+// Boot time budget: a hard ceiling on the SPL's own run time, however
+// its individually bounded waits (flash retries, the console window,
+// hashing, the memory test) add up. The clock starts at spl_main(), and
+// the deadline is checked at the phase boundaries below.
+//
+// As the deadline nears, the optional work goes first, in this order:
+// the memory test once half the time is used, debug messages at 70%,
+// the console window at 85%. Once it has passed with nothing loaded,
+// spl_main() takes its terminal action. Time a person or the storm logic
+// asked for (the recovery shell, an XMODEM transfer, a storm delay) is
+// excused: it counts against nothing.
+//
+// Each phase also has a budget of its own (BUDGET_MS), only to tell
+// which one ran long: an overrun is logged and shows in the boot report,
+// but only the overall deadline acts. A "dev" build takes both from
+// /chosen: "spl,boot-deadline-ms" (u32) and "spl,phase-budgets-ms" (u32
+// cells, in Phase order).
+
+/// The major phases, in boot order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Console, DTB, timers, fw_cfg.
+    Init,
+    /// Flash probe, boot metadata and black box setup.
+    Flash,
+    /// The update key window and the autoboot countdown.
+    Window,
+    /// Boot log scan and bank choice.
+    Meta,
+    /// Candidates: checks, memory test, copy.
+    Load,
+    /// Boot log, SPL2, DTB patch, up to the jump.
+    Handoff,
+}
+
+const PHASES: [Phase; 6] = [Phase::Init, Phase::Flash, Phase::Window, Phase::Meta, Phase::Load, Phase::Handoff];
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Init => "init",
+            Phase::Flash => "flash",
+            Phase::Window => "window",
+            Phase::Meta => "meta",
+            Phase::Load => "load",
+            Phase::Handoff => "handoff",
+        }
+    }
+}
+
+// Built-in phase budgets, in Phase order.
+const BUDGET_MS: [u32; PHASES.len()] = [100, 200, 300, 200, 5_000, 1_000];
+
+/// Work a late boot can do without, shed in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Optional {
+    /// The memory test of the load region.
+    Memtest,
+    /// Debug-level log messages.
+    Verbose,
+    /// The update key window and the autoboot countdown.
+    Window,
+}
+
+impl Optional {
+    fn name(self) -> &'static str {
+        match self {
+            Optional::Memtest => "memory test",
+            Optional::Verbose => "debug messages",
+            Optional::Window => "console window",
+        }
+    }
+
+    // Share of the deadline used past which it is shed.
+    fn shed_at_percent(self) -> u64 {
+        match self {
+            Optional::Memtest => 50,
+            Optional::Verbose => 70,
+            Optional::Window => 85,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    /// mtime at start().
+    start: u64,
+    deadline_ms: Option<u32>,
+    budget_ms: [u32; PHASES.len()],
+    phase: Phase,
+    /// mtime each phase was entered at, None until it is.
+    entered: [Option<u64>; PHASES.len()],
+    /// Ticks excused in each phase.
+    excused: [u64; PHASES.len()],
+    /// Optional work shed so far, by Optional (logged once each).
+    shed: [bool; 3],
+    expired_logged: bool,
+}
+
+// As nothing has started yet.
+const IDLE: State = State {
+    start: 0,
+    deadline_ms: None,
+    budget_ms: BUDGET_MS,
+    phase: Phase::Init,
+    entered: [None; PHASES.len()],
+    excused: [0; PHASES.len()],
+    shed: [false; 3],
+    expired_logged: false,
+};
+
+// Only the boot hart runs Rust code, so a plain static is enough.
+static mut STATE: State = IDLE;
+
+fn state() -> State {
+    unsafe { (&raw const STATE).read() }
+}
+
+fn update(f: impl FnOnce(&mut State)) {
+    let mut s = state();
+    f(&mut s);
+    unsafe { (&raw mut STATE).write(s) };
+}
+
+/// Start the clock, in phase Init; `deadline_ms` None leaves the
+/// deadline off (phases are still timed).
+pub fn start(deadline_ms: Option<u32>) {
+    let now = clint::mtime();
+    update(|s| {
+        s.start = now;
+        s.deadline_ms = deadline_ms;
+        s.entered[Phase::Init as usize] = Some(now);
+    });
+}
+
+/// Take the deadline and phase budgets from /chosen, in a "dev" build.
+pub fn configure(fdt: Option<&Fdt>) {
+    let Some(fdt) = fdt else {
+        return;
+    };
+    let deadline = fdt.node_prop("chosen", "spl,boot-deadline-ms").ok().flatten();
+    let budgets = fdt.node_prop("chosen", "spl,phase-budgets-ms").ok().flatten();
+    if deadline.is_none() && budgets.is_none() {
+        return;
+    }
+    if !cfg!(feature = "dev") {
+        slog_warn!("WARNING: boot budget: /chosen settings ignored, only a dev build takes them");
+        return;
+    }
+    update(|s| {
+        if let Some(ms) = deadline.and_then(|v| v.try_into().ok()).map(u32::from_be_bytes) {
+            // Zero turns it off.
+            s.deadline_ms = (ms != 0).then_some(ms);
+        }
+        for (budget, cell) in s.budget_ms.iter_mut().zip(budgets.unwrap_or(&[]).chunks_exact(4)) {
+            *budget = u32::from_be_bytes(cell.try_into().unwrap());
+        }
+    });
+    match state().deadline_ms {
+        Some(ms) => slog_info!("boot budget: deadline {} ms (from /chosen)", ms),
+        None => slog_info!("boot budget: no deadline (from /chosen)"),
+    }
+}
+
+// Ticks since start(), less the excused ones.
+fn elapsed_ticks() -> u64 {
+    let s = state();
+    let excused: u64 = s.excused.iter().sum();
+    clint::mtime().saturating_sub(s.start).saturating_sub(excused)
+}
+
+/// Milliseconds counted against the deadline so far.
+pub fn elapsed_ms() -> u64 {
+    clint::ticks_to_us(elapsed_ticks()) / 1000
+}
+
+/// The phase running.
+pub fn phase() -> Phase {
+    state().phase
+}
+
+pub fn deadline_ms() -> Option<u32> {
+    state().deadline_ms
+}
+
+// Milliseconds `phase` has taken (so far, for the one running), None if
+// it hasn't been entered.
+fn actual_ms(phase: Phase) -> Option<u64> {
+    let s = state();
+    let i = phase as usize;
+    let start = s.entered[i]?;
+    let end = s.entered[i + 1..].iter().flatten().next().copied().unwrap_or_else(clint::mtime);
+    Some(clint::ticks_to_us(end.saturating_sub(start).saturating_sub(s.excused[i])) / 1000)
+}
+
+/// Phase boundary: close the phase running (logging an overrun of its
+/// budget), start `phase`, and shed what the time left calls for.
+pub fn enter(phase: Phase) {
+    let s = state();
+    let done = s.phase;
+    if let Some(ms) = actual_ms(done)
+        && ms > s.budget_ms[done as usize] as u64
+    {
+        slog_warn!(
+            "WARNING: boot budget: {} took {} ms, over its {} ms",
+            done.name(),
+            ms,
+            s.budget_ms[done as usize]
+        );
+    }
+    let now = clint::mtime();
+    update(|s| {
+        s.phase = phase;
+        s.entered[phase as usize] = Some(now);
+    });
+    if !allows(Optional::Verbose) {
+        logger::mute_debug();
+    }
+    if expired() && !s.expired_logged {
+        update(|s| s.expired_logged = true);
+        let used = elapsed_ms();
+        slog_warn!("WARNING: boot budget: deadline passed, {} ms used, entering {}", used, phase.name());
+    }
+}
+
+/// Whether there is still time for `work`. Logs it the first time
+/// there isn't.
+pub fn allows(work: Optional) -> bool {
+    let s = state();
+    let Some(deadline) = s.deadline_ms else {
+        return true;
+    };
+    if elapsed_ms() * 100 < deadline as u64 * work.shed_at_percent() {
+        return true;
+    }
+    if !s.shed[work as usize] {
+        update(|s| s.shed[work as usize] = true);
+        slog_warn!(
+            "WARNING: boot budget: {} of {} ms used, skipping the {}",
+            elapsed_ms(),
+            deadline,
+            work.name()
+        );
+    }
+    false
+}
+
+/// Whether the deadline has passed.
+pub fn expired() -> bool {
+    state().deadline_ms.is_some_and(|ms| elapsed_ms() >= ms as u64)
+}
+
+/// What spl_main() does once the deadline has passed with nothing
+/// loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminal {
+    /// A "dev" build: the first candidate only, its checks only warning.
+    BestEffort,
+    /// Record it in the black box, then reset or stay in the SPL.
+    Stop,
+}
+
+/// The terminal action due, None while the deadline hasn't passed.
+pub fn terminal() -> Option<Terminal> {
+    expired().then_some(if cfg!(feature = "dev") { Terminal::BestEffort } else { Terminal::Stop })
+}
+
+/// Don't count the time since `since` (an mtime value) against the
+/// deadline, nor against the phase running.
+pub fn excuse(since: u64) {
+    let now = clint::mtime();
+    update(|s| s.excused[s.phase as usize] += now.saturating_sub(since));
+}
+
+/// Budget against actual time, for the boot report.
+pub struct Summary;
+
+/// As the boot report prints it, in ms: `init:3/100,flash:41/200,...,
+/// total:1234/10000`; `-` for a phase not entered or no deadline.
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = state();
+        for phase in PHASES {
+            f.write_str(phase.name())?;
+            match actual_ms(phase) {
+                Some(ms) => write!(f, ":{}", ms)?,
+                None => f.write_str(":-")?,
+            }
+            write!(f, "/{},", s.budget_ms[phase as usize])?;
+        }
+        write!(f, "total:{}/", elapsed_ms())?;
+        match s.deadline_ms {
+            Some(ms) => write!(f, "{}", ms),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // STATE is one for all the test threads.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    const DEADLINE_MS: u32 = 1000;
+
+    fn advance_ms(ms: u64) {
+        clint::advance_us(ms * 1000);
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Boot {
+        window: bool,
+        memtest: bool,
+        debug: bool,
+        terminal: Option<Terminal>,
+    }
+
+    // The phases up to Load as spl_main() goes through them, Init to
+    // Meta taking `ms` each, a person at the window `held_ms` more.
+    fn boot(ms: [u64; 4], held_ms: u64) -> Boot {
+        update(|s| *s = IDLE);
+        logger::take_debug_on();
+        start(Some(DEADLINE_MS));
+        advance_ms(ms[0]);
+        enter(Phase::Flash);
+        advance_ms(ms[1]);
+        enter(Phase::Window);
+        let window = allows(Optional::Window);
+        if window {
+            let since = clint::mtime();
+            advance_ms(held_ms);
+            excuse(since);
+            advance_ms(ms[2]);
+        }
+        enter(Phase::Meta);
+        advance_ms(ms[3]);
+        enter(Phase::Load);
+        let terminal = terminal();
+        Boot {
+            window,
+            memtest: allows(Optional::Memtest),
+            debug: logger::take_debug_on(),
+            terminal,
+        }
+    }
+
+    // The memory test goes at half the deadline, debug messages at 70%,
+    // the console window at 85%, and once gone they stay gone.
+    #[test]
+    fn sheds_in_order() {
+        let _serial = SERIAL.lock().unwrap();
+        update(|s| *s = IDLE);
+        start(Some(DEADLINE_MS));
+        let work = [Optional::Memtest, Optional::Verbose, Optional::Window];
+        let mut shed_at = [None; 3];
+        let mut expired_at = None;
+        for ms in 0..1100 {
+            for (at, work) in shed_at.iter_mut().zip(work) {
+                let allowed = allows(work);
+                assert!(!allowed || at.is_none(), "{:?} back at {} ms", work, ms);
+                if !allowed {
+                    at.get_or_insert(ms);
+                }
+            }
+            if expired() {
+                expired_at.get_or_insert(ms);
+            }
+            advance_ms(1);
+        }
+        assert_eq!(shed_at, [Some(500), Some(700), Some(850)]);
+        assert_eq!(expired_at, Some(1000));
+    }
+
+    // Slow phases shed what the time left calls for by Load; past the
+    // deadline, the terminal action is due. Time excused counts for
+    // nothing.
+    #[test]
+    fn slow_phases() {
+        let _serial = SERIAL.lock().unwrap();
+        let late = Some(if cfg!(feature = "dev") { Terminal::BestEffort } else { Terminal::Stop });
+        let boot_with = |window, memtest, debug, terminal| Boot {
+            window,
+            memtest,
+            debug,
+            terminal,
+        };
+        assert_eq!(boot([10, 50, 100, 50], 0), boot_with(true, true, true, None));
+        assert_eq!(boot([10, 450, 100, 50], 0), boot_with(true, false, true, None));
+        assert_eq!(boot([10, 450, 100, 50], 5_000), boot_with(true, false, true, None));
+        assert_eq!(boot([10, 50, 100, 600], 0), boot_with(true, false, false, None));
+        assert_eq!(boot([10, 860, 100, 50], 0), boot_with(false, false, false, None));
+        assert_eq!(boot([10, 980, 100, 50], 0), boot_with(false, false, false, late));
+        assert_eq!(boot([10, 50, 100, 50], 60_000), boot_with(true, true, true, None));
+    }
+
+    #[test]
+    fn summary() {
+        let _serial = SERIAL.lock().unwrap();
+        let report = concat!(
+            "init:10/100,flash:600/200,window:100/300,meta:50/200,",
+            "load:0/5000,handoff:-/1000,total:760/1000"
+        );
+        boot([10, 600, 100, 50], 0);
+        assert_eq!(Summary.to_string(), report);
+        boot([10, 600, 100, 50], 5_000);
+        assert_eq!(Summary.to_string(), report);
+    }
+}
@@ -0,0 +1,217 @@
+// Readers for fixed-layout structures in byte buffers (image headers,
+// GPT, CFI tables...): any alignment, explicit endianness, and a
+// `Truncated` error instead of a panic when the buffer is too short. A
+// layout is declared as typed field constants, its offset table in code:
+//
+//     const MAGIC: Be32 = Be32(0x00);
+//     const NAME: Bytes<32> = Bytes(0x20);
+//     let magic = MAGIC.get(&raw)?;
+//
+// and a buffer read front to back goes through a `Cursor`. Everything
+// copies out of the buffer: nothing hands out a reference to a u32 that
+// may be misaligned.
+
+use core::ops::Range;
+
+/// The buffer ends before `len` bytes at `at`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncated {
+    pub at: usize,
+    pub len: usize,
+}
+
+/// The `len` bytes at `at` in `buf`.
+pub fn slice(buf: &[u8], at: usize, len: usize) -> Result<&[u8], Truncated> {
+    at.checked_add(len).and_then(|end| buf.get(at..end)).ok_or(Truncated { at, len })
+}
+
+/// The `N` bytes at `at` in `buf`, copied.
+pub fn array<const N: usize>(buf: &[u8], at: usize) -> Result<[u8; N], Truncated> {
+    let mut a = [0u8; N];
+    a.copy_from_slice(slice(buf, at, N)?);
+    Ok(a)
+}
+
+/// A byte field at an offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct U8(pub usize);
+
+impl U8 {
+    pub fn get(self, buf: &[u8]) -> Result<u8, Truncated> {
+        buf.get(self.0).copied().ok_or(Truncated { at: self.0, len: 1 })
+    }
+}
+
+macro_rules! int_field {
+    ($($(#[$meta:meta])* $name:ident: $t:ty, $from:ident;)*) => {$(
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name(pub usize);
+
+        impl $name {
+            pub fn get(self, buf: &[u8]) -> Result<$t, Truncated> {
+                array(buf, self.0).map(<$t>::$from)
+            }
+
+            /// Where the field is, e.g. to zero it before a CRC. Not every
+            /// field type has one that is.
+            #[allow(dead_code)]
+            pub const fn range(self) -> Range<usize> {
+                self.0..self.0 + size_of::<$t>()
+            }
+        }
+    )*};
+}
+
+int_field! {
+    /// A little-endian u16 field at an offset.
+    Le16: u16, from_le_bytes;
+    /// A little-endian u32 field at an offset.
+    Le32: u32, from_le_bytes;
+    /// A big-endian u32 field at an offset.
+    Be32: u32, from_be_bytes;
+    /// A little-endian u64 field at an offset.
+    Le64: u64, from_le_bytes;
+}
+
+/// An `N`-byte field at an offset (a name, a digest, a GUID).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes<const N: usize>(pub usize);
+
+impl<const N: usize> Bytes<N> {
+    pub fn get(self, buf: &[u8]) -> Result<[u8; N], Truncated> {
+        array(buf, self.0)
+    }
+}
+
+/// Reads a buffer front to back. A failed read leaves the position
+/// where it was.
+#[derive(Debug, Clone)]
+pub struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Cursor { buf, pos: 0 }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Bytes left to read.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Truncated> {
+        let bytes = slice(self.buf, self.pos, len)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Truncated> {
+        let a = array(self.buf, self.pos)?;
+        self.pos += N;
+        Ok(a)
+    }
+
+    pub fn skip(&mut self, len: usize) -> Result<(), Truncated> {
+        self.read_bytes(len).map(|_| ())
+    }
+
+    pub fn read_u16_be(&mut self) -> Result<u16, Truncated> {
+        self.read_array().map(u16::from_be_bytes)
+    }
+
+    pub fn read_u32_be(&mut self) -> Result<u32, Truncated> {
+        self.read_array().map(u32::from_be_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rng;
+
+    // Random bytes, `len` of them.
+    fn noise(rng: &mut Rng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| rng.below(256) as u8).collect()
+    }
+
+    // Every field type read at `at` of `buf`: the value as decoded by
+    // hand, or Truncated if it doesn't fit.
+    fn check_fields(buf: &[u8], at: usize) {
+        let fits = |n: usize| at.checked_add(n).is_some_and(|end| end <= buf.len());
+        let want = |n: usize| match fits(n) {
+            true => Ok(buf[at..at + n].to_vec()),
+            false => Err(Truncated { at, len: n }),
+        };
+        let le = |n| want(n).map(|b| b.iter().rev().fold(0u64, |v, &x| v << 8 | x as u64));
+        let be = |n| want(n).map(|b| b.iter().fold(0u64, |v, &x| v << 8 | x as u64));
+        assert_eq!(U8(at).get(buf).map(u64::from), le(1), "u8 at {} of {}", at, buf.len());
+        assert_eq!(Le16(at).get(buf).map(u64::from), le(2), "le16 at {} of {}", at, buf.len());
+        assert_eq!(Le32(at).get(buf).map(u64::from), le(4), "le32 at {} of {}", at, buf.len());
+        assert_eq!(Be32(at).get(buf).map(u64::from), be(4), "be32 at {} of {}", at, buf.len());
+        assert_eq!(Le64(at).get(buf), le(8), "le64 at {} of {}", at, buf.len());
+        assert_eq!(Bytes::<16>(at).get(buf).map(|b| b.to_vec()), want(16), "bytes at {} of {}", at, buf.len());
+    }
+
+    // Fields at every offset (so at every alignment) of buffers of every
+    // length up to past them, and at offsets a length field could claim.
+    #[test]
+    fn fields_truncated_or_misaligned() {
+        let mut rng = Rng(1);
+        for len in 0..40 {
+            let buf = noise(&mut rng, len);
+            for at in 0..len + 20 {
+                check_fields(&buf, at);
+            }
+            for at in [usize::MAX, usize::MAX - 3, usize::MAX - 15, 1 << 40] {
+                check_fields(&buf, at);
+            }
+        }
+        // A field not at the start of its buffer's allocation.
+        let buf = noise(&mut rng, 64);
+        for skew in 1..8 {
+            check_fields(&buf[skew..], 3);
+        }
+    }
+
+    // Random reads of a cursor over random buffers: each one fits or is
+    // Truncated, and a failed one goes nowhere.
+    #[test]
+    fn cursor_runs_out() {
+        let mut rng = Rng(7);
+        for _ in 0..2000 {
+            let len = rng.below(48) as usize;
+            let buf = noise(&mut rng, len);
+            let mut c = Cursor::new(&buf);
+            for _ in 0..24 {
+                let (pos, left) = (c.position(), c.remaining());
+                let (n, ok) = match rng.below(7) {
+                    0 => (2, c.read_u16_be().is_ok()),
+                    1 => (4, c.read_u32_be().is_ok()),
+                    2 => (3, c.read_array::<3>().is_ok()),
+                    3 => (0, c.read_bytes(0).is_ok()),
+                    4 => {
+                        let n = rng.below(16) as usize;
+                        (n, c.skip(n).is_ok())
+                    }
+                    5 => (usize::MAX, c.read_bytes(usize::MAX).is_ok()),
+                    _ => {
+                        let n = rng.below(16) as usize;
+                        (n, c.read_bytes(n).is_ok_and(|b| b == &buf[pos..pos + n]))
+                    }
+                };
+                assert_eq!(ok, n <= left, "{} bytes, {} left", n, left);
+                assert_eq!(c.position(), if ok { pos + n } else { pos });
+                assert_eq!(c.position() + c.remaining(), buf.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::board;
+use crate::mmio::Reg32;
+
+// CLINT (core-local interruptor) time source: the free-running 64-bit
+// mtime counter, used for delays, timeouts and boot timestamps. Also the
+// msip registers, to wake parked harts.
+
+const MSIP_OFFSET: usize = 0x0000; // + 4 * hartid
+const MTIMECMP_OFFSET: usize = 0x4000; // + 8 * hartid
+const MTIME_OFFSET: usize = 0xbff8;
+
+static CLINT_BASE: AtomicUsize = AtomicUsize::new(board::CLINT_BASE);
+static TIMEBASE: AtomicU32 = AtomicU32::new(board::TIMEBASE_HZ);
+
+/// Use the CLINT at `base`, counting at `timebase_hz` (both normally from
+/// the DTB).
+pub fn init(base: usize, timebase_hz: u32) {
+    CLINT_BASE.store(base, Ordering::Relaxed);
+    if timebase_hz != 0 {
+        TIMEBASE.store(timebase_hz, Ordering::Relaxed);
+    }
+}
+
+/// Address of the mtime register, for code that can't call mtime() (the
+/// flash routines running from RAM).
+pub fn mtime_addr() -> usize {
+    CLINT_BASE.load(Ordering::Relaxed) + MTIME_OFFSET
+}
+
+/// Current mtime value, in timebase ticks.
+///
+/// Read as two 32-bit halves (hi, lo, hi again) so the same code is
+/// correct on CLINTs that only take 32-bit accesses, and a carry from
+/// lo into hi between the two reads can't give a torn value.
+pub fn mtime() -> u64 {
+    let (lo, hi) = unsafe { (Reg32::at(mtime_addr(), 0), Reg32::at(mtime_addr(), 4)) };
+    loop {
+        let (hi, lo, hi2) = (hi.read(), lo.read(), hi.read());
+        if hi == hi2 {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+/// Set mtimecmp of `hartid`: its timer interrupt is pending once mtime
+/// reaches `ticks`.
+///
+/// Written in 32-bit halves, high word parked at all-ones first so no
+/// intermediate value can trigger an early interrupt.
+pub fn set_mtimecmp(hartid: usize, ticks: u64) {
+    let cmp = CLINT_BASE.load(Ordering::Relaxed) + MTIMECMP_OFFSET + 8 * hartid;
+    let (lo, hi) = unsafe { (Reg32::at(cmp, 0), Reg32::at(cmp, 4)) };
+    hi.write(u32::MAX);
+    lo.write(ticks as u32);
+    hi.write((ticks >> 32) as u32);
+}
+
+fn msip(hartid: usize) -> Reg32 {
+    unsafe { Reg32::at(CLINT_BASE.load(Ordering::Relaxed) + MSIP_OFFSET, 4 * hartid) }
+}
+
+/// Raise a software interrupt on `hartid`.
+pub fn send_ipi(hartid: usize) {
+    msip(hartid).write(1);
+}
+
+/// Acknowledge the software interrupt of `hartid`.
+pub fn clear_ipi(hartid: usize) {
+    msip(hartid).write(0);
+}
+
+fn us_to_ticks(us: u64) -> u64 {
+    us.saturating_mul(TIMEBASE.load(Ordering::Relaxed) as u64) / 1_000_000
+}
+
+/// Convert a tick count to microseconds.
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    ticks.saturating_mul(1_000_000) / TIMEBASE.load(Ordering::Relaxed) as u64
+}
+
+/// mtime in microseconds since reset.
+pub fn now_us() -> u64 {
+    ticks_to_us(mtime())
+}
+
+/// A point in time to wait for, e.g. the end of a timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    at: u64,
+}
+
+impl Deadline {
+    /// `us` microseconds from now.
+    pub fn after_us(us: u64) -> Self {
+        Deadline {
+            at: mtime().saturating_add(us_to_ticks(us)),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        mtime() >= self.at
+    }
+
+    /// The mtime value at which it expires.
+    pub fn ticks(&self) -> u64 {
+        self.at
+    }
+}
+
+/// Busy-wait for at least `us` microseconds.
+pub fn delay_us(us: u64) {
+    let deadline = Deadline::after_us(us);
+    while !deadline.expired() {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,928 @@
+use core::fmt::{self, Write};
+use core::result::Result;
+
+use crate::addr::FlashOffset;
+use crate::arch::probe::{self, Fault, Primitive};
+use crate::board;
+use crate::bootmeta::{BootBank, BootMeta};
+use crate::clint::Deadline;
+use crate::descriptor;
+use crate::flash_intel::{FlashError, IntelFlash};
+use crate::hash::Hex;
+use crate::image::{flash_crc32, SplImageHeader};
+use crate::layout;
+use crate::logger::{self, ConsoleWriter, RxError, HEXDUMP_WIDTH};
+use crate::reset_cause::{self, ResetCause};
+use crate::uimage::UImageHeader;
+use crate::vcache::VerifyCache;
+use crate::watchdog::{self, Cancelled, Progress};
+
+// Recovery shell on the console, for bring-up and field recovery. It is
+// entered by a key during the boot window or by stopping autoboot, and
+// only built with the "console" feature.
+//
+// Entering it writes nothing: only commands that say so (confirm,
+// erase-meta, provision) touch the flash. "bank <name>" overrides the bank
+// for this boot only, it is not stored anywhere. "md" reads anywhere, a
+// fault printed rather than fatal; "mw" only writes RAM that
+// layout::check_poke() allows, never the flash or the SPL itself.
+// Ctrl-C stops a long command between two flash blocks.
+
+const PROMPT: &str = "spl1> ";
+const MAX_LINE: usize = 80;
+// Command word included.
+const MAX_ARGS: usize = 4;
+// Largest "md" dump in bytes, so a typo doesn't flood the console for
+// minutes.
+const MD_MAX_LEN: usize = 4096;
+// Lines "meta" and "status" print before waiting for a key.
+const PAGE_LINES: usize = 22;
+// Newest boot log entries "status" shows.
+const STATUS_ENTRIES: usize = 8;
+// Digest bytes "status" shows of each image.
+const DIGEST_PREFIX: usize = 8;
+// Stops a long command (see interruptible()).
+const CTRL_C: u8 = 0x03;
+// What "provision" wants typed before it erases anything.
+#[cfg(feature = "provision")]
+const PROVISION_CONFIRM: &str = "erase everything";
+// "provision" progress line every this many blocks.
+#[cfg(feature = "provision")]
+const PROVISION_REPORT_EVERY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmdError {
+    /// Wrong arguments: print the command's usage.
+    Usage,
+    Flash(FlashError),
+    /// A memory access faulted.
+    Fault(Fault),
+    /// "mw" outside what it may write, and why.
+    Refused(&'static str),
+}
+
+struct Shell<'a> {
+    /// The flash units, by index.
+    flash: &'a [IntelFlash],
+    meta: &'a BootMeta<'a>,
+    forced: Option<BootBank>,
+    done: bool,
+}
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&mut Shell, &[&str]) -> Result<(), CmdError>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list commands",
+        run: cmd_help,
+    },
+    Command {
+        name: "version",
+        usage: "version",
+        help: "SPL build and the layout it was built for",
+        run: cmd_version,
+    },
+    Command {
+        name: "md",
+        usage: "md <addr> <len>",
+        help: "same as md.b",
+        run: cmd_md::<u8>,
+    },
+    Command {
+        name: "md.b",
+        usage: "md.b <addr> <count>",
+        help: "dump memory, byte reads (4096 bytes max)",
+        run: cmd_md::<u8>,
+    },
+    Command {
+        name: "md.w",
+        usage: "md.w <addr> <count>",
+        help: "same, 16-bit reads",
+        run: cmd_md::<u16>,
+    },
+    Command {
+        name: "md.d",
+        usage: "md.d <addr> <count>",
+        help: "same, 32-bit reads",
+        run: cmd_md::<u32>,
+    },
+    Command {
+        name: "mw.b",
+        usage: "mw.b <addr> <value>",
+        help: "write a byte (scratch RAM, load windows)",
+        run: cmd_mw::<u8>,
+    },
+    Command {
+        name: "mw.w",
+        usage: "mw.w <addr> <value>",
+        help: "same, 16 bits",
+        run: cmd_mw::<u16>,
+    },
+    Command {
+        name: "mw.d",
+        usage: "mw.d <addr> <value>",
+        help: "same, 32 bits",
+        run: cmd_mw::<u32>,
+    },
+    Command {
+        name: "flash",
+        usage: "flash info",
+        help: "flash layout and bank contents",
+        run: cmd_flash,
+    },
+    Command {
+        name: "meta",
+        usage: "meta",
+        help: "dump the boot log entries",
+        run: cmd_meta,
+    },
+    Command {
+        name: "status",
+        usage: "status",
+        help: "why the next boot picks its bank (reads only)",
+        run: cmd_status,
+    },
+    Command {
+        name: "bank",
+        usage: "bank <name>",
+        help: "boot this bank first (this boot only)",
+        run: cmd_bank,
+    },
+    Command {
+        name: "confirm",
+        usage: "confirm <name>",
+        help: "confirm the bank's last boot (writes flash)",
+        run: cmd_confirm,
+    },
+    Command {
+        name: "erase-meta",
+        usage: "erase-meta",
+        help: "erase the boot log (writes flash)",
+        run: cmd_erase_meta,
+    },
+    #[cfg(feature = "provision")]
+    Command {
+        name: "provision",
+        usage: "provision",
+        help: "erase all writable flash but the SPL (asks first)",
+        run: cmd_provision,
+    },
+    Command {
+        name: "boot",
+        usage: "boot",
+        help: "leave the shell and boot",
+        run: cmd_boot,
+    },
+    Command {
+        name: "reset",
+        usage: "reset",
+        help: "reset the board",
+        run: cmd_reset,
+    },
+];
+
+/// Run the shell until "boot". Returns the bank forced with "bank", if
+/// any.
+pub fn run(flash: &[IntelFlash], meta: &BootMeta) -> Option<BootBank> {
+    let mut shell = Shell {
+        flash,
+        meta,
+        forced: None,
+        done: false,
+    };
+    let mut w = ConsoleWriter;
+    let _ = writeln!(w, "SPL1 recovery console, 'help' for commands");
+    let mut line = [0u8; MAX_LINE];
+    while !shell.done {
+        logger::console_puts(PROMPT);
+        let n = loop {
+            match logger::read_line(&mut line, Deadline::after_us(u64::MAX)) {
+                Ok(n) => break n,
+                // A line error loses the line: start over on a new one.
+                Err(RxError::Timeout) => {}
+                Err(e) => {
+                    let _ = write!(w, "\n{:?}\n{}", e, PROMPT);
+                }
+            }
+        };
+        match core::str::from_utf8(&line[..n]) {
+            Ok(s) => shell.execute(s),
+            Err(_) => logger::console_puts("not ASCII\n"),
+        }
+    }
+    shell.forced
+}
+
+impl Shell<'_> {
+    fn execute(&mut self, line: &str) {
+        let mut argv = [""; MAX_ARGS];
+        let mut argc = 0;
+        for word in line.split_ascii_whitespace() {
+            if argc == MAX_ARGS {
+                logger::console_puts("too many arguments\n");
+                return;
+            }
+            argv[argc] = word;
+            argc += 1;
+        }
+        if argc == 0 {
+            return;
+        }
+        let mut w = ConsoleWriter;
+        let Some(cmd) = COMMANDS.iter().find(|c| c.name == argv[0]) else {
+            let _ = writeln!(w, "unknown command '{}', try 'help'", argv[0]);
+            return;
+        };
+        let outer = watchdog::set_yield_hook(Some(interruptible));
+        let result = (cmd.run)(self, &argv[1..argc]);
+        watchdog::set_yield_hook(outer);
+        match result {
+            Ok(()) => {}
+            Err(CmdError::Usage) => {
+                let _ = writeln!(w, "usage: {}", cmd.usage);
+            }
+            Err(CmdError::Fault(f)) => {
+                let _ = writeln!(w, "{}: access fault at 0x{:x} (mcause {})", cmd.name, f.addr, f.cause);
+            }
+            Err(CmdError::Refused(why)) => {
+                let _ = writeln!(w, "{}: refused: {}", cmd.name, why);
+            }
+            Err(e) => {
+                let _ = writeln!(w, "{}: {:?}", cmd.name, e);
+            }
+        }
+    }
+}
+
+// Yield hook while a command runs: the SPL's own, and Ctrl-C stops a
+// long one (a provision, say) at its next yield point.
+fn interruptible(p: Progress) -> Result<(), Cancelled> {
+    watchdog::pet_hook(p)?;
+    match logger::getc_timeout(Deadline::after_us(0)) {
+        Ok(CTRL_C) => Err(Cancelled),
+        _ => Ok(()),
+    }
+}
+
+// "0x"-prefixed hex, else decimal.
+fn parse_num(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// Hex, with or without "0x": addresses and values.
+fn parse_hex(s: &str) -> Option<usize> {
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    usize::from_str_radix(hex, 16).ok()
+}
+
+fn cmd_help(_: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    let mut w = ConsoleWriter;
+    for c in COMMANDS {
+        let _ = writeln!(w, "  {:<18} {}", c.usage, c.help);
+    }
+    Ok(())
+}
+
+fn cmd_version(_: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    let _ = descriptor::read().dump(&mut ConsoleWriter);
+    Ok(())
+}
+
+// "md.b", "md.w", "md.d": `count` units of T from `addr`, each one read
+// with a single access of its size (device registers care), through the
+// probe so that a fault ends the dump with a message instead of the
+// trap handler's.
+fn cmd_md<T: Primitive + Into<u64>>(_: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    let [addr, count] = args else {
+        return Err(CmdError::Usage);
+    };
+    let (Some(addr), Some(count)) = (parse_hex(addr), parse_num(count)) else {
+        return Err(CmdError::Usage);
+    };
+    let size = core::mem::size_of::<T>();
+    let len = count.checked_mul(size).filter(|len| (1..=MD_MAX_LEN).contains(len));
+    let Some(len) = len.filter(|&len| addr.is_multiple_of(size) && addr.checked_add(len).is_some()) else {
+        return Err(CmdError::Usage);
+    };
+    let mut w = ConsoleWriter;
+    let mut line = [0u8; HEXDUMP_WIDTH];
+    for start in (0..len).step_by(HEXDUMP_WIDTH) {
+        let n = (len - start).min(HEXDUMP_WIDTH);
+        for i in (0..n).step_by(size) {
+            // Reads may have side effects on a device: the user's call.
+            let v: T = unsafe { probe::try_read_volatile(addr + start + i) }.map_err(CmdError::Fault)?;
+            line[i..i + size].copy_from_slice(&v.into().to_le_bytes()[..size]);
+        }
+        let _ = logger::hexdump_line(&mut w, addr + start, &line[..n]);
+    }
+    Ok(())
+}
+
+// "mw.b", "mw.w", "mw.d": one write of `value` at `addr`, which must be
+// aligned for it, only where layout::check_poke() allows.
+fn cmd_mw<T: Primitive + TryFrom<u64>>(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    let [addr, value] = args else {
+        return Err(CmdError::Usage);
+    };
+    let (Some(addr), Some(value)) = (parse_hex(addr), parse_hex(value)) else {
+        return Err(CmdError::Usage);
+    };
+    let size = core::mem::size_of::<T>();
+    let (Ok(value), true) = (T::try_from(value as u64), addr.is_multiple_of(size)) else {
+        return Err(CmdError::Usage);
+    };
+    layout::check_poke(addr, size, shell.flash).map_err(CmdError::Refused)?;
+    // Checked above: scratch RAM or a load window, nothing live.
+    unsafe { probe::try_write_volatile(addr, value) }.map_err(CmdError::Fault)
+}
+
+// What the start of a bank looks like, without checking any CRC.
+fn bank_kind(flash: &IntelFlash, offset: FlashOffset) -> &'static str {
+    let magic = flash.read_u32_le(offset);
+    if magic == SplImageHeader::MAGIC {
+        "SPL1 header"
+    } else if UImageHeader::probe(flash, offset.get()) {
+        "uImage"
+    } else if magic == 0xFFFF_FFFF {
+        "erased?"
+    } else {
+        "raw?"
+    }
+}
+
+fn cmd_flash(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if args != ["info"] {
+        return Err(CmdError::Usage);
+    }
+    let mut w = ConsoleWriter;
+    for (unit, flash) in shell.flash.iter().enumerate() {
+        let _ = writeln!(
+            w,
+            "flash{} at 0x{:x}, {} KiB, {} KiB blocks{}",
+            unit,
+            flash.base,
+            flash.size / 1024,
+            flash.block_size / 1024,
+            if board::FLASH_WRITABLE[unit] { "" } else { ", read-only" }
+        );
+    }
+    for bank in shell.meta.banks() {
+        let desc = bank.desc();
+        let flash = &shell.flash[desc.unit];
+        let _ = writeln!(
+            w,
+            "  bank {}  0x{:08x}+0x{:x}  {}",
+            desc.name,
+            flash.addr_of(desc.offset),
+            desc.size,
+            bank_kind(flash, desc.offset)
+        );
+    }
+    if let Some(offset) = crate::GOLDEN_OFFSET.map(FlashOffset::new) {
+        let golden = &shell.flash[crate::GOLDEN_UNIT];
+        let _ = writeln!(
+            w,
+            "  golden  0x{:08x}+0x{:x}  {}",
+            golden.addr_of(offset),
+            board::BANK_SIZE,
+            bank_kind(golden, offset)
+        );
+    }
+    let _ = writeln!(
+        w,
+        "  meta    0x{:08x}+0x{:x}",
+        shell.flash[board::META_UNIT].addr_of(FlashOffset::new(board::META_OFFSET)),
+        board::META_SIZE
+    );
+    Ok(())
+}
+
+// Console output a screen at a time: past PAGE_LINES lines it waits for
+// a key, and 'q' drops the rest (writes fail from then on, which the
+// commands ignore).
+#[derive(Default)]
+struct Pager {
+    lines: usize,
+    quit: bool,
+}
+
+impl Write for Pager {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s;
+        while !rest.is_empty() {
+            if self.quit {
+                return Err(fmt::Error);
+            }
+            if self.lines == PAGE_LINES {
+                logger::console_puts("-- more, q to stop --");
+                let key = logger::getc_timeout(Deadline::after_us(u64::MAX));
+                logger::console_puts("\r                     \r");
+                self.quit = matches!(key, Ok(b'q' | b'Q'));
+                self.lines = 0;
+                continue;
+            }
+            let (line, tail) = rest.split_at(rest.find('\n').map_or(rest.len(), |i| i + 1));
+            logger::console_puts(line);
+            if line.ends_with('\n') {
+                self.lines += 1;
+            }
+            rest = tail;
+        }
+        Ok(())
+    }
+}
+
+// One boot log entry, as "meta" and "status" show it.
+fn entry_line(w: &mut impl Write, meta: &BootMeta, i: usize, word: u32) -> fmt::Result {
+    let digits = 2 * <BootMeta>::ENTRY_SIZE;
+    match meta.decode(word) {
+        Some((bank, state)) => writeln!(w, "  {:5}: 0x{:0digits$x} bank {} {:?}", i, word, bank, state),
+        None => writeln!(w, "  {:5}: 0x{:0digits$x} unknown, log ends here", i, word),
+    }
+}
+
+fn cmd_meta(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    let mut w = Pager::default();
+    for (i, word) in shell.meta.entries().enumerate() {
+        if entry_line(&mut w, shell.meta, i, word).is_err() {
+            return Ok(());
+        }
+    }
+    let trials = shell.meta.scan();
+    for bank in shell.meta.banks() {
+        let t = trials.bank(bank);
+        let _ = writeln!(
+            w,
+            "bank {}: {} unconfirmed, {} confirmed, {} died in SPL1",
+            bank,
+            t.unconfirmed,
+            t.confirmed,
+            t.no_handoff
+        );
+    }
+    let _ = writeln!(w, "next_idx = {}, compactions = {:?}", trials.next_idx, shell.meta.compaction_count());
+    if shell.meta.poisoned() {
+        let _ = writeln!(w, "poisoned (failed compaction or corrupt block): erase-meta to start over");
+    }
+    Ok(())
+}
+
+// Everything that goes into the choice of a bank, read from flash
+// without writing any: the boot log, the override, each bank's image
+// and what the verification cache says of it, and the flash parts.
+fn cmd_status(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    let meta = shell.meta;
+    let mut w = Pager::default();
+    let state = if meta.poisoned() {
+        "POISONED"
+    } else if meta.read_only() {
+        "read-only"
+    } else if meta.erase_interrupted() {
+        "compaction cut off"
+    } else {
+        "ok"
+    };
+    let _ = write!(w, "boot log    {} encoding, {}, ", meta.encoding(), state);
+    let _ = match meta.compaction_count() {
+        Some(n) => {
+            let permille = u64::from(n) * 1000 / u64::from(crate::FLASH_ERASE_CYCLES);
+            writeln!(w, "{} compactions (~{}.{}% worn)", n, permille / 10, permille % 10)
+        }
+        None => writeln!(w, "no header yet"),
+    };
+    let _ = match meta.boot_override() {
+        None => writeln!(w, "override    none"),
+        Some(Ok(force)) => writeln!(w, "override    {}, next boot", force),
+        Some(Err(word)) => writeln!(w, "override    0x{:08x}, not valid: cleared at boot", word),
+    };
+    let chosen = meta.choose_bank(crate::MAX_TRIALS);
+    let _ = writeln!(w, "next boot   bank {} ({} failed trials max)", chosen, crate::MAX_TRIALS);
+
+    let trials = meta.scan();
+    let _ = writeln!(
+        w,
+        "\n{:<8} {:>8} {:>11} {:>9} {:>10}",
+        "bank", "attempts", "unconfirmed", "confirmed", "no handoff"
+    );
+    for bank in meta.banks() {
+        let t = trials.bank(bank);
+        let _ = writeln!(
+            w,
+            "{:<8} {:>8} {:>11} {:>9} {:>10}",
+            bank.desc().name,
+            t.no_handoff + t.unconfirmed + t.confirmed,
+            t.unconfirmed,
+            t.confirmed,
+            t.no_handoff
+        );
+    }
+    let total = meta.entries().count();
+    let _ = writeln!(w, "\nnewest entries ({} in all):", total);
+    for (i, word) in meta.entries().enumerate().skip(total.saturating_sub(STATUS_ENTRIES)) {
+        let _ = entry_line(&mut w, meta, i, word);
+    }
+
+    let cache_offset = FlashOffset::new(board::VCACHE_OFFSET);
+    let cache = VerifyCache::new(&shell.flash[board::VCACHE_UNIT], cache_offset, board::VCACHE_SIZE);
+    let _ = writeln!(w, "\n{:<8} {:>4} {:>10}  {:<16}  cache", "bank", "hdr", "payload", "sha256");
+    for bank in meta.banks() {
+        let desc = bank.desc();
+        let flash = &shell.flash[desc.unit];
+        let hdr = match SplImageHeader::parse(flash, desc.offset.get(), desc.size, &[]) {
+            Ok(hdr) => hdr,
+            Err(e) => {
+                let _ = writeln!(w, "{:<8} {} ({:?})", desc.name, bank_kind(flash, desc.offset), e);
+                continue;
+            }
+        };
+        let hdr_crc = flash_crc32(flash, desc.offset.get(), hdr.hdr_size as usize);
+        let cached = match cache.lookup(bank) {
+            None => "none",
+            Some(c) if c.hdr_crc != hdr_crc => "other image",
+            Some(c) if c.ok => "verified",
+            Some(_) => "failed",
+        };
+        let _ = match hdr.expected_sha256() {
+            Some(digest) => writeln!(
+                w,
+                "{:<8} {:>4} {:>10}  {:<16}  {}",
+                desc.name,
+                hdr.version,
+                hdr.payload_size,
+                Hex(&digest[..DIGEST_PREFIX]),
+                cached
+            ),
+            None => writeln!(
+                w,
+                "{:<8} {:>4} {:>10}  {:<16}  {}",
+                desc.name, hdr.version, hdr.payload_size, "-", cached
+            ),
+        };
+    }
+
+    let _ = writeln!(w);
+    for (unit, flash) in shell.flash.iter().enumerate() {
+        let blocks = flash.size / flash.block_size;
+        let id = match flash.identify() {
+            Ok(id) => id,
+            Err(e) => {
+                let _ = writeln!(w, "flash{}      no identifier ({:?}), {} blocks", unit, e, blocks);
+                continue;
+            }
+        };
+        let mut map = [0u8; crate::LOCK_MAP_BYTES];
+        let _ = write!(
+            w,
+            "flash{}      vendor=0x{:02x} dev=0x{:02x} {} MiB, {} blocks, ",
+            unit,
+            id.manufacturer,
+            id.device,
+            flash.size >> 20,
+            blocks
+        );
+        let _ = match flash.lock_map(&id, &mut map) {
+            Ok(n) => writeln!(w, "{} locked", n),
+            Err(e) => writeln!(w, "lock sweep failed ({:?})", e),
+        };
+    }
+    Ok(())
+}
+
+// The bank named by the only argument.
+fn bank_arg(shell: &Shell, args: &[&str]) -> Result<BootBank, CmdError> {
+    match args {
+        [name] => shell.meta.bank_by_name(name).ok_or(CmdError::Usage),
+        _ => Err(CmdError::Usage),
+    }
+}
+
+fn cmd_bank(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    let bank = bank_arg(shell, args)?;
+    shell.forced = Some(bank);
+    let _ = writeln!(ConsoleWriter, "bank {} first on 'boot'", bank);
+    Ok(())
+}
+
+fn cmd_confirm(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    let bank = bank_arg(shell, args)?;
+    shell.meta.record_success(bank).map_err(CmdError::Flash)?;
+    let _ = writeln!(ConsoleWriter, "bank {} boot confirmed", bank);
+    Ok(())
+}
+
+fn cmd_erase_meta(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    shell.meta.erase().map_err(CmdError::Flash)?;
+    logger::console_puts("boot log erased\n");
+    Ok(())
+}
+
+// Bring a blank or unknown flash to the layout we expect: every
+// writable unit erased, the SPL's own region excepted. Erased is the
+// initial state of everything else: banks are empty, and an erased
+// metadata block is an empty boot log.
+#[cfg(feature = "provision")]
+fn cmd_provision(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    let mut w = ConsoleWriter;
+    let _ = write!(w, "type '{}' to erase all writable flash: ", PROVISION_CONFIRM);
+    let mut line = [0u8; MAX_LINE];
+    let confirmed = match logger::read_line(&mut line, Deadline::after_us(u64::MAX)) {
+        Ok(n) => line[..n].trim_ascii() == PROVISION_CONFIRM.as_bytes(),
+        Err(_) => false,
+    };
+    if !confirmed {
+        logger::console_puts("not confirmed, nothing erased\n");
+        return Ok(());
+    }
+    for (unit, flash) in shell.flash.iter().enumerate() {
+        if !board::FLASH_WRITABLE[unit] {
+            let _ = writeln!(w, "flash{}: read-only, skipped", unit);
+            continue;
+        }
+        let keep = if unit == 0 { board::SPL_FLASH_SIZE } else { 0 };
+        let _ = writeln!(w, "flash{}: erasing from 0x{:x}", unit, flash.addr_of(FlashOffset::new(keep)));
+        flash
+            .chip_erase(keep, |done, total| {
+                if done % PROVISION_REPORT_EVERY == 0 || done == total {
+                    let _ = writeln!(ConsoleWriter, "flash{}: {}/{} blocks erased", unit, done, total);
+                }
+            })
+            .map_err(CmdError::Flash)?;
+    }
+    logger::console_puts("provisioned: banks empty, boot log empty\n");
+    Ok(())
+}
+
+fn cmd_boot(shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    shell.done = true;
+    Ok(())
+}
+
+fn cmd_reset(_shell: &mut Shell, args: &[&str]) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage);
+    }
+    logger::console_puts("resetting\n");
+    reset_cause::reset(ResetCause::Software)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::PhysAddr;
+    use crate::{arena, banks};
+    use crate::watchdog::tests::HOOKED;
+
+    // The board's flash units, erased but for an SPL1 header magic at
+    // the start of the first bank.
+    fn units() -> Vec<IntelFlash> {
+        let flash: Vec<_> = (0..board::FLASH_UNITS)
+            .map(|unit| {
+                let base = PhysAddr::new(board::FLASH_BASE[unit]);
+                IntelFlash::new(base, board::FLASH_SIZE[unit], board::FLASH_BLOCK_SIZE[unit], Vec::new())
+            })
+            .collect();
+        let a = banks::table()[0];
+        flash[a.unit].program_u32_le(a.offset, SplImageHeader::MAGIC).unwrap();
+        flash
+    }
+
+    fn meta(flash: &[IntelFlash]) -> BootMeta<'_> {
+        let offset = FlashOffset::new(board::META_OFFSET);
+        BootMeta::new(&flash[board::META_UNIT], offset, board::META_SIZE, banks::table()).unwrap()
+    }
+
+    // Programs and erases since the last call, on every unit.
+    fn writes(flash: &[IntelFlash]) -> u32 {
+        flash
+            .iter()
+            .map(|f| {
+                let ops = f.ops();
+                f.inject(Default::default());
+                ops.programs + ops.erases
+            })
+            .sum()
+    }
+
+    // Type `script` at the prompt and run the shell until "boot": what
+    // it returned and what it printed. The whole script is read.
+    fn session(flash: &[IntelFlash], meta: &BootMeta, script: &[u8]) -> (Option<BootBank>, String) {
+        let _turn = HOOKED.lock().unwrap_or_else(|e| e.into_inner());
+        logger::type_input(script);
+        let forced = run(flash, meta);
+        assert_eq!(logger::getc_timeout(Deadline::after_us(0)), Err(RxError::Timeout));
+        (forced, String::from_utf8(logger::take_output()).unwrap())
+    }
+
+    // Each of `expected` in `output`, in that order.
+    fn assert_in_order(output: &str, expected: &[&str]) {
+        let mut rest = output;
+        for e in expected {
+            match rest.find(e) {
+                Some(i) => rest = &rest[i + e.len()..],
+                None => panic!("{:?} missing, or out of order, in:\n{}", e, output),
+            }
+        }
+    }
+
+    // A session of commands that only read: what each prints, a line
+    // edited with backspace, the bank picked with "bank" returned on
+    // "boot", and not one flash write.
+    #[test]
+    fn scripted_session() {
+        let flash = units();
+        let meta = meta(&flash);
+        writes(&flash);
+        let (a, b) = (banks::table()[0], banks::table()[1]);
+        let edit = format!("bak\x7f\x7fank {}", b.name);
+        let lines = ["help", "versoin", "md", "flash info", "meta", "status", &edit, "", "boot", ""];
+        let script = lines.join("\r");
+        let (forced, output) = session(&flash, &meta, script.as_bytes());
+        assert_eq!(forced, meta.bank_by_name(b.name));
+        assert_eq!(writes(&flash), 0);
+        assert_eq!(output.matches(PROMPT).count(), 9);
+        let bank_a = format!("  bank {}  0x{:08x}+0x", a.name, flash[a.unit].addr_of(a.offset));
+        let edited = format!("bak\x08 \x08\x08 \x08ank {}\n", b.name);
+        let forced_line = format!("bank {} first on 'boot'\n", b.name);
+        assert_in_order(
+            &output,
+            &[
+                "SPL1 recovery console, 'help' for commands\n",
+                "spl1> help\n",
+                "  md <addr> <len>    same as md.b\n",
+                "  boot               leave the shell and boot\n",
+                "spl1> versoin\nunknown command 'versoin', try 'help'\n",
+                "spl1> md\nusage: md <addr> <len>\n",
+                "spl1> flash info\n",
+                &bank_a,
+                "SPL1 header\n",
+                "erased?\n",
+                "  meta    0x",
+                "spl1> meta\n",
+                "next_idx = ",
+                "spl1> status\n",
+                "boot log    ",
+                "newest entries (",
+                "0 locked\n",
+                &edited,
+                &forced_line,
+                "spl1> \n",
+                "spl1> boot\n",
+            ],
+        );
+    }
+
+    // Only "confirm" and "erase-meta" write, and do.
+    #[test]
+    fn writes_only_when_told() {
+        let flash = units();
+        let meta = meta(&flash);
+        let a = banks::table()[0].name;
+        let bank = meta.bank_by_name(a).unwrap();
+        let idx = meta.record_boot(bank).unwrap();
+        meta.record_handoff(idx).unwrap();
+        writes(&flash);
+
+        let (_, output) = session(&flash, &meta, format!("confirm {}\rboot\r", a).as_bytes());
+        assert!(output.contains(&format!("bank {} boot confirmed\n", a)), "{}", output);
+        assert!(writes(&flash) > 0);
+        assert_eq!(meta.scan().bank(bank).confirmed, 1);
+
+        let (_, output) = session(&flash, &meta, b"erase-meta\rboot\r");
+        assert!(output.contains("boot log erased\n"), "{}", output);
+        assert!(writes(&flash) > 0);
+        assert_eq!(meta.scan().bank(bank).confirmed, 0);
+    }
+
+    // "meta" stops after a screenful for a key: any goes on, 'q' drops
+    // the rest.
+    #[test]
+    fn pager() {
+        let flash = units();
+        let meta = meta(&flash);
+        let bank = meta.bank_by_name(banks::table()[0].name).unwrap();
+        for _ in 0..PAGE_LINES {
+            meta.record_boot(bank).unwrap();
+        }
+        let entries = meta.entries().count();
+        assert!(entries >= PAGE_LINES);
+        let more = "-- more, q to stop --";
+
+        let (_, output) = session(&flash, &meta, b"meta\r boot\r");
+        assert_eq!(output.matches(more).count(), 1);
+        assert_eq!(output.matches(" bank ").count(), entries);
+        assert!(output.contains("next_idx = "));
+
+        let (_, output) = session(&flash, &meta, b"meta\rqboot\r");
+        assert_eq!(output.matches(more).count(), 1);
+        assert_eq!(output.matches(" bank ").count(), PAGE_LINES);
+        assert!(!output.contains("next_idx = "));
+    }
+
+    // A hexdump line as "md" prints it.
+    fn dump_line(addr: usize, bytes: &[u8]) -> String {
+        let mut line = String::new();
+        logger::hexdump_line(&mut line, addr, bytes).unwrap();
+        line
+    }
+
+    // "mw" writes scratch RAM, at each width, and "md" reads it back.
+    // Anywhere else it is refused and writes nothing: not the log ring,
+    // nor the SPL's stack and image or the flash, which aren't host
+    // memory here and would crash the test.
+    #[test]
+    fn mw_whitelist() {
+        let flash = units();
+        let meta = meta(&flash);
+        let (heap, _) = arena::region();
+        let (ring, _) = logger::ringbuf::region();
+        let (stack, _) = crate::arch::stack_region();
+        let (image, _) = crate::arch::image_region();
+        let ring_before = unsafe { core::ptr::read_volatile(ring as *const u64) };
+        let script = [
+            format!("mw.d {:x} 12345678", heap),
+            format!("mw.w 0x{:x} beef", heap + 4),
+            format!("mw.b {:x} 5a", heap + 6),
+            format!("md.b {:x} 8", heap),
+            format!("mw.d {:x} 0", ring),
+            format!("mw.d {:x} 0", stack),
+            format!("mw.w {:x} 0", image),
+            format!("mw.b {:x} 0", flash[0].base),
+            format!("mw.d {:x} 0", heap + 2),
+            format!("mw.b {:x} 100", heap),
+            "boot\r".into(),
+        ];
+        let (_, output) = session(&flash, &meta, script.join("\r").as_bytes());
+        let refused = "refused: not in scratch RAM or a load window\n";
+        assert_in_order(
+            &output,
+            &[
+                &dump_line(heap, &[0x78, 0x56, 0x34, 0x12, 0xef, 0xbe, 0x5a, 0]),
+                &format!("mw.d: {}", refused),
+                &format!("mw.d: {}", refused),
+                "mw.w: refused: SPL image\n",
+                "mw.b: refused: flash window\n",
+                "usage: mw.d <addr> <value>\n",
+                "usage: mw.b <addr> <value>\n",
+            ],
+        );
+        assert_eq!(unsafe { core::ptr::read_volatile(ring as *const u64) }, ring_before);
+    }
+
+    // A read that faults ends "md" there with the address and the cause,
+    // after the lines read before it, and the shell carries on.
+    #[test]
+    fn md_fault() {
+        let flash = units();
+        let meta = meta(&flash);
+        let (heap, _) = arena::region();
+        let hole = heap + 2 * HEXDUMP_WIDTH + 4;
+        probe::unmap(hole..hole + 4);
+        let script = format!("md.d {:x} 16\rmd.b {:x} 1\rmd.w {:x} 1\rboot\r", heap, hole + 3, hole + 4);
+        let (_, output) = session(&flash, &meta, script.as_bytes());
+        let cause = probe::LOAD_ACCESS_FAULT;
+        let fault = |cmd, addr| format!("{}: access fault at 0x{:x} (mcause {})\n", cmd, addr, cause);
+        assert_in_order(
+            &output,
+            &[
+                &format!("{:08x} ", heap),
+                &format!("{:08x} ", heap + HEXDUMP_WIDTH),
+                &fault("md.d", hole),
+                &fault("md.b", hole + 3),
+                &format!("spl1> md.w {:x} 1\n{:08x} ", hole + 4, hole + 4),
+                "spl1> boot\n",
+            ],
+        );
+        assert!(!output.contains(&format!("{:08x} ", heap + 2 * HEXDUMP_WIDTH)));
+    }
+}
@@ -0,0 +1,108 @@
+// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320), as used by zlib,
+// gzip and U-Boot.
+//
+// Uses a 16-entry nibble table (64 bytes of .rodata) rather than the
+// usual 1 KiB byte table: two lookups per byte is plenty for an SPL.
+
+use crate::digest::{Digest, Output};
+
+const POLY: u32 = 0xEDB8_8320;
+
+const TABLE: [u32; 16] = {
+    let mut t = [0u32; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 4 {
+            c = if c & 1 != 0 { (c >> 1) ^ POLY } else { c >> 1 };
+            k += 1;
+        }
+        t[i] = c;
+        i += 1;
+    }
+    t
+};
+
+/// Incremental CRC-32, for data that arrives in chunks (e.g. read out of
+/// flash a block at a time).
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    // const (hence the while loop) for data known when building, like
+    // the self-descriptor's CRC.
+    pub const fn update(&mut self, data: &[u8]) {
+        let mut crc = self.state;
+        let mut i = 0;
+        while i < data.len() {
+            crc ^= data[i] as u32;
+            crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+            crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+            i += 1;
+        }
+        self.state = crc;
+    }
+
+    pub const fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Digest for Crc32 {
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        Crc32::update(self, data)
+    }
+
+    fn finalize(&self) -> Output {
+        Output::from_crc32(self.finish())
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 of `data` in one go.
+pub const fn crc32(data: &[u8]) -> u32 {
+    let mut c = Crc32::new();
+    c.update(data);
+    c.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(&[0]), 0xD202_EF8D);
+    }
+
+    // Chunked the same as in one go, wherever the data is split.
+    #[test]
+    fn incremental() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(crc32(data), 0x414F_A339);
+        for at in 0..=data.len() {
+            let mut c = Crc32::new();
+            c.update(&data[..at]);
+            c.update(&data[at..]);
+            assert_eq!(c.finish(), 0x414F_A339);
+        }
+    }
+}
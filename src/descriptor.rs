@@ -0,0 +1,223 @@
+use core::fmt;
+
+use crate::board;
+use crate::bootmeta::MAX_BANKS;
+use crate::crc32::crc32;
+use crate::rtc::DateTime;
+
+// The SPL's self-descriptor: which build this is and the layout it was
+// built for, at a fixed offset into the flat binary (OFFSET, see
+// linker.ld) so a tool can read it off a flash dump without running
+// anything. Scanning for the magic works too. Little-endian:
+//
+//   0x00  [8]   magic        b"SPL1DESC"
+//   0x08  u16   format       1
+//   0x0a  u16   size         0x100
+//   0x0c  u32   banks        entries used in `bank`
+//   0x10  [16]  version      crate version, NUL-padded
+//   0x20  [40]  git          commit hash (hex), NUL-padded
+//   0x48  u64   build_time   Unix seconds (SOURCE_DATE_EPOCH if set)
+//   0x50  u64   flash_base   flash unit 0, the one the SPL boots from
+//   0x58  u32   block_size   of flash unit 0
+//   0x5c  u32   meta_unit
+//   0x60  u64   meta_offset
+//   0x68  u64   meta_size
+//   0x70  u64   golden       golden image offset, all-ones if none
+//   0x78  bank[8]: u32 unit, u32 size, u64 offset
+//   0xf8  u32   reserved     zero
+//   0xfc  u32   crc          CRC-32 of bytes 0x00..0xfc
+//
+// spl_main checks the CRC first thing, which catches an SPL that was
+// truncated or damaged when written.
+
+pub const MAGIC: [u8; 8] = *b"SPL1DESC";
+/// Offset of the descriptor into the SPL image.
+pub const OFFSET: usize = 0x400;
+const FORMAT: u16 = 1;
+const SIZE: usize = 0x100;
+const BANKS_AT: usize = 0x78;
+const CRC_AT: usize = SIZE - 4;
+
+const _: () = assert!(BANKS_AT + 16 * MAX_BANKS <= CRC_AT - 4, "descriptor: too many banks");
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT: &str = env!("SPL_GIT_HASH");
+const BUILD_TIME: u64 = parse_u64(env!("SPL_BUILD_TIME"));
+
+const fn parse_u64(s: &str) -> u64 {
+    let b = s.as_bytes();
+    let mut v = 0u64;
+    let mut i = 0;
+    while i < b.len() {
+        v = v * 10 + (b[i] - b'0') as u64;
+        i += 1;
+    }
+    v
+}
+
+// Copy `v` to `b[at..]`, truncated to `max` bytes.
+const fn put(b: &mut [u8; SIZE], at: usize, v: &[u8], max: usize) {
+    let mut i = 0;
+    while i < v.len() && i < max {
+        b[at + i] = v[i];
+        i += 1;
+    }
+}
+
+const fn build() -> [u8; SIZE] {
+    let mut b = [0u8; SIZE];
+    put(&mut b, 0x00, &MAGIC, 8);
+    put(&mut b, 0x08, &FORMAT.to_le_bytes(), 2);
+    put(&mut b, 0x0a, &(SIZE as u16).to_le_bytes(), 2);
+    put(&mut b, 0x0c, &(crate::BOOT_BANKS.len() as u32).to_le_bytes(), 4);
+    put(&mut b, 0x10, VERSION.as_bytes(), 16);
+    put(&mut b, 0x20, GIT.as_bytes(), 40);
+    put(&mut b, 0x48, &BUILD_TIME.to_le_bytes(), 8);
+    put(&mut b, 0x50, &(board::FLASH_BASE[0] as u64).to_le_bytes(), 8);
+    put(&mut b, 0x58, &(board::FLASH_BLOCK_SIZE[0] as u32).to_le_bytes(), 4);
+    put(&mut b, 0x5c, &(board::META_UNIT as u32).to_le_bytes(), 4);
+    put(&mut b, 0x60, &(board::META_OFFSET as u64).to_le_bytes(), 8);
+    put(&mut b, 0x68, &(board::META_SIZE as u64).to_le_bytes(), 8);
+    let golden = match crate::GOLDEN_OFFSET {
+        Some(offset) => offset as u64,
+        None => u64::MAX,
+    };
+    put(&mut b, 0x70, &golden.to_le_bytes(), 8);
+    let mut i = 0;
+    while i < crate::BOOT_BANKS.len() {
+        let bank = &crate::BOOT_BANKS[i];
+        let at = BANKS_AT + 16 * i;
+        put(&mut b, at, &(bank.unit as u32).to_le_bytes(), 4);
+        put(&mut b, at + 4, &(bank.size as u32).to_le_bytes(), 4);
+        put(&mut b, at + 8, &(bank.offset.get() as u64).to_le_bytes(), 8);
+        i += 1;
+    }
+    let (body, _) = b.split_at(CRC_AT);
+    let crc = crc32(body);
+    put(&mut b, CRC_AT, &crc.to_le_bytes(), 4);
+    b
+}
+
+// Stays in flash (not copied to RAM with the rest of the image).
+#[used]
+#[unsafe(link_section = ".spl_descriptor")]
+static DESCRIPTOR: [u8; SIZE] = build();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorError {
+    BadMagic,
+    BadCrc { stored: u32, computed: u32 },
+}
+
+/// The descriptor as read back from the SPL image.
+pub struct Descriptor {
+    bytes: [u8; SIZE],
+}
+
+/// Read our own descriptor, as it is in flash: volatile, so what was
+/// built in can't stand in for what is actually there.
+pub fn read() -> Descriptor {
+    let base = (&raw const DESCRIPTOR).cast::<u8>();
+    let mut bytes = [0u8; SIZE];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = unsafe { core::ptr::read_volatile(base.add(i)) };
+    }
+    Descriptor { bytes }
+}
+
+/// Where the descriptor is.
+pub fn addr() -> usize {
+    (&raw const DESCRIPTOR) as usize
+}
+
+impl Descriptor {
+    pub fn check(&self) -> Result<(), DescriptorError> {
+        if self.bytes[..8] != MAGIC {
+            return Err(DescriptorError::BadMagic);
+        }
+        let stored = self.u32_at(CRC_AT);
+        let computed = crc32(&self.bytes[..CRC_AT]);
+        if stored != computed {
+            return Err(DescriptorError::BadCrc { stored, computed });
+        }
+        Ok(())
+    }
+
+    fn u32_at(&self, at: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, at: usize) -> u64 {
+        u64::from_le_bytes(self.bytes[at..at + 8].try_into().unwrap())
+    }
+
+    fn str_at(&self, at: usize, len: usize) -> &str {
+        let field = &self.bytes[at..at + len];
+        let end = field.iter().position(|&c| c == 0).unwrap_or(len);
+        core::str::from_utf8(&field[..end]).unwrap_or("?")
+    }
+
+    pub fn version(&self) -> &str {
+        self.str_at(0x10, 16)
+    }
+
+    pub fn git(&self) -> &str {
+        self.str_at(0x20, 40)
+    }
+
+    pub fn build_time(&self) -> BuildTime {
+        BuildTime(self.u64_at(0x48))
+    }
+
+    /// Every field, one per line, for the console.
+    #[cfg_attr(not(feature = "console"), allow(dead_code))]
+    pub fn dump<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        writeln!(w, "version     {}", self.version())?;
+        writeln!(w, "git         {}", self.git())?;
+        writeln!(w, "built       {}", self.build_time())?;
+        writeln!(w, "flash base  0x{:x}", self.u64_at(0x50))?;
+        writeln!(w, "block size  0x{:x}", self.u32_at(0x58))?;
+        writeln!(
+            w,
+            "meta        unit {} 0x{:x}+0x{:x}",
+            self.u32_at(0x5c),
+            self.u64_at(0x60),
+            self.u64_at(0x68)
+        )?;
+        match self.u64_at(0x70) {
+            u64::MAX => writeln!(w, "golden      none")?,
+            offset => writeln!(w, "golden      0x{:x}", offset)?,
+        }
+        let banks = (self.u32_at(0x0c) as usize).min(MAX_BANKS);
+        for i in 0..banks {
+            let at = BANKS_AT + 16 * i;
+            writeln!(
+                w,
+                "bank {}      unit {} 0x{:x}+0x{:x}",
+                i,
+                self.u32_at(at),
+                self.u64_at(at + 8),
+                self.u32_at(at + 4)
+            )?;
+        }
+        match self.check() {
+            Ok(()) => writeln!(w, "crc         0x{:08x} ok", self.u32_at(CRC_AT)),
+            Err(e) => writeln!(w, "crc         {:?}", e),
+        }
+    }
+}
+
+/// Unix seconds, printed as a UTC date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildTime(pub u64);
+
+impl fmt::Display for BuildTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let t = DateTime::from_unix(self.0);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            t.year, t.month, t.day, t.hour, t.minute, t.second
+        )
+    }
+}
@@ -0,0 +1,194 @@
+use crate::crc32::Crc32;
+use crate::hash::{Sha256, SHA256_LEN};
+
+// One interface for the digests a payload is checked with (CRC-32 from a
+// raw trailer or a uImage header, SHA-256 from ours), so the code that
+// streams a payload out of flash is written once: image::stream() feeds
+// any Digest, and a Pair feeds two from the same reads when a header
+// vouches for its payload both ways.
+//
+// Outputs are byte strings of the algorithm's length, in the order they
+// are usually printed: SHA-256 as is, CRC-32 big-endian.
+
+/// Room for the longest digest (SHA-512's, one day).
+pub const MAX_LEN: usize = 64;
+
+/// A finished digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Output {
+    bytes: [u8; MAX_LEN],
+    len: usize,
+}
+
+impl Output {
+    /// `bytes`, MAX_LEN at most.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut out = Output {
+            bytes: [0; MAX_LEN],
+            len: bytes.len(),
+        };
+        out.bytes[..bytes.len()].copy_from_slice(bytes);
+        out
+    }
+
+    /// A CRC-32 value, as Crc32 outputs it.
+    pub fn from_crc32(crc: u32) -> Self {
+        Self::new(&crc.to_be_bytes())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// The first 4 bytes as a big-endian u32: a CRC-32 back as a value.
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.bytes[..4].try_into().unwrap())
+    }
+
+    /// The first 32 bytes: a SHA-256 back as an array.
+    pub fn to_sha256(self) -> [u8; SHA256_LEN] {
+        self.bytes[..SHA256_LEN].try_into().unwrap()
+    }
+}
+
+/// An incremental digest.
+pub trait Digest {
+    /// Start over, as new, to reuse it for other data.
+    #[allow(dead_code)]
+    fn reset(&mut self);
+    fn update(&mut self, data: &[u8]);
+    /// The digest of everything since the last reset. The state is left
+    /// as it was: more data may follow.
+    fn finalize(&self) -> Output;
+}
+
+/// The digests a header can check its payload with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Crc32,
+    Sha256,
+}
+
+impl Algorithm {
+    /// A fresh digest of this kind.
+    pub fn hasher(self) -> AnyDigest {
+        match self {
+            Algorithm::Crc32 => AnyDigest::Crc32(Crc32::new()),
+            Algorithm::Sha256 => AnyDigest::Sha256(Sha256::new()),
+        }
+    }
+}
+
+/// Any Algorithm's digest, picked at run time without a vtable.
+#[derive(Clone)]
+pub enum AnyDigest {
+    Crc32(Crc32),
+    Sha256(Sha256),
+}
+
+impl Digest for AnyDigest {
+    fn reset(&mut self) {
+        match self {
+            AnyDigest::Crc32(d) => d.reset(),
+            AnyDigest::Sha256(d) => d.reset(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            AnyDigest::Crc32(d) => Digest::update(d, data),
+            AnyDigest::Sha256(d) => Digest::update(d, data),
+        }
+    }
+
+    fn finalize(&self) -> Output {
+        match self {
+            AnyDigest::Crc32(d) => d.finalize(),
+            AnyDigest::Sha256(d) => d.finalize(),
+        }
+    }
+}
+
+/// Two digests fed the same data, e.g. CRC-32 and SHA-256 of a payload
+/// read once. finalize() is the first's output then the second's; most
+/// callers finalize each on its own.
+#[derive(Clone)]
+pub struct Pair<A, B>(pub A, pub B);
+
+impl<A: Digest, B: Digest> Digest for Pair<A, B> {
+    fn reset(&mut self) {
+        self.0.reset();
+        self.1.reset();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+        self.1.update(data);
+    }
+
+    fn finalize(&self) -> Output {
+        let (a, b) = (self.0.finalize(), self.1.finalize());
+        let mut out = Output::new(a.as_bytes());
+        let n = b.len.min(MAX_LEN - a.len);
+        out.bytes[a.len..a.len + n].copy_from_slice(&b.as_bytes()[..n]);
+        out.len = a.len + n;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32::crc32;
+    use crate::Rng;
+
+    // `data` in random chunks, as flash reads of any size would give it.
+    fn feed(d: &mut dyn Digest, data: &[u8], rng: &mut Rng) {
+        let mut done = 0;
+        while done < data.len() {
+            let n = (1 + rng.below(300) as usize).min(data.len() - done);
+            d.update(&data[done..done + n]);
+            done += n;
+        }
+    }
+
+    fn sha256(data: &[u8]) -> [u8; SHA256_LEN] {
+        let mut h = Sha256::new();
+        h.update(data);
+        h.finish()
+    }
+
+    // One pass through a Pair gives what a run of each digest on its own
+    // gives, whatever the chunks, after a reset and with more data after
+    // a finalize(); the output of both is cut at MAX_LEN.
+    #[test]
+    fn pair_matches_separate_runs() {
+        let mut rng = Rng(5);
+        for len in [0, 1, 55, 56, 63, 64, 65, 1000, 4103] {
+            let data: Vec<u8> = (0..len).map(|_| rng.below(256) as u8).collect();
+            let (crc, sha) = (crc32(&data), sha256(&data));
+            let mut both = [0u8; 4 + SHA256_LEN];
+            both[..4].copy_from_slice(&crc.to_be_bytes());
+            both[4..].copy_from_slice(&sha);
+
+            let mut pair = Pair(Algorithm::Crc32.hasher(), Algorithm::Sha256.hasher());
+            feed(&mut pair, &data, &mut rng);
+            assert_eq!(pair.0.finalize().to_u32(), crc, "{} bytes", len);
+            assert_eq!(pair.1.finalize().to_sha256(), sha, "{} bytes", len);
+            assert_eq!(pair.finalize().as_bytes(), both, "{} bytes", len);
+
+            let mut swapped = Pair(Sha256::new(), Crc32::new());
+            feed(&mut swapped, &data[..len / 2], &mut rng);
+            let _ = swapped.finalize();
+            feed(&mut swapped, &data[len / 2..], &mut rng);
+            assert_eq!((swapped.0.finalize().to_sha256(), swapped.1.finish()), (sha, crc), "{} bytes", len);
+
+            let mut nested = Pair(pair.clone(), Algorithm::Sha256.hasher());
+            nested.reset();
+            feed(&mut nested, &data, &mut rng);
+            assert_eq!(nested.0.finalize().as_bytes(), both, "{} bytes", len);
+            assert_eq!(nested.1.finalize().to_sha256(), sha, "{} bytes", len);
+            assert_eq!(nested.finalize().as_bytes(), [&both[..], &sha[..MAX_LEN - both.len()]].concat());
+        }
+    }
+}
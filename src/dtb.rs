@@ -0,0 +1,603 @@
+use core::result::Result;
+
+use crate::arch::{self, Fault};
+
+// Minimal flattened device tree (FDT) reader: no allocation, just enough
+// to validate the header, walk the structure block and pull `reg` out of
+// nodes matched by `compatible`.
+//
+// All FDT fields are big-endian.
+
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
+pub const FDT_BEGIN_NODE: u32 = 0x1;
+pub const FDT_END_NODE: u32 = 0x2;
+pub const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+pub const FDT_END: u32 = 0x9;
+
+pub const HEADER_SIZE: usize = 40;
+
+// Refuse blobs larger than this: a DTB for a small board is a few KiB, so
+// anything bigger is most likely garbage at a1.
+const MAX_TOTALSIZE: usize = 1024 * 1024;
+
+// Deepest node nesting we track #address-cells/#size-cells for.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    NullPointer,
+    /// Reading the header faulted: nothing at that address.
+    Unreadable(Fault),
+    BadMagic(u32),
+    BadVersion(u32),
+    BadSize,
+    Truncated,
+    BadToken(u32),
+    TooDeep,
+}
+
+/// A memory-mapped region decoded from a `reg` property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub base: usize,
+    pub size: usize,
+}
+
+/// A node found by a lookup, with the first entry of its `reg`.
+#[derive(Debug, Clone, Copy)]
+pub struct Device<'a> {
+    pub name: &'a str,
+    pub reg: Region,
+}
+
+/// One item of the structure block.
+#[derive(Debug, Clone, Copy)]
+pub enum Token<'a> {
+    BeginNode(&'a str),
+    Prop {
+        name: &'a str,
+        /// Offset of `name` in the strings block, as stored in the blob.
+        nameoff: usize,
+        value: &'a [u8],
+    },
+    EndNode,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    off_rsvmap: usize,
+    boot_cpuid: u32,
+    off_struct: usize,
+    size_struct: usize,
+    off_strings: usize,
+    size_strings: usize,
+}
+
+fn be32(buf: &[u8], off: usize) -> Option<u32> {
+    let b = buf.get(off..off + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn cstr(buf: &[u8], off: usize) -> Option<&str> {
+    let rest = buf.get(off..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+const fn align4(v: usize) -> usize {
+    (v + 3) & !3
+}
+
+impl<'a> Fdt<'a> {
+    /// Validate the header of a blob and build a reader over it.
+    pub fn new(blob: &'a [u8]) -> Result<Self, FdtError> {
+        let hdr = |idx: usize| be32(blob, idx * 4).ok_or(FdtError::Truncated);
+
+        let magic = hdr(0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let totalsize = hdr(1)? as usize;
+        let off_struct = hdr(2)? as usize;
+        let off_strings = hdr(3)? as usize;
+        let off_rsvmap = hdr(4)? as usize;
+        let version = hdr(5)?;
+        let last_comp = hdr(6)?;
+        let boot_cpuid = hdr(7)?;
+        let size_strings = hdr(8)? as usize;
+        let size_struct = hdr(9)? as usize;
+
+        // size_dt_struct only exists from v17; v16 readers are what we are.
+        if version < 17 || last_comp > 17 {
+            return Err(FdtError::BadVersion(version));
+        }
+        if totalsize < HEADER_SIZE || totalsize > blob.len() {
+            return Err(FdtError::BadSize);
+        }
+        let struct_end = off_struct.checked_add(size_struct).ok_or(FdtError::BadSize)?;
+        let strings_end = off_strings.checked_add(size_strings).ok_or(FdtError::BadSize)?;
+        if struct_end > totalsize || strings_end > totalsize || off_rsvmap >= totalsize {
+            return Err(FdtError::BadSize);
+        }
+
+        Ok(Fdt {
+            blob: &blob[..totalsize],
+            off_rsvmap,
+            boot_cpuid,
+            off_struct,
+            size_struct,
+            off_strings,
+            size_strings,
+        })
+    }
+
+    /// Build a reader over a DTB at a physical address (as received in a1).
+    ///
+    /// # Safety
+    /// `pa` must be readable memory for at least the header, and for
+    /// `totalsize` bytes if the header looks valid.
+    pub unsafe fn from_addr(pa: usize) -> Result<Fdt<'static>, FdtError> {
+        if pa == 0 || !pa.is_multiple_of(4) {
+            return Err(FdtError::NullPointer);
+        }
+        // A bogus a1 may point at nothing at all: survive that.
+        unsafe { arch::try_read_volatile::<u32>(pa) }.map_err(FdtError::Unreadable)?;
+        let head = unsafe { core::slice::from_raw_parts(pa as *const u8, HEADER_SIZE) };
+        let magic = be32(head, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let totalsize = be32(head, 4).ok_or(FdtError::Truncated)? as usize;
+        if !(HEADER_SIZE..=MAX_TOTALSIZE).contains(&totalsize) {
+            return Err(FdtError::BadSize);
+        }
+        let blob = unsafe { core::slice::from_raw_parts(pa as *const u8, totalsize) };
+        Fdt::new(blob)
+    }
+
+    /// Whole blob, as bounded by the header's totalsize.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.blob
+    }
+
+    /// boot_cpuid_phys from the header.
+    pub fn boot_cpuid(&self) -> u32 {
+        self.boot_cpuid
+    }
+
+    /// Memory reservation block, including its all-zero terminator entry.
+    pub fn mem_rsvmap(&self) -> Result<&'a [u8], FdtError> {
+        let mut off = self.off_rsvmap;
+        loop {
+            let entry = self.blob.get(off..off + 16).ok_or(FdtError::Truncated)?;
+            off += 16;
+            if entry.iter().all(|&b| b == 0) {
+                return Ok(&self.blob[self.off_rsvmap..off]);
+            }
+        }
+    }
+
+    /// Raw strings block.
+    pub fn strings(&self) -> &'a [u8] {
+        &self.blob[self.off_strings..self.off_strings + self.size_strings]
+    }
+
+    /// Value of property `prop` in the root's direct child `node`
+    /// (e.g. `node_prop("chosen", "bootargs")`).
+    pub fn node_prop(&self, node: &str, prop: &str) -> Result<Option<&'a [u8]>, FdtError> {
+        let mut depth = 0usize;
+        let mut in_node = false;
+        for tok in self.tokens() {
+            match tok? {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    in_node = depth == 2 && name == node;
+                }
+                Token::Prop { name, value, .. } => {
+                    if in_node && depth == 2 && name == prop {
+                        return Ok(Some(value));
+                    }
+                }
+                Token::EndNode => {
+                    if in_node && depth == 2 {
+                        return Ok(None);
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterate over the structure block tokens (NOPs are skipped).
+    pub fn tokens(&self) -> Tokens<'a> {
+        Tokens {
+            fdt: *self,
+            off: self.off_struct,
+            done: false,
+        }
+    }
+
+    fn string(&self, off: usize) -> Option<&'a str> {
+        if off >= self.size_strings {
+            return None;
+        }
+        cstr(self.strings(), off)
+    }
+
+    /// Find the first node whose `compatible` list contains `compat` and
+    /// decode the first entry of its `reg` property, using the parent's
+    /// #address-cells / #size-cells.
+    pub fn find_compatible_reg(&self, compat: &str) -> Result<Option<Device<'a>>, FdtError> {
+        self.find_reg_by("compatible", compat, |_| true)
+    }
+
+    /// Same as `find_compatible_reg()`, skipping nodes `accept` returns
+    /// false for (e.g. virtio-mmio transports with the wrong device
+    /// behind them).
+    pub fn find_compatible_reg_where(
+        &self,
+        compat: &str,
+        accept: impl FnMut(&Device<'a>) -> bool,
+    ) -> Result<Option<Device<'a>>, FdtError> {
+        self.find_reg_by("compatible", compat, accept)
+    }
+
+    /// Same as `find_compatible_reg()`, for nodes with `device_type`
+    /// `dtype` (e.g. "memory", which carries no `compatible`).
+    pub fn find_device_type_reg(&self, dtype: &str) -> Result<Option<Device<'a>>, FdtError> {
+        self.find_reg_by("device_type", dtype, |_| true)
+    }
+
+    // First node whose string-list property `key` contains `want` and
+    // that `accept` takes.
+    fn find_reg_by(
+        &self,
+        key: &str,
+        want: &str,
+        mut accept: impl FnMut(&Device<'a>) -> bool,
+    ) -> Result<Option<Device<'a>>, FdtError> {
+        // cells[d] = (#address-cells, #size-cells) declared by the node at
+        // depth d, i.e. what its children use. Root defaults per spec.
+        let mut cells = [(2u32, 1u32); MAX_DEPTH];
+        let mut depth = 0usize;
+        let mut name = "";
+        let mut matched = false;
+        let mut reg: Option<&[u8]> = None;
+
+        for tok in self.tokens() {
+            match tok? {
+                Token::BeginNode(child) => {
+                    if let Some(r) = self.check_node(matched, reg, depth, &cells) {
+                        let dev = Device { name, reg: r };
+                        if accept(&dev) {
+                            return Ok(Some(dev));
+                        }
+                    }
+                    name = child;
+                    depth += 1;
+                    if depth >= MAX_DEPTH {
+                        return Err(FdtError::TooDeep);
+                    }
+                    cells[depth] = (2, 1);
+                    matched = false;
+                    reg = None;
+                }
+                Token::Prop { name, value, .. } => match name {
+                    "#address-cells" => cells[depth].0 = be32(value, 0).unwrap_or(2),
+                    "#size-cells" => cells[depth].1 = be32(value, 0).unwrap_or(1),
+                    "reg" => reg = Some(value),
+                    _ if name == key => {
+                        matched = value.split(|&b| b == 0).any(|s| s == want.as_bytes());
+                    }
+                    _ => {}
+                },
+                Token::EndNode => {
+                    if let Some(r) = self.check_node(matched, reg, depth, &cells) {
+                        let dev = Device { name, reg: r };
+                        if accept(&dev) {
+                            return Ok(Some(dev));
+                        }
+                    }
+                    matched = false;
+                    reg = None;
+                    depth = depth.saturating_sub(1);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // A node's properties all come before its children, so once we see
+    // the first child or the end of the node we know whether it matched.
+    fn check_node(
+        &self,
+        matched: bool,
+        reg: Option<&[u8]>,
+        depth: usize,
+        cells: &[(u32, u32); MAX_DEPTH],
+    ) -> Option<Region> {
+        if !matched || depth == 0 {
+            return None;
+        }
+        let (addr_cells, size_cells) = cells[depth - 1];
+        let reg = reg?;
+        let base = read_cells(reg, 0, addr_cells)?;
+        let size = read_cells(reg, addr_cells as usize * 4, size_cells)?;
+        Some(Region {
+            base: base as usize,
+            size: size as usize,
+        })
+    }
+}
+
+fn read_cells(buf: &[u8], off: usize, n: u32) -> Option<u64> {
+    match n {
+        0 => Some(0),
+        1 => be32(buf, off).map(u64::from),
+        2 => {
+            let hi = be32(buf, off)? as u64;
+            let lo = be32(buf, off + 4)? as u64;
+            Some((hi << 32) | lo)
+        }
+        _ => None,
+    }
+}
+
+pub struct Tokens<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+    done: bool,
+}
+
+impl<'a> Tokens<'a> {
+    fn next_token(&mut self) -> Result<Option<Token<'a>>, FdtError> {
+        let blob = self.fdt.blob;
+        let end = self.fdt.off_struct + self.fdt.size_struct;
+
+        loop {
+            if self.off + 4 > end {
+                return Err(FdtError::Truncated);
+            }
+            let tok = be32(blob, self.off).ok_or(FdtError::Truncated)?;
+            self.off += 4;
+
+            match tok {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(&blob[..end], self.off).ok_or(FdtError::Truncated)?;
+                    self.off = align4(self.off + name.len() + 1);
+                    return Ok(Some(Token::BeginNode(name)));
+                }
+                FDT_PROP => {
+                    let len = be32(blob, self.off).ok_or(FdtError::Truncated)? as usize;
+                    let nameoff = be32(blob, self.off + 4).ok_or(FdtError::Truncated)? as usize;
+                    let start = self.off + 8;
+                    let value = blob.get(start..start + len).ok_or(FdtError::Truncated)?;
+                    let name = self.fdt.string(nameoff).ok_or(FdtError::Truncated)?;
+                    self.off = align4(start + len);
+                    return Ok(Some(Token::Prop {
+                        name,
+                        nameoff,
+                        value,
+                    }));
+                }
+                FDT_END_NODE => return Ok(Some(Token::EndNode)),
+                FDT_NOP => continue,
+                FDT_END => return Ok(None),
+                other => return Err(FdtError::BadToken(other)),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Token<'a>, FdtError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_token() {
+            Ok(Some(tok)) => Some(Ok(tok)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// One item of a structure block, for blob().
+    #[derive(Debug, Clone, Copy)]
+    pub enum Item<'a> {
+        Begin(&'a str),
+        Prop(&'a str, &'a [u8]),
+        End,
+    }
+
+    /// A version 17 DTB of `items`, with an empty reservation map and
+    /// each property name once in the strings block.
+    pub fn blob(items: &[Item]) -> Vec<u8> {
+        let mut strings: Vec<u8> = Vec::new();
+        let mut structure: Vec<u8> = Vec::new();
+        let pad = |v: &mut Vec<u8>| v.resize(align4(v.len()), 0);
+        for item in items {
+            match *item {
+                Item::Begin(name) => {
+                    structure.extend(FDT_BEGIN_NODE.to_be_bytes());
+                    structure.extend(name.as_bytes());
+                    structure.push(0);
+                    pad(&mut structure);
+                }
+                Item::Prop(name, value) => {
+                    let found = cstrings(&strings).find(|&(_, s)| s == name).map(|(off, _)| off);
+                    let nameoff = match found {
+                        Some(off) => off,
+                        None => {
+                            strings.extend(name.as_bytes());
+                            strings.push(0);
+                            strings.len() - name.len() - 1
+                        }
+                    };
+                    structure.extend(FDT_PROP.to_be_bytes());
+                    structure.extend((value.len() as u32).to_be_bytes());
+                    structure.extend((nameoff as u32).to_be_bytes());
+                    structure.extend(value);
+                    pad(&mut structure);
+                }
+                Item::End => structure.extend(FDT_END_NODE.to_be_bytes()),
+            }
+        }
+        structure.extend(FDT_END.to_be_bytes());
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + 16;
+        let off_strings = off_struct + structure.len();
+        let totalsize = off_strings + strings.len();
+        let header = [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ];
+        let mut b: Vec<u8> = header.iter().flat_map(|v| v.to_be_bytes()).collect();
+        b.extend([0; 16]);
+        b.extend(structure);
+        b.extend(strings);
+        b
+    }
+
+    // Each string of a strings block, with its offset.
+    fn cstrings(strings: &[u8]) -> impl Iterator<Item = (usize, &str)> {
+        let mut off = 0;
+        strings.split(|&b| b == 0).filter_map(move |s| {
+            let at = off;
+            off += s.len() + 1;
+            core::str::from_utf8(s).ok().map(|s| (at, s))
+        })
+    }
+
+    /// Big-endian cells, as a property value.
+    pub fn cells(v: &[u32]) -> Vec<u8> {
+        v.iter().flat_map(|c| c.to_be_bytes()).collect()
+    }
+
+    // Most of what QEMU virt passes: 2-cell addresses at the root, a
+    // 1-cell bus under it, and two virtio transports.
+    fn virt() -> Vec<u8> {
+        use Item::*;
+        let (two, one) = (cells(&[2]), cells(&[1]));
+        let memory = cells(&[0, 0x8000_0000, 0, 0x800_0000]);
+        let uart = cells(&[0, 0x1000_0000, 0, 0x100]);
+        let flash = cells(&[0, 0x2000_0000, 0, 0x200_0000, 0, 0x2200_0000, 0, 0x200_0000]);
+        let (virtio0, virtio1) = (cells(&[0x1000_1000, 0x1000]), cells(&[0x1000_2000, 0x1000]));
+        blob(&[
+            Begin(""),
+            Prop("#address-cells", &two),
+            Prop("#size-cells", &two),
+            Begin("chosen"),
+            Prop("bootargs", b"console=ttyS0\0"),
+            End,
+            Begin("memory@80000000"),
+            Prop("device_type", b"memory\0"),
+            Prop("reg", &memory),
+            End,
+            Begin("soc"),
+            Prop("#address-cells", &two),
+            Prop("#size-cells", &two),
+            Prop("compatible", b"simple-bus\0"),
+            Begin("serial@10000000"),
+            Prop("compatible", b"ns16550a\0"),
+            Prop("reg", &uart),
+            End,
+            Begin("flash@20000000"),
+            Prop("compatible", b"cfi-flash\0"),
+            Prop("reg", &flash),
+            End,
+            Begin("bus32"),
+            Prop("#address-cells", &one),
+            Prop("#size-cells", &one),
+            Begin("virtio_mmio@10001000"),
+            Prop("compatible", b"virtio,mmio\0"),
+            Prop("reg", &virtio0),
+            End,
+            Begin("virtio_mmio@10002000"),
+            Prop("compatible", b"virtio,mmio\0"),
+            Prop("reg", &virtio1),
+            End,
+            End,
+            End,
+            End,
+        ])
+    }
+
+    // Each lookup finds its node, `reg` decoded with its parent's cells.
+    #[test]
+    fn lookups() {
+        let b = virt();
+        let fdt = Fdt::new(&b).unwrap();
+        assert_eq!(fdt.as_bytes().len(), b.len());
+        assert_eq!(fdt.mem_rsvmap().unwrap(), &[0; 16]);
+        assert_eq!(fdt.node_prop("chosen", "bootargs"), Ok(Some(&b"console=ttyS0\0"[..])));
+        assert_eq!(fdt.node_prop("chosen", "stdout-path"), Ok(None));
+        assert_eq!(fdt.node_prop("aliases", "serial0"), Ok(None));
+        // Only the root's direct children.
+        assert_eq!(fdt.node_prop("serial@10000000", "compatible"), Ok(None));
+
+        let uart = fdt.find_compatible_reg("ns16550a").unwrap().unwrap();
+        assert_eq!(uart.name, "serial@10000000");
+        assert_eq!(uart.reg, Region { base: 0x1000_0000, size: 0x100 });
+        let flash = fdt.find_compatible_reg("cfi-flash").unwrap().unwrap();
+        assert_eq!(flash.reg, Region { base: 0x2000_0000, size: 0x200_0000 });
+        let memory = fdt.find_device_type_reg("memory").unwrap().unwrap();
+        assert_eq!(memory.reg, Region { base: 0x8000_0000, size: 0x800_0000 });
+        let virtio = fdt.find_compatible_reg("virtio,mmio").unwrap().unwrap();
+        assert_eq!(virtio.reg, Region { base: 0x1000_1000, size: 0x1000 });
+        let second = fdt.find_compatible_reg_where("virtio,mmio", |d| d.reg.base != 0x1000_1000);
+        assert_eq!(second.unwrap().unwrap().name, "virtio_mmio@10002000");
+        assert!(fdt.find_compatible_reg("sifive,uart0").unwrap().is_none());
+        assert!(fdt.find_compatible_reg_where("virtio,mmio", |_| false).unwrap().is_none());
+    }
+
+    // A blob cut anywhere is refused by its header, and a structure
+    // block cut short ends every walk with Truncated.
+    #[test]
+    fn truncated() {
+        let b = virt();
+        for len in 0..b.len() {
+            let want = if len < HEADER_SIZE { FdtError::Truncated } else { FdtError::BadSize };
+            assert_eq!(Fdt::new(&b[..len]).err(), Some(want), "{} of {} bytes", len, b.len());
+        }
+
+        let size_struct = be32(&b, 36).unwrap();
+        let mut short = b.clone();
+        short[36..40].copy_from_slice(&(size_struct - 8).to_be_bytes());
+        let fdt = Fdt::new(&short).unwrap();
+        assert_eq!(fdt.tokens().last().unwrap().err(), Some(FdtError::Truncated));
+        assert_eq!(fdt.find_compatible_reg("sifive,uart0").err(), Some(FdtError::Truncated));
+        assert_eq!(fdt.node_prop("chosen", "stdout-path"), Ok(None));
+
+        let mut bad = b.clone();
+        bad[0] ^= 1;
+        assert_eq!(Fdt::new(&bad).err(), Some(FdtError::BadMagic(FDT_MAGIC ^ 1 << 24)));
+        let mut old = b.clone();
+        old[20..24].copy_from_slice(&16u32.to_be_bytes());
+        assert_eq!(Fdt::new(&old).err(), Some(FdtError::BadVersion(16)));
+    }
+}
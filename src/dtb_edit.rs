@@ -0,0 +1,299 @@
+use core::result::Result;
+
+use crate::dtb::{self, Fdt, FdtError, Token};
+
+// Rewrite a (read-only) source DTB into a RAM buffer, adding or replacing
+// properties of /chosen on the way. /chosen is created if it is missing.
+//
+// The output is laid out as: header, memory reservation block, structure
+// block, strings block (original strings first, new names appended).
+
+/// Max number of /chosen properties a single patch can set.
+pub const MAX_PROPS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditError {
+    Fdt(FdtError),
+    NoSpace,
+    TooManyProps,
+}
+
+impl From<FdtError> for EditError {
+    fn from(e: FdtError) -> Self {
+        EditError::Fdt(e)
+    }
+}
+
+/// A property to set in /chosen. Values are raw bytes: strings must carry
+/// their NUL terminator, cells must already be big-endian.
+#[derive(Debug, Clone, Copy)]
+pub struct Prop<'p> {
+    pub name: &'p str,
+    pub value: &'p [u8],
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn put(&mut self, data: &[u8]) -> Result<(), EditError> {
+        let end = self.pos.checked_add(data.len()).ok_or(EditError::NoSpace)?;
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(EditError::NoSpace)?
+            .copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn put_u32(&mut self, v: u32) -> Result<(), EditError> {
+        self.put(&v.to_be_bytes())
+    }
+
+    fn pad4(&mut self) -> Result<(), EditError> {
+        while !self.pos.is_multiple_of(4) {
+            self.put(&[0])?;
+        }
+        Ok(())
+    }
+
+    fn begin_node(&mut self, name: &str) -> Result<(), EditError> {
+        self.put_u32(dtb::FDT_BEGIN_NODE)?;
+        self.put(name.as_bytes())?;
+        self.put(&[0])?;
+        self.pad4()
+    }
+
+    fn prop(&mut self, nameoff: usize, value: &[u8]) -> Result<(), EditError> {
+        self.put_u32(dtb::FDT_PROP)?;
+        self.put_u32(value.len() as u32)?;
+        self.put_u32(nameoff as u32)?;
+        self.put(value)?;
+        self.pad4()
+    }
+
+    fn set_u32(&mut self, off: usize, v: u32) {
+        self.buf[off..off + 4].copy_from_slice(&v.to_be_bytes());
+    }
+}
+
+// Offset of an existing entry equal to `name` in the strings block.
+fn find_string(strings: &[u8], name: &str) -> Option<usize> {
+    let mut off = 0;
+    for s in strings.split(|&b| b == 0) {
+        if s == name.as_bytes() {
+            return Some(off);
+        }
+        off += s.len() + 1;
+    }
+    None
+}
+
+/// Copy `src` into `out` with `props` set in /chosen, and return a reader
+/// over the result (which also proves it still parses).
+pub fn patch_chosen<'b>(
+    src: &Fdt,
+    props: &[Prop],
+    out: &'b mut [u8],
+) -> Result<Fdt<'b>, EditError> {
+    if props.len() > MAX_PROPS {
+        return Err(EditError::TooManyProps);
+    }
+
+    // Resolve name offsets: reuse strings already in the blob, append the
+    // others after the original strings block.
+    let strings = src.strings();
+    let mut nameoffs = [0usize; MAX_PROPS];
+    let mut extra = strings.len();
+    for (i, p) in props.iter().enumerate() {
+        nameoffs[i] = match find_string(strings, p.name) {
+            Some(off) => off,
+            None => {
+                let off = extra;
+                extra += p.name.len() + 1;
+                off
+            }
+        };
+    }
+
+    let mut w = Writer { buf: out, pos: 0 };
+    w.put(&[0; dtb::HEADER_SIZE])?;
+
+    let off_rsvmap = w.pos;
+    w.put(src.mem_rsvmap()?)?;
+
+    let off_struct = w.pos;
+    let emit = |w: &mut Writer| -> Result<(), EditError> {
+        for (i, p) in props.iter().enumerate() {
+            w.prop(nameoffs[i], p.value)?;
+        }
+        Ok(())
+    };
+
+    let mut depth = 0usize;
+    let mut in_chosen = false;
+    let mut seen_chosen = false;
+    let mut emitted = false;
+
+    for tok in src.tokens() {
+        match tok? {
+            Token::BeginNode(name) => {
+                // Our props go after chosen's own props, before any child.
+                if in_chosen && !emitted {
+                    emit(&mut w)?;
+                    emitted = true;
+                }
+                depth += 1;
+                if depth == 2 && name == "chosen" {
+                    in_chosen = true;
+                    seen_chosen = true;
+                }
+                w.begin_node(name)?;
+            }
+            Token::Prop {
+                name,
+                nameoff,
+                value,
+            } => {
+                let replaced = in_chosen && depth == 2 && props.iter().any(|p| p.name == name);
+                if !replaced {
+                    w.prop(nameoff, value)?;
+                }
+            }
+            Token::EndNode => {
+                if in_chosen && depth == 2 {
+                    if !emitted {
+                        emit(&mut w)?;
+                        emitted = true;
+                    }
+                    in_chosen = false;
+                }
+                if depth == 1 && !seen_chosen {
+                    w.begin_node("chosen")?;
+                    emit(&mut w)?;
+                    w.put_u32(dtb::FDT_END_NODE)?;
+                    seen_chosen = true;
+                }
+                depth = depth.saturating_sub(1);
+                w.put_u32(dtb::FDT_END_NODE)?;
+            }
+        }
+    }
+    w.put_u32(dtb::FDT_END)?;
+    let size_struct = w.pos - off_struct;
+
+    let off_strings = w.pos;
+    w.put(strings)?;
+    for (i, p) in props.iter().enumerate() {
+        if nameoffs[i] >= strings.len() {
+            w.put(p.name.as_bytes())?;
+            w.put(&[0])?;
+        }
+    }
+    let size_strings = w.pos - off_strings;
+    let totalsize = w.pos;
+
+    let header = [
+        dtb::FDT_MAGIC,
+        totalsize as u32,
+        off_struct as u32,
+        off_strings as u32,
+        off_rsvmap as u32,
+        17, // version
+        16, // last_comp_version
+        src.boot_cpuid(),
+        size_strings as u32,
+        size_struct as u32,
+    ];
+    for (i, v) in header.iter().enumerate() {
+        w.set_u32(i * 4, *v);
+    }
+
+    let out: &'b [u8] = w.buf;
+    Ok(Fdt::new(&out[..totalsize])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtb::tests::{Item, Item::Begin, Item::End, blob, cells};
+
+    fn patch<'b>(src: &[u8], props: &[Prop], out: &'b mut [u8]) -> Result<Fdt<'b>, EditError> {
+        patch_chosen(&Fdt::new(src).unwrap(), props, out)
+    }
+
+    // Props already in /chosen are replaced, new ones appended after its
+    // own, and the rest of the tree comes through unchanged.
+    #[test]
+    fn patches_chosen() {
+        let (one, reg) = (cells(&[1]), cells(&[0x1000_0000, 0x100]));
+        let src = blob(&[
+            Begin(""),
+            Item::Prop("#address-cells", &one),
+            Item::Prop("#size-cells", &one),
+            Begin("chosen"),
+            Item::Prop("bootargs", b"quiet\0"),
+            Item::Prop("stdout-path", b"/uart\0"),
+            Begin("child"),
+            End,
+            End,
+            Begin("uart"),
+            Item::Prop("compatible", b"ns16550a\0"),
+            Item::Prop("reg", &reg),
+            End,
+            End,
+        ]);
+        let initrd = cells(&[0x8800_0000]);
+        let props = [
+            Prop { name: "bootargs", value: b"console=ttyS0\0" },
+            Prop { name: "linux,initrd-start", value: &initrd },
+        ];
+        let mut out = [0u8; 512];
+        let fdt = patch(&src, &props, &mut out).unwrap();
+        assert_eq!(fdt.node_prop("chosen", "bootargs"), Ok(Some(&b"console=ttyS0\0"[..])));
+        assert_eq!(fdt.node_prop("chosen", "stdout-path"), Ok(Some(&b"/uart\0"[..])));
+        assert_eq!(fdt.node_prop("chosen", "linux,initrd-start"), Ok(Some(&initrd[..])));
+        assert_eq!(fdt.find_compatible_reg("ns16550a").unwrap().unwrap().reg.base, 0x1000_0000);
+        let names: Vec<_> = fdt
+            .tokens()
+            .filter_map(|t| match t.unwrap() {
+                Token::BeginNode(n) => Some(n),
+                Token::Prop { name, .. } => Some(name),
+                Token::EndNode => None,
+            })
+            .collect();
+        let want = ["chosen", "stdout-path", "bootargs", "linux,initrd-start", "child", "uart"];
+        assert_eq!(names[3..3 + want.len()], want);
+
+        // Patching the result again changes nothing.
+        let mut again = [0u8; 512];
+        let len = fdt.as_bytes().len();
+        assert_eq!(patch(&out[..len], &props, &mut again).unwrap().as_bytes(), &out[..len]);
+    }
+
+    // A tree without /chosen gets one, at the end of the root.
+    #[test]
+    fn creates_chosen() {
+        let src = blob(&[Begin(""), Item::Prop("model", b"virt\0"), Begin("cpus"), End, End]);
+        let props = [Prop { name: "bootargs", value: b"root=/dev/vda\0" }];
+        let mut out = [0u8; 256];
+        let fdt = patch(&src, &props, &mut out).unwrap();
+        assert_eq!(fdt.node_prop("chosen", "bootargs"), Ok(Some(&b"root=/dev/vda\0"[..])));
+        assert_eq!(fdt.node_prop("cpus", "bootargs"), Ok(None));
+    }
+
+    #[test]
+    fn limits() {
+        let src = blob(&[Begin(""), Begin("chosen"), End, End]);
+        let props = [Prop { name: "bootargs", value: b"x\0" }; MAX_PROPS + 1];
+        let mut out = [0u8; 1024];
+        assert_eq!(patch(&src, &props, &mut out).err(), Some(EditError::TooManyProps));
+        assert!(patch(&src, &props[..MAX_PROPS], &mut out).is_ok());
+        let need = patch(&src, &props[..1], &mut out).unwrap().as_bytes().len();
+        for len in 0..need {
+            assert_eq!(patch(&src, &props[..1], &mut out[..len]).err(), Some(EditError::NoSpace));
+        }
+    }
+}
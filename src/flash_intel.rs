@@ -1,56 +1,644 @@
+use core::arch::global_asm;
+use core::cell::Cell;
 use core::result::Result;
 
+use crate::addr::{FlashOffset, PhysAddr};
+use crate::arch::{self, barrier};
+use crate::board;
+use crate::bytes::{Bytes, Le16, Le32, Truncated, U8};
+use crate::clint::{self, Deadline};
+use crate::image::ImageSource;
+use crate::mmio::{Reg64, Reg8};
+use crate::slog_warn;
+use crate::watchdog::{self, Cancelled};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FlashError {
     ProgramError,
     EraseError,
+    /// The status register didn't report ready before the deadline.
+    Timeout,
+    /// The block is locked; retrying won't help.
+    Protected,
+    /// program_aligned() offset not a multiple of the value size.
+    Unaligned(usize),
+    /// Still failing after `attempts` tries. `status` is the last status
+    /// register (bit 7 clear: timed out).
+    GaveUp { attempts: u32, status: u8 },
+    /// No CFI query table ("QRY") at any bus width.
+    NoCfi,
+    /// The chip (or its emulation) doesn't do this, e.g. OTP reads that
+    /// come back as array data.
+    NotSupported,
+    /// Out of range: an OTP region that doesn't exist, a block index
+    /// past the last block, or an offset and length running past the
+    /// end of the device (or the address space).
+    OutOfRange,
+    /// Nothing answers at the base: reads fault, or look like an empty
+    /// bus.
+    Unreachable,
+    /// The boot log is unusable after a failed compaction: nothing is
+    /// recorded until it is erased (see BootMeta).
+    MetaPoisoned,
+    /// The boot log's block is write-protected, or policy says not to
+    /// write it: nothing is recorded (see BootMeta).
+    MetaReadOnly,
+    /// The yield hook stopped a long operation between two commands
+    /// (see watchdog::yield_point()): what was done so far stays done.
+    Cancelled,
+}
+
+impl From<Cancelled> for FlashError {
+    fn from(_: Cancelled) -> Self {
+        FlashError::Cancelled
+    }
+}
+
+/// One-time-programmable protection register region: 0 (half of it
+/// factory-programmed) or 1..=16, 16 bytes each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtpRegion(pub u8);
+
+impl OtpRegion {
+    pub const SIZE: usize = 16;
+    // First bytes of region 0, programmed by the manufacturer.
+    const FACTORY_SIZE: usize = 8;
+}
+
+/// Proof that the caller knows an OTP write can never be undone:
+/// required by program_otp(), and only obtained by spelling it out.
+pub struct OtpPermanent(());
+
+// For provisioning: nothing in the SPL writes OTP on its own.
+#[allow(dead_code)]
+impl OtpPermanent {
+    pub fn i_understand_this_is_permanent() -> Self {
+        OtpPermanent(())
+    }
+}
+
+/// How often a failed program or erase is re-issued. Marginal parts
+/// sometimes report a program error once and then succeed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries in all, the first one included (1: no retry).
+    pub attempts: u32,
+    /// Delay before each retry.
+    pub backoff_us: u64,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        backoff_us: 1_000,
+    };
+}
+
+// Once the chip leaves read-array mode, reads from it (instruction
+// fetches included) return status bytes instead of data. The SPL runs
+// from its RAM copy (see _start), and each command sequence is one small
+// asm routine, so nothing but the status register is read between the
+// command and the return to read-array. A routine issues its command,
+// polls the status register until ready or mtime reaches the deadline,
+// clears the status on error and puts the chip back in read-array mode.
+// It returns the status register; bit 7 clear means timeout.
+//
+//   spl_flash_erase(addr, mtime, deadline)
+//   spl_flash_program_byte(addr, mtime, deadline, value)
+//   spl_flash_program_u16(addr, mtime, deadline, value)
+//   spl_flash_program_u32(addr, mtime, deadline, value)
+//   spl_flash_program_otp(addr, mtime, deadline, value)
+//   spl_flash_program_buffer(addr, mtime, deadline, src, len)
+//   spl_flash_erase_suspend(addr, mtime, deadline)
+//   spl_flash_wait(addr, mtime, deadline)
+//
+// A suspended erase reports status bit 6 set; one that had finished
+// before the suspend came, bit 6 clear. spl_flash_erase_start(addr) and
+// spl_flash_erase_resume(addr) only issue their commands and return,
+// leaving the chip erasing and reading as status.
+//
+// mtime is read as hi/lo/hi 32-bit halves, like clint::mtime().
+//
+// spl_flash_read_mode(addr, buf, stride, len, cmd) is the odd one out:
+// it switches the chip to another read mode (0x98 CFI query, 0x90 read
+// identifier), copies `len` bytes from `addr` on, one every `stride`
+// bytes, into `buf` and returns nothing. Those modes need no polling.
+global_asm!(
+    r#"
+    .section .data.spl_flash_ram, "awx"
+    .balign 4
+
+    // t1 = mtime
+    .macro SPL_MTIME
+9:  lwu t1, 4(a1)
+    lwu t2, 0(a1)
+    lwu t3, 4(a1)
+    bne t1, t3, 9b
+    slli t1, t1, 32
+    or t1, t1, t2
+    .endm
+
+    .macro SPL_FLASH_POLL
+1:  lbu t0, 0(a0)
+    andi t1, t0, 0x80
+    bnez t1, 3f
+    SPL_MTIME
+    bltu t1, a2, 1b
+    li t0, 0
+    j 4f
+3:  andi t1, t0, 0x3a
+    beqz t1, 4f
+    li t1, 0x50
+    sb t1, 0(a0)
+4:  li t1, 0xff
+    sb t1, 0(a0)
+    mv a0, t0
+    .endm
+
+    .globl spl_flash_erase
+spl_flash_erase:
+    li t0, 0x20
+    sb t0, 0(a0)
+    li t0, 0xd0
+    sb t0, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    .globl spl_flash_erase_start
+spl_flash_erase_start:
+    li t0, 0x20
+    sb t0, 0(a0)
+    li t0, 0xd0
+    sb t0, 0(a0)
+    ret
+
+    // Read status (0x70) after the suspend: a chip that doesn't know
+    // 0xb0 drops back to read-array, and would be polled as data.
+    .globl spl_flash_erase_suspend
+spl_flash_erase_suspend:
+    li t0, 0xb0
+    sb t0, 0(a0)
+    li t0, 0x70
+    sb t0, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    .globl spl_flash_erase_resume
+spl_flash_erase_resume:
+    li t0, 0xd0
+    sb t0, 0(a0)
+    ret
+
+    .globl spl_flash_wait
+spl_flash_wait:
+    li t0, 0x70
+    sb t0, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    .globl spl_flash_program_byte
+spl_flash_program_byte:
+    li t0, 0x40
+    sb t0, 0(a0)
+    sb a3, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    // One program cycle of a whole bus word (x16/x32 parts).
+    .globl spl_flash_program_u16
+spl_flash_program_u16:
+    li t0, 0x40
+    sh t0, 0(a0)
+    sh a3, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    .globl spl_flash_program_u32
+spl_flash_program_u32:
+    li t0, 0x40
+    sw t0, 0(a0)
+    sw a3, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    .globl spl_flash_program_buffer
+spl_flash_program_buffer:
+    li t0, 0xe8
+    sb t0, 0(a0)
+5:  lbu t0, 0(a0)
+    andi t1, t0, 0x80
+    bnez t1, 7f
+    SPL_MTIME
+    bltu t1, a2, 5b
+    li t0, 0
+    li t1, 0xff
+    sb t1, 0(a0)
+    mv a0, t0
+    ret
+7:  addi t0, a4, -1
+    sb t0, 0(a0)
+    mv t4, a0
+6:  lbu t0, 0(a3)
+    sb t0, 0(t4)
+    addi a3, a3, 1
+    addi t4, t4, 1
+    addi a4, a4, -1
+    bnez a4, 6b
+    li t0, 0xd0
+    sb t0, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    .globl spl_flash_program_otp
+spl_flash_program_otp:
+    li t0, 0xc0
+    sh t0, 0(a0)
+    sh a3, 0(a0)
+    SPL_FLASH_POLL
+    ret
+
+    .globl spl_flash_read_mode
+spl_flash_read_mode:
+    sb a4, 0(a0)
+    mv t4, a0
+8:  lbu t0, 0(t4)
+    sb t0, 0(a1)
+    add t4, t4, a2
+    addi a1, a1, 1
+    addi a3, a3, -1
+    bnez a3, 8b
+    li t0, 0xff
+    sb t0, 0(a0)
+    ret
+
+    .text
+"#
+);
+
+unsafe extern "C" {
+    fn spl_flash_erase(addr: PhysAddr, mtime: usize, deadline: u64) -> u8;
+    fn spl_flash_erase_start(addr: PhysAddr);
+    fn spl_flash_erase_suspend(addr: PhysAddr, mtime: usize, deadline: u64) -> u8;
+    fn spl_flash_erase_resume(addr: PhysAddr);
+    fn spl_flash_wait(addr: PhysAddr, mtime: usize, deadline: u64) -> u8;
+    fn spl_flash_program_byte(addr: PhysAddr, mtime: usize, deadline: u64, value: u8) -> u8;
+    fn spl_flash_program_u16(addr: PhysAddr, mtime: usize, deadline: u64, value: u16) -> u8;
+    fn spl_flash_program_u32(addr: PhysAddr, mtime: usize, deadline: u64, value: u32) -> u8;
+    fn spl_flash_program_buffer(
+        addr: PhysAddr,
+        mtime: usize,
+        deadline: u64,
+        src: *const u8,
+        len: usize,
+    ) -> u8;
+    fn spl_flash_program_otp(addr: PhysAddr, mtime: usize, deadline: u64, value: u16) -> u8;
+    fn spl_flash_read_mode(addr: PhysAddr, buf: *mut u8, stride: usize, len: usize, cmd: u8);
+}
+
+/// Values program_aligned() writes in as few program cycles as the bus
+/// allows.
+pub trait FlashWord: Copy {
+    const SIZE: usize;
+    fn to_le_u64(self) -> u64;
+}
+
+macro_rules! flash_word {
+    ($($t:ty),*) => {$(
+        impl FlashWord for $t {
+            const SIZE: usize = core::mem::size_of::<$t>();
+            fn to_le_u64(self) -> u64 {
+                self as u64
+            }
+        }
+    )*};
 }
 
-/// Very small Intel CFI NOR (pflash_cfi01) driver, 8-bit commands,
-/// simplified for QEMU:
-///   - No status polling
-///   - No real erase (we rely on pre-erased image for the meta block)
+flash_word!(u16, u32, u64);
+
+/// What a CFI query says about a chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CfiGeometry {
+    /// Device size in bytes.
+    pub size: usize,
+    /// Size of the blocks of the first erase block region.
+    pub block_size: usize,
+    /// Erase block regions (more than one: mixed block sizes, e.g. boot
+    /// blocks, which we don't support).
+    pub regions: u8,
+    /// Bytes between two query table entries, i.e. the bus width the
+    /// chip answered at.
+    pub stride: usize,
+}
+
+/// What a chip says about itself in read-identifier mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashId {
+    /// JEDEC manufacturer code (0x89: Intel).
+    pub manufacturer: u16,
+    pub device: u16,
+    /// Feature support bits of the Intel extended query table ("PRI"),
+    /// if the chip has one.
+    pub features: Option<u32>,
+    /// Bytes between two query or identifier entries (the bus width).
+    pub stride: usize,
+}
+
+// A block erase started by erase_start(), by block offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BgErase {
+    Idle,
+    /// The chip reads as status.
+    Running(FlashOffset),
+    /// Reads as data again until resumed.
+    Suspended(FlashOffset),
+    /// Over, its outcome not collected by erase_finish() yet.
+    Done(Result<(), FlashError>),
+}
+
+/// Very small Intel CFI NOR (pflash_cfi01) driver, 8-bit commands.
 pub struct IntelFlash {
-    pub base: usize,
+    pub base: PhysAddr,
+    pub size: usize,
     pub block_size: usize,
+    /// Bytes per program cycle: 1 (x8), 2 (x16) or 4 (x32). Only
+    /// program_aligned() uses more than one.
+    pub bus_width: usize,
+    /// Applied to program_byte() and block_erase().
+    pub retry: RetryPolicy,
+    erase: Cell<BgErase>,
 }
 
 impl IntelFlash {
-    const CMD_PROGRAM: u8 = 0x40;
+    const SR_READY: u8 = 1 << 7;
+    const SR_ERASE_SUSPENDED: u8 = 1 << 6;
+    const SR_ERASE_ERR: u8 = 1 << 5;
+    const SR_PROGRAM_ERR: u8 = 1 << 4;
+    const SR_VPP_ERR: u8 = 1 << 3;
+    const SR_LOCKED: u8 = 1 << 1;
 
-    #[inline(always)]
-    fn write_cmd8(&self, offset: usize, cmd: u8) {
-        unsafe {
-            let ptr = (self.base + offset) as *mut u8;
-            core::ptr::write_volatile(ptr, cmd);
+    // Word program is ~100 us typical on real parts; leave plenty.
+    const PROGRAM_TIMEOUT_US: u64 = 5_000;
+    // A 128 KiB block erase is ~1 s typical, a few seconds worst case.
+    const ERASE_TIMEOUT_US: u64 = 5_000_000;
+    // Erase suspend latency is ~20 us typical.
+    const SUSPEND_TIMEOUT_US: u64 = 1_000;
+    // Smallest write buffer of the parts we care about (QEMU's is larger);
+    // a buffered write must not cross a buffer boundary.
+    const WRITE_BUFFER_SIZE: usize = 32;
+
+    const CMD_QUERY: u8 = 0x98;
+    const CMD_READ_ID: u8 = 0x90;
+
+    // Query table bytes up to the first erase block region (0x00..=0x30),
+    // and the fields we read.
+    const CFI_TABLE_LEN: usize = 0x31;
+    const CFI_QRY: Bytes<3> = Bytes(0x10);
+    const CFI_CMD_SET: Le16 = Le16(0x13);
+    const CFI_PRI: Le16 = Le16(0x15);
+    const CFI_DEVICE_SIZE: U8 = U8(0x27);
+    const CFI_REGIONS: U8 = U8(0x2c);
+    const CFI_REGION0_BLOCK: Le16 = Le16(0x2f);
+    // Command sets whose extended query table is Intel's.
+    const CMD_SET_INTEL: [u16; 2] = [0x0001, 0x0003];
+    // Extended query table: "PRI", version, then the feature bits.
+    const PRI_LEN: usize = 9;
+    const PRI_SIGNATURE: Bytes<3> = Bytes(0);
+    const PRI_FEATURES: Le32 = Le32(5);
+    // Identifier word at each block's base + 2 words: bit 0 locked.
+    const ID_BLOCK_LOCK: usize = 2;
+
+    // Offsets read by reachable() when there is no CFI table to go by.
+    const BUS_SAMPLES: [usize; 4] = [0, 0x1000, 0x1_0000, 0x10_0000];
+
+    // Protection registers, in read-identifier space (16-bit word
+    // addresses, so x16 parts): PR0's lock word, then PR0 (4 factory
+    // words, 4 user words), then the lock word of PR1..=16 and their
+    // 8 words each.
+    const OTP_WORD: usize = 2;
+    const OTP_PR0_LOCK: usize = 0x80;
+    const OTP_PR0: usize = 0x81;
+    const OTP_PR1_LOCK: usize = 0x89;
+    const OTP_PR1: usize = 0x8a;
+
+    /// The chip at `base`, with the default retry policy and no erase
+    /// under way.
+    pub const fn new(base: PhysAddr, size: usize, block_size: usize, bus_width: usize) -> Self {
+        IntelFlash {
+            base,
+            size,
+            block_size,
+            bus_width,
+            retry: RetryPolicy::DEFAULT,
+            erase: Cell::new(BgErase::Idle),
         }
     }
 
-    #[inline(always)]
-    fn write_data8(&self, offset: usize, data: u8) {
-        unsafe {
-            let ptr = (self.base + offset) as *mut u8;
-            core::ptr::write_volatile(ptr, data);
+    /// Flash unit `unit` at `base`, with the board's geometry.
+    pub const fn unit(unit: usize, base: PhysAddr) -> Self {
+        let (size, block_size) = (board::FLASH_SIZE[unit], board::FLASH_BLOCK_SIZE[unit]);
+        IntelFlash::new(base, size, block_size, board::FLASH_BUS_WIDTH[unit])
+    }
+
+    // Read-while-write guard, before any command: from the command until
+    // the routine puts the chip back in read-array mode, the whole chip
+    // reads as status, so neither the code issuing it nor the constants
+    // it uses may be in it. _start and linker.ld see to that; this turns
+    // a layout that regressed into a panic instead of a hang. Same for a
+    // command from another hart than the boot hart, which would race it
+    // on the chip's state.
+    fn check_not_in_use(base: PhysAddr, size: usize) {
+        let hart = arch::csr::read_mhartid();
+        if hart != arch::boot_hart() {
+            panic!("flash at 0x{:x}: command from hart {}, not the boot hart", base, hart);
+        }
+        let window = base.get()..base.get().saturating_add(size);
+        let pc = arch::current_pc();
+        if window.contains(&pc) {
+            panic!("flash at 0x{:x}: command from code running in it (pc=0x{:x})", base, pc);
+        }
+        let (start, end) = arch::rodata_region();
+        if start < window.end && window.start < end {
+            panic!("flash at 0x{:x}: command with .rodata in it (0x{:x}..0x{:x})", base, start, end);
+        }
+    }
+
+    /// Read the CFI query table of the chip at `base`, trying each bus
+    /// width until "QRY" shows up.
+    pub fn query(base: PhysAddr) -> Result<CfiGeometry, FlashError> {
+        // Its size is what we are asking: assume the largest unit's.
+        let size = board::FLASH_SIZE.into_iter().max().unwrap_or(0);
+        Self::check_not_in_use(base, size);
+        let mut t = [0u8; Self::CFI_TABLE_LEN];
+        for stride in [1, 2, 4] {
+            barrier::fence_i();
+            unsafe { spl_flash_read_mode(base, t.as_mut_ptr(), stride, t.len(), Self::CMD_QUERY) };
+            if Self::CFI_QRY.get(&t) != Ok(*b"QRY") {
+                continue;
+            }
+            let geometry = || -> Result<CfiGeometry, Truncated> {
+                // A region's size field is in 256-byte units, 0 meaning 128.
+                let block_size = match Self::CFI_REGION0_BLOCK.get(&t)? as usize {
+                    0 => 128,
+                    n => n * 256,
+                };
+                Ok(CfiGeometry {
+                    size: 1usize.checked_shl(Self::CFI_DEVICE_SIZE.get(&t)? as u32).unwrap_or(0),
+                    block_size,
+                    regions: Self::CFI_REGIONS.get(&t)?,
+                    stride,
+                })
+            };
+            return geometry().map_err(|_| FlashError::NoCfi);
         }
+        Err(FlashError::NoCfi)
     }
 
-    #[inline(always)]
-    fn read_u8(&self, offset: usize) -> u8 {
+    /// Manufacturer and device codes, and the Intel extended query
+    /// table's feature bits. Every read-mode switch ends with a read-array
+    /// command, whatever it found.
+    pub fn identify(&self) -> Result<FlashId, FlashError> {
+        self.before_command();
+        let stride = Self::query(self.base)?.stride;
+        let mut t = [0u8; Self::CFI_TABLE_LEN];
+        let mut ext = [0u8; Self::PRI_LEN];
+        let mut id = [0u8; 4 * 2];
+        barrier::fence_i();
         unsafe {
-            let ptr = (self.base + offset) as *const u8;
-            core::ptr::read_volatile(ptr)
+            spl_flash_read_mode(self.base, t.as_mut_ptr(), stride, t.len(), Self::CMD_QUERY);
+            // Manufacturer then device, a word each, as bytes.
+            spl_flash_read_mode(self.base, id.as_mut_ptr(), 1, 2 * stride, Self::CMD_READ_ID);
+        }
+        let le16 = |b: &[u8]| u16::from_le_bytes([b[0], if b.len() > 1 { b[1] } else { 0 }]);
+        let word = stride.min(2);
+        let cmd_set = Self::CFI_CMD_SET.get(&t).map_err(|_| FlashError::NoCfi)?;
+        let pri = Self::CFI_PRI.get(&t).map_err(|_| FlashError::NoCfi)? as usize;
+        let mut features = None;
+        if Self::CMD_SET_INTEL.contains(&cmd_set) && pri != 0 {
+            let addr = self.base + pri * stride;
+            barrier::fence_i();
+            unsafe { spl_flash_read_mode(addr, ext.as_mut_ptr(), stride, ext.len(), Self::CMD_QUERY) };
+            if Self::PRI_SIGNATURE.get(&ext) == Ok(*b"PRI") {
+                features = Self::PRI_FEATURES.get(&ext).ok();
+            }
+        }
+        Ok(FlashId {
+            manufacturer: le16(&id[..word]),
+            device: le16(&id[stride..stride + word]),
+            features,
+            stride,
+        })
+    }
+
+    /// Sweep the lock status of every block into `map`, one bit per
+    /// block (LSB first), and return how many are locked. `id` is what
+    /// identify() returned.
+    pub fn lock_map(&self, id: &FlashId, map: &mut [u8]) -> Result<usize, FlashError> {
+        let blocks = self.size / self.block_size;
+        if map.len() * 8 < blocks {
+            return Err(FlashError::OutOfRange);
+        }
+        self.before_command();
+        map.fill(0);
+        let mut locked = 0;
+        for block in 0..blocks {
+            let addr = self.base + block * self.block_size + Self::ID_BLOCK_LOCK * id.stride;
+            let mut status = 0u8;
+            barrier::fence_i();
+            unsafe { spl_flash_read_mode(addr, &mut status, 1, 1, Self::CMD_READ_ID) };
+            if status & 1 != 0 {
+                map[block / 8] |= 1 << (block % 8);
+                locked += 1;
+            }
+        }
+        Ok(locked)
+    }
+
+    /// Check that a flash answers at `base` before sending it commands
+    /// or trusting what it reads as. Unreachable if a read there faults,
+    /// or if it has no CFI table and a few samples all read as 0 or all
+    /// as ones, like an empty bus.
+    pub fn reachable(base: PhysAddr) -> Result<(), FlashError> {
+        // Before anything else: query() writes commands.
+        if let Err(fault) = unsafe { arch::try_read_volatile::<u32>(base.get()) } {
+            slog_warn!("flash at 0x{:x}: read faulted ({:?})", base, fault);
+            return Err(FlashError::Unreachable);
+        }
+        if Self::query(base).is_ok() {
+            return Ok(());
+        }
+        let samples =
+            Self::BUS_SAMPLES.map(|o| unsafe { arch::try_read_volatile::<u32>(base.get() + o) }.ok());
+        let all = |v| samples.iter().all(|&s| s == Some(v));
+        if samples.contains(&None) || all(0) || all(0xFFFF_FFFF) {
+            return Err(FlashError::Unreachable);
+        }
+        Ok(())
+    }
+
+    /// Where the byte at `offset` is for the CPU. Says nothing of
+    /// whether it is on the device: range() does.
+    pub fn addr_of(&self, offset: FlashOffset) -> PhysAddr {
+        offset.to_phys(self.base)
+    }
+
+    // The CPU address of [offset, offset + len), if it is all on the
+    // device (size as CFI reported it, else the board's). Nothing may
+    // wrap around into whatever MMIO lies past it.
+    fn range(&self, offset: FlashOffset, len: usize) -> Result<PhysAddr, FlashError> {
+        match offset.get().checked_add(len) {
+            Some(end) if end <= self.size && self.base.checked_add(end).is_some() => Ok(self.addr_of(offset)),
+            _ => Err(FlashError::OutOfRange),
         }
     }
 
     /// Read `buf.len()` bytes starting from `flash_offset`.
-    pub fn read_slice(&self, flash_offset: usize, buf: &mut [u8]) {
-        for (i, b) in buf.iter_mut().enumerate() {
-            *b = self.read_u8(flash_offset + i);
+    pub fn read_slice(&self, flash_offset: FlashOffset, buf: &mut [u8]) {
+        unsafe { self.copy_to_ram(flash_offset, buf.as_mut_ptr(), buf.len()) }
+    }
+
+    /// Copy `len` bytes from `flash_offset` straight to `dest`. Aligned
+    /// u64 reads in the steady state, bytes for an unaligned head and
+    /// tail; every flash access is volatile. A range past the end of the
+    /// device panics: reads have no error to return, and the callers
+    /// bound what they read by the layout.
+    ///
+    /// # Safety
+    /// `dest` must be valid for `len` bytes of writes, and not overlap
+    /// the flash.
+    pub unsafe fn copy_to_ram(&self, flash_offset: FlashOffset, dest: *mut u8, len: usize) {
+        const WORD: usize = core::mem::size_of::<u64>();
+        let Ok(src) = self.range(flash_offset, len) else {
+            panic!(
+                "flash at 0x{:x}: read of {} bytes at 0x{:x}, past its {} bytes",
+                self.base, len, flash_offset, self.size
+            );
+        };
+        self.before_read();
+        let mut src = src.get();
+        let end = src + len;
+        let mut dst = dest;
+        unsafe {
+            while src < end && !src.is_multiple_of(WORD) {
+                dst.write(Reg8::at(src, 0).read());
+                src += 1;
+                dst = dst.add(1);
+            }
+            while end - src >= WORD {
+                let w = Reg64::at(src, 0).read();
+                dst.cast::<u64>().write_unaligned(w);
+                src += WORD;
+                dst = dst.add(WORD);
+            }
+            while src < end {
+                dst.write(Reg8::at(src, 0).read());
+                src += 1;
+                dst = dst.add(1);
+            }
         }
     }
 
     /// Read a little-endian u32 from flash.
-    pub fn read_u32_le(&self, flash_offset: usize) -> u32 {
+    pub fn read_u32_le(&self, flash_offset: FlashOffset) -> u32 {
         let mut tmp = [0u8; 4];
         self.read_slice(flash_offset, &mut tmp);
         u32::from_le_bytes(tmp)
@@ -58,8 +646,17 @@ impl IntelFlash {
 
     /// Program a single byte at `offset`.
     /// Enforces NOR semantics: only 1→0 transitions allowed.
-    pub fn program_byte(&self, offset: usize, value: u8) -> Result<(), FlashError> {
-        let current = self.read_u8(offset);
+    pub fn program_byte(&self, offset: FlashOffset, value: u8) -> Result<(), FlashError> {
+        self.program_cycle(offset, 1, value as u64)
+    }
+
+    // One program cycle of `size` bytes (1, 2 or 4, naturally aligned),
+    // `value` little-endian. Only 1→0 transitions are allowed.
+    fn program_cycle(&self, offset: FlashOffset, size: usize, value: u64) -> Result<(), FlashError> {
+        let addr = self.range(offset, size)?;
+        let mut current = [0u8; 8];
+        self.read_slice(offset, &mut current[..size]);
+        let current = u64::from_le_bytes(current);
 
         // Only allow 1→0 transitions; cannot set bits back to 1.
         if (value | current) != current {
@@ -67,16 +664,200 @@ impl IntelFlash {
         }
 
         // Intel "program" sequence: cmd at address, then data.
-        self.write_cmd8(offset, Self::CMD_PROGRAM);
-        self.write_data8(offset, value);
+        self.before_command();
+        self.with_retry("program", offset, FlashError::ProgramError, || {
+            let deadline = Deadline::after_us(Self::PROGRAM_TIMEOUT_US);
+            let (mtime, at) = (clint::mtime_addr(), deadline.ticks());
+            barrier::fence_i();
+            unsafe {
+                match size {
+                    1 => spl_flash_program_byte(addr, mtime, at, value as u8),
+                    2 => spl_flash_program_u16(addr, mtime, at, value as u16),
+                    _ => spl_flash_program_u32(addr, mtime, at, value as u32),
+                }
+            }
+        })
+    }
+
+    /// Program `value` (little-endian) at `offset`, a multiple of its
+    /// size. A value no wider than the bus goes in one program cycle, so
+    /// a power cut leaves it either written or not.
+    ///
+    /// Wider values (any value on an x8 part) take several cycles, issued
+    /// from the least significant end: an interrupted write always leaves
+    /// the most significant byte erased (0xFF). That is best effort only:
+    /// it lets a reader tell a torn value from a complete one, provided
+    /// no complete value has 0xFF as its top byte.
+    pub fn program_aligned<W: FlashWord>(&self, offset: FlashOffset, value: W) -> Result<(), FlashError> {
+        if !offset.is_aligned(W::SIZE) {
+            return Err(FlashError::Unaligned(offset.get()));
+        }
+        let chunk = self.bus_width.min(W::SIZE).min(4);
+        let value = value.to_le_u64();
+        for i in (0..W::SIZE).step_by(chunk) {
+            let bits = (chunk * 8) as u32;
+            let part = (value >> (i * 8)) & (u64::MAX >> (64 - bits));
+            self.program_cycle(offset + i, chunk, part)?;
+        }
+        Ok(())
+    }
+
+    /// program_aligned() for a u16.
+    #[cfg_attr(not(feature = "meta-x16"), allow(dead_code))]
+    pub fn program_u16_le(&self, offset: FlashOffset, value: u16) -> Result<(), FlashError> {
+        self.program_aligned(offset, value)
+    }
+
+    /// program_aligned() for a u32.
+    pub fn program_u32_le(&self, offset: FlashOffset, value: u32) -> Result<(), FlashError> {
+        self.program_aligned(offset, value)
+    }
+
+    // Run `op` (a RAM routine, which returns the status register and has
+    // already cleared it on error) until it succeeds, the block turns out
+    // to be locked, or the retry policy runs out.
+    fn with_retry(
+        &self,
+        what: &str,
+        offset: FlashOffset,
+        err: FlashError,
+        mut op: impl FnMut() -> u8,
+    ) -> Result<(), FlashError> {
+        let mut attempt = 1;
+        loop {
+            let sr = op();
+            match Self::check_status(sr, err) {
+                Ok(()) => {
+                    if attempt > 1 {
+                        slog_warn!(
+                            "WARNING: flash {} at 0x{:x} needed {} attempts",
+                            what,
+                            offset,
+                            attempt
+                        );
+                    }
+                    return Ok(());
+                }
+                Err(FlashError::Protected) => return Err(FlashError::Protected),
+                Err(e) if attempt >= self.retry.attempts => {
+                    return Err(if attempt > 1 {
+                        FlashError::GaveUp {
+                            attempts: attempt,
+                            status: sr,
+                        }
+                    } else {
+                        e
+                    });
+                }
+                Err(_) => {
+                    clint::delay_us(self.retry.backoff_us);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Decode the status register returned by a RAM routine.
+    fn check_status(sr: u8, err: FlashError) -> Result<(), FlashError> {
+        if sr & Self::SR_READY == 0 {
+            Err(FlashError::Timeout)
+        } else if sr & Self::SR_LOCKED != 0 {
+            Err(FlashError::Protected)
+        } else if sr & (Self::SR_ERASE_ERR | Self::SR_PROGRAM_ERR | Self::SR_VPP_ERR) != 0 {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
 
-        // In real hardware we would poll SR here; for QEMU's pflash we
-        // assume the write completes "instantly".
+    // Byte offset (from the flash base) of `region`'s data and its lock
+    // word, and the lock bit that covers its user part.
+    fn otp_layout(region: OtpRegion) -> Result<(usize, usize, u16), FlashError> {
+        let (data, lock, bit) = match region.0 {
+            0 => (Self::OTP_PR0, Self::OTP_PR0_LOCK, 1),
+            n @ 1..=16 => (Self::OTP_PR1 + (n as usize - 1) * 8, Self::OTP_PR1_LOCK, n - 1),
+            _ => return Err(FlashError::OutOfRange),
+        };
+        Ok((data * Self::OTP_WORD, lock * Self::OTP_WORD, 1 << bit))
+    }
+
+    // Read `buf.len()` bytes of read-identifier space at byte `offset`,
+    // and tell them apart from plain array data at the same place.
+    fn read_id_space(&self, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.before_command();
+        barrier::fence_i();
+        let addr = self.base + offset;
+        unsafe { spl_flash_read_mode(addr, buf.as_mut_ptr(), 1, buf.len(), Self::CMD_READ_ID) };
+        let mut array = [0u8; OtpRegion::SIZE];
+        let array = &mut array[..buf.len()];
+        self.read_slice(FlashOffset::new(offset), array);
+        if buf == array {
+            return Err(FlashError::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes of OTP `region`, from `offset` in it.
+    /// All 0xFF is unprogrammed.
+    pub fn read_otp(&self, region: OtpRegion, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        let (data, _, _) = Self::otp_layout(region)?;
+        match offset.checked_add(buf.len()) {
+            Some(end) if end <= OtpRegion::SIZE => {}
+            _ => return Err(FlashError::OutOfRange),
+        }
+        // Compared as a whole region: a short read may well match the
+        // array by chance.
+        let mut all = [0u8; OtpRegion::SIZE];
+        self.read_id_space(data, &mut all)?;
+        buf.copy_from_slice(&all[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    /// Whether the user part of OTP `region` is locked (no more writes).
+    pub fn otp_locked(&self, region: OtpRegion) -> Result<bool, FlashError> {
+        let (_, lock, bit) = Self::otp_layout(region)?;
+        let mut word = [0u8; 2];
+        self.read_id_space(lock, &mut word)?;
+        // Lock bits are programmed (cleared) to lock.
+        Ok(u16::from_le_bytes(word) & bit == 0)
+    }
+
+    /// Program `data` into OTP `region` at `offset`, a 16-bit word at a
+    /// time. Like the array, only 1→0 transitions; unlike it, no erase,
+    /// ever. The factory half of region 0 and locked regions are refused.
+    #[allow(dead_code)]
+    pub fn program_otp(
+        &self,
+        region: OtpRegion,
+        offset: usize,
+        data: &[u8],
+        _: OtpPermanent,
+    ) -> Result<(), FlashError> {
+        let (base, _, _) = Self::otp_layout(region)?;
+        match offset.checked_add(data.len()) {
+            Some(end) if end <= OtpRegion::SIZE => {}
+            _ => return Err(FlashError::OutOfRange),
+        }
+        if !offset.is_multiple_of(Self::OTP_WORD) || !data.len().is_multiple_of(Self::OTP_WORD) {
+            return Err(FlashError::Unaligned(offset));
+        }
+        if (region.0 == 0 && offset < OtpRegion::FACTORY_SIZE) || self.otp_locked(region)? {
+            return Err(FlashError::Protected);
+        }
+        for (i, w) in data.chunks_exact(Self::OTP_WORD).enumerate() {
+            let value = u16::from_le_bytes([w[0], w[1]]);
+            let addr = self.base + base + offset + i * Self::OTP_WORD;
+            let deadline = Deadline::after_us(Self::PROGRAM_TIMEOUT_US);
+            barrier::fence_i();
+            let sr = unsafe { spl_flash_program_otp(addr, clint::mtime_addr(), deadline.ticks(), value) };
+            Self::check_status(sr, FlashError::ProgramError)?;
+        }
         Ok(())
     }
 
     /// Program arbitrary data at `flash_offset`.
-    pub fn program(&self, flash_offset: usize, data: &[u8]) -> Result<(), FlashError> {
+    pub fn program(&self, flash_offset: FlashOffset, data: &[u8]) -> Result<(), FlashError> {
+        self.range(flash_offset, data.len())?;
         for (i, b) in data.iter().enumerate() {
             let dst_off = flash_offset + i;
             self.program_byte(dst_off, *b)?;
@@ -84,9 +865,169 @@ impl IntelFlash {
         Ok(())
     }
 
-    /// Stubbed erase: not used in normal path (we pre-erase the meta block),
-    /// but kept for API compatibility.
-    pub fn block_erase(&self, _block_index: usize) -> Result<(), FlashError> {
-        Err(FlashError::EraseError)
+    /// Program `data` at `flash_offset` through the chip's write buffer,
+    /// which is much faster than byte programming. The range must be
+    /// erased; `data` must not itself live in this flash.
+    pub fn program_buffered(&self, flash_offset: FlashOffset, data: &[u8]) -> Result<(), FlashError> {
+        self.range(flash_offset, data.len())?;
+        self.before_command();
+        let mut done = 0;
+        while done < data.len() {
+            let offset = flash_offset + done;
+            let room = Self::WRITE_BUFFER_SIZE - offset.get() % Self::WRITE_BUFFER_SIZE;
+            let n = core::cmp::min(room, data.len() - done);
+            let deadline = Deadline::after_us(Self::PROGRAM_TIMEOUT_US);
+            barrier::fence_i();
+            let sr = unsafe {
+                spl_flash_program_buffer(
+                    self.addr_of(offset),
+                    clint::mtime_addr(),
+                    deadline.ticks(),
+                    data[done..].as_ptr(),
+                    n,
+                )
+            };
+            Self::check_status(sr, FlashError::ProgramError)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Erase block `block_index` (every byte back to 0xFF).
+    pub fn block_erase(&self, block_index: usize) -> Result<(), FlashError> {
+        if block_index >= self.size / self.block_size {
+            return Err(FlashError::OutOfRange);
+        }
+        let offset = FlashOffset::new(block_index * self.block_size);
+        self.before_command();
+        self.with_retry("erase", offset, FlashError::EraseError, || {
+            let deadline = Deadline::after_us(Self::ERASE_TIMEOUT_US);
+            barrier::fence_i();
+            unsafe { spl_flash_erase(self.addr_of(offset), clint::mtime_addr(), deadline.ticks()) }
+        })
+    }
+
+    /// Start erasing block `block_index` and return at once: the erase
+    /// goes on while the boot does. Reading this chip suspends it (other
+    /// blocks read fine then), erase_resume() lets it go on, and any
+    /// other command waits for it to end. erase_finish() waits too, and
+    /// returns how it went; so does the next erase_start(), for the
+    /// previous erase.
+    pub fn erase_start(&self, block_index: usize) -> Result<(), FlashError> {
+        if block_index >= self.size / self.block_size {
+            return Err(FlashError::OutOfRange);
+        }
+        self.erase_finish()?;
+        let offset = FlashOffset::new(block_index * self.block_size);
+        Self::check_not_in_use(self.base, self.size);
+        barrier::fence_i();
+        unsafe { spl_flash_erase_start(self.addr_of(offset)) };
+        self.erase.set(BgErase::Running(offset));
+        Ok(())
+    }
+
+    /// Let a suspended erase go on.
+    pub fn erase_resume(&self) {
+        if let BgErase::Suspended(offset) = self.erase.get() {
+            barrier::fence_i();
+            unsafe { spl_flash_erase_resume(self.addr_of(offset)) };
+            self.erase.set(BgErase::Running(offset));
+        }
+    }
+
+    /// Wait for the erase_start() erase to end (resuming it if need be)
+    /// and return its outcome; Ok if there is none.
+    pub fn erase_finish(&self) -> Result<(), FlashError> {
+        self.erase_resume();
+        let result = match self.erase.get() {
+            BgErase::Idle => Ok(()),
+            BgErase::Done(result) => result,
+            BgErase::Running(offset) | BgErase::Suspended(offset) => {
+                let deadline = Deadline::after_us(Self::ERASE_TIMEOUT_US);
+                barrier::fence_i();
+                let addr = self.addr_of(offset);
+                let sr = unsafe { spl_flash_wait(addr, clint::mtime_addr(), deadline.ticks()) };
+                Self::check_status(sr, FlashError::EraseError)
+            }
+        };
+        self.erase.set(BgErase::Idle);
+        result
+    }
+
+    // Suspend a running erase_start() erase, so the array reads as data.
+    fn before_read(&self) {
+        let BgErase::Running(offset) = self.erase.get() else {
+            return;
+        };
+        let deadline = Deadline::after_us(Self::SUSPEND_TIMEOUT_US);
+        barrier::fence_i();
+        let addr = self.addr_of(offset);
+        let sr = unsafe { spl_flash_erase_suspend(addr, clint::mtime_addr(), deadline.ticks()) };
+        if sr & Self::SR_READY == 0 {
+            panic!("flash at 0x{:x}: erase at 0x{:x} won't suspend", self.base, offset);
+        }
+        self.erase.set(if sr & Self::SR_ERASE_SUSPENDED != 0 {
+            BgErase::Suspended(offset)
+        } else {
+            BgErase::Done(Self::check_status(sr, FlashError::EraseError))
+        });
+    }
+
+    // Before a command: the read-while-write guard, and any erase_start()
+    // erase run to its end (outcome kept for erase_finish()).
+    fn before_command(&self) {
+        Self::check_not_in_use(self.base, self.size);
+        if matches!(self.erase.get(), BgErase::Running(_) | BgErase::Suspended(_)) {
+            let result = self.erase_finish();
+            self.erase.set(BgErase::Done(result));
+        }
+    }
+
+    /// Erase every block overlapping [flash_offset, flash_offset + len).
+    pub fn erase_range(&self, flash_offset: FlashOffset, len: usize) -> Result<(), FlashError> {
+        self.range(flash_offset, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let first = flash_offset.get() / self.block_size;
+        let last = (flash_offset.get() + len - 1) / self.block_size;
+        for block in first..=last {
+            watchdog::yield_point("erase", block - first, last + 1 - first)?;
+            self.block_erase(block)?;
+        }
+        Ok(())
+    }
+
+    /// Erase the whole device but its first `keep` bytes (rounded up to
+    /// whole blocks), calling `progress(done, total)`, in blocks, after
+    /// each block. Intel CFI parts have no chip erase command, so this
+    /// is one block erase after another.
+    #[cfg_attr(not(feature = "provision"), allow(dead_code))]
+    pub fn chip_erase(&self, keep: usize, mut progress: impl FnMut(usize, usize)) -> Result<(), FlashError> {
+        let blocks = self.size / self.block_size;
+        let first = keep.div_ceil(self.block_size).min(blocks);
+        let total = blocks - first;
+        for (done, block) in (first..blocks).enumerate() {
+            watchdog::yield_point("chip erase", done, total)?;
+            self.block_erase(block)?;
+            progress(done + 1, total);
+        }
+        Ok(())
+    }
+}
+
+// Source offsets are plain usize (a disk or RAM has them too): here they
+// are flash offsets.
+impl ImageSource for IntelFlash {
+    fn read_slice(&self, offset: usize, buf: &mut [u8]) {
+        IntelFlash::read_slice(self, FlashOffset::new(offset), buf)
+    }
+
+    unsafe fn copy_to_ram(&self, offset: usize, dest: *mut u8, len: usize) {
+        unsafe { IntelFlash::copy_to_ram(self, FlashOffset::new(offset), dest, len) }
+    }
+
+    fn log_addr(&self, offset: usize) -> usize {
+        self.addr_of(FlashOffset::new(offset)).get()
     }
 }
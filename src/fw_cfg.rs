@@ -0,0 +1,311 @@
+// QEMU firmware configuration device (fw_cfg), MMIO flavour (virt), for
+// tests: a QEMU command line can hand the SPL a payload and settings
+// without touching the flash images,
+//
+//   -fw_cfg name=opt/spl/payload,file=bank.img
+//   -fw_cfg name=opt/spl/env,file=spl.env
+//
+// Items are numbered by a 16-bit key, written (big-endian) to the
+// selector; the data register then streams the item from its start.
+// Named ("file") items are listed in the directory item. With the DMA
+// interface, which QEMU has had since 2.5, an item is copied straight to
+// RAM in one register write instead of 8 bytes per load.
+//
+// The env item holds /chosen properties, one per line, in the DTS syntax
+// for the three kinds of values the SPL reads: nothing, a string, or
+// 32-bit cells. `#` starts a comment, the `;` is optional:
+//
+//   spl,bootdelay-ms = <3000>;
+//   spl,boot-device = "disk";
+//   spl,paranoid;
+
+use crate::arch::{self, barrier};
+use crate::bytes::Cursor;
+use crate::dtb_edit::{MAX_PROPS, Prop};
+use crate::mmio::register_block;
+
+/// The items the SPL looks for.
+pub const PAYLOAD_FILE: &str = "opt/spl/payload";
+pub const ENV_FILE: &str = "opt/spl/env";
+
+register_block! {
+    /// fw_cfg registers (virt layout).
+    struct Regs {
+        /// Item data, a byte per load.
+        data8: Reg8 @ 0x00,
+        /// Item data, 8 bytes per load, in item order.
+        data64: Reg64 @ 0x00,
+        /// Item selector (write, big-endian).
+        selector: Reg16 @ 0x08,
+        /// DMA descriptor address, big-endian; writing the low half
+        /// starts the transfer.
+        dma_hi: Reg32 @ 0x10,
+        dma_lo: Reg32 @ 0x14,
+    }
+}
+
+const KEY_SIGNATURE: u16 = 0x0000;
+const KEY_ID: u16 = 0x0001;
+const KEY_FILE_DIR: u16 = 0x0019;
+
+const SIGNATURE: [u8; 4] = *b"QEMU";
+// KEY_ID feature bits
+const ID_DMA: u32 = 1 << 1;
+
+// DMA control word
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SELECT: u32 = 1 << 3;
+
+// Directory entry: size (u32 BE), key (u16 BE), reserved, NUL-padded name
+const DIR_ENTRY_SIZE: usize = 64;
+const NAME_LEN: usize = 56;
+
+// Control word polls before a DMA transfer counts as failed. QEMU
+// completes it within the register write; this only bounds a broken one.
+const DMA_SPIN_LIMIT: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FwCfgError {
+    /// The item doesn't fit the buffer it is read into.
+    TooBig { size: usize, room: usize },
+    /// The device flagged the DMA transfer, or never finished it.
+    Dma,
+}
+
+/// A named item, as listed in the directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct File {
+    pub key: u16,
+    pub size: usize,
+}
+
+// The DMA descriptor, all big-endian.
+#[repr(C, align(8))]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+pub struct FwCfg {
+    regs: Regs,
+    dma: bool,
+}
+
+impl FwCfg {
+    /// The device at `base` (normally from the DTB), None if nothing
+    /// answers there or it doesn't sign as QEMU's.
+    pub fn probe(base: usize) -> Option<Self> {
+        // A bare load first: one that faults means no device.
+        unsafe { arch::try_read_volatile::<u8>(base) }.ok()?;
+        let fw = FwCfg {
+            regs: unsafe { Regs::new(base) },
+            dma: false,
+        };
+        fw.select(KEY_SIGNATURE);
+        let mut signature = [0u8; 4];
+        fw.read_pio(&mut signature);
+        if signature != SIGNATURE {
+            return None;
+        }
+        fw.select(KEY_ID);
+        let mut id = [0u8; 4];
+        fw.read_pio(&mut id);
+        Some(FwCfg {
+            dma: u32::from_le_bytes(id) & ID_DMA != 0,
+            ..fw
+        })
+    }
+
+    /// Whether items are read by DMA (else 8 bytes per load).
+    pub fn has_dma(&self) -> bool {
+        self.dma
+    }
+
+    fn select(&self, key: u16) {
+        self.regs.selector().write(key.to_be());
+    }
+
+    // The next buf.len() bytes of the selected item.
+    fn read_pio(&self, buf: &mut [u8]) {
+        let mut chunks = buf.chunks_exact_mut(8);
+        for chunk in &mut chunks {
+            chunk.copy_from_slice(&self.regs.data64().read().to_ne_bytes());
+        }
+        for b in chunks.into_remainder() {
+            *b = self.regs.data8().read();
+        }
+    }
+
+    // Item `key`, from its start, into `buf`.
+    fn read_dma(&self, key: u16, buf: &mut [u8]) -> Result<(), FwCfgError> {
+        let mut access = DmaAccess {
+            control: ((key as u32) << 16 | DMA_SELECT | DMA_READ).to_be(),
+            length: (buf.len() as u32).to_be(),
+            address: (buf.as_mut_ptr() as u64).to_be(),
+        };
+        let at = &raw mut access as u64;
+        // The device reads the descriptor and writes the buffer behind
+        // our back: our stores first, its stores before we look.
+        barrier::fence_rw_rw();
+        self.regs.dma_hi().write(((at >> 32) as u32).to_be());
+        self.regs.dma_lo().write((at as u32).to_be());
+        for _ in 0..DMA_SPIN_LIMIT {
+            let control = u32::from_be(unsafe { core::ptr::read_volatile(&raw const access.control) });
+            if control & DMA_ERROR != 0 {
+                return Err(FwCfgError::Dma);
+            }
+            if control == 0 {
+                barrier::fence_rw_rw();
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(FwCfgError::Dma)
+    }
+
+    /// The named item `name`, None if the directory doesn't list it.
+    pub fn find(&self, name: &str) -> Option<File> {
+        if name.len() >= NAME_LEN {
+            return None;
+        }
+        self.select(KEY_FILE_DIR);
+        let mut count = [0u8; 4];
+        self.read_pio(&mut count);
+        for _ in 0..u32::from_be_bytes(count) {
+            let mut entry = [0u8; DIR_ENTRY_SIZE];
+            self.read_pio(&mut entry);
+            let mut c = Cursor::new(&entry);
+            let (Ok(size), Ok(key), Ok(()), Ok(entry_name)) =
+                (c.read_u32_be(), c.read_u16_be(), c.skip(2), c.read_bytes(NAME_LEN))
+            else {
+                return None;
+            };
+            if entry_name.starts_with(name.as_bytes()) && entry_name[name.len()] == 0 {
+                return Some(File {
+                    key,
+                    size: size as usize,
+                });
+            }
+        }
+        None
+    }
+
+    /// Read all of `file` to the start of `buf`, returning its size.
+    pub fn read(&self, file: File, buf: &mut [u8]) -> Result<usize, FwCfgError> {
+        let Some(dest) = buf.get_mut(..file.size) else {
+            return Err(FwCfgError::TooBig {
+                size: file.size,
+                room: buf.len(),
+            });
+        };
+        if self.dma {
+            self.read_dma(file.key, dest)?;
+        } else {
+            self.select(file.key);
+            self.read_pio(dest);
+        }
+        Ok(file.size)
+    }
+}
+
+// Room for the encoded values of an env, all properties together.
+const ENV_VALUES: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvError {
+    /// Not UTF-8.
+    Encoding,
+    /// This line (from 1) isn't `name`, `name = "string"` or
+    /// `name = <cells>`.
+    Syntax { line: usize },
+    /// More properties than one /chosen patch takes.
+    TooManyProps,
+    /// The values don't fit ENV_VALUES bytes.
+    TooLong,
+}
+
+/// The properties of an env item, with their values encoded as in a DTB.
+pub struct Env<'a> {
+    names: [&'a str; MAX_PROPS],
+    // Value i is values[ends[i - 1]..ends[i]].
+    ends: [usize; MAX_PROPS],
+    values: [u8; ENV_VALUES],
+    len: usize,
+}
+
+impl<'a> Env<'a> {
+    pub fn parse(text: &'a [u8]) -> Result<Self, EnvError> {
+        let text = core::str::from_utf8(text).map_err(|_| EnvError::Encoding)?;
+        let mut env = Env {
+            names: [""; MAX_PROPS],
+            ends: [0; MAX_PROPS],
+            values: [0; ENV_VALUES],
+            len: 0,
+        };
+        let mut used = 0;
+        for (n, line) in text.lines().enumerate() {
+            let syntax = EnvError::Syntax { line: n + 1 };
+            let line = line.split('#').next().unwrap_or("").trim();
+            let line = line.strip_suffix(';').unwrap_or(line).trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim_end(), Some(value.trim_start())),
+                None => (line, None),
+            };
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(syntax);
+            }
+            if env.len == MAX_PROPS {
+                return Err(EnvError::TooManyProps);
+            }
+            let mut put = |bytes: &[u8]| -> Result<(), EnvError> {
+                let end = used + bytes.len();
+                env.values.get_mut(used..end).ok_or(EnvError::TooLong)?.copy_from_slice(bytes);
+                used = end;
+                Ok(())
+            };
+            match value {
+                None => {}
+                Some(v) if v.len() >= 2 && v.starts_with('"') && v.ends_with('"') => {
+                    put(&v.as_bytes()[1..v.len() - 1])?;
+                    put(&[0])?;
+                }
+                Some(v) if v.len() >= 2 && v.starts_with('<') && v.ends_with('>') => {
+                    for cell in v[1..v.len() - 1].split_whitespace() {
+                        put(&parse_cell(cell).ok_or(syntax)?.to_be_bytes())?;
+                    }
+                }
+                Some(_) => return Err(syntax),
+            }
+            env.names[env.len] = name;
+            env.ends[env.len] = used;
+            env.len += 1;
+        }
+        Ok(env)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The properties, for dtb_edit::patch_chosen(); only the first
+    /// len() are set.
+    pub fn props(&self) -> [Prop<'_>; MAX_PROPS] {
+        core::array::from_fn(|i| Prop {
+            name: self.names[i],
+            value: &self.values[if i == 0 { 0 } else { self.ends[i - 1] }..self.ends[i]],
+        })
+    }
+}
+
+// A cell: decimal, or hex with 0x.
+fn parse_cell(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
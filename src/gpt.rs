@@ -0,0 +1,369 @@
+use core::result::Result;
+
+use crate::bytes::{Bytes, Le16, Le32, Le64, Truncated};
+use crate::crc32::crc32;
+use crate::image::{flash_crc32, ImageSource};
+
+// GUID Partition Table (UEFI 2.10, chapter 5), read-only, so the banks
+// on a disk can be found by partition name instead of at fixed LBAs.
+//
+// Header at LBA 1, backup at the last LBA, all fields little-endian:
+//
+//   0x00  [8]  signature        b"EFI PART"
+//   0x08  u32  revision         0x00010000
+//   0x0c  u32  header_size      92 (at least)
+//   0x10  u32  header_crc32     CRC-32 of header_size bytes, this field zeroed
+//   0x18  u64  my_lba           where this copy lives
+//   0x20  u64  alternate_lba    where the other copy lives
+//   0x28  u64  first_usable_lba
+//   0x30  u64  last_usable_lba
+//   0x38  [16] disk_guid
+//   0x48  u64  entries_lba
+//   0x50  u32  num_entries
+//   0x54  u32  entry_size       128 << n
+//   0x58  u32  entries_crc32    CRC-32 of num_entries * entry_size bytes
+//
+// Entry (first 128 bytes of each entry_size slot):
+//
+//   0x00  [16] type GUID (all zero: unused)
+//   0x10  [16] unique GUID
+//   0x20  u64  first_lba
+//   0x28  u64  last_lba (inclusive)
+//   0x30  u64  attributes
+//   0x38  [72] name, UTF-16LE, NUL-padded
+//
+// The entry array is streamed, never held in RAM, so any entry count
+// works (128 is only the usual minimum).
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Partition names our provisioning tooling uses.
+pub const BANK_A_NAME: &str = "bank_a";
+pub const BANK_B_NAME: &str = "bank_b";
+pub const BOOTMETA_NAME: &str = "bootmeta";
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const HEADER_MIN_SIZE: usize = 92;
+const ENTRY_MIN_SIZE: usize = 128;
+const NAME_UNITS: usize = 36;
+
+// Header fields
+const HDR_SIGNATURE: Bytes<8> = Bytes(0x00);
+const HDR_SIZE: Le32 = Le32(0x0c);
+const HDR_CRC32: Le32 = Le32(0x10);
+const HDR_MY_LBA: Le64 = Le64(0x18);
+const HDR_ENTRIES_LBA: Le64 = Le64(0x48);
+const HDR_NUM_ENTRIES: Le32 = Le32(0x50);
+const HDR_ENTRY_SIZE: Le32 = Le32(0x54);
+const HDR_ENTRIES_CRC32: Le32 = Le32(0x58);
+
+// Entry fields
+const ENTRY_TYPE: Bytes<16> = Bytes(0x00);
+const ENTRY_FIRST_LBA: Le64 = Le64(0x20);
+const ENTRY_LAST_LBA: Le64 = Le64(0x28);
+const ENTRY_NAME: usize = 0x38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GptError {
+    /// No "EFI PART" signature: the disk has no GPT (at this copy).
+    NoSignature,
+    BadHeaderSize(u32),
+    HeaderCrc { expected: u32, actual: u32 },
+    /// The header doesn't say it lives where we read it.
+    BadMyLba(u64),
+    /// Entry size not 128 << n, or the array runs off the disk.
+    BadEntries,
+    EntriesCrc { expected: u32, actual: u32 },
+    /// A field past the end of what was read.
+    Truncated(Truncated),
+}
+
+impl From<Truncated> for GptError {
+    fn from(e: Truncated) -> Self {
+        GptError::Truncated(e)
+    }
+}
+
+/// A validated partition table (primary, or backup if that one was bad).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gpt {
+    pub entries_lba: u64,
+    pub num_entries: u32,
+    pub entry_size: u32,
+    /// Found through the backup header.
+    pub backup: bool,
+}
+
+/// A partition, as [first_lba, last_lba] in 512-byte sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub index: u32,
+    pub first_lba: u64,
+    pub last_lba: u64,
+}
+
+impl Partition {
+    pub fn offset(&self) -> usize {
+        self.first_lba as usize * SECTOR_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        (self.last_lba - self.first_lba + 1) as usize * SECTOR_SIZE
+    }
+}
+
+/// On-disk form of a GUID written as a1a2a3a4-b1b2-c1c2-d..., whose first
+/// three groups are stored little-endian.
+#[allow(dead_code)]
+pub const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6],
+        d[7],
+    ]
+}
+
+impl Gpt {
+    /// Read the GPT of a disk whose last sector is `last_lba`, falling
+    /// back to the backup header when the primary one (or its entry
+    /// array) is bad. The primary's error is returned if both are.
+    pub fn read(src: &dyn ImageSource, last_lba: u64) -> Result<Self, GptError> {
+        let primary = match Self::read_at(src, 1, last_lba) {
+            Ok(gpt) => return Ok(gpt),
+            Err(e) => e,
+        };
+        match Self::read_at(src, last_lba, last_lba) {
+            Ok(gpt) => Ok(Gpt { backup: true, ..gpt }),
+            Err(_) => Err(primary),
+        }
+    }
+
+    fn read_at(src: &dyn ImageSource, lba: u64, last_lba: u64) -> Result<Self, GptError> {
+        let mut hdr = [0u8; SECTOR_SIZE];
+        src.read_slice(lba as usize * SECTOR_SIZE, &mut hdr);
+        if HDR_SIGNATURE.get(&hdr)? != *SIGNATURE {
+            return Err(GptError::NoSignature);
+        }
+        let size = HDR_SIZE.get(&hdr)?;
+        if (size as usize) < HEADER_MIN_SIZE || size as usize > SECTOR_SIZE {
+            return Err(GptError::BadHeaderSize(size));
+        }
+        let expected = HDR_CRC32.get(&hdr)?;
+        hdr[HDR_CRC32.range()].fill(0);
+        let actual = crc32(&hdr[..size as usize]);
+        if actual != expected {
+            return Err(GptError::HeaderCrc { expected, actual });
+        }
+        let my_lba = HDR_MY_LBA.get(&hdr)?;
+        if my_lba != lba {
+            return Err(GptError::BadMyLba(my_lba));
+        }
+
+        let gpt = Gpt {
+            entries_lba: HDR_ENTRIES_LBA.get(&hdr)?,
+            num_entries: HDR_NUM_ENTRIES.get(&hdr)?,
+            entry_size: HDR_ENTRY_SIZE.get(&hdr)?,
+            backup: false,
+        };
+        let entry_size = gpt.entry_size as usize;
+        if entry_size < ENTRY_MIN_SIZE || !entry_size.is_power_of_two() {
+            return Err(GptError::BadEntries);
+        }
+        let array_sectors = (gpt.array_len() as u64).div_ceil(SECTOR_SIZE as u64);
+        if gpt.entries_lba == 0
+            || gpt.entries_lba.checked_add(array_sectors).is_none_or(|end| end > last_lba + 1)
+        {
+            return Err(GptError::BadEntries);
+        }
+        let expected = HDR_ENTRIES_CRC32.get(&hdr)?;
+        let actual = flash_crc32(src, gpt.entries_lba as usize * SECTOR_SIZE, gpt.array_len());
+        if actual != expected {
+            return Err(GptError::EntriesCrc { expected, actual });
+        }
+        Ok(gpt)
+    }
+
+    fn array_len(&self) -> usize {
+        self.num_entries as usize * self.entry_size as usize
+    }
+
+    // First used entry `pick` takes, given its first 128 bytes.
+    fn find(
+        &self,
+        src: &dyn ImageSource,
+        mut pick: impl FnMut(&[u8; ENTRY_MIN_SIZE]) -> bool,
+    ) -> Option<Partition> {
+        let base = self.entries_lba as usize * SECTOR_SIZE;
+        let mut entry = [0u8; ENTRY_MIN_SIZE];
+        for index in 0..self.num_entries {
+            src.read_slice(base + index as usize * self.entry_size as usize, &mut entry);
+            if ENTRY_TYPE.get(&entry) == Ok([0; 16]) || !pick(&entry) {
+                continue;
+            }
+            let (Ok(first_lba), Ok(last_lba)) =
+                (ENTRY_FIRST_LBA.get(&entry), ENTRY_LAST_LBA.get(&entry))
+            else {
+                continue;
+            };
+            if last_lba < first_lba {
+                continue;
+            }
+            return Some(Partition {
+                index,
+                first_lba,
+                last_lba,
+            });
+        }
+        None
+    }
+
+    /// First partition named `name` (ASCII, compared exactly).
+    pub fn find_by_name(&self, src: &dyn ImageSource, name: &str) -> Option<Partition> {
+        let want = name.as_bytes();
+        if want.len() > NAME_UNITS {
+            return None;
+        }
+        self.find(src, |entry| {
+            let unit = |i: usize| Le16(ENTRY_NAME + 2 * i).get(entry);
+            want.iter().enumerate().all(|(i, &c)| unit(i) == Ok(c as u16))
+                && (want.len() == NAME_UNITS || unit(want.len()) == Ok(0))
+        })
+    }
+
+    /// First partition of type `type_guid` (on-disk byte order, see
+    /// `guid()`).
+    #[allow(dead_code)]
+    pub fn find_by_type(&self, src: &dyn ImageSource, type_guid: &[u8; 16]) -> Option<Partition> {
+        self.find(src, |entry| ENTRY_TYPE.get(entry) == Ok(*type_guid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rng;
+
+    const LAST_LBA: u64 = 63;
+    const ENTRIES_LBA: u64 = 2;
+    const NUM_ENTRIES: u32 = 128;
+
+    // A disk image; reads past its end are all-ones, as a failed disk
+    // read is.
+    struct Disk(Vec<u8>);
+
+    impl ImageSource for Disk {
+        fn read_slice(&self, offset: usize, buf: &mut [u8]) {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = offset.checked_add(i).and_then(|at| self.0.get(at)).copied().unwrap_or(0xff);
+            }
+        }
+    }
+
+    fn put(disk: &mut [u8], at: usize, bytes: &[u8]) {
+        disk[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // Recompute the CRCs of the header at `lba` (the entry array's first).
+    fn seal(disk: &mut [u8], lba: u64) {
+        let hdr = lba as usize * SECTOR_SIZE;
+        let entries = ENTRIES_LBA as usize * SECTOR_SIZE;
+        let len = NUM_ENTRIES as usize * ENTRY_MIN_SIZE;
+        put(disk, hdr + HDR_ENTRIES_CRC32.0, &crc32(&disk[entries..entries + len]).to_le_bytes());
+        put(disk, hdr + HDR_CRC32.0, &[0; 4]);
+        let crc = crc32(&disk[hdr..hdr + HEADER_MIN_SIZE]);
+        put(disk, hdr + HDR_CRC32.0, &crc.to_le_bytes());
+    }
+
+    // Both headers, sharing an array with the three partitions of ours.
+    fn disk() -> Vec<u8> {
+        let mut disk = vec![0; (LAST_LBA as usize + 1) * SECTOR_SIZE];
+        for (lba, alternate) in [(1, LAST_LBA), (LAST_LBA, 1)] {
+            let hdr = lba as usize * SECTOR_SIZE;
+            put(&mut disk, hdr, SIGNATURE);
+            put(&mut disk, hdr + 0x08, &0x0001_0000u32.to_le_bytes());
+            put(&mut disk, hdr + HDR_SIZE.0, &(HEADER_MIN_SIZE as u32).to_le_bytes());
+            put(&mut disk, hdr + HDR_MY_LBA.0, &lba.to_le_bytes());
+            put(&mut disk, hdr + 0x20, &alternate.to_le_bytes());
+            put(&mut disk, hdr + HDR_ENTRIES_LBA.0, &ENTRIES_LBA.to_le_bytes());
+            put(&mut disk, hdr + HDR_NUM_ENTRIES.0, &NUM_ENTRIES.to_le_bytes());
+            put(&mut disk, hdr + HDR_ENTRY_SIZE.0, &(ENTRY_MIN_SIZE as u32).to_le_bytes());
+        }
+        for (i, (name, first, last)) in [(BANK_A_NAME, 40, 47), (BANK_B_NAME, 48, 55), (BOOTMETA_NAME, 56, 56)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = ENTRIES_LBA as usize * SECTOR_SIZE + i * ENTRY_MIN_SIZE;
+            put(&mut disk, entry + ENTRY_TYPE.0, &[0xA5; 16]);
+            put(&mut disk, entry + ENTRY_FIRST_LBA.0, &(first as u64).to_le_bytes());
+            put(&mut disk, entry + ENTRY_LAST_LBA.0, &(last as u64).to_le_bytes());
+            for (j, c) in name.bytes().enumerate() {
+                put(&mut disk, entry + ENTRY_NAME + 2 * j, &(c as u16).to_le_bytes());
+            }
+        }
+        seal(&mut disk, 1);
+        seal(&mut disk, LAST_LBA);
+        disk
+    }
+
+    // Read it all as the SPL does: whatever the disk holds, an error or
+    // partitions on the disk, never a panic.
+    fn read_all(disk: &Disk) -> Result<Gpt, GptError> {
+        let gpt = Gpt::read(disk, LAST_LBA)?;
+        for name in [BANK_A_NAME, BANK_B_NAME, BOOTMETA_NAME, "", "bank_a_but_longer_than_36_units_of_name"] {
+            if let Some(p) = gpt.find_by_name(disk, name) {
+                assert!(p.first_lba <= p.last_lba && p.index < gpt.num_entries);
+            }
+        }
+        gpt.find_by_type(disk, &[0xA5; 16]);
+        Ok(gpt)
+    }
+
+    #[test]
+    fn valid() {
+        let disk = Disk(disk());
+        let gpt = read_all(&disk).unwrap();
+        assert!(!gpt.backup);
+        let meta = gpt.find_by_name(&disk, BOOTMETA_NAME).unwrap();
+        assert_eq!((meta.index, meta.offset(), meta.size()), (2, 56 * SECTOR_SIZE, SECTOR_SIZE));
+        assert_eq!(gpt.find_by_name(&disk, "bank"), None);
+    }
+
+    // A disk cut short anywhere: the backup header is gone first, then
+    // the array, then the primary header.
+    #[test]
+    fn truncated() {
+        let full = disk();
+        for len in (0..full.len()).step_by(61).chain([SECTOR_SIZE * 2 - 1, full.len() - 1]) {
+            let disk = Disk(full[..len].to_vec());
+            let result = read_all(&disk);
+            let array_end = (ENTRIES_LBA as usize * SECTOR_SIZE) + NUM_ENTRIES as usize * ENTRY_MIN_SIZE;
+            assert_eq!(result.is_ok(), len >= array_end, "disk of {} bytes: {:?}", len, result);
+        }
+    }
+
+    // Random header fields and entries, the CRCs made right again so the
+    // checks past them see the garbage too.
+    #[test]
+    fn garbage_fields() {
+        let mut rng = Rng(3);
+        let good = disk();
+        for _ in 0..3000 {
+            let mut disk = good.clone();
+            for _ in 0..1 + rng.below(4) {
+                let at = match rng.below(3) {
+                    0 => SECTOR_SIZE + rng.below(HEADER_MIN_SIZE as u64) as usize,
+                    1 => ENTRIES_LBA as usize * SECTOR_SIZE + rng.below(3 * ENTRY_MIN_SIZE as u64) as usize,
+                    _ => LAST_LBA as usize * SECTOR_SIZE + rng.below(HEADER_MIN_SIZE as u64) as usize,
+                };
+                disk[at] = rng.below(256) as u8;
+            }
+            if rng.below(4) != 0 {
+                seal(&mut disk, 1);
+                seal(&mut disk, LAST_LBA);
+            }
+            let _ = read_all(&Disk(disk));
+        }
+    }
+}
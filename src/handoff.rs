@@ -0,0 +1,116 @@
+use crate::bootmeta::MAX_BANKS;
+use crate::crc32::crc32;
+use crate::hash::SHA256_LEN;
+use crate::{board, clint, logger};
+
+// What a payload gets told about its boot, for payloads not written in
+// Rust (a C bare-metal app, a vendor loader) that can't use spl2.rs's
+// block. A stable ABI: fields are only ever added at the end, with a new
+// version, and `size` says how much is there. Written last thing before
+// the jump, at a fixed address per board, board::HANDOFF_ADDR (just
+// under the postcode, kept off the stack by linker.ld), and passed in a2
+// too. Little-endian:
+//
+//   0x00  magic          b"SPLH"
+//   0x04  version        1
+//   0x08  size           0x80
+//   0x0c  slot           bank index, or bootlog::SLOT_GOLDEN / SLOT_RAM
+//   0x10  attempts       unconfirmed attempts of the slot, this one included
+//   0x14  banks          entries of bank_attempts in use
+//   0x18  bank_attempts  u32 per bank (8): unconfirmed attempts, by index
+//   0x38  dtb            u64, the DTB (as in a1)
+//   0x40  log_ring       u64 address, u64 size: the console log ring
+//   0x50  boot_us        u64, time since reset at the handoff
+//   0x58  digest         [32], payload sha256
+//   0x78  crc            CRC-32 of everything above
+//   0x7c  reserved       zero
+//
+// The matching C declaration is in the README. With SPL2, a2 holds
+// SPL2's own block instead; this one is written all the same.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SPLH");
+const VERSION: u32 = 1;
+// bank_attempts entries: part of the ABI, whatever MAX_BANKS becomes.
+const BANKS: usize = 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SplHandoffV1 {
+    magic: u32,
+    version: u32,
+    size: u32,
+    pub slot: u32,
+    pub attempts: u32,
+    banks: u32,
+    bank_attempts: [u32; BANKS],
+    dtb: u64,
+    log_ring: [u64; 2],
+    boot_us: u64,
+    digest: [u8; SHA256_LEN],
+    crc: u32,
+    reserved: u32,
+}
+
+const _: () = {
+    use core::mem::{offset_of, size_of};
+    assert!(size_of::<SplHandoffV1>() == 0x80);
+    assert!(size_of::<SplHandoffV1>() <= board::POSTCODE_ADDR - board::HANDOFF_ADDR);
+    assert!(board::HANDOFF_ADDR.is_multiple_of(8));
+    assert!(MAX_BANKS <= BANKS);
+    assert!(offset_of!(SplHandoffV1, slot) == 0x0c);
+    assert!(offset_of!(SplHandoffV1, bank_attempts) == 0x18);
+    assert!(offset_of!(SplHandoffV1, dtb) == 0x38);
+    assert!(offset_of!(SplHandoffV1, log_ring) == 0x40);
+    assert!(offset_of!(SplHandoffV1, boot_us) == 0x50);
+    assert!(offset_of!(SplHandoffV1, digest) == 0x58);
+    assert!(offset_of!(SplHandoffV1, crc) == 0x78);
+};
+
+impl SplHandoffV1 {
+    /// A block for a payload booted from `slot` (see bootlog::SLOT_*),
+    /// with the log ring filled in and no attempts counted.
+    pub fn new(slot: u32, dtb: usize, digest: [u8; SHA256_LEN]) -> Self {
+        let (ring, ring_size) = logger::ringbuf::region();
+        SplHandoffV1 {
+            magic: MAGIC,
+            version: VERSION,
+            size: core::mem::size_of::<SplHandoffV1>() as u32,
+            slot,
+            attempts: 0,
+            banks: 0,
+            bank_attempts: [0; BANKS],
+            dtb: dtb as u64,
+            log_ring: [ring as u64, ring_size as u64],
+            boot_us: 0,
+            digest,
+            crc: 0,
+            reserved: 0,
+        }
+    }
+
+    /// Record `attempts` for the bank of index `bank`.
+    pub fn set_bank_attempts(&mut self, bank: usize, attempts: u32) {
+        self.bank_attempts[bank] = attempts;
+        self.banks = self.banks.max(bank as u32 + 1);
+    }
+
+    fn crc_of(&self) -> u32 {
+        let p = self as *const SplHandoffV1 as *const u8;
+        crc32(unsafe { core::slice::from_raw_parts(p, core::mem::offset_of!(SplHandoffV1, crc)) })
+    }
+}
+
+/// Stamp the time and CRC on `handoff` and store it at
+/// board::HANDOFF_ADDR; return that address (for a2).
+pub fn publish(handoff: &SplHandoffV1) -> usize {
+    let mut h = *handoff;
+    h.boot_us = clint::now_us();
+    h.crc = h.crc_of();
+    unsafe { core::ptr::write_volatile(board::HANDOFF_ADDR as *mut SplHandoffV1, h) };
+    board::HANDOFF_ADDR
+}
+
+/// Address and size of the block.
+pub fn region() -> (usize, usize) {
+    (board::HANDOFF_ADDR, core::mem::size_of::<SplHandoffV1>())
+}
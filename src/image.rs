@@ -0,0 +1,973 @@
+use core::result::Result;
+
+use crate::addr::PhysAddr;
+use crate::arch::barrier;
+use crate::arch::csr::PrivMode;
+use crate::bytes::Truncated;
+use crate::clint;
+use crate::crc32::crc32;
+use crate::digest::{Algorithm, Digest, Output, Pair};
+use crate::hash::SHA256_LEN;
+use crate::inflate::{gunzip, InflateError};
+use crate::memtest::MemFault;
+use crate::uimage::{UImageError, UImageHeader};
+use crate::verify_policy::{Check, VerifyPolicy};
+use crate::watchdog;
+
+/// Size of the Ed25519 signature field.
+pub const SIGNATURE_LEN: usize = 64;
+use crate::slog_debug;
+
+// Chunk size used when streaming flash through a hasher.
+const STREAM_CHUNK: usize = 256;
+
+/// Somewhere bank images are read from (NOR flash, a disk), addressed
+/// in bytes from its start.
+pub trait ImageSource {
+    /// Read `buf.len()` bytes starting at `offset`. A source that can
+    /// fail (a disk) logs the error and returns all-ones, like erased
+    /// flash, so every check on the data fails.
+    fn read_slice(&self, offset: usize, buf: &mut [u8]);
+
+    /// Copy `len` bytes at `offset` straight to `dest`, for loading a
+    /// payload without a bounce buffer.
+    ///
+    /// # Safety
+    /// `dest` must be valid for `len` bytes of writes.
+    unsafe fn copy_to_ram(&self, offset: usize, dest: *mut u8, len: usize) {
+        self.read_slice(offset, unsafe { core::slice::from_raw_parts_mut(dest, len) })
+    }
+
+    /// Read a little-endian u32.
+    fn read_u32_le(&self, offset: usize) -> u32 {
+        let mut tmp = [0u8; 4];
+        self.read_slice(offset, &mut tmp);
+        u32::from_le_bytes(tmp)
+    }
+
+    /// Address to label `offset` with in logs: the CPU address if the
+    /// source is memory-mapped, else the offset itself.
+    fn log_addr(&self, offset: usize) -> usize {
+        offset
+    }
+}
+
+/// An image already in RAM (put there by a debugger or the machine's
+/// loader), read in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemSource {
+    pub base: usize,
+    pub size: usize,
+}
+
+impl ImageSource for MemSource {
+    fn read_slice(&self, offset: usize, buf: &mut [u8]) {
+        // Callers stay within a bank, which is `size` here.
+        unsafe {
+            core::ptr::copy_nonoverlapping((self.base + offset) as *const u8, buf.as_mut_ptr(), buf.len())
+        }
+    }
+
+    fn log_addr(&self, offset: usize) -> usize {
+        self.base + offset
+    }
+}
+
+/// Feed `len` bytes of flash at `offset` to `digest`, streamed through a
+/// small stack buffer instead of a full RAM copy: each byte is read
+/// once, however many digests `digest` stands for (see digest::Pair).
+pub fn stream(flash: &dyn ImageSource, offset: usize, len: usize, digest: &mut dyn Digest) {
+    let mut buf = [0u8; STREAM_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let n = core::cmp::min(STREAM_CHUNK, len - done);
+        flash.read_slice(offset + done, &mut buf[..n]);
+        digest.update(&buf[..n]);
+        done += n;
+    }
+}
+
+/// The `alg` digest of `len` bytes of flash at `offset`.
+pub fn digest_of(flash: &dyn ImageSource, offset: usize, len: usize, alg: Algorithm) -> Output {
+    let mut digest = alg.hasher();
+    stream(flash, offset, len, &mut digest);
+    digest.finalize()
+}
+
+/// CRC-32 of `len` bytes of flash at `offset`.
+pub fn flash_crc32(flash: &dyn ImageSource, offset: usize, len: usize) -> u32 {
+    digest_of(flash, offset, len, Algorithm::Crc32).to_u32()
+}
+
+/// A payload digest as a header states it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expected {
+    pub alg: Algorithm,
+    pub value: Output,
+}
+
+impl Expected {
+    pub fn crc32(crc: u32) -> Self {
+        Expected {
+            alg: Algorithm::Crc32,
+            value: Output::from_crc32(crc),
+        }
+    }
+
+    pub fn sha256(digest: &[u8; SHA256_LEN]) -> Self {
+        Expected {
+            alg: Algorithm::Sha256,
+            value: Output::new(digest),
+        }
+    }
+
+    /// Check `len` bytes of flash at `offset` against it, in one pass.
+    /// The error is the digest they have instead.
+    pub fn check(&self, flash: &dyn ImageSource, offset: usize, len: usize) -> Result<(), Output> {
+        let actual = digest_of(flash, offset, len, self.alg);
+        if actual == self.value { Ok(()) } else { Err(actual) }
+    }
+}
+
+/// The `a` and `b` digests of `len` bytes of flash at `offset`, from a
+/// single read of them.
+pub fn digest_pair(
+    flash: &dyn ImageSource,
+    offset: usize,
+    len: usize,
+    a: Algorithm,
+    b: Algorithm,
+) -> (Output, Output) {
+    let mut pair = Pair(a.hasher(), b.hasher());
+    stream(flash, offset, len, &mut pair);
+    (pair.0.finalize(), pair.1.finalize())
+}
+
+/// Whether `needle` appears in the first `len` bytes of `src`, e.g. a
+/// firmware's banner string.
+pub fn contains_bytes(src: &dyn ImageSource, len: usize, needle: &[u8]) -> bool {
+    let mut buf = [0u8; STREAM_CHUNK];
+    let keep = needle.len() - 1;
+    let mut done = 0;
+    // Each chunk starts with the tail of the previous one, so a match
+    // across chunks is still found.
+    let mut carried = 0;
+    while done < len {
+        let n = core::cmp::min(STREAM_CHUNK - carried, len - done);
+        src.read_slice(done, &mut buf[carried..carried + n]);
+        let filled = carried + n;
+        if buf[..filled].windows(needle.len()).any(|w| w == needle) {
+            return true;
+        }
+        carried = keep.min(filled);
+        buf.copy_within(filled - carried..filled, 0);
+        done += n;
+    }
+    false
+}
+
+/// Refuse a load region [load_addr, load_addr + size) that wraps around
+/// or hits one of the `forbidden` ranges.
+pub fn check_load_region(
+    load_addr: PhysAddr,
+    size: usize,
+    forbidden: &[Forbidden],
+) -> Result<(), ImageError> {
+    let overlap = |region| ImageError::LoadOverlap {
+        load: load_addr,
+        size,
+        region,
+    };
+    let load_end = load_addr
+        .checked_add(size)
+        .ok_or(overlap("end of address space"))?;
+    if let Some(f) = forbidden
+        .iter()
+        .find(|f| load_addr < f.end && f.start < load_end)
+    {
+        return Err(overlap(f.name));
+    }
+    Ok(())
+}
+
+/// Native SPL image header, found at the start of each bank.
+///
+/// Layout (little-endian, header slot is `hdr_size` bytes, payload follows):
+///
+/// ```text
+///   0x00  u32  magic         b"SPL1"
+///   0x04  u16  version       1
+///   0x06  u16  hdr_size      256 (payload starts at bank + hdr_size)
+///   0x08  u32  payload_size  bytes stored in flash
+///   0x0c  u32  flags         FLAG_* bits
+///   0x10  u64  load_addr     RAM address the payload is copied to
+///   0x18  u32  entry_offset  entry = load_addr + entry_offset
+///   0x1c  u32  payload_crc   CRC-32 (zlib) of the payload as loaded in RAM
+///   0x20  [32] sha256        SHA-256 of the stored payload (if FLAG_SHA256)
+///   0x40  [64] signature     Ed25519 signature of the stored payload (if FLAG_SIGNED)
+///   0x80  u32  load_size     inflated size (if FLAG_GZIP, else payload_size)
+///   0x84  ...  reserved, zero up to hdr_size
+/// ```
+///
+/// With FLAG_SMODE the payload is entered in S-mode (an SBI-less kernel
+/// or test payload), otherwise in M-mode like OpenSBI. With
+/// FLAG_RELEASE_HARTS the parked secondary harts enter it too, in the
+/// same mode, instead of staying parked for the payload to wake.
+/// FLAG_META_REWRITE is for an SPL2 image only: it can finish a boot
+/// log compaction SPL1 hands it (see spl2.rs).
+///
+/// With FLAG_GZIP the stored payload is a gzip member, inflated straight
+/// to load_addr. The digest and signature cover the stored (compressed)
+/// bytes so they are checked before anything is inflated.
+///
+/// Wrapping an OpenSBI build for bank A:
+///
+/// ```text
+/// riscv64-unknown-elf-objcopy -O binary fw_jump.elf fw_jump.bin
+/// python3 -c 'import hashlib,struct,sys,zlib; p=open(sys.argv[1],"rb").read(); \
+///   sys.stdout.buffer.write(struct.pack("<4sHHIIQII32s", b"SPL1", 1, 256, \
+///   len(p), 1, 0x80200000, 0, zlib.crc32(p), hashlib.sha256(p).digest()) \
+///   .ljust(256, b"\0") + p)' fw_jump.bin > bank_a.img
+/// ```
+///
+/// A signed image additionally sets FLAG_SIGNED and stores the 64-byte
+/// Ed25519 signature of the payload at 0x40. A compressed image
+/// additionally sets FLAG_GZIP (4), stores `gzip.compress(p)` as the
+/// payload and `len(p)` at 0x80; the CRC stays that of `p`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplImageHeader {
+    pub version: u16,
+    pub hdr_size: u16,
+    pub payload_size: u32,
+    pub flags: u32,
+    pub load_addr: PhysAddr,
+    pub entry_offset: u32,
+    pub payload_crc: u32,
+    pub load_size: u32,
+    pub sha256: [u8; SHA256_LEN],
+    pub signature: [u8; SIGNATURE_LEN],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    BadMagic(u32),
+    UnsupportedVersion(u16),
+    BadHeaderSize(u16),
+    BadPayloadSize(u32),
+    BadEntry(u32),
+    /// Load region [load, load + size) hits a region we must not clobber.
+    LoadOverlap {
+        load: PhysAddr,
+        size: usize,
+        region: &'static str,
+    },
+    CrcMismatch { expected: u32, actual: u32 },
+    /// The compressed payload doesn't inflate cleanly.
+    Inflate(InflateError),
+    /// Inflated size differs from load_size.
+    SizeMismatch { expected: u32, actual: u32 },
+    /// Legacy U-Boot image rejected.
+    UImage(UImageError),
+    /// Payload SHA-256 differs from the one in the header.
+    DigestMismatch,
+    /// Secure boot is on and the header carries no signature.
+    SignatureMissing,
+    /// The signature doesn't verify against the embedded public key.
+    #[cfg_attr(not(feature = "secure"), allow(dead_code))]
+    SignatureInvalid,
+    /// Raw bank whose CRC trailer is still erased: nothing was written.
+    Erased,
+    /// The slot has no location in the flash layout.
+    NotConfigured,
+    /// RAM under the load region failed the memory test.
+    MemTest(MemFault),
+    /// The yield hook stopped the memory test or the copy (see
+    /// watchdog::yield_point()).
+    Cancelled,
+    /// An S-mode payload, but PMP isn't set up: its first access would
+    /// fault.
+    SmodeWithoutPmp,
+    /// A payload found in RAM that isn't at its load address (or is
+    /// compressed), so it can't be entered where it is.
+    NotInPlace { load: PhysAddr, at: PhysAddr },
+    /// An SPL2 that wants to run below M-mode: it must be able to enter
+    /// the payload in any mode.
+    Spl2NotMachine(PrivMode),
+    /// A header field past the end of what was read.
+    Truncated(Truncated),
+}
+
+impl From<Truncated> for ImageError {
+    fn from(e: Truncated) -> Self {
+        ImageError::Truncated(e)
+    }
+}
+
+// SplImageHeader fields, as laid out above.
+mod spl1 {
+    use crate::bytes::{Bytes, Le16, Le32, Le64};
+    use crate::hash::SHA256_LEN;
+
+    pub const MAGIC: Le32 = Le32(0x00);
+    pub const VERSION: Le16 = Le16(0x04);
+    pub const HDR_SIZE: Le16 = Le16(0x06);
+    pub const PAYLOAD_SIZE: Le32 = Le32(0x08);
+    pub const FLAGS: Le32 = Le32(0x0c);
+    pub const LOAD_ADDR: Le64 = Le64(0x10);
+    pub const ENTRY_OFFSET: Le32 = Le32(0x18);
+    pub const PAYLOAD_CRC: Le32 = Le32(0x1c);
+    pub const SHA256: Bytes<SHA256_LEN> = Bytes(0x20);
+    pub const SIGNATURE: Bytes<{ super::SIGNATURE_LEN }> = Bytes(0x40);
+    pub const LOAD_SIZE: Le32 = Le32(0x80);
+}
+
+/// A memory range the payload must not be loaded over, [start, end).
+#[derive(Debug, Clone, Copy)]
+pub struct Forbidden {
+    pub name: &'static str,
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+}
+
+impl Forbidden {
+    /// [start, start + size).
+    pub fn new(name: &'static str, start: PhysAddr, size: usize) -> Self {
+        Forbidden {
+            name,
+            start,
+            end: start + size,
+        }
+    }
+}
+
+impl SplImageHeader {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"SPL1");
+    pub const VERSION: u16 = 1;
+    /// Bytes of the header slot actually defined by version 1.
+    pub const V1_SIZE: usize = 0x84;
+
+    /// `sha256` holds the payload digest and must be checked.
+    pub const FLAG_SHA256: u32 = 1 << 0;
+    /// `signature` holds an Ed25519 signature of the payload.
+    pub const FLAG_SIGNED: u32 = 1 << 1;
+    /// The payload is gzip-compressed, `load_size` is its inflated size.
+    pub const FLAG_GZIP: u32 = 1 << 2;
+    /// Enter the payload in S-mode.
+    pub const FLAG_SMODE: u32 = 1 << 3;
+    /// Release the parked harts into the payload along with the boot hart.
+    pub const FLAG_RELEASE_HARTS: u32 = 1 << 4;
+    /// SPL2 only: it finishes a boot log compaction handed to it.
+    pub const FLAG_META_REWRITE: u32 = 1 << 5;
+
+    /// Read and validate the header of the bank at `bank_offset`.
+    pub fn parse(
+        flash: &dyn ImageSource,
+        bank_offset: usize,
+        bank_size: usize,
+        forbidden: &[Forbidden],
+    ) -> Result<Self, ImageError> {
+        let mut raw = [0u8; Self::V1_SIZE];
+        flash.read_slice(bank_offset, &mut raw);
+        let hdr = Self::decode(&raw)?;
+
+        if hdr.version != Self::VERSION {
+            return Err(ImageError::UnsupportedVersion(hdr.version));
+        }
+        if (hdr.hdr_size as usize) < Self::V1_SIZE || hdr.hdr_size as usize > bank_size {
+            return Err(ImageError::BadHeaderSize(hdr.hdr_size));
+        }
+        let size = hdr.payload_size as usize;
+        if size == 0 || size > bank_size - hdr.hdr_size as usize {
+            return Err(ImageError::BadPayloadSize(hdr.payload_size));
+        }
+        if hdr.load_size == 0 {
+            return Err(ImageError::BadPayloadSize(hdr.load_size));
+        }
+        if hdr.entry_offset >= hdr.load_size {
+            return Err(ImageError::BadEntry(hdr.entry_offset));
+        }
+        check_load_region(hdr.load_addr, hdr.load_size as usize, forbidden)?;
+
+        Ok(hdr)
+    }
+
+    // The fields of the header in `raw`, checking only its magic.
+    fn decode(raw: &[u8]) -> Result<Self, ImageError> {
+        let magic = spl1::MAGIC.get(raw)?;
+        if magic != Self::MAGIC {
+            return Err(ImageError::BadMagic(magic));
+        }
+        let flags = spl1::FLAGS.get(raw)?;
+        let payload_size = spl1::PAYLOAD_SIZE.get(raw)?;
+        Ok(SplImageHeader {
+            version: spl1::VERSION.get(raw)?,
+            hdr_size: spl1::HDR_SIZE.get(raw)?,
+            payload_size,
+            flags,
+            load_addr: PhysAddr::new(spl1::LOAD_ADDR.get(raw)? as usize),
+            entry_offset: spl1::ENTRY_OFFSET.get(raw)?,
+            payload_crc: spl1::PAYLOAD_CRC.get(raw)?,
+            load_size: if flags & Self::FLAG_GZIP != 0 {
+                spl1::LOAD_SIZE.get(raw)?
+            } else {
+                payload_size
+            },
+            sha256: spl1::SHA256.get(raw)?,
+            signature: spl1::SIGNATURE.get(raw)?,
+        })
+    }
+
+    /// Flash offset of the payload for a bank at `bank_offset`.
+    pub fn payload_offset(&self, bank_offset: usize) -> usize {
+        bank_offset + self.hdr_size as usize
+    }
+
+    /// The strongest digest the header vouches for its payload as
+    /// stored with: its sha256 if it has one, else its CRC, which only
+    /// covers the stored payload uncompressed (None for a gzip one).
+    pub fn stored_digest(&self) -> Option<Expected> {
+        match self.expected_sha256() {
+            Some(sha256) => Some(Expected::sha256(sha256)),
+            None if !self.is_compressed() => Some(Expected::crc32(self.payload_crc)),
+            None => None,
+        }
+    }
+
+    /// Expected payload digest, if the header carries one.
+    pub fn expected_sha256(&self) -> Option<&[u8; SHA256_LEN]> {
+        if self.flags & Self::FLAG_SHA256 != 0 {
+            Some(&self.sha256)
+        } else {
+            None
+        }
+    }
+
+    /// Payload signature, if the header carries one.
+    pub fn signature(&self) -> Option<&[u8; SIGNATURE_LEN]> {
+        if self.flags & Self::FLAG_SIGNED != 0 {
+            Some(&self.signature)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & Self::FLAG_GZIP != 0
+    }
+
+    pub fn entry(&self) -> usize {
+        (self.load_addr + self.entry_offset as usize).get()
+    }
+
+    /// Privilege level the payload wants to start in.
+    pub fn entry_mode(&self) -> PrivMode {
+        if self.flags & Self::FLAG_SMODE != 0 {
+            PrivMode::Supervisor
+        } else {
+            PrivMode::Machine
+        }
+    }
+
+    /// What `LoadableImage::load()` has to do for the bank at
+    /// `bank_offset`.
+    pub fn loadable(&self, bank_offset: usize) -> LoadableImage {
+        LoadableImage {
+            payload_offset: self.payload_offset(bank_offset),
+            payload_size: self.payload_size as usize,
+            load_addr: self.load_addr,
+            load_size: self.load_size as usize,
+            entry: self.entry(),
+            mode: self.entry_mode(),
+            release_harts: self.flags & Self::FLAG_RELEASE_HARTS != 0,
+            meta_rewrite: self.flags & Self::FLAG_META_REWRITE != 0,
+            compressed: self.is_compressed(),
+            loaded_crc: Some(self.payload_crc),
+        }
+    }
+}
+
+/// A validated image, whatever header it came with: where the payload
+/// sits in flash, where it goes in RAM and how to check it once there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadableImage {
+    pub payload_offset: usize,
+    pub payload_size: usize,
+    pub load_addr: PhysAddr,
+    /// Bytes written at load_addr (the inflated size if compressed).
+    pub load_size: usize,
+    pub entry: usize,
+    /// Privilege level to enter it in.
+    pub mode: PrivMode,
+    /// Enter it on every parked hart too, not just the boot hart.
+    pub release_harts: bool,
+    /// An SPL2 that finishes a boot log compaction handed to it.
+    pub meta_rewrite: bool,
+    /// Payload is a gzip member.
+    pub compressed: bool,
+    /// CRC-32 expected over the loaded bytes, if the format has one.
+    pub loaded_crc: Option<u32>,
+}
+
+impl LoadableImage {
+    /// Copy or inflate the payload to its load address and check it
+    /// there. Returns the entry point.
+    ///
+    /// # Safety
+    /// [load_addr, load_addr + load_size) must have been checked against
+    /// everything the SPL still needs (see `check_load_region()`).
+    pub unsafe fn load(&self, flash: &dyn ImageSource) -> Result<usize, ImageError> {
+        let dest =
+            unsafe { core::slice::from_raw_parts_mut(self.load_addr.as_mut_ptr(), self.load_size) };
+        if self.compressed {
+            let written = gunzip(flash, self.payload_offset, self.payload_size, dest)
+                .map_err(ImageError::Inflate)?;
+            slog_debug!("inflated {} -> {} bytes", self.payload_size, written);
+            if written != dest.len() {
+                return Err(ImageError::SizeMismatch {
+                    expected: self.load_size as u32,
+                    actual: written as u32,
+                });
+            }
+        } else {
+            let start = clint::mtime();
+            unsafe { flash.copy_to_ram(self.payload_offset, dest.as_mut_ptr(), dest.len()) };
+            let us = clint::ticks_to_us(clint::mtime() - start).max(1);
+            slog_debug!(
+                "copied {} bytes in {} us ({} KiB/s)",
+                dest.len(),
+                us,
+                dest.len() as u64 * 1_000_000 / 1024 / us
+            );
+        }
+
+        // The payload is code we are about to jump into.
+        barrier::sync_icache_for_region(self.load_addr.get(), self.load_size);
+
+        match self.loaded_crc {
+            Some(_) => self.check_crc(crc32(dest)).map(|()| self.entry),
+            None => Ok(self.entry),
+        }
+    }
+
+    /// Copy the payload (not compressed) to its load address in a single
+    /// pass over the flash: CHUNK bytes at a time into a stack buffer,
+    /// fed to `digest`, then written out, with a yield point before each
+    /// (see watchdog.rs). Nothing is checked: the caller checks the
+    /// digest, then calls enter() or scrub(), which a Cancelled copy
+    /// needs too.
+    ///
+    /// # Safety
+    /// Same as `load()`.
+    pub unsafe fn copy_hashed<const CHUNK: usize>(
+        &self,
+        flash: &dyn ImageSource,
+        digest: &mut dyn Digest,
+    ) -> Result<(), ImageError> {
+        let dest = self.load_addr.as_mut_ptr::<u8>();
+        let mut buf = [0u8; CHUNK];
+        let mut done = 0;
+        while done < self.payload_size {
+            watchdog::yield_point("load", done, self.payload_size).map_err(|_| ImageError::Cancelled)?;
+            let n = core::cmp::min(CHUNK, self.payload_size - done);
+            flash.read_slice(self.payload_offset + done, &mut buf[..n]);
+            digest.update(&buf[..n]);
+            unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), dest.add(done), n) };
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Check `actual`, the CRC-32 of the loaded bytes, against the one
+    /// the format carries, if any.
+    pub fn check_crc(&self, actual: u32) -> Result<(), ImageError> {
+        let Some(expected) = self.loaded_crc else {
+            return Ok(());
+        };
+        slog_debug!("payload crc: expected=0x{:08x} actual=0x{:08x}", expected, actual);
+        if actual != expected {
+            return Err(ImageError::CrcMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// Once copy_hashed()'s payload passed its checks: make it fetchable.
+    /// Returns the entry point.
+    pub fn enter(&self) -> usize {
+        barrier::sync_icache_for_region(self.load_addr.get(), self.load_size);
+        self.entry
+    }
+
+    /// Zero the load region, for a payload refused once in RAM: a later
+    /// bug can't run what was rejected.
+    ///
+    /// # Safety
+    /// Same as `load()`.
+    pub unsafe fn scrub(&self) {
+        unsafe { core::ptr::write_bytes(self.load_addr.as_mut_ptr::<u8>(), 0, self.load_size) };
+    }
+}
+
+/// Header-less bank: the payload is the whole bank minus its last 4
+/// bytes, which hold the little-endian CRC-32 of everything before them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawImage {
+    pub size: usize,
+    pub crc: u32,
+}
+
+impl RawImage {
+    const TRAILER_SIZE: usize = 4;
+
+    /// Check the trailer CRC of the raw bank at `bank_offset`, straight
+    /// out of flash (nothing is copied yet), as far as `policy` says.
+    pub fn probe(
+        flash: &dyn ImageSource,
+        bank_offset: usize,
+        bank_size: usize,
+        policy: &VerifyPolicy,
+    ) -> Result<Self, ImageError> {
+        let size = bank_size - Self::TRAILER_SIZE;
+        let expected = flash.read_u32_le(bank_offset + size);
+        if expected == 0xFFFF_FFFF {
+            return Err(ImageError::Erased);
+        }
+        if policy.runs(Check::Crc)
+            && let Err(actual) = Expected::crc32(expected).check(flash, bank_offset, size)
+        {
+            let actual = actual.to_u32();
+            policy.decide(Check::Crc, Err(ImageError::CrcMismatch { expected, actual }))?;
+        }
+        Ok(RawImage { size, crc: expected })
+    }
+
+    /// What `LoadableImage::load()` has to do to copy the (already
+    /// verified) payload to `load_addr` and enter it there.
+    pub fn loadable(&self, bank_offset: usize, load_addr: PhysAddr) -> LoadableImage {
+        LoadableImage {
+            payload_offset: bank_offset,
+            payload_size: self.size,
+            load_addr,
+            load_size: self.size,
+            entry: load_addr.get(),
+            mode: PrivMode::Machine,
+            release_harts: false,
+            meta_rewrite: false,
+            compressed: false,
+            // probe() checked the trailer in flash.
+            loaded_crc: None,
+        }
+    }
+}
+
+/// The formats a bank may hold, with their parsed header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Spl(SplImageHeader),
+    UImage(UImageHeader),
+    Raw(RawImage),
+}
+
+/// A bank image that passed every check possible before loading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub format: ImageFormat,
+    pub load: LoadableImage,
+}
+
+impl Image {
+    /// Find out what the bank at `bank_offset` holds, trying our own
+    /// header, then a uImage, then a raw payload with a CRC trailer. A
+    /// raw payload is loaded at `raw_load_addr`. Load regions are checked
+    /// against `forbidden`, the raw fallback and the CRCs checked in
+    /// flash as `policy` says.
+    ///
+    /// Errors are those of the last format tried that could apply: a bad
+    /// SPL1 or uImage header is reported as such, not as a raw CRC error.
+    pub fn probe(
+        flash: &dyn ImageSource,
+        bank_offset: usize,
+        bank_size: usize,
+        raw_load_addr: PhysAddr,
+        forbidden: &[Forbidden],
+        policy: &VerifyPolicy,
+    ) -> Result<Self, ImageError> {
+        let magic = match SplImageHeader::parse(flash, bank_offset, bank_size, forbidden) {
+            Ok(hdr) => {
+                return Ok(Image {
+                    format: ImageFormat::Spl(hdr),
+                    load: hdr.loadable(bank_offset),
+                });
+            }
+            Err(ImageError::BadMagic(magic)) => magic,
+            Err(e) => return Err(e),
+        };
+        if UImageHeader::probe(flash, bank_offset) {
+            let hdr = UImageHeader::parse(flash, bank_offset, bank_size, forbidden, policy)?;
+            return Ok(Image {
+                format: ImageFormat::UImage(hdr),
+                load: hdr.loadable(flash, bank_offset),
+            });
+        }
+        slog_debug!("no SPL1 header (magic 0x{:08x}), trying raw + CRC trailer", magic);
+        policy.decide(Check::Magic, Err(ImageError::BadMagic(magic)))?;
+        let raw = RawImage::probe(flash, bank_offset, bank_size, policy)?;
+        check_load_region(raw_load_addr, raw.size, forbidden)?;
+        Ok(Image {
+            format: ImageFormat::Raw(raw),
+            load: raw.loadable(bank_offset, raw_load_addr),
+        })
+    }
+
+    /// Short format name, for logs.
+    pub fn kind(&self) -> &'static str {
+        match self.format {
+            ImageFormat::Spl(_) => "SPL1",
+            ImageFormat::UImage(_) => "uImage",
+            ImageFormat::Raw(_) => "raw",
+        }
+    }
+
+    /// Whether the stored payload was already found intact in flash.
+    /// Otherwise its CRC is checked once loaded (SPL1 images, whose CRC
+    /// covers the loaded bytes).
+    pub fn verified_in_flash(&self) -> bool {
+        self.load.loaded_crc.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash_intel::IntelFlash;
+    use crate::uimage::tests::mkimage;
+    use crate::watchdog::tests::{longest_gap, watched, CADENCE_US};
+    use crate::Rng;
+
+    const CHUNK: usize = 4096;
+    // Not a whole number of chunks.
+    const PAYLOAD: usize = 16 * CHUNK + 100;
+    const BLOCK: usize = 64 * 1024;
+    // What RAM holds before a load.
+    const FILL: u8 = 0xa5;
+
+    // A flash with `payload` at its start.
+    fn flash(payload: &[u8]) -> IntelFlash {
+        IntelFlash::new(PhysAddr::new(0x2000_0000), 4 * BLOCK, BLOCK, payload.to_vec())
+    }
+
+    fn payload(seed: u64) -> Vec<u8> {
+        let mut rng = Rng(seed);
+        (0..PAYLOAD).map(|_| rng.below(256) as u8).collect()
+    }
+
+    // The payload at the start of the flash, loaded at `ram`.
+    fn loadable(ram: &mut [u8]) -> LoadableImage {
+        LoadableImage {
+            payload_offset: 0,
+            payload_size: PAYLOAD,
+            load_addr: PhysAddr::new(ram.as_mut_ptr() as usize),
+            load_size: PAYLOAD,
+            entry: ram.as_ptr() as usize,
+            mode: PrivMode::Machine,
+            release_harts: false,
+            meta_rewrite: false,
+            compressed: false,
+            loaded_crc: None,
+        }
+    }
+
+    // A yield point before every chunk, with no more than a chunk read
+    // and copied between two.
+    #[test]
+    fn copy_yields_every_chunk() {
+        let payload = payload(11);
+        let flash = flash(&payload);
+        let mut ram = vec![FILL; PAYLOAD];
+        let load = loadable(&mut ram);
+        let mut crc = Algorithm::Crc32.hasher();
+        let start = clint::now_us();
+        let copy = || unsafe { load.copy_hashed::<CHUNK>(&flash, &mut crc) };
+        let (result, yields) = watched(None, || {}, copy);
+        assert_eq!(result, Ok(()));
+        assert_eq!(ram, payload);
+        let done: Vec<_> = yields.iter().map(|y| (y.what, y.done, y.total)).collect();
+        assert_eq!(done, (0..PAYLOAD).step_by(CHUNK).map(|d| ("load", d, PAYLOAD)).collect::<Vec<_>>());
+        assert!(longest_gap(start, &yields, clint::now_us()) <= CADENCE_US);
+    }
+
+    // Cancelled, the copy stops before the next chunk: nothing of it or
+    // past it is written, and the digest saw what was.
+    #[test]
+    fn cancelled_copy_stops_at_a_chunk() {
+        let payload = payload(12);
+        let flash = flash(&payload);
+        for n in [0, 1, 7, PAYLOAD / CHUNK] {
+            let mut ram = vec![FILL; PAYLOAD];
+            let load = loadable(&mut ram);
+            let mut crc = Algorithm::Crc32.hasher();
+            let copy = || unsafe { load.copy_hashed::<CHUNK>(&flash, &mut crc) };
+            let (result, yields) = watched(Some(n), || {}, copy);
+            assert_eq!(result, Err(ImageError::Cancelled));
+            assert_eq!(yields.len(), n + 1);
+            let copied = n * CHUNK;
+            assert_eq!(ram[..copied], payload[..copied]);
+            assert!(ram[copied..].iter().all(|&b| b == FILL));
+            assert_eq!(crc.finalize().to_u32(), crc32(&payload[..copied]));
+        }
+    }
+
+    // The SHA-256 and CRC-32 of one streamed copy, and the copy itself,
+    // are what the two passes make of the payload: digest_of() over the
+    // flash, then load() and a CRC-32 of RAM. Whatever the chunk size.
+    #[test]
+    fn streamed_digests_match_two_passes() {
+        fn streamed<const N: usize>(flash: &IntelFlash) -> (Vec<u8>, Output, u32) {
+            let mut ram = vec![FILL; PAYLOAD];
+            let load = loadable(&mut ram);
+            let mut pair = Pair(Algorithm::Sha256.hasher(), Algorithm::Crc32.hasher());
+            assert_eq!(unsafe { load.copy_hashed::<N>(flash, &mut pair) }, Ok(()));
+            (ram, pair.0.finalize(), pair.1.finalize().to_u32())
+        }
+
+        for seed in 0..4 {
+            let payload = payload(20 + seed);
+            let flash = flash(&payload);
+            let sha = digest_of(&flash, 0, PAYLOAD, Algorithm::Sha256);
+            let mut ram = vec![FILL; PAYLOAD];
+            let mut load = loadable(&mut ram);
+            load.loaded_crc = Some(crc32(&payload));
+            assert_eq!(unsafe { load.load(&flash) }, Ok(load.entry));
+            let two_pass = (ram, sha, crc32(&payload));
+            assert_eq!(streamed::<CHUNK>(&flash), two_pass);
+            assert_eq!(streamed::<1000>(&flash), two_pass);
+            assert_eq!(streamed::<BLOCK>(&flash), two_pass);
+        }
+    }
+
+    // A payload refused once in RAM, whichever pass put it there, or a
+    // copy cancelled half way, is zeroed over the whole load region and
+    // nothing past it.
+    #[test]
+    fn refused_payload_is_scrubbed() {
+        const GUARD: usize = 64;
+        let payload = payload(30);
+        let mut bad = payload.clone();
+        bad[PAYLOAD / 2] ^= 1;
+        let flash = flash(&bad);
+        let scrubbed = |ram: &[u8]| {
+            assert!(ram[..GUARD].iter().all(|&b| b == FILL));
+            assert!(ram[GUARD..GUARD + PAYLOAD].iter().all(|&b| b == 0));
+            assert!(ram[GUARD + PAYLOAD..].iter().all(|&b| b == FILL));
+        };
+        let mismatch = Err(ImageError::CrcMismatch {
+            expected: crc32(&payload),
+            actual: crc32(&bad),
+        });
+
+        let mut ram = vec![FILL; GUARD + PAYLOAD + GUARD];
+        let mut load = loadable(&mut ram[GUARD..GUARD + PAYLOAD]);
+        load.loaded_crc = Some(crc32(&payload));
+        let mut crc = Algorithm::Crc32.hasher();
+        assert_eq!(unsafe { load.copy_hashed::<CHUNK>(&flash, &mut crc) }, Ok(()));
+        assert_eq!(load.check_crc(crc.finalize().to_u32()), mismatch);
+        unsafe { load.scrub() };
+        scrubbed(&ram);
+
+        let mut ram = vec![FILL; GUARD + PAYLOAD + GUARD];
+        let mut load = loadable(&mut ram[GUARD..GUARD + PAYLOAD]);
+        load.loaded_crc = Some(crc32(&payload));
+        assert_eq!(unsafe { load.load(&flash) }.map(|_| ()), mismatch);
+        unsafe { load.scrub() };
+        scrubbed(&ram);
+
+        let mut ram = vec![FILL; GUARD + PAYLOAD + GUARD];
+        let load = loadable(&mut ram[GUARD..GUARD + PAYLOAD]);
+        let mut crc = Algorithm::Crc32.hasher();
+        let copy = || unsafe { load.copy_hashed::<CHUNK>(&flash, &mut crc) };
+        let (result, _) = watched(Some(3), || {}, copy);
+        assert_eq!(result, Err(ImageError::Cancelled));
+        unsafe { load.scrub() };
+        scrubbed(&ram);
+    }
+
+    const BANK: usize = 4096;
+    const RAW_LOAD: PhysAddr = PhysAddr::new(0x8100_0000);
+
+    // The bank `image` is at the start of, erased past it, probed as
+    // the boot path does with the SPL at the bottom of RAM.
+    fn probe(image: &[u8], policy: &VerifyPolicy) -> Result<Image, ImageError> {
+        let mut bank = image.to_vec();
+        bank.resize(BANK, 0xff);
+        let flash = MemSource { base: bank.as_ptr() as usize, size: BANK };
+        let spl = [Forbidden::new("spl", PhysAddr::new(0x8000_0000), 0x20_0000)];
+        Image::probe(&flash, 0, BANK, RAW_LOAD, &spl, policy)
+    }
+
+    // As the wrapping recipe in the SplImageHeader doc makes it.
+    fn spl1(payload: &[u8], version: u16) -> Vec<u8> {
+        let mut img = vec![0u8; 256];
+        img[spl1::MAGIC.range()].copy_from_slice(b"SPL1");
+        img[spl1::VERSION.range()].copy_from_slice(&version.to_le_bytes());
+        img[spl1::HDR_SIZE.range()].copy_from_slice(&256u16.to_le_bytes());
+        img[spl1::PAYLOAD_SIZE.range()].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        img[spl1::LOAD_ADDR.range()].copy_from_slice(&0x8020_0000u64.to_le_bytes());
+        img[spl1::PAYLOAD_CRC.range()].copy_from_slice(&crc32(payload).to_le_bytes());
+        img.extend(payload);
+        img
+    }
+
+    // A header-less bank: the payload, erased up to the CRC trailer.
+    fn raw(payload: &[u8]) -> Vec<u8> {
+        let mut img = payload.to_vec();
+        img.resize(BANK - 4, 0xff);
+        let crc = crc32(&img);
+        img.extend(crc.to_le_bytes());
+        img
+    }
+
+    // Each format is recognised, with its load region and checks.
+    #[test]
+    fn probe_formats() {
+        let payload = payload(40)[..1000].to_vec();
+        let policy = VerifyPolicy::built_in();
+
+        let image = probe(&spl1(&payload, 1), &policy).unwrap();
+        assert!(matches!(image.format, ImageFormat::Spl(_)));
+        assert_eq!((image.kind(), image.load.payload_offset, image.load.load_size), ("SPL1", 256, 1000));
+        assert_eq!(image.load.loaded_crc, Some(crc32(&payload)));
+        assert!(!image.verified_in_flash());
+
+        let image = probe(&mkimage(&payload), &policy).unwrap();
+        assert!(matches!(image.format, ImageFormat::UImage(_)));
+        assert_eq!((image.kind(), image.load.payload_offset, image.load.load_size), ("uImage", 64, 1000));
+        assert!(image.verified_in_flash());
+
+        let image = probe(&raw(&payload), &policy).unwrap();
+        assert!(matches!(image.format, ImageFormat::Raw(_)));
+        assert_eq!((image.kind(), image.load.load_addr, image.load.load_size), ("raw", RAW_LOAD, BANK - 4));
+        assert!(image.verified_in_flash());
+    }
+
+    // A bank that is none of them is refused, and a header that is
+    // there but bad is reported as such rather than as a raw bank.
+    #[test]
+    fn probe_garbage() {
+        let mut rng = Rng(41);
+        let garbage: Vec<u8> = (0..BANK).map(|_| rng.below(256) as u8).collect();
+        let magic = u32::from_le_bytes(garbage[..4].try_into().unwrap());
+        let policy = VerifyPolicy::built_in();
+        assert!(matches!(probe(&garbage, &policy), Err(ImageError::CrcMismatch { .. })));
+        assert_eq!(probe(&garbage, &VerifyPolicy::STRICT), Err(ImageError::BadMagic(magic)));
+        assert_eq!(probe(&[], &policy), Err(ImageError::Erased));
+
+        let payload = payload(42)[..1000].to_vec();
+        assert_eq!(probe(&spl1(&payload, 2), &policy), Err(ImageError::UnsupportedVersion(2)));
+        let mut uimage = mkimage(&payload);
+        uimage[8] ^= 1;
+        let e = probe(&uimage, &policy);
+        assert!(matches!(e, Err(ImageError::UImage(UImageError::HeaderCrc { .. }))), "{:?}", e);
+        let mut bad = raw(&payload);
+        bad[10] ^= 1;
+        assert!(matches!(probe(&bad, &policy), Err(ImageError::CrcMismatch { .. })));
+    }
+}
@@ -0,0 +1,521 @@
+// gzip / DEFLATE (RFC 1951, RFC 1952) decompressor, allocation-free.
+//
+// Input is streamed out of flash a chunk at a time; output goes straight
+// to its final place in RAM. Since the whole output stays addressable,
+// it doubles as the 32 KiB history window: back-references are copied
+// from bytes already written, no separate window buffer is needed.
+//
+// Huffman decoding is the canonical "count + sorted symbols" scheme from
+// zlib's puff.c: one bit at a time, slow but tiny.
+
+use core::result::Result;
+
+use crate::crc32::crc32;
+use crate::image::ImageSource;
+
+// Flash chunk buffered by the bit reader.
+const CHUNK: usize = 256;
+
+const MAX_BITS: usize = 15;
+const MAX_LCODES: usize = 286;
+const MAX_DCODES: usize = 30;
+const FIXED_LCODES: usize = 288;
+
+const GZIP_ID: [u8; 2] = [0x1f, 0x8b];
+const GZIP_CM_DEFLATE: u8 = 8;
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order in which code length code lengths are stored.
+const CL_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    BadGzipHeader,
+    UnsupportedMethod(u8),
+    BadBlockType,
+    BadStoredLength,
+    /// Over-subscribed or unusable set of Huffman code lengths.
+    BadCodeLengths,
+    BadCode,
+    /// Back-reference to before the start of the output.
+    BadDistance,
+    /// Output would not fit in the destination.
+    OutputOverflow,
+    /// Compressed stream ends early.
+    Truncated,
+    TrailerCrc { expected: u32, actual: u32 },
+    TrailerSize { expected: u32, actual: u32 },
+}
+
+// LSB-first bit reader over a flash range.
+struct BitReader<'f> {
+    flash: &'f dyn ImageSource,
+    pos: usize,
+    end: usize,
+    buf: [u8; CHUNK],
+    buf_pos: usize,
+    buf_len: usize,
+    bit_buf: u32,
+    bit_cnt: u32,
+}
+
+impl<'f> BitReader<'f> {
+    fn new(flash: &'f dyn ImageSource, offset: usize, len: usize) -> Self {
+        BitReader {
+            flash,
+            pos: offset,
+            end: offset + len,
+            buf: [0; CHUNK],
+            buf_pos: 0,
+            buf_len: 0,
+            bit_buf: 0,
+            bit_cnt: 0,
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, InflateError> {
+        if self.buf_pos == self.buf_len {
+            let n = core::cmp::min(CHUNK, self.end - self.pos);
+            if n == 0 {
+                return Err(InflateError::Truncated);
+            }
+            self.flash.read_slice(self.pos, &mut self.buf[..n]);
+            self.pos += n;
+            self.buf_pos = 0;
+            self.buf_len = n;
+        }
+        let b = self.buf[self.buf_pos];
+        self.buf_pos += 1;
+        Ok(b)
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, InflateError> {
+        while self.bit_cnt < n {
+            self.bit_buf |= (self.byte()? as u32) << self.bit_cnt;
+            self.bit_cnt += 8;
+        }
+        let v = self.bit_buf & ((1u32 << n) - 1);
+        self.bit_buf >>= n;
+        self.bit_cnt -= n;
+        Ok(v)
+    }
+
+    // Drop the bits left in the current byte.
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_cnt = 0;
+    }
+
+    fn u16_le(&mut self) -> Result<u16, InflateError> {
+        Ok(u16::from_le_bytes([self.byte()?, self.byte()?]))
+    }
+
+    fn u32_le(&mut self) -> Result<u32, InflateError> {
+        Ok(u32::from_le_bytes([self.byte()?, self.byte()?, self.byte()?, self.byte()?]))
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), InflateError> {
+        for _ in 0..n {
+            self.byte()?;
+        }
+        Ok(())
+    }
+
+    fn skip_cstr(&mut self) -> Result<(), InflateError> {
+        while self.byte()? != 0 {}
+        Ok(())
+    }
+}
+
+struct Output<'o> {
+    buf: &'o mut [u8],
+    pos: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, b: u8) -> Result<(), InflateError> {
+        *self.buf.get_mut(self.pos).ok_or(InflateError::OutputOverflow)? = b;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn copy_back(&mut self, dist: usize, len: usize) -> Result<(), InflateError> {
+        if dist > self.pos {
+            return Err(InflateError::BadDistance);
+        }
+        if len > self.buf.len() - self.pos {
+            return Err(InflateError::OutputOverflow);
+        }
+        // Byte by byte: the source may overlap what we are writing.
+        for _ in 0..len {
+            self.buf[self.pos] = self.buf[self.pos - dist];
+            self.pos += 1;
+        }
+        Ok(())
+    }
+}
+
+// Canonical Huffman code: number of codes per length, and the symbols
+// sorted by code.
+struct Huffman<const N: usize> {
+    count: [u16; MAX_BITS + 1],
+    symbol: [u16; N],
+}
+
+impl<const N: usize> Huffman<N> {
+    const fn new() -> Self {
+        Huffman {
+            count: [0; MAX_BITS + 1],
+            symbol: [0; N],
+        }
+    }
+
+    // Build from per-symbol code lengths. Returns the number of unused
+    // codes: 0 for a complete code, > 0 for an incomplete one.
+    fn build(&mut self, lengths: &[u8]) -> Result<i32, InflateError> {
+        self.count = [0; MAX_BITS + 1];
+        for &l in lengths {
+            self.count[l as usize] += 1;
+        }
+        if self.count[0] as usize == lengths.len() {
+            return Ok(0);
+        }
+
+        let mut left = 1i32;
+        for len in 1..=MAX_BITS {
+            left <<= 1;
+            left -= self.count[len] as i32;
+            if left < 0 {
+                return Err(InflateError::BadCodeLengths);
+            }
+        }
+
+        let mut offs = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offs[len + 1] = offs[len] + self.count[len];
+        }
+        for (sym, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                self.symbol[offs[l as usize] as usize] = sym as u16;
+                offs[l as usize] += 1;
+            }
+        }
+        Ok(left)
+    }
+
+    // A complete code, or the one-code-of-length-1 case RFC 1951 allows.
+    fn build_usable(&mut self, lengths: &[u8]) -> Result<(), InflateError> {
+        let left = self.build(lengths)?;
+        let used = lengths.len() - self.count[0] as usize;
+        if left > 0 && !(used == 1 && self.count[1] == 1) {
+            return Err(InflateError::BadCodeLengths);
+        }
+        Ok(())
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, InflateError> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..=MAX_BITS {
+            code |= r.bits(1)? as i32;
+            let count = self.count[len] as i32;
+            if code - count < first {
+                return Ok(self.symbol[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::BadCode)
+    }
+}
+
+fn stored(r: &mut BitReader, out: &mut Output) -> Result<(), InflateError> {
+    r.align();
+    let len = r.u16_le()?;
+    let nlen = r.u16_le()?;
+    if len != !nlen {
+        return Err(InflateError::BadStoredLength);
+    }
+    for _ in 0..len {
+        out.push(r.byte()?)?;
+    }
+    Ok(())
+}
+
+fn codes<const L: usize, const D: usize>(
+    r: &mut BitReader,
+    out: &mut Output,
+    lencode: &Huffman<L>,
+    distcode: &Huffman<D>,
+) -> Result<(), InflateError> {
+    loop {
+        let sym = lencode.decode(r)? as usize;
+        match sym {
+            0..=255 => out.push(sym as u8)?,
+            256 => return Ok(()),
+            _ => {
+                let idx = sym - 257;
+                if idx >= LEN_BASE.len() {
+                    return Err(InflateError::BadCode);
+                }
+                let len = LEN_BASE[idx] as usize + r.bits(LEN_EXTRA[idx] as u32)? as usize;
+
+                let dsym = distcode.decode(r)? as usize;
+                if dsym >= DIST_BASE.len() {
+                    return Err(InflateError::BadDistance);
+                }
+                let dist = DIST_BASE[dsym] as usize + r.bits(DIST_EXTRA[dsym] as u32)? as usize;
+                out.copy_back(dist, len)?;
+            }
+        }
+    }
+}
+
+fn fixed(r: &mut BitReader, out: &mut Output) -> Result<(), InflateError> {
+    let mut lengths = [0u8; FIXED_LCODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let mut lencode = Huffman::<FIXED_LCODES>::new();
+    lencode.build(&lengths)?;
+
+    let mut distcode = Huffman::<MAX_DCODES>::new();
+    distcode.build(&[5; MAX_DCODES])?;
+
+    codes(r, out, &lencode, &distcode)
+}
+
+fn dynamic(r: &mut BitReader, out: &mut Output) -> Result<(), InflateError> {
+    let nlen = r.bits(5)? as usize + 257;
+    let ndist = r.bits(5)? as usize + 1;
+    let ncode = r.bits(4)? as usize + 4;
+    if nlen > MAX_LCODES || ndist > MAX_DCODES {
+        return Err(InflateError::BadCodeLengths);
+    }
+
+    let mut lengths = [0u8; MAX_LCODES + MAX_DCODES];
+    for &i in &CL_ORDER[..ncode] {
+        lengths[i] = r.bits(3)? as u8;
+    }
+    let mut clcode = Huffman::<19>::new();
+    if clcode.build(&lengths[..19])? != 0 {
+        return Err(InflateError::BadCodeLengths);
+    }
+
+    let mut i = 0;
+    while i < nlen + ndist {
+        let sym = clcode.decode(r)?;
+        let (value, repeat) = match sym {
+            0..=15 => {
+                lengths[i] = sym as u8;
+                i += 1;
+                continue;
+            }
+            16 => {
+                if i == 0 {
+                    return Err(InflateError::BadCodeLengths);
+                }
+                (lengths[i - 1], 3 + r.bits(2)? as usize)
+            }
+            17 => (0, 3 + r.bits(3)? as usize),
+            _ => (0, 11 + r.bits(7)? as usize),
+        };
+        if i + repeat > nlen + ndist {
+            return Err(InflateError::BadCodeLengths);
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    // Without an end-of-block code the block can never finish.
+    if lengths[256] == 0 {
+        return Err(InflateError::BadCodeLengths);
+    }
+
+    let mut lencode = Huffman::<MAX_LCODES>::new();
+    lencode.build_usable(&lengths[..nlen])?;
+    let mut distcode = Huffman::<MAX_DCODES>::new();
+    distcode.build_usable(&lengths[nlen..nlen + ndist])?;
+
+    codes(r, out, &lencode, &distcode)
+}
+
+fn deflate(r: &mut BitReader, out: &mut Output) -> Result<(), InflateError> {
+    loop {
+        let last = r.bits(1)? != 0;
+        match r.bits(2)? {
+            0 => stored(r, out)?,
+            1 => fixed(r, out)?,
+            2 => dynamic(r, out)?,
+            _ => return Err(InflateError::BadBlockType),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn gzip_header(r: &mut BitReader) -> Result<(), InflateError> {
+    if [r.byte()?, r.byte()?] != GZIP_ID {
+        return Err(InflateError::BadGzipHeader);
+    }
+    let cm = r.byte()?;
+    if cm != GZIP_CM_DEFLATE {
+        return Err(InflateError::UnsupportedMethod(cm));
+    }
+    let flg = r.byte()?;
+    if flg & 0xe0 != 0 {
+        return Err(InflateError::BadGzipHeader);
+    }
+    r.skip(6)?; // mtime, xfl, os
+    if flg & FEXTRA != 0 {
+        let xlen = r.u16_le()? as usize;
+        r.skip(xlen)?;
+    }
+    if flg & FNAME != 0 {
+        r.skip_cstr()?;
+    }
+    if flg & FCOMMENT != 0 {
+        r.skip_cstr()?;
+    }
+    if flg & FHCRC != 0 {
+        r.skip(2)?;
+    }
+    Ok(())
+}
+
+/// Inflate the gzip member of `len` bytes at flash `offset` into `out`,
+/// checking the gzip CRC-32 and size trailer. Returns the number of bytes
+/// written.
+pub fn gunzip(
+    flash: &dyn ImageSource,
+    offset: usize,
+    len: usize,
+    out: &mut [u8],
+) -> Result<usize, InflateError> {
+    let mut r = BitReader::new(flash, offset, len);
+    let mut out = Output { buf: out, pos: 0 };
+
+    gzip_header(&mut r)?;
+    deflate(&mut r, &mut out)?;
+
+    r.align();
+    let expected = r.u32_le()?;
+    let isize = r.u32_le()?;
+    let written = out.pos;
+    if isize != written as u32 {
+        return Err(InflateError::TrailerSize {
+            expected: isize,
+            actual: written as u32,
+        });
+    }
+    let actual = crc32(&out.buf[..written]);
+    if actual != expected {
+        return Err(InflateError::TrailerCrc { expected, actual });
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::MemSource;
+
+    // "hello, hello, hello, world\n" from zlib with Z_FIXED.
+    const FIXED: [u8; 35] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7,
+        0x51, 0xc8, 0x40, 0xa1, 0xca, 0xf3, 0x8b, 0x72, 0x52, 0xb8, 0x00, 0xc3, 0x70, 0xa3, 0xc2, 0x1b,
+        0x00, 0x00, 0x00,
+    ];
+
+    // verses(4) from zlib at level 9, which picks a dynamic block.
+    const DYNAMIC: [u8; 105] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x95, 0xcc, 0xb1, 0x0d, 0x80, 0x20,
+        0x10, 0x46, 0xe1, 0x9e, 0x29, 0xfe, 0x01, 0x88, 0x25, 0x78, 0x7b, 0xb8, 0xc0, 0x11, 0xce, 0x68,
+        0x24, 0x9c, 0x01, 0x0c, 0xeb, 0x6b, 0x6f, 0x41, 0xa8, 0xdf, 0xcb, 0x47, 0x84, 0xa0, 0xad, 0x25,
+        0xa9, 0xd0, 0x1d, 0x41, 0xa4, 0x40, 0x33, 0xda, 0x21, 0xe8, 0x9c, 0x92, 0x05, 0xfd, 0xfa, 0x62,
+        0x36, 0xbe, 0xe4, 0xbb, 0x04, 0x51, 0x7b, 0xb6, 0xb8, 0xb9, 0x56, 0x9c, 0x0d, 0x5c, 0xf4, 0xc9,
+        0xd1, 0xd0, 0x3a, 0x10, 0xd7, 0x69, 0xd1, 0x0f, 0x44, 0x3f, 0x2d, 0xba, 0x81, 0xe8, 0x66, 0xc5,
+        0x17, 0x92, 0x37, 0x04, 0x70, 0x48, 0x01, 0x00, 0x00,
+    ];
+
+    // The first `n` verses of the song.
+    fn verses(n: u32) -> Vec<u8> {
+        let verse = |i| {
+            format!("{i} bottles of beer on the wall, {i} bottles of beer.\nTake one down, pass it around\n")
+        };
+        (100 - n..100).rev().flat_map(|i| verse(i).into_bytes()).collect()
+    }
+
+    fn gunzip_vec(gz: &[u8], out_len: usize) -> Result<Vec<u8>, InflateError> {
+        let flash = MemSource { base: gz.as_ptr() as usize, size: gz.len() };
+        let mut out = vec![0u8; out_len];
+        let n = gunzip(&flash, 0, gz.len(), &mut out)?;
+        out.truncate(n);
+        Ok(out)
+    }
+
+    // A gzip member of stored blocks, as `gzip` never writes for small
+    // inputs: one block per 100 bytes, the last one flagged.
+    fn stored(data: &[u8]) -> Vec<u8> {
+        let mut gz = vec![0x1f, 0x8b, 8, FNAME, 0, 0, 0, 0, 0, 3];
+        gz.extend(b"data.bin\0");
+        let mut chunks = data.chunks(100).peekable();
+        while let Some(chunk) = chunks.next() {
+            let len = chunk.len() as u16;
+            gz.push(chunks.peek().is_none() as u8);
+            gz.extend(len.to_le_bytes());
+            gz.extend((!len).to_le_bytes());
+            gz.extend(chunk);
+        }
+        gz.extend(crc32(data).to_le_bytes());
+        gz.extend((data.len() as u32).to_le_bytes());
+        gz
+    }
+
+    #[test]
+    fn block_types() {
+        let data = verses(4);
+        assert_eq!(gunzip_vec(&stored(&data), 4096), Ok(data.clone()));
+        assert_eq!(gunzip_vec(&FIXED, 4096).unwrap(), b"hello, hello, hello, world\n");
+        assert_eq!(gunzip_vec(&DYNAMIC, 4096), Ok(data.clone()));
+        // Exactly the size it needs, then one byte short.
+        assert_eq!(gunzip_vec(&DYNAMIC, data.len()), Ok(data.clone()));
+        assert_eq!(gunzip_vec(&DYNAMIC, data.len() - 1), Err(InflateError::OutputOverflow));
+    }
+
+    // Cut anywhere, a stream gives an error rather than a short output.
+    #[test]
+    fn truncated() {
+        for gz in [&stored(&verses(3))[..], &FIXED, &DYNAMIC] {
+            for len in 0..gz.len() {
+                assert!(gunzip_vec(&gz[..len], 4096).is_err(), "{} of {} bytes", len, gz.len());
+            }
+        }
+        let mut bad = DYNAMIC;
+        bad[DYNAMIC.len() - 8] ^= 1;
+        assert!(matches!(gunzip_vec(&bad, 4096), Err(InflateError::TrailerCrc { .. })));
+        bad[0] = 0;
+        assert_eq!(gunzip_vec(&bad, 4096), Err(InflateError::BadGzipHeader));
+    }
+}
@@ -0,0 +1,394 @@
+use crate::addr::{FlashOffset, PhysAddr};
+use crate::flash_intel::IntelFlash;
+use crate::{arch, arena, banks, board, bootlog, handoff, logger, ramtrials, reset_cause, storm, warmboot};
+use crate::slog_debug;
+
+// Every region of the flash and RAM layout in one table, so they can be
+// checked against each other: the flash ones when building (a bank over
+// the metadata block doesn't compile), the RAM ones, which the linker
+// places, first thing at boot. Anything new that claims flash or RAM
+// (a bank, a ring, a buffer) gets an entry here.
+
+/// Address space a region lives in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    /// Offsets from the base of this flash unit (see board::FLASH_BASE).
+    Flash(usize),
+    /// CPU addresses.
+    Ram,
+}
+
+/// One named region, [base, base + size); `base` is a FlashOffset or a
+/// PhysAddr by `space`, kept as a plain number to compare them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub name: &'static str,
+    pub space: Space,
+    pub base: usize,
+    pub size: usize,
+}
+
+impl Area {
+    const fn flash(name: &'static str, unit: usize, offset: FlashOffset, size: usize) -> Self {
+        Area {
+            name,
+            space: Space::Flash(unit),
+            base: offset.get(),
+            size,
+        }
+    }
+
+    fn ram(name: &'static str, (start, end): (usize, usize)) -> Self {
+        Area {
+            name,
+            space: Space::Ram,
+            base: start,
+            size: end - start,
+        }
+    }
+
+    const fn end(&self) -> usize {
+        self.base + self.size
+    }
+
+    const fn same_space(&self, other: &Area) -> bool {
+        match (self.space, other.space) {
+            (Space::Flash(a), Space::Flash(b)) => a == b,
+            (Space::Ram, Space::Ram) => true,
+            _ => false,
+        }
+    }
+
+    const fn overlaps(&self, other: &Area) -> bool {
+        self.size != 0
+            && other.size != 0
+            && self.same_space(other)
+            && self.base < other.end()
+            && other.base < self.end()
+    }
+}
+
+const FIXED_FLASH_AREAS: usize = 7;
+
+/// The flash layout: the SPL (which boots from unit 0, at its start),
+/// the golden image, the metadata, the verification cache, the SPL2
+/// slot, the black box, the layout descriptor, then every bank of
+/// crate::BOOT_BANKS (named after the bank).
+pub const FLASH_AREAS: [Area; FIXED_FLASH_AREAS + crate::BOOT_BANKS.len()] = {
+    const fn at(offset: usize) -> FlashOffset {
+        FlashOffset::new(offset)
+    }
+    let mut areas = [Area::flash("", 0, FlashOffset::ZERO, 0); FIXED_FLASH_AREAS + crate::BOOT_BANKS.len()];
+    areas[0] = Area::flash("spl", 0, FlashOffset::ZERO, board::SPL_FLASH_SIZE);
+    areas[1] = match crate::GOLDEN_OFFSET {
+        Some(offset) => Area::flash("golden", crate::GOLDEN_UNIT, at(offset), board::BANK_SIZE),
+        None => Area::flash("golden", crate::GOLDEN_UNIT, FlashOffset::ZERO, 0),
+    };
+    areas[2] = Area::flash("meta", board::META_UNIT, at(board::META_OFFSET), board::META_SIZE);
+    areas[3] = Area::flash("vcache", board::VCACHE_UNIT, at(board::VCACHE_OFFSET), board::VCACHE_SIZE);
+    areas[4] = Area::flash("spl2", board::SPL2_UNIT, at(board::SPL2_OFFSET), board::SPL2_SIZE);
+    areas[5] = Area::flash("blackbox", board::BLACKBOX_UNIT, at(board::BLACKBOX_OFFSET), board::BLACKBOX_SIZE);
+    areas[6] = Area::flash("layout", board::LAYOUT_UNIT, at(board::LAYOUT_OFFSET), board::LAYOUT_SIZE);
+    let mut i = 0;
+    while i < crate::BOOT_BANKS.len() {
+        let b = &crate::BOOT_BANKS[i];
+        areas[FIXED_FLASH_AREAS + i] = Area::flash(b.name, b.unit, b.offset, b.size);
+        i += 1;
+    }
+    areas
+};
+
+// First two regions of `areas` that overlap, by index.
+const fn first_overlap(areas: &[Area]) -> Option<(usize, usize)> {
+    let mut i = 0;
+    while i < areas.len() {
+        let mut j = i + 1;
+        while j < areas.len() {
+            if areas[i].overlaps(&areas[j]) {
+                return Some((i, j));
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    None
+}
+
+// Whether every flash region of `areas` is on an existing unit and ends
+// within its size.
+const fn all_within(areas: &[Area], sizes: &[usize]) -> bool {
+    let mut i = 0;
+    while i < areas.len() {
+        if let Space::Flash(unit) = areas[i].space
+            && (unit >= sizes.len() || areas[i].end() > sizes[unit])
+        {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(
+    first_overlap(&FLASH_AREAS).is_none(),
+    "flash layout: two regions of layout::FLASH_AREAS overlap"
+);
+const _: () = assert!(
+    all_within(&FLASH_AREAS, &board::FLASH_SIZE),
+    "flash layout: a region of layout::FLASH_AREAS runs past its unit's FLASH_SIZE"
+);
+// The SPL writes the banks (updates) and the metadata (every boot).
+const _: () = assert!(
+    board::FLASH_WRITABLE[board::META_UNIT],
+    "flash layout: boot metadata on a read-only flash unit"
+);
+const _: () = assert!(
+    board::FLASH_WRITABLE[board::VCACHE_UNIT],
+    "flash layout: verification cache on a read-only flash unit"
+);
+const _: () = assert!(
+    board::FLASH_WRITABLE[board::BLACKBOX_UNIT],
+    "flash layout: black box on a read-only flash unit"
+);
+const _: () = assert!(
+    banks_writable(),
+    "flash layout: a bank of BOOT_BANKS on a read-only flash unit"
+);
+
+const fn banks_writable() -> bool {
+    let mut i = 0;
+    while i < crate::BOOT_BANKS.len() {
+        let unit = crate::BOOT_BANKS[i].unit;
+        if unit >= board::FLASH_UNITS || !board::FLASH_WRITABLE[unit] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Why a bank the flash's layout descriptor places can't be used there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankMisfit {
+    NoSuchUnit,
+    ReadOnlyUnit,
+    PastEnd,
+    /// Not whole blocks of its unit: we erase banks.
+    Unaligned,
+    /// Over this region.
+    Overlaps(&'static str),
+}
+
+/// Check bank `index` of the table at `size` bytes at `offset` of
+/// flash `unit`, as the layout descriptor puts it (see layout_desc.rs):
+/// on a writable unit, whole blocks of it, and clear of every other
+/// region of FLASH_AREAS and bank of banks::table().
+pub fn check_bank(
+    index: usize,
+    unit: usize,
+    offset: FlashOffset,
+    size: usize,
+    flash: &[IntelFlash],
+) -> Result<(), BankMisfit> {
+    let f = flash.get(unit).ok_or(BankMisfit::NoSuchUnit)?;
+    if !board::FLASH_WRITABLE[unit] {
+        return Err(BankMisfit::ReadOnlyUnit);
+    }
+    if size == 0 || offset.get().checked_add(size).is_none_or(|end| end > f.size) {
+        return Err(BankMisfit::PastEnd);
+    }
+    if !offset.is_aligned(f.block_size) || !size.is_multiple_of(f.block_size) {
+        return Err(BankMisfit::Unaligned);
+    }
+    let bank = Area::flash("", unit, offset, size);
+    let others = banks::table()
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != index)
+        .map(|(_, b)| Area::flash(b.name, b.unit, b.offset, b.size));
+    match FLASH_AREAS[..FIXED_FLASH_AREAS].iter().copied().chain(others).find(|a| a.overlaps(&bank)) {
+        Some(a) => Err(BankMisfit::Overlaps(a.name)),
+        None => Ok(()),
+    }
+}
+
+/// The RAM layout: the SPL's own sections, as linked, and where raw
+/// payloads are loaded.
+pub fn ram_areas() -> [Area; 13] {
+    [
+        Area::ram("spl image", arch::image_region()),
+        Area::ram("spl bss", arch::bss_region()),
+        Area::ram("log ring", {
+            let (base, size) = logger::ringbuf::region();
+            (base, base + size)
+        }),
+        Area::ram("reset signature", {
+            let (base, size) = reset_cause::region();
+            (base, base + size)
+        }),
+        Area::ram("storm counter", {
+            let (base, size) = storm::region();
+            (base, base + size)
+        }),
+        Area::ram("warm token", {
+            let (base, size) = warmboot::region();
+            (base, base + size)
+        }),
+        Area::ram("ram trials", {
+            let (base, size) = ramtrials::region();
+            (base, base + size)
+        }),
+        Area::ram("event log", {
+            let (base, size) = bootlog::region();
+            (base, base + size)
+        }),
+        Area::ram("heap", arena::region()),
+        Area::ram("stack", arch::stack_region()),
+        Area::ram("handoff", {
+            let (base, size) = handoff::region();
+            (base, base + size)
+        }),
+        Area::ram("raw load", (crate::RAW_LOAD_ADDR, crate::RAW_LOAD_ADDR + board::BANK_SIZE)),
+        Area::ram("ram stage", (crate::RAM_STAGE_ADDR, crate::RAM_STAGE_ADDR + board::BANK_SIZE)),
+    ]
+}
+
+// The areas of ram_areas() the console's "mw" may write: scratch RAM
+// and the payload load windows, nothing the SPL runs on.
+#[cfg(feature = "console")]
+const POKE_AREAS: [&str; 3] = ["heap", "raw load", "ram stage"];
+
+/// Whether the console may write the `len` bytes at `addr`: they must
+/// lie within one of the POKE_AREAS, and clear of each flash unit's
+/// window (its command interface), the boot log and the SPL image
+/// (text and rodata) whatever those say. Err says why not.
+#[cfg(feature = "console")]
+pub fn check_poke(addr: usize, len: usize, flash: &[IntelFlash]) -> Result<(), &'static str> {
+    let end = addr.checked_add(len).ok_or("wraps around")?;
+    let hits = |(start, stop): (usize, usize)| addr < stop && start < end;
+    let meta = FlashOffset::new(board::META_OFFSET).to_phys(flash[board::META_UNIT].base).get();
+    if hits((meta, meta + board::META_SIZE)) {
+        return Err("boot log");
+    }
+    if flash.iter().any(|f| hits((f.base.get(), f.base.get() + f.size))) {
+        return Err("flash window");
+    }
+    if hits(arch::image_region()) {
+        return Err("SPL image");
+    }
+    match ram_areas().iter().any(|a| POKE_AREAS.contains(&a.name) && a.base <= addr && end <= a.end()) {
+        true => Ok(()),
+        false => Err("not in scratch RAM or a load window"),
+    }
+}
+
+/// Check the RAM layout (the flash one was checked when building) and
+/// panic naming the first two regions that collide, or if the SPL was
+/// linked for another board's RAM window.
+pub fn validate() {
+    let (start, end) = arch::spl_ram_region();
+    if start != board::RAM_BASE || end - start != board::SPL_RAM_SIZE {
+        panic!("layout: linked for RAM at 0x{:x}..0x{:x}, not this board's", start, end);
+    }
+    let ram = ram_areas();
+    if let Some((i, j)) = first_overlap(&ram) {
+        panic!("layout: {} overlaps {}", ram[i].name, ram[j].name);
+    }
+}
+
+/// Check the flash regions of `unit` against what its CFI query said
+/// (size and block size), panicking on the first that doesn't fit. Only
+/// the regions we erase need whole blocks.
+pub fn validate_unit(unit: usize, size: usize, block_size: usize) {
+    for a in FLASH_AREAS.iter().filter(|a| a.space == Space::Flash(unit) && a.size != 0) {
+        if a.end() > size {
+            panic!("layout: {} runs past flash unit {} ({} KiB)", a.name, unit, size / 1024);
+        }
+        let erased = !matches!(a.name, "spl" | "golden" | "spl2");
+        if erased && (!FlashOffset::new(a.base).is_aligned(block_size) || !a.size.is_multiple_of(block_size)) {
+            panic!(
+                "layout: {} not aligned to the {} KiB blocks of flash unit {}",
+                a.name,
+                block_size / 1024,
+                unit
+            );
+        }
+    }
+}
+
+/// Log the whole map at debug level, flash regions at their CPU address
+/// (`flash_bases` by unit).
+pub fn log_map(flash_bases: &[PhysAddr]) {
+    if !logger::log_enabled(logger::Level::Debug) {
+        return;
+    }
+    let ram = ram_areas();
+    // The banks where they are, if the layout descriptor moved them.
+    let banks = banks::table().iter().map(|b| Area::flash(b.name, b.unit, b.offset, b.size));
+    slog_debug!("memory map:");
+    for a in FLASH_AREAS[..FIXED_FLASH_AREAS].iter().copied().chain(banks).chain(ram).filter(|a| a.size != 0) {
+        let (space, base) = match a.space {
+            Space::Flash(unit) => ("flash", FlashOffset::new(a.base).to_phys(flash_bases[unit]).get()),
+            Space::Ram => ("ram", a.base),
+        };
+        slog_debug!("  {:<5} 0x{:08x}..0x{:08x} {}", space, base, base + a.size, a.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOT_SCRATCH: Result<(), &str> = Err("not in scratch RAM or a load window");
+
+    fn units() -> Vec<IntelFlash> {
+        (0..board::FLASH_UNITS)
+            .map(|unit| {
+                let base = PhysAddr::new(board::FLASH_BASE[unit]);
+                IntelFlash::new(base, board::FLASH_SIZE[unit], board::FLASH_BLOCK_SIZE[unit], Vec::new())
+            })
+            .collect()
+    }
+
+    fn area(name: &str) -> Area {
+        ram_areas().into_iter().find(|a| a.name == name).unwrap()
+    }
+
+    // Scratch RAM and the load windows, to their last byte, not one
+    // past either end.
+    #[test]
+    fn poke_areas() {
+        let flash = units();
+        for name in POKE_AREAS {
+            let a = area(name);
+            assert_eq!(check_poke(a.base, 4, &flash), Ok(()), "{}", name);
+            assert_eq!(check_poke(a.end() - 4, 4, &flash), Ok(()), "{}", name);
+            assert_eq!(check_poke(a.base, a.size, &flash), Ok(()), "{}", name);
+            assert_eq!(check_poke(a.base - 1, 4, &flash), NOT_SCRATCH, "{}", name);
+            assert_eq!(check_poke(a.end() - 2, 4, &flash), NOT_SCRATCH, "{}", name);
+        }
+    }
+
+    // Everything else: the flash windows, the boot log among them, the
+    // SPL image and the RAM the SPL runs on, each for what it is.
+    #[test]
+    fn poke_refused() {
+        let flash = units();
+        let meta = FlashOffset::new(board::META_OFFSET).to_phys(flash[board::META_UNIT].base).get();
+        assert_eq!(check_poke(meta, 4, &flash), Err("boot log"));
+        assert_eq!(check_poke(meta + board::META_SIZE - 1, 1, &flash), Err("boot log"));
+        for f in &flash {
+            for addr in [f.base.get(), f.base.get() + f.size / 2, f.base.get() + f.size - 1] {
+                let log = (meta..meta + board::META_SIZE).contains(&addr);
+                let expected = if log { Err("boot log") } else { Err("flash window") };
+                assert_eq!(check_poke(addr, 1, &flash), expected, "0x{:x}", addr);
+            }
+        }
+        for a in ram_areas().iter().filter(|a| !POKE_AREAS.contains(&a.name)) {
+            let expected = if a.name == "spl image" { Err("SPL image") } else { NOT_SCRATCH };
+            assert_eq!(check_poke(a.base, 1, &flash), expected, "{}", a.name);
+            assert_eq!(check_poke(a.end() - 1, 1, &flash), expected, "{}", a.name);
+        }
+        assert_eq!(check_poke(usize::MAX - 1, 4, &flash), Err("wraps around"));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::addr::FlashOffset;
+use crate::bootmeta::MAX_BANKS;
+use crate::crc32::crc32;
+use crate::flash_intel::IntelFlash;
+use crate::image::SplImageHeader;
+use crate::{banks, board, layout};
+use crate::{slog_debug, slog_error, slog_info, slog_warn};
+
+// The flash layout descriptor: how the provisioning tool (see
+// prepare_flash.sh) laid the flash out, in its own block at
+// board::LAYOUT_OFFSET, so an SPL older or newer than the tool can tell
+// instead of misreading the flash. Little-endian:
+//
+//   0x00  [8]   magic        b"SPL1LAYT"
+//   0x08  u16   major        LAYOUT_MAJOR the tool wrote for
+//   0x0a  u16   minor        LAYOUT_MINOR likewise
+//   0x0c  u16   size         0x100
+//   0x0e  u16   image        bank image header version it writes
+//   0x10  [16]  tool         its name and version, NUL-padded
+//   0x20  u32   meta_unit
+//   0x24  u32   banks        entries used in `bank`
+//   0x28  u64   meta_offset
+//   0x30  u64   meta_size
+//   0x38  u64   reserved     zero
+//   0x40  bank[8]: u32 unit, u32 size, u64 offset
+//   0xc0  [60]  reserved     zero
+//   0xfc  u32   crc          CRC-32 of bytes 0x00..0xfc
+//
+// Checked against what this SPL was built for (the board module) right
+// after the flash probe. The banks it lists are used where it puts them,
+// if they fit (see layout::check_bank()), whatever the versions say: the
+// tool wrote them there. A minor version of its own is only a warning.
+// Another major version, a damaged descriptor or a boot log somewhere
+// else is a layout this SPL doesn't know, so the boot log is read-only
+// for the boot: better no trial counts than writing over who knows what.
+// No descriptor at all (a flash provisioned before there was one) is
+// the compiled-in layout, as before.
+
+const MAGIC: [u8; 8] = *b"SPL1LAYT";
+/// Bumped when the flash is laid out in a way older SPLs would misread.
+const LAYOUT_MAJOR: u16 = 1;
+/// Bumped for additions older SPLs can do without.
+const LAYOUT_MINOR: u16 = 0;
+const SIZE: usize = 0x100;
+const BANKS_AT: usize = 0x40;
+const CRC_AT: usize = SIZE - 4;
+
+const _: () = assert!(BANKS_AT + 16 * MAX_BANKS <= CRC_AT, "layout descriptor: too many banks");
+const _: () = assert!(SIZE <= board::LAYOUT_SIZE);
+
+/// How the descriptor on the flash compares with this SPL's layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skew {
+    /// None there: the compiled-in layout.
+    Absent,
+    /// The magic, but a bad CRC.
+    Damaged,
+    Match,
+    /// Same major version, another minor one.
+    Minor { theirs: u16 },
+    /// Another major version.
+    Major { theirs: u16 },
+}
+
+/// What the boot makes of the descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub skew: Skew,
+    /// The boot log may be written.
+    pub meta_writes: bool,
+    /// Banks moved from their compiled-in place, by bit.
+    pub moved: u32,
+}
+
+/// One bank as the descriptor lists it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BankPlace {
+    unit: usize,
+    offset: FlashOffset,
+    size: usize,
+}
+
+/// The descriptor as read from the flash.
+pub struct Descriptor {
+    bytes: [u8; SIZE],
+}
+
+impl Descriptor {
+    fn u16_at(&self, at: usize) -> u16 {
+        u16::from_le_bytes(self.bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(&self, at: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, at: usize) -> u64 {
+        u64::from_le_bytes(self.bytes[at..at + 8].try_into().unwrap())
+    }
+
+    fn tool(&self) -> &str {
+        let field = &self.bytes[0x10..0x20];
+        let end = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+        core::str::from_utf8(&field[..end]).unwrap_or("?")
+    }
+
+    fn meta(&self) -> (usize, usize, usize) {
+        (self.u32_at(0x20) as usize, self.u64_at(0x28) as usize, self.u64_at(0x30) as usize)
+    }
+
+    fn banks(&self) -> usize {
+        self.u32_at(0x24) as usize
+    }
+
+    fn bank(&self, i: usize) -> BankPlace {
+        let at = BANKS_AT + 16 * i;
+        BankPlace {
+            unit: self.u32_at(at) as usize,
+            size: self.u32_at(at + 4) as usize,
+            offset: FlashOffset::new(self.u64_at(at + 8) as usize),
+        }
+    }
+
+    /// How it compares with this SPL, leaving the banks aside.
+    pub fn skew(&self) -> Skew {
+        if self.bytes[..8] != MAGIC {
+            return Skew::Absent;
+        }
+        if self.u32_at(CRC_AT) != crc32(&self.bytes[..CRC_AT]) {
+            return Skew::Damaged;
+        }
+        match (self.u16_at(0x08), self.u16_at(0x0a)) {
+            (LAYOUT_MAJOR, LAYOUT_MINOR) => Skew::Match,
+            (LAYOUT_MAJOR, minor) => Skew::Minor { theirs: minor },
+            (major, _) => Skew::Major { theirs: major },
+        }
+    }
+}
+
+/// Read the descriptor from its block of `flash`.
+pub fn read(flash: &IntelFlash) -> Descriptor {
+    let mut bytes = [0u8; SIZE];
+    flash.read_slice(FlashOffset::new(board::LAYOUT_OFFSET), &mut bytes);
+    Descriptor { bytes }
+}
+
+/// Read the descriptor, move the banks it places elsewhere (through
+/// banks::relocate()) and say what the boot makes of it. Before
+/// anything else reads the bank table.
+pub fn apply(flash: &[IntelFlash]) -> Verdict {
+    let desc = read(&flash[board::LAYOUT_UNIT]);
+    let skew = desc.skew();
+    let mut verdict = Verdict {
+        skew,
+        meta_writes: matches!(skew, Skew::Absent | Skew::Match | Skew::Minor { .. }),
+        moved: 0,
+    };
+    match skew {
+        Skew::Absent => {
+            slog_debug!("no flash layout descriptor: compiled-in layout");
+            return verdict;
+        }
+        Skew::Damaged => {
+            slog_error!("ERROR: flash layout descriptor damaged (bad CRC): compiled-in layout");
+            return verdict;
+        }
+        Skew::Match => slog_info!("flash layout {}.{}, by {}", LAYOUT_MAJOR, LAYOUT_MINOR, desc.tool()),
+        Skew::Minor { theirs } => slog_warn!(
+            "WARNING: flash layout {}.{} by {}, this SPL knows {}.{}",
+            LAYOUT_MAJOR,
+            theirs,
+            desc.tool(),
+            LAYOUT_MAJOR,
+            LAYOUT_MINOR
+        ),
+        Skew::Major { theirs } => slog_error!(
+            "ERROR: flash layout version {} by {}, this SPL knows {}: not writing the boot log",
+            theirs,
+            desc.tool(),
+            LAYOUT_MAJOR
+        ),
+    }
+    let image = desc.u16_at(0x0e);
+    if image != SplImageHeader::VERSION {
+        slog_warn!(
+            "WARNING: flash provisioned with version {} bank images, this SPL reads version {}",
+            image,
+            SplImageHeader::VERSION
+        );
+    }
+    let (unit, offset, size) = desc.meta();
+    if (unit, offset, size) != (board::META_UNIT, board::META_OFFSET, board::META_SIZE) {
+        slog_error!(
+            "ERROR: boot log at flash{} 0x{:x} ({} bytes) by the layout descriptor, built for flash{} 0x{:x}: \
+             not writing it",
+            unit,
+            offset,
+            size,
+            board::META_UNIT,
+            board::META_OFFSET
+        );
+        verdict.meta_writes = false;
+    }
+    let count = banks::table().len();
+    if desc.banks() != count {
+        slog_warn!("WARNING: layout descriptor lists {} banks, this SPL has {}", desc.banks(), count);
+    }
+    // A copy of each: relocate() rewrites the table.
+    for i in 0..desc.banks().min(count) {
+        let b = banks::table()[i];
+        let place = desc.bank(i);
+        if (place.unit, place.offset, place.size) == (b.unit, b.offset, b.size) {
+            continue;
+        }
+        match layout::check_bank(i, place.unit, place.offset, place.size, flash) {
+            Ok(()) => {
+                slog_info!(
+                    "bank {}: at flash{} 0x{:x} ({} KiB) by the layout descriptor",
+                    b.name,
+                    place.unit,
+                    place.offset,
+                    place.size / 1024
+                );
+                banks::relocate(i, place.unit, place.offset, place.size);
+                verdict.moved |= 1 << i;
+            }
+            Err(e) => slog_warn!(
+                "WARNING: bank {}: layout descriptor puts it at flash{} 0x{:x}: {:?}, kept at 0x{:x}",
+                b.name,
+                place.unit,
+                place.offset,
+                e,
+                b.offset
+            ),
+        }
+    }
+    verdict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::PhysAddr;
+
+    // The descriptor prepare_flash.sh writes for this SPL's layout, as
+    // version `major`.`minor`.
+    fn fixture(major: u16, minor: u16) -> [u8; SIZE] {
+        let mut b = [0u8; SIZE];
+        b[..8].copy_from_slice(&MAGIC);
+        b[0x08..0x0a].copy_from_slice(&major.to_le_bytes());
+        b[0x0a..0x0c].copy_from_slice(&minor.to_le_bytes());
+        b[0x0c..0x0e].copy_from_slice(&(SIZE as u16).to_le_bytes());
+        b[0x0e..0x10].copy_from_slice(&SplImageHeader::VERSION.to_le_bytes());
+        b[0x10..0x1f].copy_from_slice(b"prepare_flash 1");
+        b[0x20..0x24].copy_from_slice(&(board::META_UNIT as u32).to_le_bytes());
+        b[0x24..0x28].copy_from_slice(&(banks::table().len() as u32).to_le_bytes());
+        b[0x28..0x30].copy_from_slice(&(board::META_OFFSET as u64).to_le_bytes());
+        b[0x30..0x38].copy_from_slice(&(board::META_SIZE as u64).to_le_bytes());
+        for (i, bank) in banks::table().iter().enumerate() {
+            let at = BANKS_AT + 16 * i;
+            b[at..at + 4].copy_from_slice(&(bank.unit as u32).to_le_bytes());
+            b[at + 4..at + 8].copy_from_slice(&(bank.size as u32).to_le_bytes());
+            b[at + 8..at + 16].copy_from_slice(&(bank.offset.get() as u64).to_le_bytes());
+        }
+        seal(&mut b);
+        b
+    }
+
+    fn seal(b: &mut [u8; SIZE]) {
+        let crc = crc32(&b[..CRC_AT]);
+        b[CRC_AT..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // What apply() makes of `desc` in its block.
+    fn verdict(desc: Option<&[u8; SIZE]>) -> Verdict {
+        let mut image = vec![0xff; board::LAYOUT_OFFSET + SIZE];
+        if let Some(desc) = desc {
+            image[board::LAYOUT_OFFSET..].copy_from_slice(desc);
+        }
+        let unit = IntelFlash::new(PhysAddr::new(0x2000_0000), image.len(), board::LAYOUT_SIZE, image);
+        // Units before it, if any, aren't read.
+        let mut flash: Vec<_> =
+            (0..board::LAYOUT_UNIT).map(|_| IntelFlash::new(PhysAddr::new(0), 0, 1, Vec::new())).collect();
+        flash.push(unit);
+        apply(&flash)
+    }
+
+    #[test]
+    fn matching() {
+        let expected = Verdict {
+            skew: Skew::Match,
+            meta_writes: true,
+            moved: 0,
+        };
+        assert_eq!(verdict(Some(&fixture(LAYOUT_MAJOR, LAYOUT_MINOR))), expected);
+    }
+
+    // A newer tool's additions: a warning, the log still written.
+    #[test]
+    fn minor_skew() {
+        let theirs = LAYOUT_MINOR + 1;
+        let expected = Verdict {
+            skew: Skew::Minor { theirs },
+            meta_writes: true,
+            moved: 0,
+        };
+        assert_eq!(verdict(Some(&fixture(LAYOUT_MAJOR, theirs))), expected);
+    }
+
+    // A layout this SPL doesn't know, either way: the log read-only.
+    #[test]
+    fn major_skew() {
+        for theirs in [LAYOUT_MAJOR - 1, LAYOUT_MAJOR + 1] {
+            let expected = Verdict {
+                skew: Skew::Major { theirs },
+                meta_writes: false,
+                moved: 0,
+            };
+            assert_eq!(verdict(Some(&fixture(theirs, LAYOUT_MINOR))), expected);
+        }
+    }
+
+    // No descriptor is the compiled-in layout; a damaged one, or one
+    // with the log elsewhere, leaves the log read-only.
+    #[test]
+    fn absent_damaged_or_elsewhere() {
+        assert_eq!(verdict(None).skew, Skew::Absent);
+        assert!(verdict(None).meta_writes);
+
+        let mut damaged = fixture(LAYOUT_MAJOR, LAYOUT_MINOR);
+        damaged[0x10] ^= 1;
+        assert_eq!(verdict(Some(&damaged)).skew, Skew::Damaged);
+        assert!(!verdict(Some(&damaged)).meta_writes);
+
+        let mut elsewhere = fixture(LAYOUT_MAJOR, LAYOUT_MINOR);
+        elsewhere[0x28..0x30].copy_from_slice(&((board::META_OFFSET - board::META_SIZE) as u64).to_le_bytes());
+        seal(&mut elsewhere);
+        assert_eq!(verdict(Some(&elsewhere)).skew, Skew::Match);
+        assert!(!verdict(Some(&elsewhere)).meta_writes);
+    }
+}
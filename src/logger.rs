@@ -1,36 +1,254 @@
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-// UART logging (NS16550)
-const UART0_BASE: *mut u8 = 0x1000_0000 as *mut u8; // QEMU virt UART
+use crate::arch::csr;
+use crate::clint::{self, Deadline};
+use crate::image::ImageSource;
 
-#[inline(always)]
-pub fn uart_putc(b: u8) {
-    unsafe {
-        core::ptr::write_volatile(UART0_BASE, b);
+pub mod emergency;
+mod line;
+mod lock;
+mod ns16550;
+pub mod ringbuf;
+#[cfg(feature = "semihosting")]
+mod semihosting;
+mod sifive_uart;
+pub mod throttle;
+mod uart;
+#[cfg(feature = "uart-irq")]
+pub mod uart_irq;
+
+use lock::ConsoleGuard;
+pub use line::{hexdump_line, read_line, ConsoleWriter, HEXDUMP_WIDTH};
+pub use ns16550::uart_divisor;
+#[cfg(feature = "plic-selftest")]
+pub use ns16550::set_tx_irq;
+pub use uart::{probe as console_probe, Found};
+#[cfg(feature = "plic-selftest")]
+pub use uart::irq_capable;
+
+/// Boot stage name at the start of every log line.
+pub const STAGE: &str = "SPL1";
+
+/// Receive-side errors, from LSR (or our own timeout).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "semihosting", allow(dead_code))]
+pub enum RxError {
+    /// Characters were lost: the FIFO was full.
+    Overrun,
+    Parity,
+    Framing,
+    Break,
+    Timeout,
+}
+
+/// Where console output goes and input comes from.
+pub trait Console {
+    fn write_bytes(&self, bytes: &[u8]);
+    /// Next input byte, if one is waiting.
+    fn try_read(&self) -> Result<Option<u8>, RxError>;
+}
+
+// Backend picked at build time; static dispatch, so no vtable and, with
+// the feature off, no semihosting code at all. Which UART is picked at
+// boot (see uart.rs).
+#[cfg(feature = "semihosting")]
+const CONSOLE: semihosting::Semihosting = semihosting::Semihosting;
+#[cfg(not(feature = "semihosting"))]
+const CONSOLE: uart::Uart = uart::Uart;
+
+/// Write to the active console (and the RAM log).
+pub fn console_puts(s: &str) {
+    console_write(s.as_bytes());
+}
+
+/// Write one byte to the active console (and the RAM log).
+pub fn console_putc(b: u8) {
+    console_write(&[b]);
+}
+
+// Only the boot hart's output: no other hart runs the SPL, see arch.rs
+// (the trap and panic paths have the emergency console).
+fn console_write(bytes: &[u8]) {
+    if !crate::arch::on_boot_hart() {
+        return;
     }
+    ringbuf::push(bytes);
+    CONSOLE.write_bytes(bytes);
 }
 
-pub fn uart_puts(s: &str) {
-    for &b in s.as_bytes() {
-        uart_putc(b);
+/// Send one byte of a binary protocol (XMODEM): straight to the
+/// console, not into the RAM log.
+pub fn putc_raw(b: u8) {
+    CONSOLE.write_bytes(&[b]);
+}
+
+/// Send whatever console output is still queued and leave the UART
+/// polled, its interrupt off: before a handoff or a reset.
+pub fn quiesce() {
+    #[cfg(feature = "uart-irq")]
+    uart_irq::stop();
+}
+
+/// Replay the RAM log to the console (without logging it again).
+pub fn replay_log() {
+    ringbuf::for_each(|chunk| CONSOLE.write_bytes(chunk));
+}
+
+/// Wait for a console byte until `deadline`.
+pub fn getc_timeout(deadline: Deadline) -> Result<u8, RxError> {
+    loop {
+        if let Some(b) = CONSOLE.try_read()? {
+            return Ok(b);
+        }
+        if deadline.expired() {
+            return Err(RxError::Timeout);
+        }
+        core::hint::spin_loop();
     }
 }
 
-pub struct UartWriter;
+/// Dump `data` to the console, labelling the first byte `addr`.
+pub fn hexdump(addr: usize, data: &[u8]) {
+    let mut w = ConsoleWriter;
+    for (i, chunk) in data.chunks(HEXDUMP_WIDTH).enumerate() {
+        let _ = hexdump_line(&mut w, addr + i * HEXDUMP_WIDTH, chunk);
+    }
+}
 
-impl Write for UartWriter {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        uart_puts(s);
-        Ok(())
+/// Dump `len` bytes of flash (or another image source) at `offset` a
+/// line at a time, without a RAM copy. Lines are labelled with the CPU
+/// address when there is one.
+pub fn hexdump_flash(flash: &dyn ImageSource, offset: usize, len: usize) {
+    let mut w = ConsoleWriter;
+    let mut buf = [0u8; HEXDUMP_WIDTH];
+    let mut done = 0;
+    while done < len {
+        let n = core::cmp::min(HEXDUMP_WIDTH, len - done);
+        flash.read_slice(offset + done, &mut buf[..n]);
+        let _ = hexdump_line(&mut w, flash.log_addr(offset + done), &buf[..n]);
+        done += n;
     }
 }
 
+/// Log verbosity, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+/// Most verbose level compiled in, from the log-* cargo features (the
+/// most verbose one enabled wins; errors are always kept).
+pub const MAX_LEVEL: Level = if cfg!(feature = "log-debug") {
+    Level::Debug
+} else if cfg!(feature = "log-info") {
+    Level::Info
+} else {
+    Level::Error
+};
+
+/// Whether `level` messages are compiled in.
+pub const fn log_enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL as u8
+}
+
+/// Write one complete log line:
+/// `[SPL1 h<hart>] [secs.micros] [file:line] message`, the source
+/// location only in debug-level builds.
+pub fn log_line(file: &str, line: u32, args: fmt::Arguments) {
+    let us = clint::now_us();
+    let hart = csr::read_mhartid();
+    let _guard = ConsoleGuard::lock();
+    let mut w = ConsoleWriter;
+    let _ = write!(
+        w,
+        "[{} h{}] [{:5}.{:06}] ",
+        STAGE,
+        hart,
+        us / 1_000_000,
+        us % 1_000_000
+    );
+    if log_enabled(Level::Debug) {
+        let _ = write!(w, "[{}:{}] ", file, line);
+    }
+    let _ = w.write_fmt(args);
+    console_puts("\n");
+}
+
+// Cleared by the boot budget when time runs short (see budget.rs):
+// debug messages then stop, compiled in or not.
+static DEBUG_ON: AtomicBool = AtomicBool::new(true);
+
+/// Drop debug messages from now on.
+pub fn mute_debug() {
+    DEBUG_ON.store(false, Ordering::Relaxed);
+}
+
+/// log_line() for the slog_*! macros: errors always, other levels until
+/// their call site `site` is throttled (see throttle.rs), debug ones
+/// until mute_debug().
+pub fn log_site(level: Level, site: u32, file: &'static str, line: u32, args: fmt::Arguments) {
+    if level == Level::Error {
+        return log_line(file, line, args);
+    }
+    if level == Level::Debug && !DEBUG_ON.load(Ordering::Relaxed) {
+        return;
+    }
+    throttle::log(level, site, file, line, args)
+}
+
+/// Write one complete line as is: no prefix, whatever the log level.
+/// For output meant for scripts (see report.rs).
+pub fn raw_line(args: fmt::Arguments) {
+    let _guard = ConsoleGuard::lock();
+    let _ = ConsoleWriter.write_fmt(args);
+    console_puts("\n");
+}
+
+// Calls above MAX_LEVEL sit behind a constant `if false`: the arguments
+// are still type-checked, but the strings and formatting code are gone
+// from the binary. The others are throttled by call site.
+#[doc(hidden)]
 #[macro_export]
-macro_rules! slog {
-    ($($arg:tt)*) => {{
-        let mut w = $crate::logger::UartWriter;
-        let _ = core::fmt::write(&mut w, format_args!("[{}:{}] ", file!(), line!()));
-        let _ = core::fmt::write(&mut w, format_args!($($arg)*));
-        $crate::logger::uart_puts("\n");
+macro_rules! slog_at {
+    ($level:expr, $($arg:tt)*) => {{
+        const ON: bool = $crate::logger::log_enabled($level);
+        if ON {
+            const SITE: u32 = $crate::logger::throttle::site_id(file!(), line!());
+            // Only debug builds print (and so keep) the file names.
+            let file = if $crate::logger::log_enabled($crate::logger::Level::Debug) {
+                file!()
+            } else {
+                ""
+            };
+            $crate::logger::log_site($level, SITE, file, line!(), format_args!($($arg)*));
+        }
     }};
 }
+
+/// Something failed and the boot can't go on as intended.
+#[macro_export]
+macro_rules! slog_error {
+    ($($arg:tt)*) => { $crate::slog_at!($crate::logger::Level::Error, $($arg)*) };
+}
+
+/// Something is off, but we carry on (fallback, retry, default).
+#[macro_export]
+macro_rules! slog_warn {
+    ($($arg:tt)*) => { $crate::slog_at!($crate::logger::Level::Warn, $($arg)*) };
+}
+
+/// Boot progress and decisions.
+#[macro_export]
+macro_rules! slog_info {
+    ($($arg:tt)*) => { $crate::slog_at!($crate::logger::Level::Info, $($arg)*) };
+}
+
+/// Details only useful while developing or debugging a board.
+#[macro_export]
+macro_rules! slog_debug {
+    ($($arg:tt)*) => { $crate::slog_at!($crate::logger::Level::Debug, $($arg)*) };
+}
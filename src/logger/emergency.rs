@@ -0,0 +1,202 @@
+// Emergency console, for the panic and trap paths: they may have
+// interrupted the logger mid-line, hold its lock, or run after its
+// state got trashed. Nothing here takes the console lock, touches the
+// RAM log, waits without a bound or needs core::fmt: bytes go straight
+// to the UART, LSR-polled (or to the semihosting host), and numbers
+// are rendered by hand.
+
+use core::fmt::{self, Write};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "semihosting")]
+use super::Console;
+use crate::arch::csr;
+
+// Set by the first panic. A panic in the panic handler (formatting the
+// message, recording it) only gets its location printed, then stops.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Start emergency output: interrupts off for good, and what the
+/// uart-irq FIFO still holds sent first (at most the FIFO's size,
+/// whatever state it is in), so the dump follows the log it cut off.
+pub fn begin() {
+    csr::write_mstatus(csr::read_mstatus().with_mie(false));
+    #[cfg(feature = "uart-irq")]
+    super::uart_irq::drain_bounded();
+}
+
+/// Write `bytes` to the console hardware, nothing else.
+pub fn write_bytes(bytes: &[u8]) {
+    #[cfg(feature = "semihosting")]
+    super::semihosting::Semihosting.write_bytes(bytes);
+    #[cfg(not(feature = "semihosting"))]
+    for &b in bytes {
+        super::uart::putc_polled(b);
+    }
+}
+
+pub fn puts(s: &str) {
+    write_bytes(s.as_bytes());
+}
+
+pub fn putc(b: u8) {
+    write_bytes(&[b]);
+}
+
+/// `v` as 0x and 16 hex digits, like the trap dump always printed it.
+pub fn put_hex(v: u64) {
+    let mut buf = *b"0x0000000000000000";
+    for (i, c) in buf[2..].iter_mut().enumerate() {
+        *c = b"0123456789abcdef"[(v >> (60 - 4 * i) & 0xf) as usize];
+    }
+    write_bytes(&buf);
+}
+
+/// `v` in decimal.
+pub fn put_dec(mut v: u64) {
+    let mut buf = [0u8; 20];
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (v % 10) as u8;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    write_bytes(&buf[i..]);
+}
+
+/// `core::fmt::Write` on the emergency console, for what only comes as
+/// fmt::Arguments (a panic message). Whoever uses it must survive a
+/// panic in the formatting.
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        puts(s);
+        Ok(())
+    }
+}
+
+/// The panic handler's report, up to the end of its line: where, then
+/// what (`text` if the message is plain, else `message` formatted),
+/// only the where for a panic in the handler itself. Returns whether it
+/// was one.
+pub fn report_panic(location: Option<&Location>, message: &dyn fmt::Display, text: Option<&str>) -> bool {
+    let nested = PANICKING.swap(true, Ordering::Relaxed);
+    begin();
+    puts(if nested { "\r\nPANIC in SPL1 panic handler" } else { "PANIC in SPL1" });
+    if let Some(loc) = location {
+        puts(" at ");
+        puts(loc.file());
+        putc(b':');
+        put_dec(loc.line().into());
+        putc(b':');
+        put_dec(loc.column().into());
+    }
+    if !nested {
+        puts(": ");
+        match text {
+            Some(s) => puts(s),
+            None => {
+                let _ = write!(Writer, "{}", message);
+            }
+        }
+    }
+    nested
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{lock, log_line, take_output};
+    use std::cell::Cell;
+    use std::panic;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // PANICKING and the panic hook are one for all the test threads.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    // Panics when formatted, as a log line's argument.
+    struct Boom;
+
+    impl fmt::Display for Boom {
+        fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+            panic!("boom")
+        }
+    }
+
+    // Formatting it reports a panic: the panic handler's own.
+    struct Nested<'a>(&'a Location<'a>, &'a Cell<bool>);
+
+    impl fmt::Display for Nested<'_> {
+        fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+            self.1.set(report_panic(Some(self.0), &"again", Some("again")));
+            Ok(())
+        }
+    }
+
+    fn at(loc: &Location) -> String {
+        format!(" at {}:{}:{}", loc.file(), loc.line(), loc.column())
+    }
+
+    #[test]
+    fn reported_under_the_lock() {
+        let _turn = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        PANICKING.store(false, Ordering::Relaxed);
+        // The SPL's handler, on the thread standing in for the hart: what
+        // it writes, and whether the lock was still held when it did.
+        let outer = Arc::new(panic::take_hook());
+        let other = Arc::clone(&outer);
+        panic::set_hook(Box::new(move |info| {
+            if thread::current().name() != Some("hart") {
+                return other(info);
+            }
+            let text = info.payload_as_str();
+            report_panic(info.location(), &text.unwrap_or("?"), text);
+            putc(if lock::held() { b'L' } else { b'-' });
+        }));
+        let (tx, rx) = mpsc::channel();
+        let hart = thread::Builder::new()
+            .name("hart".into())
+            .spawn(move || {
+                let caught = panic::catch_unwind(|| log_line(file!(), line!(), format_args!("{}", Boom)));
+                tx.send((caught.is_err(), take_output())).unwrap();
+            })
+            .unwrap();
+        // A report that waited for the lock would never come.
+        let report = rx.recv_timeout(Duration::from_secs(1));
+        drop(panic::take_hook());
+        if let Ok(outer) = Arc::try_unwrap(outer) {
+            panic::set_hook(outer);
+        }
+        let (panicked, output) = report.expect("panic report blocked on the console lock");
+        hart.join().unwrap();
+        assert!(panicked);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(&format!("PANIC in SPL1 at {}:", file!())), "{output:?}");
+        assert!(output.ends_with(": boomL"), "{output:?}");
+        assert!(!lock::held());
+    }
+
+    #[test]
+    fn nested_gets_the_location_only() {
+        let _turn = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        PANICKING.store(false, Ordering::Relaxed);
+        take_output();
+        let first = Location::caller();
+        let second = Location::caller();
+        let nested = Cell::new(false);
+        assert!(!report_panic(Some(first), &Nested(second, &nested), None));
+        assert!(nested.get());
+        let output = String::from_utf8(take_output()).unwrap();
+        let want = format!("PANIC in SPL1{}: \r\nPANIC in SPL1 panic handler{}", at(first), at(second));
+        assert_eq!(output, want);
+        PANICKING.store(false, Ordering::Relaxed);
+    }
+}
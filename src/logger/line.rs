@@ -0,0 +1,69 @@
+use core::fmt::{self, Write};
+
+use super::{console_putc, console_puts, getc_timeout, RxError};
+use crate::clint::Deadline;
+
+// Line input and formatted output over whichever console the logger
+// has: the same code behind the UART and behind the simulator's
+// scripted one (see src/sim/logger.rs).
+
+/// Read a line into `buf`, echoing it, until CR or LF (not stored).
+/// Backspace/DEL erase the last character; input past the end of `buf`
+/// is dropped. Returns the line length.
+pub fn read_line(buf: &mut [u8], deadline: Deadline) -> Result<usize, RxError> {
+    let mut len = 0;
+    loop {
+        match getc_timeout(deadline)? {
+            b'\r' | b'\n' => {
+                console_puts("\n");
+                return Ok(len);
+            }
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                console_puts("\x08 \x08");
+            }
+            0x08 | 0x7f => {}
+            b if len < buf.len() => {
+                buf[len] = b;
+                len += 1;
+                console_putc(b);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `core::fmt::Write` adapter for the active console.
+pub struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        console_puts(s);
+        Ok(())
+    }
+}
+
+/// Bytes per hexdump line.
+pub const HEXDUMP_WIDTH: usize = 16;
+
+/// Format one hexdump line (up to 16 bytes) labelled `addr`:
+/// `addr  xx xx .. xx  xx .. xx  |ascii|`, short lines padded so the
+/// ASCII gutter stays aligned.
+pub fn hexdump_line<W: Write>(w: &mut W, addr: usize, bytes: &[u8]) -> fmt::Result {
+    write!(w, "{:08x} ", addr)?;
+    for i in 0..HEXDUMP_WIDTH {
+        if i % 8 == 0 {
+            w.write_str(" ")?;
+        }
+        match bytes.get(i) {
+            Some(b) => write!(w, "{:02x} ", b)?,
+            None => w.write_str("   ")?,
+        }
+    }
+    w.write_str(" |")?;
+    for &b in bytes {
+        let c = if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' };
+        w.write_char(c)?;
+    }
+    w.write_str("|\n")
+}
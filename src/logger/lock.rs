@@ -0,0 +1,32 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Held while a log line is written, so lines from different harts don't
+// interleave. The trap and panic paths write without it: they may have
+// interrupted a holder. A u32 so the swap is a single amoswap.w (byte
+// atomics would need an LR/SC loop without Zabha).
+static CONSOLE_LOCK: AtomicU32 = AtomicU32::new(0);
+
+pub struct ConsoleGuard;
+
+impl ConsoleGuard {
+    pub fn lock() -> Self {
+        while CONSOLE_LOCK.swap(1, Ordering::Acquire) != 0 {
+            while CONSOLE_LOCK.load(Ordering::Relaxed) != 0 {
+                core::hint::spin_loop();
+            }
+        }
+        ConsoleGuard
+    }
+}
+
+/// Whether someone holds the lock now.
+#[cfg(test)]
+pub fn held() -> bool {
+    CONSOLE_LOCK.load(Ordering::Relaxed) != 0
+}
+
+impl Drop for ConsoleGuard {
+    fn drop(&mut self) {
+        CONSOLE_LOCK.store(0, Ordering::Release);
+    }
+}
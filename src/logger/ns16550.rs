@@ -0,0 +1,179 @@
+// NS16550 UART console driver.
+//
+// Still built with the semihosting console: uart_init() runs regardless
+// and the UART comes back as soon as the feature is off.
+#![cfg_attr(feature = "semihosting", allow(dead_code))]
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{Console, RxError};
+use crate::arch::probe;
+use crate::board;
+use crate::mmio::register_block;
+
+register_block! {
+    /// NS16550 registers (byte-wide, reg-shift 0).
+    pub(super) struct Regs {
+        /// Divisor latch low (DLAB=1).
+        dll: Reg8 @ 0,
+        /// Divisor latch high (DLAB=1).
+        dlm: Reg8 @ 1,
+        /// Receive buffer (read, DLAB=0).
+        rbr: Reg8 @ 0,
+        /// Transmit holding (write, DLAB=0).
+        thr: Reg8 @ 0,
+        /// Interrupt enable.
+        ier: Reg8 @ 1,
+        /// FIFO control (write).
+        fcr: Reg8 @ 2,
+        /// Line control.
+        lcr: Reg8 @ 3,
+        /// Modem control.
+        mcr: Reg8 @ 4,
+        /// Line status.
+        lsr: Reg8 @ 5,
+    }
+}
+
+const LSR_DR: u8 = 1 << 0; // data ready
+const LSR_OE: u8 = 1 << 1; // overrun
+const LSR_PE: u8 = 1 << 2; // parity error
+const LSR_FE: u8 = 1 << 3; // framing error
+const LSR_BI: u8 = 1 << 4; // break
+pub(super) const LSR_THRE: u8 = 1 << 5; // THR empty
+
+const LCR_8N1: u8 = 0x03; // 8 data bits, no parity, 1 stop bit
+const LCR_DLAB: u8 = 1 << 7; // divisor latch access: RBR/IER become DLL/DLM
+#[cfg_attr(not(feature = "uart-irq"), allow(dead_code))]
+const IER_ETBEI: u8 = 1 << 1; // THR empty interrupt
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+// LSR polls per character before giving up. Counted in iterations, not
+// mtime, so the console never depends on the CLINT being set up; at
+// 115200 baud a character takes ~87 us, far less than this.
+const TX_SPIN_LIMIT: u32 = 1_000_000;
+
+// Active UART base: the board's until uart_init() (see uart.rs).
+static UART_BASE: AtomicUsize = AtomicUsize::new(board::UART_BASE);
+
+// Set once THRE failed to show up in time: from then on we write
+// without waiting, so a dead UART costs one timeout instead of one per
+// character.
+static UART_STUCK: AtomicBool = AtomicBool::new(false);
+
+// The UART at UART_BASE, which only ever holds the board's or the one
+// uart.rs found.
+pub(super) fn regs() -> Regs {
+    unsafe { Regs::new(UART_BASE.load(Ordering::Relaxed)) }
+}
+
+/// Whether a read of `base`'s LSR goes through, without a trap dump if
+/// it doesn't. trap::init() must have run.
+pub fn present(base: usize) -> bool {
+    // Only the address is used, through the probe.
+    let lsr = unsafe { Regs::new(base) }.lsr().addr();
+    unsafe { probe::try_read_volatile::<u8>(lsr) }.is_ok()
+}
+
+/// Baud rate divisor for a 16550 fed with `clock_hz`, or None if `baud`
+/// can't be reached (zero, too fast, or divisor over 16 bits).
+pub const fn uart_divisor(clock_hz: u32, baud: u32) -> Option<u16> {
+    if baud == 0 {
+        return None;
+    }
+    // Round to nearest, like Linux and U-Boot do.
+    let div = (clock_hz as u64 + 8 * baud as u64) / (16 * baud as u64);
+    if div == 0 || div > u16::MAX as u64 {
+        None
+    } else {
+        Some(div as u16)
+    }
+}
+
+/// Program the 16550 at `base` for `baud` 8N1 with FIFOs on, and make it
+/// the one this driver talks to. An unreachable baud rate keeps whatever
+/// divisor the previous stage left.
+pub fn uart_init(base: usize, clock_hz: u32, baud: u32) {
+    // What is queued was meant for the old one.
+    #[cfg(feature = "uart-irq")]
+    super::uart_irq::flush();
+    UART_BASE.store(base, Ordering::Relaxed);
+    let regs = regs();
+    regs.ier().write(0);
+    if let Some(div) = uart_divisor(clock_hz, baud) {
+        regs.lcr().write(LCR_DLAB);
+        regs.dll().write(div as u8);
+        regs.dlm().write((div >> 8) as u8);
+    }
+    regs.lcr().write(LCR_8N1);
+    regs.fcr().write(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+}
+
+/// Turn the THR-empty interrupt on or off.
+#[cfg_attr(not(any(feature = "uart-irq", feature = "plic-selftest")), allow(dead_code))]
+pub fn set_tx_irq(on: bool) {
+    let ier = regs().ier();
+    let v = ier.read();
+    ier.write(if on { v | IER_ETBEI } else { v & !IER_ETBEI });
+}
+
+pub(super) fn uart_putc(b: u8) {
+    let regs = regs();
+    if !UART_STUCK.load(Ordering::Relaxed) {
+        let mut spins = 0;
+        while regs.lsr().read() & LSR_THRE == 0 {
+            spins += 1;
+            if spins == TX_SPIN_LIMIT {
+                UART_STUCK.store(true, Ordering::Relaxed);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    regs.thr().write(b);
+}
+
+/// Return the next received byte, if any. A byte flagged with a line
+/// error is read out (so the FIFO advances) and reported as the error.
+pub fn uart_try_getc() -> Result<Option<u8>, RxError> {
+    let regs = regs();
+    let lsr = regs.lsr().read();
+    if lsr & LSR_DR == 0 {
+        return Ok(None);
+    }
+    let b = regs.rbr().read();
+    // Reading LSR cleared these; report the most specific one.
+    if lsr & LSR_BI != 0 {
+        Err(RxError::Break)
+    } else if lsr & LSR_FE != 0 {
+        Err(RxError::Framing)
+    } else if lsr & LSR_PE != 0 {
+        Err(RxError::Parity)
+    } else if lsr & LSR_OE != 0 {
+        Err(RxError::Overrun)
+    } else {
+        Ok(Some(b))
+    }
+}
+
+/// The NS16550 uart_init() set up.
+pub struct Ns16550;
+
+impl Console for Ns16550 {
+    fn write_bytes(&self, bytes: &[u8]) {
+        for &b in bytes {
+            #[cfg(feature = "uart-irq")]
+            if super::uart_irq::enqueue(b) {
+                continue;
+            }
+            uart_putc(b);
+        }
+    }
+
+    fn try_read(&self) -> Result<Option<u8>, RxError> {
+        uart_try_getc()
+    }
+}
+
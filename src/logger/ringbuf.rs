@@ -0,0 +1,154 @@
+// RAM copy of everything written to the console, for the next stage or
+// a JTAG dump to pick up (its location is advertised in /chosen as
+// "spl,log-ring").
+//
+// Layout at `region().0`, all little-endian u32s then the data:
+//
+//   0x00  magic     b"SLOG"
+//   0x04  capacity  bytes of data
+//   0x08  head      next write index into data
+//   0x0c  wraps     times head went past the end
+//   0x10  data[capacity]
+//
+// The ring sits in its own NOLOAD section that _start doesn't clear, so
+// a warm reset finds the previous boot's log intact; it is kept and the
+// new boot appends to it. Where this boot's part starts is remembered,
+// to send it to a console found late (see uart.rs).
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SLOG");
+const CAPACITY: usize = 16 * 1024;
+
+#[repr(C, align(8))]
+struct Ring {
+    magic: u32,
+    capacity: u32,
+    head: u32,
+    wraps: u32,
+    data: [u8; CAPACITY],
+}
+
+#[unsafe(link_section = ".spl_log")]
+static mut RING: Ring = Ring {
+    magic: 0,
+    capacity: 0,
+    head: 0,
+    wraps: 0,
+    data: [0; CAPACITY],
+};
+
+// Bytes ever pushed, wraps included, when this boot's log started.
+// Only the boot hart runs Rust code, so a plain static is enough.
+static mut BOOT_START: u64 = 0;
+
+fn ring() -> *mut Ring {
+    &raw mut RING
+}
+
+// Bytes ever pushed, as far as the ring can tell.
+fn position() -> u64 {
+    let r = ring();
+    unsafe { (*r).wraps as u64 * CAPACITY as u64 + (*r).head as u64 }
+}
+
+fn valid(r: *const Ring) -> bool {
+    unsafe { (*r).magic == MAGIC && (*r).capacity as usize == CAPACITY && ((*r).head as usize) < CAPACITY }
+}
+
+/// Set up the ring. Returns the number of bytes kept from the previous
+/// boot, or None if there was no valid log (cold boot) and it was reset.
+pub fn init() -> Option<usize> {
+    let r = ring();
+    if valid(r) {
+        let kept = len();
+        push(b"\n--- log from the previous boot ends here ---\n");
+        unsafe { (&raw mut BOOT_START).write(position()) };
+        return Some(kept);
+    }
+    unsafe {
+        (*r).capacity = CAPACITY as u32;
+        (*r).head = 0;
+        (*r).wraps = 0;
+        (*r).magic = MAGIC;
+        (&raw mut BOOT_START).write(0);
+    }
+    None
+}
+
+/// Append `bytes`, overwriting the oldest ones once full. Dropped until
+/// init() has run.
+pub fn push(bytes: &[u8]) {
+    let r = ring();
+    if !valid(r) {
+        return;
+    }
+    unsafe {
+        let data = (&raw mut (*r).data).cast::<u8>();
+        let mut head = (*r).head as usize;
+        for &b in bytes {
+            data.add(head).write(b);
+            head += 1;
+            if head == CAPACITY {
+                head = 0;
+                (*r).wraps = (*r).wraps.wrapping_add(1);
+            }
+        }
+        (*r).head = head as u32;
+    }
+}
+
+/// Bytes currently held.
+pub fn len() -> usize {
+    let r = ring();
+    unsafe {
+        if (*r).wraps == 0 {
+            (*r).head as usize
+        } else {
+            CAPACITY
+        }
+    }
+}
+
+/// Address and size (header included) of the ring.
+pub fn region() -> (usize, usize) {
+    (ring() as usize, core::mem::size_of::<Ring>())
+}
+
+/// Call `f` with the logged bytes, oldest first (in up to two pieces).
+pub fn for_each(mut f: impl FnMut(&[u8])) {
+    let r = ring();
+    if !valid(r) {
+        return;
+    }
+    let (data, head, wrapped) = unsafe {
+        (
+            core::slice::from_raw_parts((&raw const (*r).data).cast::<u8>(), CAPACITY),
+            (*r).head as usize,
+            (*r).wraps != 0,
+        )
+    };
+    if wrapped {
+        f(&data[head..]);
+    }
+    f(&data[..head]);
+}
+
+/// Same as for_each(), for this boot's part only (as much of it as the
+/// ring still holds).
+pub fn for_each_since_boot(mut f: impl FnMut(&[u8])) {
+    let r = ring();
+    if !valid(r) {
+        return;
+    }
+    let start = unsafe { (&raw const BOOT_START).read() };
+    let n = position().saturating_sub(start).min(CAPACITY as u64) as usize;
+    let (data, head) = unsafe {
+        (
+            core::slice::from_raw_parts((&raw const (*r).data).cast::<u8>(), CAPACITY),
+            (*r).head as usize,
+        )
+    };
+    if n > head {
+        f(&data[CAPACITY - (n - head)..]);
+    }
+    f(&data[head.saturating_sub(n)..head]);
+}
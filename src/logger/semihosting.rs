@@ -0,0 +1,57 @@
+use core::arch::asm;
+
+use super::{Console, RxError};
+
+// RISC-V semihosting: the magic three-instruction sequence around an
+// ebreak tells the debugger (or QEMU with -semihosting) to service the
+// request in a0 with the argument block in a1, instead of taking a
+// breakpoint. The sequence must be uncompressed, in this order and
+// within one page (hence the alignment).
+//
+// Without a semihosting host the ebreak is a plain breakpoint trap, so
+// only build this in for runs that provide one.
+
+const SYS_WRITE0: usize = 0x04;
+
+// Bytes per SYS_WRITE0 call (plus the NUL it needs).
+const CHUNK: usize = 64;
+
+fn call(op: usize, arg: usize) -> usize {
+    let mut ret = op;
+    unsafe {
+        asm!(
+            ".option push",
+            ".option norvc",
+            ".balign 16",
+            "slli x0, x0, 0x1f",
+            "ebreak",
+            "srai x0, x0, 7",
+            ".option pop",
+            inout("a0") ret,
+            in("a1") arg,
+            options(nostack, preserves_flags)
+        );
+    }
+    ret
+}
+
+/// Console on the semihosting host's stdout.
+pub struct Semihosting;
+
+impl Console for Semihosting {
+    fn write_bytes(&self, bytes: &[u8]) {
+        // SYS_WRITE0 prints a C string: copy out NUL-terminated pieces.
+        let mut buf = [0u8; CHUNK + 1];
+        for piece in bytes.chunks(CHUNK) {
+            buf[..piece.len()].copy_from_slice(piece);
+            buf[piece.len()] = 0;
+            call(SYS_WRITE0, buf.as_ptr() as usize);
+        }
+    }
+
+    /// Semihosting only has a blocking SYS_READC, so there is never
+    /// input waiting as far as we are concerned.
+    fn try_read(&self) -> Result<Option<u8>, RxError> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,105 @@
+// SiFive UART console driver (FU540 UART0, QEMU sifive_u): TX and RX
+// FIFOs behind two data registers, no line status. A byte goes out once
+// txdata stops reading "full"; rxdata reads "empty" or the next byte.
+#![cfg_attr(feature = "semihosting", allow(dead_code))]
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{Console, RxError};
+use crate::arch::probe;
+use crate::board;
+use crate::mmio::register_block;
+
+register_block! {
+    /// SiFive UART registers (32-bit).
+    struct Regs {
+        /// Transmit data, FULL in bit 31 on reads.
+        txdata: Reg32 @ 0x00,
+        /// Receive data, EMPTY in bit 31.
+        rxdata: Reg32 @ 0x04,
+        /// Transmit control.
+        txctrl: Reg32 @ 0x08,
+        /// Receive control.
+        rxctrl: Reg32 @ 0x0c,
+        /// Interrupt enable.
+        ie: Reg32 @ 0x10,
+        /// Baud rate divisor.
+        div: Reg32 @ 0x18,
+    }
+}
+
+const DATA_FULL: u32 = 1 << 31; // txdata: TX FIFO full
+const DATA_EMPTY: u32 = 1 << 31; // rxdata: RX FIFO empty
+const TXCTRL_TXEN: u32 = 1 << 0;
+const RXCTRL_RXEN: u32 = 1 << 0;
+
+// txdata polls per character before giving up, as for the 16550.
+const TX_SPIN_LIMIT: u32 = 1_000_000;
+
+// Active UART base: the board's until uart_init(), for the emergency
+// console on a board with this UART.
+static UART_BASE: AtomicUsize = AtomicUsize::new(board::UART_BASE);
+
+// Same as the 16550's: one timeout for a dead UART, not one a character.
+static UART_STUCK: AtomicBool = AtomicBool::new(false);
+
+// The UART at the base uart_init() got.
+fn regs() -> Regs {
+    unsafe { Regs::new(UART_BASE.load(Ordering::Relaxed)) }
+}
+
+/// Whether a read of `base`'s txctrl goes through, without a trap dump
+/// if it doesn't. trap::init() must have run.
+pub fn present(base: usize) -> bool {
+    // Only the address is used, through the probe.
+    let txctrl = unsafe { Regs::new(base) }.txctrl().addr();
+    unsafe { probe::try_read_volatile::<u32>(txctrl) }.is_ok()
+}
+
+/// Set up the SiFive UART at `base` for `baud` (8N1, one stop bit, the
+/// only framing it has) and make it the one this driver talks to. An
+/// unreachable baud rate keeps whatever divisor the previous stage left.
+pub fn uart_init(base: usize, clock_hz: u32, baud: u32) {
+    UART_BASE.store(base, Ordering::Relaxed);
+    let regs = regs();
+    regs.ie().write(0);
+    // f_baud = f_in / (div + 1)
+    if let Some(div) = clock_hz.checked_div(baud).and_then(|d| d.checked_sub(1)) {
+        regs.div().write(div);
+    }
+    regs.txctrl().write(TXCTRL_TXEN);
+    regs.rxctrl().write(RXCTRL_RXEN);
+}
+
+pub(super) fn uart_putc(b: u8) {
+    let txdata = regs().txdata();
+    if !UART_STUCK.load(Ordering::Relaxed) {
+        let mut spins = 0;
+        while txdata.read() & DATA_FULL != 0 {
+            spins += 1;
+            if spins == TX_SPIN_LIMIT {
+                UART_STUCK.store(true, Ordering::Relaxed);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    txdata.write(b as u32);
+}
+
+/// The SiFive UART uart_init() set up.
+pub struct SifiveUart;
+
+impl Console for SifiveUart {
+    fn write_bytes(&self, bytes: &[u8]) {
+        for &b in bytes {
+            uart_putc(b);
+        }
+    }
+
+    /// No line status on this UART: a byte is a byte.
+    fn try_read(&self) -> Result<Option<u8>, RxError> {
+        let v = regs().rxdata().read();
+        Ok((v & DATA_EMPTY == 0).then_some(v as u8))
+    }
+}
@@ -0,0 +1,217 @@
+// Per call site throttle for the slog_*! macros: a failure path that
+// loops (flash retries, a flaky UART) would otherwise print the same
+// warning thousands of times, drowning the log and slowing the boot at
+// ~87 us a character. Each call site gets a compile-time id, a hash of
+// its file and line; past LIMIT messages from one site the rest are only
+// counted, and summary() logs the counts before the handoff.
+//
+// Errors are never throttled. A fixed table, no allocation: sites past
+// the first SITES to log print unthrottled. The info and debug sites
+// of a normal boot outnumber the table, so WARN_SITES of its slots are
+// kept for warnings, the ones that loop.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use super::Level;
+
+/// Messages a call site prints before it goes quiet.
+pub const LIMIT: u32 = 32;
+// Call sites tracked, and of those the slots only warning sites take
+// (the last ones).
+const SITES: usize = 32;
+const WARN_SITES: usize = 16;
+
+// Site ids, 0 for a free slot; a slot, once taken, is never given back.
+// Published last, once the site's FILE and LINE are in.
+static ID: [AtomicU32; SITES] = [const { AtomicU32::new(0) }; SITES];
+// Set by the hart taking the slot, before it publishes the id.
+static TAKEN: [AtomicBool; SITES] = [const { AtomicBool::new(false) }; SITES];
+// Messages from the site, printed or not.
+static COUNT: [AtomicU32; SITES] = [const { AtomicU32::new(0) }; SITES];
+// Where the site is, for summary(): its file (empty in builds that don't
+// keep file names) and line.
+static FILE: [AtomicPtr<u8>; SITES] = [const { AtomicPtr::new(core::ptr::null_mut()) }; SITES];
+static FILE_LEN: [AtomicUsize; SITES] = [const { AtomicUsize::new(0) }; SITES];
+static LINE: [AtomicU32; SITES] = [const { AtomicU32::new(0) }; SITES];
+
+/// A call site's id: FNV-1a of `file` and `line`, never 0. Evaluated at
+/// compile time by the macros, so the file name only stays in the binary
+/// if the log line prints it.
+pub const fn site_id(file: &str, line: u32) -> u32 {
+    const PRIME: u32 = 0x0100_0193;
+    let mut h: u32 = 0x811c_9dc5;
+    let bytes = file.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        h = (h ^ bytes[i] as u32).wrapping_mul(PRIME);
+        i += 1;
+    }
+    let line = line.to_le_bytes();
+    let mut i = 0;
+    while i < line.len() {
+        h = (h ^ line[i] as u32).wrapping_mul(PRIME);
+        i += 1;
+    }
+    if h == 0 { 1 } else { h }
+}
+
+/// What to do with one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admit {
+    Print,
+    /// Print it, and say the site goes quiet: it is the LIMITth.
+    Last,
+    Drop,
+}
+
+// The slot of `site`, a `level` one, taken for it if it has none; None
+// if the table is full (for its level). A warning site looks in its own
+// slots first, any other only in the shared ones: a site always looks
+// in the same order, so it finds its slot before any free one.
+fn slot(level: Level, site: u32, file: &'static str, line: u32) -> Option<usize> {
+    let shared = 0..SITES - WARN_SITES;
+    let order = match level {
+        Level::Warn => (shared.end..SITES).chain(shared),
+        _ => (shared.end..shared.end).chain(shared),
+    };
+    for i in order {
+        match ID[i].load(Ordering::Acquire) {
+            id if id == site => return Some(i),
+            0 => {
+                // Another hart is taking it and hasn't published whose it
+                // is: maybe this site's, so this message goes unthrottled.
+                if TAKEN[i].swap(true, Ordering::Relaxed) {
+                    return None;
+                }
+                FILE[i].store(file.as_ptr().cast_mut(), Ordering::Relaxed);
+                FILE_LEN[i].store(file.len(), Ordering::Relaxed);
+                LINE[i].store(line, Ordering::Relaxed);
+                ID[i].store(site, Ordering::Release);
+                return Some(i);
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Count a message from `site` (a `level` one, at `file`:`line`) and say
+/// whether it gets printed.
+fn admit(level: Level, site: u32, file: &'static str, line: u32) -> Admit {
+    let Some(i) = slot(level, site, file, line) else {
+        return Admit::Print;
+    };
+    let n = COUNT[i].fetch_add(1, Ordering::Relaxed).saturating_add(1);
+    match n {
+        n if n < LIMIT => Admit::Print,
+        LIMIT => Admit::Last,
+        _ => Admit::Drop,
+    }
+}
+
+/// Log a `level` message (not an error) from `site` unless the site has
+/// gone quiet, saying so with its last one.
+pub fn log(level: Level, site: u32, file: &'static str, line: u32, args: fmt::Arguments) {
+    match admit(level, site, file, line) {
+        Admit::Print => super::log_line(file, line, args),
+        Admit::Last => {
+            super::log_line(file, line, args);
+            super::log_line(file, line, format_args!("... suppressing further messages from this site"));
+        }
+        Admit::Drop => {}
+    }
+}
+
+/// Messages dropped so far, all sites together.
+pub fn suppressed() -> u32 {
+    COUNT.iter().map(|c| c.load(Ordering::Relaxed).saturating_sub(LIMIT)).fold(0, u32::saturating_add)
+}
+
+/// Log how many messages each throttled site dropped. Before the handoff:
+/// the last word on them.
+pub fn summary() {
+    for i in 0..SITES {
+        if ID[i].load(Ordering::Acquire) == 0 {
+            continue;
+        }
+        let dropped = COUNT[i].load(Ordering::Relaxed).saturating_sub(LIMIT);
+        if dropped == 0 {
+            continue;
+        }
+        let ptr = FILE[i].load(Ordering::Relaxed);
+        let file = if ptr.is_null() {
+            ""
+        } else {
+            let len = FILE_LEN[i].load(Ordering::Relaxed);
+            core::str::from_utf8(unsafe { core::slice::from_raw_parts(ptr, len) }).unwrap_or("?")
+        };
+        let line = LINE[i].load(Ordering::Relaxed);
+        super::log_line(
+            file,
+            line,
+            format_args!("log: {} more messages from line {} suppressed", dropped, line),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::take_lines;
+    use std::sync::Mutex;
+
+    // One table for every test.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    const HERE: &str = "src/flash_intel.rs";
+    const NOTICE: &str = "... suppressing further messages from this site";
+
+    fn at(level: Level, line: u32, msg: fmt::Arguments) {
+        log(level, site_id(HERE, line), HERE, line, msg);
+    }
+
+    // A warning in a loop: LIMIT lines, the notice with the last, then
+    // only a count, which summary() gives.
+    #[test]
+    fn repeated_warning() {
+        let _serial = SERIAL.lock().unwrap();
+        let (line, n) = (9001, 100);
+        let before = suppressed();
+        take_lines();
+        for i in 0..n {
+            at(Level::Warn, line, format_args!("WARNING: retry {}", i));
+        }
+        let mut expected: Vec<_> = (0..LIMIT).map(|i| format!("WARNING: retry {}", i)).collect();
+        expected.push(NOTICE.into());
+        assert_eq!(take_lines(), expected);
+        assert_eq!(suppressed() - before, n - LIMIT);
+
+        summary();
+        let count = format!("log: {} more messages from line {} suppressed", n - LIMIT, line);
+        assert!(take_lines().contains(&count));
+        at(Level::Warn, line, format_args!("WARNING: retry {}", n));
+        assert_eq!(take_lines(), Vec::<String>::new());
+    }
+
+    // More info sites than the table holds: those past the shared slots
+    // print unthrottled, and a warning still gets a slot.
+    #[test]
+    fn warnings_keep_their_slots() {
+        let _serial = SERIAL.lock().unwrap();
+        take_lines();
+        for line in 10_000..10_000 + SITES as u32 {
+            for _ in 0..=LIMIT {
+                at(Level::Info, line, format_args!("step"));
+            }
+            let lines = take_lines();
+            assert_eq!(lines.len() as u32, LIMIT + 1);
+            let tracked = line < 10_000 + (SITES - WARN_SITES) as u32;
+            assert_eq!(lines.last().unwrap() == NOTICE, tracked, "info site {}", line);
+        }
+        for _ in 0..=LIMIT {
+            at(Level::Warn, 9002, format_args!("WARNING: again"));
+        }
+        assert_eq!(take_lines().last().unwrap(), NOTICE);
+    }
+}
@@ -0,0 +1,159 @@
+// Console UART picked at boot rather than at build time, so one binary
+// talks on any board's UART (both QEMU machines, for QA). probe() tries,
+// in order:
+//
+//  - the DTB a1 points to, if valid: the first node compatible with a
+//    UART we have a driver for
+//  - each board's UART (board::UARTS) that answers a read, through the
+//    trap-and-recover probe (arch/probe.rs): no trap dump if not there
+//  - the board's own, blindly
+//
+// Until then nothing goes to a UART: what is logged only goes to the RAM
+// log, and is sent once the UART is known.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::ns16550::{self, Ns16550};
+use super::sifive_uart::{self, SifiveUart};
+use super::{ringbuf, Console, RxError};
+use crate::board;
+use crate::dtb::Fdt;
+
+/// UARTs the console has a driver for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartKind {
+    Ns16550,
+    Sifive,
+}
+
+impl UartKind {
+    /// Its DTB `compatible`, as the board files give it.
+    pub const fn compatible(self) -> &'static str {
+        match self {
+            UartKind::Ns16550 => "ns16550a",
+            UartKind::Sifive => "sifive,uart0",
+        }
+    }
+
+    fn from_compatible(compat: &str) -> Option<Self> {
+        [UartKind::Ns16550, UartKind::Sifive].into_iter().find(|k| k.compatible() == compat)
+    }
+
+    fn present(self, base: usize) -> bool {
+        match self {
+            UartKind::Ns16550 => ns16550::present(base),
+            UartKind::Sifive => sifive_uart::present(base),
+        }
+    }
+}
+
+/// How probe() found the console UART.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Found {
+    Dtb,
+    Probed,
+    /// Nothing answered: the board's, in case it's there anyway.
+    Default,
+}
+
+/// The console UART probe() settled on.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsoleUart {
+    pub kind: UartKind,
+    pub base: usize,
+    pub found: Found,
+}
+
+// UartKind as stored, 0 until probe().
+const NONE: u8 = 0;
+static KIND: AtomicU8 = AtomicU8::new(NONE);
+
+const fn code(kind: UartKind) -> u8 {
+    kind as u8 + 1
+}
+
+/// The console UART, None until probe().
+pub fn kind() -> Option<UartKind> {
+    match KIND.load(Ordering::Relaxed) {
+        n if n == code(UartKind::Ns16550) => Some(UartKind::Ns16550),
+        n if n == code(UartKind::Sifive) => Some(UartKind::Sifive),
+        _ => None,
+    }
+}
+
+// The board's own UART: what the emergency console uses before probe().
+fn board_kind() -> UartKind {
+    UartKind::from_compatible(board::UART_COMPATIBLE).unwrap_or(UartKind::Ns16550)
+}
+
+/// Find the console UART (see above), set it up and send it what was
+/// logged so far. `fdt` is the DTB the SPL was given, if valid.
+/// trap::init() must have run.
+pub fn probe(fdt: Option<&Fdt>) -> ConsoleUart {
+    // A board's UART: its kind, base and input clock.
+    let known = || {
+        board::UARTS
+            .iter()
+            .filter_map(|&(compat, base, clock)| Some((UartKind::from_compatible(compat)?, base, clock)))
+    };
+    let in_dtb = fdt.and_then(|f| {
+        known().find_map(|(kind, _, clock)| match f.find_compatible_reg(kind.compatible()) {
+            Ok(Some(dev)) => Some((kind, dev.reg.base, clock)),
+            _ => None,
+        })
+    });
+    let (found, (kind, base, clock)) = match in_dtb {
+        Some(uart) => (Found::Dtb, uart),
+        None => match known().find(|&(kind, base, _)| kind.present(base)) {
+            Some(uart) => (Found::Probed, uart),
+            None => (Found::Default, (board_kind(), board::UART_BASE, board::UART_CLOCK_HZ)),
+        },
+    };
+    match kind {
+        UartKind::Ns16550 => ns16550::uart_init(base, clock, board::UART_BAUD),
+        UartKind::Sifive => sifive_uart::uart_init(base, clock, board::UART_BAUD),
+    }
+    KIND.store(code(kind), Ordering::Relaxed);
+    // The semihosting console had it all already.
+    if !cfg!(feature = "semihosting") {
+        ringbuf::for_each_since_boot(|chunk| Uart.write_bytes(chunk));
+    }
+    ConsoleUart { kind, base, found }
+}
+
+/// Whether the console is a 16550 on the board's UART interrupt: what
+/// the uart-irq code drives.
+#[cfg_attr(not(any(feature = "uart-irq", feature = "plic-selftest")), allow(dead_code))]
+pub fn irq_capable() -> bool {
+    kind() == Some(UartKind::Ns16550) && board_kind() == UartKind::Ns16550
+}
+
+/// Send one byte straight to the UART, polled: for the emergency
+/// console, which may run before probe() (the board's UART then).
+pub fn putc_polled(b: u8) {
+    match kind().unwrap_or_else(board_kind) {
+        UartKind::Ns16550 => ns16550::uart_putc(b),
+        UartKind::Sifive => sifive_uart::uart_putc(b),
+    }
+}
+
+/// The console UART, whichever it is; nothing before probe().
+pub struct Uart;
+
+impl Console for Uart {
+    fn write_bytes(&self, bytes: &[u8]) {
+        match kind() {
+            Some(UartKind::Ns16550) => Ns16550.write_bytes(bytes),
+            Some(UartKind::Sifive) => SifiveUart.write_bytes(bytes),
+            None => {}
+        }
+    }
+
+    fn try_read(&self) -> Result<Option<u8>, RxError> {
+        match kind() {
+            Some(UartKind::Ns16550) => Ns16550.try_read(),
+            Some(UartKind::Sifive) => SifiveUart.try_read(),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Interrupt-driven UART TX ("uart-irq"): console bytes go into a RAM
+// FIFO and the THR-empty interrupt, dispatched by the PLIC driver on the
+// boot hart, feeds them to the UART 16 at a time, so a log line costs a copy
+// instead of ~87 us a character at 115200 baud.
+//
+// Only the boot hart touches the FIFO: the writer with mstatus.MIE
+// cleared, the handler with it cleared by the trap. When interrupts are
+// off anyway (trap dump, panic) or the FIFO is full, the writer drains
+// it by polling, so output stays in order and nothing waits on an
+// interrupt that can't come. stop() flushes it and leaves the UART,
+// the PLIC source and mie.MEIE as it found them, before any handoff or
+// reset.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::ns16550;
+use crate::arch::csr;
+use crate::{board, plic};
+
+const FIFO_SIZE: usize = 4096;
+// Bytes the 16550 takes once THR is empty.
+const HW_FIFO: usize = 16;
+
+static mut FIFO: [u8; FIFO_SIZE] = [0; FIFO_SIZE];
+// Free-running: HEAD - TAIL bytes are queued.
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+static TAIL: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// What the TX interrupt did, for the log.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Bytes sent from the interrupt handler.
+    pub sent: usize,
+    /// Most bytes ever queued at once.
+    pub peak: usize,
+    pub size: usize,
+}
+
+fn queued() -> usize {
+    HEAD.load(Ordering::Relaxed).wrapping_sub(TAIL.load(Ordering::Relaxed))
+}
+
+fn pop() -> Option<u8> {
+    let tail = TAIL.load(Ordering::Relaxed);
+    if tail == HEAD.load(Ordering::Relaxed) {
+        return None;
+    }
+    let b = unsafe { (&raw const FIFO).cast::<u8>().add(tail % FIFO_SIZE).read() };
+    TAIL.store(tail.wrapping_add(1), Ordering::Relaxed);
+    Some(b)
+}
+
+fn push(b: u8) {
+    let head = HEAD.load(Ordering::Relaxed);
+    unsafe { (&raw mut FIFO).cast::<u8>().add(head % FIFO_SIZE).write(b) };
+    HEAD.store(head.wrapping_add(1), Ordering::Relaxed);
+    PEAK.fetch_max(queued(), Ordering::Relaxed);
+}
+
+// Send everything queued by polling. Interrupts must be off.
+fn drain_polled() {
+    while let Some(b) = pop() {
+        ns16550::uart_putc(b);
+    }
+}
+
+/// Send what is queued by polling, for the emergency console: at most
+/// FIFO_SIZE bytes, even if the indices are garbage. Interrupts must be
+/// off.
+pub fn drain_bounded() {
+    for _ in 0..FIFO_SIZE {
+        match pop() {
+            Some(b) => ns16550::uart_putc(b),
+            None => break,
+        }
+    }
+}
+
+/// Start sending console output from the TX interrupt, on the boot hart
+/// (unless the PLIC routes nothing to it, or the console isn't the
+/// board's 16550: output stays polled).
+pub fn start() {
+    if !plic::routed() || !super::uart::irq_capable() {
+        return;
+    }
+    plic::register(board::UART_IRQ, on_irq);
+    plic::set_priority(board::UART_IRQ, 1);
+    plic::enable(board::UART_IRQ);
+    ACTIVE.store(true, Ordering::Relaxed);
+    csr::write_mie(csr::read_mie() | csr::MIE_MEIE);
+    csr::write_mstatus(csr::read_mstatus().with_mie(true));
+}
+
+/// Flush the FIFO and go back to polled output, with the UART's TX
+/// interrupt, its PLIC source and mie.MEIE off.
+pub fn stop() {
+    let mstatus = csr::read_mstatus();
+    csr::write_mstatus(mstatus.with_mie(false));
+    if ACTIVE.swap(false, Ordering::Relaxed) {
+        drain_polled();
+        ns16550::set_tx_irq(false);
+        plic::disable(board::UART_IRQ);
+        plic::unregister(board::UART_IRQ);
+        csr::write_mie(csr::read_mie() & !csr::MIE_MEIE);
+    }
+    csr::write_mstatus(mstatus);
+}
+
+/// Send what is queued right away (e.g. before moving the console).
+pub fn flush() {
+    let mstatus = csr::read_mstatus();
+    csr::write_mstatus(mstatus.with_mie(false));
+    drain_polled();
+    csr::write_mstatus(mstatus);
+}
+
+/// Queue `b` for the interrupt to send. False if the caller must send
+/// it itself (not started, or interrupts off), after what was queued.
+pub fn enqueue(b: u8) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mstatus = csr::read_mstatus();
+    if !mstatus.mie() {
+        drain_polled();
+        return false;
+    }
+    csr::write_mstatus(mstatus.with_mie(false));
+    if queued() == FIFO_SIZE {
+        // Full: make room the slow way.
+        for _ in 0..HW_FIFO {
+            match pop() {
+                Some(b) => ns16550::uart_putc(b),
+                None => break,
+            }
+        }
+    }
+    push(b);
+    ns16550::set_tx_irq(true);
+    csr::write_mstatus(mstatus);
+    true
+}
+
+// The UART's PLIC source fired: refill its FIFO, or stop the THR-empty
+// interrupt once ours is empty.
+fn on_irq() {
+    let regs = ns16550::regs();
+    if regs.lsr().read() & ns16550::LSR_THRE == 0 {
+        return;
+    }
+    for _ in 0..HW_FIFO {
+        match pop() {
+            Some(b) => {
+                regs.thr().write(b);
+                SENT.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                ns16550::set_tx_irq(false);
+                break;
+            }
+        }
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        sent: SENT.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+        size: FIFO_SIZE,
+    }
+}
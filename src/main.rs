@@ -2,105 +2,2303 @@
 #![no_main]
 
 mod arch;         // _start entry in global_asm!
-mod logger;       // UART + slog!
+mod mmio;         // typed device registers
+mod bytes;        // on-flash structure fields
+mod board;        // board addresses, picked by feature
+mod arena;        // scratch buffers
+mod addr;         // physical addresses vs flash offsets
+mod logger;       // console (UART/semihosting) + slog_*!
 mod flash_intel;  // NOR driver
+mod clint;        // mtime, delays, deadlines
+mod bootstage;    // boot phase timestamps
 mod bootmeta;     // A/B metadata
+mod bootlog;      // measured-boot event log
+mod trap;         // M-mode trap handler
+mod platform;     // sifive_test exit/reset
+mod dtb;          // FDT reader
+mod dtb_edit;     // /chosen patching
+mod crc32;        // CRC-32 (IEEE)
+mod image;        // bank image header
+mod hash;         // SHA-256
+mod digest;       // one interface for CRC-32 and SHA-256
+mod inflate;      // gzip payloads
+mod uimage;       // U-Boot legacy images
+mod pmp;          // PMP setup for the next stage
+#[cfg(feature = "console")]
+mod console;      // recovery shell
+mod xmodem;       // serial image update
+mod watchdog;     // reset on a hung payload
+mod virtio_blk;   // bank images on a virtio disk
+mod gpt;          // disk partition table
+mod memtest;      // load region RAM test
+mod layout;       // flash/RAM map checks
+mod reset_cause;  // why we were reset
+#[cfg(feature = "secure")]
+mod verify;       // Ed25519 secure boot
+#[cfg(feature = "sbi-shim")]
+mod sbi;          // console ecalls for S-mode payloads
+mod report;       // one-line boot summary for scripts
+mod descriptor;   // build and layout info, in the image
+mod vcache;       // skip re-hashing verified images
+mod storm;        // reboot storm detection
+mod spl2;         // chainloading a second stage
+mod verify_policy; // what a failed image check does
+mod rtc;          // goldfish wall clock
+mod plic;         // external interrupts
+mod blackbox;     // crash records in flash
+mod banks;        // bank table, shared with the simulator
+mod postcode;     // boot progress byte for debuggers
+mod fw_cfg;       // QEMU test payload and settings
+mod budget;       // boot time ceiling
+mod warmboot;     // payload-vouched warm reboots
+mod ramtrials;    // power-cycle trials for a read-only boot log
+mod handoff;      // C handoff block for payloads
+mod layout_desc;  // flash layout as provisioned
+mod metablob;     // boot log summary for the OS
+mod boot_policy;  // which boots the boot log records
 
+use core::fmt::Write;
 use core::panic::PanicInfo;
 
-use crate::bootmeta::{BootBank, BootMeta};
-use crate::flash_intel::IntelFlash;
-use crate::logger::uart_puts;
+use crate::addr::{FlashOffset, PhysAddr};
+use crate::arch::csr::PrivMode;
+use crate::arch::HandoffArgs;
+use crate::banks::{BOOT_BANKS, MAX_TRIALS};
+use crate::blackbox::BlackBox;
+use crate::boot_policy::BootPolicy;
+use crate::bootlog::{Event, Record};
+use crate::bootmeta::{BankDesc, BootBank, BootMeta, EntryState, ForceBoot, Trials, MAX_BANKS};
+use crate::bootstage::Stage;
+use crate::budget::{Optional, Phase, Terminal};
+use crate::digest::{Algorithm, Digest, Output, Pair};
+use crate::dtb::{Fdt, Region};
+use crate::dtb_edit::Prop;
+use crate::flash_intel::{FlashError, IntelFlash, OtpRegion};
+use crate::fw_cfg::{Env, FwCfg};
+use crate::gpt::{Gpt, GptError};
+use crate::handoff::SplHandoffV1;
+use crate::hash::{flash_sha256, Hex, SHA256_LEN};
+use crate::image::{
+    check_load_region, flash_crc32, Forbidden, Image, ImageError, ImageFormat, ImageSource, LoadableImage,
+    MemSource, SplImageHeader,
+};
+use crate::logger::{console_puts, uart_divisor, ConsoleWriter, Found, Level};
+use crate::memtest::MemTestError;
+use crate::platform::{exit_qemu, ExitCode};
+use crate::postcode::Postcode;
+use crate::report::Report;
+use crate::reset_cause::ResetCause;
+use crate::spl2::Handoff;
+use crate::storm::Storm;
+use crate::uimage::UImageHeader;
+use crate::vcache::{Cached, VerifyCache};
+use crate::verify_policy::{Action, Check, VerifyPolicy};
+use crate::virtio_blk::VirtioBlk;
+use crate::watchdog::{Watchdog, WATCHDOG};
 
-// Flash layout constants (must match prepare_flash.sh)
-const FLASH_BASE: usize       = 0x2000_0000;            // QEMU pflash0 base
-const FLASH_BLOCK_SIZE: usize = 128 * 1024;             // 128 KiB
-const META_OFFSET: usize      = FLASH_BLOCK_SIZE * 255; // last block of 32 MiB
-const META_SIZE: usize        = FLASH_BLOCK_SIZE;
+// Addresses and the flash layout come from the board (src/board/), and
+// must match prepare_flash.sh. Regions are checked in layout.rs.
+const _: () = assert!(uart_divisor(board::UART_CLOCK_HZ, board::UART_BAUD).is_some());
 
-const MAX_TRIALS: u32 = 4;
+// Optional read-only golden image, tried when both banks fail, on flash
+// unit GOLDEN_UNIT. Set to e.g. Some(0x0020_0000) (BANK_SIZE long, right
+// after the SPL2 slot) once provisioned.
+const GOLDEN_OFFSET: Option<usize> = None;
+const GOLDEN_UNIT: usize = 0;
 
-// Where QEMU would load OpenSBI fw_jump.bin (TODO)
-const OPENSBI_BASE: usize = 0x8020_0000;
+// The same banks on a virtio-blk disk, by index: their GPT partition
+// name, or without a GPT their LBA in 512-byte sectors, BANK_SIZE each
+// (no golden image there). Metadata always stays in flash.
+const DISK_BANKS: [(&str, u64); 2] = [
+    (gpt::BANK_A_NAME, 0x800),                          // 1 MiB
+    (gpt::BANK_B_NAME, 0x4800),                         // 9 MiB
+];
 
-// QEMU virt (current) DTB address we see in our logs.
+// Where the banks are read from, overridden by /chosen "spl,boot-device"
+// ("flash" or "disk").
+const BOOT_DEVICE: BootDevice = BootDevice::Flash;
+
+// Also lock the SPL's own flash (SPL_FLASH_SIZE) read/execute-only before
+// handoff. Off by default: a locked entry sticks until reset, so nothing
+// after us could update the SPL or reprogram that entry.
+const PMP_LOCK_SPL_FLASH: bool = false;
+
+// Header-less banks (trailing CRC) are loaded here, OpenSBI fw_jump style
+const RAW_LOAD_ADDR: usize = board::RAM_BASE + 0x20_0000;
+
+// Where to look for a bank image already in RAM (BANK_SIZE at most) when
+// the flash is unreachable, e.g. put there with QEMU's
+// `-device loader,file=bank.img,addr=...`.
+const RAM_STAGE_ADDR: usize = board::RAM_BASE + 0x400_0000;
+
+// Boot a payload that is already in RAM (QEMU's -kernel or -device
+// loader) in place of the chosen bank's: bank selection and the boot log
+// work as usual, only the load is skipped. Also on with /chosen
+// "spl,ram-payload", empty or the u64 address to look at.
+const PREFER_RAM_PAYLOAD: bool = cfg!(feature = "prefer-ram-payload");
+const RAM_PAYLOAD_ADDR: usize = RAW_LOAD_ADDR;
+// A header-less RAM payload must be OpenSBI: its banner string within
+// this many bytes of its start.
+const OPENSBI_BANNER: &[u8] = b"\nOpenSBI ";
+const OPENSBI_SCAN: usize = 512 * 1024;
+
+// Take a payload and /chosen settings from QEMU's fw_cfg device when it
+// has them (see fw_cfg.rs): the payload, a bank image, is staged at
+// RAM_STAGE_ADDR and booted in place of the chosen bank's, like a RAM
+// payload. Tests only.
+const FWCFG: bool = cfg!(feature = "fwcfg");
+// Largest fw_cfg env we read.
+const FWCFG_ENV_MAX: usize = 1024;
+
+// Pressing this key within the window after reset starts an XMODEM
+// update of the inactive bank; with the "console" feature, any other key
+// enters the recovery shell.
+const UPDATE_KEY: u8 = b'u';
+const UPDATE_WINDOW_US: u64 = 200_000;
+
+// Autoboot countdown, overridden by /chosen "spl,bootdelay-ms" (u32).
+// Zero (production) skips it without any delay.
+const AUTOBOOT_DELAY_MS: u32 = 0;
+
+// Test the RAM under the payload before loading it. Also enabled by a
+// "spl,memtest" property in /chosen.
+const MEMTEST: bool = cfg!(feature = "memtest");
+
+// Hash every payload, even one whose image verified on an earlier boot
+// (see vcache.rs). Also set by a "spl,paranoid" property in /chosen.
+const PARANOID_VERIFY: bool = false;
+
+// Reset the board if the payload hasn't petted or stopped the watchdog
+// this long after the jump (see watchdog.rs); None leaves it off.
+const WATCHDOG_TIMEOUT_US: Option<u64> = Some(30_000_000);
+
+// Board identity in the flash's OTP protection registers (flash unit 0),
+// out of reach of any erase: an 8-byte serial number, then the
+// provisioning time (u64 LE, seconds since the epoch).
+const OTP_IDENTITY_REGION: OtpRegion = OtpRegion(1);
+
+// Boot log policy (see BootPolicy), also set by the /chosen properties
+// "spl,trust-success" and "spl,coalesce-attempts". Both are for
+// development boards only: with either one a bank that breaks is never
+// given up on (trust-success: after a confirmed boot; coalesce: ever),
+// as its failures stop adding up to MAX_TRIALS.
+const TRUST_SUCCESS: bool = false;
+const COALESCE_ATTEMPTS: bool = false;
+
+// Never write the boot log (also /chosen "spl,meta-read-only"), for
+// deployments that lock its block after provisioning: banks are chosen
+// from its counts as they are, plus this power cycle's failed boots
+// (see ramtrials.rs). A locked block is found out by itself anyway, on
+// the first write refused.
+const META_READ_ONLY: bool = false;
+
+// Rated erase cycles of a flash block, and the number of boot log
+// compactions (each one erases the metadata block) past which we warn.
+const FLASH_ERASE_CYCLES: u32 = 100_000;
+const META_WEAR_WARN: u32 = 80_000;
+
+// Reboot storm (see storm.rs): past this many warm boots in a row
+// without a confirmed one, only one attempt in STORM_RECORD_EVERY is
+// recorded, after a delay doubling from STORM_DELAY_US with each boot.
+// Also set by /chosen "spl,storm-boots" and "spl,storm-record-every"
+// (u32 each).
+const STORM_BOOTS: u32 = 8;
+const STORM_RECORD_EVERY: u32 = 4;
+const STORM_DELAY_US: u64 = 1_000_000;
+const STORM_DELAY_MAX_US: u64 = 60_000_000;
+
+// Ceiling on the SPL's own run time, from spl_main() to the jump (see
+// budget.rs); None leaves it off. Past it with nothing loaded, a "dev"
+// build tries the first candidate with its checks only warning; any
+// other records why and resets (DEADLINE_RESET) or stays in the SPL.
+const BOOT_DEADLINE_MS: Option<u32> = Some(10_000);
+const DEADLINE_RESET: bool = true;
+
+// Let a payload vouch for itself across a software reboot (see
+// warmboot.rs): the next boot loads the same bank unverified.
+const WARM_FAST_BOOT: bool = true;
+
+// Reset the board after a panic (and try booting again) rather than
+// stopping there: for unattended boards. A boot that keeps panicking
+// then loops on it, which the boot log can't see.
+const PANIC_RESET: bool = false;
+
+// Where QEMU virt passes its DTB (see should_record_boot()).
 const QEMU_VIRT_DTB_ADDR: usize = 0x0000_0000_8fe0_0000;
 
+// Room for the lock bitmap of a flash unit: 1024 blocks.
+const LOCK_MAP_BYTES: usize = 128;
+
+// Bytes per flash read when a payload is copied and hashed in one pass
+// (see stream_load()), through a buffer on the stack: larger reads cost
+// less per byte, up to what the stack can spare.
+const LOAD_CHUNK: usize = 4 * 1024;
+
+// RAM copy of the DTB handed to the next stage: the one we got may live
+// in ROM, and we need room to grow /chosen anyway.
+const DTB_OUT_SIZE: usize = 64 * 1024;
+
+// Ballast for the "oversize-test" feature: over the code budget on its
+// own, the link has to refuse it.
+#[cfg(feature = "oversize-test")]
+#[used]
+static OVERSIZE: [u8; board::CODE_BUDGET] = [0xa5; board::CODE_BUDGET];
+
+// On the emergency console only: the panic may come from inside the
+// logger, with its lock held.
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    uart_puts("PANIC in SPL1\r\n");
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    use crate::logger::emergency as em;
+
+    postcode::set(Postcode::Panic);
+    let nested = em::report_panic(info.location(), &info.message(), info.message().as_str());
+    if !nested {
+        let (file, line) = info.location().map_or(("", 0), |l| (l.file(), l.line()));
+        blackbox::record_panic(file, line);
+    }
+    em::puts("\r\n");
+    if PANIC_RESET {
+        em::puts("resetting\r\n");
+        platform::reset();
+    }
+    exit_qemu(ExitCode::Fail(1))
 }
 
-// Decide at runtime if we should touch NOR flash (record_boot).
+// Whether we may write the NOR flash at all. Yes, but in a
+// "qemu-no-writes" build booted with the DTB where QEMU virt puts it.
 fn should_record_boot(dtb_pa: usize) -> bool {
-    // For now: very simple heuristic.
-    //  - On QEMU virt: dtb_pa == 0x8fe00000  -> skip writes
-    //  - On real HW : dtb_pa likely different -> enable writes
-    if dtb_pa == QEMU_VIRT_DTB_ADDR {
-        slog!(
-            "Likely detected QEMU virt DTB at 0x{:016x}, will NOT write to NOR",
-            dtb_pa
-        );
-        false
-    } else {
-        slog!(
-            "DTB at 0x{:016x} != QEMU_VIRT_DTB_ADDR, enabling NOR writes",
-            dtb_pa
+    if cfg!(feature = "qemu-no-writes") && dtb_pa == QEMU_VIRT_DTB_ADDR {
+        slog_debug!("QEMU virt DTB at 0x{:016x}, will NOT write to NOR", dtb_pa);
+        return false;
+    }
+    true
+}
+
+// Count this boot towards a reboot storm, and if there is one, say so
+// and wait out its delay. The boot before was confirmed if the newest
+// entry of the boot log is.
+fn reboot_storm(fdt: Option<&Fdt>, trials: &Trials) -> Option<Storm> {
+    let boots = storm::count_boot(matches!(trials.last, Some((_, EntryState::Confirmed))));
+    let u32_prop = |name| {
+        fdt.and_then(|f| f.node_prop("chosen", name).ok().flatten())
+            .and_then(|v| v.try_into().ok())
+            .map(u32::from_be_bytes)
+    };
+    let threshold = u32_prop("spl,storm-boots").unwrap_or(STORM_BOOTS);
+    slog_debug!("reboot storm: {} warm boots without a confirmed one (limit {})", boots, threshold);
+    if boots <= threshold {
+        return None;
+    }
+    let record_every = u32_prop("spl,storm-record-every").unwrap_or(STORM_RECORD_EVERY).max(1);
+    let delay = storm::backoff_us(boots - threshold - 1, STORM_DELAY_US, STORM_DELAY_MAX_US);
+    slog_warn!("WARNING: reboot storm: {} warm boots without a confirmed one, degraded mode", boots);
+    slog_warn!(
+        "WARNING: recording one attempt in {}, waiting {} ms before this one",
+        record_every,
+        delay / 1000
+    );
+    let since = clint::mtime();
+    clint::delay_us(delay);
+    budget::excuse(since);
+    Some(Storm { boots, record_every })
+}
+
+// Set up flash unit `unit` at `base`, with the size and block size its
+// CFI query reports. A unit that doesn't answer keeps the board's values;
+// one that answers with a layout that doesn't fit it panics.
+fn probe_flash(unit: usize, base: PhysAddr) -> IntelFlash {
+    let mut flash = IntelFlash::unit(unit, base);
+    match IntelFlash::query(base) {
+        Ok(cfi) => {
+            slog_debug!(
+                "flash{}: 0x{:x}, CFI: {} KiB, {} KiB blocks, x{} query",
+                unit,
+                base,
+                cfi.size / 1024,
+                cfi.block_size / 1024,
+                cfi.stride * 8
+            );
+            if cfi.regions > 1 {
+                slog_warn!("WARNING: flash{}: {} erase block regions, using the first", unit, cfi.regions);
+            }
+            if cfi.size != flash.size || cfi.block_size != flash.block_size {
+                slog_warn!(
+                    "WARNING: flash{}: CFI geometry differs from the board's ({} KiB, {} KiB blocks)",
+                    unit,
+                    flash.size / 1024,
+                    flash.block_size / 1024
+                );
+            }
+            flash.size = cfi.size;
+            flash.block_size = cfi.block_size;
+        }
+        Err(e) => slog_warn!("WARNING: flash{}: at 0x{:x}: {:?}, using board geometry", unit, base, e),
+    }
+    layout::validate_unit(unit, flash.size, flash.block_size);
+    flash
+}
+
+// Log how many erase cycles the boot log has used up on its block, and
+// warn when it gets close to the rating. Compactions are the only thing
+// erasing it, but the count starts from whenever the log got a header.
+fn log_meta_wear(meta: &BootMeta) {
+    let Some(count) = meta.compaction_count() else {
+        slog_info!("boot log: no compaction count yet");
+        return;
+    };
+    let permille = u64::from(count) * 1000 / u64::from(FLASH_ERASE_CYCLES);
+    slog_info!(
+        "boot log: {} compactions, ~{}.{}% of the block's {} erase cycles",
+        count,
+        permille / 10,
+        permille % 10,
+        FLASH_ERASE_CYCLES
+    );
+    if count >= META_WEAR_WARN {
+        slog_warn!(
+            "WARNING: boot log block has been erased {} times, close to wearing out",
+            count
         );
-        true
     }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn spl_main(hartid: usize, dtb_pa: usize) -> ! {
-    slog!("spl1 starting (hartid={}, dtb=0x{:016x})", hartid, dtb_pa);
+// Log which part flash unit `unit` is and how many of its blocks are
+// locked; the lock bitmap too at debug level.
+fn log_flash_id(unit: usize, flash: &IntelFlash) {
+    let id = match flash.identify() {
+        Ok(id) => id,
+        Err(e) => {
+            slog_debug!("flash{}: no identifier ({:?})", unit, e);
+            return;
+        }
+    };
+    let blocks = flash.size / flash.block_size;
+    let mut map = [0u8; LOCK_MAP_BYTES];
+    let locked = flash.lock_map(&id, &mut map);
+    let (vendor, dev, mib) = (id.manufacturer, id.device, flash.size >> 20);
+    match locked {
+        Ok(n) => slog_info!(
+            "flash{}: vendor=0x{:02x} dev=0x{:02x} size={}MiB blocks={} locked={}",
+            unit,
+            vendor,
+            dev,
+            mib,
+            blocks,
+            n
+        ),
+        Err(e) => slog_warn!(
+            "WARNING: flash{}: vendor=0x{:02x} dev=0x{:02x} size={}MiB blocks={}, lock sweep failed: {:?}",
+            unit,
+            vendor,
+            dev,
+            mib,
+            blocks,
+            e
+        ),
+    }
+    if let Some(features) = id.features {
+        slog_debug!("flash{}: extended query features 0x{:08x}", unit, features);
+    }
+    if locked.is_ok() && logger::log_enabled(Level::Debug) {
+        slog_debug!("flash{}: lock bitmap (bit n: block n)", unit);
+        logger::hexdump(0, &map[..blocks.div_ceil(8)]);
+    }
+}
+
+// Log the board serial and provisioning time from OTP, if programmed.
+fn log_identity(flash: &IntelFlash) {
+    let mut id = [0u8; 16];
+    match flash.read_otp(OTP_IDENTITY_REGION, 0, &mut id) {
+        Ok(()) if id[..8] == [0xff; 8] => slog_info!("board serial: unprovisioned"),
+        Ok(()) => {
+            let serial = u64::from_be_bytes(id[..8].try_into().unwrap());
+            let when = u64::from_le_bytes(id[8..].try_into().unwrap());
+            slog_info!("board serial: {:016x}, provisioned at {} (unix time)", serial, when);
+        }
+        Err(FlashError::NotSupported) => slog_debug!("board serial: flash has no OTP"),
+        Err(e) => slog_warn!("WARNING: board serial: OTP read failed: {:?}", e),
+    }
+}
+
+// Look up the base of the first node compatible with `compat`, falling
+// back to `default` when there is no usable DTB or no such node.
+fn dtb_base_or(fdt: Option<&Fdt>, compat: &str, default: usize) -> usize {
+    let found = fdt.map(|f| f.find_compatible_reg(compat));
+    match found {
+        Some(Ok(Some(dev))) => {
+            slog_debug!(
+                "{}: {} base=0x{:x} size=0x{:x} (from DTB)",
+                compat,
+                dev.name,
+                dev.reg.base,
+                dev.reg.size
+            );
+            dev.reg.base
+        }
+        Some(Err(e)) => {
+            slog_warn!("{}: DTB walk failed ({:?}), default 0x{:x}", compat, e, default);
+            default
+        }
+        _ => {
+            slog_debug!("{}: not in DTB, default 0x{:x}", compat, default);
+            default
+        }
+    }
+}
+
+// Record the boot decision in /chosen of a RAM copy of the DTB, with the
+// boot log summed up in `blob` if there is one (see metablob.rs).
+// Returns the address to pass in a1 to the next stage (the original DTB
+// if the rewrite failed).
+fn patch_dtb(src: &Fdt, slot: Slot, attempts: u32, reset: ResetCause, blob: Option<&[u8]>) -> usize {
+    let name = slot.tag();
+    let mut bank_name = [0u8; 16];
+    let len = name.len().min(bank_name.len() - 1);
+    bank_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    let bank_name = &bank_name[..=len];
+    let attempts = attempts.to_be_bytes();
+    let (log_pa, log_size) = logger::ringbuf::region();
+    let mut log_ring = [0u8; 16];
+    log_ring[..8].copy_from_slice(&(log_pa as u64).to_be_bytes());
+    log_ring[8..].copy_from_slice(&(log_size as u64).to_be_bytes());
+    let (events_pa, events_size) = bootlog::region();
+    let mut event_log = [0u8; 16];
+    event_log[..8].copy_from_slice(&(events_pa as u64).to_be_bytes());
+    event_log[8..].copy_from_slice(&(events_size as u64).to_be_bytes());
+    let mut reset_name = [0u8; 16];
+    reset_name[..reset.name().len()].copy_from_slice(reset.name().as_bytes());
+    let reset_name = &reset_name[..=reset.name().len()];
+    let (sig_pa, sig_size) = reset_cause::region();
+    let mut reset_sig = [0u8; 16];
+    reset_sig[..8].copy_from_slice(&(sig_pa as u64).to_be_bytes());
+    reset_sig[8..].copy_from_slice(&(sig_size as u64).to_be_bytes());
+    let (warm_pa, warm_size) = warmboot::region();
+    let mut warm_token = [0u8; 16];
+    warm_token[..8].copy_from_slice(&(warm_pa as u64).to_be_bytes());
+    warm_token[8..].copy_from_slice(&(warm_size as u64).to_be_bytes());
+    let boot_hartid = (arch::boot_hart() as u32).to_be_bytes();
+    let blob_version = metablob::VERSION.to_be_bytes();
+    let props = [
+        Prop {
+            name: "spl,boot-bank",
+            value: bank_name,
+        },
+        Prop {
+            name: "spl,boot-attempts",
+            value: &attempts,
+        },
+        Prop {
+            name: "spl,log-ring",
+            value: &log_ring,
+        },
+        Prop {
+            name: "spl,event-log",
+            value: &event_log,
+        },
+        Prop {
+            name: "spl,reset-cause",
+            value: reset_name,
+        },
+        Prop {
+            name: "spl,reset-signature",
+            value: &reset_sig,
+        },
+        Prop {
+            name: "spl,warm-token",
+            value: &warm_token,
+        },
+        // What EFI and U-Boot pass a RISC-V kernel: the hart it runs on.
+        Prop {
+            name: "boot-hartid",
+            value: &boot_hartid,
+        },
+        Prop {
+            name: "spl,bootmeta-blob",
+            value: blob.unwrap_or_default(),
+        },
+        Prop {
+            name: "spl,bootmeta-version",
+            value: &blob_version,
+        },
+    ];
+    // The last two only with a blob.
+    let props = match blob {
+        Some(_) => &props[..],
+        None => &props[..props.len() - 2],
+    };
 
-    let flash = IntelFlash {
-        base: FLASH_BASE,
-        block_size: FLASH_BLOCK_SIZE,
+    let Some(out) = arena::alloc(DTB_OUT_SIZE, 8) else {
+        slog_warn!("WARNING: no room to patch the DTB, passing original");
+        return src.as_bytes().as_ptr() as usize;
     };
-    let meta = BootMeta::new(&flash, META_OFFSET, META_SIZE);
+    match dtb_edit::patch_chosen(src, props, out) {
+        Ok(patched) => {
+            // The next stage may read the blob with caches or MMU set up
+            // differently: make our stores to it visible first.
+            arch::barrier::fence_rw_rw();
+            let pa = patched.as_bytes().as_ptr() as usize;
+            let readback = patched.node_prop("chosen", "spl,boot-bank");
+            slog_debug!(
+                "patched DTB at 0x{:016x} ({} bytes), /chosen/spl,boot-bank={:?}",
+                pa,
+                patched.as_bytes().len(),
+                readback
+                    .ok()
+                    .flatten()
+                    .and_then(|v| core::str::from_utf8(v).ok())
+                    .map(|v| v.trim_end_matches('\0'))
+            );
+            if logger::log_enabled(Level::Debug) {
+                logger::hexdump(pa, &patched.as_bytes()[..dtb::HEADER_SIZE]);
+            }
+            pa
+        }
+        Err(e) => {
+            slog_warn!("WARNING: DTB patch failed ({:?}), passing original", e);
+            src.as_bytes().as_ptr() as usize
+        }
+    }
+}
+
+/// Something we can try to boot: one of the banks, or the golden image
+/// (never recorded in the metadata log), or as a last resort an image
+/// staged in RAM (see ram_fallback()).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Bank(BootBank),
+    Golden,
+    Ram,
+    /// The SPL2 slot: never booted as a payload, only chainloaded
+    /// before one (see load_spl2()).
+    Spl2,
+}
+
+impl Slot {
+    // How the event log names the slot.
+    fn event_code(self) -> u32 {
+        match self {
+            Slot::Bank(bank) => bank.index() as u32,
+            Slot::Golden => bootlog::SLOT_GOLDEN,
+            Slot::Ram => bootlog::SLOT_RAM,
+            Slot::Spl2 => bootlog::SLOT_SPL2,
+        }
+    }
+
+    // How the boot report names the slot: one word.
+    fn tag(self) -> &'static str {
+        match self {
+            Slot::Bank(bank) => bank.desc().name,
+            Slot::Golden => "golden",
+            Slot::Ram => "ram",
+            Slot::Spl2 => "spl2",
+        }
+    }
+}
+
+impl core::fmt::Display for Slot {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Slot::Bank(bank) => write!(f, "bank {}", bank),
+            Slot::Golden => f.write_str("golden"),
+            Slot::Ram => f.write_str("ram"),
+            Slot::Spl2 => f.write_str("spl2"),
+        }
+    }
+}
+
+// Flash unit a slot's image is on. Slot::Ram is never located on flash
+// (see Banks::locate()).
+fn slot_unit(slot: Slot) -> usize {
+    match slot {
+        Slot::Bank(bank) => bank.desc().unit,
+        Slot::Golden | Slot::Ram => GOLDEN_UNIT,
+        Slot::Spl2 => board::SPL2_UNIT,
+    }
+}
+
+/// Device the bank images are read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BootDevice {
+    Flash,
+    Disk,
+}
+
+impl BootDevice {
+    fn from_chosen(value: &[u8]) -> Option<Self> {
+        match value.strip_suffix(b"\0").unwrap_or(value) {
+            b"flash" => Some(BootDevice::Flash),
+            b"disk" => Some(BootDevice::Disk),
+            _ => None,
+        }
+    }
+}
+
+/// Byte range of a bank image on its device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BankRange {
+    offset: usize,
+    size: usize,
+}
+
+/// The bank images, on whichever device we boot from.
+enum Banks<'a> {
+    /// The flash units, by index.
+    Flash(&'a [IntelFlash]),
+    /// A disk, with where each bank sits on it (see disk_banks()).
+    Disk(&'a VirtioBlk, [Option<BankRange>; MAX_BANKS]),
+    /// An image staged in RAM, the only slot there.
+    Ram(&'a MemSource),
+}
 
-    let (a_count, b_count, next_idx) = meta.scan();
-    slog!(
-        "boot trials: bank A = {}, bank B = {}, next_idx = {}",
-        a_count,
-        b_count,
-        next_idx
+impl Banks<'_> {
+    // For the boot report.
+    fn kind(&self) -> &'static str {
+        match self {
+            Banks::Flash(_) => "flash",
+            Banks::Disk(..) => "disk",
+            Banks::Ram(_) => "ram",
+        }
+    }
+
+    // What a slot's image is read from.
+    fn source(&self, slot: Slot) -> &dyn ImageSource {
+        match self {
+            Banks::Flash(flash) => &flash[slot_unit(slot)],
+            Banks::Disk(disk, _) => *disk,
+            Banks::Ram(mem) => *mem,
+        }
+    }
+
+    // Where a slot's image is on the device, None if the slot isn't
+    // configured there.
+    fn locate(&self, slot: Slot) -> Option<BankRange> {
+        match (self, slot) {
+            (Banks::Flash(_), Slot::Bank(bank)) => Some(BankRange {
+                offset: bank.desc().offset.get(),
+                size: bank.desc().size,
+            }),
+            (Banks::Flash(_), Slot::Golden) => GOLDEN_OFFSET.map(|offset| BankRange {
+                offset,
+                size: board::BANK_SIZE,
+            }),
+            (Banks::Flash(_), Slot::Spl2) => Some(BankRange {
+                offset: board::SPL2_OFFSET,
+                size: board::SPL2_SIZE,
+            }),
+            (Banks::Disk(_, banks), Slot::Bank(bank)) => banks[bank.index()],
+            (Banks::Ram(mem), Slot::Ram) => Some(BankRange {
+                offset: 0,
+                size: mem.size,
+            }),
+            (Banks::Disk(..), Slot::Golden | Slot::Spl2) | (_, Slot::Ram) | (Banks::Ram(_), _) => None,
+        }
+    }
+}
+
+// The banks on the disk (DISK_BANKS): their GPT partitions, or the
+// fixed LBAs if it has no GPT at all (or only a broken one).
+fn disk_banks(disk: &VirtioBlk) -> [Option<BankRange>; MAX_BANKS] {
+    let fixed = core::array::from_fn(|i| {
+        DISK_BANKS.get(i).map(|&(_, lba)| BankRange {
+            offset: lba as usize * virtio_blk::SECTOR_SIZE,
+            size: board::BANK_SIZE,
+        })
+    });
+    let gpt = match Gpt::read(disk, disk.capacity.saturating_sub(1)) {
+        Ok(gpt) => gpt,
+        Err(GptError::NoSignature) => {
+            slog_info!("disk: no GPT, banks at fixed LBAs");
+            return fixed;
+        }
+        Err(e) => {
+            slog_warn!("WARNING: disk: GPT unusable ({:?}), banks at fixed LBAs", e);
+            return fixed;
+        }
+    };
+    if gpt.backup {
+        slog_warn!("WARNING: disk: primary GPT corrupt, using the backup");
+    }
+    slog_debug!(
+        "disk: GPT with {} entries of {} bytes at LBA {}",
+        gpt.num_entries,
+        gpt.entry_size,
+        gpt.entries_lba
     );
+    if let Some(p) = gpt.find_by_name(disk, gpt::BOOTMETA_NAME) {
+        slog_debug!(
+            "disk: '{}' is partition {}, unused: boot metadata stays in flash",
+            gpt::BOOTMETA_NAME,
+            p.index + 1
+        );
+    }
+    core::array::from_fn(|i| match DISK_BANKS.get(i).map(|&(name, _)| (name, gpt.find_by_name(disk, name)))? {
+        (name, Some(p)) => {
+            slog_info!(
+                "disk: '{}' is partition {}, LBA {}..={}",
+                name,
+                p.index + 1,
+                p.first_lba,
+                p.last_lba
+            );
+            Some(BankRange {
+                offset: p.offset(),
+                size: p.size(),
+            })
+        }
+        (name, None) => {
+            slog_warn!("WARNING: disk: no '{}' partition", name);
+            None
+        }
+    })
+}
 
-    let bank = meta.choose_bank(MAX_TRIALS);
-    slog!("chosen bank (for info): {:?}", bank);
+// Find and set up the first virtio block device, from the DTB's
+// virtio,mmio nodes or else QEMU virt's fixed transport range.
+fn probe_disk(fdt: Option<&Fdt>) -> Option<VirtioBlk> {
+    let found = fdt.map(|f| {
+        f.find_compatible_reg_where("virtio,mmio", |d| virtio_blk::is_block_device(d.reg.base))
+    });
+    let base = match found {
+        Some(Ok(Some(dev))) => {
+            slog_debug!("virtio-blk: {} base=0x{:x} (from DTB)", dev.name, dev.reg.base);
+            Some(dev.reg.base)
+        }
+        Some(Ok(None)) => None,
+        _ => (0..virtio_blk::QEMU_VIRT_MMIO_COUNT)
+            .map(|i| virtio_blk::QEMU_VIRT_MMIO_BASE + i * virtio_blk::QEMU_VIRT_MMIO_STRIDE)
+            .find(|&base| virtio_blk::is_block_device(base)),
+    };
+    let Some(base) = base else {
+        slog_warn!("WARNING: no virtio block device found");
+        return None;
+    };
+    match VirtioBlk::init(base) {
+        Ok(disk) => {
+            slog_info!(
+                "virtio-blk at 0x{:x}: {} sectors ({} MiB)",
+                base,
+                disk.capacity,
+                disk.capacity / 2048
+            );
+            Some(disk)
+        }
+        Err(e) => {
+            slog_warn!("WARNING: virtio-blk at 0x{:x}: init failed: {:?}", base, e);
+            None
+        }
+    }
+}
+
+// Measure `len` bytes of payload in flash and log the digest.
+fn measure(flash: &dyn ImageSource, slot: Slot, offset: usize, len: usize) -> [u8; SHA256_LEN] {
+    let digest = flash_sha256(flash, offset, len);
+    log_measured(slot, len, digest);
+    digest
+}
+
+// Copy `load`'s payload to its load address and hash it on the way, in
+// one pass over `flash`: its SHA-256 (logged and recorded) and CRC-32,
+// the payload left unchecked in RAM. See LOAD_CHUNK.
+fn stream_load(
+    flash: &dyn ImageSource,
+    slot: Slot,
+    load: &LoadableImage,
+) -> Result<([u8; SHA256_LEN], u32), ImageError> {
+    let mut pair = Pair(Algorithm::Sha256.hasher(), Algorithm::Crc32.hasher());
+    let start = clint::mtime();
+    // probe() checked the load region against what the SPL still needs.
+    unsafe { load.copy_hashed::<LOAD_CHUNK>(flash, &mut pair)? };
+    bootstage::throughput("copy+hash", load.payload_size, clint::mtime() - start);
+    let digest = pair.0.finalize().to_sha256();
+    log_measured(slot, load.payload_size, digest);
+    Ok((digest, pair.1.finalize().to_u32()))
+}
 
-    if should_record_boot(dtb_pa) {
-        match meta.record_boot(bank) {
-            Ok(()) => {
-                slog!("recorded new boot trial for {:?}", bank);
+// Log and record the digest of a payload just measured.
+fn log_measured(slot: Slot, len: usize, digest: [u8; SHA256_LEN]) {
+    slog_info!("{}: payload sha256={}", slot, Hex(&digest));
+    record_digest(slot, len, digest);
+}
+
+// The event log entry of a payload digest, measured or cached.
+fn record_digest(slot: Slot, len: usize, digest: [u8; SHA256_LEN]) {
+    let mut rec = Record::new(Event::ImageDigest, slot.event_code());
+    rec.value = len as u64;
+    rec.digest = digest;
+    bootlog::record(&rec);
+}
+
+// Secure boot: the payload must carry a valid signature.
+#[cfg(feature = "secure")]
+fn check_signature(
+    flash: &dyn ImageSource,
+    slot: Slot,
+    hdr: &SplImageHeader,
+    offset: usize,
+) -> Result<(), ImageError> {
+    let sig = hdr.signature().ok_or(ImageError::SignatureMissing)?;
+    let len = hdr.payload_size as usize;
+    match verify::verify_flash(flash, hdr.payload_offset(offset), len, sig) {
+        Ok(()) => {
+            slog_info!("{}: signature ok", slot);
+            Ok(())
+        }
+        Err(e) => {
+            slog_warn!("{}: signature check failed: {:?}", slot, e);
+            Err(ImageError::SignatureInvalid)
+        }
+    }
+}
+
+#[cfg(not(feature = "secure"))]
+fn check_signature(
+    _flash: &dyn ImageSource,
+    slot: Slot,
+    hdr: &SplImageHeader,
+    _offset: usize,
+) -> Result<(), ImageError> {
+    if hdr.signature().is_some() {
+        slog_info!("{}: signature present, not checked (secure boot off)", slot);
+    }
+    Ok(())
+}
+
+// What a payload must never be loaded over: our RAM, then each flash
+// unit.
+fn forbidden_regions(flash: &[IntelFlash]) -> [Forbidden; 1 + board::FLASH_UNITS] {
+    let (ram_start, ram_end) = arch::spl_ram_region();
+    core::array::from_fn(|i| match i {
+        0 => Forbidden::new("spl ram", PhysAddr::new(ram_start), ram_end - ram_start),
+        _ => Forbidden::new("flash", flash[i - 1].base, flash[i - 1].size),
+    })
+}
+
+// Memory test of a payload's load region, minus `exclude`.
+fn memtest_load_region(slot: Slot, load: &LoadableImage, exclude: &[Forbidden]) -> Result<(), ImageError> {
+    let start = clint::mtime();
+    // The caller only passes a region checked against what the SPL uses.
+    let result = unsafe { memtest::test_range(load.load_addr, load.load_size, exclude) };
+    let us = clint::ticks_to_us(clint::mtime() - start);
+    match result {
+        Ok(()) => {
+            slog_info!(
+                "{}: memtest 0x{:x}+0x{:x} passed in {} us",
+                slot,
+                load.load_addr,
+                load.load_size,
+                us
+            );
+            Ok(())
+        }
+        Err(MemTestError::Fault(f)) => {
+            slog_error!(
+                "{}: memtest failed at 0x{:x}: wrote 0x{:016x}, read 0x{:016x} ({} us)",
+                slot,
+                f.addr,
+                f.expected,
+                f.actual,
+                us
+            );
+            Err(ImageError::MemTest(f))
+        }
+        Err(MemTestError::Cancelled) => {
+            slog_warn!("{}: memtest cancelled after {} us", slot, us);
+            Err(ImageError::Cancelled)
+        }
+    }
+}
+
+/// How to enter a loaded payload.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    addr: usize,
+    mode: PrivMode,
+    /// Take the parked harts along (see arch::release_harts()).
+    release_harts: bool,
+    /// An SPL2 that can finish a boot log compaction (see spl2.rs).
+    meta_rewrite: bool,
+    /// For the boot report: image format, stored payload size and
+    /// digest, strongest check passed.
+    format: &'static str,
+    payload_size: usize,
+    digest: [u8; SHA256_LEN],
+    verify: &'static str,
+    /// Where it was loaded from: flash, disk or ram.
+    source: &'static str,
+    /// A full verification of a flash bank, for the verification cache.
+    verified: Option<Cached>,
+    /// CRC-32 of a flash bank's SPL1 header, for a warm reboot token.
+    hdr_crc: Option<u32>,
+    /// The RAM it was loaded to, [start, end).
+    region: (PhysAddr, PhysAddr),
+}
+
+// Parse, copy and check the image in `slot`. Returns its entry point, the
+// mode to enter it in and what the boot report says about it.
+// With `memtest` set, the RAM it loads to is tested first, except the
+// ranges listed there. With `cache`, a flash bank that verified on an
+// earlier boot isn't hashed again, nor, on a warm reboot its payload
+// vouched for, checked at all. Otherwise a payload that isn't compressed
+// is copied and hashed in the same pass (stream_load()), and checked
+// once in RAM. What a failed check does is up to `policy`; a payload
+// refused once in RAM is zeroed there.
+fn load_slot(
+    banks: &Banks,
+    slot: Slot,
+    forbidden: &[Forbidden],
+    memtest: Option<&[Forbidden]>,
+    cache: Option<&VerifyCache>,
+    policy: &VerifyPolicy,
+) -> Result<Entry, ImageError> {
+    let flash = banks.source(slot);
+    let range = banks.locate(slot).ok_or(ImageError::NotConfigured)?;
+    let offset = range.offset;
+    if logger::log_enabled(Level::Debug) {
+        slog_debug!("{}: header bytes", slot);
+        logger::hexdump_flash(flash, offset, SplImageHeader::V1_SIZE);
+    }
+    let image = Image::probe(flash, offset, range.size, PhysAddr::new(RAW_LOAD_ADDR), forbidden, policy)?;
+    let load = &image.load;
+    slog_info!(
+        "{}: {} image, {} bytes (loaded {}) at 0x{:x}, entry=0x{:x}, {}",
+        slot,
+        image.kind(),
+        load.payload_size,
+        load.load_size,
+        load.load_addr,
+        load.entry,
+        if image.verified_in_flash() { "intact in flash" } else { "crc checked once loaded" }
+    );
+    match &image.format {
+        ImageFormat::Spl(hdr) => slog_debug!(
+            "{}: v{} flags=0x{:x} crc=0x{:08x}",
+            slot,
+            hdr.version,
+            hdr.flags,
+            hdr.payload_crc
+        ),
+        ImageFormat::UImage(hdr) => slog_debug!(
+            "{}: uImage '{}' os={} type={} comp={}",
+            slot,
+            hdr.name(),
+            hdr.os,
+            hdr.image_type,
+            hdr.comp
+        ),
+        ImageFormat::Raw(raw) => slog_debug!("{}: raw crc=0x{:08x}", slot, raw.crc),
+    }
+
+    // Only our own header says which image this is (see vcache.rs).
+    let cacheable = match (banks, slot, &image.format) {
+        (Banks::Flash(_), Slot::Bank(bank), ImageFormat::Spl(hdr)) => {
+            Some((bank, hdr, flash_crc32(flash, offset, hdr.hdr_size as usize)))
+        }
+        _ => None,
+    };
+    let hit = cacheable.zip(cache).and_then(|((bank, hdr, hdr_crc), cache)| {
+        cache.lookup(bank).filter(|c| {
+            c.ok
+                && c.hdr_crc == hdr_crc
+                && c.payload_size == hdr.payload_size
+                && hdr.expected_sha256().is_none_or(|expected| *expected == c.digest)
+        })
+    });
+    // Like the cache, not in paranoid mode; never where a signature is
+    // enforced (see warmboot.rs).
+    let secure = cfg!(feature = "secure") && policy.action(Check::Signature) == Action::Enforce;
+    let warm = cacheable.filter(|_| cache.is_some() && !secure).and_then(|(bank, hdr, hdr_crc)| {
+        warmboot::fast_path(bank.index(), hdr_crc)
+            .filter(|digest| hdr.expected_sha256().is_none_or(|expected| expected == digest))
+    });
+    let streamed = warm.is_none() && hit.is_none() && !load.compressed;
+    if let Some(exclude) = memtest
+        && streamed
+    {
+        memtest_load_region(slot, load, exclude)?;
+    }
+    let started = clint::now_us();
+    let mut loaded_crc = None;
+    let digest = match (warm, hit) {
+        (Some(digest), _) => {
+            slog_info!("{}: warm fast boot, the payload vouched for it: not verified again", slot);
+            record_digest(slot, load.payload_size, digest);
+            digest
+        }
+        (None, Some(c)) => {
+            slog_info!("{}: verified on an earlier boot, not hashed again (~{} us saved)", slot, c.verify_us);
+            record_digest(slot, load.payload_size, c.digest);
+            c.digest
+        }
+        (None, None) if streamed => {
+            let (digest, crc) = stream_load(flash, slot, load).inspect_err(|_| unsafe { load.scrub() })?;
+            loaded_crc = Some(crc);
+            digest
+        }
+        (None, None) => measure(flash, slot, load.payload_offset, load.payload_size),
+    };
+    let hash_us = clint::now_us() - started;
+    let checks = || -> Result<&'static str, ImageError> {
+        let mut verify = if policy.runs(Check::Crc) { "crc" } else { "none" };
+        match &image.format {
+            _ if warm.is_some() => verify = "warm",
+            ImageFormat::Spl(hdr) => {
+                match hdr.expected_sha256() {
+                    Some(_) if !policy.runs(Check::Digest) => {}
+                    Some(expected) if *expected != digest => {
+                        slog_warn!("{}: expected sha256={}", slot, Hex(expected));
+                        policy.decide(Check::Digest, Err(ImageError::DigestMismatch))?;
+                    }
+                    Some(_) if hit.is_some() => verify = "sha256-cached",
+                    Some(_) => {
+                        slog_debug!("{}: sha256 matches header", slot);
+                        verify = "sha256";
+                    }
+                    None => slog_debug!("{}: header carries no sha256, measured only", slot),
+                }
+                if policy.runs(Check::Signature) {
+                    let checked = check_signature(flash, slot, hdr, offset);
+                    if checked.is_ok() && cfg!(feature = "secure") {
+                        verify = "signature";
+                    }
+                    policy.decide(Check::Signature, checked)?;
+                }
             }
-            Err(e) => {
-                slog!("WARNING: failed to record boot trial: {:?}", e);
+            // Only our own header can carry a signature.
+            _ if cfg!(feature = "secure") && policy.runs(Check::Signature) => {
+                policy.decide(Check::Signature, Err(ImageError::SignatureMissing))?
             }
+            _ => {}
+        }
+        // Streamed: the payload is in place, its CRC is the last check.
+        if let Some(crc) = loaded_crc {
+            policy.decide(Check::Crc, load.check_crc(crc))?;
         }
+        Ok(verify)
+    };
+    let verify = match checks() {
+        Ok(verify) => verify,
+        Err(e) => {
+            if streamed {
+                // Checked against `forbidden` by probe(), like the copy.
+                unsafe { load.scrub() };
+            }
+            return Err(e);
+        }
+    };
+    bootstage::mark(Stage::ImageVerified);
+
+    if let Some(exclude) = memtest
+        && warm.is_none()
+        && !streamed
+    {
+        memtest_load_region(slot, load, exclude)?;
+    }
+
+    // probe() checked the load region against `forbidden`. The payload
+    // is in place even when its CRC is off.
+    let addr = if streamed {
+        load.enter()
     } else {
-        slog!("(QEMU) skipping record_boot(): no NOR writes from SPL1");
+        match unsafe { load.load(flash) } {
+            Err(e @ ImageError::CrcMismatch { .. }) => match policy.decide(Check::Crc, Err(e)) {
+                Ok(()) => load.entry,
+                Err(e) => {
+                    unsafe { load.scrub() };
+                    return Err(e);
+                }
+            },
+            loaded => loaded?,
+        }
+    };
+    Ok(Entry {
+        addr,
+        mode: load.mode,
+        release_harts: load.release_harts,
+        meta_rewrite: load.meta_rewrite,
+        format: image.kind(),
+        payload_size: load.payload_size,
+        digest,
+        verify,
+        source: banks.kind(),
+        // Not what got through a relaxed policy.
+        verified: match (cacheable, hit) {
+            (Some((_, hdr, hdr_crc)), None) if policy.enforces_payload() => Some(Cached {
+                hdr_crc,
+                payload_size: hdr.payload_size,
+                digest,
+                verify_us: hash_us as u32,
+                ok: true,
+            }),
+            _ => None,
+        },
+        hdr_crc: cacheable.map(|(_, _, hdr_crc)| hdr_crc),
+        region: (load.load_addr, load.load_addr + load.load_size),
+    })
+}
+
+// A payload already in RAM at `addr`, to be entered where it is: an SPL1
+// bank image whose payload sits right at its load address, checked in
+// place like a bank (CRC, digest, signature), or a bare OpenSBI build,
+// recognized by its banner and entered at `addr` with nothing to check
+// it against. Failed checks are up to `policy`.
+fn probe_ram_payload(
+    addr: usize,
+    forbidden: &[Forbidden],
+    policy: &VerifyPolicy,
+) -> Result<Entry, ImageError> {
+    let mem = MemSource {
+        base: addr,
+        size: board::BANK_SIZE,
+    };
+    let magic = mem.read_u32_le(0);
+    if magic == SplImageHeader::MAGIC {
+        let hdr = SplImageHeader::parse(&mem, 0, mem.size, forbidden)?;
+        let at = PhysAddr::new(hdr.payload_offset(addr));
+        if hdr.is_compressed() || hdr.load_addr != at {
+            return Err(ImageError::NotInPlace {
+                load: hdr.load_addr,
+                at,
+            });
+        }
+        let size = hdr.payload_size as usize;
+        // Measured and CRC'd from one read of the payload.
+        let (sha256, crc) =
+            image::digest_pair(&mem, hdr.payload_offset(0), size, Algorithm::Sha256, Algorithm::Crc32);
+        let digest = sha256.to_sha256();
+        log_measured(Slot::Ram, size, digest);
+        let mut verify = if policy.runs(Check::Crc) { "crc" } else { "none" };
+        if let Some(expected) = hdr.expected_sha256()
+            && policy.runs(Check::Digest)
+        {
+            if *expected == digest {
+                verify = "sha256";
+            } else {
+                policy.decide(Check::Digest, Err(ImageError::DigestMismatch))?;
+            }
+        }
+        if policy.runs(Check::Signature) {
+            let checked = check_signature(&mem, Slot::Ram, &hdr, 0);
+            if checked.is_ok() && cfg!(feature = "secure") {
+                verify = "signature";
+            }
+            policy.decide(Check::Signature, checked)?;
+        }
+        if policy.runs(Check::Crc) && crc != Output::from_crc32(hdr.payload_crc) {
+            let (expected, actual) = (hdr.payload_crc, crc.to_u32());
+            policy.decide(Check::Crc, Err(ImageError::CrcMismatch { expected, actual }))?;
+        }
+        return Ok(Entry {
+            addr: hdr.entry(),
+            mode: hdr.entry_mode(),
+            release_harts: hdr.loadable(0).release_harts,
+            meta_rewrite: hdr.loadable(0).meta_rewrite,
+            format: "SPL1",
+            payload_size: size,
+            digest,
+            verify,
+            source: "ram",
+            verified: None,
+            hdr_crc: None,
+            region: (at, at + size),
+        });
+    }
+    // Nothing to check a bare payload against.
+    if cfg!(feature = "secure") && policy.runs(Check::Signature) {
+        policy.decide(Check::Signature, Err(ImageError::SignatureMissing))?;
+    }
+    check_load_region(PhysAddr::new(addr), OPENSBI_SCAN, forbidden)?;
+    if !image::contains_bytes(&mem, OPENSBI_SCAN, OPENSBI_BANNER) {
+        return Err(ImageError::BadMagic(magic));
+    }
+    Ok(Entry {
+        addr,
+        mode: PrivMode::Machine,
+        release_harts: false,
+        meta_rewrite: false,
+        format: "OpenSBI",
+        payload_size: 0,
+        digest: [0; SHA256_LEN],
+        verify: "none",
+        source: "ram",
+        verified: None,
+        hdr_crc: None,
+        region: (PhysAddr::new(addr), PhysAddr::new(addr) + OPENSBI_SCAN),
+    })
+}
+
+// Check a freshly written bank in flash, as far as possible without
+// loading it. Only SPL1 and uImage images are taken: a raw bank's CRC
+// trailer is at the very end of the bank, not where XMODEM stopped.
+fn verify_update(flashes: &[IntelFlash], bank: &BankDesc) -> Result<(), ImageError> {
+    let forbidden = forbidden_regions(flashes);
+    let flash = &flashes[bank.unit];
+    let offset = bank.offset.get();
+    if UImageHeader::probe(flash, offset) {
+        // parse() checks both CRCs.
+        return UImageHeader::parse(flash, offset, bank.size, &forbidden, &VerifyPolicy::STRICT).map(|_| ());
+    }
+    let hdr = SplImageHeader::parse(flash, offset, bank.size, &forbidden)?;
+    let Some(expected) = hdr.stored_digest() else {
+        // The CRC is of the inflated payload.
+        slog_warn!("update: compressed image without sha256, only checked at boot");
+        return Ok(());
+    };
+    match expected.check(flash, hdr.payload_offset(offset), hdr.payload_size as usize) {
+        Ok(()) => Ok(()),
+        Err(_) if expected.alg == Algorithm::Sha256 => Err(ImageError::DigestMismatch),
+        Err(actual) => Err(ImageError::CrcMismatch {
+            expected: hdr.payload_crc,
+            actual: actual.to_u32(),
+        }),
+    }
+}
+
+// The boot override left in the metadata block (see BootMeta), consumed
+// before it is honored so it counts for one boot only. Not honored if it
+// can't be consumed: stuck there, it would force every boot.
+fn take_boot_override(meta: &BootMeta, writes: bool) -> Option<ForceBoot> {
+    let pending = meta.boot_override()?;
+    if !writes {
+        slog_warn!("WARNING: boot override {:?} ignored: no NOR writes, it can't be consumed", pending);
+        return None;
     }
+    if meta.read_only() {
+        slog_warn!("WARNING: boot override {:?} ignored: boot log read-only, it can't be consumed", pending);
+        return None;
+    }
+    if let Err(e) = meta.consume_override() {
+        slog_warn!("WARNING: boot override {:?} ignored: could not consume it: {:?}", pending, e);
+        return None;
+    }
+    match pending {
+        Ok(force) => {
+            slog_info!("boot override: {}, this boot only (now consumed)", force);
+            Some(force)
+        }
+        Err(word) => {
+            slog_warn!("WARNING: boot override word 0x{:08x} means nothing, cleared", word);
+            None
+        }
+    }
+}
+
+// The bank an update goes to: the one we would fall back to from the
+// one we'd boot.
+fn update_target(meta: &BootMeta) -> BootBank {
+    let chosen = meta.choose_bank(MAX_TRIALS);
+    meta.fallback_order(chosen).nth(1).unwrap_or(chosen)
+}
+
+// Receive an image over XMODEM into `bank`, erasing its blocks as the
+// data reaches them, and give the bank a fresh set of boot trials if the
+// result verifies. A bad image has its first word zeroed so it can never
+// pass for a header.
+fn xmodem_update(flashes: &[IntelFlash], meta: &BootMeta, vcache: &VerifyCache, bank: BootBank) {
+    let desc = bank.desc();
+    let offset = desc.offset;
+    let flash = &flashes[desc.unit];
+    if let Err(e) = vcache.invalidate(bank) {
+        slog_warn!("WARNING: update: could not drop the cached verification of bank {}: {:?}", bank, e);
+    }
+    slog_info!("update: send the image for bank {} with XMODEM now", bank);
+    let mut erased_to = offset;
+    let received = xmodem::receive(desc.size, |pos, block| {
+        let end = offset + pos + block.len();
+        if end > erased_to {
+            let next = end.align_up(flash.block_size).ok_or(FlashError::OutOfRange)?;
+            flash.erase_range(erased_to, next - erased_to)?;
+            erased_to = next;
+        }
+        flash.program_buffered(offset + pos, block)
+    });
+    let received = match received {
+        Ok(n) => n,
+        Err(e) => {
+            slog_error!("update: transfer failed: {:?}", e);
+            if erased_to != offset {
+                let _ = flash.program(offset, &[0; 4]);
+            }
+            return;
+        }
+    };
+    slog_info!("update: received {} bytes into bank {}", received, bank);
+
+    if let Err(e) = verify_update(flashes, desc) {
+        slog_error!("update: bank {} rejected: {:?}", bank, e);
+        let _ = flash.program(offset, &[0; 4]);
+        return;
+    }
+    match meta.reset_trials(bank) {
+        Ok(()) => slog_info!("update: bank {} verified and ready", bank),
+        Err(e) => slog_warn!("WARNING: update: could not reset the trials of bank {}: {:?}", bank, e),
+    }
+}
 
-    slog!("spl1 ok, next load opensbi, bye");
+// "Hit any key to stop autoboot: N", counting down `delay_ms`. Returns
+// true if a key was hit. Any received byte counts, as does an RX error:
+// a key held down since before the countdown shows up as a full FIFO
+// (overrun) as often as a clean byte.
+fn autoboot_interrupted(delay_ms: u32) -> bool {
+    if delay_ms == 0 {
+        return false;
+    }
+    let mut w = ConsoleWriter;
+    let mut left_ms = delay_ms;
+    let _ = write!(w, "Hit any key to stop autoboot: {:2} ", left_ms.div_ceil(1000));
+    while left_ms > 0 {
+        let step = core::cmp::min(left_ms, 1000);
+        match logger::getc_timeout(clint::Deadline::after_us(step as u64 * 1000)) {
+            Err(logger::RxError::Timeout) => {}
+            _ => {
+                console_puts("\n");
+                return true;
+            }
+        }
+        left_ms -= step;
+        let _ = write!(w, "\x08\x08\x08{:2} ", left_ms.div_ceil(1000));
+    }
+    console_puts("\n");
+    false
+}
 
-    // For now, just loop so we keep QEMU alive and see the messages.
+// Recovery shell, until its "boot" command. Returns the bank it forced
+// for this boot, if any.
+#[cfg(feature = "console")]
+fn recovery_console(flash: &[IntelFlash], meta: &BootMeta) -> Option<BootBank> {
+    console::run(flash, meta)
+}
+
+// Without the shell there is nothing to go back to: stay in the SPL.
+#[cfg(not(feature = "console"))]
+fn recovery_console(_flash: &[IntelFlash], _meta: &BootMeta) -> Option<BootBank> {
+    console_idle()
+}
+
+// Nothing (more) to boot: stay in the SPL for whoever is on the console.
+fn console_idle() -> ! {
+    // CI runs: echo one line of console input back (for the RX test, if
+    // it sends any), then report success through QEMU's exit status.
+    if cfg!(feature = "test-mode") {
+        let mut line = [0u8; 64];
+        match logger::read_line(&mut line, clint::Deadline::after_us(1_000_000)) {
+            // Debug command: dump the RAM log.
+            Ok(n) if &line[..n] == b"log" => logger::replay_log(),
+            Ok(n) => slog_info!("echo: {}", core::str::from_utf8(&line[..n]).unwrap_or("?")),
+            Err(e) => slog_info!("no console input: {:?}", e),
+        }
+        exit_qemu(ExitCode::Pass);
+    }
+
+    // Keep QEMU alive so we can read the messages.
     loop {
         unsafe { core::arch::asm!("wfi") }
     }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn spl_main() -> ! {
+    budget::start(BOOT_DEADLINE_MS);
+    bootstage::mark(Stage::Start);
+    let kept_log = logger::ringbuf::init();
+    bootlog::init();
+    let reset = reset_cause::take();
+    trap::init();
+    // Long erases, copies and memory tests pet the watchdog as they go.
+    watchdog::set_yield_hook(Some(watchdog::pet_hook));
+
+    let args = arch::boot_args();
+    // The elected hart, not a0: that is what the payload gets in a0.
+    let (hartid, dtb_pa) = (args.hartid, args.dtb_pa);
+    // Until the console is found, the log only goes to RAM.
+    let dtb = unsafe { Fdt::from_addr(dtb_pa) };
+    let uart = logger::console_probe(dtb.as_ref().ok());
+    postcode::set(Postcode::UartUp);
+    slog_info!(
+        "spl1 starting on {} (hartid={}, dtb=0x{:016x})",
+        board::NAME,
+        hartid,
+        dtb_pa
+    );
+    match uart.found {
+        Found::Default => slog_warn!(
+            "WARNING: console: no UART answered, {:?} at 0x{:x} (board default)",
+            uart.kind,
+            uart.base
+        ),
+        how => slog_info!("console: {:?} UART at 0x{:x} ({:?})", uart.kind, uart.base, how),
+    }
+    layout::validate();
+    let (log_pa, log_size) = logger::ringbuf::region();
+    match kept_log {
+        Some(n) => slog_info!("log ring at 0x{:x}: kept {} bytes from the previous boot", log_pa, n),
+        None => slog_debug!("log ring at 0x{:x} ({} bytes), fresh", log_pa, log_size),
+    }
+
+    slog_info!("reset cause: {}", reset.name());
+    warmboot::take(WARM_FAST_BOOT && reset == ResetCause::Software);
+
+    if args.a0 != hartid {
+        slog_warn!("WARNING: a0 says hart {} but hart {} won the boot, using {}", args.a0, hartid, hartid);
+    }
+    let mut rec = Record::new(Event::SplStart, bootlog::SLOT_NONE);
+    rec.value = hartid as u64;
+    bootlog::record(&rec);
+
+    let desc = descriptor::read();
+    match desc.check() {
+        Ok(()) => slog_info!(
+            "spl1 version {} (git {}, built {})",
+            desc.version(),
+            desc.git(),
+            desc.build_time()
+        ),
+        Err(e) => slog_error!("SPL image damaged: bad self-descriptor ({:?})", e),
+    }
+    slog_debug!(
+        "self-descriptor at 0x{:x} (SPL image + 0x{:x})",
+        descriptor::addr(),
+        descriptor::OFFSET
+    );
+
+    match arch::check_static_init() {
+        Ok(()) => slog_debug!("static init ok (.bss cleared, .data copied)"),
+        Err((bss, data)) => slog_warn!(
+            "WARNING: static init broken (bss=0x{:08x}, data=0x{:08x})",
+            bss,
+            data
+        ),
+    }
+
+    let fdt = match dtb {
+        Ok(f) => {
+            slog_debug!(
+                "DTB magic 0x{:08x} found at 0x{:016x} ({} bytes)",
+                dtb::FDT_MAGIC,
+                dtb_pa,
+                f.as_bytes().len()
+            );
+            Some(f)
+        }
+        Err(e) => {
+            slog_warn!("WARNING: no valid DTB at 0x{:016x}: {:?}", dtb_pa, e);
+            None
+        }
+    };
+
+    let flash_base = dtb_base_or(fdt.as_ref(), "cfi-flash", board::FLASH_BASE[0]);
+    let clint_base = dtb_base_or(fdt.as_ref(), "riscv,clint0", board::CLINT_BASE);
+    let timebase = fdt
+        .as_ref()
+        .and_then(|f| f.node_prop("cpus", "timebase-frequency").ok().flatten())
+        .and_then(|v| v.try_into().ok())
+        .map(u32::from_be_bytes)
+        .unwrap_or(board::TIMEBASE_HZ);
+    clint::init(clint_base, timebase);
+    slog_debug!("timebase {} Hz, {} us since reset", timebase, clint::now_us());
+    let rtc_base = match fdt.as_ref().map(|f| f.find_compatible_reg("google,goldfish-rtc")) {
+        Some(Ok(Some(dev))) => Some(dev.reg.base),
+        _ => board::RTC_BASE,
+    };
+    rtc::init(rtc_base);
+    match rtc::now() {
+        Some(now) => slog_info!("wall clock: {}", now),
+        None => slog_debug!("wall clock: no RTC"),
+    }
+    let plic_base = dtb_base_or(fdt.as_ref(), "riscv,plic0", board::PLIC_BASE);
+    if !plic::init(plic_base, hartid) {
+        slog_debug!("PLIC: no M-mode context for hart {}, interrupts off", hartid);
+    }
+    #[cfg(feature = "plic-selftest")]
+    plic::self_test();
+    #[cfg(feature = "uart-irq")]
+    logger::uart_irq::start();
+
+    let fwcfg = if FWCFG { probe_fwcfg(fdt.as_ref()) } else { None };
+    let fdt = match (&fwcfg, fdt) {
+        (Some(fw), Some(f)) => Some(apply_fwcfg_env(fw, f)),
+        (Some(fw), None) if fw.find(fw_cfg::ENV_FILE).is_some() => {
+            slog_warn!("WARNING: fw_cfg: no DTB to put {} in, ignored", fw_cfg::ENV_FILE);
+            None
+        }
+        (_, fdt) => fdt,
+    };
+    budget::configure(fdt.as_ref());
+
+    #[cfg(feature = "fault-test")]
+    {
+        slog_info!("fault-test: executing an illegal instruction");
+        trap::trigger_test_fault();
+    }
+
+    // The DTB has one cfi-flash node for all units (QEMU: one reg entry
+    // each); the others keep their place relative to unit 0.
+    let unit_base = |unit: usize| PhysAddr::new(flash_base + board::FLASH_BASE[unit] - board::FLASH_BASE[0]);
+    budget::enter(Phase::Flash);
+    for unit in 0..board::FLASH_UNITS {
+        if let Err(e) = IntelFlash::reachable(unit_base(unit)) {
+            slog_error!("ERROR: flash{}: nothing answers at 0x{:x} ({:?})", unit, unit_base(unit), e);
+            slog_error!("ERROR: no boot log and no banks: not touching the flash at all");
+            ram_fallback(fdt.as_ref(), unit_base, hartid, dtb_pa, reset);
+        }
+    }
+    let flash: [IntelFlash; board::FLASH_UNITS] =
+        core::array::from_fn(|unit| probe_flash(unit, unit_base(unit)));
+    bootstage::mark(Stage::FlashProbed);
+    for (unit, f) in flash.iter().enumerate() {
+        log_flash_id(unit, f);
+    }
+    log_identity(&flash[0]);
+    let provisioned = layout_desc::apply(&flash);
+    layout::log_map(&flash.each_ref().map(|f| f.base));
+    let meta_flash = &flash[board::META_UNIT];
+    // A boot log we can't compact safely is a layout bug, like the ones
+    // layout::validate_unit() panics on.
+    let meta_offset = FlashOffset::new(board::META_OFFSET);
+    let Ok(meta) = BootMeta::new(meta_flash, meta_offset, board::META_SIZE, banks::table()) else {
+        panic!("boot metadata doesn't fit flash{}", board::META_UNIT);
+    };
+    if !provisioned.meta_writes {
+        meta.set_read_only("flash layout this SPL doesn't know");
+    }
+    if logger::log_enabled(Level::Debug) {
+        slog_debug!("boot metadata (first 64 bytes)");
+        logger::hexdump_flash(meta_flash, board::META_OFFSET, 64);
+    }
+    let vcache_offset = FlashOffset::new(board::VCACHE_OFFSET);
+    let vcache = VerifyCache::new(&flash[board::VCACHE_UNIT], vcache_offset, board::VCACHE_SIZE);
+    let blackbox_offset = FlashOffset::new(board::BLACKBOX_OFFSET);
+    let blackbox = BlackBox::new(&flash[board::BLACKBOX_UNIT], blackbox_offset, board::BLACKBOX_SIZE);
+    blackbox.report();
 
-    // TODO:
-    // jump_to_opensbi(hartid, dtb_pa);
+    budget::enter(Phase::Window);
+    let window = budget::allows(Optional::Window);
+    let mut forced = None;
+    let mut shell_used = false;
+    // Time in the shell or an update is the user's, not the boot's.
+    let since = clint::mtime();
+    let key = match window {
+        true => logger::getc_timeout(clint::Deadline::after_us(UPDATE_WINDOW_US)),
+        false => Err(logger::RxError::Timeout),
+    };
+    match key {
+        Ok(UPDATE_KEY) => {
+            xmodem_update(&flash, &meta, &vcache, update_target(&meta));
+            budget::excuse(since);
+        }
+        Ok(_) if cfg!(feature = "console") => {
+            forced = recovery_console(&flash, &meta);
+            shell_used = true;
+            budget::excuse(since);
+        }
+        _ => {}
+    }
+
+    let bootdelay = fdt
+        .as_ref()
+        .and_then(|f| f.node_prop("chosen", "spl,bootdelay-ms").ok().flatten())
+        .and_then(|v| v.try_into().ok())
+        .map(u32::from_be_bytes)
+        .unwrap_or(AUTOBOOT_DELAY_MS);
+    if window && !shell_used && autoboot_interrupted(bootdelay) {
+        // Nothing touches the boot log before this, so stopping autoboot
+        // never costs a bank a trial.
+        slog_info!("autoboot stopped, staying in SPL1");
+        let since = clint::mtime();
+        forced = recovery_console(&flash, &meta);
+        budget::excuse(since);
+    }
+
+    budget::enter(Phase::Meta);
+    let mut trials = meta.scan();
+    bootstage::mark(Stage::MetaScanned);
+    let storm = reboot_storm(fdt.as_ref(), &trials);
+    let built_in = BootPolicy {
+        writes: should_record_boot(dtb_pa),
+        no_record: false,
+        trust_success: TRUST_SUCCESS,
+        coalesce: COALESCE_ATTEMPTS,
+        meta_read_only: META_READ_ONLY,
+        reset,
+        storm,
+    };
+    let policy = BootPolicy::from_chosen(fdt.as_ref(), built_in);
+    policy.prepare(&meta, &ramtrials::take(reset), &mut trials);
+    if policy.writes {
+        if let Err(e) = blackbox.acknowledge() {
+            slog_warn!("WARNING: black box: {:?}", e);
+        }
+        blackbox::arm(&flash[board::BLACKBOX_UNIT]);
+    }
+    for bank in meta.banks() {
+        let t = trials.bank(bank);
+        slog_info!(
+            "boot trials: bank {}: {} unconfirmed, {} confirmed, {} died in SPL1",
+            bank,
+            t.unconfirmed,
+            t.confirmed,
+            t.no_handoff
+        );
+    }
+    slog_debug!("boot log next_idx = {}", trials.next_idx);
+    log_meta_wear(&meta);
+    if meta.poisoned() {
+        slog_error!("ERROR: boot log poisoned: banks by priority, nothing recorded");
+        slog_error!("ERROR: erase the boot log (console erase-meta) to recover");
+    }
+    if meta.erase_interrupted() {
+        slog_warn!("WARNING: boot log compaction was cut off mid-erase: no trials counted, compacting again");
+    }
+    if let Some((b, EntryState::HandedOff)) = trials.last {
+        match reset {
+            ResetCause::Watchdog => slog_warn!("WARNING: bank {}: previous boot hung, boot watchdog reset", b),
+            ResetCause::Software => slog_info!("bank {}: previous boot asked for a reboot", b),
+            ResetCause::PowerOn | ResetCause::Unknown | ResetCause::Deadline => {}
+        }
+    }
+
+    let mut golden_first = false;
+    let bank = match forced {
+        Some(bank) => {
+            slog_info!("chosen bank: {} (forced from the console)", bank);
+            bank
+        }
+        None => {
+            let bank = meta.choose_bank_from(&trials, MAX_TRIALS);
+            match take_boot_override(&meta, policy.writes) {
+                Some(ForceBoot::Bank(forced)) => {
+                    slog_info!("chosen bank: {} (boot override; would have been {})", forced, bank);
+                    forced
+                }
+                Some(ForceBoot::Golden) => {
+                    slog_info!("chosen: golden image (boot override; would have been bank {})", bank);
+                    golden_first = true;
+                    bank
+                }
+                None => {
+                    slog_info!("chosen bank: {}", bank);
+                    bank
+                }
+            }
+        }
+    };
+    let mut rec = Record::new(Event::MetaScan, bank.index() as u32);
+    rec.result = trials.bank(bank).failed();
+    rec.value = trials.next_idx as u64;
+    bootlog::record(&rec);
+
+    let boot_device = fdt
+        .as_ref()
+        .and_then(|f| f.node_prop("chosen", "spl,boot-device").ok().flatten())
+        .and_then(BootDevice::from_chosen)
+        .unwrap_or(BOOT_DEVICE);
+    let disk = match boot_device {
+        BootDevice::Disk => probe_disk(fdt.as_ref()),
+        BootDevice::Flash => None,
+    };
+    let banks = match &disk {
+        Some(disk) => Banks::Disk(disk, disk_banks(disk)),
+        None => Banks::Flash(&flash),
+    };
+    if boot_device == BootDevice::Disk && disk.is_none() {
+        slog_warn!("WARNING: boot device is disk but there is none, using flash");
+    }
+    let forbidden = forbidden_regions(&flash);
+    let memtest = MEMTEST
+        || fdt
+            .as_ref()
+            .is_some_and(|f| matches!(f.node_prop("chosen", "spl,memtest"), Ok(Some(_))));
+    // The memory test must also spare the DTB we were given (our patched
+    // copy is in the SPL's RAM, already in `forbidden`).
+    let (dtb_start, dtb_len) = fdt
+        .as_ref()
+        .map_or((0, 0), |f| (f.as_bytes().as_ptr() as usize, f.as_bytes().len()));
+    let dtb_region = Forbidden::new("dtb", PhysAddr::new(dtb_start), dtb_len);
+    // Flash is never in RAM: our RAM and the DTB are all there is to spare.
+    let memtest_exclude = [forbidden[0], dtb_region];
+    let paranoid = PARANOID_VERIFY
+        || fdt
+            .as_ref()
+            .is_some_and(|f| matches!(f.node_prop("chosen", "spl,paranoid"), Ok(Some(_))));
+    if paranoid {
+        slog_info!("paranoid: hashing every payload, cached verifications ignored");
+    }
+    let mut verify_policy = VerifyPolicy::from_chosen(fdt.as_ref());
+    verify_policy.log();
+
+    // Try each candidate in order. Validation alone never counts as an
+    // attempt: only the slot we actually jump into gets recorded.
+    // The golden image last, or first when the boot override asks.
+    let mut candidates = [Slot::Golden; MAX_BANKS + 1];
+    let mut n = usize::from(golden_first);
+    for b in meta.fallback_order(bank) {
+        candidates[n] = Slot::Bank(b);
+        n += 1;
+    }
+    let mut n = if golden_first { n } else { n + 1 };
+    budget::enter(Phase::Load);
+    if let Some(terminal) = budget::terminal() {
+        if terminal == Terminal::Stop {
+            deadline_stop();
+        }
+        slog_warn!("WARNING: boot deadline passed, dev build: trying {} only, checks only warn", candidates[0]);
+        verify_policy = verify_policy.best_effort();
+        n = 1;
+    }
+    let mut failures: [Option<ImageError>; MAX_BANKS + 1] = [None; MAX_BANKS + 1];
+    // PMP is only checked for S/U-mode accesses: set up for the payload
+    // now, so we know whether one that wants S-mode can get it.
+    let pmp_ready = setup_pmp(fdt.as_ref(), &flash[0]);
+    let mut booted = None;
+    let ram_payload = match fdt.as_ref().map(|f| f.node_prop("chosen", "spl,ram-payload")) {
+        Some(Ok(Some(v))) => {
+            Some(v.try_into().map_or(RAM_PAYLOAD_ADDR, |a| u64::from_be_bytes(a) as usize))
+        }
+        _ => PREFER_RAM_PAYLOAD.then_some(RAM_PAYLOAD_ADDR),
+    };
+    if let Some(fw) = &fwcfg
+        && let Some(entry) = load_fwcfg_payload(fw, &forbidden, pmp_ready, &verify_policy)
+    {
+        slog_info!("{} payload from fw_cfg, booting it as bank {}", entry.format, bank);
+        booted = Some((Slot::Bank(bank), entry));
+    }
+    if booted.is_none()
+        && let Some(addr) = ram_payload
+    {
+        match probe_ram_payload(addr, &forbidden, &verify_policy).and_then(|e| check_entry_mode(e, pmp_ready)) {
+            Ok(entry) => {
+                bootlog::record(&Record::new(Event::Verdict, Slot::Ram.event_code()));
+                slog_info!(
+                    "{} payload already in RAM at 0x{:x}, booting it as bank {}",
+                    entry.format,
+                    addr,
+                    bank
+                );
+                booted = Some((Slot::Bank(bank), entry));
+            }
+            Err(e) => slog_warn!(
+                "WARNING: no payload to boot in RAM at 0x{:x} ({:?}), loading bank {}",
+                addr,
+                e,
+                bank
+            ),
+        }
+    }
+    // Nothing to load when the payload is already in RAM.
+    let candidates = if booted.is_some() { &candidates[..0] } else { &candidates[..n] };
+    for (i, &slot) in candidates.iter().enumerate() {
+        if i > 0 && budget::expired() {
+            slog_error!("ERROR: boot deadline passed, {} and the rest not tried", slot);
+            break;
+        }
+        let cache = (!paranoid).then_some(&vcache);
+        let memtest = (memtest && budget::allows(Optional::Memtest)).then_some(&memtest_exclude[..]);
+        let loaded = load_slot(&banks, slot, &forbidden, memtest, cache, &verify_policy)
+            .and_then(|loaded| check_entry_mode(loaded, pmp_ready));
+        match loaded {
+            Ok(entry) => {
+                bootlog::record(&Record::new(Event::Verdict, slot.event_code()));
+                if let (Slot::Bank(b), Some(cached)) = (slot, entry.verified)
+                    && policy.writes
+                    && let Err(e) = vcache.store(b, &cached)
+                {
+                    slog_warn!("WARNING: bank {}: could not cache its verification: {:?}", b, e);
+                }
+                bootstage::mark(Stage::ImageCopied);
+                slog_info!("{} ok, entry=0x{:016x} ({:?} mode)", slot, entry.addr, entry.mode);
+                booted = Some((slot, entry));
+                break;
+            }
+            Err(ImageError::NotConfigured) => {}
+            Err(e) => {
+                slog_warn!("WARNING: {} failed: {:?}", slot, e);
+                let mut rec = Record::new(Event::Verdict, slot.event_code());
+                rec.result = 1;
+                bootlog::record(&rec);
+                if let Slot::Bank(b) = slot
+                    && policy.writes
+                    && let Err(e) = vcache.invalidate(b)
+                {
+                    slog_warn!("WARNING: bank {}: could not drop its cached verification: {:?}", b, e);
+                }
+                failures[i] = Some(e);
+            }
+        }
+    }
+
+    if let Some((slot, entry)) = booted {
+        budget::enter(Phase::Handoff);
+        let mut attempts = match slot {
+            Slot::Bank(b) => trials.bank(b).failed(),
+            Slot::Golden | Slot::Ram | Slot::Spl2 => 0,
+        };
+
+        let recorded = match slot {
+            Slot::Bank(b) => policy.record(&meta, &trials, b),
+            Slot::Golden | Slot::Ram | Slot::Spl2 => {
+                slog_info!("{} image: not recorded in the boot log", slot);
+                None
+            }
+        };
+        attempts += u32::from(recorded.is_some());
+
+        blackbox::note_attempts(attempts);
+
+        // Before the handoff is recorded: a broken SPL2 is skipped, not
+        // a failed boot.
+        let spl2 = load_spl2(&flash, &forbidden, &entry, &verify_policy);
+        // An SPL2 that can finish a compaction gets it: no waiting for
+        // the erase here.
+        if spl2.as_ref().is_some_and(|spl2| spl2.meta_rewrite) {
+            meta.hand_off_compaction();
+        }
+        // Reading SPL2 suspends a compaction's erase on a shared chip.
+        flash[board::META_UNIT].erase_resume();
+        if let (Slot::Bank(b), Some(hdr_crc)) = (slot, entry.hdr_crc)
+            && WARM_FAST_BOOT
+        {
+            warmboot::arm(b.index(), hdr_crc, &entry.digest);
+        }
+        // Reading the log for it waits for a compaction's erase (but one
+        // handed to SPL2).
+        let blob = metablob::build(&meta, slot.event_code(), &entry.digest);
+        let next_dtb_pa = match fdt.as_ref() {
+            Some(src) => patch_dtb(src, slot, attempts, reset, Some(blob.as_bytes())),
+            None => dtb_pa,
+        };
+        bootstage::mark(Stage::Jumping);
+        bootstage::report();
+        match &spl2 {
+            Some(spl2) => slog_info!(
+                "spl1 ok, jumping to spl2 at 0x{:016x} for {} at 0x{:016x} (dtb=0x{:016x}), bye",
+                spl2.addr,
+                slot,
+                entry.addr,
+                next_dtb_pa
+            ),
+            None => slog_info!(
+                "spl1 ok, jumping to {} at 0x{:016x} (dtb=0x{:016x}), bye",
+                slot,
+                entry.addr,
+                next_dtb_pa
+            ),
+        }
+        let stack = arch::stack_check();
+        slog_info!("stack used: {} of {} bytes", stack.used, stack.size);
+        let (arena_peak, arena_size) = arena::peak();
+        slog_info!("arena used: {} of {} bytes", arena_peak, arena_size);
+        // The flash access of this boot ends here, unless SPL2 has the
+        // compaction: nothing of ours can fail past this point.
+        boot_policy::record_handoff(&meta, recorded);
+        if meta.read_only() {
+            ramtrials::handoff(match slot {
+                Slot::Bank(b) => Some(b),
+                Slot::Golden | Slot::Ram | Slot::Spl2 => None,
+            });
+        }
+        let (jump_slot, jump_addr) = match &spl2 {
+            Some(spl2) => (Slot::Spl2, spl2.addr),
+            None => (slot, entry.addr),
+        };
+        let mut rec = Record::new(Event::Jump, jump_slot.event_code());
+        rec.value = jump_addr as u64;
+        bootlog::record(&rec);
+        let (events, digest) = bootlog::summary();
+        slog_debug!("event log: {} records, digest {}", events, Hex(&digest));
+        let mut block = SplHandoffV1::new(slot.event_code(), next_dtb_pa, entry.digest);
+        block.attempts = attempts;
+        let mut counts = [("", 0); MAX_BANKS];
+        let mut banks = 0;
+        for b in meta.banks() {
+            let this_boot = recorded.is_some() && slot == Slot::Bank(b);
+            counts[banks] = (b.desc().name, trials.bank(b).failed() + this_boot as u32);
+            block.set_bank_attempts(b.index(), counts[banks].1);
+            banks += 1;
+        }
+        logger::throttle::summary();
+        report::emit(&Report {
+            slot: slot.tag(),
+            attempts: &counts[..banks],
+            format: entry.format,
+            payload_size: entry.payload_size,
+            digest: &entry.digest,
+            verify: entry.verify,
+            source: entry.source,
+            mode: entry.mode,
+            rejected: failures.iter().flatten().count(),
+            boot_us: clint::now_us(),
+            storm: storm.map(|s| s.boots),
+            spl2: spl2.as_ref().map(|s| &s.digest),
+            policy: &verify_policy,
+            time: rtc::now(),
+            suppressed: logger::throttle::suppressed(),
+            budget: budget::Summary,
+            meta: if meta.poisoned() {
+                "poisoned"
+            } else if meta.read_only() {
+                "read-only"
+            } else {
+                "ok"
+            },
+            meta_blob: Some(blob.as_bytes().len()),
+        });
+        // Last, after record_boot(): a payload that never runs must still
+        // have its attempt counted. Not below M-mode: without an SBI the
+        // payload has no way to stop it.
+        let watchdog = WATCHDOG_TIMEOUT_US.filter(|_| entry.mode == PrivMode::Machine);
+        match watchdog {
+            Some(us) => {
+                slog_info!("arming boot watchdog: {} ms", us / 1000);
+                WATCHDOG.arm(us);
+            }
+            None if WATCHDOG_TIMEOUT_US.is_some() => {
+                slog_info!("boot watchdog not armed: {:?} mode payload", entry.mode)
+            }
+            None => {}
+        }
+        let block = handoff::publish(&block);
+        let Some(spl2) = spl2 else {
+            enter(entry, hartid, next_dtb_pa, watchdog.is_some(), block);
+        };
+        let mut handoff = Handoff::new(entry.addr, entry.mode, next_dtb_pa, entry.digest);
+        handoff.slot = slot.event_code();
+        handoff.attempts = attempts;
+        handoff.recorded = recorded.map_or(u32::MAX, |idx| idx as u32);
+        handoff.next_idx = meta.scan().next_idx as u32;
+        handoff.compactions = meta.compaction_count().unwrap_or(u32::MAX);
+        if entry.release_harts {
+            handoff.flags |= spl2::FLAG_RELEASE_HARTS;
+        }
+        if meta.poisoned() {
+            handoff.flags |= spl2::FLAG_META_POISONED;
+        }
+        if meta.read_only() {
+            handoff.flags |= spl2::FLAG_META_READ_ONLY;
+        }
+        if let Some(rewrite) = meta.handed_off() {
+            handoff.compaction(flash[board::META_UNIT].base.get() + board::META_OFFSET, &rewrite);
+            // Reads since may have suspended its erase.
+            flash[board::META_UNIT].erase_resume();
+        }
+        enter(spl2, hartid, next_dtb_pa, watchdog.is_some(), spl2::publish(&handoff));
+    }
+
+    if budget::terminal() == Some(Terminal::Stop) {
+        deadline_stop();
+    }
+    slog_error!("all boot candidates failed:");
+    for (slot, failure) in candidates.iter().zip(failures.iter()) {
+        if let Some(e) = failure {
+            slog_error!("  {}: {:?}", slot, e);
+        }
+    }
+    if cfg!(feature = "secure") {
+        slog_error!("secure boot: no bank passed verification, halting");
+    } else {
+        slog_error!("no bootable bank, staying in SPL1");
+    }
+
+    // Recovery: the shell can fix the boot log, then start over.
+    if cfg!(feature = "console") {
+        recovery_console(&flash, &meta);
+        slog_info!("resetting to boot again");
+        reset_cause::reset(ResetCause::Software);
+    }
+    console_idle();
 }
 
-#[allow(dead_code)]
-fn jump_to_opensbi(hartid: usize, dtb_pa: usize) -> ! {
-    let entry_ptr = OPENSBI_BASE as *const ();
-    let entry: extern "C" fn(usize, usize) -> ! =
-        unsafe { core::mem::transmute(entry_ptr) };
-    entry(hartid, dtb_pa)
+// The boot deadline passed with nothing loaded: record it in the black
+// box and reset (the reset signature says why), or stay in the SPL.
+fn deadline_stop() -> ! {
+    let phase = budget::phase();
+    slog_error!(
+        "ERROR: boot deadline of {} ms passed in {} with nothing loaded, giving up",
+        budget::deadline_ms().unwrap_or(0),
+        phase.name()
+    );
+    blackbox::record_deadline(phase.name());
+    if DEADLINE_RESET {
+        reset_cause::reset(ResetCause::Deadline);
+    }
+    console_idle()
+}
+
+// Leave PMP granting the next stage all of RAM.
+// Returns whether it took.
+fn setup_pmp(fdt: Option<&Fdt>, spl_flash: &IntelFlash) -> bool {
+    let ram = match fdt.map(|f| f.find_device_type_reg("memory")) {
+        Some(Ok(Some(dev))) => dev.reg,
+        _ => Region {
+            base: board::RAM_BASE,
+            size: board::RAM_SIZE,
+        },
+    };
+    let spl_flash = PMP_LOCK_SPL_FLASH.then_some(Region {
+        base: spl_flash.base.get(),
+        size: board::SPL_FLASH_SIZE,
+    });
+    match pmp::setup(ram, spl_flash) {
+        Ok(()) => true,
+        Err(e) => {
+            slog_warn!("WARNING: PMP setup failed: {:?}", e);
+            false
+        }
+    }
+}
+
+// Refuse a payload that wants S-mode (or U-mode) when PMP didn't take:
+// with no entry granting it memory, its first access would fault.
+fn check_entry_mode(entry: Entry, pmp_ready: bool) -> Result<Entry, ImageError> {
+    if entry.mode != PrivMode::Machine && !pmp_ready {
+        slog_error!("payload wants {:?} mode but PMP isn't set up, refusing it", entry.mode);
+        return Err(ImageError::SmodeWithoutPmp);
+    }
+    Ok(entry)
+}
+
+// The SPL2 to chainload before `payload`, loaded and checked like a
+// bank, and kept off the payload. None if its slot is erased (boot the
+// payload directly), or if it is broken: a bad SPL2 costs the features
+// it would have brought, not the boot.
+fn load_spl2(
+    flash: &[IntelFlash],
+    forbidden: &[Forbidden; 1 + board::FLASH_UNITS],
+    payload: &Entry,
+    policy: &VerifyPolicy,
+) -> Option<Entry> {
+    let unit = &flash[board::SPL2_UNIT];
+    if unit.read_u32_le(FlashOffset::new(board::SPL2_OFFSET)) == 0xFFFF_FFFF {
+        slog_debug!("spl2: slot erased, booting the payload directly");
+        return None;
+    }
+    let (start, end) = payload.region;
+    let mut avoid = [Forbidden::new("payload", start, end - start); 2 + board::FLASH_UNITS];
+    avoid[..forbidden.len()].copy_from_slice(forbidden);
+    let loaded = load_slot(&Banks::Flash(flash), Slot::Spl2, &avoid, None, None, policy).and_then(|e| {
+        if e.mode != PrivMode::Machine {
+            return Err(ImageError::Spl2NotMachine(e.mode));
+        }
+        Ok(e)
+    });
+    match loaded {
+        Ok(entry) => {
+            slog_info!("spl2 ok, entry=0x{:016x}, chainloading it", entry.addr);
+            Some(entry)
+        }
+        Err(e) => {
+            slog_error!("ERROR: spl2: {:?}, booting the payload directly", e);
+            None
+        }
+    }
+}
+
+// The fw_cfg device at the DTB's qemu,fw-cfg-mmio node or the board's
+// address, None if there is none.
+fn probe_fwcfg(fdt: Option<&Fdt>) -> Option<FwCfg> {
+    let base = match fdt.map(|f| f.find_compatible_reg("qemu,fw-cfg-mmio")) {
+        Some(Ok(Some(dev))) => dev.reg.base,
+        _ => board::FWCFG_BASE?,
+    };
+    let fw = FwCfg::probe(base);
+    match &fw {
+        Some(fw) => slog_info!(
+            "fw_cfg at 0x{:x} ({})",
+            base,
+            if fw.has_dma() { "dma" } else { "pio" }
+        ),
+        None => slog_debug!("fw_cfg: nothing at 0x{:x}", base),
+    }
+    fw
+}
+
+// Set the fw_cfg env's properties in /chosen of a RAM copy of `fdt`, so
+// every setting read from there sees them, and so does the next stage.
+// `fdt` as it was if there is no env or it can't be applied.
+fn apply_fwcfg_env(fw: &FwCfg, fdt: Fdt<'static>) -> Fdt<'static> {
+    let Some(file) = fw.find(fw_cfg::ENV_FILE) else {
+        return fdt;
+    };
+    let mut text = [0u8; FWCFG_ENV_MAX];
+    let env = match fw.read(file, &mut text) {
+        Ok(len) => Env::parse(&text[..len]),
+        Err(e) => {
+            slog_warn!("WARNING: fw_cfg: {}: {:?}, ignored", fw_cfg::ENV_FILE, e);
+            return fdt;
+        }
+    };
+    let env = match env {
+        Ok(env) => env,
+        Err(e) => {
+            slog_warn!("WARNING: fw_cfg: {}: {:?}, ignored", fw_cfg::ENV_FILE, e);
+            return fdt;
+        }
+    };
+    let Some(out) = arena::alloc(DTB_OUT_SIZE, 8) else {
+        slog_warn!("WARNING: fw_cfg: no room to patch the DTB, {} ignored", fw_cfg::ENV_FILE);
+        return fdt;
+    };
+    let props = env.props();
+    let props = &props[..env.len()];
+    match dtb_edit::patch_chosen(&fdt, props, out) {
+        Ok(patched) => {
+            for p in props {
+                slog_info!("fw_cfg: /chosen/{} set ({} bytes)", p.name, p.value.len());
+            }
+            patched
+        }
+        Err(e) => {
+            slog_warn!("WARNING: fw_cfg: {} not applied ({:?})", fw_cfg::ENV_FILE, e);
+            fdt
+        }
+    }
+}
+
+// Copy the fw_cfg payload, a bank image, to RAM_STAGE_ADDR and load it
+// from there like a bank. None, for the banks to be tried, if there is
+// none or it fails.
+fn load_fwcfg_payload(
+    fw: &FwCfg,
+    spl_forbidden: &[Forbidden],
+    pmp_ready: bool,
+    policy: &VerifyPolicy,
+) -> Option<Entry> {
+    let file = fw.find(fw_cfg::PAYLOAD_FILE)?;
+    let staged = MemSource {
+        base: RAM_STAGE_ADDR,
+        size: board::BANK_SIZE,
+    };
+    let stage = unsafe { core::slice::from_raw_parts_mut(staged.base as *mut u8, staged.size) };
+    if let Err(e) = fw.read(file, stage) {
+        slog_warn!("WARNING: fw_cfg: {}: {:?}, loading the banks", fw_cfg::PAYLOAD_FILE, e);
+        return None;
+    }
+    slog_info!(
+        "fw_cfg: {} ({} bytes) staged at 0x{:x}",
+        fw_cfg::PAYLOAD_FILE,
+        file.size,
+        staged.base
+    );
+    let stage_region = Forbidden::new("ram stage", PhysAddr::new(staged.base), staged.size);
+    let mut forbidden = [stage_region; 2 + board::FLASH_UNITS];
+    forbidden[..spl_forbidden.len()].copy_from_slice(spl_forbidden);
+    let loaded = load_slot(&Banks::Ram(&staged), Slot::Ram, &forbidden, None, None, policy)
+        .and_then(|loaded| check_entry_mode(loaded, pmp_ready));
+    match loaded {
+        Ok(entry) => {
+            bootlog::record(&Record::new(Event::Verdict, Slot::Ram.event_code()));
+            bootstage::mark(Stage::ImageCopied);
+            Some(Entry {
+                source: "fw_cfg",
+                ..entry
+            })
+        }
+        Err(e) => {
+            slog_warn!("WARNING: fw_cfg payload: {:?}, loading the banks", e);
+            let mut rec = Record::new(Event::Verdict, Slot::Ram.event_code());
+            rec.result = 1;
+            bootlog::record(&rec);
+            None
+        }
+    }
+}
+
+// Last resort when a flash unit is unreachable: boot an image staged in
+// RAM at RAM_STAGE_ADDR, checked like a bank, and record nothing. The
+// flash units only appear as regions to keep payloads off (and the SPL
+// flash for PMP); nothing is read from them.
+fn ram_fallback(
+    fdt: Option<&Fdt>,
+    unit_base: impl Fn(usize) -> PhysAddr,
+    hartid: usize,
+    dtb_pa: usize,
+    reset: ResetCause,
+) -> ! {
+    let staged = MemSource {
+        base: RAM_STAGE_ADDR,
+        size: board::BANK_SIZE,
+    };
+    slog_info!("looking for an image staged in RAM at 0x{:x}", staged.base);
+    let flash: [IntelFlash; board::FLASH_UNITS] =
+        core::array::from_fn(|unit| IntelFlash::unit(unit, unit_base(unit)));
+    let spl_forbidden = forbidden_regions(&flash);
+    let stage_region = Forbidden::new("ram stage", PhysAddr::new(staged.base), staged.size);
+    let mut forbidden = [stage_region; 2 + board::FLASH_UNITS];
+    forbidden[..spl_forbidden.len()].copy_from_slice(&spl_forbidden);
+
+    let pmp_ready = setup_pmp(fdt, &flash[0]);
+    let policy = VerifyPolicy::from_chosen(fdt);
+    policy.log();
+    let loaded = load_slot(&Banks::Ram(&staged), Slot::Ram, &forbidden, None, None, &policy)
+        .and_then(|loaded| check_entry_mode(loaded, pmp_ready));
+    let entry = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            slog_error!("ram: {:?}, nothing left to boot", e);
+            console_idle();
+        }
+    };
+    bootlog::record(&Record::new(Event::Verdict, Slot::Ram.event_code()));
+    let next_dtb_pa = match fdt {
+        Some(src) => patch_dtb(src, Slot::Ram, 0, reset, None),
+        None => dtb_pa,
+    };
+    bootstage::mark(Stage::Jumping);
+    let mut rec = Record::new(Event::Jump, Slot::Ram.event_code());
+    rec.value = entry.addr as u64;
+    bootlog::record(&rec);
+    logger::throttle::summary();
+    report::emit(&Report {
+        slot: Slot::Ram.tag(),
+        attempts: &[],
+        format: entry.format,
+        payload_size: entry.payload_size,
+        digest: &entry.digest,
+        verify: entry.verify,
+        source: entry.source,
+        mode: entry.mode,
+        rejected: 0,
+        boot_us: clint::now_us(),
+        storm: None,
+        spl2: None,
+        policy: &policy,
+        time: rtc::now(),
+        suppressed: logger::throttle::suppressed(),
+        budget: budget::Summary,
+        meta: "-",
+        meta_blob: None,
+    });
+    slog_info!(
+        "spl1: jumping to the RAM image at 0x{:016x} (dtb=0x{:016x}), bye",
+        entry.addr,
+        next_dtb_pa
+    );
+    let block = handoff::publish(&SplHandoffV1::new(Slot::Ram.event_code(), next_dtb_pa, entry.digest));
+    enter(entry, hartid, next_dtb_pa, false, block)
+}
+
+// Hand off to `entry` on this hart, releasing the parked ones into it
+// first if it asked for them. `arg2` goes in a2 (see spl2.rs and
+// handoff.rs).
+fn enter(entry: Entry, hartid: usize, dtb: usize, interrupts: bool, arg2: usize) -> ! {
+    #[cfg(feature = "uart-irq")]
+    {
+        let tx = logger::uart_irq::stats();
+        slog_info!("console: {} bytes sent by the TX interrupt, FIFO peak {} of {}", tx.sent, tx.peak, tx.size);
+    }
+    // A quiet UART for the next stage.
+    logger::quiesce();
+    let args = HandoffArgs {
+        entry: entry.addr,
+        hartid,
+        dtb,
+        arg2,
+        mode: entry.mode,
+        interrupts,
+    };
+    if entry.release_harts {
+        let released = arch::release_harts(&args);
+        slog_info!("released {} parked hart(s) into the payload", released);
+    }
+    // The payload may reset the board without us.
+    arch::reopen_election();
+    arch::handoff(&args)
 }
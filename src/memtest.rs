@@ -0,0 +1,177 @@
+use core::result::Result;
+
+use crate::addr::PhysAddr;
+use crate::image::Forbidden;
+use crate::watchdog::{self, Cancelled};
+
+// Quick destructive RAM test, for catching DRAM init problems in the SPL
+// (where we can still log) rather than as a payload crashing later.
+//
+// Words are sampled on a power-of-two stride chosen so a range of any
+// size costs at most MAX_SAMPLES words per pass:
+//
+//   1. address in address: every sample is written first, then read
+//      back, so an address line stuck or shorted shows up as an alias;
+//   2. 0x55.. then 0xAA.. in every sample (each data bit both ways);
+//   3. walking ones in every WALK_EVERY-th sample (data lines).
+//
+// Samples overlapping an excluded range are never touched. Each pass
+// over the samples ends in a yield point (see watchdog.rs): MAX_SAMPLES
+// words at most between two.
+
+const MAX_SAMPLES: usize = 1 << 16;
+const WALK_EVERY: usize = 16;
+const WORD: usize = core::mem::size_of::<u64>();
+
+const PATTERNS: [u64; 2] = [0x5555_5555_5555_5555, 0xaaaa_aaaa_aaaa_aaaa];
+// Passes over the samples: address write and check, each pattern's,
+// walking ones.
+const PASSES: usize = 2 + 2 * PATTERNS.len() + 1;
+
+/// First word that didn't read back as written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemFault {
+    pub addr: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemTestError {
+    Fault(MemFault),
+    /// Stopped by the yield hook, between two passes.
+    Cancelled,
+}
+
+impl From<MemFault> for MemTestError {
+    fn from(f: MemFault) -> Self {
+        MemTestError::Fault(f)
+    }
+}
+
+impl From<Cancelled> for MemTestError {
+    fn from(_: Cancelled) -> Self {
+        MemTestError::Cancelled
+    }
+}
+
+// Distance between tested words for a `len`-byte range.
+fn stride_for(len: usize) -> usize {
+    (len / MAX_SAMPLES).max(WORD).next_power_of_two()
+}
+
+fn write(addr: usize, v: u64) {
+    unsafe { core::ptr::write_volatile(addr as *mut u64, v) }
+}
+
+fn check(addr: usize, expected: u64) -> Result<(), MemFault> {
+    let actual = unsafe { core::ptr::read_volatile(addr as *const u64) };
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(MemFault {
+            addr,
+            expected,
+            actual,
+        })
+    }
+}
+
+/// Test [start, start + len), leaving garbage in it, skipping every word
+/// that overlaps one of the `exclude` ranges. Only whole aligned words
+/// inside the range are used.
+///
+/// # Safety
+/// Nothing in the range outside `exclude` may be in use.
+pub unsafe fn test_range(start: PhysAddr, len: usize, exclude: &[Forbidden]) -> Result<(), MemTestError> {
+    let first = start.get().next_multiple_of(WORD);
+    let end = start.get().saturating_add(len) & !(WORD - 1);
+    if first >= end {
+        return Ok(());
+    }
+    let stride = stride_for(end - first);
+    let samples = || {
+        (first..end - WORD + 1)
+            .step_by(stride)
+            .filter(|&a| !exclude.iter().any(|f| a < f.end.get() && f.start.get() < a + WORD))
+    };
+
+    let mut pass = 0;
+    let mut passed = || {
+        pass += 1;
+        watchdog::yield_point("memtest", pass, PASSES)
+    };
+
+    for a in samples() {
+        write(a, a as u64);
+    }
+    passed()?;
+    for a in samples() {
+        check(a, a as u64)?;
+    }
+    passed()?;
+
+    for pattern in PATTERNS {
+        for a in samples() {
+            write(a, pattern);
+        }
+        passed()?;
+        for a in samples() {
+            check(a, pattern)?;
+        }
+        passed()?;
+    }
+
+    for a in samples().step_by(WALK_EVERY) {
+        for bit in 0..u64::BITS {
+            write(a, 1 << bit);
+            check(a, 1 << bit)?;
+        }
+    }
+    passed()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watchdog::tests::watched;
+
+    // 4 MiB of host RAM, eight times the words a pass may touch.
+    const RAM_WORDS: usize = 8 * MAX_SAMPLES;
+
+    fn run(ram: &mut [u64], cancel_after: Option<usize>) -> (Result<(), MemTestError>, Vec<usize>) {
+        let (base, len) = (ram.as_mut_ptr() as usize, ram.len() * WORD);
+        // The first pass writes each sample's own address there: count
+        // them when it ends.
+        let at_yield = move || {
+            let ram = unsafe { core::slice::from_raw_parts(base as *const u64, len / WORD) };
+            let written = (0..ram.len()).filter(|&i| ram[i] == (base + i * WORD) as u64).count();
+            assert!(written <= MAX_SAMPLES, "{} words in a pass", written);
+        };
+        let test = || unsafe { test_range(PhysAddr::new(base), len, &[]) };
+        let (result, yields) = watched(cancel_after, at_yield, test);
+        assert!(yields.iter().all(|y| y.what == "memtest" && y.total == PASSES));
+        (result, yields.iter().map(|y| y.done).collect())
+    }
+
+    // A yield point after every pass, MAX_SAMPLES words at most each.
+    #[test]
+    fn yields_every_pass() {
+        let mut ram = vec![0u64; RAM_WORDS];
+        let (result, passes) = run(&mut ram, None);
+        assert_eq!(result, Ok(()));
+        assert_eq!(passes, (1..=PASSES).collect::<Vec<_>>());
+    }
+
+    // Cancelled, it stops at the end of the pass it was in.
+    #[test]
+    fn cancel_between_passes() {
+        let mut ram = vec![0u64; RAM_WORDS];
+        for n in 0..PASSES {
+            let (result, passes) = run(&mut ram, Some(n));
+            assert_eq!(result, Err(MemTestError::Cancelled));
+            assert_eq!(passes, (1..=n + 1).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use crate::bootmeta::{BootMeta, EntryState, MAX_BANKS};
+use crate::crc32::crc32;
+use crate::hash::SHA256_LEN;
+
+// The boot log summed up for the OS, so userspace gets the boot history
+// from /sys/firmware/devicetree instead of parsing the console: the
+// bytes of /chosen "spl,bootmeta-blob", with the format version in
+// "spl,bootmeta-version" (u32 cell). Built on the stack right before
+// the DTB is patched, no bigger than MAX_SIZE. Little-endian:
+//
+//   0x00  magic        b"SPLM"
+//   0x04  u16 version  VERSION
+//   0x06  u16 size     bytes of the blob, crc included
+//   0x08  compactions  u32, u32::MAX if the log has no header
+//   0x0c  slot         u32, bank index, or bootlog::SLOT_GOLDEN / SLOT_RAM
+//   0x10  next_idx     u32, the log's next free entry
+//   0x14  u8 banks     entries of `counts`
+//   0x15  u8 entries   entries of `recent`
+//   0x16  u8 flags     FLAG_*
+//   0x17  u8           zero
+//   0x18  digest       [32], booted payload sha256
+//   0x38  counts       per bank: u32 died in SPL1, u32 unconfirmed,
+//                      u32 confirmed
+//         recent       the last RECENT log entries at most, oldest
+//                      first: u8 bank index, u8 REASON_* (0xFF, 0xFF
+//                      for an entry that isn't an attempt), zero-padded
+//                      to 4 bytes
+//         crc          CRC-32 of everything before it
+//
+// Fields are only ever added with a new version; the counts and entries
+// are as the log has them after this boot's attempt is recorded.
+
+const MAGIC: [u8; 4] = *b"SPLM";
+/// Format version, also in /chosen "spl,bootmeta-version".
+pub const VERSION: u32 = 1;
+/// Log entries the blob carries at most.
+pub const RECENT: usize = 16;
+const COUNTS_AT: usize = 0x38;
+const COUNT_SIZE: usize = 12;
+/// Largest blob: every bank, RECENT entries.
+pub const MAX_SIZE: usize = COUNTS_AT + COUNT_SIZE * MAX_BANKS + (2 * RECENT).next_multiple_of(4) + 4;
+
+pub const FLAG_POISONED: u8 = 1 << 0;
+pub const FLAG_READ_ONLY: u8 = 1 << 1;
+pub const FLAG_ERASE_INTERRUPTED: u8 = 1 << 2;
+
+// How far an attempt got, by its log entry.
+pub const REASON_DIED_IN_SPL1: u8 = 0;
+pub const REASON_UNCONFIRMED: u8 = 1;
+pub const REASON_CONFIRMED: u8 = 2;
+
+/// A blob, in a buffer of the largest size.
+pub struct Blob {
+    bytes: [u8; MAX_SIZE],
+    len: usize,
+}
+
+impl Blob {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Sum up `meta` for a payload booted from `slot` (see bootlog::SLOT_*)
+/// with sha256 `digest`. Reads the log, so a compaction record_boot()
+/// left erasing is finished first.
+pub fn build(meta: &BootMeta, slot: u32, digest: &[u8; SHA256_LEN]) -> Blob {
+    let trials = meta.scan();
+    let mut b = [0u8; MAX_SIZE];
+    let mut flags = 0;
+    if meta.poisoned() {
+        flags |= FLAG_POISONED;
+    }
+    if meta.read_only() {
+        flags |= FLAG_READ_ONLY;
+    }
+    if meta.erase_interrupted() {
+        flags |= FLAG_ERASE_INTERRUPTED;
+    }
+    b[0x00..0x04].copy_from_slice(&MAGIC);
+    b[0x04..0x06].copy_from_slice(&(VERSION as u16).to_le_bytes());
+    b[0x08..0x0c].copy_from_slice(&meta.compaction_count().unwrap_or(u32::MAX).to_le_bytes());
+    b[0x0c..0x10].copy_from_slice(&slot.to_le_bytes());
+    b[0x10..0x14].copy_from_slice(&(trials.next_idx as u32).to_le_bytes());
+    b[0x16] = flags;
+    b[0x18..COUNTS_AT].copy_from_slice(digest);
+
+    let mut at = COUNTS_AT;
+    for bank in meta.banks() {
+        let t = trials.bank(bank);
+        for n in [t.no_handoff, t.unconfirmed, t.confirmed] {
+            b[at..at + 4].copy_from_slice(&n.to_le_bytes());
+            at += 4;
+        }
+        b[0x14] += 1;
+    }
+    for token in meta.entries_before(trials.next_idx, RECENT) {
+        let (bank, reason) = match meta.decode(token) {
+            Some((bank, EntryState::Started)) => (bank.index() as u8, REASON_DIED_IN_SPL1),
+            Some((bank, EntryState::HandedOff)) => (bank.index() as u8, REASON_UNCONFIRMED),
+            Some((bank, EntryState::Confirmed)) => (bank.index() as u8, REASON_CONFIRMED),
+            None => (0xFF, 0xFF),
+        };
+        b[at] = bank;
+        b[at + 1] = reason;
+        at += 2;
+        b[0x15] += 1;
+    }
+    at = at.next_multiple_of(4);
+    let len = at + 4;
+    b[0x06..0x08].copy_from_slice(&(len as u16).to_le_bytes());
+    let crc = crc32(&b[..at]);
+    b[at..len].copy_from_slice(&crc.to_le_bytes());
+    Blob { bytes: b, len }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::{FlashOffset, PhysAddr};
+    use crate::banks::BOOT_BANKS;
+    use crate::bootmeta::{BankDesc, BootBank};
+    use crate::flash_intel::IntelFlash;
+
+    const BLOCK: usize = 4096;
+    const SLOT: u32 = 1;
+    const DIGEST: [u8; SHA256_LEN] = [0x5A; SHA256_LEN];
+
+    // The blob as userspace reads it, by the layout above alone.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Decoded {
+        compactions: u32,
+        slot: u32,
+        next_idx: u32,
+        flags: u8,
+        digest: [u8; SHA256_LEN],
+        counts: Vec<[u32; 3]>,
+        recent: Vec<(u8, u8)>,
+    }
+
+    fn u32_at(b: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+    }
+
+    fn decode(b: &[u8]) -> Result<Decoded, &'static str> {
+        if b.len() < 0x38 + 4 || b.len() > MAX_SIZE {
+            return Err("size");
+        }
+        if b[..4] != *b"SPLM" || u16::from_le_bytes([b[4], b[5]]) != 1 {
+            return Err("magic or version");
+        }
+        if u16::from_le_bytes([b[6], b[7]]) as usize != b.len() {
+            return Err("size field");
+        }
+        let crc_at = b.len() - 4;
+        if u32_at(b, crc_at) != crc32(&b[..crc_at]) {
+            return Err("crc");
+        }
+        let (banks, entries) = (b[0x14] as usize, b[0x15] as usize);
+        let recent_at = 0x38 + 12 * banks;
+        if b[0x17] != 0 || recent_at + (2 * entries).next_multiple_of(4) != crc_at {
+            return Err("counts");
+        }
+        if b[recent_at + 2 * entries..crc_at].iter().any(|&x| x != 0) {
+            return Err("padding");
+        }
+        Ok(Decoded {
+            compactions: u32_at(b, 0x08),
+            slot: u32_at(b, 0x0c),
+            next_idx: u32_at(b, 0x10),
+            flags: b[0x16],
+            digest: b[0x18..0x38].try_into().unwrap(),
+            counts: (0..banks).map(|i| core::array::from_fn(|j| u32_at(b, 0x38 + 12 * i + 4 * j))).collect(),
+            recent: (0..entries).map(|i| (b[recent_at + 2 * i], b[recent_at + 2 * i + 1])).collect(),
+        })
+    }
+
+    fn flash() -> IntelFlash {
+        IntelFlash::new(PhysAddr::new(0x2000_0000), BLOCK, BLOCK, Vec::new())
+    }
+
+    // `n` boots of `bank`s in turn, every third one never handed off and
+    // every other one confirmed.
+    fn boots(meta: &BootMeta, banks: &[BootBank], n: usize) {
+        for i in 0..n {
+            let bank = banks[i % banks.len()];
+            let idx = meta.record_boot(bank).unwrap();
+            meta.finish_compaction().unwrap();
+            if i % 3 == 2 {
+                continue;
+            }
+            meta.record_handoff(idx).unwrap();
+            if i % 2 == 0 {
+                meta.record_success(bank).unwrap();
+            }
+        }
+    }
+
+    // What build() is to say about `meta`, straight from the log.
+    fn expected(meta: &BootMeta) -> Decoded {
+        let trials = meta.scan();
+        let reason = |token| match meta.decode(token) {
+            Some((bank, EntryState::Started)) => (bank.index() as u8, 0),
+            Some((bank, EntryState::HandedOff)) => (bank.index() as u8, 1),
+            Some((bank, EntryState::Confirmed)) => (bank.index() as u8, 2),
+            None => (0xFF, 0xFF),
+        };
+        Decoded {
+            compactions: meta.compaction_count().unwrap_or(u32::MAX),
+            slot: SLOT,
+            next_idx: trials.next_idx as u32,
+            flags: 0,
+            digest: DIGEST,
+            counts: meta
+                .banks()
+                .map(|b| trials.bank(b))
+                .map(|t| [t.no_handoff, t.unconfirmed, t.confirmed])
+                .collect(),
+            recent: meta.entries_before(trials.next_idx, RECENT).map(reason).collect(),
+        }
+    }
+
+    // A blob decodes back to what the log holds, with one entry per log
+    // entry up to RECENT of the newest, oldest first.
+    #[test]
+    fn round_trip() {
+        let flash = flash();
+        let meta = BootMeta::new(&flash, FlashOffset::new(0), BLOCK, &BOOT_BANKS).unwrap();
+        meta.init(true).unwrap();
+        let banks: Vec<_> = meta.banks().collect();
+        let mut done = 0;
+        for n in [0, 1, 5, RECENT - 1, RECENT, 40] {
+            boots(&meta, &banks, n - done);
+            done = n;
+            let blob = build(&meta, SLOT, &DIGEST);
+            let decoded = decode(blob.as_bytes()).unwrap();
+            assert_eq!(decoded, expected(&meta), "after {} boots", n);
+            assert_eq!(decoded.recent.len(), meta.entries().count().min(RECENT), "after {} boots", n);
+            assert_eq!(decoded.counts.len(), BOOT_BANKS.len());
+        }
+    }
+
+    // Every bank there can be and RECENT entries make the largest blob.
+    #[test]
+    fn max_size() {
+        let table: [BankDesc; MAX_BANKS] = core::array::from_fn(|i| BankDesc {
+            tag: 0x1234_5670 + ((i as u32) << 24),
+            legacy: None,
+            priority: i as u8,
+            ..BOOT_BANKS[0]
+        });
+        assert!(<BootMeta>::valid_table(&table));
+        let flash = flash();
+        let meta = BootMeta::new(&flash, FlashOffset::new(0), BLOCK, &table).unwrap();
+        meta.init(true).unwrap();
+        let banks: Vec<_> = meta.banks().collect();
+        boots(&meta, &banks, RECENT + MAX_BANKS);
+        let blob = build(&meta, SLOT, &DIGEST);
+        assert_eq!(blob.as_bytes().len(), MAX_SIZE);
+        let decoded = decode(blob.as_bytes()).unwrap();
+        assert_eq!((decoded.counts.len(), decoded.recent.len()), (MAX_BANKS, RECENT));
+        assert_eq!(decoded, expected(&meta));
+    }
+
+    // The CRC catches any bit flipped, and the flags say what the log
+    // is up to.
+    #[test]
+    fn crc_and_flags() {
+        let flash = flash();
+        let meta = BootMeta::new(&flash, FlashOffset::new(0), BLOCK, &BOOT_BANKS).unwrap();
+        meta.init(true).unwrap();
+        let banks: Vec<_> = meta.banks().collect();
+        boots(&meta, &banks, 7);
+        let blob = build(&meta, SLOT, &DIGEST);
+        let bytes = blob.as_bytes();
+        for bit in 0..8 * bytes.len() {
+            let mut b = bytes.to_vec();
+            b[bit / 8] ^= 1 << (bit % 8);
+            assert!(decode(&b).is_err(), "bit {} flipped", bit);
+        }
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "cut to {}", len);
+        }
+
+        meta.set_read_only("policy");
+        let decoded = decode(build(&meta, SLOT, &DIGEST).as_bytes()).unwrap();
+        assert_eq!(decoded.flags, FLAG_READ_ONLY);
+        assert_eq!(decoded.counts, expected(&meta).counts);
+    }
+}
@@ -0,0 +1,99 @@
+// Memory-mapped device registers.
+//
+// A `Reg<T>` is an address and a width, and every access through it is
+// volatile. Drivers declare their registers once with
+// `register_block!` and get one accessor per register, so the unsafe
+// (the promise that there is a device at that address) is made once,
+// where the block is created, instead of at every access:
+//
+//     register_block! {
+//         /// The UART.
+//         struct Uart {
+//             thr: Reg8 @ 0,
+//             lsr: Reg8 @ 5,
+//         }
+//     }
+//     let uart = unsafe { Uart::new(base) };
+//     uart.thr().write(b'x');
+
+use core::marker::PhantomData;
+
+/// One register of type `T` (u8, u16, u32, u64).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reg<T> {
+    addr: usize,
+    _width: PhantomData<T>,
+}
+
+pub type Reg8 = Reg<u8>;
+pub type Reg16 = Reg<u16>;
+pub type Reg32 = Reg<u32>;
+pub type Reg64 = Reg<u64>;
+
+impl<T: Copy> Reg<T> {
+    /// The register at `base + offset`.
+    ///
+    /// # Safety
+    /// There must be a `T`-wide register (or memory) there, for as long
+    /// as the Reg is used, that tolerates volatile accesses of that width.
+    pub const unsafe fn at(base: usize, offset: usize) -> Self {
+        Reg {
+            addr: base + offset,
+            _width: PhantomData,
+        }
+    }
+
+    pub const fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn read(&self) -> T {
+        unsafe { core::ptr::read_volatile(self.addr as *const T) }
+    }
+
+    pub fn write(&self, v: T) {
+        unsafe { core::ptr::write_volatile(self.addr as *mut T, v) }
+    }
+}
+
+/// Declare a register block: a struct holding the base address, with a
+/// `const unsafe fn new(base)` and one `Reg` accessor per register.
+/// Registers may share an offset (e.g. a read and a write register).
+macro_rules! register_block {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$reg_meta:meta])* $reg:ident: $width:ident @ $offset:expr),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        $vis struct $name {
+            base: usize,
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            /// The block at `base`.
+            ///
+            /// # Safety
+            /// The device must be at `base` (see `Reg::at()`).
+            $vis const unsafe fn new(base: usize) -> Self {
+                $name { base }
+            }
+
+            $vis const fn base(&self) -> usize {
+                self.base
+            }
+
+            $(
+                $(#[$reg_meta])*
+                $vis const fn $reg(&self) -> $crate::mmio::$width {
+                    unsafe { $crate::mmio::$width::at(self.base, $offset) }
+                }
+            )*
+        }
+    };
+}
+
+pub(crate) use register_block;
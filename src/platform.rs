@@ -0,0 +1,64 @@
+use crate::arch;
+use crate::board;
+use crate::clint::Deadline;
+use crate::logger;
+use crate::mmio::Reg32;
+use crate::slog_error;
+
+// QEMU "sifive_test" finisher device. Writing one of the FINISHER_*
+// values makes QEMU exit with a status derived from it.
+const SIFIVE_TEST: Reg32 = unsafe { Reg32::at(board::TEST_FINISHER_BASE, 0) };
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+// How long a reset gets to take effect before we give up on it.
+const RESET_WAIT_US: u64 = 100_000;
+
+/// Exit status reported to QEMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// QEMU exits with status 0.
+    Pass,
+    /// QEMU exits with the given (non-zero) status.
+    Fail(u16),
+}
+
+/// Terminate QEMU through the sifive_test device.
+///
+/// On anything that is not QEMU virt the write goes nowhere, so we fall
+/// back to parking the hart.
+pub fn exit_qemu(code: ExitCode) -> ! {
+    let value = match code {
+        ExitCode::Pass => FINISHER_PASS,
+        ExitCode::Fail(status) => ((status as u32) << 16) | FINISHER_FAIL,
+    };
+    logger::quiesce();
+    SIFIVE_TEST.write(value);
+
+    loop {
+        unsafe { core::arch::asm!("wfi") }
+    }
+}
+
+/// Reset the machine through the board's reset register (on QEMU the
+/// sifive_test device: it starts over from the reset vector, or exits
+/// with `-no-reboot`).
+///
+/// If that had no effect after RESET_WAIT_US, logs it and parks the hart.
+pub fn reset() -> ! {
+    logger::quiesce();
+    // RAM may well survive: let the next boot elect a boot hart again,
+    // with no secondary hart released before it says so.
+    arch::prepare_reset();
+    unsafe { Reg32::at(board::RESET_REG, 0) }.write(board::RESET_VALUE);
+    let deadline = Deadline::after_us(RESET_WAIT_US);
+    while !deadline.expired() {
+        core::hint::spin_loop();
+    }
+    slog_error!("reset: no effect after {} ms, halting", RESET_WAIT_US / 1000);
+
+    loop {
+        unsafe { core::arch::asm!("wfi") }
+    }
+}
@@ -0,0 +1,193 @@
+// Platform-level interrupt controller, SiFive layout (QEMU virt, FU540).
+// Sources are routed to the boot hart's M-mode context only: a machine
+// external interrupt is claimed, handed to the handler registered for
+// its source, and completed. A source without a handler is disabled
+// when it fires, so a stray one can't keep the hart in the trap.
+#![cfg_attr(not(any(feature = "uart-irq", feature = "plic-selftest")), allow(dead_code))]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::board;
+use crate::mmio::Reg32;
+use crate::slog_warn;
+
+const PRIORITY: usize = 0x00_0000; // + 4 * source
+const ENABLE: usize = 0x00_2000; // + 0x80 * context, one bit per source
+const THRESHOLD: usize = 0x20_0000; // + 0x1000 * context
+const CLAIM: usize = 0x20_0004; // + 0x1000 * context, complete on write
+
+// Sources, 0 (none) included.
+const SOURCES: usize = board::PLIC_SOURCES as usize;
+
+static BASE: AtomicUsize = AtomicUsize::new(board::PLIC_BASE);
+// usize::MAX: no M-mode context for the boot hart, nothing routed.
+static CONTEXT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// Written with the source disabled, read by dispatch().
+static mut HANDLERS: [Option<fn()>; SOURCES] = [None; SOURCES];
+
+// The PLIC's registers are all 32 bits wide.
+fn reg(offset: usize) -> Reg32 {
+    unsafe { Reg32::at(BASE.load(Ordering::Relaxed), offset) }
+}
+
+fn context() -> Option<usize> {
+    let context = CONTEXT.load(Ordering::Relaxed);
+    (context != usize::MAX).then_some(context)
+}
+
+fn enable_bit(context: usize, source: u32) -> (Reg32, u32) {
+    let word = ENABLE + 0x80 * context + 4 * (source as usize / 32);
+    (reg(word), 1 << (source % 32))
+}
+
+/// Use the PLIC at `base` (normally from the DTB), routing to the M-mode
+/// context of `hart`, with every source disabled there and the threshold
+/// at 0. False if the board has no such context: nothing is routed then.
+pub fn init(base: usize, hart: usize) -> bool {
+    BASE.store(base, Ordering::Relaxed);
+    let Some(&context) = board::PLIC_M_CONTEXT.get(hart) else {
+        CONTEXT.store(usize::MAX, Ordering::Relaxed);
+        return false;
+    };
+    for word in 0..SOURCES.div_ceil(32) {
+        reg(ENABLE + 0x80 * context + 4 * word).write(0);
+    }
+    CONTEXT.store(context, Ordering::Relaxed);
+    set_threshold(0);
+    true
+}
+
+/// Whether init() found a context to route to.
+pub fn routed() -> bool {
+    context().is_some()
+}
+
+/// Call `handler` when `source` fires. Register before enabling it.
+pub fn register(source: u32, handler: fn()) {
+    assert!(source != 0 && (source as usize) < SOURCES, "PLIC source {} out of range", source);
+    unsafe { (&raw mut HANDLERS).cast::<Option<fn()>>().add(source as usize).write(Some(handler)) };
+}
+
+/// Forget `source`'s handler (disable it first).
+pub fn unregister(source: u32) {
+    if (source as usize) < SOURCES {
+        unsafe { (&raw mut HANDLERS).cast::<Option<fn()>>().add(source as usize).write(None) };
+    }
+}
+
+fn handler(source: u32) -> Option<fn()> {
+    if source as usize >= SOURCES {
+        return None;
+    }
+    unsafe { (&raw const HANDLERS).cast::<Option<fn()>>().add(source as usize).read() }
+}
+
+/// 0 (never interrupts) to 7 on both boards.
+pub fn set_priority(source: u32, priority: u32) {
+    reg(PRIORITY + 4 * source as usize).write(priority);
+}
+
+/// Only sources above `threshold` interrupt the boot hart.
+pub fn set_threshold(threshold: u32) {
+    if let Some(context) = context() {
+        reg(THRESHOLD + 0x1000 * context).write(threshold);
+    }
+}
+
+/// Let `source` interrupt the boot hart (its priority must be above the
+/// threshold too).
+pub fn enable(source: u32) {
+    if let Some(context) = context() {
+        let (reg, bit) = enable_bit(context, source);
+        reg.write(reg.read() | bit);
+    }
+}
+
+pub fn disable(source: u32) {
+    if let Some(context) = context() {
+        let (reg, bit) = enable_bit(context, source);
+        reg.write(reg.read() & !bit);
+    }
+}
+
+/// The highest-priority pending source, now in service; 0 if none.
+pub fn claim() -> u32 {
+    context().map_or(0, |context| reg(CLAIM + 0x1000 * context).read())
+}
+
+/// Done with `source`, as returned by claim().
+pub fn complete(source: u32) {
+    if let Some(context) = context() {
+        reg(CLAIM + 0x1000 * context).write(source);
+    }
+}
+
+/// A machine external interrupt: run the handler of every pending
+/// source, from the trap handler.
+pub fn dispatch() {
+    loop {
+        let source = claim();
+        if source == 0 {
+            return;
+        }
+        match handler(source) {
+            Some(handler) => handler(),
+            None => {
+                slog_warn!("WARNING: PLIC source {} has no handler, disabled", source);
+                disable(source);
+            }
+        }
+        complete(source);
+    }
+}
+
+/// Debug aid: check that the console UART's interrupt makes it through
+/// the PLIC and the trap handler. Its TX FIFO is filled, then the
+/// THR-empty interrupt enabled: it must fire once the line is out.
+#[cfg(feature = "plic-selftest")]
+pub fn self_test() {
+    use crate::arch::csr;
+    use crate::clint::Deadline;
+    use crate::{logger, slog_info};
+
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn on_uart() {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+        logger::set_tx_irq(false);
+    }
+
+    if !routed() {
+        slog_warn!("WARNING: plic self-test: no M-mode context for this hart, skipped");
+        return;
+    }
+    if !logger::irq_capable() {
+        slog_warn!("WARNING: plic self-test: console not on the board's 16550, skipped");
+        return;
+    }
+    let source = board::UART_IRQ;
+    register(source, on_uart);
+    set_priority(source, 1);
+    enable(source);
+    let (mie, mstatus) = (csr::read_mie(), csr::read_mstatus());
+    // 16 bytes: the 16550's whole FIFO.
+    logger::console_puts("plic self-test\r\n");
+    logger::set_tx_irq(true);
+    csr::write_mie(mie | csr::MIE_MEIE);
+    csr::write_mstatus(mstatus.with_mie(true));
+    let deadline = Deadline::after_us(100_000);
+    while FIRED.load(Ordering::Relaxed) == 0 && !deadline.expired() {
+        core::hint::spin_loop();
+    }
+    csr::write_mstatus(mstatus);
+    csr::write_mie(mie);
+    logger::set_tx_irq(false);
+    disable(source);
+    unregister(source);
+    assert!(
+        FIRED.load(Ordering::Relaxed) != 0,
+        "plic self-test: UART source {} never reached its handler",
+        source
+    );
+    slog_info!("plic self-test: UART source {} dispatched", source);
+}
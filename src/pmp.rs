@@ -0,0 +1,111 @@
+use core::result::Result;
+
+use crate::arch::csr::{self, csr_read, csr_write};
+use crate::dtb::Region;
+use crate::slog_debug;
+
+// Physical memory protection, as left for the next stage.
+//
+// Entry 0 (optional) locks the SPL's own flash read/execute-only, entry 1
+// grants RWX on all of RAM. Everything else stays off. Note that with
+// any entry enabled S/U-mode accesses matching no entry are denied, so a
+// payload running below M-mode relies on OpenSBI to reprogram PMP, which
+// it does on every platform it supports.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmpError {
+    /// NAPOT needs a power-of-two region aligned to its size, >= 8 bytes.
+    NotNapot(Region),
+    /// The entry didn't read back as written (no PMP, or fewer entries).
+    NotImplemented,
+}
+
+// pmpaddr value for a NAPOT region. The size is rounded up to a power
+// of two (covering a bit more is harmless for a grant).
+fn napot_addr(r: Region) -> Result<usize, PmpError> {
+    let size = r.size.max(8).checked_next_power_of_two().ok_or(PmpError::NotNapot(r))?;
+    if !r.base.is_multiple_of(size) {
+        return Err(PmpError::NotNapot(r));
+    }
+    Ok((r.base >> 2) | ((size >> 3) - 1))
+}
+
+// Decode a NAPOT pmpaddr back to [base, base + size).
+fn napot_region(addr: usize) -> Region {
+    let ones = addr.trailing_ones();
+    let size = 1usize << (ones + 3);
+    Region {
+        base: (addr & !((1 << ones) - 1)) << 2,
+        size,
+    }
+}
+
+/// Program PMP entries 0/1 and log what the hardware kept.
+pub fn setup(ram: Region, spl_flash: Option<Region>) -> Result<(), PmpError> {
+    let ram_addr = napot_addr(ram)?;
+    let flash_addr = spl_flash.map(napot_addr).transpose()?;
+
+    let mut cfg = [csr::PMP_A_OFF; 2];
+    if let Some(addr) = flash_addr {
+        csr_write!(pmpaddr0, addr);
+        cfg[0] = csr::PMP_A_NAPOT | csr::PMP_R | csr::PMP_X | csr::PMP_L;
+    }
+    csr_write!(pmpaddr1, ram_addr);
+    cfg[1] = csr::PMP_A_NAPOT | csr::PMP_R | csr::PMP_W | csr::PMP_X;
+
+    // pmpcfg0 holds entries 0..7 on RV64; 2..7 are left off.
+    csr_write!(pmpcfg0, u16::from_le_bytes(cfg) as usize);
+
+    let readback = csr_read!(pmpcfg0);
+    let addrs = [csr_read!(pmpaddr0), csr_read!(pmpaddr1)];
+    for (i, &addr) in addrs.iter().enumerate() {
+        let c = (readback >> (8 * i)) as u8;
+        if c & csr::PMP_A_MASK == csr::PMP_A_OFF {
+            slog_debug!("pmp{}: off", i);
+            continue;
+        }
+        let r = napot_region(addr);
+        slog_debug!(
+            "pmp{}: napot 0x{:x}+0x{:x} {}{}{}{}",
+            i,
+            r.base,
+            r.size,
+            if c & csr::PMP_R != 0 { "r" } else { "-" },
+            if c & csr::PMP_W != 0 { "w" } else { "-" },
+            if c & csr::PMP_X != 0 { "x" } else { "-" },
+            if c & csr::PMP_L != 0 { " locked" } else { "" }
+        );
+    }
+    if (readback >> 8) as u8 != cfg[1] || addrs[1] != ram_addr {
+        return Err(PmpError::NotImplemented);
+    }
+    Ok(())
+}
+
+/// Entries 0/1 of one hart, as setup() left them, to program the same on
+/// another (PMP is per hart).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Saved {
+    cfg: usize,
+    addr: [usize; 2],
+}
+
+impl Saved {
+    pub const OFF: Saved = Saved { cfg: 0, addr: [0; 2] };
+}
+
+/// Read back this hart's entries 0/1.
+pub fn save() -> Saved {
+    Saved {
+        cfg: csr_read!(pmpcfg0) & 0xffff,
+        addr: [csr_read!(pmpaddr0), csr_read!(pmpaddr1)],
+    }
+}
+
+/// Program this hart's entries 0/1 like `saved`, addresses first since
+/// a locked entry takes no more writes.
+pub fn restore(saved: &Saved) {
+    csr_write!(pmpaddr0, saved.addr[0]);
+    csr_write!(pmpaddr1, saved.addr[1]);
+    csr_write!(pmpcfg0, saved.cfg);
+}
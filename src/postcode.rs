@@ -0,0 +1,54 @@
+// Boot progress codes ("postcodes"): one byte at board::POSTCODE_ADDR,
+// overwritten at each milestone, so a debugger (or a RAM dump) tells how
+// far a silent or hung boot got, UART or not. Codes only go up during a
+// boot; the trap and panic paths stamp their own, above all of them.
+//
+// The byte sits in the last 16 bytes of the SPL RAM window, which the
+// stack stops short of (see linker.ld); nothing clears it, so it also
+// survives a warm reset until the next boot's first stamp. The first
+// three codes are stamped by _start, before any Rust code runs, with the
+// same single byte store. The board may also have a port (LEDs, a GPIO
+// register) that gets every code from Rust on.
+
+use crate::board;
+
+/// Every code, in boot order. Keep the README's table in sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Postcode {
+    /// _start, boot hart elected, running from flash.
+    Entered = 0x01,
+    /// Image relocated to RAM, .bss cleared (_start).
+    BssCleared = 0x02,
+    /// Stack painted and sp set, about to enter spl_main (_start).
+    StackSet = 0x03,
+    /// Console UART initialized: from here on, the log says more.
+    UartUp = 0x04,
+    FlashProbed = 0x05,
+    MetaScanned = 0x06,
+    /// A candidate's image passed its checks (once per candidate).
+    ImageVerified = 0x07,
+    /// ...and was copied to its load address.
+    ImageLoaded = 0x08,
+    /// Handing off to the payload (or SPL2).
+    Jumping = 0x09,
+    /// Fatal trap in the SPL.
+    Trap = 0xf0,
+    /// Panic in the SPL.
+    Panic = 0xf1,
+}
+
+/// Stamp `code`: a single volatile byte store, and one to the board's
+/// port if it has one.
+pub fn set(code: Postcode) {
+    unsafe { core::ptr::write_volatile(board::POSTCODE_ADDR as *mut u8, code as u8) };
+    if let Some(port) = board::POSTCODE_PORT {
+        unsafe { core::ptr::write_volatile(port as *mut u8, code as u8) };
+    }
+}
+
+/// The last code stamped (by this boot, or the one before a warm reset
+/// if this one hasn't stamped yet).
+pub fn get() -> u8 {
+    unsafe { core::ptr::read_volatile(board::POSTCODE_ADDR as *const u8) }
+}
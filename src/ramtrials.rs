@@ -0,0 +1,130 @@
+use crate::bootmeta::{BootBank, Trials, MAX_BANKS};
+use crate::crc32::crc32;
+use crate::reset_cause::ResetCause;
+
+// Boot trials of the current power cycle, for a boot log the SPL can't
+// write (read-only, see BootMeta). Nothing is recorded in flash then, so
+// the attempts are counted in a RAM record that _start doesn't clear,
+// next to the reset signature (see reset_cause.rs) and surviving the
+// same resets:
+//
+//   0x00  magic   b"SRTL"
+//   0x04  flags   FLAG_READ_ONLY: the boot log was read-only
+//   0x08  last    index + 1 of the bank the last boot handed off, or 0
+//   0x0c  failed  u32 per bank (MAX_BANKS): handoffs that ended in a
+//                 reset nobody asked for
+//   0x2c  crc     CRC-32 of everything above
+//
+// A payload can't confirm its boot in a log nobody can write: a handoff
+// counts as failed when the next boot follows a reset other than a
+// software one (the watchdog's, or one nobody signed). The counts are
+// added to the boot log's, which stay as they were, for the bank choice.
+// No valid record means RAM didn't survive: a cold boot, nothing
+// counted. The read-only flag lasts as long: once a write was refused,
+// the SPL stops trying until the next power cycle.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SRTL");
+const FLAG_READ_ONLY: u32 = 1 << 0;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(8))]
+struct Record {
+    magic: u32,
+    flags: u32,
+    last: u32,
+    failed: [u32; MAX_BANKS],
+    crc: u32,
+}
+
+const EMPTY: Record = Record {
+    magic: 0,
+    flags: 0,
+    last: 0,
+    failed: [0; MAX_BANKS],
+    crc: 0,
+};
+
+impl Record {
+    fn crc_of(&self) -> u32 {
+        let mut b = [0u8; 12 + 4 * MAX_BANKS];
+        b[..4].copy_from_slice(&self.magic.to_le_bytes());
+        b[4..8].copy_from_slice(&self.flags.to_le_bytes());
+        b[8..12].copy_from_slice(&self.last.to_le_bytes());
+        for (chunk, n) in b[12..].chunks_exact_mut(4).zip(self.failed) {
+            chunk.copy_from_slice(&n.to_le_bytes());
+        }
+        crc32(&b)
+    }
+}
+
+#[unsafe(link_section = ".spl_noinit")]
+static mut RECORD: Record = EMPTY;
+
+fn record() -> *mut Record {
+    &raw mut RECORD
+}
+
+fn load() -> Record {
+    let r = unsafe { core::ptr::read_volatile(record()) };
+    if r.magic == MAGIC && r.crc == r.crc_of() { r } else { EMPTY }
+}
+
+fn store(mut r: Record) {
+    r.magic = MAGIC;
+    r.crc = r.crc_of();
+    unsafe { core::ptr::write_volatile(record(), r) };
+}
+
+/// What the earlier boots of this power cycle left.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cycle {
+    /// The boot log was found read-only.
+    pub read_only: bool,
+    failed: [u32; MAX_BANKS],
+}
+
+impl Cycle {
+    /// Handoffs of `bank` that ended in a reset nobody asked for.
+    pub fn failed(&self, bank: BootBank) -> u32 {
+        self.failed[bank.index()]
+    }
+
+    /// Count them as unconfirmed attempts in `trials` too.
+    pub fn add_to(&self, trials: &mut Trials) {
+        for (t, n) in trials.banks.iter_mut().zip(self.failed) {
+            t.unconfirmed = t.unconfirmed.saturating_add(n);
+        }
+    }
+}
+
+/// Read the record, `reset` being what reset the board: the handoff of
+/// the boot before fails unless it was a software reset.
+pub fn take(reset: ResetCause) -> Cycle {
+    let mut r = load();
+    let last = (r.last as usize).checked_sub(1).filter(|&i| i < MAX_BANKS);
+    if let Some(i) = last
+        && reset != ResetCause::Software
+    {
+        r.failed[i] = r.failed[i].saturating_add(1);
+    }
+    r.last = 0;
+    store(r);
+    Cycle {
+        read_only: r.flags & FLAG_READ_ONLY != 0,
+        failed: r.failed,
+    }
+}
+
+/// Before a handoff with the boot log read-only: remember that, and the
+/// bank handed off (None for anything else), for the next boot.
+pub fn handoff(bank: Option<BootBank>) {
+    let mut r = load();
+    r.flags |= FLAG_READ_ONLY;
+    r.last = bank.map_or(0, |b| b.index() as u32 + 1);
+    store(r);
+}
+
+/// Address and size of the record.
+pub fn region() -> (usize, usize) {
+    (record() as usize, core::mem::size_of::<Record>())
+}
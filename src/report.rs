@@ -0,0 +1,127 @@
+use core::fmt;
+
+use crate::arch::csr::PrivMode;
+use crate::budget;
+use crate::hash::{Hex, SHA256_LEN};
+use crate::logger;
+use crate::rtc::DateTime;
+use crate::verify_policy::VerifyPolicy;
+
+// One line summing up the boot, printed right before the jump for the
+// scripts that read the console:
+//
+//   SPL1-REPORT: version=0.1.0 git=v0.1-12-g1a2b3c4 slot=A attempts=A:1,B:0
+//   format=SPL1 size=123456 sha256=9f86d081 verify=sha256 mode=M
+//   rejected=0 boot_us=81234 source=flash storm=- spl2=-
+//   policy=magic:S,crc:E,sha256:E,signature:E time=2026-10-15T09:12:44Z
+//   suppressed=0 budget=init:3/100,flash:41/200,window:200/300,
+//   meta:12/200,load:310/5000,handoff:9/1000,total:575/10000 meta=ok
+//   metablob=96
+//
+// (one line on the console, wrapped here). Space-separated key=value
+// pairs in this order; values never hold spaces. Keys are only ever
+// added, at the end. Printed whatever the log level and without the log
+// line prefix, so a quiet build reports too.
+
+pub const SENTINEL: &str = "SPL1-REPORT:";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// `git describe` of the tree built, from build.rs.
+pub const GIT: &str = env!("SPL_GIT_DESCRIBE");
+
+// Digest bytes printed: enough to tell images apart in a lab log.
+const DIGEST_PREFIX: usize = 4;
+
+/// What went into the boot, as printed by emit().
+pub struct Report<'a> {
+    /// Bank name, "golden" or "ram".
+    pub slot: &'a str,
+    /// Unconfirmed attempts of every bank, by name, this one included.
+    pub attempts: &'a [(&'a str, u32)],
+    /// Image format: SPL1, uImage, raw, OpenSBI (bare, found in RAM).
+    pub format: &'a str,
+    pub payload_size: usize,
+    pub digest: &'a [u8; SHA256_LEN],
+    /// Strongest check the image passed: signature, sha256,
+    /// sha256-cached (on an earlier boot, see vcache.rs), crc, or none (a
+    /// bare payload found in RAM).
+    pub verify: &'a str,
+    /// Where the payload came from: flash, disk, or ram (already there,
+    /// or staged).
+    pub source: &'a str,
+    pub mode: PrivMode,
+    /// Candidates tried and refused before this one.
+    pub rejected: usize,
+    pub boot_us: u64,
+    /// Warm boots in a row without a confirmed one, when that made it a
+    /// reboot storm (degraded mode, see storm.rs).
+    pub storm: Option<u32>,
+    /// Digest of the SPL2 chainloaded before the payload, if any (see
+    /// spl2.rs).
+    pub spl2: Option<&'a [u8; SHA256_LEN]>,
+    /// What a failed check of the payload did (see verify_policy.rs).
+    pub policy: &'a VerifyPolicy,
+    /// Wall-clock time of the report, if the board has an RTC.
+    pub time: Option<DateTime>,
+    /// Log messages dropped by the throttle (see logger/throttle.rs).
+    pub suppressed: u32,
+    /// Time used against the boot budget, phase by phase, in ms (see
+    /// budget.rs).
+    pub budget: budget::Summary,
+    /// The boot log: ok, read-only or poisoned (see BootMeta), "-" for a
+    /// boot that didn't read it.
+    pub meta: &'a str,
+    /// Bytes of /chosen "spl,bootmeta-blob" (see metablob.rs), None
+    /// without one.
+    pub meta_blob: Option<usize>,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} version={} git={} slot={} attempts=", SENTINEL, VERSION, GIT, self.slot)?;
+        if self.attempts.is_empty() {
+            f.write_str("-")?;
+        }
+        for (i, (bank, n)) in self.attempts.iter().enumerate() {
+            write!(f, "{}{}:{}", if i == 0 { "" } else { "," }, bank, n)?;
+        }
+        write!(
+            f,
+            " format={} size={} sha256={} verify={} mode={} rejected={} boot_us={} source={}",
+            self.format,
+            self.payload_size,
+            Hex(&self.digest[..DIGEST_PREFIX]),
+            self.verify,
+            match self.mode {
+                PrivMode::Machine => "M",
+                PrivMode::Supervisor => "S",
+                PrivMode::User => "U",
+            },
+            self.rejected,
+            self.boot_us,
+            self.source
+        )?;
+        match self.storm {
+            Some(boots) => write!(f, " storm={}", boots)?,
+            None => f.write_str(" storm=-")?,
+        }
+        match self.spl2 {
+            Some(digest) => write!(f, " spl2={}", Hex(&digest[..DIGEST_PREFIX]))?,
+            None => f.write_str(" spl2=-")?,
+        }
+        write!(f, " policy={}", self.policy)?;
+        match self.time {
+            Some(time) => write!(f, " time={}", time)?,
+            None => f.write_str(" time=-")?,
+        }
+        write!(f, " suppressed={} budget={} meta={}", self.suppressed, self.budget, self.meta)?;
+        match self.meta_blob {
+            Some(size) => write!(f, " metablob={}", size),
+            None => f.write_str(" metablob=-"),
+        }
+    }
+}
+
+/// Print the report line.
+pub fn emit(report: &Report) {
+    logger::raw_line(format_args!("{}", report));
+}
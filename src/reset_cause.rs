@@ -0,0 +1,156 @@
+use crate::crc32::crc32;
+use crate::platform;
+
+// Why the board came out of reset. QEMU virt has no reset-cause register,
+// so whoever resets on purpose leaves a signature in a RAM word that
+// _start doesn't clear, and the next boot reads and wipes it:
+//
+//   0x00  magic  b"SRST"
+//   0x04  cause  ResetCause as u32 (Watchdog, Software or Deadline)
+//   0x08  crc    CRC-32 of the 8 bytes above
+//
+// No valid signature means nobody asked for this reset: a cold boot as
+// far as we can tell. RAM holds garbage at power-on on real boards,
+// hence the CRC.
+//
+// The next stage can ask for a deliberate reboot the same way: its
+// location is advertised in /chosen as "spl,reset-signature" (u64
+// address, u64 size). A reset nobody signed (an SBI reset from the
+// kernel, say) looks like a power-on.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SRST");
+
+/// What reset the board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ResetCause {
+    /// No signature: power-on, or a reset nobody announced.
+    PowerOn = 0,
+    /// The boot watchdog expired: the previous payload hung.
+    Watchdog = 1,
+    /// Asked for, by the SPL or the next stage.
+    Software = 2,
+    /// A signature with a good CRC but a cause we don't know.
+    Unknown = 3,
+    /// The SPL ran out of boot time with nothing loaded (see budget.rs).
+    Deadline = 4,
+}
+
+impl ResetCause {
+    pub fn name(self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power-on",
+            ResetCause::Watchdog => "watchdog",
+            ResetCause::Software => "software",
+            ResetCause::Unknown => "unknown",
+            ResetCause::Deadline => "boot deadline",
+        }
+    }
+}
+
+#[repr(C, align(8))]
+struct Signature {
+    magic: u32,
+    cause: u32,
+    crc: u32,
+}
+
+impl Signature {
+    fn crc_of(magic: u32, cause: u32) -> u32 {
+        let mut b = [0u8; 8];
+        b[..4].copy_from_slice(&magic.to_le_bytes());
+        b[4..].copy_from_slice(&cause.to_le_bytes());
+        crc32(&b)
+    }
+}
+
+#[unsafe(link_section = ".spl_noinit")]
+static mut SIGNATURE: Signature = Signature {
+    magic: 0,
+    cause: 0,
+    crc: 0,
+};
+
+fn sig() -> *mut Signature {
+    &raw mut SIGNATURE
+}
+
+/// Read the signature left by the previous boot, and wipe it so the next
+/// unannounced reset reads as a power-on.
+pub fn take() -> ResetCause {
+    let s = sig();
+    let (magic, cause, crc) = unsafe {
+        (
+            core::ptr::read_volatile(&raw const (*s).magic),
+            core::ptr::read_volatile(&raw const (*s).cause),
+            core::ptr::read_volatile(&raw const (*s).crc),
+        )
+    };
+    unsafe { core::ptr::write_volatile(&raw mut (*s).magic, 0) };
+    if magic != MAGIC || crc != Signature::crc_of(magic, cause) {
+        return ResetCause::PowerOn;
+    }
+    match cause {
+        1 => ResetCause::Watchdog,
+        2 => ResetCause::Software,
+        4 => ResetCause::Deadline,
+        _ => ResetCause::Unknown,
+    }
+}
+
+/// Leave `cause` for the next boot to find.
+pub fn set(cause: ResetCause) {
+    let s = sig();
+    unsafe {
+        core::ptr::write_volatile(&raw mut (*s).cause, cause as u32);
+        core::ptr::write_volatile(&raw mut (*s).crc, Signature::crc_of(MAGIC, cause as u32));
+        core::ptr::write_volatile(&raw mut (*s).magic, MAGIC);
+    }
+}
+
+/// Reset the board, telling the next boot why.
+pub fn reset(cause: ResetCause) -> ! {
+    set(cause);
+    platform::reset()
+}
+
+/// Address and size of the signature.
+pub fn region() -> (usize, usize) {
+    (sig() as usize, core::mem::size_of::<Signature>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The signature as the previous boot, or power-on RAM, left it.
+    fn leave(magic: u32, cause: u32, crc: u32) {
+        unsafe { sig().write_volatile(Signature { magic, cause, crc }) };
+    }
+
+    // One test, as the signature is a static.
+    #[test]
+    fn signatures() {
+        for cause in [ResetCause::Watchdog, ResetCause::Software, ResetCause::Deadline] {
+            set(cause);
+            assert_eq!(take(), cause);
+            // Taken, it is stale: the next reset nobody signs is a power-on.
+            assert_eq!(take(), ResetCause::PowerOn);
+        }
+
+        // A good CRC over a cause we don't know.
+        leave(MAGIC, 7, Signature::crc_of(MAGIC, 7));
+        assert_eq!(take(), ResetCause::Unknown);
+
+        // A cause changed since its CRC was.
+        leave(MAGIC, 2, Signature::crc_of(MAGIC, 1));
+        assert_eq!(take(), ResetCause::PowerOn);
+
+        // Power-on RAM: erased-looking, zeroed, or only the magic right.
+        let good = Signature::crc_of(MAGIC, 1);
+        for (magic, cause, crc) in [(!0, !0, !0), (0, 0, 0), (MAGIC, 1, 0), (MAGIC ^ 1, 1, good)] {
+            leave(magic, cause, crc);
+            assert_eq!(take(), ResetCause::PowerOn, "{:08x} {} {:08x}", magic, cause, crc);
+        }
+    }
+}
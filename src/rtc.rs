@@ -0,0 +1,112 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch;
+
+// Goldfish RTC (QEMU virt): wall-clock time as 64-bit nanoseconds since
+// the Unix epoch, in two 32-bit registers. Reading TIME_LOW latches
+// TIME_HIGH, so low must be read first. Used for timestamps only: the
+// SPL never depends on the date being right, and a board without an RTC
+// (or with one never set, reading zero) just has no wall clock.
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+// 0: no RTC.
+static RTC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Use the goldfish RTC at `base` (normally from the DTB), or none.
+pub fn init(base: Option<usize>) {
+    RTC_BASE.store(base.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Seconds since the Unix epoch, None without a working RTC. A read
+/// that faults (nothing at the address) counts as none.
+pub fn unix_secs() -> Option<u64> {
+    let base = RTC_BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return None;
+    }
+    // Low first: it latches high.
+    let lo = unsafe { arch::try_read_volatile::<u32>(base + TIME_LOW) }.ok()?;
+    let hi = unsafe { arch::try_read_volatile::<u32>(base + TIME_HIGH) }.ok()?;
+    let ns = (hi as u64) << 32 | lo as u64;
+    (ns != 0).then_some(ns / NS_PER_SEC)
+}
+
+/// The current UTC date and time, None without a working RTC.
+pub fn now() -> Option<DateTime> {
+    unix_secs().map(DateTime::from_unix)
+}
+
+/// A UTC date and time, to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Break down `secs` since 1970-01-01 00:00:00 UTC.
+    pub fn from_unix(secs: u64) -> Self {
+        let (days, secs) = (secs / 86400, secs % 86400);
+        // Civil date from days since 1970-01-01 (Howard Hinnant's
+        // days_from_civil, inverted).
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        DateTime {
+            year: yoe + era * 400 + (month <= 2) as i64,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+/// ISO 8601, no spaces: 2024-02-29T12:34:56Z.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(secs: u64) -> String {
+        format!("{}", DateTime::from_unix(secs))
+    }
+
+    // The epoch, both sides of the leap days 2000 has and 2100 hasn't,
+    // and past 2^31 seconds.
+    #[test]
+    fn from_unix() {
+        assert_eq!(date(0), "1970-01-01T00:00:00Z");
+        assert_eq!(date(951_782_399), "2000-02-28T23:59:59Z");
+        assert_eq!(date(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(date(951_868_800), "2000-03-01T00:00:00Z");
+        assert_eq!(date(4_107_542_399), "2100-02-28T23:59:59Z");
+        assert_eq!(date(4_107_542_400), "2100-03-01T00:00:00Z");
+        assert_eq!(date(1_709_210_096), "2024-02-29T12:34:56Z");
+        assert_eq!(date(1 << 31), "2038-01-19T03:14:08Z");
+    }
+}
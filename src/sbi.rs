@@ -0,0 +1,92 @@
+use crate::arch::csr::csr_read;
+use crate::clint::Deadline;
+use crate::logger;
+use crate::reset_cause::{self, ResetCause};
+use crate::trap::TrapFrame;
+use crate::warmboot;
+
+// Just enough SBI for a bare S-mode payload to talk: the legacy console
+// putchar/getchar calls, the base extension and reboots through the
+// system reset extension, answered from the SPL's trap handler (the SPL
+// stays resident in M-mode under the payload, its trap vector still in
+// mtvec). Everything else is SBI_ERR_NOT_SUPPORTED. A warm reboot writes
+// the payload's warm token first (see warmboot.rs); a cold one doesn't.
+//
+// A debug aid for payload bring-up ("sbi-shim" feature), not an
+// OpenSBI replacement: no timer, IPI, HSM or shutdown, and the payload
+// must leave the SPL's RAM alone.
+
+const EXT_LEGACY_PUTCHAR: usize = 0x01;
+const EXT_LEGACY_GETCHAR: usize = 0x02;
+const EXT_BASE: usize = 0x10;
+const EXT_SRST: usize = 0x5352_5354;
+
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_GET_IMPL_ID: usize = 1;
+const BASE_GET_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+const BASE_GET_MVENDORID: usize = 4;
+const BASE_GET_MARCHID: usize = 5;
+const BASE_GET_MIMPID: usize = 6;
+
+const SRST_SYSTEM_RESET: usize = 0;
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_TYPE_COLD_REBOOT: usize = 1;
+const RESET_TYPE_WARM_REBOOT: usize = 2;
+
+const SBI_SUCCESS: isize = 0;
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+const SBI_ERR_INVALID_PARAM: isize = -3;
+
+// SBI v0.2: the first with the base extension.
+const SPEC_VERSION: usize = 2;
+// Not a registered implementation ID: "SPL1".
+const IMPL_ID: usize = u32::from_le_bytes(*b"SPL1") as usize;
+const IMPL_VERSION: usize = 1;
+
+/// Answer the ecall in `frame` (a7: extension, a6: function, a0..: its
+/// arguments), leaving the result in its a0/a1. The caller steps mepc
+/// over the ecall.
+pub fn handle_ecall(frame: &mut TrapFrame) {
+    let (eid, fid, arg0) = (frame.a(7), frame.a(6), frame.a(0));
+    // Legacy calls return a single value in a0.
+    match eid {
+        EXT_LEGACY_PUTCHAR => {
+            logger::console_putc(arg0 as u8);
+            frame.set_a(0, 0);
+            return;
+        }
+        EXT_LEGACY_GETCHAR => {
+            let c = logger::getc_timeout(Deadline::after_us(0)).map_or(-1, |b| b as isize);
+            frame.set_a(0, c as usize);
+            return;
+        }
+        _ => {}
+    }
+    let (error, value) = match (eid, fid) {
+        (EXT_BASE, BASE_GET_SPEC_VERSION) => (SBI_SUCCESS, SPEC_VERSION),
+        (EXT_BASE, BASE_GET_IMPL_ID) => (SBI_SUCCESS, IMPL_ID),
+        (EXT_BASE, BASE_GET_IMPL_VERSION) => (SBI_SUCCESS, IMPL_VERSION),
+        (EXT_BASE, BASE_PROBE_EXTENSION) => (
+            SBI_SUCCESS,
+            matches!(arg0, EXT_BASE | EXT_SRST | EXT_LEGACY_PUTCHAR | EXT_LEGACY_GETCHAR) as usize,
+        ),
+        (EXT_BASE, BASE_GET_MVENDORID) => (SBI_SUCCESS, csr_read!(mvendorid)),
+        (EXT_BASE, BASE_GET_MARCHID) => (SBI_SUCCESS, csr_read!(marchid)),
+        (EXT_BASE, BASE_GET_MIMPID) => (SBI_SUCCESS, csr_read!(mimpid)),
+        (EXT_SRST, SRST_SYSTEM_RESET) => match arg0 {
+            RESET_TYPE_WARM_REBOOT => {
+                if !warmboot::sign() {
+                    logger::console_puts("sbi: warm reboot, nothing to vouch for\n");
+                }
+                reset_cause::reset(ResetCause::Software)
+            }
+            RESET_TYPE_COLD_REBOOT => reset_cause::reset(ResetCause::Software),
+            RESET_TYPE_SHUTDOWN => (SBI_ERR_NOT_SUPPORTED, 0),
+            _ => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    frame.set_a(0, error as usize);
+    frame.set_a(1, value);
+}
@@ -0,0 +1,159 @@
+use crate::board;
+
+// Host stand-in for src/arch/: the CSRs the shared modules read and
+// write, kept per thread (mhartid reads as 0), no instruction cache to
+// sync, and the probe's loads and stores done as plain ones but where a
+// test unmapped the address (probe::unmap()). The SPL's own regions are
+// where linker.ld puts them in the board's RAM window, image then .bss
+// then the stack, with nothing of the host's there.
+
+pub use probe::{try_read_volatile, Fault};
+
+const IMAGE_SIZE: usize = 256 * 1024;
+const BSS_SIZE: usize = 128 * 1024;
+const STACK_SIZE: usize = 64 * 1024;
+
+pub fn spl_ram_region() -> (usize, usize) {
+    (board::RAM_BASE, board::RAM_BASE + board::SPL_RAM_SIZE)
+}
+
+pub fn image_region() -> (usize, usize) {
+    (board::RAM_BASE, board::RAM_BASE + IMAGE_SIZE)
+}
+
+pub fn bss_region() -> (usize, usize) {
+    let (_, start) = image_region();
+    (start, start + BSS_SIZE)
+}
+
+pub fn stack_region() -> (usize, usize) {
+    let (_, start) = bss_region();
+    (start, start + STACK_SIZE)
+}
+
+pub mod barrier {
+    pub fn sync_icache_for_region(_addr: usize, _len: usize) {}
+}
+
+pub mod csr {
+    use std::cell::Cell;
+
+    /// Every CSR read by name reads as zero.
+    macro_rules! csr_read {
+        ($csr:ident) => {
+            0usize
+        };
+    }
+
+    pub(crate) use csr_read;
+
+    pub const MIE_MTIE: usize = 1 << 7;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PrivMode {
+        User = 0,
+        Supervisor = 1,
+        Machine = 3,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Mstatus(pub usize);
+
+    impl Mstatus {
+        const MIE: usize = 1 << 3;
+
+        pub fn mie(self) -> bool {
+            self.0 & Self::MIE != 0
+        }
+
+        pub fn with_mie(self, on: bool) -> Self {
+            if on {
+                Mstatus(self.0 | Self::MIE)
+            } else {
+                Mstatus(self.0 & !Self::MIE)
+            }
+        }
+    }
+
+    thread_local! {
+        static MIE: Cell<usize> = const { Cell::new(0) };
+        static MSTATUS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub fn read_mhartid() -> usize {
+        0
+    }
+
+    pub fn read_mie() -> usize {
+        MIE.with(Cell::get)
+    }
+
+    pub fn write_mie(v: usize) {
+        MIE.with(|mie| mie.set(v));
+    }
+
+    pub fn read_mstatus() -> Mstatus {
+        Mstatus(MSTATUS.with(Cell::get))
+    }
+
+    pub fn write_mstatus(v: Mstatus) {
+        MSTATUS.with(|mstatus| mstatus.set(v.0));
+    }
+}
+
+pub mod probe {
+    use std::cell::RefCell;
+    use std::ops::Range;
+
+    // mcause of an access fault.
+    pub const LOAD_ACCESS_FAULT: usize = 5;
+    pub const STORE_ACCESS_FAULT: usize = 7;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Fault {
+        pub cause: usize,
+        pub addr: usize,
+    }
+
+    thread_local! {
+        static HOLES: RefCell<Vec<Range<usize>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Make this thread's probed accesses to `range` fault from now on,
+    /// as with nothing mapped there.
+    #[cfg(test)]
+    pub fn unmap(range: Range<usize>) {
+        HOLES.with(|holes| holes.borrow_mut().push(range));
+    }
+
+    // The fault of a `T` access at `addr`, if it touches a hole.
+    fn hole<T>(addr: usize, cause: usize) -> Result<(), Fault> {
+        let end = addr + core::mem::size_of::<T>();
+        match HOLES.with(|holes| holes.borrow().iter().any(|h| addr < h.end && h.start < end)) {
+            true => Err(Fault { cause, addr }),
+            false => Ok(()),
+        }
+    }
+
+    pub trait Primitive: Copy + Default {}
+
+    impl Primitive for u8 {}
+    impl Primitive for u16 {}
+    impl Primitive for u32 {}
+    impl Primitive for u64 {}
+
+    /// # Safety
+    /// `addr` must be readable host memory, or unmapped.
+    pub unsafe fn try_read_volatile<T: Primitive>(addr: usize) -> Result<T, Fault> {
+        hole::<T>(addr, LOAD_ACCESS_FAULT)?;
+        Ok(unsafe { core::ptr::read_volatile(addr as *const T) })
+    }
+
+    /// # Safety
+    /// `addr` must be writable host memory, or unmapped.
+    pub unsafe fn try_write_volatile<T: Primitive>(addr: usize, v: T) -> Result<(), Fault> {
+        hole::<T>(addr, STORE_ACCESS_FAULT)?;
+        unsafe { core::ptr::write_volatile(addr as *mut T, v) };
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+// Host stand-in for src/arena.rs: only the heap's place, a buffer of the
+// host's aligned as linker.ld aligns .heap, so that RAM the console's
+// "mw" may write is there to write.
+
+const SIZE: usize = 64 * 1024;
+
+#[repr(align(16))]
+struct Heap([u8; SIZE]);
+
+static mut HEAP: Heap = Heap([0; SIZE]);
+
+pub fn region() -> (usize, usize) {
+    let base = &raw mut HEAP as usize;
+    (base, base + SIZE)
+}
@@ -0,0 +1,65 @@
+use std::cell::Cell;
+
+// Host stand-in for src/clint.rs: a clock that only moves when the
+// simulation says time passed, each thread its own. The stand-ins for
+// the hardware advance it by what their work takes on a real board
+// (a flash erase, a byte on the serial line), waits jump it to their
+// end, and tests read it to see how often a long operation yields.
+// mtime counts microseconds.
+
+thread_local! {
+    static NOW: Cell<u64> = const { Cell::new(0) };
+    static MTIMECMP: Cell<u64> = const { Cell::new(u64::MAX) };
+}
+
+/// Let `us` microseconds pass.
+pub fn advance_us(us: u64) {
+    NOW.with(|now| now.set(now.get().saturating_add(us)));
+}
+
+pub fn mtime() -> u64 {
+    NOW.with(Cell::get)
+}
+
+pub fn set_mtimecmp(_hartid: usize, ticks: u64) {
+    MTIMECMP.with(|cmp| cmp.set(ticks));
+}
+
+/// What the last set_mtimecmp() left there.
+#[cfg(test)]
+pub fn mtimecmp() -> u64 {
+    MTIMECMP.with(Cell::get)
+}
+
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    ticks
+}
+
+pub fn now_us() -> u64 {
+    mtime()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    at: u64,
+}
+
+impl Deadline {
+    pub fn after_us(us: u64) -> Self {
+        Deadline {
+            at: mtime().saturating_add(us),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        mtime() >= self.at
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.at
+    }
+}
+
+pub fn delay_us(us: u64) {
+    advance_us(us);
+}
@@ -0,0 +1,593 @@
+use std::cell::{Cell, RefCell};
+
+use crate::addr::{FlashOffset, PhysAddr};
+use crate::{board, clint};
+use crate::image::ImageSource;
+use crate::watchdog::{self, Cancelled};
+
+// Host stand-in for the NOR driver (src/flash_intel.rs): the part of
+// IntelFlash the boot log uses, over a RAM copy of a pflash image.
+// Programming only clears bits, like the real part, and is checked the
+// same way. A background erase (erase_start()) behaves as the real
+// one: nothing changes until something waits for it, a read of the
+// chip suspends it and the block under erase reads as garbage (zeros)
+// meanwhile, and any other command runs it to its end first.
+//
+// Tests make it misbehave with inject(): failed programs or erases, a
+// power cut partway through one, bytes stuck at their value. A block
+// can be locked too (lock()): a program or erase of it fails with
+// Protected, as the part's status register has it, and changes nothing.
+//
+// Operations take simulated time (see clint.rs), about what a real part
+// needs: reads READ_US_PER_KIB, a program PROGRAM_US, a block erase
+// ERASE_US. The long ones (erase_range(), chip_erase()) have the
+// driver's yield points, between two block erases.
+
+const READ_US_PER_KIB: u64 = 20;
+const PROGRAM_US: u64 = 20;
+const ERASE_US: u64 = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+    ProgramError,
+    #[cfg_attr(not(test), allow(dead_code))]
+    EraseError,
+    Protected,
+    OutOfRange,
+    MetaPoisoned,
+    MetaReadOnly,
+    Cancelled,
+}
+
+impl From<Cancelled> for FlashError {
+    fn from(_: Cancelled) -> Self {
+        FlashError::Cancelled
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff_us: u64,
+}
+
+/// A block erase started by erase_start(), by block index, with the
+/// error inject() has it end with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BgErase {
+    Idle,
+    Running(usize, Option<FlashError>),
+    /// Reads as data again (but its own block) until resumed.
+    Suspended(usize, Option<FlashError>),
+    /// Over, its outcome not collected by erase_finish() yet.
+    Done(Result<(), FlashError>),
+}
+
+/// What inject() makes the flash do. Operations count from 1, from the
+/// inject() call on.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct Faults {
+    /// Programs `.0` to `.0 + .1 - 1` fail with `.2`, the flash left as
+    /// it was.
+    pub program: Option<(u32, u32, FlashError)>,
+    /// The same for erases (block_erase() and erase_start()).
+    pub erase: Option<(u32, u32, FlashError)>,
+    /// Power is cut after this many more bytes programmed or erased:
+    /// the operation under way stops there (a program from its lowest
+    /// byte up, so a word is torn with its top byte erased), and nothing
+    /// after it changes the flash.
+    pub power_cut: Option<usize>,
+    /// Offsets of bytes no program or erase changes.
+    pub stuck: Vec<usize>,
+}
+
+/// What the flash went through since inject().
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct Ops {
+    pub programs: u32,
+    pub erases: u32,
+    /// Bytes programmed or erased.
+    pub bytes: usize,
+    /// The power is cut.
+    pub cut: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashId {
+    pub manufacturer: u16,
+    pub device: u16,
+    pub features: Option<u32>,
+    pub stride: usize,
+}
+
+pub struct IntelFlash {
+    pub base: PhysAddr,
+    pub size: usize,
+    pub block_size: usize,
+    pub retry: RetryPolicy,
+    mem: RefCell<Vec<u8>>,
+    faults: RefCell<Faults>,
+    ops: Cell<Ops>,
+    erase: Cell<BgErase>,
+    locked: RefCell<Vec<bool>>,
+}
+
+impl IntelFlash {
+    /// The unit at `base`, `image` its contents (padded with erased
+    /// bytes to `size`).
+    pub fn new(base: PhysAddr, size: usize, block_size: usize, mut image: Vec<u8>) -> Self {
+        image.resize(size, 0xff);
+        IntelFlash {
+            base,
+            size,
+            block_size,
+            retry: RetryPolicy { attempts: 3, backoff_us: 0 },
+            mem: RefCell::new(image),
+            faults: RefCell::new(Faults::default()),
+            ops: Cell::new(Ops::default()),
+            erase: Cell::new(BgErase::Idle),
+            locked: RefCell::new(vec![false; size / block_size]),
+        }
+    }
+
+    /// Flash unit `unit` at `base`, with the board's geometry, erased.
+    pub fn unit(unit: usize, base: PhysAddr) -> Self {
+        IntelFlash::new(base, board::FLASH_SIZE[unit], board::FLASH_BLOCK_SIZE[unit], Vec::new())
+    }
+
+    /// Misbehave as `faults` says from now on, in place of any earlier
+    /// faults (Faults::default(): power back, and none).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn inject(&self, faults: Faults) {
+        self.faults.replace(faults);
+        self.ops.set(Ops::default());
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn ops(&self) -> Ops {
+        self.ops.get()
+    }
+
+    /// Set the lock bit of a block: no program or erase of it from now
+    /// on (the mock has no unlock command).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn lock(&self, block_index: usize) {
+        self.locked.borrow_mut()[block_index] = true;
+    }
+
+    // Whether any byte of `range` is in a locked block.
+    fn is_locked(&self, range: core::ops::Range<usize>) -> bool {
+        let locked = self.locked.borrow();
+        (range.start / self.block_size..range.end.div_ceil(self.block_size)).any(|b| locked[b])
+    }
+
+    // The error operation `n` of a kind is to fail with, if any.
+    fn fault(n: u32, window: Option<(u32, u32, FlashError)>) -> Option<FlashError> {
+        window.filter(|&(from, len, _)| n >= from && n - from < len).map(|(_, _, e)| e)
+    }
+
+    // Bytes of a `len`-byte operation done before the power cut: all of
+    // them without one.
+    fn powered(&self, len: usize) -> usize {
+        let mut ops = self.ops.get();
+        let mut faults = self.faults.borrow_mut();
+        let n = match faults.power_cut {
+            Some(left) => {
+                faults.power_cut = Some(left.saturating_sub(len));
+                ops.cut = left <= len;
+                left.min(len)
+            }
+            None => len,
+        };
+        ops.bytes += n;
+        self.ops.set(ops);
+        n
+    }
+
+    // Program `bytes` at `offset` as the part does: bits only go from 1
+    // to 0, and the result is checked.
+    pub fn program(&self, offset: FlashOffset, bytes: &[u8]) -> Result<(), FlashError> {
+        let offset = offset.get();
+        if offset.checked_add(bytes.len()).is_none_or(|end| end > self.size) {
+            return Err(FlashError::OutOfRange);
+        }
+        self.before_command();
+        clint::advance_us(PROGRAM_US);
+        let mut ops = self.ops.get();
+        ops.programs += 1;
+        self.ops.set(ops);
+        if self.is_locked(offset..offset + bytes.len()) {
+            return Err(FlashError::Protected);
+        }
+        if let Some(e) = Self::fault(ops.programs, self.faults.borrow().program) {
+            return Err(e);
+        }
+        let n = self.powered(bytes.len());
+        let faults = self.faults.borrow();
+        let mut mem = self.mem.borrow_mut();
+        for (i, b) in bytes[..n].iter().enumerate() {
+            if !faults.stuck.contains(&(offset + i)) {
+                mem[offset + i] &= b;
+            }
+        }
+        if mem[offset..offset + bytes.len()] != *bytes {
+            return Err(FlashError::ProgramError);
+        }
+        Ok(())
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.mem.into_inner()
+    }
+
+    pub fn read_slice(&self, offset: FlashOffset, buf: &mut [u8]) {
+        let offset = offset.get();
+        clint::advance_us((buf.len() as u64 * READ_US_PER_KIB).div_ceil(1024));
+        buf.copy_from_slice(&self.mem.borrow()[offset..offset + buf.len()]);
+        self.before_read();
+        if let BgErase::Suspended(block, _) = self.erase.get() {
+            let erasing = block * self.block_size..(block + 1) * self.block_size;
+            for (i, b) in buf.iter_mut().enumerate() {
+                if erasing.contains(&(offset + i)) {
+                    *b = 0;
+                }
+            }
+        }
+    }
+
+    pub fn read_u32_le(&self, offset: FlashOffset) -> u32 {
+        let mut b = [0u8; 4];
+        self.read_slice(offset, &mut b);
+        u32::from_le_bytes(b)
+    }
+
+    pub fn addr_of(&self, offset: FlashOffset) -> PhysAddr {
+        offset.to_phys(self.base)
+    }
+
+    /// What QEMU's pflash answers: an Intel part, no extended query.
+    pub fn identify(&self) -> Result<FlashId, FlashError> {
+        self.before_command();
+        Ok(FlashId {
+            manufacturer: 0x89,
+            device: 0x18,
+            features: None,
+            stride: 1,
+        })
+    }
+
+    /// The lock bits of lock(), one per block (LSB first), and how many
+    /// are set.
+    pub fn lock_map(&self, _id: &FlashId, map: &mut [u8]) -> Result<usize, FlashError> {
+        let locked = self.locked.borrow();
+        if map.len() * 8 < locked.len() {
+            return Err(FlashError::OutOfRange);
+        }
+        self.before_command();
+        map.fill(0);
+        for (block, _) in locked.iter().enumerate().filter(|&(_, &l)| l) {
+            map[block / 8] |= 1 << (block % 8);
+        }
+        Ok(locked.iter().filter(|&&l| l).count())
+    }
+
+    pub fn program_u32_le(&self, offset: FlashOffset, value: u32) -> Result<(), FlashError> {
+        self.program(offset, &value.to_le_bytes())
+    }
+
+    #[cfg_attr(not(any(test, feature = "meta-x16")), allow(dead_code))]
+    pub fn program_u16_le(&self, offset: FlashOffset, value: u16) -> Result<(), FlashError> {
+        self.program(offset, &value.to_le_bytes())
+    }
+
+    pub fn block_erase(&self, block_index: usize) -> Result<(), FlashError> {
+        if block_index >= self.size / self.block_size {
+            return Err(FlashError::OutOfRange);
+        }
+        self.before_command();
+        match self.erase_fault(block_index) {
+            Some(e) => Err(e),
+            None => self.erase_block(block_index),
+        }
+    }
+
+    // Count an erase of `block_index`, and the error it is to fail with.
+    fn erase_fault(&self, block_index: usize) -> Option<FlashError> {
+        let mut ops = self.ops.get();
+        ops.erases += 1;
+        self.ops.set(ops);
+        let start = block_index * self.block_size;
+        if self.is_locked(start..start + self.block_size) {
+            return Some(FlashError::Protected);
+        }
+        Self::fault(ops.erases, self.faults.borrow().erase)
+    }
+
+    fn erase_block(&self, block_index: usize) -> Result<(), FlashError> {
+        clint::advance_us(ERASE_US);
+        let offset = block_index * self.block_size;
+        let n = self.powered(self.block_size);
+        let faults = self.faults.borrow();
+        let mut mem = self.mem.borrow_mut();
+        for i in offset..offset + n {
+            if !faults.stuck.contains(&i) {
+                mem[i] = 0xff;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase every block overlapping [flash_offset, flash_offset + len).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn erase_range(&self, flash_offset: FlashOffset, len: usize) -> Result<(), FlashError> {
+        if flash_offset.get().checked_add(len).is_none_or(|end| end > self.size) {
+            return Err(FlashError::OutOfRange);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let first = flash_offset.get() / self.block_size;
+        let last = (flash_offset.get() + len - 1) / self.block_size;
+        for block in first..=last {
+            watchdog::yield_point("erase", block - first, last + 1 - first)?;
+            self.block_erase(block)?;
+        }
+        Ok(())
+    }
+
+    /// Erase the whole device but its first `keep` bytes (rounded up to
+    /// whole blocks), calling `progress(done, total)` after each block.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn chip_erase(&self, keep: usize, mut progress: impl FnMut(usize, usize)) -> Result<(), FlashError> {
+        let blocks = self.size / self.block_size;
+        let first = keep.div_ceil(self.block_size).min(blocks);
+        let total = blocks - first;
+        for (done, block) in (first..blocks).enumerate() {
+            watchdog::yield_point("chip erase", done, total)?;
+            self.block_erase(block)?;
+            progress(done + 1, total);
+        }
+        Ok(())
+    }
+
+    pub fn erase_start(&self, block_index: usize) -> Result<(), FlashError> {
+        if block_index >= self.size / self.block_size {
+            return Err(FlashError::OutOfRange);
+        }
+        self.erase_finish()?;
+        self.erase.set(BgErase::Running(block_index, self.erase_fault(block_index)));
+        Ok(())
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn erase_resume(&self) {
+        if let BgErase::Suspended(block, fault) = self.erase.get() {
+            self.erase.set(BgErase::Running(block, fault));
+        }
+    }
+
+    pub fn erase_finish(&self) -> Result<(), FlashError> {
+        let result = match self.erase.get() {
+            BgErase::Idle => Ok(()),
+            BgErase::Done(result) => result,
+            BgErase::Running(_, Some(e)) | BgErase::Suspended(_, Some(e)) => Err(e),
+            BgErase::Running(block, None) | BgErase::Suspended(block, None) => self.erase_block(block),
+        };
+        self.erase.set(BgErase::Idle);
+        result
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn erase_state(&self) -> BgErase {
+        self.erase.get()
+    }
+
+    /// A reset of the chip: an erase_start() erase under way stops
+    /// without having changed anything (cut off at its start).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn reset(&self) {
+        self.erase.set(BgErase::Idle);
+    }
+
+    // Suspend a running erase_start() erase, as a read of the part does.
+    fn before_read(&self) {
+        if let BgErase::Running(block, fault) = self.erase.get() {
+            self.erase.set(BgErase::Suspended(block, fault));
+        }
+    }
+
+    // Before a command: any erase_start() erase run to its end (outcome
+    // kept for erase_finish()).
+    fn before_command(&self) {
+        if matches!(self.erase.get(), BgErase::Running(..) | BgErase::Suspended(..)) {
+            let result = self.erase_finish();
+            self.erase.set(BgErase::Done(result));
+        }
+    }
+}
+
+impl ImageSource for IntelFlash {
+    fn read_slice(&self, offset: usize, buf: &mut [u8]) {
+        IntelFlash::read_slice(self, FlashOffset::new(offset), buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watchdog::tests::{longest_gap, watched, CADENCE_US};
+
+    const BLOCK: usize = 256;
+
+    fn flash() -> IntelFlash {
+        let flash = IntelFlash::new(PhysAddr::new(0x2000_0000), 2 * BLOCK, BLOCK, Vec::new());
+        flash.program_u32_le(FlashOffset::new(0), 0x1234_5678).unwrap();
+        flash.program_u32_le(FlashOffset::new(BLOCK), 0x9abc_def0).unwrap();
+        flash
+    }
+
+    fn word(flash: &IntelFlash, offset: usize) -> u32 {
+        let mut b = [0u8; 4];
+        flash.read_slice(FlashOffset::new(offset), &mut b);
+        u32::from_le_bytes(b)
+    }
+
+    // A read suspends the erase: other blocks read as data, its own as
+    // garbage, until it is resumed and waited for.
+    #[test]
+    fn erase_suspends_on_read() {
+        let flash = flash();
+        flash.erase_start(1).unwrap();
+        assert_eq!(flash.erase_state(), BgErase::Running(1, None));
+        assert_eq!(word(&flash, 0), 0x1234_5678);
+        assert_eq!(flash.erase_state(), BgErase::Suspended(1, None));
+        assert_eq!(word(&flash, BLOCK), 0);
+        flash.erase_resume();
+        assert_eq!(flash.erase_state(), BgErase::Running(1, None));
+        assert_eq!(flash.erase_finish(), Ok(()));
+        assert_eq!(flash.erase_state(), BgErase::Idle);
+        assert_eq!(word(&flash, BLOCK), u32::MAX);
+        assert_eq!(word(&flash, 0), 0x1234_5678);
+    }
+
+    // Another command runs a suspended erase to its end first, and keeps
+    // its outcome for erase_finish().
+    #[test]
+    fn command_ends_erase() {
+        let flash = flash();
+        flash.inject(Faults {
+            erase: Some((1, 1, FlashError::EraseError)),
+            ..Faults::default()
+        });
+        flash.erase_start(1).unwrap();
+        let _ = word(&flash, 0);
+        flash.program_u32_le(FlashOffset::new(4), 0).unwrap();
+        assert_eq!(flash.erase_state(), BgErase::Done(Err(FlashError::EraseError)));
+        assert_eq!(flash.erase_finish(), Err(FlashError::EraseError));
+        assert_eq!(word(&flash, BLOCK), 0x9abc_def0);
+
+        flash.erase_start(1).unwrap();
+        flash.program_u32_le(FlashOffset::new(8), 0).unwrap();
+        assert_eq!(flash.erase_state(), BgErase::Done(Ok(())));
+        assert_eq!(word(&flash, BLOCK), u32::MAX);
+        assert_eq!(flash.erase_finish(), Ok(()));
+        assert_eq!(flash.ops().erases, 2);
+    }
+
+    // A locked block: programs and erases of it fail with Protected and
+    // leave it as it was; the block next to it is not locked.
+    #[test]
+    fn locked_block() {
+        let flash = flash();
+        flash.lock(1);
+        assert_eq!(flash.program_u32_le(FlashOffset::new(BLOCK + 4), 0), Err(FlashError::Protected));
+        assert_eq!(flash.program_u32_le(FlashOffset::new(BLOCK - 2), 0), Err(FlashError::Protected));
+        assert_eq!(flash.block_erase(1), Err(FlashError::Protected));
+        flash.erase_start(1).unwrap();
+        assert_eq!(flash.erase_finish(), Err(FlashError::Protected));
+        assert_eq!(word(&flash, BLOCK), 0x9abc_def0);
+        assert_eq!(word(&flash, BLOCK + 4), u32::MAX);
+        assert_eq!(word(&flash, BLOCK - 4), u32::MAX);
+        flash.program_u32_le(FlashOffset::new(4), 0).unwrap();
+        flash.block_erase(0).unwrap();
+        assert_eq!(flash.ops().programs, 5);
+        assert_eq!(flash.ops().erases, 3);
+    }
+
+    const UNIT_BLOCKS: usize = 6;
+    const DATA: u8 = 0x5a;
+
+    // A unit full of data, for the life of the test: the yield hook
+    // reads it.
+    fn full_unit() -> &'static IntelFlash {
+        let image = vec![DATA; UNIT_BLOCKS * BLOCK];
+        Box::leak(Box::new(IntelFlash::new(PhysAddr::new(0x2000_0000), image.len(), BLOCK, image)))
+    }
+
+    // Blocks erased, by index; the others must still hold their data,
+    // and none be half erased.
+    fn erased_blocks(flash: &IntelFlash) -> Vec<usize> {
+        let mut block = vec![0u8; BLOCK];
+        (0..UNIT_BLOCKS)
+            .filter(|&b| {
+                flash.read_slice(FlashOffset::new(b * BLOCK), &mut block);
+                assert!(block.iter().all(|&x| x == block[0]) && [0xff, DATA].contains(&block[0]));
+                block[0] == 0xff
+            })
+            .collect()
+    }
+
+    // At a yield point the flash is back to reading data: no erase left
+    // running, and a block either erased or as it was.
+    fn reads_as_data(flash: &'static IntelFlash) -> impl Fn() {
+        move || {
+            assert_eq!(flash.erase_state(), BgErase::Idle);
+            erased_blocks(flash);
+        }
+    }
+
+    // A yield point before every block, a block erase apart; cancelled,
+    // the blocks erased before stay erased and the next isn't touched.
+    #[test]
+    fn erase_range_yields_between_blocks() {
+        let (first, len) = (1, 4 * BLOCK - 8);
+        let flash = full_unit();
+        let start = clint::now_us();
+        let (result, yields) = watched(None, reads_as_data(flash), || {
+            flash.erase_range(FlashOffset::new(first * BLOCK + 4), len)
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(erased_blocks(flash), (first..first + 4).collect::<Vec<_>>());
+        let done: Vec<_> = yields.iter().map(|y| (y.what, y.done, y.total)).collect();
+        assert_eq!(done, (0..4).map(|d| ("erase", d, 4)).collect::<Vec<_>>());
+        assert!(longest_gap(start, &yields, clint::now_us()) <= CADENCE_US);
+
+        for n in 0..4 {
+            let flash = full_unit();
+            let (result, yields) = watched(Some(n), reads_as_data(flash), || {
+                flash.erase_range(FlashOffset::new(first * BLOCK), len)
+            });
+            assert_eq!(result, Err(FlashError::Cancelled));
+            assert_eq!(yields.len(), n + 1);
+            assert_eq!(erased_blocks(flash), (first..first + n).collect::<Vec<_>>());
+            assert_eq!(flash.erase_state(), BgErase::Idle);
+        }
+    }
+
+    // The same for a chip erase, which keeps the blocks under `keep`.
+    #[test]
+    fn chip_erase_yields_between_blocks() {
+        let keep = BLOCK + 1;
+        let total = UNIT_BLOCKS - 2;
+        for n in [None, Some(0), Some(1), Some(total - 1)] {
+            let flash = full_unit();
+            let start = clint::now_us();
+            let mut reported = Vec::new();
+            let (result, yields) = watched(n, reads_as_data(flash), || {
+                flash.chip_erase(keep, |done, all| reported.push((done, all)))
+            });
+            let erased = n.unwrap_or(total);
+            assert_eq!(result, if n.is_some() { Err(FlashError::Cancelled) } else { Ok(()) });
+            assert_eq!(erased_blocks(flash), (2..2 + erased).collect::<Vec<_>>());
+            assert_eq!(reported, (1..=erased).map(|d| (d, total)).collect::<Vec<_>>());
+            let done: Vec<_> = yields.iter().map(|y| (y.what, y.done, y.total)).collect();
+            let expected = (0..yields.len()).map(|d| ("chip erase", d, total));
+            assert_eq!(done, expected.collect::<Vec<_>>());
+            if n.is_none() {
+                assert!(longest_gap(start, &yields, clint::now_us()) <= CADENCE_US);
+            }
+        }
+    }
+
+    #[test]
+    fn reset_cuts_erase_off() {
+        let flash = flash();
+        flash.erase_start(1).unwrap();
+        flash.reset();
+        assert_eq!(flash.erase_state(), BgErase::Idle);
+        assert_eq!(word(&flash, BLOCK), 0x9abc_def0);
+        assert_eq!(flash.erase_finish(), Ok(()));
+    }
+}
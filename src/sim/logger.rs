@@ -0,0 +1,127 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::clint::{self, Deadline};
+
+// Host stand-in for src/logger.rs: the throttle as the SPL has it, over
+// a log_line() that keeps the lines (this thread's) for tests to read,
+// and a serial console whose input tests type ahead (type_input()) and
+// whose output they read back (take_output()). A byte takes the time
+// it does on the line; waiting for one that never comes takes the whole
+// timeout. Line input, hexdumps, the console lock and the emergency
+// console are the SPL's own (line.rs, lock.rs, emergency.rs), the last
+// writing to the same console.
+
+#[path = "../logger/emergency.rs"]
+pub mod emergency;
+#[path = "../logger/line.rs"]
+mod line;
+#[path = "../logger/lock.rs"]
+pub mod lock;
+#[path = "../logger/ringbuf.rs"]
+pub mod ringbuf;
+#[path = "../logger/throttle.rs"]
+pub mod throttle;
+
+use lock::ConsoleGuard;
+pub use line::{hexdump_line, read_line, ConsoleWriter, HEXDUMP_WIDTH};
+
+// A byte on the line at 115200 8N1, rounded up.
+const BYTE_US: u64 = 87;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxError {
+    Overrun,
+    Parity,
+    Framing,
+    Break,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+pub const fn log_enabled(level: Level) -> bool {
+    level as u8 <= Level::Debug as u8
+}
+
+thread_local! {
+    static LINES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static INPUT: RefCell<VecDeque<u8>> = const { RefCell::new(VecDeque::new()) };
+    static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static DEBUG_ON: Cell<bool> = const { Cell::new(true) };
+}
+
+pub fn log_line(_file: &str, _line: u32, args: fmt::Arguments) {
+    let _guard = ConsoleGuard::lock();
+    LINES.with(|lines| lines.borrow_mut().push(args.to_string()));
+}
+
+pub fn mute_debug() {
+    DEBUG_ON.with(|on| on.set(false));
+}
+
+/// Whether debug messages are still on, and on again from now.
+#[cfg(test)]
+pub fn take_debug_on() -> bool {
+    DEBUG_ON.with(|on| on.replace(true))
+}
+
+/// The lines logged so far, and none from now on.
+#[cfg(test)]
+pub fn take_lines() -> Vec<String> {
+    LINES.take()
+}
+
+/// Queue `bytes` as typed on the console, after what is still queued.
+#[cfg(test)]
+pub fn type_input(bytes: &[u8]) {
+    INPUT.with(|input| input.borrow_mut().extend(bytes));
+}
+
+/// What was written to the console so far, and nothing from now on.
+#[cfg(test)]
+pub fn take_output() -> Vec<u8> {
+    OUTPUT.take()
+}
+
+pub fn console_puts(s: &str) {
+    OUTPUT.with(|output| output.borrow_mut().extend(s.as_bytes()));
+}
+
+pub fn console_putc(b: u8) {
+    putc_raw(b);
+}
+
+pub fn putc_raw(b: u8) {
+    OUTPUT.with(|output| output.borrow_mut().push(b));
+}
+
+mod uart {
+    pub fn putc_polled(b: u8) {
+        super::putc_raw(b);
+    }
+}
+
+/// The next byte typed, or Timeout with the clock at `deadline` if none
+/// is left. Nothing is left to wait for past the end of the input: a
+/// wait without a deadline panics.
+pub fn getc_timeout(deadline: Deadline) -> Result<u8, RxError> {
+    match INPUT.with(|input| input.borrow_mut().pop_front()) {
+        Some(b) => {
+            clint::advance_us(BYTE_US);
+            Ok(b)
+        }
+        None if deadline.ticks() == u64::MAX => panic!("console input ran out"),
+        None => {
+            clint::advance_us(deadline.ticks().saturating_sub(clint::mtime()));
+            Err(RxError::Timeout)
+        }
+    }
+}
@@ -0,0 +1,459 @@
+// Host simulator: spl_main's boot log and bank choice, boot after boot,
+// on pflash image files, with payloads that fail at random. What SPL1
+// does around them (DTB, image checks, loading, handoff) is not
+// simulated: a bank boots if it starts with an SPL1 header magic, and
+// its payload confirms the boot or not as the dice say.
+//
+//   sim [--boots N] [--seed S] [--fail BANK=PERCENT]... [-v] IMAGE...
+//
+// IMAGEs are the flash units' pflash images, in unit order (units
+// without one start erased), as prepare_flash.sh makes them. They are
+// written back with the boot log as the run left it. The run fails
+// (exit status 1) if, with some bank bootable and never failing, the
+// unit went more boots without a confirmed one than the trial budget
+// of every bank allows.
+//
+// Built for the host only, with std:
+//   cargo run --features sim --bin sim --target x86_64-unknown-linux-gnu -- pflash0.img pflash1.img
+
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, fs};
+
+#[allow(dead_code)]
+#[path = "../addr.rs"]
+mod addr;
+#[allow(dead_code)]
+mod arch;
+#[allow(dead_code)]
+mod arena;
+#[allow(dead_code)]
+#[path = "../banks.rs"]
+mod banks;
+#[allow(dead_code)]
+#[path = "../blackbox.rs"]
+mod blackbox;
+#[allow(dead_code)]
+#[cfg(feature = "board-qemu-virt")]
+#[path = "../board/qemu_virt.rs"]
+mod board;
+#[allow(dead_code)]
+#[cfg(feature = "board-sifive-u")]
+#[path = "../board/sifive_u.rs"]
+mod board;
+#[allow(dead_code)]
+#[path = "../boot_policy.rs"]
+mod boot_policy;
+#[allow(dead_code)]
+#[path = "../bootlog.rs"]
+mod bootlog;
+#[allow(dead_code)]
+#[path = "../bootmeta.rs"]
+mod bootmeta;
+#[allow(dead_code)]
+#[path = "../budget.rs"]
+mod budget;
+#[allow(dead_code)]
+#[path = "../bytes.rs"]
+mod bytes;
+#[allow(dead_code)]
+mod clint;
+#[allow(dead_code)]
+#[cfg(feature = "console")]
+#[path = "../console.rs"]
+mod console;
+#[allow(dead_code)]
+#[path = "../crc32.rs"]
+mod crc32;
+#[allow(dead_code)]
+#[path = "../descriptor.rs"]
+mod descriptor;
+#[allow(dead_code)]
+#[path = "../digest.rs"]
+mod digest;
+#[allow(dead_code)]
+#[path = "../dtb.rs"]
+mod dtb;
+#[allow(dead_code)]
+#[path = "../dtb_edit.rs"]
+mod dtb_edit;
+mod flash_intel;
+#[allow(dead_code)]
+#[path = "../gpt.rs"]
+mod gpt;
+#[allow(dead_code)]
+#[path = "../handoff.rs"]
+mod handoff;
+#[allow(dead_code)]
+#[path = "../hash.rs"]
+mod hash;
+#[allow(dead_code)]
+#[path = "../image.rs"]
+mod image;
+#[allow(dead_code)]
+#[path = "../inflate.rs"]
+mod inflate;
+#[allow(dead_code)]
+#[path = "../layout.rs"]
+mod layout;
+#[allow(dead_code)]
+#[path = "../layout_desc.rs"]
+mod layout_desc;
+#[allow(dead_code)]
+mod logger;
+#[allow(dead_code)]
+#[path = "../memtest.rs"]
+mod memtest;
+#[allow(dead_code)]
+#[path = "../metablob.rs"]
+mod metablob;
+#[allow(dead_code)]
+mod platform;
+#[allow(dead_code)]
+#[path = "../ramtrials.rs"]
+mod ramtrials;
+#[allow(dead_code)]
+#[path = "../reset_cause.rs"]
+mod reset_cause;
+#[allow(dead_code)]
+#[path = "../rtc.rs"]
+mod rtc;
+#[allow(dead_code)]
+#[path = "../storm.rs"]
+mod storm;
+#[allow(dead_code)]
+#[path = "../uimage.rs"]
+mod uimage;
+#[allow(dead_code)]
+#[path = "../vcache.rs"]
+mod vcache;
+#[allow(dead_code)]
+#[cfg(feature = "secure")]
+#[path = "../verify.rs"]
+mod verify;
+#[allow(dead_code)]
+#[path = "../verify_policy.rs"]
+mod verify_policy;
+#[allow(dead_code)]
+#[path = "../warmboot.rs"]
+mod warmboot;
+#[allow(dead_code)]
+#[path = "../watchdog.rs"]
+mod watchdog;
+#[allow(dead_code)]
+#[path = "../xmodem.rs"]
+mod xmodem;
+
+use crate::addr::{FlashOffset, PhysAddr};
+use crate::banks::{BOOT_BANKS, MAX_TRIALS};
+use crate::boot_policy::BootPolicy;
+use crate::bootmeta::{BootBank, BootMeta, MAX_BANKS};
+use crate::flash_intel::IntelFlash;
+use crate::ramtrials::Cycle;
+use crate::reset_cause::ResetCause;
+
+// The start of an SPL1 bank image (SplImageHeader::MAGIC).
+const SPL_MAGIC: &[u8; 4] = b"SPL1";
+
+// The settings of src/main.rs the shared modules read.
+const GOLDEN_OFFSET: Option<usize> = None;
+const GOLDEN_UNIT: usize = 0;
+const RAW_LOAD_ADDR: usize = board::RAM_BASE + 0x20_0000;
+const RAM_STAGE_ADDR: usize = board::RAM_BASE + 0x400_0000;
+const FLASH_ERASE_CYCLES: u32 = 100_000;
+const LOCK_MAP_BYTES: usize = 128;
+
+// A production build's boot log policy: every attempt recorded.
+const POLICY: BootPolicy = BootPolicy {
+    writes: true,
+    no_record: false,
+    trust_success: false,
+    coalesce: false,
+    meta_read_only: false,
+    reset: ResetCause::PowerOn,
+    storm: None,
+};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// The SPL's slog_* macros, on stdout: errors and warnings always, the
+// rest with -v.
+#[macro_export]
+macro_rules! slog_error {
+    ($($arg:tt)*) => { $crate::log(true, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! slog_warn {
+    ($($arg:tt)*) => { $crate::log(true, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! slog_info {
+    ($($arg:tt)*) => { $crate::log(false, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! slog_debug {
+    ($($arg:tt)*) => { $crate::log(false, format_args!($($arg)*)) };
+}
+
+fn log(always: bool, args: std::fmt::Arguments) {
+    if always || VERBOSE.load(Ordering::Relaxed) {
+        println!("  {}", args);
+    }
+}
+
+// xorshift64*: the same seed, the same run.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+}
+
+struct Args {
+    boots: u64,
+    seed: u64,
+    /// Percent of boots of each bank whose payload never confirms.
+    fail: [u64; MAX_BANKS],
+    images: Vec<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        boots: 10_000,
+        seed: 1,
+        fail: [0; MAX_BANKS],
+        images: Vec::new(),
+    };
+    let mut it = env::args().skip(1);
+    while let Some(arg) = it.next() {
+        let mut value = |name: &str| it.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--boots" => args.boots = value("--boots")?.parse().map_err(|e| format!("--boots: {}", e))?,
+            "--seed" => args.seed = value("--seed")?.parse().map_err(|e| format!("--seed: {}", e))?,
+            "--fail" => {
+                let spec = value("--fail")?;
+                let (name, percent) = spec.split_once('=').ok_or(format!("--fail {}: not BANK=PERCENT", spec))?;
+                let idx = BOOT_BANKS
+                    .iter()
+                    .position(|b| b.name.eq_ignore_ascii_case(name))
+                    .ok_or(format!("--fail: no bank {}", name))?;
+                let percent: u64 = percent.parse().map_err(|e| format!("--fail {}: {}", spec, e))?;
+                args.fail[idx] = percent.min(100);
+            }
+            "-v" => VERBOSE.store(true, Ordering::Relaxed),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => args.images.push(arg),
+        }
+    }
+    if args.images.len() > board::FLASH_UNITS {
+        return Err(format!("{} images for {} flash units", args.images.len(), board::FLASH_UNITS));
+    }
+    // Zero would make xorshift stick at zero.
+    args.seed = args.seed.max(1);
+    Ok(args)
+}
+
+fn loadable(flash: &[IntelFlash], bank: BootBank) -> bool {
+    let desc = bank.desc();
+    let mut magic = [0u8; 4];
+    flash[desc.unit].read_slice(desc.offset, &mut magic);
+    magic == *SPL_MAGIC
+}
+
+/// One boot as spl_main runs it once a bank image checks out, with its
+/// boot log code: the log scanned and made ready, the bank choice with
+/// its fallbacks, the attempt recorded, then handed off, then confirmed
+/// if the payload makes it. The bank booted and whether it confirmed.
+fn boot(meta: &BootMeta, flash: &[IntelFlash], args: &Args, rng: &mut Rng) -> Option<(BootBank, bool)> {
+    let mut trials = meta.scan();
+    POLICY.prepare(meta, &Cycle::default(), &mut trials);
+    let chosen = meta.choose_bank_from(&trials, MAX_TRIALS);
+    let bank = meta.fallback_order(chosen).find(|&b| loadable(flash, b))?;
+    let recorded = POLICY.record(meta, &trials, bank);
+    boot_policy::record_handoff(meta, recorded);
+    let confirmed = rng.below(100) >= args.fail[bank.index()];
+    if confirmed && let Err(e) = meta.record_success(bank) {
+        slog_warn!("WARNING: bank {}: failed to confirm: {:?}", bank, e);
+    }
+    Some((bank, confirmed))
+}
+
+/// What a run of boots did.
+struct Run {
+    /// Boots of each bank, by index.
+    booted: [u64; MAX_BANKS],
+    /// Most boots in a row without a confirmed one.
+    worst: u64,
+}
+
+// However the other banks fail, a unit must not go more boots without a
+// confirmed one than their trials add up to.
+const BUDGET: u64 = MAX_TRIALS as u64 * BOOT_BANKS.len() as u64;
+
+impl Run {
+    /// Whether the unit kept to BUDGET, when some bank booted and its
+    /// payload never fails (otherwise there is nothing to converge to).
+    fn converged(&self, args: &Args) -> bool {
+        let sound = (0..BOOT_BANKS.len()).any(|i| args.fail[i] == 0 && self.booted[i] > 0);
+        !sound || self.worst <= BUDGET
+    }
+}
+
+/// Run `args.boots` boots on `flash` and print what the boot log says
+/// at the end. None if the board's boot log doesn't fit its flash.
+fn simulate(flash: &[IntelFlash], args: &Args) -> Option<Run> {
+    let meta_flash = &flash[board::META_UNIT];
+    let meta_offset = FlashOffset::new(board::META_OFFSET);
+    let meta = BootMeta::new(meta_flash, meta_offset, board::META_SIZE, &BOOT_BANKS).ok()?;
+    let mut rng = Rng(args.seed);
+    let mut booted = [0u64; MAX_BANKS];
+    let mut confirmed = [0u64; MAX_BANKS];
+    let (mut streak, mut worst, mut stuck) = (0u64, 0u64, 0u64);
+    for n in 0..args.boots {
+        let outcome = boot(&meta, flash, args, &mut rng);
+        if VERBOSE.load(Ordering::Relaxed) {
+            println!("boot {}: {:?}", n, outcome.map(|(b, ok)| (b.desc().name, ok)));
+        }
+        match outcome {
+            Some((bank, ok)) => {
+                booted[bank.index()] += 1;
+                confirmed[bank.index()] += u64::from(ok);
+                streak = if ok { 0 } else { streak + 1 };
+            }
+            None => {
+                stuck += 1;
+                streak += 1;
+            }
+        }
+        worst = worst.max(streak);
+    }
+
+    println!("{} boots, seed {}, {} with nothing to boot", args.boots, args.seed, stuck);
+    let trials = meta.scan();
+    for bank in meta.banks() {
+        let t = trials.bank(bank);
+        println!(
+            "bank {}: {} boots, {} confirmed; log now {} unconfirmed, {} confirmed, {} died in SPL1",
+            bank,
+            booted[bank.index()],
+            confirmed[bank.index()],
+            t.unconfirmed,
+            t.confirmed,
+            t.no_handoff
+        );
+    }
+    println!("boot log: {} encoding, {:?} compactions", meta.encoding(), meta.compaction_count());
+    Some(Run { booted, worst })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("sim: {}", e);
+            eprintln!("usage: sim [--boots N] [--seed S] [--fail BANK=PERCENT]... [-v] IMAGE...");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut flash = Vec::new();
+    for unit in 0..board::FLASH_UNITS {
+        let image = match args.images.get(unit) {
+            Some(path) => match fs::read(path) {
+                Ok(image) if image.len() <= board::FLASH_SIZE[unit] => image,
+                Ok(image) => {
+                    let size = board::FLASH_SIZE[unit];
+                    eprintln!("sim: {}: {} bytes, flash{} has {}", path, image.len(), unit, size);
+                    return ExitCode::FAILURE;
+                }
+                Err(e) => {
+                    eprintln!("sim: {}: {}", path, e);
+                    return ExitCode::FAILURE;
+                }
+            },
+            None => Vec::new(),
+        };
+        flash.push(IntelFlash::new(
+            PhysAddr::new(board::FLASH_BASE[unit]),
+            board::FLASH_SIZE[unit],
+            board::FLASH_BLOCK_SIZE[unit],
+            image,
+        ));
+    }
+
+    let Some(run) = simulate(&flash, &args) else {
+        return ExitCode::FAILURE;
+    };
+
+    for (path, unit) in args.images.iter().zip(flash) {
+        if let Err(e) = fs::write(path, unit.into_image()) {
+            eprintln!("sim: {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    println!("longest run without a confirmed boot: {} (budget {})", run.worst, BUDGET);
+    if !run.converged(&args) {
+        println!("FAIL: did not converge to the bank that never fails");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The flash units with an SPL1 image in every bank and the boot log
+    // erased, as prepare_flash.sh leaves them.
+    fn units() -> Vec<IntelFlash> {
+        let flash: Vec<IntelFlash> = (0..board::FLASH_UNITS)
+            .map(|unit| {
+                let base = PhysAddr::new(board::FLASH_BASE[unit]);
+                IntelFlash::new(base, board::FLASH_SIZE[unit], board::FLASH_BLOCK_SIZE[unit], Vec::new())
+            })
+            .collect();
+        for bank in &BOOT_BANKS {
+            flash[bank.unit].program_u32_le(bank.offset, u32::from_le_bytes(*SPL_MAGIC)).unwrap();
+        }
+        flash
+    }
+
+    // 10,000 boots with payloads failing at random: whichever bank never
+    // fails, and however often the others do, the unit always converges
+    // to it within the trial budget.
+    #[test]
+    fn converges_to_bootable_bank() {
+        let mut dice = Rng(0x5eed);
+        for seed in 1..=8 {
+            let good = dice.below(BOOT_BANKS.len() as u64) as usize;
+            let mut fail = [0; MAX_BANKS];
+            for (i, percent) in fail.iter_mut().enumerate().take(BOOT_BANKS.len()) {
+                if i != good {
+                    *percent = 1 + dice.below(100);
+                }
+            }
+            let args = Args {
+                boots: 10_000,
+                seed,
+                fail,
+                images: Vec::new(),
+            };
+            let run = simulate(&units(), &args).unwrap();
+            assert!(run.booted[good] > 0, "seed {}: bank {} never booted", seed, good);
+            assert!(
+                run.converged(&args),
+                "seed {}, fail {:?}: {} boots in a row unconfirmed",
+                seed,
+                fail,
+                run.worst
+            );
+        }
+    }
+}
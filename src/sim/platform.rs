@@ -0,0 +1,6 @@
+// Host stand-in for src/platform.rs: a reset ends the simulated boot,
+// there is no board to start over.
+
+pub fn reset() -> ! {
+    panic!("board reset");
+}
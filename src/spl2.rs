@@ -0,0 +1,160 @@
+use crate::arch::csr::PrivMode;
+use crate::bootmeta::{Rewrite, RewriteRun, REWRITE_RUNS};
+use crate::hash::SHA256_LEN;
+use crate::{bootlog, logger};
+
+// Chainloading an SPL2 (DRAM init, richer drivers) from its own flash
+// slot, board::SPL2_OFFSET. An erased slot means no SPL2: the payload is
+// entered directly, as before. Otherwise SPL1 still picks, verifies and
+// loads the payload and records the attempt, then loads the SPL2 image
+// (an SPL1-format bank image, checked the same way) and enters it
+// instead, in M-mode, with the DTB in a1 and this block in a2, so SPL2
+// needn't scan the flash again. Little-endian, in SPL1's RAM (which
+// SPL2 must leave alone):
+//
+//   0x00  magic        b"SPL2"
+//   0x04  version      1
+//   0x08  size         0x90
+//   0x0c  slot         bank index, or bootlog::SLOT_GOLDEN
+//   0x10  attempts     unconfirmed attempts of the slot, this one included
+//   0x14  recorded     boot log entry of this attempt, all-ones if none
+//   0x18  next_idx     first free boot log entry
+//   0x1c  compactions  of the boot log, all-ones if it has no header
+//   0x20  entry        u64, the payload's entry point (already loaded)
+//   0x28  mode         payload privilege level: 3 M, 1 S, 0 U
+//   0x2c  flags        FLAG_*
+//   0x30  dtb          u64, the DTB (as in a1)
+//   0x38  log_ring     u64 address, u64 size: the console log ring
+//   0x48  event_log    u64 address, u64 size: the measured-boot log
+//   0x58  digest       [32], payload sha256
+//   0x78  meta_block   u64, address of the boot log's block
+//   0x80  rewrite      u64 address, u64 count: RewriteRun (see
+//                      bootmeta.rs) of a compaction to finish
+//
+// The parked harts are released into SPL2 only if its own header asks;
+// FLAG_RELEASE_HARTS says whether the payload's does.
+//
+// An SPL2 with FLAG_META_REWRITE in its image header can finish a boot
+// log compaction, so that SPL1 doesn't wait about a second for its
+// erase. With FLAG_META_COMPACTING set the erase of meta_block is
+// under way (suspended if the chip reads as data: 0xD0 resumes it). Once
+// the status register says it is over, SPL2 programs each run in order:
+// `count` values of `width` bytes, each `value`, from `offset` bytes into
+// the block on. A failed erase or program is tried again (as the flash
+// driver's retry policy says); if one still fails, SPL2 clears bit 31
+// of the block's first word, poisoning the log (see BootMeta). Whatever
+// is cut short, the next boot sees it and compacts again.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SPL2");
+const VERSION: u32 = 1;
+
+/// The payload wants the parked harts released into it too.
+pub const FLAG_RELEASE_HARTS: u32 = 1 << 0;
+/// The boot log is poisoned (see BootMeta): don't write to it.
+pub const FLAG_META_POISONED: u32 = 1 << 1;
+/// The boot log is read-only (see BootMeta): it can't be written.
+pub const FLAG_META_READ_ONLY: u32 = 1 << 2;
+/// A boot log compaction for SPL2 to finish: meta_block, rewrite.
+pub const FLAG_META_COMPACTING: u32 = 1 << 3;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Handoff {
+    magic: u32,
+    version: u32,
+    size: u32,
+    pub slot: u32,
+    pub attempts: u32,
+    pub recorded: u32,
+    pub next_idx: u32,
+    pub compactions: u32,
+    pub entry: u64,
+    pub mode: u32,
+    pub flags: u32,
+    pub dtb: u64,
+    log_ring: [u64; 2],
+    event_log: [u64; 2],
+    pub digest: [u8; SHA256_LEN],
+    meta_block: u64,
+    rewrite: [u64; 2],
+}
+
+const _: () = assert!(core::mem::size_of::<Handoff>() == 0x90);
+const _: () = assert!(core::mem::size_of::<RewriteRun>() == 16);
+
+impl Handoff {
+    /// A block for the payload at `entry`, with the log ring and event
+    /// log filled in and the boot log fields left to the caller.
+    pub fn new(entry: usize, mode: PrivMode, dtb: usize, digest: [u8; SHA256_LEN]) -> Self {
+        let (ring, ring_size) = logger::ringbuf::region();
+        let (events, events_size) = bootlog::region();
+        Handoff {
+            magic: MAGIC,
+            version: VERSION,
+            size: core::mem::size_of::<Handoff>() as u32,
+            slot: bootlog::SLOT_NONE,
+            attempts: 0,
+            recorded: u32::MAX,
+            next_idx: 0,
+            compactions: u32::MAX,
+            entry: entry as u64,
+            mode: mode as u32,
+            flags: 0,
+            dtb: dtb as u64,
+            log_ring: [ring as u64, ring_size as u64],
+            event_log: [events as u64, events_size as u64],
+            digest,
+            meta_block: 0,
+            rewrite: [0; 2],
+        }
+    }
+
+    /// Hand SPL2 the compaction of the boot log block at `block`, with
+    /// its write-back (see BootMeta::hand_off_compaction()).
+    pub fn compaction(&mut self, block: usize, rewrite: &Rewrite) {
+        let runs = &raw mut REWRITE;
+        let n = rewrite.runs().len();
+        let mut all = [RewriteRun::default(); REWRITE_RUNS];
+        all[..n].copy_from_slice(rewrite.runs());
+        unsafe { core::ptr::write_volatile(runs, all) };
+        self.meta_block = block as u64;
+        self.rewrite = [runs as u64, n as u64];
+        self.flags |= FLAG_META_COMPACTING;
+    }
+}
+
+// The runs of Handoff::rewrite.
+static mut REWRITE: [RewriteRun; REWRITE_RUNS] = [RewriteRun {
+    offset: 0,
+    width: 0,
+    count: 0,
+    value: 0,
+}; REWRITE_RUNS];
+
+static mut HANDOFF: Handoff = Handoff {
+    magic: 0,
+    version: 0,
+    size: 0,
+    slot: 0,
+    attempts: 0,
+    recorded: 0,
+    next_idx: 0,
+    compactions: 0,
+    entry: 0,
+    mode: 0,
+    flags: 0,
+    dtb: 0,
+    log_ring: [0; 2],
+    event_log: [0; 2],
+    digest: [0; SHA256_LEN],
+    meta_block: 0,
+    rewrite: [0; 2],
+};
+
+/// Store `handoff` where SPL2 will find it, and return that address
+/// (for a2).
+pub fn publish(handoff: &Handoff) -> usize {
+    let p = &raw mut HANDOFF;
+    unsafe { core::ptr::write_volatile(p, *handoff) };
+    p as usize
+}
@@ -0,0 +1,85 @@
+use crate::crc32::crc32;
+
+// Reboot storm detection. A payload that resets the board right away
+// would otherwise cost a boot log entry per boot, and an erase of the
+// metadata block every few hundred: weeks to wear it out. So the SPL
+// counts warm boots without a confirmed one in between, in a RAM record
+// that _start doesn't clear, next to the reset signature (see
+// reset_cause.rs) and surviving the same resets:
+//
+//   0x00  magic  b"SSTM"
+//   0x04  count  warm boots in a row without a confirmed boot
+//   0x08  crc    CRC-32 of the 8 bytes above
+//
+// No valid record means RAM didn't survive: a cold boot, count 0. Past
+// a threshold the boot is in degraded mode (see spl_main): only every
+// Kth attempt is recorded, and the SPL waits longer and longer before
+// each one.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SSTM");
+
+/// Degraded mode, for a reboot storm.
+#[derive(Debug, Clone, Copy)]
+pub struct Storm {
+    /// Warm boots in a row without a confirmed one, this one included.
+    pub boots: u32,
+    pub record_every: u32,
+}
+
+#[repr(C, align(8))]
+struct Counter {
+    magic: u32,
+    count: u32,
+    crc: u32,
+}
+
+fn crc_of(magic: u32, count: u32) -> u32 {
+    let mut b = [0u8; 8];
+    b[..4].copy_from_slice(&magic.to_le_bytes());
+    b[4..].copy_from_slice(&count.to_le_bytes());
+    crc32(&b)
+}
+
+#[unsafe(link_section = ".spl_noinit")]
+static mut COUNTER: Counter = Counter {
+    magic: 0,
+    count: 0,
+    crc: 0,
+};
+
+fn counter() -> *mut Counter {
+    &raw mut COUNTER
+}
+
+/// Count this boot: one more warm boot in a row, unless RAM didn't
+/// survive the reset or the previous boot was `confirmed`, which start
+/// over at 0. Returns the count, this boot included.
+pub fn count_boot(confirmed: bool) -> u32 {
+    let c = counter();
+    let (magic, count, crc) = unsafe {
+        (
+            core::ptr::read_volatile(&raw const (*c).magic),
+            core::ptr::read_volatile(&raw const (*c).count),
+            core::ptr::read_volatile(&raw const (*c).crc),
+        )
+    };
+    let warm = magic == MAGIC && crc == crc_of(magic, count);
+    let count = if warm && !confirmed { count.saturating_add(1) } else { 0 };
+    unsafe {
+        core::ptr::write_volatile(&raw mut (*c).count, count);
+        core::ptr::write_volatile(&raw mut (*c).crc, crc_of(MAGIC, count));
+        core::ptr::write_volatile(&raw mut (*c).magic, MAGIC);
+    }
+    count
+}
+
+/// Degraded-mode delay for the `excess`th boot past the threshold:
+/// `base_us`, doubled each time, up to `max_us`.
+pub fn backoff_us(excess: u32, base_us: u64, max_us: u64) -> u64 {
+    base_us.saturating_mul(2u64.saturating_pow(excess)).min(max_us)
+}
+
+/// Address and size of the counter.
+pub fn region() -> (usize, usize) {
+    (counter() as usize, core::mem::size_of::<Counter>())
+}
@@ -0,0 +1,268 @@
+use core::arch::{asm, global_asm};
+
+use crate::arch;
+use crate::arch::csr::{self, Mtvec, PrivMode, TrapMode};
+use crate::logger::emergency as em;
+use crate::postcode::{self, Postcode};
+use crate::reset_cause::{self, ResetCause};
+use crate::watchdog::{Watchdog, WATCHDOG};
+
+// Emergency stack for the trap handler, so a corrupted sp in the
+// faulting context doesn't prevent the dump.
+const TRAP_STACK_SIZE: usize = 4096;
+
+#[repr(C, align(16))]
+struct TrapStack([u8; TRAP_STACK_SIZE]);
+
+#[unsafe(no_mangle)]
+static mut TRAP_STACK: TrapStack = TrapStack([0; TRAP_STACK_SIZE]);
+
+/// General purpose registers as saved by trap_entry (x0 slot unused).
+#[repr(C)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+}
+
+impl TrapFrame {
+    pub fn ra(&self) -> usize {
+        self.regs[1]
+    }
+
+    pub fn sp(&self) -> usize {
+        self.regs[2]
+    }
+
+    /// Argument register a<n> (x10 + n).
+    pub fn a(&self, n: usize) -> usize {
+        self.regs[10 + n]
+    }
+
+    /// Set a<n> for when the trap returns.
+    pub fn set_a(&mut self, n: usize, v: usize) {
+        self.regs[10 + n] = v;
+    }
+}
+
+// Direct-mode trap vector: switch to the emergency stack (original sp is
+// parked in mscratch), save all GPRs in a TrapFrame and call into Rust.
+global_asm!(
+    r#"
+    .section .text
+    .globl trap_entry
+    .align 2
+trap_entry:
+    csrw mscratch, sp
+    la sp, TRAP_STACK + {stack_size}
+    addi sp, sp, -{frame_size}
+
+    sd x1,   1*8(sp)
+    sd x3,   3*8(sp)
+    sd x4,   4*8(sp)
+    sd x5,   5*8(sp)
+    sd x6,   6*8(sp)
+    sd x7,   7*8(sp)
+    sd x8,   8*8(sp)
+    sd x9,   9*8(sp)
+    sd x10, 10*8(sp)
+    sd x11, 11*8(sp)
+    sd x12, 12*8(sp)
+    sd x13, 13*8(sp)
+    sd x14, 14*8(sp)
+    sd x15, 15*8(sp)
+    sd x16, 16*8(sp)
+    sd x17, 17*8(sp)
+    sd x18, 18*8(sp)
+    sd x19, 19*8(sp)
+    sd x20, 20*8(sp)
+    sd x21, 21*8(sp)
+    sd x22, 22*8(sp)
+    sd x23, 23*8(sp)
+    sd x24, 24*8(sp)
+    sd x25, 25*8(sp)
+    sd x26, 26*8(sp)
+    sd x27, 27*8(sp)
+    sd x28, 28*8(sp)
+    sd x29, 29*8(sp)
+    sd x30, 30*8(sp)
+    sd x31, 31*8(sp)
+    csrr t0, mscratch
+    sd t0,   2*8(sp)
+
+    mv a0, sp
+    call trap_handler
+
+    // trap_handler only returns from a trap it handled (a faulted
+    // probe, see arch/probe.rs; an SBI call): resume with the registers
+    // and mepc it left.
+    ld x1,   1*8(sp)
+    ld x3,   3*8(sp)
+    ld x4,   4*8(sp)
+    ld x5,   5*8(sp)
+    ld x6,   6*8(sp)
+    ld x7,   7*8(sp)
+    ld x8,   8*8(sp)
+    ld x9,   9*8(sp)
+    ld x10, 10*8(sp)
+    ld x11, 11*8(sp)
+    ld x12, 12*8(sp)
+    ld x13, 13*8(sp)
+    ld x14, 14*8(sp)
+    ld x15, 15*8(sp)
+    ld x16, 16*8(sp)
+    ld x17, 17*8(sp)
+    ld x18, 18*8(sp)
+    ld x19, 19*8(sp)
+    ld x20, 20*8(sp)
+    ld x21, 21*8(sp)
+    ld x22, 22*8(sp)
+    ld x23, 23*8(sp)
+    ld x24, 24*8(sp)
+    ld x25, 25*8(sp)
+    ld x26, 26*8(sp)
+    ld x27, 27*8(sp)
+    ld x28, 28*8(sp)
+    ld x29, 29*8(sp)
+    ld x30, 30*8(sp)
+    ld x31, 31*8(sp)
+    ld x2,   2*8(sp)
+    mret
+"#,
+    stack_size = const TRAP_STACK_SIZE,
+    frame_size = const core::mem::size_of::<TrapFrame>(),
+);
+
+unsafe extern "C" {
+    fn trap_entry();
+}
+
+// Load/store address misaligned, access fault; load/store page fault.
+const MCAUSE_LOAD_STORE: [usize; 6] = [4, 5, 6, 7, 13, 15];
+#[cfg(feature = "sbi-shim")]
+const MCAUSE_ECALL_S: usize = 9;
+const MCAUSE_MEI: usize = (1 << (usize::BITS - 1)) | 11;
+
+/// Point mtvec at trap_entry (direct mode).
+pub fn init() {
+    let vec = trap_entry as *const () as usize;
+    csr::write_mtvec(Mtvec::new(vec, TrapMode::Direct));
+}
+
+fn mcause_name(mcause: usize) -> &'static str {
+    const INTERRUPT: usize = 1 << (usize::BITS - 1);
+    if mcause & INTERRUPT != 0 {
+        return match mcause & !INTERRUPT {
+            3 => "machine software interrupt",
+            7 => "machine timer interrupt",
+            11 => "machine external interrupt",
+            _ => "interrupt",
+        };
+    }
+    match mcause {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store/AMO address misaligned",
+        7 => "store/AMO access fault",
+        8 => "ecall from U-mode",
+        9 => "ecall from S-mode",
+        11 => "ecall from M-mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store/AMO page fault",
+        _ => "unknown",
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let mcause = csr::read_mcause();
+    let mepc = csr::read_mepc();
+    let mtval = csr::read_mtval();
+    let mstatus = csr::read_mstatus();
+
+    // A probe faulted: it gets the fault back instead of a dump.
+    if MCAUSE_LOAD_STORE.contains(&mcause) && arch::probe::recover(frame, mepc, mcause, mtval) {
+        return;
+    }
+
+    // A peripheral interrupt: to its handler (see plic.rs).
+    if mcause == MCAUSE_MEI {
+        crate::plic::dispatch();
+        return;
+    }
+
+    #[cfg(feature = "sbi-shim")]
+    if mcause == MCAUSE_ECALL_S {
+        crate::sbi::handle_ecall(frame);
+        csr::write_mepc(mepc + 4);
+        return;
+    }
+
+    // From here on the emergency console only: the trap may have
+    // interrupted the logger, lock held.
+    em::begin();
+    if WATCHDOG.expired(mcause) {
+        em::puts("\n*** boot watchdog expired (pc=");
+        em::put_hex(mepc as u64);
+        em::puts("), resetting ***\n");
+        reset_cause::reset(ResetCause::Watchdog);
+    }
+    let reached = postcode::get();
+    postcode::set(Postcode::Trap);
+    em::puts("\n*** TRAP in SPL1 ***\n");
+    if !arch::stack_guard_intact() {
+        em::puts("stack overflow: guard word at _stack_bottom overwritten\n");
+    }
+    dump_reg("mcause = ", mcause);
+    em::puts(" (");
+    em::puts(mcause_name(mcause));
+    em::puts(")\n");
+    dump_reg("mepc   = ", mepc);
+    em::putc(b'\n');
+    dump_reg("mtval  = ", mtval);
+    em::putc(b'\n');
+    dump_reg("mstatus= ", mstatus.0);
+    em::puts(match mstatus.mpp() {
+        Some(PrivMode::Machine) => " (MPP=M",
+        Some(PrivMode::Supervisor) => " (MPP=S",
+        Some(PrivMode::User) => " (MPP=U",
+        None => " (MPP=?",
+    });
+    em::puts(if mstatus.mpie() { " MPIE=1)\n" } else { " MPIE=0)\n" });
+    dump_reg("postcode=", reached as usize);
+    em::puts(" (before the trap)\n");
+    dump_reg("ra     = ", frame.ra());
+    em::putc(b'\n');
+    dump_reg("sp     = ", frame.sp());
+    em::putc(b'\n');
+    for n in 0..8 {
+        em::putc(b'a');
+        em::put_dec(n as u64);
+        dump_reg("     = ", frame.a(n));
+        em::putc(b'\n');
+    }
+    crate::blackbox::record_trap(mcause, mepc, mtval, frame.sp());
+    em::puts("parking hart\n");
+
+    loop {
+        unsafe { asm!("wfi") }
+    }
+}
+
+// `name` and `value` in hex, no newline.
+fn dump_reg(name: &str, value: usize) {
+    em::puts(name);
+    em::put_hex(value as u64);
+}
+
+/// Deliberately execute an illegal instruction to exercise the dump.
+/// The trap handler parks the hart, so this never actually returns.
+#[cfg(feature = "fault-test")]
+pub fn trigger_test_fault() {
+    unsafe {
+        asm!("unimp");
+    }
+}
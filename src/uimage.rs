@@ -0,0 +1,295 @@
+use core::result::Result;
+
+use crate::addr::PhysAddr;
+use crate::arch::csr::PrivMode;
+use crate::bytes::{Be32, Bytes, U8};
+use crate::crc32::crc32;
+use crate::image::{check_load_region, Expected, Forbidden, ImageError, ImageSource, LoadableImage};
+use crate::verify_policy::{Check, VerifyPolicy};
+
+// U-Boot legacy image (mkimage -A riscv -T firmware|kernel ...), so the
+// payloads the U-Boot build already wraps can go in a bank as they are.
+//
+// 64-byte header, all fields big-endian:
+//
+//   0x00  u32  ih_magic   0x27051956
+//   0x04  u32  ih_hcrc    CRC-32 of the header with ih_hcrc zeroed
+//   0x08  u32  ih_time
+//   0x0c  u32  ih_size    data bytes following the header
+//   0x10  u32  ih_load
+//   0x14  u32  ih_ep
+//   0x18  u32  ih_dcrc    CRC-32 of the (stored) data
+//   0x1c  u8   ih_os, ih_arch, ih_type, ih_comp
+//   0x20  [32] ih_name
+
+pub const UIMAGE_MAGIC: u32 = 0x2705_1956;
+pub const HEADER_SIZE: usize = 64;
+
+const IH_MAGIC: Be32 = Be32(0x00);
+const IH_HCRC: Be32 = Be32(0x04);
+const IH_SIZE: Be32 = Be32(0x0c);
+const IH_LOAD: Be32 = Be32(0x10);
+const IH_EP: Be32 = Be32(0x14);
+const IH_DCRC: Be32 = Be32(0x18);
+const IH_OS: U8 = U8(0x1c);
+const IH_ARCH: U8 = U8(0x1d);
+const IH_TYPE: U8 = U8(0x1e);
+const IH_COMP: U8 = U8(0x1f);
+const IH_NAME: Bytes<NAME_LEN> = Bytes(0x20);
+
+const IH_ARCH_RISCV: u8 = 26;
+const IH_TYPE_KERNEL: u8 = 2;
+const IH_TYPE_FIRMWARE: u8 = 5;
+const IH_COMP_NONE: u8 = 0;
+const IH_COMP_GZIP: u8 = 1;
+
+const NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UImageError {
+    HeaderCrc { expected: u32, actual: u32 },
+    DataCrc { expected: u32, actual: u32 },
+    BadSize(u32),
+    /// Entry point outside the loaded data.
+    BadEntry(u32),
+    UnsupportedArch(u8),
+    UnsupportedType(u8),
+    UnsupportedCompression(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UImageHeader {
+    pub size: u32,
+    pub load: u32,
+    pub ep: u32,
+    pub dcrc: u32,
+    pub os: u8,
+    pub arch: u8,
+    pub image_type: u8,
+    pub comp: u8,
+    name: [u8; NAME_LEN],
+}
+
+impl From<UImageError> for ImageError {
+    fn from(e: UImageError) -> Self {
+        ImageError::UImage(e)
+    }
+}
+
+impl UImageHeader {
+    /// Whether the bank at `bank_offset` starts with a uImage magic.
+    pub fn probe(flash: &dyn ImageSource, bank_offset: usize) -> bool {
+        let mut magic = [0u8; 4];
+        flash.read_slice(bank_offset, &mut magic);
+        IH_MAGIC.get(&magic) == Ok(UIMAGE_MAGIC)
+    }
+
+    /// Read and validate the header and data CRCs of the uImage at
+    /// `bank_offset`. Nothing is copied to RAM yet. What a bad data CRC
+    /// does is up to `policy`; a bad header CRC is always refused.
+    pub fn parse(
+        flash: &dyn ImageSource,
+        bank_offset: usize,
+        bank_size: usize,
+        forbidden: &[Forbidden],
+        policy: &VerifyPolicy,
+    ) -> Result<Self, ImageError> {
+        let mut raw = [0u8; HEADER_SIZE];
+        flash.read_slice(bank_offset, &mut raw);
+
+        let magic = IH_MAGIC.get(&raw)?;
+        if magic != UIMAGE_MAGIC {
+            return Err(ImageError::BadMagic(magic));
+        }
+        let hcrc = IH_HCRC.get(&raw)?;
+        let mut zeroed = raw;
+        zeroed[IH_HCRC.range()].fill(0);
+        let actual = crc32(&zeroed);
+        if actual != hcrc {
+            return Err(UImageError::HeaderCrc {
+                expected: hcrc,
+                actual,
+            }
+            .into());
+        }
+
+        let hdr = UImageHeader {
+            size: IH_SIZE.get(&raw)?,
+            load: IH_LOAD.get(&raw)?,
+            ep: IH_EP.get(&raw)?,
+            dcrc: IH_DCRC.get(&raw)?,
+            os: IH_OS.get(&raw)?,
+            arch: IH_ARCH.get(&raw)?,
+            image_type: IH_TYPE.get(&raw)?,
+            comp: IH_COMP.get(&raw)?,
+            name: IH_NAME.get(&raw)?,
+        };
+
+        if hdr.arch != IH_ARCH_RISCV {
+            return Err(UImageError::UnsupportedArch(hdr.arch).into());
+        }
+        if hdr.image_type != IH_TYPE_KERNEL && hdr.image_type != IH_TYPE_FIRMWARE {
+            return Err(UImageError::UnsupportedType(hdr.image_type).into());
+        }
+        if hdr.comp != IH_COMP_NONE && hdr.comp != IH_COMP_GZIP {
+            return Err(UImageError::UnsupportedCompression(hdr.comp).into());
+        }
+        let size = hdr.size as usize;
+        if size == 0 || size > bank_size - HEADER_SIZE {
+            return Err(UImageError::BadSize(hdr.size).into());
+        }
+
+        // ih_dcrc covers the data as stored, so check it straight from
+        // flash before the load address is touched.
+        if policy.runs(Check::Crc)
+            && let Err(actual) = Expected::crc32(hdr.dcrc).check(flash, bank_offset + HEADER_SIZE, size)
+        {
+            policy.decide(
+                Check::Crc,
+                Err(UImageError::DataCrc {
+                    expected: hdr.dcrc,
+                    actual: actual.to_u32(),
+                }
+                .into()),
+            )?;
+        }
+
+        let load_size = hdr.load_size(flash, bank_offset);
+        if hdr.ep < hdr.load || (hdr.ep - hdr.load) as usize >= load_size {
+            return Err(UImageError::BadEntry(hdr.ep).into());
+        }
+        check_load_region(PhysAddr::new(hdr.load as usize), load_size, forbidden)?;
+
+        Ok(hdr)
+    }
+
+    /// Image name, up to its NUL.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.comp == IH_COMP_GZIP
+    }
+
+    // Bytes the data occupies once loaded. uImage doesn't record the
+    // inflated size, but the gzip trailer ends with it (ISIZE).
+    fn load_size(&self, flash: &dyn ImageSource, bank_offset: usize) -> usize {
+        if self.is_compressed() && self.size >= 4 {
+            let end = bank_offset + HEADER_SIZE + self.size as usize;
+            flash.read_u32_le(end - 4) as usize
+        } else {
+            self.size as usize
+        }
+    }
+
+    /// What `LoadableImage::load()` has to do for the uImage at
+    /// `bank_offset`.
+    pub fn loadable(&self, flash: &dyn ImageSource, bank_offset: usize) -> LoadableImage {
+        LoadableImage {
+            payload_offset: bank_offset + HEADER_SIZE,
+            payload_size: self.size as usize,
+            load_addr: PhysAddr::new(self.load as usize),
+            load_size: self.load_size(flash, bank_offset),
+            entry: self.ep as usize,
+            // uImage has no field for it: M-mode firmware, like OpenSBI.
+            mode: PrivMode::Machine,
+            release_harts: false,
+            meta_rewrite: false,
+            compressed: self.is_compressed(),
+            // ih_dcrc was checked in flash; gzip checks its own trailer.
+            loaded_crc: None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::image::MemSource;
+
+    pub const LOAD: u32 = 0x8020_0000;
+
+    /// What `mkimage -A riscv -O linux -T kernel -C none -a 0x80200000
+    /// -e 0x80200000 -n test` writes, with ih_time left at zero.
+    pub fn mkimage(data: &[u8]) -> Vec<u8> {
+        let mut img = vec![0u8; HEADER_SIZE];
+        for (field, v) in [
+            (IH_MAGIC, UIMAGE_MAGIC),
+            (IH_SIZE, data.len() as u32),
+            (IH_LOAD, LOAD),
+            (IH_EP, LOAD),
+            (IH_DCRC, crc32(data)),
+        ] {
+            img[field.range()].copy_from_slice(&v.to_be_bytes());
+        }
+        img[0x1c..0x20].copy_from_slice(&[5, IH_ARCH_RISCV, IH_TYPE_KERNEL, IH_COMP_NONE]);
+        img[0x20..0x24].copy_from_slice(b"test");
+        let hcrc = crc32(&img);
+        img[IH_HCRC.range()].copy_from_slice(&hcrc.to_be_bytes());
+        img.extend(data);
+        img
+    }
+
+    fn parse(img: &[u8], policy: &VerifyPolicy) -> Result<UImageHeader, ImageError> {
+        let flash = MemSource { base: img.as_ptr() as usize, size: img.len() };
+        let kernel = [Forbidden::new("spl", PhysAddr::new(0x8000_0000), 0x20_0000)];
+        UImageHeader::parse(&flash, 0, img.len(), &kernel, policy)
+    }
+
+    #[test]
+    fn valid() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let img = mkimage(&data);
+        let flash = MemSource { base: img.as_ptr() as usize, size: img.len() };
+        assert!(UImageHeader::probe(&flash, 0));
+        let hdr = parse(&img, &VerifyPolicy::STRICT).unwrap();
+        assert_eq!((hdr.size, hdr.load, hdr.ep, hdr.dcrc), (1000, LOAD, LOAD, crc32(&data)));
+        assert_eq!(hdr.name(), "test");
+        let l = hdr.loadable(&flash, 0);
+        assert_eq!((l.payload_offset, l.payload_size, l.load_size), (HEADER_SIZE, 1000, 1000));
+        assert!(!l.compressed);
+    }
+
+    // Any header byte changed fails its CRC, whatever the policy.
+    #[test]
+    fn bad_header_crc() {
+        let img = mkimage(b"payload");
+        let warn = VerifyPolicy::STRICT.best_effort();
+        for at in 4..HEADER_SIZE {
+            let mut bad = img.clone();
+            bad[at] ^= 0x10;
+            let e = parse(&bad, &warn);
+            let crc = matches!(e, Err(ImageError::UImage(UImageError::HeaderCrc { .. })));
+            assert!(crc, "byte {}: {:?}", at, e);
+        }
+        let mut bad = img.clone();
+        bad[0] ^= 1;
+        assert_eq!(parse(&bad, &warn), Err(ImageError::BadMagic(UIMAGE_MAGIC ^ 1 << 24)));
+    }
+
+    // A data CRC mismatch is refused under Enforce, booted under Warn.
+    #[test]
+    fn bad_data_crc() {
+        let mut img = mkimage(b"payload");
+        let last = img.len() - 1;
+        img[last] ^= 1;
+        let want = UImageError::DataCrc { expected: crc32(b"payload"), actual: crc32(b"payloae") };
+        assert_eq!(parse(&img, &VerifyPolicy::STRICT), Err(ImageError::UImage(want)));
+        assert!(parse(&img, &VerifyPolicy::STRICT.best_effort()).is_ok());
+    }
+
+    // The load region is checked against what must not be clobbered.
+    #[test]
+    fn load_overlap() {
+        let mut img = mkimage(b"payload");
+        img[IH_LOAD.range()].copy_from_slice(&0x801f_fffcu32.to_be_bytes());
+        img[IH_EP.range()].copy_from_slice(&0x801f_fffcu32.to_be_bytes());
+        img[IH_HCRC.range()].fill(0);
+        let hcrc = crc32(&img[..HEADER_SIZE]);
+        img[IH_HCRC.range()].copy_from_slice(&hcrc.to_be_bytes());
+        let e = parse(&img, &VerifyPolicy::STRICT);
+        assert!(matches!(e, Err(ImageError::LoadOverlap { region: "spl", .. })), "{:?}", e);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::addr::FlashOffset;
+use crate::bootmeta::{BootBank, MAX_BANKS};
+use crate::crc32::crc32;
+use crate::flash_intel::{FlashError, IntelFlash};
+use crate::hash::SHA256_LEN;
+use crate::{slog_debug, slog_info};
+
+// Verification cache: per bank, what the last full check of its image
+// found, so the next boot of the same image can skip hashing the
+// payload. One flash block of fixed-size records, appended, the newest
+// of a bank winning; when full, the block is erased and the newest
+// record of each bank written back. Little-endian:
+//
+//   0x00  u32   magic        "SPVC"
+//   0x04  u32   bank         index into crate::BOOT_BANKS
+//   0x08  u32   hdr_crc      CRC-32 of the image header, hdr_size bytes
+//   0x0c  u32   payload_size
+//   0x10  [32]  digest       payload sha256
+//   0x30  u32   verify_us    what hashing it took
+//   0x34  u32   flags        bit 0: verified ok
+//   0x38  u32   reserved     zero
+//   0x3c  u32   crc          CRC-32 of bytes 0x00..0x3c
+//
+// A record that fails its CRC (torn write) is skipped. Only the digest
+// is taken from the cache: the CRC of the payload is still checked once
+// loaded, and a signature always. The header CRC covers the header's
+// payload CRC and digest, so a new image never matches an old record.
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SPVC");
+const RECORD_SIZE: usize = 0x40;
+const CRC_AT: usize = RECORD_SIZE - 4;
+const FLAG_OK: u32 = 1 << 0;
+const ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+/// What the last full verification of a bank's image found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cached {
+    pub hdr_crc: u32,
+    pub payload_size: u32,
+    pub digest: [u8; SHA256_LEN],
+    pub verify_us: u32,
+    pub ok: bool,
+}
+
+impl Cached {
+    fn encode(&self, bank: BootBank) -> [u8; RECORD_SIZE] {
+        let mut b = [0u8; RECORD_SIZE];
+        b[0x00..0x04].copy_from_slice(&MAGIC.to_le_bytes());
+        b[0x04..0x08].copy_from_slice(&(bank.index() as u32).to_le_bytes());
+        b[0x08..0x0c].copy_from_slice(&self.hdr_crc.to_le_bytes());
+        b[0x0c..0x10].copy_from_slice(&self.payload_size.to_le_bytes());
+        b[0x10..0x30].copy_from_slice(&self.digest);
+        b[0x30..0x34].copy_from_slice(&self.verify_us.to_le_bytes());
+        let flags = if self.ok { FLAG_OK } else { 0 };
+        b[0x34..0x38].copy_from_slice(&flags.to_le_bytes());
+        let crc = crc32(&b[..CRC_AT]);
+        b[CRC_AT..].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    // The bank and entry of a record, None unless its magic and CRC are
+    // right.
+    fn decode(b: &[u8; RECORD_SIZE]) -> Option<(usize, Self)> {
+        let u32_at = |o: usize| u32::from_le_bytes(b[o..o + 4].try_into().unwrap());
+        if u32_at(0x00) != MAGIC || u32_at(CRC_AT) != crc32(&b[..CRC_AT]) {
+            return None;
+        }
+        let cached = Cached {
+            hdr_crc: u32_at(0x08),
+            payload_size: u32_at(0x0c),
+            digest: b[0x10..0x30].try_into().unwrap(),
+            verify_us: u32_at(0x30),
+            ok: u32_at(0x34) & FLAG_OK != 0,
+        };
+        Some((u32_at(0x04) as usize, cached))
+    }
+}
+
+pub struct VerifyCache<'a> {
+    flash: &'a IntelFlash,
+    offset: FlashOffset,
+    size: usize,
+}
+
+impl<'a> VerifyCache<'a> {
+    pub fn new(flash: &'a IntelFlash, offset: FlashOffset, size: usize) -> Self {
+        VerifyCache { flash, offset, size }
+    }
+
+    fn capacity(&self) -> usize {
+        self.size / RECORD_SIZE
+    }
+
+    fn read(&self, idx: usize) -> [u8; RECORD_SIZE] {
+        let mut b = [0u8; RECORD_SIZE];
+        self.flash.read_slice(self.offset + idx * RECORD_SIZE, &mut b);
+        b
+    }
+
+    // The newest record of every bank, and the index of the first free
+    // slot.
+    fn scan(&self) -> ([Option<Cached>; MAX_BANKS], usize) {
+        let mut newest = [None; MAX_BANKS];
+        let mut idx = 0;
+        while idx < self.capacity() {
+            let b = self.read(idx);
+            if u32::from_le_bytes(b[..4].try_into().unwrap()) == ERASED_WORD {
+                break;
+            }
+            if let Some((bank, cached)) = Cached::decode(&b)
+                && bank < MAX_BANKS
+            {
+                newest[bank] = Some(cached);
+            }
+            idx += 1;
+        }
+        (newest, idx)
+    }
+
+    /// The newest record of `bank`, if any.
+    pub fn lookup(&self, bank: BootBank) -> Option<Cached> {
+        self.scan().0[bank.index()]
+    }
+
+    /// Append a record for `bank`, erasing the block first (and keeping
+    /// the other banks' newest records) if it is full.
+    pub fn store(&self, bank: BootBank, cached: &Cached) -> Result<(), FlashError> {
+        let (mut newest, mut next) = self.scan();
+        if next >= self.capacity() {
+            newest[bank.index()] = None;
+            next = self.compact(&newest)?;
+            if next >= self.capacity() {
+                return Err(FlashError::ProgramError);
+            }
+        }
+        slog_debug!("vcache: bank {} record at slot {}", bank, next);
+        self.flash.program(self.offset + next * RECORD_SIZE, &cached.encode(bank))
+    }
+
+    /// Forget that `bank` verified: its image failed, or is about to be
+    /// replaced. Writes nothing unless there is something to forget.
+    pub fn invalidate(&self, bank: BootBank) -> Result<(), FlashError> {
+        match self.lookup(bank) {
+            Some(cached) if cached.ok => self.store(bank, &Cached { ok: false, ..cached }),
+            _ => Ok(()),
+        }
+    }
+
+    // Erase the block and write `newest` back, the verified ones only.
+    // Returns the first free slot.
+    fn compact(&self, newest: &[Option<Cached>; MAX_BANKS]) -> Result<usize, FlashError> {
+        slog_info!("vcache: full, erasing");
+        self.flash.erase_range(self.offset, self.size)?;
+        let mut idx = 0;
+        for (bank, cached) in newest.iter().enumerate() {
+            if let Some(cached) = cached.filter(|c| c.ok) {
+                let b = cached.encode(BootBank(bank as u8));
+                self.flash.program(self.offset + idx * RECORD_SIZE, &b)?;
+                idx += 1;
+            }
+        }
+        Ok(idx)
+    }
+}
@@ -0,0 +1,104 @@
+// Ed25519 verification of bank payloads (secure boot).
+//
+// The public key lives in its own .spl_pubkey section (see linker.ld) so
+// a release flow can swap it in the flat binary at the __spl_pubkey_load
+// offset without recompiling. It is read with a volatile load so the
+// compiler can't fold the built-in value into the code.
+
+use ed25519_compact::{PublicKey, Signature};
+
+use crate::image::ImageSource;
+
+pub const PUBKEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+// Development key: RFC 8032 test vector 1, whose private key is public.
+// Production images MUST replace it.
+#[unsafe(link_section = ".spl_pubkey")]
+#[used]
+static SPL_PUBKEY: [u8; PUBKEY_LEN] = [
+    0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a,
+    0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The embedded public key is not a valid curve point.
+    BadKey,
+    /// The signature is malformed or doesn't match the payload.
+    BadSignature,
+}
+
+/// The public key currently embedded in the image.
+pub fn public_key() -> [u8; PUBKEY_LEN] {
+    unsafe { core::ptr::read_volatile(&raw const SPL_PUBKEY) }
+}
+
+/// Check `signature` over `len` bytes of flash at `offset`, streamed in
+/// chunks so the payload doesn't need to be in RAM yet.
+pub fn verify_flash(
+    flash: &dyn ImageSource,
+    offset: usize,
+    len: usize,
+    signature: &[u8; SIGNATURE_LEN],
+) -> Result<(), VerifyError> {
+    const CHUNK: usize = 256;
+
+    let pk = PublicKey::from_slice(&public_key()).map_err(|_| VerifyError::BadKey)?;
+    let sig = Signature::from_slice(signature).map_err(|_| VerifyError::BadSignature)?;
+    let mut state = pk
+        .verify_incremental(&sig)
+        .map_err(|_| VerifyError::BadSignature)?;
+
+    let mut buf = [0u8; CHUNK];
+    let mut done = 0;
+    while done < len {
+        let n = core::cmp::min(CHUNK, len - done);
+        flash.read_slice(offset + done, &mut buf[..n]);
+        state.absorb(&buf[..n]);
+        done += n;
+    }
+    state.verify().map_err(|_| VerifyError::BadSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_compact::{KeyPair, Seed};
+
+    use super::*;
+    use crate::image::MemSource;
+
+    // The secret half of the development key (RFC 8032 test vector 1).
+    const DEV_SEED: [u8; 32] = [
+        0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c, 0xc4,
+        0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60,
+    ];
+
+    fn verify(data: &[u8], signature: &[u8; SIGNATURE_LEN]) -> Result<(), VerifyError> {
+        let flash = MemSource { base: data.as_ptr() as usize, size: data.len() };
+        verify_flash(&flash, 0, data.len(), signature)
+    }
+
+    // A payload signed with the development key passes, streamed in
+    // chunks; one flipped bit anywhere in it or in the signature fails.
+    #[test]
+    fn signed_payload() {
+        let keys = KeyPair::from_seed(Seed::new(DEV_SEED));
+        assert_eq!(*keys.pk, public_key());
+
+        let mut data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let mut sig = *keys.sk.sign(&data, None);
+        assert_eq!(verify(&data, &sig), Ok(()));
+        for bit in [0, 8 * 255, 8 * 256 + 3, 8 * 999 + 7] {
+            data[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(verify(&data, &sig), Err(VerifyError::BadSignature), "payload bit {}", bit);
+            data[bit / 8] ^= 1 << (bit % 8);
+        }
+        for bit in [0, 8 * 32 + 1, 8 * 63 + 5] {
+            sig[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(verify(&data, &sig), Err(VerifyError::BadSignature), "signature bit {}", bit);
+            sig[bit / 8] ^= 1 << (bit % 8);
+        }
+        assert_eq!(verify(&data[..999], &sig), Err(VerifyError::BadSignature));
+    }
+}
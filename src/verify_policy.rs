@@ -0,0 +1,266 @@
+use core::fmt;
+
+use crate::dtb::Fdt;
+use crate::image::ImageError;
+use crate::{slog_debug, slog_info, slog_warn};
+
+// What an image check failing does, check by check: refuse the image
+// (Enforce), log it and boot the image anyway (Warn), or not check at
+// all (Skip). The built-in policy comes from the features: Enforce
+// everywhere but the header magic, or Warn with "verify-warn" for
+// bring-up. /chosen "spl,verify-policy" ("crc=warn,signature=skip",
+// comma-separated) can tighten any check; only a "dev" build lets it
+// relax one, so a production build never boots what Enforce refuses.
+//
+// A header-less bank is a raw payload with a CRC trailer, a supported
+// format: the magic check is Skip unless asked for, and Enforce then
+// refuses raw banks.
+
+/// The checks the policy covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// An SPL1 or uImage header, rather than a raw payload.
+    Magic,
+    /// The payload CRC (SPL1 header, uImage data, raw trailer).
+    Crc,
+    /// The payload sha256 against the one in the SPL1 header.
+    Digest,
+    /// The payload signature (secure boot).
+    Signature,
+}
+
+const CHECKS: [Check; 4] = [Check::Magic, Check::Crc, Check::Digest, Check::Signature];
+
+impl Check {
+    fn name(self) -> &'static str {
+        match self {
+            Check::Magic => "magic",
+            Check::Crc => "crc",
+            Check::Digest => "sha256",
+            Check::Signature => "signature",
+        }
+    }
+}
+
+/// Least to most strict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Skip,
+    Warn,
+    Enforce,
+}
+
+impl Action {
+    fn parse(value: &[u8]) -> Option<Self> {
+        match value {
+            b"skip" => Some(Action::Skip),
+            b"warn" => Some(Action::Warn),
+            b"enforce" => Some(Action::Enforce),
+            _ => None,
+        }
+    }
+
+    fn letter(self) -> char {
+        match self {
+            Action::Skip => 'S',
+            Action::Warn => 'W',
+            Action::Enforce => 'E',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyPolicy {
+    actions: [Action; CHECKS.len()],
+}
+
+impl VerifyPolicy {
+    /// Every check enforced: for checking an update, where a warning
+    /// would only hide a bad transfer.
+    pub const STRICT: VerifyPolicy = VerifyPolicy {
+        actions: [Action::Enforce; CHECKS.len()],
+    };
+
+    /// The policy built in, before /chosen has its say.
+    pub const fn built_in() -> Self {
+        let failed = if cfg!(feature = "verify-warn") { Action::Warn } else { Action::Enforce };
+        VerifyPolicy {
+            actions: [Action::Skip, failed, failed, failed],
+        }
+    }
+
+    /// The built-in policy, adjusted by /chosen "spl,verify-policy".
+    /// Entries that don't parse, or that relax a check outside a "dev"
+    /// build, are logged and ignored.
+    pub fn from_chosen(fdt: Option<&Fdt>) -> Self {
+        let mut policy = Self::built_in();
+        let value = match fdt.map(|f| f.node_prop("chosen", "spl,verify-policy")) {
+            Some(Ok(Some(v))) => v,
+            _ => return policy,
+        };
+        let value = value.strip_suffix(b"\0").unwrap_or(value);
+        for item in value.split(|&c| c == b',') {
+            let parsed = item.iter().position(|&c| c == b'=').and_then(|eq| {
+                let check = CHECKS.into_iter().find(|c| c.name().as_bytes() == &item[..eq])?;
+                Some((check, Action::parse(&item[eq + 1..])?))
+            });
+            let Some((check, action)) = parsed else {
+                slog_warn!("WARNING: spl,verify-policy: '{}' ignored", core::str::from_utf8(item).unwrap_or("?"));
+                continue;
+            };
+            if let Err(current) = policy.set(check, action) {
+                slog_warn!(
+                    "WARNING: spl,verify-policy: {} stays {:?}, only a dev build may relax it to {:?}",
+                    check.name(),
+                    current,
+                    action
+                );
+            }
+        }
+        policy
+    }
+
+    /// Set what a failed `check` does. Relaxing a check takes a "dev"
+    /// build; otherwise it is left alone, and its action returned.
+    pub fn set(&mut self, check: Check, action: Action) -> Result<(), Action> {
+        let current = self.action(check);
+        if action < current && !cfg!(feature = "dev") {
+            return Err(current);
+        }
+        self.actions[check as usize] = action;
+        Ok(())
+    }
+
+    pub fn action(&self, check: Check) -> Action {
+        self.actions[check as usize]
+    }
+
+    /// The same, with every enforced check only warning: a "dev" build's
+    /// last try once out of boot time (see budget.rs).
+    pub fn best_effort(self) -> Self {
+        VerifyPolicy {
+            actions: self.actions.map(|a| a.min(Action::Warn)),
+        }
+    }
+
+    /// Whether `check` is to be run at all (logged when it isn't).
+    pub fn runs(&self, check: Check) -> bool {
+        let runs = self.action(check) != Action::Skip;
+        if !runs {
+            slog_debug!("{} not checked (policy: skip)", check.name());
+        }
+        runs
+    }
+
+    /// Whether every check that vouches for a payload's bytes is
+    /// enforced, so that one that got through is known good.
+    pub fn enforces_payload(&self) -> bool {
+        [Check::Crc, Check::Digest, Check::Signature]
+            .into_iter()
+            .all(|c| self.action(c) == Action::Enforce)
+    }
+
+    /// What becomes of the outcome of `check`: a failure is returned
+    /// under Enforce only, and logged otherwise.
+    pub fn decide(&self, check: Check, result: Result<(), ImageError>) -> Result<(), ImageError> {
+        let Err(e) = result else {
+            return Ok(());
+        };
+        match self.action(check) {
+            Action::Enforce => Err(e),
+            Action::Warn => {
+                slog_warn!("WARNING: {} check failed ({:?}), booting anyway (policy: warn)", check.name(), e);
+                Ok(())
+            }
+            Action::Skip => {
+                slog_debug!("{} check not enforced ({:?}, policy: skip)", check.name(), e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Log the policy, if it isn't the production one.
+    pub fn log(&self) {
+        if *self != Self::built_in() || cfg!(feature = "verify-warn") {
+            slog_info!("verify policy: {}", self);
+        }
+    }
+}
+
+/// As the boot report prints it: magic:S,crc:E,sha256:E,signature:E.
+impl fmt::Display for VerifyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, check) in CHECKS.into_iter().enumerate() {
+            write!(f, "{}{}:{}", if i == 0 { "" } else { "," }, check.name(), self.action(check).letter())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtb::tests::{Item, Item::Begin, Item::End, blob};
+
+    const ACTIONS: [Action; 3] = [Action::Skip, Action::Warn, Action::Enforce];
+
+    fn chosen(policy: &[u8]) -> Vec<u8> {
+        blob(&[Begin(""), Begin("chosen"), Item::Prop("spl,verify-policy", policy), End, End])
+    }
+
+    // A failure is only returned under Enforce, for every check.
+    #[test]
+    fn decide_matrix() {
+        let e = ImageError::CrcMismatch { expected: 1, actual: 2 };
+        for action in ACTIONS {
+            let policy = VerifyPolicy { actions: [action; CHECKS.len()] };
+            for check in CHECKS {
+                assert_eq!(policy.decide(check, Ok(())), Ok(()));
+                let want = if action == Action::Enforce { Err(e) } else { Ok(()) };
+                assert_eq!(policy.decide(check, Err(e)), want, "{:?} {:?}", check, action);
+                assert_eq!(policy.runs(check), action != Action::Skip);
+            }
+        }
+    }
+
+    #[test]
+    fn built_in() {
+        let policy = VerifyPolicy::built_in();
+        let failed = if cfg!(feature = "verify-warn") { 'W' } else { 'E' };
+        assert_eq!(format!("{}", policy), format!("magic:S,crc:{0},sha256:{0},signature:{0}", failed));
+        assert_eq!(policy.enforces_payload(), !cfg!(feature = "verify-warn"));
+        assert!(VerifyPolicy::STRICT.enforces_payload());
+        let best = VerifyPolicy::STRICT.best_effort();
+        assert_eq!(format!("{}", best), "magic:W,crc:W,sha256:W,signature:W");
+        assert!(!best.enforces_payload());
+        assert_eq!(VerifyPolicy::from_chosen(None), policy);
+    }
+
+    // Tightening a check always takes; relaxing one only in a dev build.
+    #[test]
+    fn cannot_downgrade() {
+        for from in ACTIONS {
+            for to in ACTIONS {
+                let mut policy = VerifyPolicy { actions: [from; CHECKS.len()] };
+                let relaxes = to < from;
+                let want = if relaxes && !cfg!(feature = "dev") { Err(from) } else { Ok(()) };
+                assert_eq!(policy.set(Check::Crc, to), want, "{:?} to {:?}", from, to);
+                assert_eq!(policy.action(Check::Crc), if want.is_ok() { to } else { from });
+                assert_eq!(policy.action(Check::Digest), from);
+            }
+        }
+
+        let dtb = chosen(b"magic=enforce,crc=skip,bogus,sha256=maybe,signature=warn\0");
+        let policy = VerifyPolicy::from_chosen(Some(&Fdt::new(&dtb).unwrap()));
+        let built_in = VerifyPolicy::built_in();
+        assert_eq!(policy.action(Check::Magic), Action::Enforce);
+        assert_eq!(policy.action(Check::Digest), built_in.action(Check::Digest));
+        if cfg!(feature = "dev") {
+            assert_eq!(policy.action(Check::Crc), Action::Skip);
+            assert_eq!(policy.action(Check::Signature), Action::Warn);
+        } else {
+            assert_eq!(policy.action(Check::Crc), built_in.action(Check::Crc));
+            assert_eq!(policy.action(Check::Signature), built_in.action(Check::Signature));
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use core::cell::Cell;
+use core::result::Result;
+
+use crate::arch::barrier;
+use crate::clint::Deadline;
+use crate::image::ImageSource;
+use crate::mmio::Reg32;
+use crate::slog_warn;
+
+// Minimal virtio block driver over the virtio-mmio transport, legacy
+// (version 1, QEMU's default) or modern (version 2). Polled, one request
+// in flight, a single static virtqueue: one device at a time.
+//
+// A read is the usual three-descriptor chain: request header (device
+// reads it), data buffer, status byte (device writes them). The data
+// goes straight into the caller's buffer, which is fine as long as the
+// device sees physical addresses, as it does from M-mode.
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// QEMU virt puts 8 virtio-mmio transports here, 0x1000 apart.
+pub const QEMU_VIRT_MMIO_BASE: usize = 0x1000_1000;
+pub const QEMU_VIRT_MMIO_COUNT: usize = 8;
+pub const QEMU_VIRT_MMIO_STRIDE: usize = 0x1000;
+
+// MMIO registers (virtio 1.2, 4.2.2 and 4.2.4 for legacy).
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028; // legacy
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c; // legacy
+const QUEUE_PFN: usize = 0x040; // legacy
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100; // virtio_blk_config, capacity first
+
+const MAGIC: u32 = u32::from_le_bytes(*b"virt");
+const DEVICE_ID_BLOCK: u32 = 2;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+// The only feature we take, and only on a modern device (feature bit 32,
+// bit 0 of the second feature word).
+const F_VERSION_1: u32 = 1 << 0;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const REQ_IN: u32 = 0;
+const REQ_STATUS_OK: u8 = 0;
+
+const QUEUE_SIZE: usize = 8;
+// Legacy devices find the used ring at the next QUEUE_ALIGN boundary
+// after the available ring; the modern layout uses the same addresses.
+const PAGE_SIZE: usize = 4096;
+
+// Largest single request, so a multi-MiB payload read doesn't sit in
+// one descriptor (nothing about SIZE_MAX/SEG_MAX is negotiated).
+const MAX_REQUEST_SECTORS: usize = 128;
+const REQUEST_TIMEOUT_US: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// No virtio magic at this address.
+    NotVirtio,
+    UnsupportedVersion(u32),
+    /// A virtio transport, but not a block device (0: nothing attached).
+    NotBlock(u32),
+    /// A modern device without VIRTIO_F_VERSION_1, or one that refused
+    /// our feature set.
+    FeaturesRejected,
+    /// Queue 0 is missing, already live, or smaller than QUEUE_SIZE.
+    QueueUnavailable(u32),
+    /// Buffer length is not a whole number of sectors.
+    BadLength(usize),
+    /// Read past the end of the disk.
+    OutOfRange { lba: u64, sectors: u64 },
+    /// The device didn't complete the request in time.
+    Timeout,
+    /// The device completed the request with this status.
+    Io(u8),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C, align(4096))]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+// Descriptor table and available ring on the first page, used ring on
+// the second (legacy layout for QUEUE_ALIGN = PAGE_SIZE).
+#[repr(C, align(4096))]
+struct VirtQueue {
+    desc: [Desc; QUEUE_SIZE],
+    avail: AvailRing,
+    used: UsedRing,
+}
+
+const _: () = assert!(core::mem::offset_of!(VirtQueue, used) == PAGE_SIZE);
+
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Only the boot hart runs Rust code, so plain statics are enough. All of
+// it is in .bss, so the rings start out zeroed as the spec wants.
+static mut QUEUE: VirtQueue = VirtQueue {
+    desc: [Desc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE],
+    avail: AvailRing { flags: 0, idx: 0, ring: [0; QUEUE_SIZE], used_event: 0 },
+    used: UsedRing {
+        flags: 0,
+        idx: 0,
+        ring: [UsedElem { id: 0, len: 0 }; QUEUE_SIZE],
+        avail_event: 0,
+    },
+};
+static mut REQ_HEADER: BlkReqHeader = BlkReqHeader { req_type: 0, reserved: 0, sector: 0 };
+static mut REQ_STATUS: u8 = 0;
+// Partial-sector reads through ImageSource go through here.
+static mut BOUNCE: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
+
+/// An initialized virtio block device.
+pub struct VirtioBlk {
+    base: usize,
+    version: u32,
+    /// Disk size in 512-byte sectors.
+    pub capacity: u64,
+    // Sector currently held in BOUNCE, if any.
+    bounce_lba: Cell<Option<u64>>,
+}
+
+// Only ever called with a base found as a virtio,mmio node in the DTB.
+fn read32(base: usize, off: usize) -> u32 {
+    unsafe { Reg32::at(base, off) }.read()
+}
+
+fn write32(base: usize, off: usize, val: u32) {
+    unsafe { Reg32::at(base, off) }.write(val)
+}
+
+/// Whether a block device sits behind the virtio-mmio transport at
+/// `base`, without touching its state.
+pub fn is_block_device(base: usize) -> bool {
+    read32(base, MAGIC_VALUE) == MAGIC && read32(base, DEVICE_ID) == DEVICE_ID_BLOCK
+}
+
+impl VirtioBlk {
+    /// Reset and set up the block device at `base`.
+    pub fn init(base: usize) -> Result<Self, VirtioError> {
+        if read32(base, MAGIC_VALUE) != MAGIC {
+            return Err(VirtioError::NotVirtio);
+        }
+        let version = read32(base, VERSION);
+        if version != 1 && version != 2 {
+            return Err(VirtioError::UnsupportedVersion(version));
+        }
+        let id = read32(base, DEVICE_ID);
+        if id != DEVICE_ID_BLOCK {
+            return Err(VirtioError::NotBlock(id));
+        }
+
+        let dev = VirtioBlk {
+            base,
+            version,
+            capacity: 0,
+            bounce_lba: Cell::new(None),
+        };
+        write32(base, STATUS, 0);
+        dev.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        if let Err(e) = dev.negotiate().and_then(|()| dev.setup_queue()) {
+            dev.set_status(STATUS_FAILED);
+            return Err(e);
+        }
+        dev.set_status(STATUS_DRIVER_OK);
+
+        let capacity = dev.read_capacity();
+        Ok(VirtioBlk { capacity, ..dev })
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    fn set_status(&self, bits: u32) {
+        write32(self.base, STATUS, read32(self.base, STATUS) | bits);
+    }
+
+    // Take no optional feature at all: plain 512-byte sector reads are
+    // all we need. A modern device still wants VERSION_1 acknowledged.
+    fn negotiate(&self) -> Result<(), VirtioError> {
+        if self.is_legacy() {
+            write32(self.base, DRIVER_FEATURES_SEL, 0);
+            write32(self.base, DRIVER_FEATURES, 0);
+            return Ok(());
+        }
+        write32(self.base, DEVICE_FEATURES_SEL, 1);
+        if read32(self.base, DEVICE_FEATURES) & F_VERSION_1 == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        write32(self.base, DRIVER_FEATURES_SEL, 0);
+        write32(self.base, DRIVER_FEATURES, 0);
+        write32(self.base, DRIVER_FEATURES_SEL, 1);
+        write32(self.base, DRIVER_FEATURES, F_VERSION_1);
+        self.set_status(STATUS_FEATURES_OK);
+        if read32(self.base, STATUS) & STATUS_FEATURES_OK == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(())
+    }
+
+    fn setup_queue(&self) -> Result<(), VirtioError> {
+        let base = self.base;
+        if self.is_legacy() {
+            write32(base, GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        }
+        write32(base, QUEUE_SEL, 0);
+        let max = read32(base, QUEUE_NUM_MAX);
+        let busy = if self.is_legacy() {
+            read32(base, QUEUE_PFN) != 0
+        } else {
+            read32(base, QUEUE_READY) != 0
+        };
+        if busy || (max as usize) < QUEUE_SIZE {
+            return Err(VirtioError::QueueUnavailable(max));
+        }
+        write32(base, QUEUE_NUM, QUEUE_SIZE as u32);
+
+        let q = &raw mut QUEUE;
+        let desc = q as usize as u64;
+        if self.is_legacy() {
+            write32(base, QUEUE_ALIGN, PAGE_SIZE as u32);
+            write32(base, QUEUE_PFN, (desc / PAGE_SIZE as u64) as u32);
+        } else {
+            let avail = unsafe { &raw mut (*q).avail } as usize as u64;
+            let used = unsafe { &raw mut (*q).used } as usize as u64;
+            write32(base, QUEUE_DESC_LOW, desc as u32);
+            write32(base, QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            write32(base, QUEUE_DRIVER_LOW, avail as u32);
+            write32(base, QUEUE_DRIVER_HIGH, (avail >> 32) as u32);
+            write32(base, QUEUE_DEVICE_LOW, used as u32);
+            write32(base, QUEUE_DEVICE_HIGH, (used >> 32) as u32);
+            write32(base, QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    // capacity is a u64 read as two words; a modern device may change
+    // its config between them, which the generation counter catches
+    // (legacy devices have none).
+    fn read_capacity(&self) -> u64 {
+        let generation = || if self.is_legacy() { 0 } else { read32(self.base, CONFIG_GENERATION) };
+        loop {
+            let before = generation();
+            let lo = read32(self.base, CONFIG) as u64;
+            let hi = read32(self.base, CONFIG + 4) as u64;
+            if generation() == before {
+                return (hi << 32) | lo;
+            }
+        }
+    }
+
+    /// Read whole sectors starting at `lba` into `buf`, whose length
+    /// must be a multiple of SECTOR_SIZE.
+    pub fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), VirtioError> {
+        if !buf.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(VirtioError::BadLength(buf.len()));
+        }
+        let sectors = (buf.len() / SECTOR_SIZE) as u64;
+        if lba.checked_add(sectors).is_none_or(|end| end > self.capacity) {
+            return Err(VirtioError::OutOfRange { lba, sectors });
+        }
+        // Whatever lands in the caller's buffer may well overlap what the
+        // bounce buffer caches: drop it.
+        self.bounce_lba.set(None);
+        for (i, chunk) in buf.chunks_mut(MAX_REQUEST_SECTORS * SECTOR_SIZE).enumerate() {
+            self.request(lba + (i * MAX_REQUEST_SECTORS) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    // One VIRTIO_BLK_T_IN request, polled to completion.
+    fn request(&self, lba: u64, buf: &mut [u8]) -> Result<(), VirtioError> {
+        let q = &raw mut QUEUE;
+        let header = &raw mut REQ_HEADER;
+        let status = &raw mut REQ_STATUS;
+        let idx = unsafe {
+            header.write(BlkReqHeader {
+                req_type: REQ_IN,
+                reserved: 0,
+                sector: lba,
+            });
+            status.write_volatile(0xff);
+            (*q).desc[0] = Desc {
+                addr: header as usize as u64,
+                len: core::mem::size_of::<BlkReqHeader>() as u32,
+                flags: DESC_F_NEXT,
+                next: 1,
+            };
+            (*q).desc[1] = Desc {
+                addr: buf.as_mut_ptr() as usize as u64,
+                len: buf.len() as u32,
+                flags: DESC_F_WRITE | DESC_F_NEXT,
+                next: 2,
+            };
+            (*q).desc[2] = Desc {
+                addr: status as usize as u64,
+                len: 1,
+                flags: DESC_F_WRITE,
+                next: 0,
+            };
+            let idx = (&raw const (*q).avail.idx).read_volatile();
+            (*q).avail.ring[idx as usize % QUEUE_SIZE] = 0;
+            // Descriptors and ring entry before the index that publishes
+            // them, and the index before the notify.
+            barrier::fence_rw_rw();
+            (&raw mut (*q).avail.idx).write_volatile(idx.wrapping_add(1));
+            barrier::fence_rw_rw();
+            idx.wrapping_add(1)
+        };
+        write32(self.base, QUEUE_NOTIFY, 0);
+
+        let deadline = Deadline::after_us(REQUEST_TIMEOUT_US);
+        while unsafe { (&raw const (*q).used.idx).read_volatile() } != idx {
+            if deadline.expired() {
+                return Err(VirtioError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        // The data and status writes are visible once used.idx is.
+        barrier::fence_rw_rw();
+        write32(self.base, INTERRUPT_ACK, read32(self.base, INTERRUPT_STATUS));
+
+        match unsafe { status.read_volatile() } {
+            REQ_STATUS_OK => Ok(()),
+            s => Err(VirtioError::Io(s)),
+        }
+    }
+
+    // Byte-granular read: whole sectors go straight into `buf`, partial
+    // ones through the bounce buffer (which keeps the last one, so
+    // streaming a bank in small chunks doesn't read each sector twice).
+    fn read_bytes(&self, offset: usize, buf: &mut [u8]) -> Result<(), VirtioError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let lba = (pos / SECTOR_SIZE) as u64;
+            let skip = pos % SECTOR_SIZE;
+            let left = buf.len() - done;
+            if skip == 0 && left >= SECTOR_SIZE {
+                let n = left - left % SECTOR_SIZE;
+                self.read_sectors(lba, &mut buf[done..done + n])?;
+                done += n;
+                continue;
+            }
+            let bounce = unsafe { core::slice::from_raw_parts_mut((&raw mut BOUNCE).cast::<u8>(), SECTOR_SIZE) };
+            if self.bounce_lba.get() != Some(lba) {
+                self.read_sectors(lba, bounce)?;
+                self.bounce_lba.set(Some(lba));
+            }
+            let n = core::cmp::min(SECTOR_SIZE - skip, left);
+            buf[done..done + n].copy_from_slice(&bounce[skip..skip + n]);
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl ImageSource for VirtioBlk {
+    fn read_slice(&self, offset: usize, buf: &mut [u8]) {
+        if let Err(e) = self.read_bytes(offset, buf) {
+            slog_warn!(
+                "WARNING: virtio-blk read of {} bytes at 0x{:x} failed: {:?}",
+                buf.len(),
+                offset,
+                e
+            );
+            buf.fill(0xff);
+        }
+    }
+}
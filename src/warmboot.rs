@@ -0,0 +1,302 @@
+use crate::arch::csr::csr_read;
+use crate::bootlog;
+use crate::clint;
+use crate::crc32::crc32;
+use crate::hash::{hmac_sha256, Sha256, SHA256_LEN};
+use crate::{slog_debug, slog_info, slog_warn};
+
+// Warm reboot fast path. A payload that restarts the board without
+// touching the flash (a plain reboot) can vouch for itself: the next
+// boot then loads the same bank without hashing it, checking its
+// signature or testing its RAM. The proof lives in a RAM record that
+// _start doesn't clear, next to the reset signature (see reset_cause.rs):
+//
+//   0x00  magic    b"SWRM"  the SPL's part, written at each handoff
+//   0x04  bank     bank index booted
+//   0x08  hdr_crc  CRC-32 of that bank's SPL1 header
+//   0x0c  crc      CRC-32 of 0x00..0x0c and 0x10..0x50
+//   0x10  key      32 bytes, new at each handoff
+//   0x30  digest   SHA-256 of the payload booted
+//   0x50  tmagic   b"WTOK"  the token, written by the payload before it
+//   0x54  tbank    bank     resets, or by the SBI shim on its behalf
+//   0x58  tdigest  digest
+//   0x78  mac      HMAC-SHA256(key, tbank LE || tdigest)
+//
+// The token holds only if its MAC is right under the key of the boot
+// that handed off, names what that boot booted, and the reset was a
+// software one (reset_cause.rs); the bank's header must also still have
+// the CRC it had then. Anything else (power-on, a bad or stale token, an
+// image updated since) is an ordinary boot with every check. Each record
+// is wiped once read: one token, one fast boot.
+//
+// The key isn't secret from the payload, which reads it to write the
+// token: the MAC only ties a token to the boot it was written in, so
+// one left over from an earlier boot, or RAM garbage, never passes.
+// Hence no fast path where a signature check is enforced.
+//
+// Its location is advertised in /chosen as "spl,warm-token" (u64
+// address, u64 size).
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SWRM");
+const TOKEN_MAGIC: u32 = u32::from_le_bytes(*b"WTOK");
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Token {
+    magic: u32,
+    bank: u32,
+    digest: [u8; SHA256_LEN],
+    mac: [u8; SHA256_LEN],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(8))]
+struct Record {
+    magic: u32,
+    bank: u32,
+    hdr_crc: u32,
+    crc: u32,
+    key: [u8; SHA256_LEN],
+    digest: [u8; SHA256_LEN],
+    token: Token,
+}
+
+const EMPTY: Record = Record {
+    magic: 0,
+    bank: 0,
+    hdr_crc: 0,
+    crc: 0,
+    key: [0; SHA256_LEN],
+    digest: [0; SHA256_LEN],
+    token: Token {
+        magic: 0,
+        bank: 0,
+        digest: [0; SHA256_LEN],
+        mac: [0; SHA256_LEN],
+    },
+};
+
+impl Record {
+    fn crc_of(&self) -> u32 {
+        let mut b = [0u8; 12 + 2 * SHA256_LEN];
+        b[..4].copy_from_slice(&self.magic.to_le_bytes());
+        b[4..8].copy_from_slice(&self.bank.to_le_bytes());
+        b[8..12].copy_from_slice(&self.hdr_crc.to_le_bytes());
+        b[12..12 + SHA256_LEN].copy_from_slice(&self.key);
+        b[12 + SHA256_LEN..].copy_from_slice(&self.digest);
+        crc32(&b)
+    }
+
+    fn mac_of(&self, bank: u32, digest: &[u8; SHA256_LEN]) -> [u8; SHA256_LEN] {
+        hmac_sha256(&self.key, &[&bank.to_le_bytes(), digest])
+    }
+}
+
+#[unsafe(link_section = ".spl_noinit")]
+static mut RECORD: Record = EMPTY;
+
+/// What the token vouched for, until the bank is loaded.
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    bank: usize,
+    hdr_crc: u32,
+    digest: [u8; SHA256_LEN],
+}
+
+// Only the boot hart runs Rust code, so a plain static is enough.
+static mut CLAIM: Option<Claim> = None;
+
+fn record() -> *mut Record {
+    &raw mut RECORD
+}
+
+/// Read the record the previous boot and its payload left, and wipe it.
+/// `warm` is whether this boot follows a software reset; the token is
+/// only taken then.
+pub fn take(warm: bool) {
+    let r = unsafe { core::ptr::read_volatile(record()) };
+    unsafe { core::ptr::write_volatile(record(), EMPTY) };
+    if r.token.magic != TOKEN_MAGIC {
+        return;
+    }
+    let why = if !warm {
+        "not after a software reset"
+    } else if r.magic != MAGIC || r.crc != r.crc_of() {
+        "no handoff record"
+    } else if r.token.mac != r.mac_of(r.token.bank, &r.token.digest) {
+        "bad MAC"
+    } else if r.token.bank != r.bank || r.token.digest != r.digest {
+        "not what was booted"
+    } else {
+        slog_debug!("warm token: bank index {}, fast boot if its header is unchanged", r.bank);
+        unsafe {
+            (&raw mut CLAIM).write(Some(Claim {
+                bank: r.bank as usize,
+                hdr_crc: r.hdr_crc,
+                digest: r.digest,
+            }))
+        };
+        return;
+    };
+    slog_warn!("WARNING: warm token ignored ({}), full verification", why);
+}
+
+/// The digest of bank `bank` to take for granted, if a warm token vouched
+/// for it and its header still has CRC `hdr_crc`. Once only.
+pub fn fast_path(bank: usize, hdr_crc: u32) -> Option<[u8; SHA256_LEN]> {
+    let claim = unsafe { (&raw const CLAIM).read() }?;
+    if claim.bank != bank {
+        return None;
+    }
+    unsafe { (&raw mut CLAIM).write(None) };
+    if claim.hdr_crc != hdr_crc {
+        slog_info!("warm token: bank header changed since, full verification");
+        return None;
+    }
+    Some(claim.digest)
+}
+
+// A key for this handoff. Not a secret (see above), only never the same
+// twice: what RAM held (the last key, or power-on garbage), the time,
+// the cycle count and this boot's event log, hashed.
+fn new_key(old: &[u8; SHA256_LEN]) -> [u8; SHA256_LEN] {
+    let mut h = Sha256::new();
+    h.update(old);
+    h.update(&clint::mtime().to_le_bytes());
+    h.update(&(csr_read!(mcycle) as u64).to_le_bytes());
+    h.update(&bootlog::summary().1);
+    h.finish()
+}
+
+/// Before handing off bank `bank` (header CRC `hdr_crc`, payload SHA-256
+/// `digest`): a fresh key, and what a token may vouch for.
+pub fn arm(bank: usize, hdr_crc: u32, digest: &[u8; SHA256_LEN]) {
+    let old = unsafe { core::ptr::read_volatile(&raw const (*record()).key) };
+    let mut r = Record {
+        magic: MAGIC,
+        bank: bank as u32,
+        hdr_crc,
+        key: new_key(&old),
+        digest: *digest,
+        ..EMPTY
+    };
+    r.crc = r.crc_of();
+    unsafe { core::ptr::write_volatile(record(), r) };
+}
+
+/// Write the token for what was handed off, as the payload would: the
+/// SBI shim's warm reboot. False if nothing was.
+#[cfg_attr(not(feature = "sbi-shim"), allow(dead_code))]
+pub fn sign() -> bool {
+    let mut r = unsafe { core::ptr::read_volatile(record()) };
+    if r.magic != MAGIC || r.crc != r.crc_of() {
+        return false;
+    }
+    r.token = Token {
+        magic: TOKEN_MAGIC,
+        bank: r.bank,
+        digest: r.digest,
+        mac: r.mac_of(r.bank, &r.digest),
+    };
+    unsafe { core::ptr::write_volatile(record(), r) };
+    true
+}
+
+/// Address and size of the record.
+pub fn region() -> (usize, usize) {
+    (record() as usize, core::mem::size_of::<Record>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // One record and one claim for every test.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    const BANK: usize = 1;
+    const HDR_CRC: u32 = 0x1234_5678;
+    const DIGEST: [u8; SHA256_LEN] = [0x5A; SHA256_LEN];
+
+    // A boot handed off BANK and its payload wrote the token; no claim
+    // left over from an earlier test.
+    fn signed() {
+        unsafe { (&raw mut CLAIM).write(None) };
+        arm(BANK, HDR_CRC, &DIGEST);
+        assert!(sign());
+    }
+
+    // What the payload (or RAM decay) does to the record before the reset.
+    fn tamper(f: impl FnOnce(&mut Record)) {
+        let mut r = unsafe { core::ptr::read_volatile(record()) };
+        f(&mut r);
+        unsafe { core::ptr::write_volatile(record(), r) };
+    }
+
+    #[test]
+    fn warm_reboot() {
+        let _serial = SERIAL.lock().unwrap();
+        signed();
+        take(true);
+        assert_eq!(fast_path(BANK - 1, HDR_CRC), None);
+        assert_eq!(fast_path(BANK, HDR_CRC), Some(DIGEST));
+        assert_eq!(fast_path(BANK, HDR_CRC), None);
+        take(true);
+        assert_eq!(fast_path(BANK, HDR_CRC), None);
+    }
+
+    // A token whose MAC isn't the handing-off boot's: a byte of it
+    // changed, made under another key, or right but for something else.
+    #[test]
+    fn forged_mac() {
+        let _serial = SERIAL.lock().unwrap();
+        let forgeries: [fn(&mut Record); 4] = [
+            |r| r.token.mac[SHA256_LEN - 1] ^= 1,
+            |r| r.token.mac = hmac_sha256(&[0; SHA256_LEN], &[&r.bank.to_le_bytes(), &r.digest]),
+            |r| r.token.digest[0] ^= 1,
+            |r| {
+                r.token.bank = 0;
+                r.token.mac = r.mac_of(0, &r.digest);
+            },
+        ];
+        for (i, forge) in forgeries.into_iter().enumerate() {
+            signed();
+            tamper(forge);
+            take(true);
+            assert_eq!(fast_path(BANK, HDR_CRC), None, "forgery {}", i);
+            assert_eq!(fast_path(0, HDR_CRC), None, "forgery {}", i);
+        }
+    }
+
+    // A token left over: from the boot before the last handoff (another
+    // key since), or for an image updated since (another header CRC).
+    #[test]
+    fn stale_token() {
+        let _serial = SERIAL.lock().unwrap();
+        signed();
+        let old = unsafe { core::ptr::read_volatile(record()) }.token;
+        arm(BANK, HDR_CRC, &DIGEST);
+        tamper(|r| r.token = old);
+        take(true);
+        assert_eq!(fast_path(BANK, HDR_CRC), None);
+
+        signed();
+        take(true);
+        assert_eq!(fast_path(BANK, HDR_CRC ^ 1), None);
+        assert_eq!(fast_path(BANK, HDR_CRC), None);
+    }
+
+    // A power-on or any other reset but a software one: the token is
+    // wiped unused.
+    #[test]
+    fn cold_boot() {
+        let _serial = SERIAL.lock().unwrap();
+        signed();
+        take(false);
+        assert_eq!(fast_path(BANK, HDR_CRC), None);
+        take(true);
+        assert_eq!(fast_path(BANK, HDR_CRC), None);
+        assert!(!sign());
+    }
+}
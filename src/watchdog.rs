@@ -0,0 +1,245 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::arch::csr;
+use crate::clint::{self, Deadline};
+use crate::slog_debug;
+
+// Boot watchdog, armed just before the jump so a payload that hangs for
+// good resets the board and the A/B logic gets to try again (the attempt
+// was already recorded by then).
+//
+// Contract with the next stage. When it starts:
+//   - mtimecmp of the boot hart holds the expiry time,
+//   - mie.MTIE and mstatus.MIE are set,
+//   - mtvec still points at the SPL's trap handler, which resets the
+//     board on that timer interrupt.
+// It pets the watchdog by moving mtimecmp forward, and stops it by
+// clearing mie.MTIE (OpenSBI clears mie as one of its first
+// instructions, so it is stopped right away there). It must do one or
+// the other before pointing mtvec at its own handler, which would
+// otherwise get the expiry as an ordinary timer interrupt.
+//
+// Long operations of the SPL itself (erasing a bank, a memory test,
+// copying a payload, an XMODEM transfer) call yield_point() as they go,
+// between two units of work (a block erase, a chunk, a pass, a block
+// received), with the flash back in read-array mode. It runs the yield hook, if one is set: pet_hook()
+// pets the watchdog and logs a progress tick now and then, and a hook
+// may also stop the operation, which then returns its Cancelled error.
+// No hook, no cost but the check.
+
+/// A boot watchdog backend.
+pub trait Watchdog {
+    /// Reset the board `timeout_us` from now unless the next stage pets
+    /// or stops the watchdog first.
+    fn arm(&self, timeout_us: u64);
+    /// Whether the trap with this mcause is the watchdog expiring.
+    fn expired(&self, mcause: usize) -> bool;
+    /// Push the expiry back a whole timeout from now, if armed.
+    fn pet(&self);
+}
+
+// Set once armed, so a stray timer interrupt inside the SPL still gets
+// the ordinary trap dump.
+static ARMED: AtomicBool = AtomicBool::new(false);
+// The timeout it was armed with, for pet().
+static TIMEOUT_US: AtomicU64 = AtomicU64::new(0);
+
+const MCAUSE_MTI: usize = (1 << (usize::BITS - 1)) | 7;
+
+/// Software watchdog on the CLINT machine timer of the boot hart, for
+/// boards (like QEMU virt) without a watchdog device we drive.
+pub struct ClintWatchdog;
+
+impl Watchdog for ClintWatchdog {
+    fn arm(&self, timeout_us: u64) {
+        let hart = csr::read_mhartid();
+        clint::set_mtimecmp(hart, Deadline::after_us(timeout_us).ticks());
+        TIMEOUT_US.store(timeout_us, Ordering::Relaxed);
+        ARMED.store(true, Ordering::Relaxed);
+        csr::write_mie(csr::read_mie() | csr::MIE_MTIE);
+        csr::write_mstatus(csr::read_mstatus().with_mie(true));
+    }
+
+    fn expired(&self, mcause: usize) -> bool {
+        mcause == MCAUSE_MTI && ARMED.load(Ordering::Relaxed)
+    }
+
+    fn pet(&self) {
+        if ARMED.load(Ordering::Relaxed) {
+            let timeout_us = TIMEOUT_US.load(Ordering::Relaxed);
+            clint::set_mtimecmp(csr::read_mhartid(), Deadline::after_us(timeout_us).ticks());
+        }
+    }
+}
+
+/// Backend used by the SPL.
+pub const WATCHDOG: ClintWatchdog = ClintWatchdog;
+
+/// How far a long operation got, for the yield hook.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub what: &'static str,
+    /// Done so far and in all, in the operation's own units (blocks,
+    /// bytes, passes), total 0 if unknown.
+    pub done: usize,
+    pub total: usize,
+}
+
+/// The yield hook stopped the operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+/// Called at each yield point; Err stops the operation.
+pub type YieldHook = fn(Progress) -> Result<(), Cancelled>;
+
+static mut HOOK: Option<YieldHook> = None;
+
+/// Set the yield hook (None: no hook), returning the one it replaces.
+pub fn set_yield_hook(hook: Option<YieldHook>) -> Option<YieldHook> {
+    unsafe { core::ptr::replace(&raw mut HOOK, hook) }
+}
+
+/// Yield point of a long operation: run the hook, if any, and pass on
+/// its verdict.
+#[inline]
+pub fn yield_point(what: &'static str, done: usize, total: usize) -> Result<(), Cancelled> {
+    match unsafe { (&raw const HOOK).read() } {
+        Some(hook) => hook(Progress { what, done, total }),
+        None => Ok(()),
+    }
+}
+
+// Least time between two progress ticks.
+const TICK_US: u64 = 1_000_000;
+
+// When pet_hook() last logged one (clint::now_us()).
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// The SPL's own yield hook: pet the watchdog, and log a progress tick
+/// at most every TICK_US. Never cancels.
+pub fn pet_hook(p: Progress) -> Result<(), Cancelled> {
+    WATCHDOG.pet();
+    let now = clint::now_us();
+    if now - LAST_TICK.load(Ordering::Relaxed) >= TICK_US {
+        LAST_TICK.store(now, Ordering::Relaxed);
+        match p.total {
+            0 => slog_debug!("{}: {} so far", p.what, p.done),
+            total => slog_debug!("{}: {}/{}", p.what, p.done, total),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::sync::Mutex;
+
+    /// Most simulated time (see the sim's clint.rs) any long operation
+    /// may work between two yield points, or before its first.
+    pub const CADENCE_US: u64 = 100_000;
+
+    /// A yield point reached, and the simulated time it was reached at.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Yield {
+        pub what: &'static str,
+        pub done: usize,
+        pub total: usize,
+        pub at_us: u64,
+    }
+
+    /// The hook is global: tests that set one, or run code that does,
+    /// take turns. What it does is the test's thread's business.
+    pub static HOOKED: Mutex<()> = Mutex::new(());
+
+    type Check = Box<dyn Fn()>;
+
+    thread_local! {
+        static SEEN: RefCell<Vec<Yield>> = const { RefCell::new(Vec::new()) };
+        static CANCEL_AFTER: Cell<Option<usize>> = const { Cell::new(None) };
+        static AT_YIELD: RefCell<Option<Check>> = const { RefCell::new(None) };
+    }
+
+    fn recorder(p: Progress) -> Result<(), Cancelled> {
+        let seen = SEEN.with(|seen| {
+            seen.borrow_mut().push(Yield {
+                what: p.what,
+                done: p.done,
+                total: p.total,
+                at_us: clint::now_us(),
+            });
+            seen.borrow().len()
+        });
+        AT_YIELD.with(|f| f.borrow().as_ref().map(|f| f()));
+        match CANCEL_AFTER.get() {
+            Some(n) if seen > n => Err(Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run `op` with a hook that records every yield point, calls
+    /// `at_yield` there, and, with `cancel_after` Some(n), lets n of
+    /// them through and cancels the next.
+    pub fn watched<R>(
+        cancel_after: Option<usize>,
+        at_yield: impl Fn() + 'static,
+        op: impl FnOnce() -> R,
+    ) -> (R, Vec<Yield>) {
+        let _turn = HOOKED.lock().unwrap_or_else(|e| e.into_inner());
+        SEEN.take();
+        CANCEL_AFTER.set(cancel_after);
+        AT_YIELD.set(Some(Box::new(at_yield)));
+        let outer = set_yield_hook(Some(recorder));
+        let result = op();
+        set_yield_hook(outer);
+        AT_YIELD.take();
+        (result, SEEN.take())
+    }
+
+    /// Longest stretch of simulated time without a yield point from
+    /// `start_us` to `end_us`.
+    pub fn longest_gap(start_us: u64, yields: &[Yield], end_us: u64) -> u64 {
+        let mut prev = start_us;
+        let mut longest = 0;
+        for at in yields.iter().map(|y| y.at_us).chain([end_us]) {
+            longest = longest.max(at - prev);
+            prev = at;
+        }
+        longest
+    }
+
+    #[test]
+    fn no_hook_never_cancels() {
+        let _turn = HOOKED.lock().unwrap_or_else(|e| e.into_inner());
+        let outer = set_yield_hook(None);
+        assert_eq!(yield_point("test", 1, 2), Ok(()));
+        set_yield_hook(outer);
+    }
+
+    // The SPL's hook moves the expiry a whole timeout past now, and only
+    // once the watchdog is armed.
+    #[test]
+    fn pet_hook_pets() {
+        let _turn = HOOKED.lock().unwrap_or_else(|e| e.into_inner());
+        let progress = Progress {
+            what: "test",
+            done: 0,
+            total: 0,
+        };
+        ARMED.store(false, Ordering::Relaxed);
+        clint::set_mtimecmp(0, u64::MAX);
+        assert_eq!(pet_hook(progress), Ok(()));
+        assert_eq!(clint::mtimecmp(), u64::MAX);
+
+        WATCHDOG.arm(30_000_000);
+        assert_eq!(clint::mtimecmp(), clint::mtime() + 30_000_000);
+        assert_ne!(csr::read_mie() & csr::MIE_MTIE, 0);
+        assert!(csr::read_mstatus().mie());
+        clint::advance_us(1_000_000);
+        assert_eq!(pet_hook(progress), Ok(()));
+        assert_eq!(clint::mtimecmp(), clint::mtime() + 30_000_000);
+        assert!(WATCHDOG.expired(MCAUSE_MTI));
+        ARMED.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,271 @@
+use core::result::Result;
+
+use crate::clint::Deadline;
+use crate::flash_intel::FlashError;
+use crate::logger::{self, RxError};
+use crate::watchdog;
+
+// XMODEM receiver (128-byte blocks, checksum or CRC-16), for pushing a
+// new bank image over the serial console with `sx`, minicom, etc.
+//
+// We start by sending 'C' to ask for CRC-16 and fall back to NAK (plain
+// additive checksum) for old senders. Each block is:
+//
+//   SOH  blk  ~blk  data[128]  sum | crc_hi crc_lo
+//
+// blk counts from 1 and wraps at 255. A repeat of the previous block
+// (our ACK was lost) is ACKed again and dropped; anything else out of
+// sequence aborts the transfer. EOT ends it, CAN CAN cancels it.
+//
+// Nothing may be logged to the console while a transfer runs.
+
+pub const BLOCK_SIZE: usize = 128;
+
+const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_START: u8 = b'C';
+
+// 'C' this many times before falling back to NAK, one per interval;
+// about a minute in all to get the sender going.
+const CRC_TRIES: u32 = 4;
+const START_TRIES: u32 = 20;
+const START_INTERVAL_US: u64 = 3_000_000;
+// Silence allowed between blocks, and between bytes of a block.
+const BLOCK_TIMEOUT_US: u64 = 10_000_000;
+const BYTE_TIMEOUT_US: u64 = 1_000_000;
+// Consecutive bad or missing blocks before we give up.
+const MAX_ERRORS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    /// No sender showed up, or it went quiet for too long.
+    Timeout,
+    /// The sender cancelled (CAN CAN), or the yield hook did (see
+    /// watchdog::yield_point()) and we told the sender so.
+    Cancelled,
+    /// Too many corrupt blocks in a row.
+    TooManyErrors,
+    /// A block that is neither the next one nor a repeat.
+    BadSequence { expected: u8, got: u8 },
+    /// More data than the destination holds.
+    TooLarge,
+    /// Storing a block failed.
+    Write(FlashError),
+}
+
+/// CRC-16/XMODEM (poly 0x1021, init 0, not reflected).
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn getc(us: u64) -> Result<u8, RxError> {
+    logger::getc_timeout(Deadline::after_us(us))
+}
+
+// Drop input until the line has been quiet for a second, so a NAK lands
+// between blocks rather than in the middle of one.
+fn purge() {
+    while getc(BYTE_TIMEOUT_US) != Err(RxError::Timeout) {}
+}
+
+fn cancel() {
+    logger::putc_raw(CAN);
+    logger::putc_raw(CAN);
+    purge();
+}
+
+// Rest of a block after its SOH. None if it is corrupt or truncated.
+fn read_block(crc: bool) -> Option<(u8, [u8; BLOCK_SIZE])> {
+    let blk = getc(BYTE_TIMEOUT_US).ok()?;
+    let inv = getc(BYTE_TIMEOUT_US).ok()?;
+    let mut data = [0u8; BLOCK_SIZE];
+    for b in data.iter_mut() {
+        *b = getc(BYTE_TIMEOUT_US).ok()?;
+    }
+    let ok = if crc {
+        let hi = getc(BYTE_TIMEOUT_US).ok()?;
+        let lo = getc(BYTE_TIMEOUT_US).ok()?;
+        crc16(&data) == u16::from_be_bytes([hi, lo])
+    } else {
+        let sum = getc(BYTE_TIMEOUT_US).ok()?;
+        data.iter().fold(0u8, |a, &b| a.wrapping_add(b)) == sum
+    };
+    (ok && blk == !inv).then_some((blk, data))
+}
+
+/// Receive a file of at most `max_len` bytes, handing each block to
+/// `sink` with its offset in the file. The last block is padded (with
+/// whatever the sender uses, usually 0x1a). Returns the bytes received.
+pub fn receive(
+    max_len: usize,
+    mut sink: impl FnMut(usize, &[u8]) -> Result<(), FlashError>,
+) -> Result<usize, XmodemError> {
+    let mut crc = true;
+    let mut first = None;
+    for i in 0..START_TRIES {
+        crc = i < CRC_TRIES;
+        logger::putc_raw(if crc { CRC_START } else { NAK });
+        if let Ok(b) = getc(START_INTERVAL_US) {
+            first = Some(b);
+            break;
+        }
+    }
+    let Some(mut b) = first else {
+        return Err(XmodemError::Timeout);
+    };
+
+    let mut expected: u8 = 1;
+    let mut received = 0usize;
+    let mut errors = 0u32;
+    loop {
+        match b {
+            SOH => match read_block(crc) {
+                Some((blk, data)) if blk == expected => {
+                    if received + BLOCK_SIZE > max_len {
+                        cancel();
+                        return Err(XmodemError::TooLarge);
+                    }
+                    if let Err(e) = sink(received, &data) {
+                        cancel();
+                        return Err(XmodemError::Write(e));
+                    }
+                    received += BLOCK_SIZE;
+                    expected = expected.wrapping_add(1);
+                    errors = 0;
+                    if watchdog::yield_point("xmodem", received, max_len).is_err() {
+                        cancel();
+                        return Err(XmodemError::Cancelled);
+                    }
+                    logger::putc_raw(ACK);
+                }
+                Some((blk, _)) if blk == expected.wrapping_sub(1) => logger::putc_raw(ACK),
+                Some((blk, _)) => {
+                    cancel();
+                    return Err(XmodemError::BadSequence { expected, got: blk });
+                }
+                None => {
+                    errors += 1;
+                    if errors > MAX_ERRORS {
+                        cancel();
+                        return Err(XmodemError::TooManyErrors);
+                    }
+                    purge();
+                    logger::putc_raw(NAK);
+                }
+            },
+            EOT => {
+                logger::putc_raw(ACK);
+                return Ok(received);
+            }
+            CAN if getc(BYTE_TIMEOUT_US) == Ok(CAN) => return Err(XmodemError::Cancelled),
+            // Line noise between blocks.
+            _ => {}
+        }
+
+        b = loop {
+            match getc(BLOCK_TIMEOUT_US) {
+                Ok(b) => break b,
+                Err(_) => {
+                    errors += 1;
+                    if errors > MAX_ERRORS {
+                        cancel();
+                        return Err(XmodemError::Timeout);
+                    }
+                    logger::putc_raw(NAK);
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clint;
+    use crate::logger::{take_output, type_input};
+    use crate::watchdog::tests::{longest_gap, watched, CADENCE_US};
+
+    const BLOCKS: usize = 12;
+
+    fn file() -> Vec<u8> {
+        (0..BLOCKS * BLOCK_SIZE).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    // What a sender in CRC mode sends for `file`, EOT included.
+    fn sent(file: &[u8]) -> Vec<u8> {
+        let mut line = Vec::new();
+        for (i, data) in file.chunks(BLOCK_SIZE).enumerate() {
+            let blk = (i + 1) as u8;
+            line.extend([SOH, blk, !blk]);
+            line.extend(data);
+            line.extend(crc16(data).to_be_bytes());
+        }
+        line.push(EOT);
+        line
+    }
+
+    // What transfer() saw: the outcome, what the sink got, the yield
+    // points (bytes done) and what was sent back.
+    type Transfer = (Result<usize, XmodemError>, Vec<u8>, Vec<usize>, Vec<u8>);
+
+    // Receive `file`, cancelling as watched() does with `cancel_after`.
+    fn transfer(file: &[u8], cancel_after: Option<usize>) -> Transfer {
+        type_input(&sent(file));
+        let start = clint::now_us();
+        let mut got = Vec::new();
+        let (result, yields) = watched(cancel_after, || {}, || {
+            receive(file.len(), |offset, data| {
+                assert_eq!(offset, got.len());
+                got.extend_from_slice(data);
+                Ok(())
+            })
+        });
+        assert!(yields.iter().all(|y| y.what == "xmodem" && y.total == file.len()));
+        // Once cancelled, it waits out the sender: no work.
+        let end = cancel_after.map_or(clint::now_us(), |_| yields.last().unwrap().at_us);
+        assert!(longest_gap(start, &yields, end) <= CADENCE_US);
+        (result, got, yields.iter().map(|y| y.done).collect(), take_output())
+    }
+
+    // A yield point after every block stored, a block's time on the line
+    // at most between two.
+    #[test]
+    fn yields_every_block() {
+        let file = file();
+        let (result, got, done, replies) = transfer(&file, None);
+        assert_eq!(result, Ok(file.len()));
+        assert_eq!(got, file);
+        assert_eq!(done, (1..=BLOCKS).map(|n| n * BLOCK_SIZE).collect::<Vec<_>>());
+        let mut expected = vec![CRC_START];
+        expected.extend([ACK; BLOCKS + 1]);
+        assert_eq!(replies, expected);
+    }
+
+    // Cancelled, the receiver tells the sender (CAN CAN) and drains what
+    // it still sends; the block stored before stays stored.
+    #[test]
+    fn cancel_tells_the_sender() {
+        let file = file();
+        for n in [0, 5, BLOCKS - 1] {
+            let (result, got, done, replies) = transfer(&file, Some(n));
+            assert_eq!(result, Err(XmodemError::Cancelled));
+            assert_eq!(got, file[..(n + 1) * BLOCK_SIZE]);
+            assert_eq!(done.len(), n + 1);
+            let mut expected = vec![CRC_START];
+            expected.extend(vec![ACK; n]);
+            expected.extend([CAN, CAN]);
+            assert_eq!(replies, expected);
+            assert_eq!(logger::getc_timeout(clint::Deadline::after_us(0)), Err(RxError::Timeout));
+        }
+    }
+}